@@ -0,0 +1,28 @@
+//! Compare two file trees built in memory, without touching the disk
+//!
+//! Run with `cargo run --example memory_fs`.
+
+use diffpatch::diff::{self, DiffOptions};
+use diffpatch::vfs::MemoryFs;
+
+fn main() -> anyhow::Result<()> {
+    let source = MemoryFs::new()
+        .named("v1")
+        .file("README.txt", "Version 1\n")
+        .file("bin/app", [0x7f, b'E', b'L', b'F', 1])
+        .file("data/old.dat", "obsolete");
+    let target = source
+        .clone()
+        .named("v2")
+        .file("README.txt", "Version 2\n")
+        .file("data/new.dat", "fresh")
+        .without("data/old.dat");
+
+    let report = diff::compare_vfs(&source, &target, &DiffOptions::new())?;
+    let mut diffs = report.diffs;
+    diffs.sort_by(|a, b| a.path().cmp(b.path()));
+    for diff in &diffs {
+        println!("{:<8} {}", diff.kind(), diff.path().display());
+    }
+    Ok(())
+}
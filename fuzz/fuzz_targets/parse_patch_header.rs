@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The first 8 bytes pick the claimed file size, the rest is the trailer
+    if data.len() < 8 {
+        return;
+    }
+    let (size, trailer) = data.split_at(8);
+    let file_size = u64::from_le_bytes(size.try_into().unwrap());
+    let _ = diffpatch::patch::parse_patch_header(trailer, file_size);
+});
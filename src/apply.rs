@@ -0,0 +1,205 @@
+use crate::diff::{self, DiffChangeTag, DiffReport, DiffType, FileDiff, FileInfo};
+use crate::digest;
+use crate::fsops::{self, FileMode};
+use crate::staging::StagingFile;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// What `apply_report` changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplySummary {
+    /// Added and modified files copied from the payload directory
+    pub copied: usize,
+    /// Modified files rebuilt from their text diff
+    pub patched: usize,
+    pub renamed: usize,
+    pub removed: usize,
+    /// Files given the permissions recorded in the report
+    pub permissions: usize,
+    /// Files whose content was checked against the hash in the report
+    pub verified: usize,
+}
+
+/// Apply the differences of a report to `dest_dir`
+///
+/// Added and modified files are copied from `payload_dir`, usually the target tree the
+/// report was made from or a copy of just its changed files. Text diffs are applied to
+/// the files in `dest_dir`, which must still have the content they were made against.
+/// Renamed files are moved, removed files deleted and symbolic links created. Written
+/// files and those whose permissions changed get the mode recorded for them, where it
+/// is one of this platform.
+///
+/// Every written file is hashed before it replaces the old one and must match the hash
+/// in the report, so a damaged payload or a diff applied to the wrong file fails without
+/// leaving a partly written file behind. Files without a hash (left by lazy hashing) are
+/// copied unchecked. Changes made before a failure are kept.
+pub fn apply_report(report: &DiffReport, payload_dir: &Path, dest_dir: &Path) -> Result<ApplySummary> {
+    let mut summary = ApplySummary::default();
+    // Paths the report writes, which a rename must not delete after moving their old content
+    let written: HashSet<&Path> = report.diffs.iter().filter(|d| !matches!(d, DiffType::Removed(_))).map(DiffType::path).collect();
+
+    // Renames first: their source may be replaced or removed by other entries
+    let mut moved_from = Vec::new();
+    for diff in &report.diffs {
+        if let DiffType::Renamed(rename) = diff {
+            let content = read_file(&dest_dir.join(&rename.from))?;
+            summary.verified += usize::from(write_verified(&dest_dir.join(&rename.to.relative_path), &content, &rename.to.hash)?);
+            summary.permissions += usize::from(set_mode(&dest_dir.join(&rename.to.relative_path), rename.to.mode)?);
+            moved_from.push(rename.from.as_path());
+            summary.renamed += 1;
+        }
+    }
+    for from in moved_from {
+        if !written.contains(from) {
+            remove_file(&dest_dir.join(from))?;
+        }
+    }
+
+    for diff in &report.diffs {
+        match diff {
+            DiffType::Added(info) | DiffType::Modified(info) => {
+                summary.verified += usize::from(copy_payload(info, payload_dir, dest_dir)?);
+                summary.copied += 1;
+                if !info.is_symlink() {
+                    summary.permissions += usize::from(set_mode(&dest_dir.join(&info.relative_path), info.mode)?);
+                }
+            }
+            DiffType::ModifiedDiff(file_diff) => {
+                patch_text_file(file_diff, dest_dir)?;
+                summary.verified += 1;
+                summary.patched += 1;
+                summary.permissions += usize::from(set_mode(&dest_dir.join(&file_diff.relative_path), file_diff.mode)?);
+            }
+            DiffType::MetadataChanged(info) => {
+                summary.permissions += usize::from(set_mode(&dest_dir.join(&info.relative_path), info.mode)?);
+            }
+            DiffType::Removed(path) => {
+                remove_file(&dest_dir.join(path))?;
+                summary.removed += 1;
+            }
+            DiffType::Renamed(_) => {}
+        }
+    }
+    Ok(summary)
+}
+
+/// Apply the line changes of a text diff to the content it was made against
+///
+/// The diff does not record line endings, so the result takes the line ending of
+/// `original` and is checked against the hash of the target with and without a final
+/// line break; content that matches neither is an error.
+pub fn apply_text_diff(original: &str, file_diff: &FileDiff) -> Result<String> {
+    let old_lines: Vec<&str> = original.lines().collect();
+    let mut lines: Vec<&str> = Vec::with_capacity(old_lines.len());
+    // Next line of `original` not yet copied or replaced
+    let mut old_pos = 0;
+    let malformed = || anyhow!("Text diff of {} does not match the file it is applied to", file_diff.relative_path.display());
+
+    for change in &file_diff.changes {
+        let old_start = match (change.old_range, change.new_range) {
+            (Some((start, _)), _) => start,
+            // Inserts only know their position in the new file; the lines before them are unchanged
+            (None, Some((new_start, _))) => old_pos + new_start.checked_sub(lines.len()).ok_or_else(malformed)?,
+            (None, None) => continue,
+        };
+        lines.extend(old_lines.get(old_pos..old_start).ok_or_else(malformed)?);
+        old_pos = old_start;
+        if let Some((_, len)) = change.old_range
+            && change.tag != DiffChangeTag::Insert
+        {
+            old_pos = old_start.checked_add(len).filter(|end| *end <= old_lines.len()).ok_or_else(malformed)?;
+        }
+        if let Some((_, len)) = change.new_range
+            && change.tag != DiffChangeTag::Delete
+        {
+            let new_lines: Vec<&str> = change.content.split('\n').collect();
+            if new_lines.len() != len {
+                return Err(malformed());
+            }
+            lines.extend(new_lines);
+        }
+    }
+    lines.extend(&old_lines[old_pos..]);
+
+    let line_ending = if original.contains("\r\n") { "\r\n" } else { "\n" };
+    let without_break = lines.join(line_ending);
+    let with_break = format!("{}{}", without_break, line_ending);
+    // Try the final line break of the original first
+    let candidates = if original.ends_with('\n') { [with_break, without_break] } else { [without_break, with_break] };
+    for candidate in candidates {
+        if digest::same_digest(&diff::hash_reader_like(candidate.as_bytes(), &file_diff.hash)?, &file_diff.hash) {
+            return Ok(candidate);
+        }
+    }
+    Err(anyhow!("Applying the text diff of {} gave content that does not match its hash", file_diff.relative_path.display()))
+}
+
+/// Copy an added or modified file from the payload; whether its hash was checked
+///
+/// Symbolic links are created from their recorded target instead.
+fn copy_payload(info: &FileInfo, payload_dir: &Path, dest_dir: &Path) -> Result<bool> {
+    if let Some(target) = &info.link_target {
+        let link = dest_dir.join(&info.relative_path);
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        fsops::create_symlink(target, &link).with_context(|| format!("Failed to create link: {}", link.display()))?;
+        return Ok(false);
+    }
+    let source = payload_dir.join(&info.relative_path);
+    let content = read_file(&source)?;
+    write_verified(&dest_dir.join(&info.relative_path), &content, &info.hash)
+}
+
+/// Rebuild a file from its text diff, after checking it is the version the diff was made against
+fn patch_text_file(file_diff: &FileDiff, dest_dir: &Path) -> Result<()> {
+    let path = dest_dir.join(&file_diff.relative_path);
+    let original = read_file(&path)?;
+    if !digest::same_digest(&diff::hash_reader_like(original.as_slice(), &file_diff.original_hash)?, &file_diff.original_hash) {
+        return Err(anyhow!("{} was changed since the diff was made; not applying its text diff", path.display()));
+    }
+    let original = String::from_utf8(original).with_context(|| format!("File is not UTF-8 text: {}", path.display()))?;
+    let content = apply_text_diff(&original, file_diff)?;
+    write_verified(&path, content.as_bytes(), &file_diff.hash)?;
+    Ok(())
+}
+
+/// Replace `dest` with `content` if it matches `hash` (always, if `hash` is empty);
+/// whether the hash was checked
+fn write_verified(dest: &Path, content: &[u8], hash: &str) -> Result<bool> {
+    if !hash.is_empty() {
+        let actual = diff::hash_reader_like(content, hash)?;
+        if !digest::same_digest(&actual, hash) {
+            return Err(anyhow!("Hash mismatch for {}: expected {}, got {}", dest.display(), hash, actual));
+        }
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    let staged = StagingFile::new(dest)?;
+    fs::write(staged.path(), content).with_context(|| format!("Failed to write file: {}", dest.display()))?;
+    staged.persist()?;
+    Ok(!hash.is_empty())
+}
+
+/// Give a file its recorded mode, if any; whether it was set
+fn set_mode(path: &Path, mode: Option<FileMode>) -> Result<bool> {
+    match mode {
+        Some(mode) => mode.apply(path).with_context(|| format!("Failed to set permissions of {}", path.display())),
+        None => Ok(false),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))
+}
+
+/// Delete a file; one that is already gone counts as deleted
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).with_context(|| format!("Failed to remove file: {}", path.display())),
+        _ => Ok(()),
+    }
+}
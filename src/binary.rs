@@ -0,0 +1,307 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+/// ELF sections whose content changes with every build
+const VOLATILE_ELF_SECTIONS: &[&str] = &[".note.gnu.build-id", ".note.go.buildid", ".gnu_debuglink"];
+
+/// Trailer of a signed Linux kernel module
+const MODULE_SIGNATURE_MAGIC: &[u8] = b"~Module signature appended~\n";
+
+/// Executable formats whose build stamps can be left out of a comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryFormat {
+    /// Windows PE/COFF (`.exe`, `.dll`, `.sys`)
+    Pe,
+    /// ELF executables, shared libraries and kernel modules
+    Elf,
+}
+
+/// Hash of an executable without its volatile parts, or `None` for other files
+///
+/// Left out are link timestamps, the PE checksum, Authenticode signatures, debug
+/// directory timestamps and PDB GUIDs, ELF build IDs and debug links, and kernel module
+/// signatures. Two builds of the same code with the same toolchain hash equal.
+pub fn stamp_free_digest(data: &[u8]) -> Option<(BinaryFormat, [u8; 32])> {
+    let (format, volatile) = if let Some(volatile) = pe_volatile_ranges(data) {
+        (BinaryFormat::Pe, volatile)
+    } else {
+        (BinaryFormat::Elf, elf_volatile_ranges(data)?)
+    };
+    Some((format, hash_without(data, volatile)))
+}
+
+/// Whether two files are executables of the same format that differ only in build stamps
+pub fn equal_ignoring_stamps(a: &Path, b: &Path) -> Result<bool> {
+    let a_data = fs::read(a).with_context(|| format!("Failed to read file: {}", a.display()))?;
+    let Some(a_digest) = stamp_free_digest(&a_data) else {
+        return Ok(false);
+    };
+    drop(a_data);
+    let b_data = fs::read(b).with_context(|| format!("Failed to read file: {}", b.display()))?;
+    Ok(stamp_free_digest(&b_data) == Some(a_digest))
+}
+
+/// Whether a file starts like a PE or ELF executable, to skip reading other files whole
+pub fn looks_like_executable(path: &Path) -> bool {
+    use std::io::Read;
+    let mut magic = [0u8; 4];
+    fs::File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && (magic[..2] == *b"MZ" || magic == *b"\x7fELF")
+}
+
+/// Bytes at the start of a file `sniff_target` looks at; enough for the PE header of
+/// common linkers
+pub const TARGET_SNIFF_LEN: usize = 4096;
+
+/// Platform and CPU architecture an executable is built for, named as in
+/// `condition::PLATFORMS` and `condition::ARCHITECTURES`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinaryTarget {
+    pub platform: &'static str,
+    /// `None` for machine types not in `condition::ARCHITECTURES`
+    pub arch: Option<&'static str>,
+}
+
+/// Platform and architecture of a PE, ELF or Mach-O executable from its first bytes,
+/// or `None` for other files
+///
+/// ELF files without an OS ABI (as most Linux builds are) count as Linux. Universal
+/// Mach-O binaries are not recognized, as their magic number is shared with Java classes.
+pub fn sniff_target(head: &[u8]) -> Option<BinaryTarget> {
+    if head.get(..2)? == b"MZ" {
+        let pe = read_u32(head, 0x3c, false)? as usize;
+        if head.get(pe..pe.checked_add(4)?)? != b"PE\0\0" {
+            return None;
+        }
+        let arch = match read_u16(head, pe + 4, false)? {
+            0x14c => Some("x86"),
+            0x8664 => Some("x86_64"),
+            0x1c0 | 0x1c4 => Some("arm"),
+            0xaa64 => Some("aarch64"),
+            _ => None,
+        };
+        return Some(BinaryTarget { platform: "windows", arch });
+    }
+    if head.get(..4)? == b"\x7fELF" {
+        let platform = match *head.get(7)? {
+            0 | 3 => "linux",
+            2 => "netbsd",
+            9 => "freebsd",
+            12 => "openbsd",
+            _ => return None,
+        };
+        let is_64 = *head.get(4)? == 2;
+        let arch = match read_u16(head, 0x12, *head.get(5)? == 2)? {
+            3 => Some("x86"),
+            0x3e => Some("x86_64"),
+            0x28 => Some("arm"),
+            0xb7 => Some("aarch64"),
+            0xf3 if is_64 => Some("riscv64"),
+            0x15 => Some("powerpc64"),
+            0x16 if is_64 => Some("s390x"),
+            0x102 => Some("loongarch64"),
+            _ => None,
+        };
+        return Some(BinaryTarget { platform, arch });
+    }
+    // Mach-O, 32 and 64 bit, in either byte order
+    let big_endian = match head.get(..4)? {
+        b"\xfe\xed\xfa\xce" | b"\xfe\xed\xfa\xcf" => true,
+        b"\xce\xfa\xed\xfe" | b"\xcf\xfa\xed\xfe" => false,
+        _ => return None,
+    };
+    let arch = match read_u32(head, 4, big_endian)? {
+        7 => Some("x86"),
+        0x0100_0007 => Some("x86_64"),
+        12 => Some("arm"),
+        0x0100_000c => Some("aarch64"),
+        _ => None,
+    };
+    Some(BinaryTarget { platform: "macos", arch })
+}
+
+/// Platform and architecture of the executable at `path`, see `sniff_target`
+pub fn executable_target(path: &Path) -> Result<Option<BinaryTarget>> {
+    use std::io::Read;
+    let file = fs::File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut head = Vec::with_capacity(TARGET_SNIFF_LEN);
+    file.take(TARGET_SNIFF_LEN as u64).read_to_end(&mut head).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(sniff_target(&head))
+}
+
+/// SHA-256 of `data` with the `skip` ranges left out; kept runs are length-prefixed so
+/// moving a skipped range changes the hash
+fn hash_without(data: &[u8], mut skip: Vec<Range<usize>>) -> [u8; 32] {
+    skip.retain(|range| range.start < range.end);
+    skip.sort_by_key(|range| range.start);
+    let mut hasher = Sha256::new();
+    let mut position = 0;
+    for range in skip {
+        let start = range.start.min(data.len());
+        if start > position {
+            hasher.update((start - position).to_le_bytes());
+            hasher.update(&data[position..start]);
+        }
+        position = position.max(range.end.min(data.len()));
+    }
+    hasher.update((data.len() - position).to_le_bytes());
+    hasher.update(&data[position..]);
+    hasher.finalize().into()
+}
+
+fn read_u16(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let bytes: [u8; 2] = data.get(offset..offset.checked_add(2)?)?.try_into().ok()?;
+    Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+}
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let bytes: [u8; 4] = data.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+    Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+}
+
+fn read_u64(data: &[u8], offset: usize, big_endian: bool) -> Option<u64> {
+    let bytes: [u8; 8] = data.get(offset..offset.checked_add(8)?)?.try_into().ok()?;
+    Some(if big_endian { u64::from_be_bytes(bytes) } else { u64::from_le_bytes(bytes) })
+}
+
+/// Byte ranges of a PE file that change between otherwise identical builds
+fn pe_volatile_ranges(data: &[u8]) -> Option<Vec<Range<usize>>> {
+    if data.get(..2)? != b"MZ" {
+        return None;
+    }
+    let pe = read_u32(data, 0x3c, false)? as usize;
+    if data.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let coff = pe + 4;
+    let section_count = read_u16(data, coff + 2, false)? as usize;
+    let optional_size = read_u16(data, coff + 16, false)? as usize;
+    let optional = coff + 20;
+    let (count_at, directories) = match read_u16(data, optional, false)? {
+        0x10b => (optional + 92, optional + 96),
+        0x20b => (optional + 108, optional + 112),
+        _ => return None,
+    };
+    let directory_count = read_u32(data, count_at, false)? as usize;
+    let sections = optional + optional_size;
+
+    // COFF timestamp and image checksum
+    let mut volatile = vec![coff + 4..coff + 8, optional + 64..optional + 68];
+
+    let directory = |index: usize| -> Option<(usize, usize)> {
+        if index >= directory_count {
+            return None;
+        }
+        let entry = directories + index * 8;
+        Some((read_u32(data, entry, false)? as usize, read_u32(data, entry + 4, false)? as usize))
+    };
+    let file_offset = |rva: usize| -> Option<usize> {
+        (0..section_count).find_map(|index| {
+            let header = sections + index * 40;
+            let virtual_size = read_u32(data, header + 8, false)? as usize;
+            let address = read_u32(data, header + 12, false)? as usize;
+            let raw_size = read_u32(data, header + 16, false)? as usize;
+            let raw_offset = read_u32(data, header + 20, false)? as usize;
+            (address <= rva && rva < address + virtual_size.max(raw_size)).then(|| rva - address + raw_offset)
+        })
+    };
+
+    // Authenticode signature (its directory entry holds a file offset, not an RVA)
+    if let Some((offset, size)) = directory(4)
+        && size > 0
+    {
+        volatile.push(directories + 4 * 8..directories + 5 * 8);
+        volatile.push(offset..offset.saturating_add(size));
+    }
+    // Export and resource directory timestamps
+    for index in [0, 2] {
+        if let Some((rva, size)) = directory(index)
+            && size >= 8
+            && let Some(offset) = file_offset(rva)
+        {
+            volatile.push(offset + 4..offset + 8);
+        }
+    }
+    // Debug directory: entry timestamps, PDB GUID and age, reproducible-build hashes
+    if let Some((rva, size)) = directory(6)
+        && let Some(offset) = file_offset(rva)
+    {
+        for index in 0..(size / 28).min(64) {
+            let entry = offset + index * 28;
+            volatile.push(entry + 4..entry + 8);
+            let kind = read_u32(data, entry + 12, false)?;
+            let data_size = read_u32(data, entry + 16, false)? as usize;
+            let pointer = read_u32(data, entry + 24, false)? as usize;
+            match kind {
+                // CodeView: "RSDS", GUID, age, PDB path
+                2 if data.get(pointer..pointer + 4) == Some(b"RSDS") => volatile.push(pointer + 4..pointer + 24),
+                // Hash of the image written by /Brepro
+                16 => volatile.push(pointer..pointer + data_size),
+                _ => {}
+            }
+        }
+    }
+    Some(volatile)
+}
+
+/// Byte ranges of an ELF file that change between otherwise identical builds
+fn elf_volatile_ranges(data: &[u8]) -> Option<Vec<Range<usize>>> {
+    if data.get(..4)? != b"\x7fELF" {
+        return None;
+    }
+    let is_64 = match data.get(4)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let big_endian = *data.get(5)? == 2;
+    let (header_offset, entry_size, count, names_index) = if is_64 {
+        (read_u64(data, 0x28, big_endian)? as usize, read_u16(data, 0x3a, big_endian)?, read_u16(data, 0x3c, big_endian)?, read_u16(data, 0x3e, big_endian)?)
+    } else {
+        (read_u32(data, 0x20, big_endian)? as usize, read_u16(data, 0x2e, big_endian)?, read_u16(data, 0x30, big_endian)?, read_u16(data, 0x32, big_endian)?)
+    };
+    // (name, type, offset, size) of a section header
+    let section = |index: usize| -> Option<(u32, u32, usize, usize)> {
+        let header = header_offset.checked_add(index.checked_mul(entry_size as usize)?)?;
+        let name = read_u32(data, header, big_endian)?;
+        let kind = read_u32(data, header + 4, big_endian)?;
+        if is_64 {
+            Some((name, kind, read_u64(data, header + 0x18, big_endian)? as usize, read_u64(data, header + 0x20, big_endian)? as usize))
+        } else {
+            Some((name, kind, read_u32(data, header + 0x10, big_endian)? as usize, read_u32(data, header + 0x14, big_endian)? as usize))
+        }
+    };
+
+    let mut volatile = Vec::new();
+    if header_offset != 0
+        && let Some((_, _, names_offset, names_size)) = section(names_index as usize)
+    {
+        let names = data.get(names_offset..names_offset.saturating_add(names_size)).unwrap_or_default();
+        for index in 0..count as usize {
+            let Some((name, kind, offset, size)) = section(index) else {
+                break;
+            };
+            // SHT_NOBITS sections take no space in the file
+            if kind == 8 {
+                continue;
+            }
+            let name = names.get(name as usize..).and_then(|rest| rest.split(|&b| b == 0).next()).unwrap_or_default();
+            if VOLATILE_ELF_SECTIONS.iter().any(|volatile| volatile.as_bytes() == name) {
+                volatile.push(offset..offset.saturating_add(size));
+            }
+        }
+    }
+    // Signed kernel modules: signature, 12-byte descriptor ending in the big-endian
+    // signature length, and the magic string
+    if data.ends_with(MODULE_SIGNATURE_MAGIC) {
+        let descriptor_end = data.len() - MODULE_SIGNATURE_MAGIC.len();
+        if let Some(signature_len) = descriptor_end.checked_sub(4).and_then(|at| read_u32(data, at, true))
+            && let Some(start) = descriptor_end.checked_sub(12 + signature_len as usize)
+        {
+            volatile.push(start..data.len());
+        }
+    }
+    Some(volatile)
+}
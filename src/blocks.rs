@@ -0,0 +1,438 @@
+use crate::diff::HashAlgorithm;
+use crate::digest::DigestEncoding;
+use crate::hashcache::{inode, mtime_ns};
+use crate::interrupt;
+use crate::progress::{ProgressHook, ScanProgress};
+use crate::staging::{self, StagingFile};
+use crate::tr;
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Block size used when none is given
+pub const DEFAULT_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Largest block size accepted; every block is read into memory at once
+pub const MAX_BLOCK_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Version of the block hash files; state files of other versions are ignored
+const BLOCK_HASHES_VERSION: u32 = 1;
+
+/// Start of every block delta file
+const MAGIC: &[u8; 8] = b"DPBLOCK1";
+
+/// Resume state is saved after hashing this much of a file
+const SAVE_INTERVAL: u64 = 256 * 1024 * 1024;
+
+/// Hashes of the fixed-size blocks of one file, as made by `hash_blocks`
+///
+/// While a file is being hashed, `blocks` holds the blocks done so far; `hash_blocks`
+/// saves it to its state directory now and then, so an interrupted run resumes there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHashes {
+    pub version: u32,
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_ns: Option<u64>,
+    /// Inode number (Unix only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    pub block_size: u64,
+    pub hash_algorithm: HashAlgorithm,
+    pub blocks: Vec<String>,
+}
+
+impl BlockHashes {
+    fn new(metadata: &fs::Metadata, block_size: u64, hash_algorithm: HashAlgorithm) -> Self {
+        BlockHashes {
+            version: BLOCK_HASHES_VERSION,
+            size: metadata.len(),
+            mtime_ns: mtime_ns(metadata),
+            inode: inode(metadata),
+            block_size,
+            hash_algorithm,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Number of blocks of the file; the last one may be shorter than `block_size`
+    pub fn block_count(&self) -> u64 {
+        self.size.div_ceil(self.block_size)
+    }
+
+    /// Length of block `index`
+    pub fn block_len(&self, index: u64) -> u64 {
+        self.block_size.min(self.size.saturating_sub(index.saturating_mul(self.block_size)))
+    }
+
+    /// Whether every block was hashed
+    pub fn is_complete(&self) -> bool {
+        self.blocks.len() as u64 >= self.block_count()
+    }
+
+    /// Bytes covered by the blocks hashed so far
+    pub fn hashed_bytes(&self) -> u64 {
+        (self.blocks.len() as u64).saturating_mul(self.block_size).min(self.size)
+    }
+
+    /// Short identifier of the whole block list, e.g. to check a file against the one a
+    /// block delta was made for
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.size.to_le_bytes());
+        hasher.update(self.block_size.to_le_bytes());
+        for block in &self.blocks {
+            hasher.update(block.as_bytes());
+            hasher.update(b"\n");
+        }
+        DigestEncoding::Hex.encode_as(self.hash_algorithm, &hasher.finalize())
+    }
+
+    /// Whether these hashes were made from the file with `metadata`, as it is now
+    fn matches(&self, metadata: &fs::Metadata) -> bool {
+        self.size == metadata.len() && self.mtime_ns.is_some() && self.mtime_ns == mtime_ns(metadata) && self.inode == inode(metadata)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self).context("Failed to serialize block hashes")?;
+        staging::write_atomic(path, &json).with_context(|| format!("Failed to write block hashes: {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read block hashes: {}", path.display()))?;
+        let hashes: BlockHashes = serde_json::from_slice(&data).with_context(|| format!("Failed to parse block hashes: {}", path.display()))?;
+        ensure!(hashes.version == BLOCK_HASHES_VERSION, "Unsupported block hashes version {}: {}", hashes.version, path.display());
+        ensure!(hashes.block_size > 0, "Invalid block size 0: {}", path.display());
+        Ok(hashes)
+    }
+}
+
+/// How `hash_blocks` hashes a file
+#[derive(Debug, Clone)]
+pub struct BlockOptions {
+    block_size: u64,
+    hash_algorithm: HashAlgorithm,
+    state_dir: Option<PathBuf>,
+    progress: Option<ProgressHook>,
+}
+
+impl Default for BlockOptions {
+    fn default() -> Self {
+        BlockOptions::new()
+    }
+}
+
+impl BlockOptions {
+    pub fn new() -> Self {
+        BlockOptions { block_size: DEFAULT_BLOCK_SIZE, hash_algorithm: HashAlgorithm::default(), state_dir: None, progress: None }
+    }
+
+    /// Size of the blocks; smaller blocks give smaller deltas but longer hash lists
+    pub fn block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    /// Directory keeping the hashes of partly and fully hashed files, so a run that was
+    /// interrupted resumes where it stopped, and an unchanged file is not read again
+    pub fn state_dir(mut self, state_dir: Option<PathBuf>) -> Self {
+        self.state_dir = state_dir;
+        self
+    }
+
+    /// Hook called after every block; `ScanProgress::dir` is the file, and
+    /// `files_hashed` becomes 1 when the whole file is done
+    pub fn progress(mut self, progress: ProgressHook) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.block_size > 0, "Block size must be greater than 0");
+        ensure!(self.block_size <= MAX_BLOCK_SIZE, "Block size must be at most {} bytes", MAX_BLOCK_SIZE);
+        Ok(())
+    }
+}
+
+/// File in `state_dir` holding the block hashes of the file at `path` (a canonical path)
+pub fn state_path(state_dir: &Path, path: &Path, block_size: u64, algorithm: HashAlgorithm) -> PathBuf {
+    let digest = Sha256::digest(path.to_string_lossy().as_bytes());
+    let prefix: String = digest.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+    state_dir.join(format!("{}-{}-{}.json", prefix, block_size, algorithm))
+}
+
+/// Hash the file at `path` block by block
+///
+/// With a state directory, the blocks hashed so far are saved every 256 MiB and when
+/// the run is interrupted (Ctrl-C or a read error), and a later run on the unchanged
+/// file (same size, modification time and inode) starts from there. The finished hashes
+/// are kept too, so hashing an unchanged file again reads nothing.
+pub fn hash_blocks(path: &Path, options: &BlockOptions) -> Result<BlockHashes> {
+    options.validate()?;
+    let metadata = fs::metadata(path).with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+    ensure!(metadata.is_file(), "Not a file: {}", path.display());
+
+    let state = match &options.state_dir {
+        Some(state_dir) => {
+            let canonical = path.canonicalize().with_context(|| format!("Failed to resolve path: {}", path.display()))?;
+            fs::create_dir_all(state_dir).with_context(|| format!("Failed to create state directory: {}", state_dir.display()))?;
+            Some(state_path(state_dir, &canonical, options.block_size, options.hash_algorithm))
+        }
+        None => None,
+    };
+    let mut hashes = match state.as_deref().filter(|state| state.exists()).map(BlockHashes::load) {
+        Some(Ok(saved)) if saved.block_size == options.block_size && saved.hash_algorithm == options.hash_algorithm && saved.matches(&metadata) => saved,
+        Some(Err(e)) => {
+            println!("{}", tr!("blocks.ignoring_saved", error = format!("{:#}", e)));
+            BlockHashes::new(&metadata, options.block_size, options.hash_algorithm)
+        }
+        _ => BlockHashes::new(&metadata, options.block_size, options.hash_algorithm),
+    };
+    if hashes.is_complete() && !hashes.blocks.is_empty() {
+        println!("{}", tr!("blocks.unchanged", path = path.display()));
+    } else if !hashes.blocks.is_empty() {
+        println!(
+            "{}",
+            tr!("blocks.resuming", path = path.display(), block = hashes.blocks.len(), count = hashes.block_count())
+        );
+    }
+
+    let result = hash_remaining(path, &mut hashes, options, state.as_deref());
+    if let Some(state) = &state {
+        // Saved on failure too, so the blocks read so far are not read again
+        hashes.save(state)?;
+    }
+    result?;
+
+    // A file written to while it was read gives hashes of neither version
+    let after = fs::metadata(path).with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+    if hashes.mtime_ns.is_some() && !hashes.matches(&after) {
+        if let Some(state) = &state {
+            let _ = fs::remove_file(state);
+        }
+        bail!("File changed while it was hashed: {}", path.display());
+    }
+    Ok(hashes)
+}
+
+/// Hash the blocks of `path` after those already in `hashes`
+fn hash_remaining(path: &Path, hashes: &mut BlockHashes, options: &BlockOptions, state: Option<&Path>) -> Result<()> {
+    let report = |hashes: &BlockHashes| {
+        if let Some(progress) = &options.progress {
+            progress.report(&ScanProgress {
+                dir: path,
+                files_discovered: 1,
+                bytes_discovered: hashes.size,
+                files_hashed: usize::from(hashes.is_complete()),
+                bytes_hashed: hashes.hashed_bytes(),
+            });
+        }
+    };
+    report(hashes);
+    if hashes.is_complete() {
+        return Ok(());
+    }
+
+    let mut file = File::open(path).with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
+    file.seek(SeekFrom::Start(hashes.hashed_bytes()))
+        .with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
+    let mut buffer = vec![0; options.block_size as usize];
+    let mut since_save = 0;
+    while !hashes.is_complete() {
+        interrupt::check()?;
+        let index = hashes.blocks.len() as u64;
+        let block = &mut buffer[..hashes.block_len(index) as usize];
+        file.read_exact(block)
+            .with_context(|| format!("Failed to read block {} of {}", index, path.display()))?;
+        let mut hasher = hashes.hash_algorithm.hasher();
+        hasher.write_all(block)?;
+        hashes.blocks.push(DigestEncoding::Hex.encode_as(hashes.hash_algorithm, &hasher.finalize()));
+        report(hashes);
+
+        since_save += block.len() as u64;
+        if since_save >= SAVE_INTERVAL && let Some(state) = state {
+            hashes.save(state)?;
+            since_save = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Blocks that differ between two hash lists of the same block size and algorithm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockChanges {
+    /// Indexes of the blocks of the target that are new or differ from the source
+    pub changed: Vec<u64>,
+    pub changed_bytes: u64,
+    pub source_size: u64,
+    pub target_size: u64,
+    pub block_count: u64,
+}
+
+/// Compare the block hashes of two versions of a file
+pub fn compare_blocks(source: &BlockHashes, target: &BlockHashes) -> Result<BlockChanges> {
+    ensure!(source.block_size == target.block_size, "Block sizes differ: {} and {}", source.block_size, target.block_size);
+    ensure!(
+        source.hash_algorithm == target.hash_algorithm,
+        "Hash algorithms differ: {} and {}",
+        source.hash_algorithm,
+        target.hash_algorithm
+    );
+    ensure!(source.is_complete() && target.is_complete(), "Block hashes are incomplete");
+    let changed: Vec<u64> = (0..target.block_count())
+        .filter(|&index| {
+            source.block_len(index) != target.block_len(index) || source.blocks.get(index as usize) != target.blocks.get(index as usize)
+        })
+        .collect();
+    let changed_bytes = changed.iter().map(|&index| target.block_len(index)).sum();
+    Ok(BlockChanges { changed, changed_bytes, source_size: source.size, target_size: target.size, block_count: target.block_count() })
+}
+
+/// What a block delta file was made from, stored at its start
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockDeltaHeader {
+    pub block_size: u64,
+    pub hash_algorithm: HashAlgorithm,
+    pub source_size: u64,
+    /// `BlockHashes::digest` of the file the delta applies to
+    pub source_digest: String,
+    pub target_size: u64,
+    /// `BlockHashes::digest` of the file the delta produces
+    pub target_digest: String,
+    pub blocks: u64,
+}
+
+/// Write the changed blocks of `target` (hashed as `target_hashes`) to `output`
+///
+/// The file starts with `DPBLOCK1`, the length of the JSON `BlockDeltaHeader` as a
+/// little-endian u32 and the header, followed by each block as its index and length
+/// (little-endian u64s) and its bytes. Blocks are copied one at a time, so the delta of
+/// a file much larger than memory can be written. Returns the size of the delta.
+pub fn write_block_delta(target: &Path, source_hashes: &BlockHashes, target_hashes: &BlockHashes, output: &Path) -> Result<u64> {
+    let changes = compare_blocks(source_hashes, target_hashes)?;
+    let header = BlockDeltaHeader {
+        block_size: target_hashes.block_size,
+        hash_algorithm: target_hashes.hash_algorithm,
+        source_size: source_hashes.size,
+        source_digest: source_hashes.digest(),
+        target_size: target_hashes.size,
+        target_digest: target_hashes.digest(),
+        blocks: changes.changed.len() as u64,
+    };
+    let header_json = serde_json::to_vec(&header).context("Failed to serialize block delta header")?;
+
+    let staged = StagingFile::new(output)?;
+    let write_error = || format!("Failed to write block delta: {}", output.display());
+    let mut writer = BufWriter::new(File::create(staged.path()).with_context(write_error)?);
+    writer.write_all(MAGIC).with_context(write_error)?;
+    writer.write_all(&(header_json.len() as u32).to_le_bytes()).with_context(write_error)?;
+    writer.write_all(&header_json).with_context(write_error)?;
+
+    let mut file = File::open(target).with_context(|| format!("Failed to open file: {}", target.display()))?;
+    let mut buffer = vec![0; target_hashes.block_size as usize];
+    for &index in &changes.changed {
+        interrupt::check()?;
+        let len = target_hashes.block_len(index);
+        let block = &mut buffer[..len as usize];
+        file.seek(SeekFrom::Start(index * target_hashes.block_size))
+            .and_then(|_| file.read_exact(block))
+            .with_context(|| format!("Failed to read block {} of {}", index, target.display()))?;
+        writer.write_all(&index.to_le_bytes()).with_context(write_error)?;
+        writer.write_all(&len.to_le_bytes()).with_context(write_error)?;
+        writer.write_all(block).with_context(write_error)?;
+    }
+    writer.flush().with_context(write_error)?;
+    drop(writer);
+    staged.persist()?;
+    Ok(fs::metadata(output).map_or(0, |metadata| metadata.len()))
+}
+
+/// Read the header of a block delta file
+pub fn read_block_delta_header(delta: &Path) -> Result<BlockDeltaHeader> {
+    let mut reader = BufReader::new(File::open(delta).with_context(|| format!("Failed to open block delta: {}", delta.display()))?);
+    read_header(&mut reader).with_context(|| format!("Failed to read block delta: {}", delta.display()))
+}
+
+fn read_header(reader: &mut impl Read) -> Result<BlockDeltaHeader> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    ensure!(&magic == MAGIC, "Not a block delta");
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut json = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut json)?;
+    let header: BlockDeltaHeader = serde_json::from_slice(&json)?;
+    ensure!(header.block_size > 0 && header.block_size <= MAX_BLOCK_SIZE, "Invalid block size {}", header.block_size);
+    Ok(header)
+}
+
+/// Result of `apply_block_delta`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockApplySummary {
+    pub blocks: u64,
+    pub bytes: u64,
+    pub size: u64,
+}
+
+/// Write the blocks of `delta` into `file` in place and resize it to the target size
+///
+/// The file is changed in place rather than copied, as copying an image of hundreds of
+/// gigabytes would take as long as sending it, so a failure part way leaves a mix of
+/// both versions; applying the same delta again completes it. With `check_source`, the
+/// file is hashed first and must be the version the delta was made from (or already the
+/// target version, which is left alone).
+pub fn apply_block_delta(file: &Path, delta: &Path, check_source: Option<&BlockOptions>) -> Result<BlockApplySummary> {
+    let mut reader = BufReader::new(File::open(delta).with_context(|| format!("Failed to open block delta: {}", delta.display()))?);
+    let header = read_header(&mut reader).with_context(|| format!("Failed to read block delta: {}", delta.display()))?;
+
+    if let Some(options) = check_source {
+        let options = options.clone().block_size(header.block_size).hash_algorithm(header.hash_algorithm);
+        let current = hash_blocks(file, &options)?.digest();
+        if current == header.target_digest {
+            println!("{}", tr!("blocks.already_target", path = file.display()));
+            return Ok(BlockApplySummary { blocks: 0, bytes: 0, size: header.target_size });
+        }
+        ensure!(current == header.source_digest, "{} is not the version the block delta was made from", file.display());
+    } else {
+        let size = fs::metadata(file).with_context(|| format!("Failed to read metadata: {}", file.display()))?.len();
+        ensure!(
+            size == header.source_size || size == header.target_size,
+            "{} has {} bytes, but the block delta was made for {} bytes",
+            file.display(),
+            size,
+            header.source_size
+        );
+    }
+
+    let mut out = OpenOptions::new().write(true).open(file).with_context(|| format!("Failed to open file for writing: {}", file.display()))?;
+    let read_error = || format!("Failed to read block delta: {}", delta.display());
+    let mut buffer = vec![0; header.block_size as usize];
+    let mut bytes = 0;
+    for _ in 0..header.blocks {
+        interrupt::check()?;
+        let mut record = [0; 16];
+        reader.read_exact(&mut record).with_context(read_error)?;
+        let index = u64::from_le_bytes(record[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(record[8..].try_into().unwrap());
+        let offset = index.checked_mul(header.block_size).filter(|offset| len <= header.block_size && offset + len <= header.target_size);
+        let offset = offset.ok_or_else(|| anyhow!("Invalid block {} in block delta: {}", index, delta.display()))?;
+        let block = &mut buffer[..len as usize];
+        reader.read_exact(block).with_context(read_error)?;
+        out.seek(SeekFrom::Start(offset))
+            .and_then(|_| out.write_all(block))
+            .with_context(|| format!("Failed to write block {} of {}", index, file.display()))?;
+        bytes += len;
+    }
+    out.set_len(header.target_size).with_context(|| format!("Failed to resize file: {}", file.display()))?;
+    out.sync_all().with_context(|| format!("Failed to flush file: {}", file.display()))?;
+    Ok(BlockApplySummary { blocks: header.blocks, bytes, size: header.target_size })
+}
@@ -0,0 +1,239 @@
+use crate::compression;
+use crate::diff::{DiffReport, DiffType, FileInfo};
+use crate::fsops::FileMode;
+use crate::paths::normalize_separators;
+use crate::runid;
+use crate::sink::DiffSink;
+use crate::tar::{TarEntry, TarWriter};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+/// Name of the manifest at the root of every bundle
+pub const BUNDLE_MANIFEST: &str = "bundle-manifest.json";
+
+/// Directory of the bundle holding the added and modified files, under their paths in
+/// the target tree
+pub const BUNDLE_FILES_DIR: &str = "files";
+
+const BUNDLE_VERSION: u32 = 1;
+
+/// Archive format of a bundle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BundleFormat {
+    /// ZIP with Deflate, readable everywhere without extra tools
+    #[default]
+    Zip,
+    /// POSIX tar compressed with Zstandard (`.tar.zst`), smaller and faster
+    TarZst,
+}
+
+impl BundleFormat {
+    /// Format matching the extension of `path` (`.zip`, `.tar.zst` or `.tzst`), if any
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(BundleFormat::Zip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(BundleFormat::TarZst)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for BundleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BundleFormat::Zip => "zip",
+            BundleFormat::TarZst => "tar.zst",
+        })
+    }
+}
+
+impl FromStr for BundleFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "zip" => Ok(BundleFormat::Zip),
+            "tar.zst" | "tar-zst" | "tzst" => Ok(BundleFormat::TarZst),
+            _ => Err(anyhow!("Unknown bundle format: {} (expected zip or tar.zst)", value)),
+        }
+    }
+}
+
+/// What a bundle changes, saved as `BUNDLE_MANIFEST` inside it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub version: u32,
+    /// Run that wrote the bundle (see `runid::current`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// Files to write, stored under `BUNDLE_FILES_DIR`
+    pub files: Vec<FileInfo>,
+    /// Paths to delete; includes the old paths of renamed files
+    pub removed: Vec<PathBuf>,
+}
+
+/// Writes the added and modified files of a comparison, read from the target tree, into
+/// a single archive for distribution
+///
+/// Unlike a patch the bundle is not executable and holds whole files (no diff patches or
+/// binary deltas), so it can be unpacked with standard tools over the source tree;
+/// deleting the files listed as `removed` in its manifest completes the update.
+/// Renamed files are stored under their new path and their old path is removed.
+pub struct BundleWriter {
+    target_dir: PathBuf,
+    path: PathBuf,
+    archive: Option<Archive>,
+    manifest: BundleManifest,
+    bytes: u64,
+}
+
+enum Archive {
+    Zip(Box<ZipWriter<BufWriter<File>>>, SimpleFileOptions),
+    TarZst(TarWriter<zstd::stream::write::Encoder<'static, BufWriter<File>>>),
+}
+
+impl fmt::Debug for BundleWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BundleWriter").field("path", &self.path).field("files", &self.manifest.files.len()).finish()
+    }
+}
+
+impl BundleWriter {
+    /// Create the bundle at `path`; files are read from `target_dir` as they are delivered
+    ///
+    /// `level` is the Deflate (0-9) or Zstandard (1-22) level, by default 6 and 19.
+    pub fn create(path: impl Into<PathBuf>, target_dir: impl Into<PathBuf>, format: BundleFormat, level: Option<i32>) -> Result<Self> {
+        let path = path.into();
+        let file = File::create(&path).with_context(|| format!("Failed to create bundle: {}", path.display()))?;
+        let writer = BufWriter::new(file);
+        let archive = match format {
+            BundleFormat::Zip => {
+                let options = SimpleFileOptions::default()
+                    .compression_method(zip::CompressionMethod::Deflated)
+                    .compression_level(level.map(i64::from));
+                Archive::Zip(Box::new(ZipWriter::new(writer)), options)
+            }
+            BundleFormat::TarZst => {
+                let encoder = zstd::stream::write::Encoder::new(writer, level.unwrap_or(19))
+                    .context("Failed to start Zstandard compression")?;
+                Archive::TarZst(TarWriter::new(encoder))
+            }
+        };
+        Ok(BundleWriter {
+            target_dir: target_dir.into(),
+            path,
+            archive: Some(archive),
+            manifest: BundleManifest { version: BUNDLE_VERSION, run_id: Some(runid::current().to_string()), ..BundleManifest::default() },
+            bytes: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Files and bytes stored so far
+    pub fn stored(&self) -> (usize, u64) {
+        (self.manifest.files.len(), self.bytes)
+    }
+
+    pub fn manifest(&self) -> &BundleManifest {
+        &self.manifest
+    }
+
+    fn add(&mut self, info: &FileInfo) -> Result<()> {
+        let relative = normalize_separators(&info.relative_path);
+        let name = format!("{}/{}", BUNDLE_FILES_DIR, relative.to_string_lossy());
+        let full_path = self.target_dir.join(&info.relative_path);
+        let archive = self.archive.as_mut().ok_or_else(|| anyhow!("Bundle is already finished"))?;
+        let mode = match info.mode {
+            Some(FileMode::Unix(mode)) => mode,
+            _ => 0o644,
+        };
+        if let Some(target) = &info.link_target {
+            let target = normalize_separators(target).to_string_lossy().into_owned();
+            match archive {
+                Archive::Zip(zip, options) => zip.add_symlink(name.as_str(), target, *options)?,
+                Archive::TarZst(tar) => tar.append(&name, TarEntry::Symlink(&target), 0o777, 0)?,
+            }
+        } else {
+            let mut file = File::open(&full_path).with_context(|| format!("Failed to open file for bundle: {}", full_path.display()))?;
+            let metadata = file.metadata()?;
+            match archive {
+                Archive::Zip(zip, options) => {
+                    zip.start_file(name.as_str(), options.unix_permissions(mode).large_file(compression::needs_zip64(metadata.len())))?;
+                    io::copy(&mut file, zip).with_context(|| format!("Failed to read file for bundle: {}", full_path.display()))?;
+                }
+                Archive::TarZst(tar) => {
+                    let mtime = metadata.modified().ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |age| age.as_secs());
+                    tar.append(&name, TarEntry::File(&mut file, metadata.len()), mode, mtime)
+                        .with_context(|| format!("Failed to read file for bundle: {}", full_path.display()))?;
+                }
+            }
+            self.bytes += metadata.len();
+        }
+        let mut entry = info.clone();
+        entry.relative_path = relative;
+        self.manifest.files.push(entry);
+        Ok(())
+    }
+}
+
+impl DiffSink for BundleWriter {
+    fn on_added(&mut self, info: &FileInfo) -> Result<()> {
+        self.add(info)
+    }
+
+    fn on_modified(&mut self, diff: &DiffType) -> Result<()> {
+        match diff {
+            DiffType::Modified(info) => self.add(info),
+            // Bundles hold whole files; take the rest of the entry from the target tree
+            DiffType::ModifiedDiff(file_diff) => {
+                let full_path = self.target_dir.join(&file_diff.relative_path);
+                let size = fs::metadata(&full_path).with_context(|| format!("Failed to read metadata: {}", full_path.display()))?.len();
+                let info = FileInfo { mode: file_diff.mode, tags: file_diff.tags.clone(), ..FileInfo::new(file_diff.relative_path.clone(), file_diff.hash.clone(), size) };
+                self.add(&info)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn on_removed(&mut self, path: &Path) -> Result<()> {
+        self.manifest.removed.push(normalize_separators(path));
+        Ok(())
+    }
+
+    fn on_metadata_changed(&mut self, info: &FileInfo) -> Result<()> {
+        self.add(info)
+    }
+
+    fn on_finish(&mut self, _report: &DiffReport) -> Result<()> {
+        let manifest = serde_json::to_vec_pretty(&self.manifest)?;
+        match self.archive.take() {
+            Some(Archive::Zip(mut zip, options)) => {
+                zip.start_file(BUNDLE_MANIFEST, options)?;
+                zip.write_all(&manifest)?;
+                (*zip).finish()?.flush()?;
+            }
+            Some(Archive::TarZst(mut tar)) => {
+                tar.append(BUNDLE_MANIFEST, TarEntry::File(&mut manifest.as_slice(), manifest.len() as u64), 0o644, 0)?;
+                tar.finish()?.finish()?.flush()?;
+            }
+            None => return Err(anyhow!("Bundle is already finished")),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use crate::compression::{self, CompressionSettings};
+use crate::paths;
+use crate::diff::HashAlgorithm;
+use crate::digest;
+use crate::fsops;
+use crate::staging::{self, StagingFile};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::read::ZipFile;
+use zip::{DateTime, ZipArchive, ZipWriter};
+
+/// Object cache used when none is given explicitly
+pub const CACHE_DIR_VAR: &str = "DIFFPATCH_OBJECT_CACHE";
+
+/// The cache directory to use: an explicit one, else `DIFFPATCH_OBJECT_CACHE` if set
+pub fn cache_dir(explicit: Option<&Path>) -> Option<PathBuf> {
+    explicit
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(CACHE_DIR_VAR).filter(|dir| !dir.is_empty()).map(PathBuf::from))
+}
+
+/// Which run used the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheUser {
+    Create,
+    Apply,
+}
+
+/// Lookups that found their object in the cache, and those that did not
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HitCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl HitCounts {
+    /// Share of lookups that were hits, `None` before the first lookup
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+/// Hit counts of all runs so far, kept in `stats.json`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheCounters {
+    #[serde(default)]
+    pub create: HitCounts,
+    #[serde(default)]
+    pub apply: HitCounts,
+}
+
+/// Objects one patch creation or apply used, kept in `releases/`
+///
+/// Garbage collection keeps the objects of the newest releases.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Release {
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    /// Patch file the release was created as or applied from
+    pub name: String,
+    /// Object paths relative to the cache directory, with `/` separators
+    pub objects: Vec<String>,
+}
+
+/// Size of the objects of one kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ObjectUsage {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// What the cache holds, see `ObjectCache::usage`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheUsage {
+    pub blobs: ObjectUsage,
+    /// Packed objects per compression directory, e.g. `zstd-19`
+    pub packed: BTreeMap<String, ObjectUsage>,
+    /// Oldest first
+    pub releases: Vec<Release>,
+    pub counters: CacheCounters,
+}
+
+impl CacheUsage {
+    pub fn total(&self) -> ObjectUsage {
+        self.packed.values().fold(self.blobs, |total, usage| ObjectUsage {
+            count: total.count + usage.count,
+            bytes: total.bytes + usage.bytes,
+        })
+    }
+}
+
+/// What `ObjectCache::collect_garbage` removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcSummary {
+    pub objects: u64,
+    pub bytes: u64,
+    pub releases: u64,
+}
+
+/// Local content-addressable store of file contents, shared across runs
+///
+/// Objects are keyed by SHA-256 digest, whatever encoding the hashes were written in.
+/// `blobs/` holds plain file contents, which applies clone into place instead of
+/// decompressing content they have seen before. `packed/<compression>/` holds the same
+/// contents already compressed, as single-entry ZIP archives that patch creation copies
+/// into new patches without compressing them again.
+#[derive(Debug, Clone)]
+pub struct ObjectCache {
+    root: PathBuf,
+}
+
+impl ObjectCache {
+    /// Use the cache in `root`, creating the directory if needed
+    pub fn open(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root).with_context(|| format!("Failed to create object cache: {}", root.display()))?;
+        Ok(ObjectCache { root: root.to_path_buf() })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the plain contents with `hash` are kept; `None` for empty or invalid hashes
+    pub fn blob_path(&self, hash: &str) -> Option<PathBuf> {
+        Some(sharded(&self.root.join("blobs"), &object_key(hash)?))
+    }
+
+    /// Where the contents with `hash` compressed with `compression` are kept
+    pub fn packed_path(&self, hash: &str, compression: CompressionSettings) -> Option<PathBuf> {
+        // `zstd:19` is not a valid file name on Windows
+        let dir = self.root.join("packed").join(compression.to_string().replace(':', "-"));
+        let mut path = sharded(&dir, &object_key(hash)?);
+        path.set_extension("zip");
+        Some(path)
+    }
+
+    /// Cached plain contents with `hash`, if present with the expected size
+    pub fn find_blob(&self, hash: &str, size: u64) -> Option<PathBuf> {
+        self.blob_path(hash).filter(|path| fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() == size))
+    }
+
+    /// Keep a copy of `source`, whose contents have `hash`; returns whether it was added
+    pub fn store_blob(&self, hash: &str, source: &Path) -> Result<bool> {
+        let Some(path) = self.blob_path(hash).filter(|path| !path.exists()) else {
+            return Ok(false);
+        };
+        create_parent(&path)?;
+        let staged = StagingFile::new(&path)?;
+        fsops::clone_or_copy(source, staged.path())
+            .with_context(|| format!("Failed to store object: {}", path.display()))?;
+        staged.persist()?;
+        Ok(true)
+    }
+
+    /// Cached compressed contents with `hash`, if present
+    pub fn find_packed(&self, hash: &str, compression: CompressionSettings) -> Option<PathBuf> {
+        self.packed_path(hash, compression).filter(|path| path.is_file())
+    }
+
+    /// Keep the compressed data of the archive entry `entry`, whose contents have `hash`;
+    /// returns whether it was added
+    ///
+    /// The stored entry gets a fixed timestamp, so patches built from the cache stay
+    /// reproducible. Entries too large for plain ZIP size fields are not kept.
+    pub fn store_packed(&self, hash: &str, compression: CompressionSettings, entry: ZipFile) -> Result<bool> {
+        if entry.size().max(entry.compressed_size()) > u32::MAX as u64 {
+            return Ok(false);
+        }
+        let Some(path) = self.packed_path(hash, compression).filter(|path| !path.exists()) else {
+            return Ok(false);
+        };
+        create_parent(&path)?;
+        let staged = StagingFile::new(&path)?;
+        let file = File::create(staged.path()).with_context(|| format!("Failed to create object: {}", path.display()))?;
+        let mut zip = ZipWriter::new(BufWriter::new(file));
+        zip.raw_copy_file_touch(entry, DateTime::default(), Some(0o755))
+            .and_then(|_| zip.finish())
+            .and_then(|mut writer| Ok(writer.flush()?))
+            .with_context(|| format!("Failed to store object: {}", path.display()))?;
+        staged.persist()?;
+        Ok(true)
+    }
+
+    /// Remember that a run used `objects` (paths inside the cache) and how many of its
+    /// lookups were hits
+    pub fn record_release(&self, user: CacheUser, name: &str, objects: &[PathBuf], counts: HitCounts) -> Result<()> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut objects: Vec<String> = objects
+            .iter()
+            .filter_map(|path| path.strip_prefix(&self.root).ok())
+            .map(paths::to_portable)
+            .collect();
+        objects.sort();
+        objects.dedup();
+        let release = Release { created: since_epoch.as_secs(), name: name.to_string(), objects };
+        let dir = self.root.join("releases");
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        // Sorts by time; the process id keeps concurrent runs apart
+        let file_name = format!("{:012}-{:09}-{}.json", since_epoch.as_secs(), since_epoch.subsec_nanos(), std::process::id());
+        let json = serde_json::to_vec_pretty(&release).context("Failed to serialize cache release")?;
+        staging::write_atomic(&dir.join(file_name), &json)?;
+
+        let mut counters = self.counters()?;
+        let total = match user {
+            CacheUser::Create => &mut counters.create,
+            CacheUser::Apply => &mut counters.apply,
+        };
+        total.hits += counts.hits;
+        total.misses += counts.misses;
+        let json = serde_json::to_vec_pretty(&counters).context("Failed to serialize cache statistics")?;
+        staging::write_atomic(&self.root.join("stats.json"), &json)
+    }
+
+    /// Hit counts of all runs so far
+    pub fn counters(&self) -> Result<CacheCounters> {
+        let path = self.root.join("stats.json");
+        if !path.exists() {
+            return Ok(CacheCounters::default());
+        }
+        let data = fs::read(&path).with_context(|| format!("Failed to read cache statistics: {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse cache statistics: {}", path.display()))
+    }
+
+    /// Recorded releases, oldest first, with the files they are kept in
+    fn releases(&self) -> Result<Vec<(PathBuf, Release)>> {
+        let dir = self.root.join("releases");
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return Ok(Vec::new());
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|path| {
+                let data = fs::read(&path).with_context(|| format!("Failed to read cache release: {}", path.display()))?;
+                let release = serde_json::from_slice(&data)
+                    .with_context(|| format!("Failed to parse cache release: {}", path.display()))?;
+                Ok((path, release))
+            })
+            .collect()
+    }
+
+    /// Every object file in the cache, as (path relative to the cache, size)
+    fn objects(&self) -> Vec<(String, u64)> {
+        ["blobs", "packed"]
+            .iter()
+            .flat_map(|dir| walkdir::WalkDir::new(self.root.join(dir)).into_iter().filter_map(Result::ok))
+            .filter(|entry| entry.file_type().is_file() && is_object_name(&entry.file_name().to_string_lossy()))
+            .filter_map(|entry| {
+                let size = entry.metadata().ok()?.len();
+                let relative = entry.path().strip_prefix(&self.root).ok()?;
+                Some((paths::to_portable(relative), size))
+            })
+            .collect()
+    }
+
+    /// Count and size of the cached objects, the recorded releases and the hit counts
+    pub fn usage(&self) -> Result<CacheUsage> {
+        let mut usage = CacheUsage {
+            releases: self.releases()?.into_iter().map(|(_, release)| release).collect(),
+            counters: self.counters()?,
+            ..CacheUsage::default()
+        };
+        for (path, size) in self.objects() {
+            let kind = match path.strip_prefix("packed/").and_then(|rest| rest.split_once('/')) {
+                Some((compression, _)) => usage.packed.entry(compression.to_string()).or_default(),
+                None => &mut usage.blobs,
+            };
+            kind.count += 1;
+            kind.bytes += size;
+        }
+        Ok(usage)
+    }
+
+    /// Forget all but the newest `keep_releases` releases and remove the objects none of
+    /// the remaining releases uses
+    ///
+    /// Objects being written by a running process are left alone.
+    pub fn collect_garbage(&self, keep_releases: usize) -> Result<GcSummary> {
+        let mut summary = GcSummary::default();
+        let releases = self.releases()?;
+        let first_kept = releases.len().saturating_sub(keep_releases);
+        for (path, _) in &releases[..first_kept] {
+            fs::remove_file(path).with_context(|| format!("Failed to remove cache release: {}", path.display()))?;
+            summary.releases += 1;
+        }
+        let referenced: HashSet<&str> =
+            releases[first_kept..].iter().flat_map(|(_, release)| release.objects.iter().map(String::as_str)).collect();
+
+        for (relative, size) in self.objects() {
+            if referenced.contains(relative.as_str()) {
+                continue;
+            }
+            let path = self.root.join(&relative);
+            fs::remove_file(&path).with_context(|| format!("Failed to remove object: {}", path.display()))?;
+            summary.objects += 1;
+            summary.bytes += size;
+            // Drop the shard directory once it is empty; fails harmlessly otherwise
+            if let Some(parent) = path.parent() {
+                staging::remove_stale(parent);
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Whether `name` is the file name of a blob (`<hex>`) or packed object (`<hex>.zip`)
+fn is_object_name(name: &str) -> bool {
+    let key = name.strip_suffix(".zip").unwrap_or(name);
+    key.len() == digest::DIGEST_LEN * 2 && key.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Add the compressed entry of the packed object at `object` to `zip` as `name`,
+/// without decompressing it
+pub fn copy_packed<W: Write + Seek>(object: &Path, zip: &mut ZipWriter<W>, name: &str) -> Result<()> {
+    let file = File::open(object).with_context(|| format!("Failed to open object: {}", object.display()))?;
+    let mut archive = ZipArchive::new(file).with_context(|| format!("Failed to read object: {}", object.display()))?;
+    let entry = archive.by_index(0).with_context(|| format!("Failed to read object: {}", object.display()))?;
+    compression::copy_zip_entry(zip, entry, name)
+        .with_context(|| format!("Failed to copy object into the archive: {}", name))
+}
+
+/// Hex digest naming the object of `hash`, followed by the algorithm unless it is
+/// SHA-256 (so objects cached before other algorithms existed keep their names)
+fn object_key(hash: &str) -> Option<String> {
+    if hash.is_empty() {
+        return None;
+    }
+    let (algorithm, digest) = digest::decode_any(hash).ok()?;
+    let key = digest::DigestEncoding::Hex.encode(&digest);
+    Some(match algorithm {
+        HashAlgorithm::Sha256 => key,
+        _ => format!("{}-{}", key, algorithm),
+    })
+}
+
+/// `dir/ab/abcdef...`, so no directory holds too many objects
+fn sharded(dir: &Path, key: &str) -> PathBuf {
+    dir.join(&key[..2]).join(key)
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display())),
+        None => Ok(()),
+    }
+}
@@ -1,4 +1,20 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use diffpatch::bundle::BundleFormat;
+use diffpatch::cache;
+use diffpatch::compression::{CodecSettings, CompressionSettings};
+use diffpatch::container::PatchFormat;
+use diffpatch::digest::DigestEncoding;
+use diffpatch::diff::{CollisionPolicy, CompareStrategy, ErrorPolicy, HashAlgorithm, UnchangedVerification};
+use diffpatch::i18n::Language;
+use diffpatch::filter::{FilterList, FilterRule, RuleKind};
+use diffpatch::condition;
+use diffpatch::paths::PathOrder;
+use diffpatch::patch::{self, ApplyOptions, ApplyStrategy, ConditionalFiles, ModeRule, PlatformFiles};
+use diffpatch::portability::WINDOWS_MAX_PATH;
+use diffpatch::sink::{ChangeListFormat, LineStyle};
+use diffpatch::units::{ByteSize, Percent, Rate, Span};
+use diffpatch::warning::WarningKind;
+use diffpatch::workflow::Workflow;
 use std::path::PathBuf;
 
 /// File Diff Extractor - Compare directories and create executable patches
@@ -7,49 +23,1108 @@ use std::path::PathBuf;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Language of the output: en or zh (default: system locale)
+    #[arg(long, global = true, value_name = "LANG")]
+    pub lang: Option<Language>,
+
+    /// Ignore the system, user and project configuration files
+    #[arg(long, global = true)]
+    pub no_config: bool,
+
+    /// ID of this run, written into the reports, manifests, patches, bundles and verification records it produces (default: DIFFPATCH_RUN_ID, else a new random UUID)
+    #[arg(long, global = true, value_name = "ID")]
+    pub run_id: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Create a patch file
-    Create {
-        /// Source directory path
+    Create(Box<CreateArgs>),
+
+    /// List the differences between two trees without creating a patch
+    ///
+    /// The list goes to standard output; with `--format json` or `csv` nothing else does,
+    /// so the output can be read by build scripts.
+    Diff {
+        /// Source directory; a patch file, a saved manifest (also as an http(s) or s3:// URL), a remote directory as sftp://[USER@]HOST/PATH, a disk image or a ZIP archive also works
+        #[arg(short, long, value_name = "DIR")]
+        source: PathBuf,
+
+        /// Target directory
+        #[arg(short, long, value_name = "DIR")]
+        target: PathBuf,
+
+        /// Output format: text, json or csv
+        #[arg(long, value_enum, value_name = "FORMAT", default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// What each text line shows: paths (only the path), short (kind, path and size) or full (also old and new sizes, hashes and permissions)
+        #[arg(long, value_enum, value_name = "STYLE", default_value_t = OutputStyle::Short)]
+        output_style: OutputStyle,
+
+        /// Hash function: sha256, blake3 or xxh3 (default: sha256)
+        #[arg(long, value_name = "ALGORITHM")]
+        hash_algorithm: Option<HashAlgorithm>,
+
+        /// Number of threads for hashing
+        #[arg(long, value_name = "N")]
+        threads: Option<usize>,
+
+        /// Record removed/added files with the same content and size as moves
+        #[arg(long)]
+        detect_renames: bool,
+
+        /// Keep comparison results in DIR and reuse them when the same trees are compared again with the same options
+        #[arg(long, value_name = "DIR")]
+        diff_cache: Option<PathBuf>,
+
+        /// Remember file hashes in DIR and only hash new or touched files on later scans (default: DIFFPATCH_HASH_CACHE)
+        #[arg(long, value_name = "DIR")]
+        hash_cache: Option<PathBuf>,
+
+        /// Keep watching the target directory and print each change of the differences as it happens, until Ctrl-C
+        #[arg(long, conflicts_with_all = ["detect_renames", "diff_cache"])]
+        watch: bool,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+
+    /// Write the changed files of two trees and a list of removed files to one archive
+    ///
+    /// The archive holds whole files under `files/` and a `bundle-manifest.json`
+    /// listing the written and removed paths, for unpacking with standard tools.
+    Bundle {
+        /// Source directory; a patch file, a saved manifest (also as an http(s) or s3:// URL), a remote directory as sftp://[USER@]HOST/PATH, a disk image or a ZIP archive also works
         #[arg(short, long, value_name = "DIR")]
         source: PathBuf,
 
-        /// Target directory path
+        /// Target directory, from which the changed files are read
         #[arg(short, long, value_name = "DIR")]
         target: PathBuf,
 
-        /// Output patch file path
+        /// Output archive, also as an s3://BUCKET/KEY object or an http(s) URL taking a PUT
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        /// Archive format: zip or tar.zst (default: from the output extension, else zip)
+        #[arg(long, value_name = "FORMAT")]
+        bundle_format: Option<BundleFormat>,
+
+        /// Compression level: 0-9 for zip, 1-22 for tar.zst (default: 6 and 19)
+        #[arg(long, value_name = "N")]
+        level: Option<i32>,
+
+        /// Hash function: sha256, blake3 or xxh3 (default: sha256)
+        #[arg(long, value_name = "ALGORITHM")]
+        hash_algorithm: Option<HashAlgorithm>,
+
+        /// Number of threads for hashing
+        #[arg(long, value_name = "N")]
+        threads: Option<usize>,
+
+        /// Store files moved with unchanged content under their new path and remove the old one
+        #[arg(long)]
+        detect_renames: bool,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+
+    /// Re-package a patch as a patch executable, a plain zip or a tar.zst, without comparing the trees again
+    ///
+    /// The patch manifest is copied byte for byte, so verification records keep
+    /// matching; `apply --patch-data` takes all three formats.
+    Convert {
+        /// Patch to convert, in any of the formats
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Converted patch, also as an s3://BUCKET/KEY object or an http(s) URL taking a PUT
         #[arg(short, long, value_name = "FILE")]
         output: PathBuf,
 
-        /// Verification file list (comma-separated relative paths)
-        #[arg(short, long, value_name = "FILES", value_delimiter = ',')]
-        check_files: Vec<String>,
+        /// Format to write: exe, zip or tar.zst (default: from the output extension)
+        #[arg(long, value_name = "FORMAT")]
+        format: Option<PatchFormat>,
+
+        /// Program a patch executable is built from: a diffpatch program (e.g. for another platform) or another patch executable (default: the patch's own program, else this one)
+        #[arg(long, value_name = "FILE")]
+        program: Option<PathBuf>,
+
+        /// Compression of the content taken from a tar.zst: stored, deflate, bzip2, zstd or xz, with an optional level (default: deflate)
+        #[arg(long, value_name = "METHOD[:LEVEL]", default_value = "deflate")]
+        compression: CompressionSettings,
+
+        /// Zstandard level of a tar.zst output, 1-22 (default: 19)
+        #[arg(long, value_name = "N")]
+        level: Option<i32>,
+    },
+
+    /// Compare two saved diff reports (e.g. to check that runs are reproducible)
+    CompareReports {
+        /// First report file
+        #[arg(value_name = "FIRST")]
+        first: PathBuf,
 
-        /// Exclude file extensions (comma-separated, e.g., .tmp,.bak,.log)
-        #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
-        exclude_extensions: Option<Vec<String>>,
+        /// Second report file
+        #[arg(value_name = "SECOND")]
+        second: PathBuf,
 
-        /// Exclude directories (comma-separated relative paths, e.g., node_modules,dist,target)
-        #[arg(long, value_name = "DIRECTORIES", value_delimiter = ',')]
-        exclude_dirs: Option<Vec<String>>,
-        
-        /// Use file difference patches instead of storing full files (default: false)
-        #[arg(long, default_value = "true")]
-        use_diff_patches: bool,
+        /// Order of the listed entries: bytewise, case-insensitive or natural (file2 before file10)
+        #[arg(long, value_enum, value_name = "ORDER", default_value_t = SortOrder::Bytewise)]
+        sort: SortOrder,
+    },
+
+    /// Write the files to download, reuse and delete to update one tree to another, as JSON
+    DownloadPlan {
+        /// Tree the client has: a directory, a patch file (its target manifest), a saved manifest (also as an http(s) or s3:// URL), a remote directory as sftp://[USER@]HOST/PATH, a disk image or a ZIP archive
+        #[arg(short, long, value_name = "DIR|PATCH")]
+        local: PathBuf,
+
+        /// Tree to update to: a directory, a patch file (its target manifest), a saved manifest (also as an http(s) or s3:// URL), a remote directory as sftp://[USER@]HOST/PATH, a disk image or a ZIP archive
+        #[arg(short, long, value_name = "DIR|PATCH")]
+        target: PathBuf,
+
+        /// Output JSON file
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        /// Hash function for scanned directories: sha256, blake3 or xxh3; saved manifests and patches must use the same one (default: sha256)
+        #[arg(long, value_name = "ALGORITHM")]
+        hash_algorithm: Option<HashAlgorithm>,
+    },
+
+    /// Apply a saved diff report to a directory, copying added and modified files from the target tree
+    ///
+    /// Every written file is checked against the hash in the report; removed files are
+    /// deleted, renamed files moved and recorded permissions set.
+    ApplyReport {
+        /// Report saved with `create --save-report`
+        #[arg(value_name = "REPORT")]
+        report: PathBuf,
+
+        /// Directory holding the added and modified files, usually the target tree the report was made from
+        #[arg(short, long, value_name = "DIR")]
+        payload: PathBuf,
+
+        /// Directory to update, holding the source tree of the report
+        #[arg(short, long, value_name = "DIR")]
+        dir: PathBuf,
+    },
+
+    /// Check the signature of a verification record written by `apply --verification-record`
+    VerifyRecord {
+        #[arg(value_name = "RECORD")]
+        record: PathBuf,
+
+        /// Key file the record was signed with
+        #[arg(long, value_name = "FILE")]
+        signing_key: Option<PathBuf>,
+    },
+
+    /// Save the files of a directory with their hashes and sizes as a manifest
+    ///
+    /// The manifest can later replace the directory as `create --source` or in
+    /// `download-plan`, after the directory itself is gone.
+    Manifest {
+        /// Directory to scan, also on another machine as sftp://[USER@]HOST/PATH
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+
+        /// Output JSON file, also as an s3://BUCKET/KEY object or an http(s) URL taking a PUT
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        /// How to write the hashes: hex, base64 or base32 (default: hex)
+        #[arg(long, value_name = "ENCODING")]
+        digest_encoding: Option<DigestEncoding>,
+
+        /// Hash function: sha256, blake3 or xxh3 (default: sha256)
+        #[arg(long, value_name = "ALGORITHM")]
+        hash_algorithm: Option<HashAlgorithm>,
+
+        /// Number of threads for hashing
+        #[arg(long, value_name = "N")]
+        threads: Option<usize>,
+
+        /// What to do with files and directories that cannot be read
+        #[arg(long, value_enum, value_name = "POLICY", default_value_t = OnReadError::Skip)]
+        on_read_error: OnReadError,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+
+    /// Keep the scan snapshot of a directory current as files change (Linux and macOS)
+    ///
+    /// While this runs, `create --snapshot-dir` with the same snapshot directory takes the
+    /// directory's files and hashes from the snapshot instead of scanning it.
+    Watch {
+        /// Directory to watch
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+
+        /// Snapshot directory, as passed to `create --snapshot-dir`
+        #[arg(long, value_name = "DIR")]
+        snapshot_dir: PathBuf,
+
+        /// Hash function, as passed to `create --hash-algorithm` (default: sha256)
+        #[arg(long, value_name = "ALGORITHM")]
+        hash_algorithm: Option<HashAlgorithm>,
+
+        /// Number of threads for hashing
+        #[arg(long, value_name = "N")]
+        threads: Option<usize>,
+    },
+
+    /// Show which filter rule, hidden-file check or size limit keeps or leaves out a path
+    Explain {
+        /// Path to check, relative to DIR (or a path inside DIR)
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+
+        /// Directory the path belongs to, used to read the file size
+        #[arg(short, long, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+
+    /// Show the configuration files that are read, and the settings they give
+    Config,
+
+    /// Print the content hashes of files as used in manifests, reading stdin for `-`
+    Hash {
+        /// Files to hash; `-` reads standard input
+        #[arg(value_name = "FILE", default_value = "-")]
+        files: Vec<PathBuf>,
+
+        /// How to write the hashes: hex, base64 or base32
+        #[arg(long, value_name = "ENCODING", default_value = "hex")]
+        encoding: DigestEncoding,
+
+        /// Hash function: sha256, blake3 or xxh3 (default: sha256)
+        #[arg(long, value_name = "ALGORITHM")]
+        hash_algorithm: Option<HashAlgorithm>,
+    },
+
+    /// Show or prune the local object cache (see `create --object-cache`)
+    Cache {
+        /// Object cache directory (default: DIFFPATCH_OBJECT_CACHE)
+        #[arg(long, value_name = "DIR", global = true)]
+        object_cache: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+
+    /// Show or clear the remembered file hashes (see `create --hash-cache`)
+    HashCache {
+        /// Hash cache directory (default: DIFFPATCH_HASH_CACHE)
+        #[arg(long, value_name = "DIR", global = true)]
+        hash_cache: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: HashCacheCommand,
+    },
+
+    /// Hash, compare and patch a few very large files (disk images, databases) block by block
+    Blocks {
+        #[command(subcommand)]
+        command: BlocksCommand,
+    },
+
+    /// Show the local usage statistics: recent runs and how their durations grew with the trees
+    ///
+    /// Statistics are only kept when enabled with DIFFPATCH_STATS or the `stats_file`
+    /// setting, and never leave the machine.
+    Stats {
+        /// Statistics file (default: the `stats_file` setting, else DIFFPATCH_STATS)
+        #[arg(long, value_name = "FILE")]
+        stats_file: Option<PathBuf>,
+
+        /// Only show runs of this command, e.g. create
+        #[arg(long, value_name = "COMMAND")]
+        command: Option<String>,
+
+        /// Number of recent runs to list
+        #[arg(long, value_name = "COUNT", default_value_t = 20)]
+        last: usize,
+
+        /// Only show runs started within this time, e.g. 7d or 12h
+        #[arg(long, value_name = "DURATION")]
+        since: Option<Span>,
     },
 
     /// Apply patch (typically called by the generated patch program)
     Apply {
-        /// Patch data file path
+        /// Patch file: a patch executable, or a patch converted to zip or tar.zst
         #[arg(short, long, value_name = "FILE")]
         patch_data: PathBuf,
+
+        #[command(flatten)]
+        options: ApplyArgs,
+    },
+}
+
+/// Subcommands of the `cache` command
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Forget all but the newest releases and remove the objects none of them uses
+    Gc {
+        /// Number of most recent releases (patch creations and applies) whose objects are kept
+        #[arg(long, value_name = "N")]
+        keep_releases: usize,
+    },
+
+    /// Show the size of the cache, its releases and hit rates
+    Stats,
+}
+
+/// Subcommands of the `hash-cache` command
+#[derive(Subcommand, Debug)]
+pub enum HashCacheCommand {
+    /// Show where the cache is and which directories it remembers
+    List,
+
+    /// Forget the remembered hashes, so the next scan hashes every file again
+    Clear {
+        /// Only forget the hashes of this directory
+        #[arg(value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+}
+
+/// Subcommands of the `blocks` command
+#[derive(Subcommand, Debug)]
+pub enum BlocksCommand {
+    /// Hash a file block by block and save the hashes, to compare a later version against
+    Hash {
+        /// File to hash
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Where to save the block hashes
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        options: BlockArgs,
+    },
+
+    /// Write the blocks of TARGET that differ from SOURCE to a block delta
+    Diff {
+        /// Old version of the file, or its block hashes with --from-hashes
+        #[arg(value_name = "SOURCE")]
+        source: PathBuf,
+
+        /// New version of the file
+        #[arg(value_name = "TARGET")]
+        target: PathBuf,
+
+        /// Where to write the block delta
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        /// SOURCE is a block hashes file saved by `blocks hash` or --save-hashes
+        #[arg(long)]
+        from_hashes: bool,
+
+        /// Also save the block hashes of TARGET, to make the next delta from
+        #[arg(long, value_name = "FILE")]
+        save_hashes: Option<PathBuf>,
+
+        #[command(flatten)]
+        options: BlockArgs,
     },
+
+    /// Write the blocks of a block delta into FILE, in place
+    Apply {
+        /// File to update
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Block delta written by `blocks diff`
+        #[arg(value_name = "DELTA")]
+        delta: PathBuf,
+
+        /// Do not hash FILE first to check it is the version the delta was made from
+        #[arg(long)]
+        no_check: bool,
+
+        /// Directory keeping partial and finished block hashes, so an interrupted check resumes
+        #[arg(long, value_name = "DIR")]
+        state_dir: Option<PathBuf>,
+    },
+}
+
+/// How the `blocks` commands hash files
+#[derive(Args, Debug)]
+pub struct BlockArgs {
+    /// Size of the blocks, e.g. `4MiB` (default: 4 MiB)
+    #[arg(long, value_name = "SIZE")]
+    pub block_size: Option<ByteSize>,
+
+    /// Hash function: sha256, blake3 or xxh3 (default: sha256)
+    #[arg(long, value_name = "ALGORITHM")]
+    pub hash_algorithm: Option<HashAlgorithm>,
+
+    /// Directory keeping partial and finished block hashes, so an interrupted run resumes
+    /// and an unchanged file is not read again
+    #[arg(long, value_name = "DIR")]
+    pub state_dir: Option<PathBuf>,
+}
+
+/// Arguments of the `create` command
+#[derive(Args, Debug)]
+pub struct CreateArgs {
+    /// Source directory path; a patch file (its target manifest), a saved manifest (also as an http(s) or s3:// URL), a remote directory as sftp://[USER@]HOST/PATH, an ISO/UDF disk image or a ZIP archive also works
+    #[arg(short, long, value_name = "DIR")]
+    pub source: PathBuf,
+
+    /// Target directory path
+    #[arg(short, long, value_name = "DIR")]
+    pub target: PathBuf,
+
+    /// Output patch file path; the file name may use {from}, {to}, {date}, {time} and {timestamp}
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+
+    /// Upload the patch once created: an s3://BUCKET/KEY object or an http(s) URL taking a PUT; a URL ending with / gets the patch file name
+    #[arg(long, value_name = "URL")]
+    pub upload: Option<String>,
+
+    /// Source version for {from} in --output and --save-report (default: source directory name)
+    #[arg(long, value_name = "VERSION")]
+    pub from_version: Option<String>,
+
+    /// Target version for {to} in --output and --save-report (default: target directory name)
+    #[arg(long, value_name = "VERSION")]
+    pub to_version: Option<String>,
+
+    /// Verification file list (comma-separated relative paths)
+    #[arg(short, long, value_name = "FILES", value_delimiter = ',')]
+    pub check_files: Vec<String>,
+
+    /// Preset options for a common use: deploy, backup-verify or mod-merge (further options add to it)
+    #[arg(long, value_enum, value_name = "WORKFLOW")]
+    pub workflow: Option<WorkflowMode>,
+
+    #[command(flatten)]
+    pub filters: FilterArgs,
+
+    /// Use file difference patches instead of storing full files (default: false)
+    #[arg(long, default_value = "true")]
+    pub use_diff_patches: bool,
+
+    /// Warn about target paths that differ only by case (collide on Windows/macOS)
+    #[arg(long)]
+    pub check_case_conflicts: bool,
+
+    /// Check patched paths for names Windows cannot create
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = WindowsPaths::Warn)]
+    pub windows_paths: WindowsPaths,
+
+    /// Maximum relative path length allowed by --windows-paths (leave room for the install directory)
+    #[arg(long, value_name = "N", default_value_t = WINDOWS_MAX_PATH)]
+    pub windows_max_path: usize,
+
+    /// Stop if more than this percentage of the files in both trees changed (e.g. 40), which usually means a full rebuild or the wrong source directory
+    #[arg(long, value_name = "PERCENT")]
+    pub max_changed_files: Option<Percent>,
+
+    /// Stop if the added and modified files add up to more than this size (e.g. 5GB)
+    #[arg(long, value_name = "SIZE")]
+    pub max_changed_size: Option<ByteSize>,
+
+    /// What to do when --max-changed-files or --max-changed-size is exceeded
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = ChangeLimitMode::Deny)]
+    pub on_change_limit: ChangeLimitMode,
+
+    /// Save the list of differences as a JSON report (the file name may use the --output variables)
+    #[arg(long, value_name = "FILE")]
+    pub save_report: Option<PathBuf>,
+
+    /// Save a standalone HTML page with summary statistics and tables of the changed files (the file name may use the --output variables)
+    #[arg(long, value_name = "FILE")]
+    pub html_report: Option<PathBuf>,
+
+    /// Produce byte-identical manifests and reports for identical trees (sorted entries, `/` separators)
+    #[arg(long)]
+    pub reproducible: bool,
+
+    /// Tag files from "PATTERN = TAG[, TAG...]" rules in FILE (e.g. "bin/** = core"); the summary lists the changes per tag
+    #[arg(long, value_name = "FILE")]
+    pub tag_rules: Option<PathBuf>,
+
+    /// Only patch the changes to files with one of these tags (comma-separated); the patch then embeds no target manifest
+    #[arg(long, value_name = "TAGS", value_delimiter = ',', requires = "tag_rules")]
+    pub only_tags: Vec<String>,
+
+    /// Order of the entries in the saved report: bytewise, case-insensitive or natural (file2 before file10)
+    #[arg(long, value_enum, value_name = "ORDER")]
+    pub sort: Option<SortOrder>,
+
+    /// Script to run before applying the patch (relative to the patched directory)
+    #[arg(long, value_name = "SCRIPT")]
+    pub pre_apply: Option<PathBuf>,
+
+    /// Arguments for the pre-apply script (comma-separated)
+    #[arg(long, value_name = "ARGS", value_delimiter = ',', allow_hyphen_values = true, requires = "pre_apply")]
+    pub pre_apply_args: Vec<String>,
+
+    /// Script to run after applying the patch (relative to the patched directory)
+    #[arg(long, value_name = "SCRIPT")]
+    pub post_apply: Option<PathBuf>,
+
+    /// Arguments for the post-apply script (comma-separated)
+    #[arg(long, value_name = "ARGS", value_delimiter = ',', allow_hyphen_values = true, requires = "post_apply")]
+    pub post_apply_args: Vec<String>,
+
+    /// Number of file I/O threads (default: DIFFPATCH_IO_THREADS, or CPU count capped at 4)
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+
+    /// Only count the files and bytes to process and estimate the run time, without hashing
+    #[arg(long)]
+    pub estimate: bool,
+
+    /// How long --estimate spends measuring hashing throughput (e.g. 500ms, 5s)
+    #[arg(long, value_name = "DURATION", default_value = "1s")]
+    pub estimate_time: Span,
+
+    /// Keep scan snapshots in DIR; on NTFS/ReFS later scans only re-hash files the USN change journal reports changed
+    #[arg(long, value_name = "DIR")]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Keep comparison results in DIR and reuse them when the same trees are compared again with the same options
+    #[arg(long, value_name = "DIR")]
+    pub diff_cache: Option<PathBuf>,
+
+    /// Remember file hashes in DIR and only hash new or touched files on later scans (default: DIFFPATCH_HASH_CACHE)
+    #[arg(long, value_name = "DIR")]
+    pub hash_cache: Option<PathBuf>,
+
+    /// Record removed/added files with the same content whose paths differ only by extension as renames
+    #[arg(long)]
+    pub match_extension_renames: bool,
+
+    /// Record removed/added files with the same content and size as moves, wherever their paths are
+    #[arg(long)]
+    pub detect_renames: bool,
+
+    /// Smallest file --detect-renames pairs up (e.g. 4KiB); empty files are never paired
+    #[arg(long, value_name = "SIZE", requires = "detect_renames")]
+    pub rename_min_size: Option<ByteSize>,
+
+    /// Treat PE/ELF executables that differ only in build stamps (link time, build ID, PDB GUID, signature) as unchanged
+    #[arg(long)]
+    pub ignore_build_stamps: bool,
+
+    /// Do not report files whose permissions (Unix mode, Windows read-only/hidden attributes) changed while their content did not
+    #[arg(long)]
+    pub ignore_permissions: bool,
+
+    /// List which entries changed inside changed .zip/.jar/.pak archives (the archives are still patched whole)
+    #[arg(long)]
+    pub inspect_archives: bool,
+
+    /// Hash only files present on both sides; added files are hashed once the patch is confirmed (no target manifest is embedded)
+    #[arg(long)]
+    pub lazy_hash: bool,
+
+    /// Skip hashing target files that are hard links or reflinked copies (btrfs, XFS) of the source file at the same path
+    #[arg(long)]
+    pub detect_clones: bool,
+
+    /// Store modified files as binary deltas against their source version when the delta is at most half the file size
+    #[arg(long)]
+    pub binary_delta: bool,
+
+    /// Time all binary deltas together may take (e.g. 10m); files not done by then are stored whole
+    #[arg(long, value_name = "DURATION")]
+    pub delta_time_budget: Option<Span>,
+
+    /// Largest modified file a binary delta is computed for (default and upper limit: 512MiB)
+    #[arg(long, value_name = "SIZE")]
+    pub max_delta_file_size: Option<ByteSize>,
+
+    /// Compression of the patch content: stored, deflate, bzip2, zstd or xz, with an optional level (e.g. zstd:19); Brotli is not available
+    #[arg(long, value_name = "METHOD[:LEVEL]", default_value = "deflate")]
+    pub compression: CompressionSettings,
+
+    /// Compress the patch content with a codec instead of the ZIP compression: zstd, deflate or lz4, with an optional level (e.g. zstd:19); the patch records the codec of each entry. Brotli is not built in
+    #[arg(long, value_name = "ID[:LEVEL]", conflicts_with_all = ["compression", "benchmark"])]
+    pub codec: Option<CodecSettings>,
+
+    /// Compare the directories, then try several compression settings on a sample of the patch content instead of creating a patch
+    #[arg(long)]
+    pub benchmark: bool,
+
+    /// Compare the directories and list every file the patch would add, replace, patch, rename or delete, without writing the patch or reports
+    #[arg(long, conflicts_with_all = ["benchmark", "estimate"])]
+    pub dry_run: bool,
+
+    /// Print the time spent in each phase (walking, hashing, comparing, packaging) and how busy the threads were
+    #[arg(long)]
+    pub timings: bool,
+
+    /// How to detect changed files: hash both sides, compare bytes directly, or trust equal size and modification time (no target manifest is embedded with bytes or mtime)
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = CompareMode::Hash)]
+    pub compare: CompareMode,
+
+    /// Hash every file on both sides, overriding --compare, --lazy-hash and the hashes kept in --snapshot-dir
+    #[arg(long)]
+    pub paranoid: bool,
+
+    /// How hashes are written in the report, manifests and patch: hex, base64 or base32 (e.g. sha256:base64:...; default: hex)
+    #[arg(long, value_name = "ENCODING")]
+    pub digest_encoding: Option<DigestEncoding>,
+
+    /// Hash function for file contents: sha256, blake3 (faster, also cryptographic) or xxh3 (fastest, detects changes but not tampering); a manifest or patch given as --source must use the same one (default: sha256)
+    #[arg(long, value_name = "ALGORITHM")]
+    pub hash_algorithm: Option<HashAlgorithm>,
+
+    /// Byte-compare files with equal hashes: a number of randomly chosen files, or `all`
+    #[arg(long, value_name = "N|all", value_parser = parse_unchanged_verification)]
+    pub verify_unchanged: Option<UnchangedVerification>,
+
+    /// What to do when --verify-unchanged finds files with equal hashes but different content
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = OnHashCollision::Abort)]
+    pub on_hash_collision: OnHashCollision,
+
+    /// What to do with files and directories that cannot be read while scanning
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = OnReadError::Skip)]
+    pub on_read_error: OnReadError,
+
+    /// Apply files matching the patterns only on PLATFORM (windows, linux, macos, ...), e.g. windows=*.dll,bin/win/**
+    #[arg(long, value_name = "PLATFORM=PATTERNS", value_parser = parse_platform_files)]
+    pub platform_files: Vec<PlatformFiles>,
+
+    /// Apply files matching the patterns only where all conditions hold, e.g. component=hd-textures:textures/hd/** or platform=windows,arch=x86:bin32
+    #[arg(long, value_name = "CONDITIONS:PATTERNS", value_parser = parse_conditional_files)]
+    pub apply_if: Vec<ConditionalFiles>,
+
+    /// Platform the patch is for (default: detected from the executables it contains); applying it elsewhere needs --force
+    #[arg(long, value_name = "NAME", value_parser = parse_platform)]
+    pub target_platform: Option<String>,
+
+    /// CPU architecture the patch is for (default: detected from the executables it contains); applying it elsewhere needs --force
+    #[arg(long, value_name = "NAME", value_parser = parse_arch)]
+    pub target_arch: Option<String>,
+
+    /// Fail instead of creating the patch if scanning or comparing produced warnings (notes do not count)
+    #[arg(long)]
+    pub warnings_as_errors: bool,
+
+    /// Leave out warnings of these categories: skipped-file, unreadable-dir, case-conflict, unstable-file, long-path, change-limit
+    #[arg(long, value_name = "CATEGORIES", value_delimiter = ',', value_parser = parse_warning_kind)]
+    pub suppress_warnings: Vec<WarningKind>,
+
+    /// Local object cache shared across runs: copy contents compressed before from it instead of compressing them again, and add new ones
+    #[arg(long, value_name = "DIR")]
+    pub object_cache: Option<PathBuf>,
+}
+
+/// Which files a scan leaves out, shared by `create` and `explain`
+#[derive(Args, Debug)]
+pub struct FilterArgs {
+    /// Exclude file extensions (comma-separated, e.g., .tmp,.bak,.log)
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub exclude_extensions: Option<Vec<String>>,
+
+    /// Exclude directories (comma-separated relative paths, e.g., node_modules,dist,target)
+    #[arg(long, value_name = "DIRECTORIES", value_delimiter = ',')]
+    pub exclude_dirs: Option<Vec<String>>,
+
+    /// Also scan files and directories whose names start with a dot (e.g. .config); combine with --exclude-dirs .git to leave out repositories
+    #[arg(long)]
+    pub include_hidden: bool,
+
+    /// Compare the files symbolic links point to instead of recording the links themselves (with their targets)
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Only consider files matching a .gitignore-style pattern, e.g. 'assets/**/*.pak' (may be repeated); '!PATTERN' leaves files out again
+    #[arg(long, value_name = "PATTERN", allow_hyphen_values = true)]
+    pub include_pattern: Vec<String>,
+
+    /// Filter rule like "- logs/**" or "+ logs/audit/***"; the first matching rule decides
+    #[arg(long, value_name = "RULE", allow_hyphen_values = true)]
+    pub filter: Vec<String>,
+
+    /// Read "+ PATTERN"/"- PATTERN" filter rules from FILE, checked after --filter rules
+    #[arg(long, value_name = "FILE")]
+    pub filter_from: Vec<PathBuf>,
+
+    /// Exclude files matching the patterns in FILE (one glob per line, rsync syntax)
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Keep files matching the patterns in FILE even if an --exclude-from pattern matches them
+    #[arg(long, value_name = "FILE")]
+    pub include_from: Vec<PathBuf>,
+
+    /// Exclude files matching a .gitignore-style pattern, e.g. '**/cache/**', '*.log' or '!keep.log'; the last matching pattern decides
+    #[arg(long, value_name = "PATTERN", allow_hyphen_values = true)]
+    pub ignore: Vec<String>,
+
+    /// Read .gitignore-style patterns from FILE (e.g. a project's .gitignore), checked together with --ignore patterns
+    #[arg(long, value_name = "FILE")]
+    pub ignore_from: Vec<PathBuf>,
+
+    /// Leave out files larger than this on both sides (e.g. 500MB, 2GiB)
+    #[arg(long, value_name = "SIZE")]
+    pub max_file_size: Option<ByteSize>,
+
+    /// Read files no faster than this while hashing them, over all threads (e.g. 50MiB/s)
+    #[arg(long, value_name = "RATE")]
+    pub throttle: Option<Rate>,
+}
+
+/// Arguments accepted by a generated patch executable
+#[derive(Parser, Debug)]
+#[command(version, about = "Apply the patch embedded in this executable to the current directory", long_about = None)]
+pub struct PatchCli {
+    #[command(flatten)]
+    pub options: ApplyArgs,
+
+    /// Language of the output: en or zh (default: system locale)
+    #[arg(long, value_name = "LANG")]
+    pub lang: Option<Language>,
+}
+
+/// Options controlling how a patch is applied
+#[derive(Args, Debug)]
+pub struct ApplyArgs {
+    /// Execute the pre-/post-apply hook scripts defined in the patch
+    #[arg(long)]
+    pub allow_hooks: bool,
+
+    /// Update a hard-linked copy of the directory and swap it in at the end
+    #[arg(long)]
+    pub staged: bool,
+
+    /// Write the updated tree to a new directory instead, hard-linking unchanged files
+    #[arg(long, value_name = "DIR", conflicts_with = "staged")]
+    pub extract_to: Option<PathBuf>,
+
+    /// Number of file I/O threads (default: DIFFPATCH_IO_THREADS, or CPU count capped at 4)
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+
+    /// Apply the platform-specific files of this platform (default: the current one)
+    #[arg(long, value_name = "NAME", value_parser = parse_platform)]
+    pub platform: Option<String>,
+
+    /// Check conditional files against this CPU architecture (default: the current one)
+    #[arg(long, value_name = "NAME", value_parser = parse_arch)]
+    pub arch: Option<String>,
+
+    /// Optional component installed in this directory, e.g. hd-textures (comma-separated or repeated)
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    pub component: Vec<String>,
+
+    /// Set the Unix permissions of written files matching the patterns, e.g. bin/**,*.sh=755 (first match wins)
+    #[arg(long, value_name = "PATTERNS=MODE", value_parser = parse_mode_rule)]
+    pub file_mode: Vec<ModeRule>,
+
+    /// Local object cache: clone contents seen before from it instead of decompressing them, and add new ones
+    #[arg(long, value_name = "DIR")]
+    pub object_cache: Option<PathBuf>,
+
+    /// NAME=VALUE file with the values of ${NAME} placeholders in patch paths and hook arguments
+    #[arg(long, value_name = "FILE")]
+    pub vars_file: Option<PathBuf>,
+
+    /// Environment variables placeholders may take their value from (comma-separated or repeated)
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    pub allow_env: Vec<String>,
+
+    /// Change nothing; write a JSON forecast (sizes, temporary space per strategy, conflicts, estimated time) to FILE
+    #[arg(long, value_name = "FILE")]
+    pub simulate: Option<PathBuf>,
+
+    /// Change nothing; list every file that would be added, replaced, patched, renamed or deleted
+    #[arg(long, conflicts_with = "simulate")]
+    pub dry_run: bool,
+
+    /// Do not check the patched files against the target manifest of the patch afterwards
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Undo the changes if the patched files fail verification (in place: restore backed-up files; --staged/--extract-to: discard the copy)
+    #[arg(long, conflicts_with = "no_verify")]
+    pub rollback_on_failure: bool,
+
+    /// Write a verification record (state before, check result after, signature) to FILE
+    #[arg(long, value_name = "FILE", conflicts_with = "no_verify")]
+    pub verification_record: Option<PathBuf>,
+
+    /// Sign the verification record with HMAC-SHA256 using the key in FILE
+    #[arg(long, value_name = "FILE", requires = "verification_record")]
+    pub signing_key: Option<PathBuf>,
+
+    /// Apply even if the patch was built for another platform or CPU architecture than this installation, or for other versions of the files it changes
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl ApplyArgs {
+    pub fn to_options(&self) -> ApplyOptions {
+        ApplyOptions {
+            allow_hooks: self.allow_hooks,
+            strategy: match (&self.extract_to, self.staged) {
+                (Some(dir), _) => ApplyStrategy::Extract(dir.clone()),
+                (None, true) => ApplyStrategy::Staged,
+                (None, false) => ApplyStrategy::InPlace,
+            },
+            threads: self.threads,
+            platform: self.platform.clone(),
+            arch: self.arch.clone(),
+            components: self.component.clone(),
+            file_modes: self.file_mode.clone(),
+            object_cache: cache::cache_dir(self.object_cache.as_deref()),
+            package: None,
+            vars_file: self.vars_file.clone(),
+            allow_env: self.allow_env.clone(),
+            simulate: self.simulate.clone(),
+            dry_run: self.dry_run,
+            skip_verification: self.no_verify,
+            rollback_on_failure: self.rollback_on_failure,
+            verification_record: self.verification_record.clone(),
+            signing_key: self.signing_key.clone(),
+            force: self.force,
+            cancel: None,
+        }
+    }
+}
+
+/// How to handle paths that cannot be created on Windows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WindowsPaths {
+    /// Skip the check
+    Off,
+    /// Print the offending paths and continue
+    Warn,
+    /// Print the offending paths and refuse to create the patch
+    Deny,
+}
+
+/// How to handle a change larger than --max-changed-files or --max-changed-size
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChangeLimitMode {
+    /// Print a warning and continue (fails with --warnings-as-errors)
+    Warn,
+    /// Print the exceeded limits and refuse to create the patch
+    Deny,
+}
+
+/// Preset options for a common use of `create`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WorkflowMode {
+    /// Ship a release: skip build leftovers and stamp-only executable changes, detect moves, use binary deltas, fail on warnings
+    Deploy,
+    /// Check a backup: byte-compare every file with an equal hash, fail on warnings
+    BackupVerify,
+    /// Merge a mod: inspect changed archives, detect renames and moves, check case conflicts, use binary deltas
+    ModMerge,
+}
+
+impl From<WorkflowMode> for Workflow {
+    fn from(value: WorkflowMode) -> Self {
+        match value {
+            WorkflowMode::Deploy => Workflow::Deploy,
+            WorkflowMode::BackupVerify => Workflow::BackupVerify,
+            WorkflowMode::ModMerge => Workflow::ModMerge,
+        }
+    }
+}
+
+/// How report entries are ordered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// By the bytes of each path name (uppercase before lowercase)
+    Bytewise,
+    /// Ignoring case
+    CaseInsensitive,
+    /// Ignoring case, with numbers compared by value
+    Natural,
+}
+
+impl From<SortOrder> for PathOrder {
+    fn from(value: SortOrder) -> Self {
+        match value {
+            SortOrder::Bytewise => PathOrder::Bytewise,
+            SortOrder::CaseInsensitive => PathOrder::CaseInsensitive,
+            SortOrder::Natural => PathOrder::Natural,
+        }
+    }
+}
+
+/// Output format of the `diff` command
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One `kind: path` line per difference
+    Text,
+    /// JSON array of the differences, as in saved reports
+    Json,
+    /// Comma-separated `change,path,from,size,hash` rows with a header
+    Csv,
+}
+
+impl From<OutputFormat> for ChangeListFormat {
+    fn from(value: OutputFormat) -> Self {
+        match value {
+            OutputFormat::Text => ChangeListFormat::Text,
+            OutputFormat::Json => ChangeListFormat::Json,
+            OutputFormat::Csv => ChangeListFormat::Csv,
+        }
+    }
+}
+
+/// What each line of the `diff` text output shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputStyle {
+    /// Only the path
+    Paths,
+    /// Kind, path and size
+    Short,
+    /// Kind, path, old and new sizes, hashes and permissions
+    Full,
+}
+
+impl From<OutputStyle> for LineStyle {
+    fn from(value: OutputStyle) -> Self {
+        match value {
+            OutputStyle::Paths => LineStyle::Paths,
+            OutputStyle::Short => LineStyle::Short,
+            OutputStyle::Full => LineStyle::Full,
+        }
+    }
+}
+
+/// How to detect changed files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CompareMode {
+    /// Hash every file on both sides
+    Hash,
+    /// Compare files of equal size byte by byte (source must be a directory)
+    Bytes,
+    /// Assume files of equal size and modification time are unchanged, hash the others (source must be a directory)
+    Mtime,
+}
+
+impl From<CompareMode> for CompareStrategy {
+    fn from(value: CompareMode) -> Self {
+        match value {
+            CompareMode::Hash => CompareStrategy::Hash,
+            CompareMode::Bytes => CompareStrategy::ByteCompare,
+            CompareMode::Mtime => CompareStrategy::SizeAndMtime,
+        }
+    }
+}
+
+/// How to handle files with equal hashes but different content
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OnHashCollision {
+    /// Report the files and refuse to create the patch
+    Abort,
+    /// Report the files and include them in the patch as modified
+    Modified,
+}
+
+impl From<OnHashCollision> for CollisionPolicy {
+    fn from(value: OnHashCollision) -> Self {
+        match value {
+            OnHashCollision::Abort => CollisionPolicy::Abort,
+            OnHashCollision::Modified => CollisionPolicy::TreatAsModified,
+        }
+    }
+}
+
+/// What to do with files and directories a scan cannot read
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OnReadError {
+    /// Leave them out with a skipped-file or unreadable-dir warning
+    Skip,
+    /// Stop at the first one
+    Fail,
+}
+
+impl From<OnReadError> for ErrorPolicy {
+    fn from(value: OnReadError) -> Self {
+        match value {
+            OnReadError::Skip => ErrorPolicy::SkipAndCollect,
+            OnReadError::Fail => ErrorPolicy::FailFast,
+        }
+    }
+}
+
+fn parse_unchanged_verification(value: &str) -> Result<UnchangedVerification, String> {
+    if value.eq_ignore_ascii_case("all") {
+        return Ok(UnchangedVerification::All);
+    }
+    value
+        .parse()
+        .map(UnchangedVerification::Sample)
+        .map_err(|_| format!("expected a file count or `all`, got `{}`", value))
+}
+
+fn parse_platform(value: &str) -> Result<String, String> {
+    condition::normalize_platform(value).map_err(|e| e.to_string())
+}
+
+fn parse_arch(value: &str) -> Result<String, String> {
+    condition::normalize_arch(value).map_err(|e| e.to_string())
+}
+
+/// Comma-separated patterns, matched like exclude rules
+fn parse_patterns(patterns: &str) -> Result<FilterList, String> {
+    let mut list = FilterList::new();
+    for pattern in patterns.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        list.push(FilterRule::new(RuleKind::Exclude, pattern));
+    }
+    if list.is_empty() {
+        return Err("no patterns given".to_string());
+    }
+    Ok(list)
+}
+
+fn parse_platform_files(value: &str) -> Result<PlatformFiles, String> {
+    let (platform, patterns) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected PLATFORM=PATTERNS, got `{}`", value))?;
+    Ok(PlatformFiles { platform: parse_platform(platform)?, patterns: parse_patterns(patterns)? })
+}
+
+fn parse_warning_kind(value: &str) -> Result<WarningKind, String> {
+    value.parse().map_err(|e: anyhow::Error| e.to_string())
+}
+
+fn parse_mode_rule(value: &str) -> Result<ModeRule, String> {
+    let (patterns, mode) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATTERNS=MODE, got `{}`", value))?;
+    let mode = patch::parse_mode(mode).map_err(|e| e.to_string())?;
+    Ok(ModeRule { patterns: parse_patterns(patterns)?, mode })
+}
+
+fn parse_conditional_files(value: &str) -> Result<ConditionalFiles, String> {
+    let (conditions, patterns) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected CONDITIONS:PATTERNS, got `{}`", value))?;
+    let when = condition::parse_conditions(conditions).map_err(|e| e.to_string())?;
+    Ok(ConditionalFiles { when, patterns: parse_patterns(patterns)? })
 }
 
 pub fn parse_args() -> Cli {
     Cli::parse()
+}
+
+pub fn parse_patch_args() -> PatchCli {
+    PatchCli::parse()
 } 
\ No newline at end of file
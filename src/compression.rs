@@ -0,0 +1,459 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::{Duration, Instant};
+use zip::write::FileOptions;
+use zip::read::ZipFile;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Entries at least this large are written with ZIP64 size fields: their size, or their
+/// compressed size, which can grow a little past it, may not fit the 32-bit fields of a
+/// plain entry
+pub const ZIP64_THRESHOLD: u64 = 0xF000_0000;
+
+/// Whether a ZIP entry of `size` bytes must be written with ZIP64 sizes
+pub fn needs_zip64(size: u64) -> bool {
+    size >= ZIP64_THRESHOLD
+}
+
+/// Copy `entry` into `zip` as `name`, reusing its compressed data
+///
+/// Entries too large for plain size fields are decompressed and written again,
+/// because the zip writer leaves the ZIP64 sizes of raw copies empty.
+pub fn copy_zip_entry<W: Write + Seek>(zip: &mut ZipWriter<W>, mut entry: ZipFile, name: &str) -> zip::result::ZipResult<()> {
+    let options = entry.options();
+    if entry.size().max(entry.compressed_size()) <= u32::MAX as u64 {
+        return zip.raw_copy_file_rename(entry, name);
+    }
+    zip.start_file(name, options.large_file(true))?;
+    io::copy(&mut entry, zip)?;
+    Ok(())
+}
+
+/// Compression method and level used for the patch content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionSettings {
+    pub method: CompressionMethod,
+    pub level: Option<i64>, // Method default if not set
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        CompressionSettings { method: CompressionMethod::Deflated, level: None }
+    }
+}
+
+impl CompressionSettings {
+    pub fn new(method: CompressionMethod, level: Option<i64>) -> Self {
+        CompressionSettings { method, level }
+    }
+
+    /// ZIP entry options for these settings
+    pub fn file_options(&self) -> FileOptions<'static, ()> {
+        FileOptions::default()
+            .compression_method(self.method)
+            .compression_level(self.level)
+    }
+}
+
+impl fmt::Display for CompressionSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.method {
+            CompressionMethod::Stored => "stored",
+            CompressionMethod::Deflated => "deflate",
+            CompressionMethod::Bzip2 => "bzip2",
+            CompressionMethod::Zstd => "zstd",
+            CompressionMethod::Xz => "xz",
+            _ => "unknown",
+        };
+        match self.level {
+            Some(level) => write!(f, "{}:{}", name, level),
+            None => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Parse `METHOD[:LEVEL]`, e.g. `zstd:19` or `stored`
+impl FromStr for CompressionSettings {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => {
+                let level = level.parse().map_err(|_| anyhow!("Invalid compression level: {}", level))?;
+                (name, Some(level))
+            }
+            None => (value, None),
+        };
+        let method = match name.to_ascii_lowercase().as_str() {
+            "stored" | "none" => CompressionMethod::Stored,
+            "deflate" | "deflated" => CompressionMethod::Deflated,
+            "bzip2" => CompressionMethod::Bzip2,
+            "zstd" => CompressionMethod::Zstd,
+            "xz" => CompressionMethod::Xz,
+            _ => return Err(anyhow!("Unknown compression method: {} (expected stored, deflate, bzip2, zstd or xz)", name)),
+        };
+        if method == CompressionMethod::Stored && level.is_some() {
+            return Err(anyhow!("Stored entries take no compression level"));
+        }
+        Ok(CompressionSettings { method, level })
+    }
+}
+
+/// Compression of patch entries done by diffpatch itself rather than by the ZIP format
+///
+/// Entries compressed with a codec are stored as they are in the ZIP, and the patch lists
+/// the codec ID of each of them, so applying it picks the same codec. Codecs beyond the
+/// built-in `zstd`, `deflate` and `lz4` (e.g. Brotli, which is not built in) can be
+/// added with `register_codec`; a patch using one can only be applied by a program that
+/// registered it too, so it cannot be a patch executable.
+pub trait Codec: Send + Sync {
+    /// Name recorded in patches, e.g. `lz4`
+    fn id(&self) -> &str;
+
+    /// Compress everything `input` holds into `output`, at `level` or the codec default
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write, level: Option<i32>) -> io::Result<()>;
+
+    /// Reader of the decompressed content of `input`
+    fn decompressor<'a>(&self, input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>>;
+
+    /// Levels the codec accepts, `None` if it takes none
+    fn levels(&self) -> Option<RangeInclusive<i32>> {
+        None
+    }
+}
+
+/// Zstandard frames, levels 1 to 22
+pub struct ZstdCodec;
+
+impl Codec for ZstdCodec {
+    fn id(&self) -> &str {
+        "zstd"
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write, level: Option<i32>) -> io::Result<()> {
+        zstd::stream::copy_encode(input, output, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))
+    }
+
+    fn decompressor<'a>(&self, input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(zstd::stream::Decoder::new(input)?))
+    }
+
+    fn levels(&self) -> Option<RangeInclusive<i32>> {
+        Some(1..=22)
+    }
+}
+
+/// Raw deflate streams, levels 0 to 9
+pub struct DeflateCodec;
+
+impl Codec for DeflateCodec {
+    fn id(&self) -> &str {
+        "deflate"
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write, level: Option<i32>) -> io::Result<()> {
+        let level = level.map_or(flate2::Compression::default(), |level| flate2::Compression::new(level as u32));
+        let mut encoder = flate2::write::DeflateEncoder::new(output, level);
+        io::copy(input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decompressor<'a>(&self, input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(flate2::read::DeflateDecoder::new(input)))
+    }
+
+    fn levels(&self) -> Option<RangeInclusive<i32>> {
+        Some(0..=9)
+    }
+}
+
+/// LZ4 frames (see `lz4`), very fast to decompress; takes no level
+pub struct Lz4Codec;
+
+impl Codec for Lz4Codec {
+    fn id(&self) -> &str {
+        "lz4"
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write, _level: Option<i32>) -> io::Result<()> {
+        let mut encoder = crate::lz4::FrameEncoder::new(output);
+        io::copy(input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decompressor<'a>(&self, input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(crate::lz4::FrameDecoder::new(input)))
+    }
+}
+
+/// IDs of the codecs every diffpatch program has
+///
+/// There is no built-in Brotli codec: no Brotli implementation is bundled. Library users
+/// can register one, but patch executables cannot use it (see `is_builtin_codec`).
+pub const BUILTIN_CODECS: [&str; 3] = ["zstd", "deflate", "lz4"];
+
+static CODECS: LazyLock<RwLock<HashMap<String, Arc<dyn Codec>>>> = LazyLock::new(|| {
+    let builtin: [Arc<dyn Codec>; 3] = [Arc::new(ZstdCodec), Arc::new(DeflateCodec), Arc::new(Lz4Codec)];
+    RwLock::new(builtin.into_iter().map(|codec| (codec.id().to_string(), codec)).collect())
+});
+
+/// Make `codec` available to create and apply patches; fails if its ID is taken
+pub fn register_codec(codec: Arc<dyn Codec>) -> Result<()> {
+    let id = codec.id().to_string();
+    if id.is_empty() || id.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err(anyhow!("Invalid codec ID '{}': it must not be empty or contain ':' or spaces", id));
+    }
+    let mut codecs = CODECS.write().unwrap();
+    if codecs.contains_key(&id) {
+        return Err(anyhow!("A codec with the ID '{}' is already registered", id));
+    }
+    codecs.insert(id, codec);
+    Ok(())
+}
+
+/// The codec registered under `id`
+pub fn find_codec(id: &str) -> Result<Arc<dyn Codec>> {
+    CODECS.read().unwrap().get(id).cloned()
+        .ok_or_else(|| anyhow!("Unknown codec: {} (available: {})", id, codec_ids().join(", ")))
+}
+
+/// Whether `id` names a built-in codec rather than one added with `register_codec`
+///
+/// Patch executables apply with the diffpatch program they are built from, which only
+/// has the built-in codecs, so they must not hold entries compressed with others.
+pub fn is_builtin_codec(id: &str) -> bool {
+    BUILTIN_CODECS.contains(&id)
+}
+
+/// IDs of all registered codecs, sorted
+pub fn codec_ids() -> Vec<String> {
+    let mut ids: Vec<String> = CODECS.read().unwrap().keys().cloned().collect();
+    ids.sort();
+    ids
+}
+
+/// Codec and level used for the patch entries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecSettings {
+    pub id: String,
+    pub level: Option<i32>, // Codec default if not set
+}
+
+impl CodecSettings {
+    pub fn codec(&self) -> Result<Arc<dyn Codec>> {
+        find_codec(&self.id)
+    }
+
+    /// Compress all of `input` with the codec into memory
+    pub fn compress(&self, input: &mut dyn Read) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.codec()?.compress(input, &mut output, self.level)
+            .with_context(|| format!("Failed to compress with codec {}", self))?;
+        Ok(output)
+    }
+}
+
+impl fmt::Display for CodecSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            Some(level) => write!(f, "{}:{}", self.id, level),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// Parse `ID[:LEVEL]`, e.g. `lz4` or `zstd:19`, for a registered codec
+impl FromStr for CodecSettings {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (id, level) = match value.split_once(':') {
+            Some((id, level)) => {
+                let level = level.parse().map_err(|_| anyhow!("Invalid codec level: {}", level))?;
+                (id, Some(level))
+            }
+            None => (value, None),
+        };
+        let codec = find_codec(id)?;
+        if let Some(level) = level {
+            match codec.levels() {
+                Some(levels) if !levels.contains(&level) => {
+                    return Err(anyhow!("Codec {} takes levels {} to {}, not {}", id, levels.start(), levels.end(), level));
+                }
+                None => return Err(anyhow!("Codec {} takes no level", id)),
+                _ => {}
+            }
+        }
+        Ok(CodecSettings { id: id.to_string(), level })
+    }
+}
+
+/// Settings tried by `benchmark`, from fastest to strongest per method
+pub fn benchmark_candidates() -> Vec<CompressionSettings> {
+    vec![
+        CompressionSettings::new(CompressionMethod::Stored, None),
+        CompressionSettings::new(CompressionMethod::Deflated, Some(1)),
+        CompressionSettings::new(CompressionMethod::Deflated, Some(6)),
+        CompressionSettings::new(CompressionMethod::Deflated, Some(9)),
+        CompressionSettings::new(CompressionMethod::Bzip2, Some(9)),
+        CompressionSettings::new(CompressionMethod::Zstd, Some(3)),
+        CompressionSettings::new(CompressionMethod::Zstd, Some(9)),
+        CompressionSettings::new(CompressionMethod::Zstd, Some(19)),
+        CompressionSettings::new(CompressionMethod::Xz, Some(6)),
+    ]
+}
+
+/// Compressed size and time of one benchmark candidate
+#[derive(Debug, Clone, Copy)]
+pub struct BenchmarkResult {
+    pub settings: CompressionSettings,
+    pub compressed_size: u64,
+    pub duration: Duration,
+}
+
+/// Sample of the patch payload used for benchmarking
+pub struct BenchmarkSample {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl BenchmarkSample {
+    /// Read up to `limit` bytes from `files` (relative to `dir`)
+    ///
+    /// Files are taken in strides across the list so the sample reflects the content
+    /// mix; the last file may be cut short.
+    pub fn read(dir: &Path, files: &[PathBuf], limit: u64) -> Result<Self> {
+        let stride = (files.len() / 64).max(1);
+        let mut entries = Vec::new();
+        let mut remaining = limit;
+        for path in (0..stride).flat_map(|offset| files.iter().skip(offset).step_by(stride)) {
+            if remaining == 0 {
+                break;
+            }
+            let full_path = dir.join(path);
+            let file = File::open(&full_path)
+                .with_context(|| format!("Failed to open file for benchmark: {}", full_path.display()))?;
+            let mut buffer = Vec::new();
+            file.take(remaining).read_to_end(&mut buffer)
+                .with_context(|| format!("Failed to read file for benchmark: {}", full_path.display()))?;
+            remaining -= buffer.len() as u64;
+            entries.push((path.to_string_lossy().into_owned(), buffer));
+        }
+        Ok(BenchmarkSample { entries })
+    }
+
+    pub fn files(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(|(_, data)| data.len() as u64).sum()
+    }
+
+    /// Compress the sample into an in-memory ZIP with each of the settings
+    pub fn benchmark(&self, candidates: &[CompressionSettings]) -> Result<Vec<BenchmarkResult>> {
+        candidates.iter().map(|settings| {
+            let start = Instant::now();
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            for (name, data) in &self.entries {
+                zip.start_file(name, settings.file_options())
+                    .with_context(|| format!("Failed to compress with {}", settings))?;
+                zip.write_all(data)?;
+            }
+            let compressed_size = zip.finish()?.into_inner().len() as u64;
+            Ok(BenchmarkResult { settings: *settings, compressed_size, duration: start.elapsed() })
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use zip::ZipArchive;
+
+    /// Write a ZIP archive at `path` holding `name` with `size` zero bytes
+    fn zeros_archive(path: &Path, name: &str, size: u64) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let options = CompressionSettings::new(CompressionMethod::Zstd, Some(1)).file_options().large_file(needs_zip64(size));
+        zip.start_file(name, options).unwrap();
+        io::copy(&mut io::repeat(0).take(size), &mut zip).unwrap();
+        zip.finish().unwrap();
+    }
+
+    /// Copy every entry of the archive at `from` into a new archive at `to`
+    fn copy_archive(from: &Path, to: &Path) {
+        let mut source = ZipArchive::new(File::open(from).unwrap()).unwrap();
+        let mut zip = ZipWriter::new(File::create(to).unwrap());
+        for index in 0..source.len() {
+            let entry = source.by_index(index).unwrap();
+            let name = format!("copy/{}", entry.name());
+            copy_zip_entry(&mut zip, entry, &name).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn copied_size(path: &Path, name: &str) -> u64 {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut entry = archive.by_name(name).unwrap();
+        let size = entry.size();
+        assert_eq!(io::copy(&mut entry, &mut io::sink()).unwrap(), size);
+        size
+    }
+
+    #[test]
+    fn copied_entry_keeps_its_content() {
+        let dir = TempDir::new();
+        let (from, to) = (dir.path().join("a.zip"), dir.path().join("b.zip"));
+        zeros_archive(&from, "small", 1000);
+        copy_archive(&from, &to);
+        assert_eq!(copied_size(&to, "copy/small"), 1000);
+    }
+
+    #[test]
+    #[ignore = "writes more than 4 GiB"]
+    fn copied_zip64_entry_keeps_its_size() {
+        let dir = TempDir::new();
+        let (from, to) = (dir.path().join("a.zip"), dir.path().join("b.zip"));
+        let size = u32::MAX as u64 + 4096;
+        zeros_archive(&from, "large", size);
+        copy_archive(&from, &to);
+        assert_eq!(copied_size(&to, "copy/large"), size);
+    }
+
+    /// Codec that stores content as it is
+    struct IdentityCodec;
+
+    impl Codec for IdentityCodec {
+        fn id(&self) -> &str {
+            "identity"
+        }
+
+        fn compress(&self, input: &mut dyn Read, output: &mut dyn Write, _level: Option<i32>) -> io::Result<()> {
+            io::copy(input, output).map(|_| ())
+        }
+
+        fn decompressor<'a>(&self, input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+            Ok(input)
+        }
+    }
+
+    #[test]
+    fn registered_codecs_are_not_built_in() {
+        register_codec(Arc::new(IdentityCodec)).unwrap();
+        assert!(register_codec(Arc::new(IdentityCodec)).is_err());
+        let settings: CodecSettings = "identity".parse().unwrap();
+        assert_eq!(settings.compress(&mut &b"same"[..]).unwrap(), b"same");
+        assert!(!is_builtin_codec("identity"));
+        assert!(BUILTIN_CODECS.iter().all(|id| is_builtin_codec(id) && find_codec(id).is_ok()));
+        assert!(find_codec("brotli").is_err());
+    }
+}
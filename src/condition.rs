@@ -0,0 +1,240 @@
+use anyhow::{anyhow, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Platform names accepted for platform-specific files, as in `std::env::consts::OS`
+pub const PLATFORMS: &[&str] = &["windows", "linux", "macos", "freebsd", "netbsd", "openbsd", "android", "ios"];
+
+/// Architecture names accepted in conditions, as in `std::env::consts::ARCH`
+pub const ARCHITECTURES: &[&str] = &["x86", "x86_64", "arm", "aarch64", "riscv64", "powerpc64", "s390x", "loongarch64"];
+
+/// Check a platform name, accepting `win`, `mac` and `osx` as aliases
+pub fn normalize_platform(name: &str) -> Result<String> {
+    let name = name.trim().to_ascii_lowercase();
+    let name = match name.as_str() {
+        "win" | "win32" | "win64" => "windows",
+        "mac" | "osx" | "darwin" => "macos",
+        other => other,
+    };
+    if PLATFORMS.contains(&name) {
+        Ok(name.to_string())
+    } else {
+        Err(anyhow!("Unknown platform: {} (expected one of {})", name, PLATFORMS.join(", ")))
+    }
+}
+
+/// Check an architecture name, accepting `amd64`/`x64`, `arm64` and `i386`/`i686` as aliases
+pub fn normalize_arch(name: &str) -> Result<String> {
+    let name = name.trim().to_ascii_lowercase();
+    let name = match name.as_str() {
+        "amd64" | "x64" => "x86_64",
+        "arm64" => "aarch64",
+        "i386" | "i586" | "i686" => "x86",
+        other => other,
+    };
+    if ARCHITECTURES.contains(&name) {
+        Ok(name.to_string())
+    } else {
+        Err(anyhow!("Unknown architecture: {} (expected one of {})", name, ARCHITECTURES.join(", ")))
+    }
+}
+
+/// Name of the platform this program runs on
+pub fn current_platform() -> &'static str {
+    std::env::consts::OS
+}
+
+/// Name of the CPU architecture this program runs on
+pub fn current_arch() -> &'static str {
+    std::env::consts::ARCH
+}
+
+/// What a condition looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConditionKey {
+    Platform,
+    Arch,
+    /// An optional component the user has installed (e.g. `hd-textures`)
+    Component,
+}
+
+impl fmt::Display for ConditionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConditionKey::Platform => "platform",
+            ConditionKey::Arch => "arch",
+            ConditionKey::Component => "component",
+        })
+    }
+}
+
+/// One test on the system a patch is applied to, written `key=value` or `key!=value`
+///
+/// Stored in manifests in its text form, e.g. `platform=windows` or `component!=lite`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub struct Condition {
+    pub key: ConditionKey,
+    pub value: String,
+    pub negated: bool,
+}
+
+impl Condition {
+    pub fn holds(&self, env: &ApplyEnvironment) -> bool {
+        let matches = match self.key {
+            ConditionKey::Platform => env.platform == self.value,
+            ConditionKey::Arch => env.arch == self.value,
+            ConditionKey::Component => env.components.contains(&self.value),
+        };
+        matches != self.negated
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (key, value, negated) = match text.split_once("!=") {
+            Some((key, value)) => (key, value, true),
+            None => {
+                let (key, value) = text
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid condition: {} (expected KEY=VALUE or KEY!=VALUE)", text))?;
+                (key, value, false)
+            }
+        };
+        let key = match key.trim().to_ascii_lowercase().as_str() {
+            "platform" | "os" => ConditionKey::Platform,
+            "arch" => ConditionKey::Arch,
+            "component" => ConditionKey::Component,
+            other => return Err(anyhow!("Unknown condition key: {} (expected platform, arch or component)", other)),
+        };
+        let value = match key {
+            ConditionKey::Platform => normalize_platform(value)?,
+            ConditionKey::Arch => normalize_arch(value)?,
+            ConditionKey::Component => normalize_component(value)?,
+        };
+        Ok(Condition { key, value, negated })
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        text.parse()
+    }
+}
+
+impl From<Condition> for String {
+    fn from(condition: Condition) -> Self {
+        condition.to_string()
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.key, if self.negated { "!=" } else { "=" }, self.value)
+    }
+}
+
+/// Parse comma-separated conditions, all of which must hold
+pub fn parse_conditions(text: &str) -> Result<Vec<Condition>> {
+    let conditions: Vec<Condition> = text
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(str::parse)
+        .collect::<Result<_>>()?;
+    if conditions.is_empty() {
+        return Err(anyhow!("No conditions given"));
+    }
+    Ok(conditions)
+}
+
+/// Whether every condition holds
+pub fn all_hold(conditions: &[Condition], env: &ApplyEnvironment) -> bool {
+    conditions.iter().all(|condition| condition.holds(env))
+}
+
+/// Component names are compared case-insensitively
+pub fn normalize_component(name: &str) -> Result<String> {
+    let name = name.trim().to_ascii_lowercase();
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ',' || c == '=') {
+        return Err(anyhow!("Invalid component name: `{}`", name));
+    }
+    Ok(name)
+}
+
+/// The system a patch is applied to, as seen by conditions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplyEnvironment {
+    pub platform: String,
+    pub arch: String,
+    pub components: HashSet<String>,
+}
+
+impl ApplyEnvironment {
+    /// The system this program runs on, without any optional components
+    pub fn current() -> Self {
+        ApplyEnvironment {
+            platform: current_platform().to_string(),
+            arch: current_arch().to_string(),
+            components: HashSet::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(platform: &str, arch: &str, components: &[&str]) -> ApplyEnvironment {
+        ApplyEnvironment {
+            platform: platform.to_string(),
+            arch: arch.to_string(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn aliases_are_normalized() {
+        assert_eq!(normalize_platform(" Win64 ").unwrap(), "windows");
+        assert_eq!(normalize_platform("darwin").unwrap(), "macos");
+        assert_eq!(normalize_arch("AMD64").unwrap(), "x86_64");
+        assert_eq!(normalize_arch("i686").unwrap(), "x86");
+        assert!(normalize_platform("plan9").is_err());
+        assert!(normalize_arch("z80").is_err());
+    }
+
+    #[test]
+    fn parse_and_display_round_trip() {
+        let condition: Condition = "os=osx".parse().unwrap();
+        assert_eq!(condition, Condition { key: ConditionKey::Platform, value: "macos".into(), negated: false });
+        assert_eq!(condition.to_string(), "platform=macos");
+        let condition: Condition = "component != HD-Textures".parse().unwrap();
+        assert_eq!(condition.to_string(), "component!=hd-textures");
+        assert_eq!(condition.to_string().parse::<Condition>().unwrap(), condition);
+    }
+
+    #[test]
+    fn invalid_conditions_are_rejected() {
+        for text in ["windows", "color=red", "platform=beos", "component=", "component=a b"] {
+            assert!(text.parse::<Condition>().is_err(), "{}", text);
+        }
+        assert!(parse_conditions(" , ").is_err());
+    }
+
+    #[test]
+    fn all_conditions_must_hold() {
+        let conditions = parse_conditions("platform=linux, arch!=x86, component=extras").unwrap();
+        assert!(all_hold(&conditions, &env("linux", "aarch64", &["extras"])));
+        assert!(!all_hold(&conditions, &env("linux", "x86", &["extras"])));
+        assert!(!all_hold(&conditions, &env("windows", "aarch64", &["extras"])));
+        assert!(!all_hold(&conditions, &env("linux", "aarch64", &[])));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::diff::HashAlgorithm;
+use crate::digest::DigestEncoding;
+use crate::units::ByteSize;
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory name of the user and system configuration
+pub const CONFIG_DIR_NAME: &str = "file-diff-extractor";
+
+/// File name of a project configuration, looked up from the current directory upwards
+pub const PROJECT_CONFIG_NAME: &str = ".file-diff-extractor.toml";
+
+/// Defaults for command-line options, read from `config.toml` files
+///
+/// The files are a small subset of TOML: `key = value` lines with strings, integers and
+/// arrays of strings, and `#` comments; tables are not used. For example:
+///
+/// ```toml
+/// exclude_extensions = [".tmp", ".bak"]
+/// exclude_dirs = ["node_modules"]
+/// ignore = ["**/cache/**"]
+/// digest_encoding = "base64"
+/// hash_algorithm = "blake3"
+/// max_file_size = "2GiB"
+/// threads = 4
+/// stats_file = "~/.local/state/diffpatch/stats.jsonl"
+/// ```
+///
+/// Where several files are found (see `discover`), the exclude lists and `ignore`
+/// patterns of all of them add up, so standard excludes set system-wide always apply.
+/// For the other keys the most specific file wins: project over user over system, and
+/// an option given on the command line over all of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub exclude_extensions: Vec<String>,
+    pub exclude_dirs: Vec<String>,
+    /// `.gitignore`-style exclude patterns, as `--ignore`
+    pub ignore: Vec<String>,
+    pub include_patterns: Option<Vec<String>>,
+    pub max_file_size: Option<ByteSize>,
+    pub digest_encoding: Option<DigestEncoding>,
+    pub hash_algorithm: Option<HashAlgorithm>,
+    pub threads: Option<usize>,
+    /// Where to keep local usage statistics, see `stats::stats_file`; a leading `~/`
+    /// stands for the home directory
+    pub stats_file: Option<PathBuf>,
+    /// Files the settings were read from, least specific first
+    pub sources: Vec<PathBuf>,
+}
+
+/// Level a configuration file applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfigScope {
+    System,
+    User,
+    Project,
+}
+
+impl Config {
+    /// Read the configuration files that exist, from system to project, and merge them
+    ///
+    /// The project configuration is the nearest `PROJECT_CONFIG_NAME` in `current_dir`
+    /// or one of its parents.
+    pub fn discover(current_dir: &Path) -> Result<Self> {
+        let mut config = Config::default();
+        for (_, path) in config_paths(current_dir) {
+            if path.is_file() {
+                config = config.merge(Config::load(&path)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read one configuration file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read configuration: {}", path.display()))?;
+        let mut config = Config { sources: vec![path.to_path_buf()], ..Config::default() };
+        for (number, key, value) in parse_entries(&text).with_context(|| format!("Invalid configuration: {}", path.display()))? {
+            config
+                .set(&key, value)
+                .with_context(|| format!("Invalid setting `{}` at line {} of {}", key, number, path.display()))?;
+        }
+        Ok(config)
+    }
+
+    /// Add the settings of a more specific configuration
+    pub fn merge(mut self, other: Config) -> Self {
+        self.exclude_extensions.extend(other.exclude_extensions);
+        self.exclude_dirs.extend(other.exclude_dirs);
+        self.ignore.extend(other.ignore);
+        self.include_patterns = other.include_patterns.or(self.include_patterns);
+        self.max_file_size = other.max_file_size.or(self.max_file_size);
+        self.digest_encoding = other.digest_encoding.or(self.digest_encoding);
+        self.hash_algorithm = other.hash_algorithm.or(self.hash_algorithm);
+        self.threads = other.threads.or(self.threads);
+        self.stats_file = other.stats_file.or(self.stats_file);
+        self.sources.extend(other.sources);
+        self
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<()> {
+        match key {
+            "exclude_extensions" => self.exclude_extensions = value.into_list()?,
+            "exclude_dirs" => self.exclude_dirs = value.into_list()?,
+            "ignore" => self.ignore = value.into_list()?,
+            "include_patterns" => self.include_patterns = Some(value.into_list()?),
+            "max_file_size" => {
+                self.max_file_size = Some(match value {
+                    Value::Integer(bytes) => ByteSize(bytes),
+                    value => value.into_string()?.parse()?,
+                })
+            }
+            "digest_encoding" => self.digest_encoding = Some(value.into_string()?.parse()?),
+            "hash_algorithm" => self.hash_algorithm = Some(value.into_string()?.parse()?),
+            "threads" => self.threads = Some(usize::try_from(value.into_integer()?)?),
+            "stats_file" => self.stats_file = Some(expand_home(&value.into_string()?)),
+            _ => return Err(anyhow!("Unknown setting")),
+        }
+        Ok(())
+    }
+}
+
+/// Where configuration files are looked for, least specific first
+///
+/// - System: `/etc/file-diff-extractor/config.toml`, on Windows
+///   `%ProgramData%\file-diff-extractor\config.toml`
+/// - User: `$XDG_CONFIG_HOME/file-diff-extractor/config.toml` (default `~/.config`), on
+///   Windows `%APPDATA%\file-diff-extractor\config.toml`
+/// - Project: the nearest `.file-diff-extractor.toml` in `current_dir` or a parent
+pub fn config_paths(current_dir: &Path) -> Vec<(ConfigScope, PathBuf)> {
+    let mut paths = Vec::new();
+    let system_dir = if cfg!(windows) {
+        env::var_os("ProgramData").map(PathBuf::from)
+    } else {
+        Some(PathBuf::from("/etc"))
+    };
+    if let Some(dir) = system_dir {
+        paths.push((ConfigScope::System, dir.join(CONFIG_DIR_NAME).join("config.toml")));
+    }
+    let user_dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    };
+    if let Some(dir) = user_dir {
+        paths.push((ConfigScope::User, dir.join(CONFIG_DIR_NAME).join("config.toml")));
+    }
+    if let Some(project) = current_dir.ancestors().map(|dir| dir.join(PROJECT_CONFIG_NAME)).find(|path| path.is_file()) {
+        paths.push((ConfigScope::Project, project));
+    }
+    paths
+}
+
+/// `path` with a leading `~/` replaced by the home directory, if it is known
+fn expand_home(path: &str) -> PathBuf {
+    let home = env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" });
+    match (path.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Value of a configuration key
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(u64),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn into_string(self) -> Result<String> {
+        match self {
+            Value::String(text) => Ok(text),
+            _ => Err(anyhow!("Expected a string")),
+        }
+    }
+
+    fn into_integer(self) -> Result<u64> {
+        match self {
+            Value::Integer(number) => Ok(number),
+            _ => Err(anyhow!("Expected a number")),
+        }
+    }
+
+    fn into_list(self) -> Result<Vec<String>> {
+        match self {
+            Value::List(items) => Ok(items),
+            _ => Err(anyhow!("Expected an array of strings")),
+        }
+    }
+}
+
+/// The `key = value` entries of a configuration, with the line each starts on
+fn parse_entries(text: &str) -> Result<Vec<(usize, String, Value)>> {
+    let mut entries = Vec::new();
+    let mut lines = text.lines().enumerate().map(|(index, line)| (index + 1, line));
+    while let Some((number, line)) = lines.next() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(anyhow!("Line {}: tables are not supported, put settings at the top level", number));
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| anyhow!("Line {}: expected key = value", number))?;
+        let key = key.trim().trim_matches('"').to_string();
+        let mut value = value.trim().to_string();
+        // Arrays may continue over the following lines
+        if value.starts_with('[') {
+            while !strip_comment(&value).trim_end().ends_with(']') {
+                let (_, next) = lines.next().ok_or_else(|| anyhow!("Line {}: array is not closed", number))?;
+                value.push(' ');
+                value.push_str(strip_comment(next));
+            }
+        }
+        let value = parse_value(strip_comment(&value).trim()).with_context(|| format!("Line {}", number))?;
+        entries.push((number, key, value));
+    }
+    Ok(entries)
+}
+
+fn parse_value(text: &str) -> Result<Value> {
+    if let Some(items) = text.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        let mut list = Vec::new();
+        let mut rest = items.trim();
+        while !rest.is_empty() {
+            let (item, after) = parse_string(rest)?;
+            list.push(item);
+            rest = after.trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(after) => after.trim_start(),
+                None if rest.is_empty() => rest,
+                None => return Err(anyhow!("Expected `,` between array items")),
+            };
+        }
+        return Ok(Value::List(list));
+    }
+    if text.starts_with(['"', '\'']) {
+        let (value, rest) = parse_string(text)?;
+        if !rest.trim().is_empty() {
+            return Err(anyhow!("Unexpected text after string: {}", rest.trim()));
+        }
+        return Ok(Value::String(value));
+    }
+    text.replace('_', "")
+        .parse()
+        .map(Value::Integer)
+        .map_err(|_| anyhow!("Invalid value: {} (expected a string, number or array)", text))
+}
+
+/// A quoted string at the start of `text` and the text after it
+///
+/// Double-quoted strings take the escapes `\"`, `\\`, `\n` and `\t`; single-quoted
+/// strings are literal.
+fn parse_string(text: &str) -> Result<(String, &str)> {
+    let quote = text.chars().next().filter(|c| matches!(c, '"' | '\'')).ok_or_else(|| anyhow!("Expected a quoted string: {}", text))?;
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((value, &text[index + 1..])),
+            '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c @ ('"' | '\\')) => value.push(c),
+                _ => return Err(anyhow!("Unsupported escape in string: {}", text)),
+            },
+            c => value.push(c),
+        }
+    }
+    Err(anyhow!("String is not closed: {}", text))
+}
+
+/// `line` up to a `#` that is not inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..index],
+            None => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn values_arrays_and_comments() {
+        let text = "# defaults\n\
+            exclude_extensions = [\".tmp\", '.bak'] # trailing comment\n\
+            exclude_dirs = [\n    \"node_modules\", # one per line\n    \"dir # with hash\",\n]\n\
+            \"threads\" = 1_000\n\
+            digest_encoding = \"base64\"\n\
+            ignore = [\"a\\\"b\", 'c\\d']\n";
+        let entries = parse_entries(text).unwrap();
+        let values: Vec<(&str, &Value)> = entries.iter().map(|(_, key, value)| (key.as_str(), value)).collect();
+        assert_eq!(
+            values,
+            [
+                ("exclude_extensions", &Value::List(vec![".tmp".into(), ".bak".into()])),
+                ("exclude_dirs", &Value::List(vec!["node_modules".into(), "dir # with hash".into()])),
+                ("threads", &Value::Integer(1000)),
+                ("digest_encoding", &Value::String("base64".into())),
+                ("ignore", &Value::List(vec!["a\"b".into(), "c\\d".into()])),
+            ]
+        );
+        // Line numbers are those the entries start on
+        assert_eq!(entries.iter().map(|(number, _, _)| *number).collect::<Vec<_>>(), [2, 3, 7, 8, 9]);
+        assert_eq!(parse_entries("ignore = []\n").unwrap()[0].2, Value::List(Vec::new()));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        for text in ["[section]\n", "threads\n", "ignore = [\"a\"\n", "ignore = [\"a\" \"b\"]\n", "digest_encoding = \"hex\n", "threads = -4\n", "threads = four\n", "a = \"x\" y\n", "a = \"\\q\"\n"] {
+            assert!(parse_entries(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn bad_types_and_keys_name_the_setting_and_line() {
+        let dir = TempDir::new();
+        for (text, expected) in [
+            ("\nthreads = \"4\"\n", "Invalid setting `threads` at line 2"),
+            ("exclude_dirs = \"build\"\n", "Invalid setting `exclude_dirs` at line 1"),
+            ("digest_encoding = 64\n", "Invalid setting `digest_encoding`"),
+            ("hash_algorithm = \"md5\"\n", "Invalid setting `hash_algorithm`"),
+            ("colour = \"red\"\n", "Invalid setting `colour`"),
+        ] {
+            let path = dir.write("config.toml", text.as_bytes());
+            let error = format!("{:#}", Config::load(&path).unwrap_err());
+            assert!(error.contains(expected), "{}: {}", text, error);
+        }
+    }
+
+    #[test]
+    fn settings_are_typed() {
+        let dir = TempDir::new();
+        let path = dir.write(
+            "config.toml",
+            b"max_file_size = \"2GiB\"\nhash_algorithm = \"blake3\"\ndigest_encoding = \"base32\"\nthreads = 4\ninclude_patterns = [\"*.rs\"]\n",
+        );
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.max_file_size, Some(ByteSize(2 << 30)));
+        assert_eq!(config.hash_algorithm, Some(HashAlgorithm::Blake3));
+        assert_eq!(config.digest_encoding, Some(DigestEncoding::Base32));
+        assert_eq!(config.threads, Some(4));
+        assert_eq!(config.include_patterns, Some(vec!["*.rs".to_string()]));
+        assert_eq!(config.sources, [path]);
+        let path = dir.write("bytes.toml", b"max_file_size = 4096\n");
+        assert_eq!(Config::load(&path).unwrap().max_file_size, Some(ByteSize(4096)));
+    }
+
+    #[test]
+    fn lists_add_up_and_specific_files_win() {
+        let system = Config { exclude_dirs: vec!["build".into()], threads: Some(2), max_file_size: Some(ByteSize(1)), ..Config::default() };
+        let project = Config { exclude_dirs: vec!["target".into()], threads: Some(8), ..Config::default() };
+        let merged = system.merge(project);
+        assert_eq!(merged.exclude_dirs, ["build", "target"]);
+        assert_eq!(merged.threads, Some(8));
+        assert_eq!(merged.max_file_size, Some(ByteSize(1)));
+    }
+
+    #[test]
+    fn project_configuration_is_found_in_a_parent() {
+        let dir = TempDir::new();
+        let project = dir.write(PROJECT_CONFIG_NAME, b"exclude_dirs = [\"out\"]\n");
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        let paths = config_paths(&dir.path().join("a/b"));
+        assert_eq!(paths.last(), Some(&(ConfigScope::Project, project)));
+    }
+}
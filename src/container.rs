@@ -0,0 +1,518 @@
+use crate::compression::{self, CompressionSettings};
+use crate::patch::{self, PatchData, PATCH_END_MARKER};
+use crate::staging::{StagingFile, TempFile};
+use crate::tar::{TarEntry, TarKind, TarReader, TarWriter};
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Entry holding the patch manifest (the serialized `PatchData`) in ZIP and tar containers
+pub const PATCH_DATA_ENTRY: &str = "patch_data.json";
+
+/// Directory of ZIP and tar containers holding the patch content, under the entry names
+/// of the content archive of a patch executable
+pub const CONTENT_DIR: &str = "content";
+
+const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+const ZSTD_MAGIC: &[u8; 4] = b"\x28\xB5\x2F\xFD";
+
+/// Container a patch is packaged in
+///
+/// All of them hold the same patch manifest and content, so a patch can be moved
+/// between them without comparing the trees again (see `convert`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// The diffpatch program with the patch appended, which applies itself when run
+    Executable,
+    /// Plain ZIP archive with `patch_data.json` and the content under `content/`; the
+    /// content entries keep their compression
+    Zip,
+    /// tar archive compressed with Zstandard (`.tar.zst`), laid out like `Zip`
+    TarZst,
+}
+
+impl PatchFormat {
+    /// Format matching the extension of `path` (`.exe`, `.zip`, `.tar.zst` or `.tzst`), if any
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".exe") {
+            Some(PatchFormat::Executable)
+        } else if name.ends_with(".zip") {
+            Some(PatchFormat::Zip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(PatchFormat::TarZst)
+        } else {
+            None
+        }
+    }
+
+    /// Format of the patch file at `path`, from its content
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open patch file: {}", path.display()))?;
+        if patch::read_patch_header(&mut file).is_ok() {
+            return Ok(PatchFormat::Executable);
+        }
+        let mut magic = [0u8; 4];
+        file.seek(SeekFrom::Start(0)).and_then(|_| file.read_exact(&mut magic))
+            .map_err(|_| anyhow!("Not a patch file: {}", path.display()))?;
+        match &magic {
+            ZIP_MAGIC => Ok(PatchFormat::Zip),
+            ZSTD_MAGIC => Ok(PatchFormat::TarZst),
+            _ => Err(anyhow!("Not a patch file (expected a patch executable, zip or tar.zst): {}", path.display())),
+        }
+    }
+}
+
+impl fmt::Display for PatchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PatchFormat::Executable => "exe",
+            PatchFormat::Zip => "zip",
+            PatchFormat::TarZst => "tar.zst",
+        })
+    }
+}
+
+impl FromStr for PatchFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "exe" | "executable" => Ok(PatchFormat::Executable),
+            "zip" => Ok(PatchFormat::Zip),
+            "tar.zst" | "tar-zst" | "tzst" => Ok(PatchFormat::TarZst),
+            _ => Err(anyhow!("Unknown patch format: {} (expected exe, zip or tar.zst)", value)),
+        }
+    }
+}
+
+/// The two parts of every patch, independent of its container
+#[derive(Debug, Clone)]
+pub struct PatchParts {
+    /// Serialized `PatchData`, exactly as stored, so its fingerprint stays the same
+    pub manifest: Vec<u8>,
+    /// ZIP archive of the stored files, as a patch executable holds it
+    pub content: PatchContent,
+}
+
+/// ZIP archive of the stored files of a patch, read from disk instead of memory, so
+/// patches with files of many gigabytes need no more memory than small ones
+///
+/// It is the content section of a patch executable, read in place, or a temporary
+/// archive rebuilt from a ZIP or tar.zst container, removed with the last clone.
+#[derive(Debug, Clone)]
+pub struct PatchContent {
+    path: PathBuf,
+    start: u64,
+    len: u64,
+    _temp: Option<Arc<TempFile>>,
+}
+
+impl PatchContent {
+    /// The `len` bytes of the file at `path` from `start` on
+    pub fn section(path: &Path, start: u64, len: u64) -> Self {
+        PatchContent { path: path.to_path_buf(), start, len, _temp: None }
+    }
+
+    /// The whole of `temp`, removed once this and its clones are dropped
+    fn temporary(temp: TempFile) -> Result<Self> {
+        let len = fs::metadata(temp.path()).context("Failed to get file metadata")?.len();
+        Ok(PatchContent { path: temp.path().to_path_buf(), start: 0, len, _temp: Some(Arc::new(temp)) })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// A new reader over the archive, e.g. for `zip::ZipArchive`
+    pub fn open(&self) -> Result<SectionReader> {
+        let file = File::open(&self.path).with_context(|| format!("Failed to open patch file: {}", self.path.display()))?;
+        let mut reader = SectionReader { file: BufReader::new(file), start: self.start, len: self.len, position: 0 };
+        reader.seek(SeekFrom::Start(0)).context("Failed to read patch content")?;
+        Ok(reader)
+    }
+}
+
+/// Reader over a byte range of a file, seen as a file of its own
+#[derive(Debug)]
+pub struct SectionReader {
+    file: BufReader<File>,
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+impl Read for SectionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.len.saturating_sub(self.position);
+        let want = (buf.len() as u64).min(left) as usize;
+        let read = self.file.read(&mut buf[..want])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SectionReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the section"))?;
+        self.file.seek(SeekFrom::Start(self.start + position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Empty temporary file and a ZIP writer on it, for a content archive being rebuilt
+fn temporary_archive() -> Result<(TempFile, ZipWriter<BufWriter<File>>)> {
+    let temp = TempFile::new("content")?;
+    let file = File::create(temp.path()).with_context(|| format!("Failed to create file: {}", temp.path().display()))?;
+    Ok((temp, ZipWriter::new(BufWriter::new(file))))
+}
+
+/// Finish a content archive started with `temporary_archive`
+fn finish_archive(temp: TempFile, zip: ZipWriter<BufWriter<File>>) -> Result<PatchContent> {
+    zip.finish().context("Failed to finish zip file")?.flush().context("Failed to write patch content")?;
+    PatchContent::temporary(temp)
+}
+
+/// Read the manifest and content of the patch at `path`, in any container
+///
+/// Content read from a tar container is put into a ZIP archive with `compression`, except
+/// for entries compressed with a codec, which are stored. Content of a ZIP or tar.zst
+/// container is rebuilt in a temporary file, that of a patch executable read in place.
+pub fn read(path: &Path, compression: CompressionSettings) -> Result<(PatchFormat, PatchParts)> {
+    let format = PatchFormat::detect(path)?;
+    let parts = match format {
+        PatchFormat::Executable => {
+            let (manifest, content) = patch::read_patch_sections(path)?;
+            PatchParts { manifest, content }
+        }
+        PatchFormat::Zip => read_zip(path)?,
+        PatchFormat::TarZst => read_tar_zst(path, compression)?,
+    };
+    Ok((format, parts))
+}
+
+/// Read only the manifest of the patch at `path`, in any container
+pub fn read_manifest(path: &Path) -> Result<Vec<u8>> {
+    match PatchFormat::detect(path)? {
+        PatchFormat::Executable => patch::read_manifest_section(path),
+        PatchFormat::Zip => {
+            let mut archive = open_zip(path)?;
+            let mut entry = archive.by_name(PATCH_DATA_ENTRY).map_err(|_| not_a_patch(path))?;
+            let mut manifest = Vec::new();
+            entry.read_to_end(&mut manifest).context("Failed to read patch data")?;
+            Ok(manifest)
+        }
+        PatchFormat::TarZst => {
+            let mut tar = open_tar_zst(path)?;
+            while let Some(header) = tar.next_entry()? {
+                if header.name == PATCH_DATA_ENTRY {
+                    return tar.read_data();
+                }
+            }
+            Err(not_a_patch(path))
+        }
+    }
+}
+
+fn not_a_patch(path: &Path) -> anyhow::Error {
+    anyhow!("Not a patch archive (no {}): {}", PATCH_DATA_ENTRY, path.display())
+}
+
+fn open_zip(path: &Path) -> Result<ZipArchive<BufReader<File>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open patch file: {}", path.display()))?;
+    ZipArchive::new(BufReader::new(file)).with_context(|| format!("Failed to read zip archive: {}", path.display()))
+}
+
+fn open_tar_zst(path: &Path) -> Result<TarReader<impl Read>> {
+    let file = File::open(path).with_context(|| format!("Failed to open patch file: {}", path.display()))?;
+    let decoder = zstd::stream::read::Decoder::new(file).context("Failed to start Zstandard decompression")?;
+    Ok(TarReader::new(decoder))
+}
+
+/// Entry name in the content archive of a container entry, `None` if it is not content
+fn content_name(name: &str) -> Option<&str> {
+    name.strip_prefix(CONTENT_DIR)?.strip_prefix('/').filter(|name| !name.is_empty() && !name.ends_with('/'))
+}
+
+fn read_zip(path: &Path) -> Result<PatchParts> {
+    let mut archive = open_zip(path)?;
+    let mut manifest = None;
+    let (temp, mut content) = temporary_archive()?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).context("Failed to access zip file entry")?;
+        if entry.name() == PATCH_DATA_ENTRY {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).context("Failed to read patch data")?;
+            manifest = Some(data);
+        } else if let Some(name) = content_name(entry.name()).map(str::to_string) {
+            // Copied as compressed, nothing is decompressed
+            compression::copy_zip_entry(&mut content, entry, &name).context("Failed to copy patch content")?;
+        }
+    }
+    let manifest = manifest.ok_or_else(|| not_a_patch(path))?;
+    let content = finish_archive(temp, content)?;
+    Ok(PatchParts { manifest, content })
+}
+
+fn read_tar_zst(path: &Path, compression: CompressionSettings) -> Result<PatchParts> {
+    let mut tar = open_tar_zst(path)?;
+    let (temp, mut content) = temporary_archive()?;
+    let mut manifest = None;
+    // Content ahead of the manifest is held until its codecs are known; the
+    // rest is streamed, as written by `write_patch`
+    let mut pending: Vec<(String, u32, Vec<u8>)> = Vec::new();
+    while let Some(header) = tar.next_entry()? {
+        if header.name == PATCH_DATA_ENTRY {
+            let data = tar.read_data()?;
+            let patch_data = patch::parse_manifest_bytes(&data)?;
+            for (name, mode, data) in pending.drain(..) {
+                start_content(&mut content, &patch_data, compression, &name, mode, data.len() as u64)?;
+                content.write_all(&data).with_context(|| format!("Failed to write to zip: {}", name))?;
+            }
+            manifest = Some((data, patch_data));
+        } else if header.kind == TarKind::File && let Some(name) = content_name(&header.name) {
+            let name = name.to_string();
+            match &manifest {
+                Some((_, patch_data)) => {
+                    start_content(&mut content, patch_data, compression, &name, header.mode, header.size)?;
+                    tar.copy_data(&mut content).with_context(|| format!("Failed to write to zip: {}", name))?;
+                }
+                None => pending.push((name, header.mode, tar.read_data()?)),
+            }
+        }
+    }
+    let (manifest, _) = manifest.ok_or_else(|| not_a_patch(path))?;
+    let content = finish_archive(temp, content)?;
+    Ok(PatchParts { manifest, content })
+}
+
+/// Start the content archive entry `name` of `size` bytes taken from a tar.zst
+fn start_content<W: Write + Seek>(
+    content: &mut ZipWriter<W>,
+    patch_data: &PatchData,
+    compression: CompressionSettings,
+    name: &str,
+    mode: u32,
+    size: u64,
+) -> Result<()> {
+    // Entries compressed with a codec are stored as they are
+    let settings = if patch_data.entry_codecs.contains_key(name) {
+        CompressionSettings::new(CompressionMethod::Stored, None)
+    } else {
+        compression
+    };
+    let options = settings.file_options().unix_permissions(mode).large_file(compression::needs_zip64(size));
+    content.start_file(name, options).with_context(|| format!("Failed to start zip file: {}", name))?;
+    Ok(())
+}
+
+/// Length of the program part of `path`: everything before the patch of a patch
+/// executable, else the whole file
+fn program_len(path: &Path) -> Result<u64> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open executable file: {}", path.display()))?;
+    let file_size = file.metadata().context("Failed to get file metadata")?.len();
+    match patch::read_patch_header(&mut file) {
+        Ok(header) => header.manifest_offset(file_size),
+        Err(_) => Ok(file_size),
+    }
+}
+
+/// Write `parts` to `output` in `format`, replacing it only once complete
+///
+/// A patch executable is built from the program part of `program`: a diffpatch program,
+/// or another patch executable whose patch is left out. `level` is the Zstandard level
+/// of a tar.zst container (default 19).
+pub fn write(parts: &PatchParts, format: PatchFormat, output: &Path, program: &Path, level: Option<i32>) -> Result<()> {
+    let staged = StagingFile::new(output)?;
+    let file = File::create(staged.path()).with_context(|| format!("Failed to create file: {}", staged.path().display()))?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        PatchFormat::Executable => {
+            let mut input = File::open(program).with_context(|| format!("Failed to open executable file: {}", program.display()))?;
+            let length = program_len(program)?;
+            let copied = io::copy(&mut (&mut input).take(length), &mut writer).context("Failed to copy the program")?;
+            if copied != length {
+                return Err(anyhow!("Executable file changed while it was copied: {}", program.display()));
+            }
+            writer.write_all(&parts.manifest).context("Failed to write patch data")?;
+            let copied = io::copy(&mut parts.content.open()?, &mut writer).context("Failed to write content data")?;
+            if copied != parts.content.len() {
+                return Err(anyhow!("Patch content is shorter than recorded"));
+            }
+            writer.write_all(&(parts.manifest.len() as u64).to_le_bytes())?;
+            writer.write_all(&parts.content.len().to_le_bytes())?;
+            writer.write_all(PATCH_END_MARKER).context("Failed to write end marker")?;
+            writer.flush().context("Failed to write patch")?;
+            // Runnable like the program it was built from
+            let permissions = fs::metadata(program).context("Failed to get file metadata")?.permissions();
+            fs::set_permissions(staged.path(), permissions).context("Failed to set permissions")?;
+        }
+        PatchFormat::Zip => {
+            let mut zip = ZipWriter::new(writer);
+            let stored = CompressionSettings::new(CompressionMethod::Stored, None).file_options();
+            zip.start_file(PATCH_DATA_ENTRY, stored).context("Failed to start zip file")?;
+            zip.write_all(&parts.manifest).context("Failed to write patch data")?;
+            let mut content = ZipArchive::new(parts.content.open()?).context("Failed to read patch content")?;
+            for index in 0..content.len() {
+                let entry = content.by_index(index).context("Failed to access zip file entry")?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let name = format!("{}/{}", CONTENT_DIR, entry.name());
+                compression::copy_zip_entry(&mut zip, entry, &name).context("Failed to copy patch content")?;
+            }
+            zip.finish().context("Failed to finish zip file")?.flush().context("Failed to write patch")?;
+        }
+        PatchFormat::TarZst => {
+            let encoder = zstd::stream::write::Encoder::new(writer, level.unwrap_or(19))
+                .context("Failed to start Zstandard compression")?;
+            let mut tar = TarWriter::new(encoder);
+            let manifest = &mut parts.manifest.as_slice();
+            tar.append(PATCH_DATA_ENTRY, TarEntry::File(manifest, parts.manifest.len() as u64), 0o644, 0)?;
+            let mut content = ZipArchive::new(parts.content.open()?).context("Failed to read patch content")?;
+            for index in 0..content.len() {
+                let mut entry = content.by_index(index).context("Failed to access zip file entry")?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let name = format!("{}/{}", CONTENT_DIR, entry.name());
+                let (size, mode) = (entry.size(), entry.unix_mode().unwrap_or(0o644));
+                tar.append(&name, TarEntry::File(&mut entry, size), mode, 0)?;
+            }
+            tar.finish()?.finish().context("Failed to finish Zstandard compression")?.flush().context("Failed to write patch")?;
+        }
+    }
+    staged.persist()
+}
+
+/// What `convert` did
+#[derive(Debug, Clone)]
+pub struct ConvertSummary {
+    pub from: PatchFormat,
+    pub to: PatchFormat,
+    /// Files in the patch content
+    pub entries: usize,
+    /// Size of the written patch
+    pub size: u64,
+}
+
+/// Re-package the patch at `input` as `format` at `output`, without comparing anything
+/// again
+///
+/// The patch manifest is copied byte for byte, so its fingerprint (the SHA-256 that
+/// verification records refer to) does not change, and content entries keep their
+/// compression except when read from a tar container. A patch executable is built from
+/// `program` (see `write`); by default from the program of `input` if that is a patch
+/// executable, else from the running one. Signatures made over a whole patch file do
+/// not carry over, as the file itself changes.
+pub fn convert(
+    input: &Path,
+    output: &Path,
+    format: PatchFormat,
+    program: Option<&Path>,
+    compression: CompressionSettings,
+    level: Option<i32>,
+) -> Result<ConvertSummary> {
+    let (from, parts) = read(input, compression)?;
+    let program = match program {
+        Some(program) => program.to_path_buf(),
+        None if from == PatchFormat::Executable => input.to_path_buf(),
+        None => std::env::current_exe().context("Failed to get current executable path")?,
+    };
+    let program = program.as_path();
+    let entries = ZipArchive::new(parts.content.open()?).context("Failed to read patch content")?.len();
+    // Fail before writing anything if the manifest cannot be read, or if a patch
+    // executable would hold entries its program cannot decode
+    let patch_data = patch::parse_manifest_bytes(&parts.manifest)?;
+    if format == PatchFormat::Executable {
+        patch_data.check_builtin_codecs()?;
+    }
+    write(&parts, format, output, program, level)?;
+    let size = fs::metadata(output).map_or(0, |metadata| metadata.len());
+    Ok(ConvertSummary { from, to: format, entries, size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// Write a tar.zst patch at `path`: `before` content entries, the manifest, then `after`
+    fn tar_zst_patch(path: &Path, before: &[(&str, u64)], after: &[(&str, u64)]) -> Vec<u8> {
+        let manifest = serde_json::to_vec(&PatchData::from_diffs(Vec::new(), Vec::new())).unwrap();
+        let encoder = zstd::stream::write::Encoder::new(File::create(path).unwrap(), 1).unwrap();
+        let mut tar = TarWriter::new(encoder);
+        let append = |tar: &mut TarWriter<_>, entries: &[(&str, u64)]| {
+            for &(name, size) in entries {
+                let name = format!("{}/{}", CONTENT_DIR, name);
+                tar.append(&name, TarEntry::File(&mut io::repeat(b'x').take(size), size), 0o644, 0).unwrap();
+            }
+        };
+        append(&mut tar, before);
+        tar.append(PATCH_DATA_ENTRY, TarEntry::File(&mut manifest.as_slice(), manifest.len() as u64), 0o644, 0).unwrap();
+        append(&mut tar, after);
+        tar.finish().unwrap().finish().unwrap();
+        manifest
+    }
+
+    /// Size of every entry of the content archive, checked against the bytes read
+    fn content_sizes(content: &PatchContent) -> Vec<(String, u64)> {
+        let mut archive = ZipArchive::new(content.open().unwrap()).unwrap();
+        (0..archive.len()).map(|index| {
+            let mut entry = archive.by_index(index).unwrap();
+            let size = entry.size();
+            assert_eq!(io::copy(&mut entry, &mut io::sink()).unwrap(), size);
+            (entry.name().to_string(), size)
+        }).collect()
+    }
+
+    #[test]
+    fn section_reader_stays_in_its_section() {
+        let dir = TempDir::new();
+        let path = dir.write("file", b"headCONTENTtail");
+        let mut reader = PatchContent::section(&path, 4, 7).open().unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "CONTENT");
+        assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 4);
+        assert!(reader.seek(SeekFrom::Current(-5)).is_err());
+    }
+
+    #[test]
+    fn tar_zst_content_is_read_around_the_manifest() {
+        let dir = TempDir::new();
+        let path = dir.path().join("patch.tar.zst");
+        let manifest = tar_zst_patch(&path, &[("before", 10)], &[("after", 20)]);
+        let (format, parts) = read(&path, CompressionSettings::default()).unwrap();
+        assert_eq!(format, PatchFormat::TarZst);
+        assert_eq!(parts.manifest, manifest);
+        assert_eq!(content_sizes(&parts.content), [("before".to_string(), 10), ("after".to_string(), 20)]);
+    }
+
+    #[test]
+    #[ignore = "writes more than 4 GiB"]
+    fn tar_zst_zip64_content_is_streamed() {
+        let dir = TempDir::new();
+        let path = dir.path().join("patch.tar.zst");
+        let size = u32::MAX as u64 + 4096;
+        tar_zst_patch(&path, &[], &[("large", size)]);
+        let compression = CompressionSettings::new(CompressionMethod::Zstd, Some(1));
+        let (_, parts) = read(&path, compression).unwrap();
+        assert_eq!(content_sizes(&parts.content), [("large".to_string(), size)]);
+    }
+}
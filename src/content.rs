@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Number of bytes at the start of a file that decide whether it is text (as git does)
+pub const SNIFF_LEN: usize = 8000;
+
+/// Byte order marks of text encodings; UTF-32 comes first, as its little-endian mark
+/// starts with that of UTF-16
+const BOMS: &[&[u8]] = &[
+    b"\x00\x00\xfe\xff",
+    b"\xff\xfe\x00\x00",
+    b"\xef\xbb\xbf",
+    b"\xfe\xff",
+    b"\xff\xfe",
+];
+
+/// Whether a file holds text or binary data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ContentKind {
+    Text,
+    Binary,
+}
+
+impl ContentKind {
+    /// Classify a file from its first bytes (at most `SNIFF_LEN` are looked at)
+    ///
+    /// A byte order mark makes it text (UTF-16 and UTF-32 text contains NUL bytes);
+    /// otherwise a NUL byte makes it binary. Empty files are text.
+    pub fn sniff(head: &[u8]) -> Self {
+        let head = &head[..head.len().min(SNIFF_LEN)];
+        if BOMS.iter().any(|bom| head.starts_with(bom)) {
+            return ContentKind::Text;
+        }
+        if head.contains(&0) { ContentKind::Binary } else { ContentKind::Text }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentKind::Text => "text",
+            ContentKind::Binary => "binary",
+        }
+    }
+}
+
+impl fmt::Display for ContentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Classify the file at `path`, reading only its first `SNIFF_LEN` bytes
+pub fn classify_file(path: &Path) -> Result<ContentKind> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut head).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(ContentKind::sniff(&head))
+}
+
+/// Reader that keeps the first `SNIFF_LEN` bytes passing through, to classify a stream
+/// while it is read for something else (such as hashing)
+pub struct Sniffer<R> {
+    inner: R,
+    head: Vec<u8>,
+}
+
+impl<R: Read> Sniffer<R> {
+    pub fn new(inner: R) -> Self {
+        Sniffer { inner, head: Vec::new() }
+    }
+
+    /// Classification of what was read so far
+    pub fn kind(&self) -> ContentKind {
+        ContentKind::sniff(&self.head)
+    }
+}
+
+impl<R: Read> Read for Sniffer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let wanted = SNIFF_LEN.saturating_sub(self.head.len()).min(read);
+        self.head.extend_from_slice(&buf[..wanted]);
+        Ok(read)
+    }
+}
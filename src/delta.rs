@@ -0,0 +1,224 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::time::Instant;
+
+/// First bytes of every delta
+const MAGIC: &[u8] = b"DPDELTA1";
+
+/// Length of the source blocks that are indexed and searched for in the target
+const BLOCK_SIZE: usize = 32;
+
+/// Multiplier of the rolling block hash
+const HASH_BASE: u64 = 0x0000_0100_0000_01b3;
+
+/// Largest file (source or target) a delta is computed for; both are held in memory
+pub const MAX_FILE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Target positions searched between two looks at the clock in `create_until`
+const DEADLINE_CHECK_INTERVAL: u32 = 1 << 16;
+
+const OP_INSERT: u8 = 0;
+const OP_COPY: u8 = 1;
+
+/// Binary delta that turns `source` into `target`
+///
+/// The delta is a list of copies of source ranges and inserted bytes, found by indexing
+/// the source in blocks and extending every block match in both directions. Content
+/// that moved or changed in a few places gives a delta far smaller than the target.
+pub fn create(source: &[u8], target: &[u8]) -> Vec<u8> {
+    create_until(source, target, None).unwrap_or_default()
+}
+
+/// Binary delta like `create`, or `None` if it is not done by `deadline`
+pub fn create_until(source: &[u8], target: &[u8], deadline: Option<Instant>) -> Option<Vec<u8>> {
+    let mut delta = MAGIC.to_vec();
+    write_varint(&mut delta, target.len() as u64);
+
+    let index = index_blocks(source);
+    // Start of the target bytes not yet covered by an operation
+    let mut pending = 0;
+    let mut position = 0;
+    let mut hash = target.get(..BLOCK_SIZE).map_or(0, block_hash);
+    let top_power = HASH_BASE.wrapping_pow(BLOCK_SIZE as u32 - 1);
+    let mut until_check = DEADLINE_CHECK_INTERVAL;
+    while position + BLOCK_SIZE <= target.len() {
+        until_check -= 1;
+        if until_check == 0 {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            until_check = DEADLINE_CHECK_INTERVAL;
+        }
+        if let Some(&offset) = index.get(&hash)
+            && source[offset..offset + BLOCK_SIZE] == target[position..position + BLOCK_SIZE]
+        {
+            let (mut start, mut source_start) = (position, offset);
+            while start > pending && source_start > 0 && target[start - 1] == source[source_start - 1] {
+                start -= 1;
+                source_start -= 1;
+            }
+            let (mut end, mut source_end) = (position + BLOCK_SIZE, offset + BLOCK_SIZE);
+            while end < target.len() && source_end < source.len() && target[end] == source[source_end] {
+                end += 1;
+                source_end += 1;
+            }
+            push_insert(&mut delta, &target[pending..start]);
+            push_copy(&mut delta, source_start, end - start);
+            pending = end;
+            position = end;
+            if let Some(block) = target.get(position..position + BLOCK_SIZE) {
+                hash = block_hash(block);
+            }
+            continue;
+        }
+        if position + BLOCK_SIZE < target.len() {
+            let outgoing = u64::from(target[position]).wrapping_mul(top_power);
+            hash = hash.wrapping_sub(outgoing).wrapping_mul(HASH_BASE).wrapping_add(u64::from(target[position + BLOCK_SIZE]));
+        }
+        position += 1;
+    }
+    push_insert(&mut delta, &target[pending..]);
+    Some(delta)
+}
+
+/// Rebuild the target of a delta from its source
+pub fn apply(source: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut rest = delta.strip_prefix(MAGIC).ok_or_else(|| anyhow!("Not a binary delta"))?;
+    let target_len = read_varint(&mut rest)?;
+    let mut target = Vec::with_capacity(target_len.min(MAX_FILE_SIZE) as usize);
+    while let Some((&op, after)) = rest.split_first() {
+        rest = after;
+        match op {
+            OP_INSERT => {
+                let len = read_varint(&mut rest)? as usize;
+                let bytes = rest.get(..len).ok_or_else(|| anyhow!("Binary delta is truncated"))?;
+                target.extend_from_slice(bytes);
+                rest = &rest[len..];
+            }
+            OP_COPY => {
+                let offset = read_varint(&mut rest)? as usize;
+                let len = read_varint(&mut rest)? as usize;
+                let range = offset.checked_add(len).and_then(|end| source.get(offset..end));
+                target.extend_from_slice(range.ok_or_else(|| anyhow!("Binary delta does not match the file it is applied to"))?);
+            }
+            _ => return Err(anyhow!("Invalid binary delta operation: {}", op)),
+        }
+    }
+    if target.len() as u64 != target_len {
+        return Err(anyhow!("Binary delta produced {} bytes instead of {}", target.len(), target_len));
+    }
+    Ok(target)
+}
+
+/// Offset of the first source block with each hash
+fn index_blocks(source: &[u8]) -> HashMap<u64, usize, BuildHasherDefault<BlockHasher>> {
+    let mut index = HashMap::with_capacity_and_hasher(source.len() / BLOCK_SIZE, BuildHasherDefault::default());
+    for (number, block) in source.chunks_exact(BLOCK_SIZE).enumerate() {
+        index.entry(block_hash(block)).or_insert(number * BLOCK_SIZE);
+    }
+    index
+}
+
+fn block_hash(block: &[u8]) -> u64 {
+    block.iter().fold(0u64, |hash, &byte| hash.wrapping_mul(HASH_BASE).wrapping_add(u64::from(byte)))
+}
+
+/// Block hashes are already well mixed, so the index uses them as they are
+#[derive(Default)]
+struct BlockHasher(u64);
+
+impl Hasher for BlockHasher {
+    fn finish(&self) -> u64 {
+        // Spread the low bits, which the table uses for its buckets
+        self.0.wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(32)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = value;
+    }
+}
+
+fn push_insert(delta: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    delta.push(OP_INSERT);
+    write_varint(delta, bytes.len() as u64);
+    delta.extend_from_slice(bytes);
+}
+
+fn push_copy(delta: &mut Vec<u8>, offset: usize, len: usize) {
+    delta.push(OP_COPY);
+    write_varint(delta, offset as u64);
+    write_varint(delta, len as u64);
+}
+
+/// LEB128: seven bits per byte, high bit set on all but the last
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first().ok_or_else(|| anyhow!("Binary delta is truncated"))?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Invalid number in binary delta"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let delta = create(source, target);
+        assert_eq!(apply(source, &delta).unwrap(), target);
+        delta
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let source: Vec<u8> = (0..20_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut edited = source.clone();
+        edited[40_000..40_010].copy_from_slice(b"0123456789");
+        edited.extend_from_slice(b"appended");
+        let moved = [&source[50_000..], &source[..50_000]].concat();
+        round_trip(&source, &source);
+        round_trip(&source, &[]);
+        round_trip(&[], &source);
+        round_trip(b"short", b"other");
+        assert!(round_trip(&source, &edited).len() < 200);
+        assert!(round_trip(&source, &moved).len() < 200);
+    }
+
+    #[test]
+    fn corrupt_deltas_are_errors() {
+        let source = b"source data that is long enough to hold a few blocks of thirty-two bytes".repeat(4);
+        let target = [&source[10..], b"tail"].concat();
+        let delta = create(&source, &target);
+        assert!(apply(&source, &delta[1..]).is_err());
+        for len in MAGIC.len()..delta.len() {
+            if let Ok(rebuilt) = apply(&source, &delta[..len]) {
+                assert_ne!(rebuilt, target);
+            }
+        }
+        // Copies past the end of a shorter source
+        assert!(apply(&source[..40], &delta).is_err());
+    }
+}
@@ -1,61 +1,445 @@
-use anyhow::{Context, Result};
+use crate::binary;
+use crate::content::{ContentKind, Sniffer, SNIFF_LEN};
+use crate::digest::{self, DigestEncoding, HashAbbreviations};
+use crate::fsops::{CloneDetector, FileMode};
+use crate::hashes::{Blake3, Xxh3};
+use crate::filter::{FilterDecision, FilterList, FilterRule, RuleKind};
+use crate::interrupt::{self, CancelToken};
+use crate::manifest::Manifest;
+pub use crate::paths::normalize_separators;
+use crate::paths::{self, PathOrder};
+use crate::progress::{ProgressHook, ScanCounter};
+use crate::runid;
+use crate::tags::TagRules;
+#[cfg(feature = "serde")]
+use crate::diffcache::{self, CachedComparison, EntryWriter};
+#[cfg(feature = "serde")]
+use crate::hashcache;
+#[cfg(feature = "serde")]
+use crate::snapshot;
+#[cfg(feature = "serde")]
+use crate::staging;
+use crate::stats;
+use crate::timings::Phase;
+use crate::tr;
+use crate::utils::{self, Throttle};
+use crate::vfs::{self, Vfs, VfsMetadata};
+use crate::warning::{ErrorPhase, FileError, Severity, Warning, WarningKind, WarningLog};
+use crate::par::*;
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::fmt;
 use std::fs;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, Read, Write};
+use std::ops::Range;
+use std::str::FromStr;
 use std::path::{Path, PathBuf};
+#[cfg(feature = "serde")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
-use rayon::prelude::*;
-use std::env;
 use similar::TextDiff;
 
-// Optional thread count control
-lazy_static::lazy_static! {
-    static ref IO_THREADS: usize = {
-        match env::var("DIFFPATCH_IO_THREADS") {
-            Ok(val) => val.parse().unwrap_or_else(|_| {
-                // Default to a reasonable number based on available CPUs
-                // For I/O bound operations, using too many threads can hurt performance
-                let cpus = num_cpus::get();
-                std::cmp::min(cpus, 4) // Limit to 4 or CPU count, whichever is smaller
-            }),
-            Err(_) => {
-                let cpus = num_cpus::get();
-                std::cmp::min(cpus, 4)
-            }
-        }
-    };
-}
+/// Custom metadata of a file entry, keyed by name (build IDs, asset GUIDs, review status, ...)
+#[cfg(feature = "serde")]
+pub type Extra = serde_json::Map<String, serde_json::Value>;
 
 /// File information structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FileInfo {
     pub relative_path: PathBuf,
     pub hash: String,
     pub size: u64,
+    /// Where the entry points if it is a symbolic link, which is recorded instead of the
+    /// file it points to; `hash` and `size` then describe this path (see `FileInfo::symlink`)
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub link_target: Option<PathBuf>,
+    /// Permissions of the file when it was scanned; entries read from elsewhere (such as
+    /// older manifests) may not have them
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub mode: Option<FileMode>,
+    /// Whether the file holds text or binary data, found from its first bytes when it was
+    /// hashed; `None` for links and files whose content was not read
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub content: Option<ContentKind>,
+    /// Groups the file belongs to, set by `DiffOptions::tag_rules`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub tags: Vec<String>,
+    /// Set by the `DiffOptions::annotator` hook; carried into reports, manifests and patches
+    #[cfg(feature = "serde")]
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Extra::is_empty"))]
+    pub extra: Extra,
+}
+
+impl FileInfo {
+    pub fn new(relative_path: PathBuf, hash: String, size: u64) -> Self {
+        FileInfo {
+            relative_path,
+            hash,
+            size,
+            link_target: None,
+            mode: None,
+            content: None,
+            tags: Vec::new(),
+            #[cfg(feature = "serde")]
+            extra: Extra::new(),
+        }
+    }
+
+    /// Set the mode from the metadata of the scanned file
+    pub fn with_mode(self, metadata: &fs::Metadata) -> Self {
+        FileInfo { mode: FileMode::of(metadata), ..self }
+    }
+
+    pub fn with_content(self, content: ContentKind) -> Self {
+        FileInfo { content: Some(content), ..self }
+    }
+
+    /// Entry for a symbolic link at `relative_path` pointing to `target`
+    ///
+    /// The hash is taken over `symlink:` and the target path, so a link whose target
+    /// changed shows up as modified, and a link does not match a file by content.
+    pub fn symlink(relative_path: PathBuf, target: PathBuf, algorithm: HashAlgorithm, encoding: DigestEncoding) -> Self {
+        let text = format!("symlink:{}", target.to_string_lossy());
+        let (hash, _, _) = hash_stream(text.as_bytes(), algorithm, encoding).expect("hashing a string cannot fail");
+        let size = target.as_os_str().len() as u64;
+        FileInfo { link_target: Some(target), ..FileInfo::new(relative_path, hash, size) }
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.link_target.is_some()
+    }
+
+    /// Algorithm the hash of the entry was made with
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        digest::algorithm_of(&self.hash)
+    }
+
+    /// Entry for `relative_path` with the hash (by `algorithm`, in `encoding`) and size of
+    /// everything `reader` yields
+    pub fn from_reader(relative_path: impl Into<PathBuf>, reader: impl Read, algorithm: HashAlgorithm, encoding: DigestEncoding) -> Result<Self> {
+        let relative_path = relative_path.into();
+        let (hash, size, content) = hash_stream(reader, algorithm, encoding)
+            .with_context(|| format!("Failed to hash contents of {}", relative_path.display()))?;
+        Ok(FileInfo::new(relative_path, hash, size).with_content(content))
+    }
+}
+
+/// Hook called for every scanned file with its full path, to fill in `FileInfo::extra`
+#[cfg(feature = "serde")]
+#[derive(Clone)]
+pub struct Annotator(Arc<AnnotateFn>);
+
+#[cfg(feature = "serde")]
+type AnnotateFn = dyn Fn(&Path, &mut FileInfo) + Send + Sync;
+
+#[cfg(feature = "serde")]
+impl Annotator {
+    pub fn new(annotate: impl Fn(&Path, &mut FileInfo) + Send + Sync + 'static) -> Self {
+        Annotator(Arc::new(annotate))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::fmt::Debug for Annotator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Annotator")
+    }
 }
 
 /// File difference types
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DiffType {
     Added(FileInfo),    // Added file
     Modified(FileInfo), // Modified file with full content
     ModifiedDiff(FileDiff), // Modified file with only the differences
     Removed(PathBuf),   // Removed file
+    Renamed(FileRename), // File moved to a new path with unchanged content
+    MetadataChanged(FileInfo), // Unchanged content with new permissions (`FileInfo::mode`)
+}
+
+impl DiffType {
+    /// Relative path of the file this difference applies to
+    pub fn path(&self) -> &Path {
+        match self {
+            DiffType::Added(info) | DiffType::Modified(info) | DiffType::MetadataChanged(info) => &info.relative_path,
+            DiffType::ModifiedDiff(diff) => &diff.relative_path,
+            DiffType::Removed(path) => path,
+            DiffType::Renamed(rename) => &rename.to.relative_path,
+        }
+    }
+
+    /// Rewrite the path of this entry with `/` separators
+    pub fn normalize_path(&mut self) {
+        let path = match self {
+            DiffType::Added(info) | DiffType::Modified(info) | DiffType::MetadataChanged(info) => &mut info.relative_path,
+            DiffType::ModifiedDiff(diff) => &mut diff.relative_path,
+            DiffType::Removed(path) => path,
+            DiffType::Renamed(rename) => {
+                rename.from = normalize_separators(&rename.from);
+                &mut rename.to.relative_path
+            }
+        };
+        *path = normalize_separators(path);
+    }
+
+    /// Short human-readable name of the difference type
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DiffType::Added(_) => "added",
+            DiffType::Modified(_) => "modified",
+            DiffType::ModifiedDiff(_) => "modified (diff)",
+            DiffType::Removed(_) => "removed",
+            DiffType::Renamed(_) => "renamed",
+            DiffType::MetadataChanged(_) => "permissions",
+        }
+    }
+
+    /// Hash of the file after the change, if it still exists
+    pub fn hash(&self) -> Option<&str> {
+        match self {
+            DiffType::Added(info) | DiffType::Modified(info) | DiffType::MetadataChanged(info) => Some(&info.hash),
+            DiffType::ModifiedDiff(diff) => Some(&diff.hash),
+            DiffType::Renamed(rename) => Some(&rename.to.hash),
+            DiffType::Removed(_) => None,
+        }
+    }
+}
+
+/// Result of comparing two directories
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DiffReport {
+    /// Run that made the report (see `runid::current`); `None` in reproducible reports
+    /// and reports saved before runs had IDs
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub run_id: Option<String>,
+    pub diffs: Vec<DiffType>,
+    /// Every file of the target tree, embedded into patches (not saved with the report);
+    /// `None` when the target was not fully hashed
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub target_manifest: Option<Manifest>,
+    /// Problems found while scanning and comparing, e.g. unreadable files
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub warnings: Vec<Warning>,
+    /// Member changes of archives that changed, see `DiffOptions::inspect_archives`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub archives: Vec<ArchiveDiff>,
+    /// How much the compared trees differ (not saved with the report)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub stats: ChangeStats,
+    /// Source tree entries of the modified, removed and renamed files, for showing how
+    /// each changed (not saved with the report)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub source_files: HashMap<PathBuf, FileInfo>,
+}
+
+/// How much two compared trees differ, counted when the report is built
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChangeStats {
+    /// Distinct paths in the two trees
+    pub total_files: usize,
+    /// Entries of the report; a rename counts once
+    pub changed_files: usize,
+    /// Size of the added and modified files in the target tree
+    pub changed_bytes: u64,
+}
+
+impl ChangeStats {
+    /// Changed files as a percentage of `total_files`
+    pub fn changed_percent(&self) -> f64 {
+        if self.total_files == 0 {
+            return 0.0;
+        }
+        self.changed_files as f64 * 100.0 / self.total_files as f64
+    }
+}
+
+/// Extensions of the archives whose members `DiffOptions::inspect_archives` compares
+pub const ARCHIVE_EXTENSIONS: &[&str] = &["zip", "jar", "pak"];
+
+/// Which members of a changed archive were added, modified or removed
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ArchiveDiff {
+    /// Path of the archive in the compared trees
+    pub path: PathBuf,
+    /// Changes with paths inside the archive, sorted by path
+    pub members: Vec<DiffType>,
+}
+
+/// Entries that differ between two diff reports
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReportComparison {
+    pub only_in_self: Vec<DiffType>,  // Entries missing from the other report
+    pub only_in_other: Vec<DiffType>, // Entries missing from this report
+}
+
+impl ReportComparison {
+    /// Whether both reports contained exactly the same entries
+    pub fn is_identical(&self) -> bool {
+        self.only_in_self.is_empty() && self.only_in_other.is_empty()
+    }
+}
+
+impl DiffReport {
+    pub fn new(diffs: Vec<DiffType>) -> Self {
+        DiffReport {
+            run_id: Some(runid::current().to_string()),
+            diffs,
+            target_manifest: None,
+            warnings: Vec::new(),
+            archives: Vec::new(),
+            stats: ChangeStats::default(),
+            source_files: HashMap::new(),
+        }
+    }
+
+    /// Short forms of the old and new hashes of every entry, see `HashAbbreviations`
+    pub fn hash_abbreviations(&self) -> HashAbbreviations {
+        let original = self.diffs.iter().filter_map(|diff| match diff {
+            DiffType::ModifiedDiff(file_diff) => Some(file_diff.original_hash.as_str()),
+            _ => None,
+        });
+        let hashes = self.diffs.iter()
+            .filter_map(DiffType::hash)
+            .chain(original)
+            .chain(self.source_files.values().map(|info| info.hash.as_str()));
+        HashAbbreviations::new(hashes)
+    }
+
+    /// Compare with another report, e.g. from a run on another machine or with other options
+    ///
+    /// Entry order does not matter. An entry that exists in both reports with different
+    /// details (such as a changed hash) is listed on both sides.
+    pub fn compare(&self, other: &DiffReport) -> ReportComparison {
+        let mut unmatched: HashMap<&Path, Vec<&DiffType>> = HashMap::new();
+        for diff in &other.diffs {
+            unmatched.entry(diff.path()).or_default().push(diff);
+        }
+
+        let mut only_in_self = Vec::new();
+        for diff in &self.diffs {
+            let candidates = unmatched.get_mut(diff.path());
+            match candidates.and_then(|c| c.iter().position(|d| *d == diff).map(|i| (c, i))) {
+                Some((candidates, index)) => {
+                    candidates.swap_remove(index);
+                }
+                None => only_in_self.push(diff.clone()),
+            }
+        }
+
+        let mut only_in_other: Vec<DiffType> = unmatched.into_values().flatten().cloned().collect();
+        only_in_self.sort_by(|a, b| a.path().cmp(b.path()));
+        only_in_other.sort_by(|a, b| a.path().cmp(b.path()));
+
+        ReportComparison { only_in_self, only_in_other }
+    }
+
+    /// Algorithm the hashes of the report were made with; `None` if it has none
+    pub fn hash_algorithm(&self) -> Option<HashAlgorithm> {
+        self.diffs.iter().filter_map(DiffType::hash).find(|hash| !hash.is_empty()).map(digest::algorithm_of)
+    }
+
+    /// Sort entries, and the members of changed archives, by path in `order`
+    ///
+    /// `make_reproducible` sorts byte-wise; sorting afterwards keeps the report
+    /// reproducible, as every `PathOrder` is total.
+    pub fn sort(&mut self, order: PathOrder) {
+        self.diffs.sort_by(|a, b| order.compare(a.path(), b.path()));
+        for archive in &mut self.archives {
+            archive.members.sort_by(|a, b| order.compare(a.path(), b.path()));
+        }
+        self.archives.sort_by(|a, b| order.compare(&a.path, &b.path));
+    }
+
+    /// Sort entries by path and normalize path separators to `/`
+    ///
+    /// After this the serialized report only depends on the compared trees, not on the
+    /// machine, the run or scan order, so the run IDs are dropped.
+    pub fn make_reproducible(&mut self) {
+        self.run_id = None;
+        for diff in &mut self.diffs {
+            diff.normalize_path();
+        }
+        self.diffs.sort_by(|a, b| a.path().cmp(b.path()));
+        for archive in &mut self.archives {
+            archive.path = normalize_separators(&archive.path);
+            for member in &mut archive.members {
+                member.normalize_path();
+            }
+        }
+        self.archives.sort_by(|a, b| a.path.cmp(&b.path));
+        self.source_files = std::mem::take(&mut self.source_files).into_iter()
+            .map(|(path, mut info)| {
+                info.relative_path = normalize_separators(&path);
+                (info.relative_path.clone(), info)
+            })
+            .collect();
+        if let Some(manifest) = &mut self.target_manifest {
+            manifest.make_reproducible();
+            manifest.run_id = None;
+        }
+    }
+
+    /// Save the report as JSON
+    #[cfg(feature = "serde")]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self).context("Failed to serialize diff report")?;
+        json.push('\n');
+        staging::write_atomic(path, json.as_bytes()).with_context(|| format!("Failed to write diff report: {}", path.display()))
+    }
+
+    /// Load a report saved with `save`
+    #[cfg(feature = "serde")]
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read diff report: {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse diff report: {}", path.display()))
+    }
+}
+
+/// A file that only moved: `to` has the same content as `from` had in the source tree
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileRename {
+    pub from: PathBuf,
+    pub to: FileInfo,
 }
 
 /// Structure to hold file differences
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FileDiff {
     pub relative_path: PathBuf,
     pub hash: String, // hash of target file
     pub original_hash: String, // hash of source file
     pub changes: Vec<DiffChange>, // changes to apply
+    /// Permissions of the target file, see `FileInfo::mode`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub mode: Option<FileMode>,
+    /// Tags of the target file, see `FileInfo::tags`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub tags: Vec<String>,
+    /// Custom metadata of the target file, see `FileInfo::extra`
+    #[cfg(feature = "serde")]
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Extra::is_empty"))]
+    pub extra: Extra,
 }
 
 /// Structure to represent a single change in a file
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DiffChange {
     pub tag: DiffChangeTag,
     pub content: String,
@@ -64,7 +448,8 @@ pub struct DiffChange {
 }
 
 /// Tags to represent different types of changes
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DiffChangeTag {
     Equal,
     Delete,
@@ -72,23 +457,330 @@ pub enum DiffChangeTag {
     Replace,
 }
 
+/// Hash function for file contents
+///
+/// SHA-256 hashes are written bare, as they always were; the others carry the name of
+/// the algorithm in a prefix (`blake3:...`, `xxh3:base64:...`), so a manifest says how
+/// its hashes were made. XXH3 is not cryptographic: it spots changes quickly, but does
+/// not protect against files crafted to collide.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+    /// 64-bit XXH3
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 3] = [HashAlgorithm::Sha256, HashAlgorithm::Blake3, HashAlgorithm::Xxh3];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    /// Length of a digest in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+            HashAlgorithm::Xxh3 => 8,
+        }
+    }
+
+    pub fn hasher(&self) -> ContentHasher {
+        match self {
+            HashAlgorithm::Sha256 => ContentHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => ContentHasher::Blake3(Box::default()),
+            HashAlgorithm::Xxh3 => ContentHasher::Xxh3(Xxh3::new()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        HashAlgorithm::ALL.into_iter().find(|algorithm| algorithm.name() == name.trim()).ok_or_else(|| {
+            let names: Vec<&str> = HashAlgorithm::ALL.iter().map(|algorithm| algorithm.name()).collect();
+            anyhow!("Unknown hash algorithm: {} (expected one of {})", name, names.join(", "))
+        })
+    }
+}
+
+/// Streaming hasher for one of the `HashAlgorithm`s; data is fed in with `Write`
+pub enum ContentHasher {
+    Sha256(Sha256),
+    Blake3(Box<Blake3>),
+    Xxh3(Xxh3),
+}
+
+impl ContentHasher {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            ContentHasher::Sha256(_) => HashAlgorithm::Sha256,
+            ContentHasher::Blake3(_) => HashAlgorithm::Blake3,
+            ContentHasher::Xxh3(_) => HashAlgorithm::Xxh3,
+        }
+    }
+
+    /// Raw digest of everything written (XXH3 in its canonical big-endian form)
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            ContentHasher::Blake3(hasher) => hasher.finalize().to_vec(),
+            ContentHasher::Xxh3(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.write(buf),
+            ContentHasher::Blake3(hasher) => hasher.write(buf),
+            ContentHasher::Xxh3(hasher) => hasher.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Calculate SHA256 hash of a file with buffered reading
 pub fn calculate_file_hash(path: &Path) -> Result<String> {
+    calculate_file_hash_as(path, DigestEncoding::Hex)
+}
+
+/// Calculate SHA256 hash of a file, written in `encoding`
+pub fn calculate_file_hash_as(path: &Path, encoding: DigestEncoding) -> Result<String> {
+    Ok(hash_file(path, HashAlgorithm::Sha256, encoding)?.0)
+}
+
+/// Hash a file with the algorithm and encoding of `reference`, to compare against it
+pub fn calculate_file_hash_like(path: &Path, reference: &str) -> Result<String> {
+    Ok(hash_file(path, digest::algorithm_of(reference), digest::encoding_of(reference).unwrap_or_default())?.0)
+}
+
+/// Hash a file by `algorithm`, written in `encoding`, and classify it as text or binary
+/// on the way
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm, encoding: DigestEncoding) -> Result<(String, ContentKind)> {
     let file = fs::File::open(path)
         .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
-    
+    let (hash, _, content) = hash_stream(file, algorithm, encoding).with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
+    Ok((hash, content))
+}
+
+/// Hash everything `reader` yields, the same way manifest entries are hashed
+///
+/// Reads in 64KB blocks, so memory use does not depend on the length of the stream
+/// (stdin, network streams, archive entries, ...).
+pub fn hash_reader(reader: impl Read) -> Result<String> {
+    hash_reader_as(reader, HashAlgorithm::Sha256, DigestEncoding::Hex)
+}
+
+/// `hash_reader` by `algorithm`, with the hash written in `encoding`
+pub fn hash_reader_as(reader: impl Read, algorithm: HashAlgorithm, encoding: DigestEncoding) -> Result<String> {
+    Ok(hash_stream(reader, algorithm, encoding)?.0)
+}
+
+/// `hash_reader` with the algorithm and encoding of `reference`, to compare against it
+pub fn hash_reader_like(reader: impl Read, reference: &str) -> Result<String> {
+    let encoding = digest::encoding_of(reference).unwrap_or_default();
+    Ok(hash_stream(reader, digest::algorithm_of(reference), encoding)?.0)
+}
+
+/// Hash, length and text/binary classification of everything `reader` yields
+fn hash_stream(reader: impl Read, algorithm: HashAlgorithm, encoding: DigestEncoding) -> Result<(String, u64, ContentKind)> {
     // Use a buffered reader for better I/O performance
-    let mut reader = BufReader::with_capacity(65536, file); // 64KB buffer
-    
-    let mut hasher = Sha256::new();
-    std::io::copy(&mut reader, &mut hasher)
-        .with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
-    
-    let hash = hasher.finalize();
-    Ok(format!("{:x}", hash))
+    let mut reader = BufReader::with_capacity(65536, Sniffer::new(reader)); // 64KB buffer
+
+    let mut hasher = algorithm.hasher();
+    let size = std::io::copy(&mut reader, &mut hasher).context("Failed to read stream for hashing")?;
+
+    Ok((encoding.encode_as(algorithm, &hasher.finalize()), size, reader.get_ref().kind()))
+}
+
+/// Compare two files byte by byte, stopping at the first difference
+pub fn files_equal(a: &Path, b: &Path) -> Result<bool> {
+    let open = |path: &Path| {
+        fs::File::open(path).with_context(|| format!("Failed to open file for comparison: {}", path.display()))
+    };
+    let (file_a, file_b) = (open(a)?, open(b)?);
+    if file_a.metadata()?.len() != file_b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut reader_a = BufReader::with_capacity(65536, file_a);
+    let mut reader_b = BufReader::with_capacity(65536, file_b);
+    let mut buf_a = vec![0u8; 65536];
+    let mut buf_b = vec![0u8; 65536];
+    loop {
+        let read = reader_a.read(&mut buf_a)
+            .with_context(|| format!("Failed to read file for comparison: {}", a.display()))?;
+        if read == 0 {
+            return Ok(true);
+        }
+        reader_b.read_exact(&mut buf_b[..read])
+            .with_context(|| format!("Failed to read file for comparison: {}", b.display()))?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Result of comparing two single files with `compare_files`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileComparison {
+    /// Hash, size and permissions of each file; `relative_path` is the path as given
+    pub a: FileInfo,
+    pub b: FileInfo,
+    /// Whether the contents are equal
+    pub identical: bool,
+    /// Byte ranges of `b` that differ from `a`, ascending and not overlapping, when asked
+    /// for with `DiffOptions::changed_ranges`; bytes past the end of the shorter file form
+    /// the last range
+    pub changed_ranges: Option<Vec<Range<u64>>>,
+}
+
+/// Compare two files the way `compare_directories` compares a file present in both trees
+///
+/// Both files are hashed with the algorithm and encoding of `options`, symbolic links are
+/// compared by their target unless `DiffOptions::follow_symlinks` is set, and files with
+/// equal hashes are also compared byte by byte unless `DiffOptions::verify_unchanged` is off.
+pub fn compare_files(a: &Path, b: &Path, options: &DiffOptions) -> Result<FileComparison> {
+    let info = |path: &Path| -> Result<FileInfo> {
+        let metadata = if options.follow_symlinks { fs::metadata(path) } else { fs::symlink_metadata(path) }
+            .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+        if metadata.is_symlink() {
+            let target = fs::read_link(path).with_context(|| format!("Failed to read symbolic link: {}", path.display()))?;
+            return Ok(FileInfo::symlink(path.to_path_buf(), target, options.hash_algorithm, options.digest_encoding));
+        }
+        if !metadata.is_file() {
+            return Err(anyhow!("Not a file: {}", path.display()));
+        }
+        let (hash, content) = options.hash_file(path)?;
+        Ok(FileInfo::new(path.to_path_buf(), hash, metadata.len()).with_mode(&metadata).with_content(content))
+    };
+    let (info_a, info_b) = (info(a)?, info(b)?);
+    let links = info_a.is_symlink() || info_b.is_symlink();
+    let mut identical = info_a.hash == info_b.hash;
+    if identical && !links && options.verify_unchanged != UnchangedVerification::Off {
+        identical = files_equal(a, b)?;
+    }
+    let changed_ranges = match options.changed_ranges && !links {
+        true if identical => Some(Vec::new()),
+        true => Some(changed_byte_ranges(a, b)?),
+        false => None,
+    };
+    Ok(FileComparison { a: info_a, b: info_b, identical, changed_ranges })
+}
+
+/// Byte ranges in which two files differ, see `FileComparison::changed_ranges`
+fn changed_byte_ranges(a: &Path, b: &Path) -> Result<Vec<Range<u64>>> {
+    let open = |path: &Path| {
+        fs::File::open(path).with_context(|| format!("Failed to open file for comparison: {}", path.display()))
+    };
+    let (file_a, file_b) = (open(a)?, open(b)?);
+    let (len_a, len_b) = (file_a.metadata()?.len(), file_b.metadata()?.len());
+    let common = len_a.min(len_b);
+
+    let mut reader_a = BufReader::with_capacity(65536, file_a);
+    let mut reader_b = BufReader::with_capacity(65536, file_b);
+    let mut buf_a = vec![0u8; 65536];
+    let mut buf_b = vec![0u8; 65536];
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    let mut offset = 0u64;
+    while offset < common {
+        let chunk = (common - offset).min(buf_a.len() as u64) as usize;
+        reader_a.read_exact(&mut buf_a[..chunk])
+            .with_context(|| format!("Failed to read file for comparison: {}", a.display()))?;
+        reader_b.read_exact(&mut buf_b[..chunk])
+            .with_context(|| format!("Failed to read file for comparison: {}", b.display()))?;
+        for (index, (byte_a, byte_b)) in buf_a[..chunk].iter().zip(&buf_b[..chunk]).enumerate() {
+            if byte_a == byte_b {
+                continue;
+            }
+            let position = offset + index as u64;
+            match ranges.last_mut() {
+                Some(last) if last.end == position => last.end += 1,
+                _ => ranges.push(position..position + 1),
+            }
+        }
+        offset += chunk as u64;
+    }
+    if len_a != len_b {
+        match ranges.last_mut() {
+            Some(last) if last.end == common => last.end = len_a.max(len_b),
+            _ => ranges.push(common..len_a.max(len_b)),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Pick up to `count` random elements of `items` (partial Fisher-Yates shuffle)
+fn random_sample<T>(mut items: Vec<T>, count: usize) -> Vec<T> {
+    // A randomly keyed std hasher is enough of a seed, this is not cryptographic
+    let mut state = RandomState::new().build_hasher().finish() | 1;
+    let count = count.min(items.len());
+    for i in 0..count {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let j = i + (state % (items.len() - i) as u64) as usize;
+        items.swap(i, j);
+    }
+    items.truncate(count);
+    items
+}
+
+/// Byte-compare files whose hashes match in both trees, returning the ones that differ
+fn find_hash_collisions(
+    source_dir: &Path,
+    target_dir: &Path,
+    unchanged: Vec<&PathBuf>,
+    options: &DiffOptions,
+) -> Result<Vec<PathBuf>> {
+    let to_check = match options.verify_unchanged {
+        UnchangedVerification::Off => return Ok(Vec::new()),
+        UnchangedVerification::Sample(count) => random_sample(unchanged, count),
+        UnchangedVerification::All => unchanged,
+    };
+    options.status(format_args!("{}", tr!("scan.verifying_unchanged", count = to_check.len())));
+
+    let results: Vec<Result<Option<PathBuf>>> = utils::io_thread_pool(options.thread_count()).install(|| {
+        to_check.par_iter().map(|path| {
+            let equal = files_equal(&source_dir.join(path), &target_dir.join(path))?;
+            Ok((!equal).then(|| (*path).clone()))
+        }).collect()
+    });
+    options.check_stop()?;
+
+    let mut collisions = Vec::new();
+    for result in results {
+        if let Some(path) = result? {
+            collisions.push(path);
+        }
+    }
+    collisions.sort();
+    Ok(collisions)
 }
 
 /// Check if a file should be excluded based on exclude patterns
+#[allow(clippy::collapsible_if)]
 fn should_exclude(
     path: &Path, 
     exclude_extensions: Option<&[String]>, 
@@ -104,110 +796,1209 @@ fn should_exclude(
         }
     }
     
-    // Check if path is in an excluded directory
+    // Check if path is in an excluded directory, comparing whole components
     if let Some(dirs) = exclude_dirs {
-        let path_str = path.display().to_string();
         for dir in dirs {
-            // Convert dir string into platform-specific path format
-            let platform_dir = if cfg!(windows) {
-                dir.replace('/', "\\")
+            let names: Vec<&str> = paths::split_components(dir).collect();
+            if paths::contains_components(path, &names) {
+                return true;
+            }
+        }
+    }
+    
+    false
+}
+
+
+/// How files present on both sides are found to be changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompareStrategy {
+    /// Hash every file on both sides and compare the hashes
+    #[default]
+    Hash,
+    /// Compare files of equal size byte by byte and hash only the files that end up in
+    /// the patch; needs a source directory and embeds no target manifest
+    ByteCompare,
+    /// Treat files with equal size and modification time as unchanged without reading
+    /// them, and hash only the others; needs a source directory and embeds no target
+    /// manifest
+    SizeAndMtime,
+}
+
+/// Which files with equal hashes on both sides are also compared byte by byte
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnchangedVerification {
+    #[default]
+    Off,
+    /// A random sample of this many files
+    Sample(usize),
+    /// Every unchanged file
+    All,
+}
+
+/// What to do when files with equal hashes turn out to differ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Report the files and fail the comparison
+    #[default]
+    Abort,
+    /// Report the files and include them in the patch as modified
+    TreatAsModified,
+}
+
+/// What a scan does with a file or directory it cannot read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Leave it out with a warning and list it in `ScanReport::errors`
+    #[default]
+    SkipAndCollect,
+    /// Fail the scan
+    FailFast,
+}
+
+/// A file or directory a scan could not read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanError {
+    /// Path relative to the scanned directory
+    pub path: PathBuf,
+    pub message: String,
+    /// Phase and operating system error, see `Warning::error`
+    pub error: Option<FileError>,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+/// Files found by a scan, with the files and directories it left out because they
+/// could not be read (see `ErrorPolicy::SkipAndCollect`)
+#[derive(Debug, Clone, Default)]
+pub struct ScanReport {
+    pub files: HashMap<PathBuf, FileInfo>,
+    /// Sorted by path
+    pub errors: Vec<ScanError>,
+}
+
+/// Read errors of one scan, handled as `DiffOptions::error_policy` says
+struct ScanErrors<'a> {
+    options: &'a DiffOptions,
+    policy: ErrorPolicy,
+    errors: Mutex<Vec<ScanError>>,
+}
+
+impl<'a> ScanErrors<'a> {
+    fn new(options: &'a DiffOptions) -> Self {
+        ScanErrors { options, policy: options.error_policy, errors: Mutex::new(Vec::new()) }
+    }
+
+    /// Errors of a walk that only estimates a scan, which never fails
+    fn lenient(options: &'a DiffOptions) -> Self {
+        ScanErrors { policy: ErrorPolicy::SkipAndCollect, ..ScanErrors::new(options) }
+    }
+
+    /// Record the warning for an unreadable entry, or fail with it under `ErrorPolicy::FailFast`
+    fn record(&self, warning: Warning) -> Result<()> {
+        let error = ScanError { path: warning.path.clone().unwrap_or_default(), message: warning.message.clone(), error: warning.error.clone() };
+        if self.policy == ErrorPolicy::FailFast {
+            return Err(anyhow!("Failed to scan {}", error));
+        }
+        self.errors.lock().unwrap().push(error);
+        self.options.warnings.push(warning);
+        Ok(())
+    }
+
+    fn into_errors(self) -> Vec<ScanError> {
+        let mut errors = self.errors.into_inner().unwrap();
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        errors
+    }
+}
+
+/// Why a scan keeps or leaves out a file, see `DiffOptions::explain`
+#[derive(Debug, Clone)]
+pub enum ScanDecision {
+    /// Kept; the filter rule that included it, if any
+    Included(FilterDecision),
+    /// A path component starts with `.` (the component)
+    Hidden(String),
+    /// A temporary file or directory of a diffpatch run (the component), left out even
+    /// with `include_hidden`
+    Temporary(String),
+    /// Matched `exclude_extensions` (the extension)
+    ExcludedExtension(String),
+    /// Inside one of `exclude_dirs` (the directory)
+    ExcludedDir(String),
+    /// Matches none of `include_patterns`
+    NotIncluded,
+    /// Left out by a filter rule
+    Filtered(FilterDecision),
+    /// Larger than `max_file_size`
+    TooLarge { size: u64, max: u64 },
+}
+
+impl ScanDecision {
+    pub fn is_included(&self) -> bool {
+        matches!(self, ScanDecision::Included(_))
+    }
+}
+
+impl std::fmt::Display for ScanDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanDecision::Included(decision) | ScanDecision::Filtered(decision) => write!(f, "{}", decision),
+            ScanDecision::Hidden(name) => write!(f, "{}", tr!("explain.hidden", name = name)),
+            ScanDecision::Temporary(name) => write!(f, "{}", tr!("explain.temporary", name = name)),
+            ScanDecision::ExcludedExtension(ext) => write!(f, "{}", tr!("explain.extension", extension = ext)),
+            ScanDecision::ExcludedDir(dir) => write!(f, "{}", tr!("explain.dir", dir = dir)),
+            ScanDecision::NotIncluded => write!(f, "{}", tr!("explain.not_included")),
+            ScanDecision::TooLarge { size, max } => write!(
+                f,
+                "{}",
+                tr!("explain.too_large", max = utils::format_size(*max), size = utils::format_size(*size))
+            ),
+        }
+    }
+}
+
+/// Options controlling how directories are scanned and compared
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    exclude_extensions: Option<Vec<String>>,
+    exclude_dirs: Option<Vec<String>>,
+    use_diff_patches: bool,
+    check_case_conflicts: bool,
+    match_extension_renames: bool,
+    detect_renames: bool,
+    rename_min_size: u64,
+    ignore_build_stamps: bool,
+    ignore_permissions: bool,
+    inspect_archives: bool,
+    lazy_hashing: bool,
+    detect_clones: bool,
+    paranoid: bool,
+    include_patterns: Vec<FilterRule>,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    tag_rules: Option<TagRules>,
+    filters: FilterList,
+    compare_strategy: CompareStrategy,
+    verify_unchanged: UnchangedVerification,
+    collision_policy: CollisionPolicy,
+    error_policy: ErrorPolicy,
+    threads: Option<usize>,
+    throttle: Option<Throttle>,
+    max_file_size: Option<u64>,
+    digest_encoding: DigestEncoding,
+    hash_algorithm: HashAlgorithm,
+    #[cfg(feature = "serde")]
+    snapshot_dir: Option<PathBuf>,
+    #[cfg(feature = "serde")]
+    diff_cache: Option<PathBuf>,
+    #[cfg(feature = "serde")]
+    hash_cache: Option<PathBuf>,
+    #[cfg(feature = "serde")]
+    annotator: Option<Annotator>,
+    progress: Option<ProgressHook>,
+    cancel: Option<CancelToken>,
+    status_to_stderr: bool,
+    changed_ranges: bool,
+    warnings: WarningLog,
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skip files with these extensions (e.g. `.tmp`)
+    pub fn exclude_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.exclude_extensions = Some(extensions);
+        self
+    }
+
+    /// Skip these directories (relative paths)
+    pub fn exclude_dirs(mut self, dirs: Vec<String>) -> Self {
+        self.exclude_dirs = Some(dirs);
+        self
+    }
+
+    /// Only consider files matching at least one of these patterns, e.g. `assets/**/*.pak`
+    ///
+    /// The patterns use `.gitignore` syntax (see `FilterRule::gitignore`): a pattern with a
+    /// `/` is matched from the root, one without matches a name at any depth, and a
+    /// matching directory takes in everything below it. A pattern starting with `!`
+    /// removes files again; the last matching pattern decides. The exclude lists and
+    /// filters still apply to the files kept. No patterns (the default) keeps every file.
+    pub fn include_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.include_patterns = patterns.iter().filter_map(|pattern| FilterRule::gitignore(pattern)).collect();
+        self
+    }
+
+    /// Scan files and directories whose names start with a dot, such as `.config`
+    ///
+    /// They are left out by default. Temporary files of diffpatch itself
+    /// (`staging::STAGING_PREFIX`) are always left out.
+    pub fn include_hidden(mut self, enabled: bool) -> Self {
+        self.include_hidden = enabled;
+        self
+    }
+
+    /// Hash the files symbolic links point to, as if they were regular files
+    ///
+    /// By default a link is recorded as a link (`FileInfo::link_target`) without reading
+    /// what it points to, and links to directories are not descended into.
+    pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+        self.follow_symlinks = enabled;
+        self
+    }
+
+    /// The component that makes a relative path hidden or temporary, if any
+    fn hidden_component(&self, relative_path: &Path) -> Option<ScanDecision> {
+        relative_path.components().find_map(|c| {
+            let name = c.as_os_str().to_str()?;
+            if name.starts_with(crate::staging::STAGING_PREFIX) {
+                Some(ScanDecision::Temporary(name.to_string()))
+            } else if !self.include_hidden && name.starts_with('.') {
+                Some(ScanDecision::Hidden(name.to_string()))
             } else {
-                dir.replace('\\', "/")
-            };
-            
-            // Check if path contains the excluded directory
-            if path_str.contains(&format!("{}{}", platform_dir, std::path::MAIN_SEPARATOR)) ||
-               path_str.ends_with(&platform_dir) {
+                None
+            }
+        })
+    }
+
+    /// Whether a file (relative path) passes `include_patterns`
+    fn is_included(&self, relative_path: &Path) -> bool {
+        if self.include_patterns.is_empty() {
+            return true;
+        }
+        // A plain pattern parses as an exclude rule and a `!` pattern as an include rule
+        self.include_patterns
+            .iter()
+            .rev()
+            .find(|rule| rule.matches_path(relative_path))
+            .is_some_and(|rule| rule.kind == RuleKind::Exclude)
+    }
+
+    /// Tag scanned files with these rules (see `FileInfo::tags`)
+    ///
+    /// Tags do not change what is compared; they group the differences, e.g. with
+    /// `TagRules::summarize`.
+    pub fn tag_rules(mut self, rules: TagRules) -> Self {
+        self.tag_rules = Some(rules);
+        self
+    }
+
+    /// Include/exclude patterns (e.g. from `--exclude-from`), on top of the exclude lists
+    pub fn filters(mut self, filters: FilterList) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Store text changes as diffs instead of full files
+    pub fn use_diff_patches(mut self, enabled: bool) -> Self {
+        self.use_diff_patches = enabled;
+        self
+    }
+
+    /// Warn about target paths that differ only by case
+    pub fn check_case_conflicts(mut self, enabled: bool) -> Self {
+        self.check_case_conflicts = enabled;
+        self
+    }
+
+    /// Treat a removed and an added file with the same content and the same path apart
+    /// from the extension (e.g. `data/a.dat` -> `data/a.bin`) as a rename
+    pub fn match_extension_renames(mut self, enabled: bool) -> Self {
+        self.match_extension_renames = enabled;
+        self
+    }
+
+    /// Treat a removed and an added file with the same content and size as a move,
+    /// wherever the two paths are; a removed file with the same name is preferred
+    ///
+    /// Files smaller than `rename_min_size` are left as they are.
+    pub fn detect_renames(mut self, enabled: bool) -> Self {
+        self.detect_renames = enabled;
+        self
+    }
+
+    /// Smallest file `detect_renames` pairs up (default: any); empty files, which all
+    /// share one hash, never count as moved
+    pub fn rename_min_size(mut self, bytes: u64) -> Self {
+        self.rename_min_size = bytes;
+        self
+    }
+
+    /// Whether one-sided files are matched as renames, and so need hashes
+    fn matches_renames(&self) -> bool {
+        self.match_extension_renames || self.detect_renames
+    }
+
+    /// Treat PE/ELF executables that differ only in build stamps (timestamps, build IDs,
+    /// signatures) as unchanged, see `binary::stamp_free_digest`
+    ///
+    /// The patch then keeps the installed file, so the target manifest records the
+    /// source version of such files.
+    pub fn ignore_build_stamps(mut self, enabled: bool) -> Self {
+        self.ignore_build_stamps = enabled;
+        self
+    }
+
+    /// Do not report files whose permissions changed while their content did not
+    ///
+    /// By default such files are reported as `DiffType::MetadataChanged`, and a patch
+    /// gives them (and the files it writes) the permissions of the target. Either way
+    /// the modes are recorded in `FileInfo::mode`.
+    pub fn ignore_permissions(mut self, enabled: bool) -> Self {
+        self.ignore_permissions = enabled;
+        self
+    }
+
+    /// Compare the members of changed ZIP archives (`ARCHIVE_EXTENSIONS`) and list them
+    /// in `DiffReport::archives`
+    ///
+    /// The archives are still patched whole; this shows what changed inside them.
+    pub fn inspect_archives(mut self, enabled: bool) -> Self {
+        self.inspect_archives = enabled;
+        self
+    }
+
+    /// Hash only the files present on both sides when comparing two directories
+    ///
+    /// Added and removed files are told apart by path, so trees with many one-sided files
+    /// scan much faster. Added files are left without a hash until `hash_added_files`,
+    /// snapshots are not used and no target manifest is embedded.
+    pub fn lazy_hashing(mut self, enabled: bool) -> Self {
+        self.lazy_hashing = enabled;
+        self
+    }
+
+    /// Skip hashing target files that are hard links or reflinked copies of the source
+    /// file at the same path, when comparing two directories
+    ///
+    /// Such files are provably identical, so the target takes the source's entry
+    /// instead of being read, a big saving for snapshot-based trees on btrfs or XFS (see
+    /// `fsops::CloneDetector`). Files the check cannot vouch for are hashed as usual. Not
+    /// used with a snapshot directory or hash cache, which skip unchanged files already.
+    pub fn detect_clones(mut self, enabled: bool) -> Self {
+        self.detect_clones = enabled;
+        self
+    }
+
+    /// How to decide whether a file present on both sides changed
+    pub fn compare_strategy(mut self, strategy: CompareStrategy) -> Self {
+        self.compare_strategy = strategy;
+        self
+    }
+
+    /// Hash every file on both sides, trusting no shortcut based on file metadata
+    ///
+    /// Overrides `compare_strategy`, `lazy_hashing` and the reuse of hashes from
+    /// `snapshot_dir`, whatever they are set to.
+    pub fn paranoid(mut self, enabled: bool) -> Self {
+        self.paranoid = enabled;
+        self
+    }
+
+    /// Whether a comparison takes the entries of cloned target files from the source
+    fn reuses_clones(&self) -> bool {
+        #[cfg(feature = "serde")]
+        if self.snapshot_dir.is_some() || self.hash_cache.is_some() {
+            return false;
+        }
+        self.detect_clones && !self.paranoid
+    }
+
+    fn strategy(&self) -> CompareStrategy {
+        if self.paranoid { CompareStrategy::Hash } else { self.compare_strategy }
+    }
+
+    /// Byte-compare files whose hashes match on both sides, to catch hash collisions
+    ///
+    /// Only applies when the source is a directory.
+    pub fn verify_unchanged(mut self, verification: UnchangedVerification) -> Self {
+        self.verify_unchanged = verification;
+        self
+    }
+
+    /// How to handle files that `verify_unchanged` finds to differ
+    pub fn collision_policy(mut self, policy: CollisionPolicy) -> Self {
+        self.collision_policy = policy;
+        self
+    }
+
+    /// What scans do with files and directories they cannot read
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.error_policy = policy;
+        self
+    }
+
+    /// Read files no faster than `bytes_per_second` while hashing them, counted over all
+    /// threads together
+    pub fn throttle(mut self, bytes_per_second: u64) -> Self {
+        self.throttle = Some(Throttle::new(bytes_per_second));
+        self
+    }
+
+    /// Hash a file by the algorithm and encoding of these options, within the throttle
+    pub fn hash_file(&self, path: &Path) -> Result<(String, ContentKind)> {
+        let Some(throttle) = &self.throttle else {
+            return hash_file(path, self.hash_algorithm, self.digest_encoding);
+        };
+        let file = fs::File::open(path)
+            .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
+        let (hash, _, content) = hash_stream(throttle.reader(file), self.hash_algorithm, self.digest_encoding)
+            .with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
+        Ok((hash, content))
+    }
+
+    /// Skip files larger than this many bytes, on both sides
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// How hashes are written in the report, manifests and patch (default: hex)
+    pub fn digest_encoding(mut self, encoding: DigestEncoding) -> Self {
+        self.digest_encoding = encoding;
+        self
+    }
+
+    /// Hash function for file contents (default: SHA-256)
+    ///
+    /// Manifests and patches used as the source must have been made with the same one.
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Keep a snapshot of every scanned directory in `dir`, to rescan only changed files
+    ///
+    /// Unchanged files are only known on volumes with a change journal (NTFS/ReFS);
+    /// elsewhere every file is hashed and the snapshot merely refreshed, unless a
+    /// `diffpatch watch` process keeps the snapshot current.
+    #[cfg(feature = "serde")]
+    pub fn snapshot_dir(mut self, dir: PathBuf) -> Self {
+        self.snapshot_dir = Some(dir);
+        self
+    }
+
+    /// Remember the hash of every scanned file in `dir`, to hash only new and touched
+    /// files when the same directory is scanned again
+    ///
+    /// A file keeps its remembered hash while its size, modification time and inode
+    /// (on Unix) stay the same, see `hashcache::scan_with_cache`. A `snapshot_dir`
+    /// takes precedence.
+    #[cfg(feature = "serde")]
+    pub fn hash_cache(mut self, dir: PathBuf) -> Self {
+        self.hash_cache = Some(dir);
+        self
+    }
+
+    /// Keep the result of every comparison in `dir`, to return it at once when the same
+    /// two trees are compared again with the same options (e.g. a retried CI job)
+    ///
+    /// Entries are keyed by the manifests of both trees, so both are still scanned and
+    /// hashed; only the comparison itself is skipped. Lazy hashing and paranoid
+    /// comparisons are never cached.
+    #[cfg(feature = "serde")]
+    pub fn diff_cache(mut self, dir: PathBuf) -> Self {
+        self.diff_cache = Some(dir);
+        self
+    }
+
+    /// The options that change the result of comparing two scanned trees, for the key of
+    /// a cached comparison (see `diff_cache`)
+    #[cfg(feature = "serde")]
+    fn comparison_fingerprint(&self, source_is_dir: bool) -> String {
+        format!(
+            "{:?}",
+            (
+                source_is_dir,
+                self.use_diff_patches,
+                self.check_case_conflicts,
+                self.detect_renames,
+                self.match_extension_renames,
+                self.rename_min_size,
+                self.ignore_build_stamps,
+                self.ignore_permissions,
+                self.inspect_archives,
+                (self.verify_unchanged, self.collision_policy),
+                (self.hash_algorithm, self.digest_encoding),
+            )
+        )
+    }
+
+    /// Call `annotator` for every scanned file, after it was hashed
+    ///
+    /// Entries taken from a patch manifest keep the metadata stored there, and with byte
+    /// comparison files that did not need hashing have an empty hash. Files of a `Vfs`
+    /// are passed as the name of the tree joined with their path, which is only a path on
+    /// disk for a `LocalFs`.
+    #[cfg(feature = "serde")]
+    pub fn annotator(mut self, annotator: Annotator) -> Self {
+        self.annotator = Some(annotator);
+        self
+    }
+
+    /// Report how many files scans discovered and hashed to `hook`, e.g. for a progress bar
+    ///
+    /// Byte comparison and archive inspection read files without reporting progress.
+    pub fn progress(mut self, hook: ProgressHook) -> Self {
+        self.progress = Some(hook);
+        self
+    }
+
+    /// Stop scans and comparisons once `token` is cancelled, failing with
+    /// `interrupt::Cancelled` (see `CancelToken`)
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Token set with `cancel_token`, if any
+    pub fn cancel(&self) -> Option<&CancelToken> {
+        self.cancel.as_ref()
+    }
+
+    /// Whether a scan or comparison should stop: Ctrl-C was pressed or the cancel token
+    /// was cancelled
+    pub fn should_stop(&self) -> bool {
+        interrupt::should_stop(self.cancel.as_ref())
+    }
+
+    /// Fail with `interrupt::Interrupted` or `interrupt::Cancelled` if the scan or
+    /// comparison should stop
+    pub fn check_stop(&self) -> Result<()> {
+        interrupt::check_stop(self.cancel.as_ref())
+    }
+
+    /// Print status messages ("Scanning source directory: ...") to standard error instead
+    /// of standard output, e.g. when standard output carries JSON or CSV
+    pub fn status_to_stderr(mut self, enabled: bool) -> Self {
+        self.status_to_stderr = enabled;
+        self
+    }
+
+    /// Have `compare_files` list the byte ranges in which two different files differ;
+    /// this reads both files once more
+    pub fn changed_ranges(mut self, enabled: bool) -> Self {
+        self.changed_ranges = enabled;
+        self
+    }
+
+    /// Print a status message of a scan or comparison, where `status_to_stderr` says
+    pub fn status(&self, message: std::fmt::Arguments) {
+        if self.status_to_stderr {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    /// Drop warnings of these categories instead of collecting them
+    pub fn suppress_warnings(mut self, kinds: HashSet<WarningKind>) -> Self {
+        self.warnings = WarningLog::new(kinds);
+        self
+    }
+
+    /// Warnings collected by scans with these options (and their clones) that were not
+    /// taken into a report yet
+    pub fn warnings(&self) -> &WarningLog {
+        &self.warnings
+    }
+
+    /// Number of threads for file I/O
+    ///
+    /// Defaults to `DIFFPATCH_IO_THREADS` if set, otherwise to the CPU count capped at 4.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Check the options for values that cannot work
+    pub fn validate(&self) -> Result<()> {
+        if let Some(threads) = self.threads {
+            utils::validate_thread_count(threads)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a relative path is left out of scans (hidden or excluded)
+    pub fn is_excluded(&self, relative_path: &Path) -> bool {
+        self.hidden_component(relative_path).is_some()
+            || should_exclude(relative_path, self.exclude_extensions.as_deref(), self.exclude_dirs.as_deref())
+            || !self.is_included(relative_path)
+            || self.filters.is_excluded(relative_path)
+    }
+
+    /// Whether a directory (relative path) holds nothing a scan keeps: it is hidden, or
+    /// excluded by `exclude_dirs` or a filter rule, so the walk need not enter it
+    fn is_dir_excluded(&self, relative_path: &Path) -> bool {
+        self.hidden_component(relative_path).is_some()
+            || should_exclude(relative_path, None, self.exclude_dirs.as_deref())
+            || self.filters.is_dir_excluded(relative_path)
+    }
+
+    /// Why a scan keeps or leaves out a file (relative path), checking the same things
+    /// in the same order as `is_excluded`; the size limit is only checked if `size` is given
+    pub fn explain(&self, relative_path: &Path, size: Option<u64>) -> ScanDecision {
+        if let Some(decision) = self.hidden_component(relative_path) {
+            return decision;
+        }
+        let exclude_extensions = self.exclude_extensions.as_deref().unwrap_or_default();
+        if let Some(ext) = exclude_extensions.iter().find(|ext| should_exclude(relative_path, Some(std::slice::from_ref(ext)), None)) {
+            return ScanDecision::ExcludedExtension(ext.clone());
+        }
+        let exclude_dirs = self.exclude_dirs.as_deref().unwrap_or_default();
+        if let Some(dir) = exclude_dirs.iter().find(|dir| should_exclude(relative_path, None, Some(std::slice::from_ref(dir)))) {
+            return ScanDecision::ExcludedDir(dir.clone());
+        }
+        if !self.is_included(relative_path) {
+            return ScanDecision::NotIncluded;
+        }
+        let decision = self.filters.explain(relative_path);
+        if decision.excluded {
+            return ScanDecision::Filtered(decision);
+        }
+        if let (Some(size), Some(max)) = (size, self.max_file_size)
+            && size > max
+        {
+            return ScanDecision::TooLarge { size, max };
+        }
+        ScanDecision::Included(decision)
+    }
+
+    /// Whether a file of `size` bytes is over the `max_file_size` limit
+    pub fn exceeds_max_file_size(&self, size: u64) -> bool {
+        self.max_file_size.is_some_and(|max| size > max)
+    }
+
+    /// Thread count actually used, after applying the fallbacks
+    pub fn thread_count(&self) -> usize {
+        utils::io_thread_count(self.threads)
+    }
+
+    /// Encoding hashes are written in, see `digest_encoding`
+    pub fn encoding(&self) -> DigestEncoding {
+        self.digest_encoding
+    }
+
+    /// Hash function for file contents, see `hash_algorithm`
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    /// Whether permission-only changes go unreported, see `ignore_permissions`
+    pub fn permissions_ignored(&self) -> bool {
+        self.ignore_permissions
+    }
+
+    /// Whether hidden files are scanned, see `include_hidden`
+    pub fn hidden_included(&self) -> bool {
+        self.include_hidden
+    }
+
+    /// Whether links are followed, see `follow_symlinks`
+    pub fn symlinks_followed(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// SHA-256 of the exclude lists, include patterns, filter rules and size limit, or
+    /// an empty string if none is set; scans with the same one leave out the same files
+    pub fn filter_fingerprint(&self) -> String {
+        if self.exclude_extensions.is_none()
+            && self.exclude_dirs.is_none()
+            && self.include_patterns.is_empty()
+            && self.filters.is_empty()
+            && self.max_file_size.is_none()
+        {
+            return String::new();
+        }
+        let filters = (&self.exclude_extensions, &self.exclude_dirs, &self.include_patterns, &self.filters, self.max_file_size);
+        format!("{:x}", Sha256::digest(format!("{:?}", filters)))
+    }
+}
+
+/// Files under `dir_path` that a scan processes, after the hidden, exclude and size filters
+fn collect_scan_entries(dir_path: &Path, options: &DiffOptions, errors: &ScanErrors) -> Result<Vec<walkdir::DirEntry>> {
+    let relative = |path: &Path| path.strip_prefix(dir_path).unwrap_or(path).to_path_buf();
+    let mut entries = Vec::new();
+    // Excluded directories are pruned before they are listed, so errors reading them
+    // are not reported either
+    let walk = WalkDir::new(dir_path).follow_links(options.follow_symlinks).into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_type().is_dir() || !options.is_dir_excluded(&relative(e.path())));
+    for entry in walk {
+        // Listing a large tree takes a while too
+        options.check_stop()?;
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                // Entries that fail before they can be pruned, e.g. files that cannot be
+                // stat'ed, are checked here instead
+                if e.depth() > 0 && let Some(path) = e.path() {
+                    let is_dir = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir());
+                    let path = relative(path);
+                    let excluded = if is_dir { options.is_dir_excluded(&path) } else { options.is_excluded(&path) };
+                    if excluded {
+                        continue;
+                    }
+                }
+                let warning = walk_error_warning(&e);
+                let warning = match e.path() {
+                    Some(path) => warning.with_path(&relative(path)),
+                    None => warning,
+                };
+                errors.record(warning)?;
+            }
+        }
+    }
+    Ok(entries.into_iter()
+        .filter(|e| e.file_type().is_file() || e.file_type().is_symlink())
+        .filter(|e| {
+            let relative_path = e.path().strip_prefix(dir_path).unwrap_or_else(|_| Path::new(""));
+            // Skip hidden files and files matching the exclude patterns
+            !options.is_excluded(relative_path)
+        })
+        .filter(|e| {
+            let Some(max) = options.max_file_size else {
                 return true;
+            };
+            match e.metadata() {
+                Ok(metadata) if metadata.len() > max => {
+                    let message = format!("larger than --max-file-size ({})", utils::format_size(metadata.len()));
+                    options.warnings.push(Warning::new(WarningKind::SkippedFile, Severity::Info, message).with_path(&relative(e.path())));
+                    false
+                }
+                Ok(_) => true,
+                Err(_) => false,
+            }
+        })
+        .collect())
+}
+
+/// How to get read access to a directory the scan was denied
+#[cfg(windows)]
+const ACCESS_HINT: &str = "run diffpatch as administrator or grant your account read access";
+#[cfg(not(windows))]
+const ACCESS_HINT: &str = "run diffpatch as a user who can read it (e.g. with sudo) or grant read access";
+
+/// Warning for an entry the directory walk could not read
+///
+/// An unreadable directory hides its whole subtree, whose files would then look added or
+/// removed, so it gets a category of its own.
+fn walk_error_warning(error: &walkdir::Error) -> Warning {
+    let is_dir = error.path().is_some_and(|path| fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()));
+    if !is_dir || error.loop_ancestor().is_some() {
+        let phase = if is_dir { ErrorPhase::List } else { ErrorPhase::Metadata };
+        let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, format!("cannot be read ({})", error));
+        return warning.with_error(walk_file_error(phase, error));
+    }
+    let denied = error.io_error().is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied);
+    let message = if denied {
+        format!("directory cannot be listed (permission denied), its files are left out of the scan; to include them, {}", ACCESS_HINT)
+    } else {
+        format!("directory cannot be listed ({}), its files are left out of the scan", error)
+    };
+    Warning::new(WarningKind::UnreadableDir, Severity::Warning, message).with_error(walk_file_error(ErrorPhase::List, error))
+}
+
+/// Error context of a failed directory walk step; loops have no I/O error behind them
+fn walk_file_error(phase: ErrorPhase, error: &walkdir::Error) -> FileError {
+    match error.io_error() {
+        Some(io_error) => FileError::from_io(phase, io_error),
+        None => FileError::new(phase, error.to_string()),
+    }
+}
+
+/// Warning for a file (relative path) that could not be hashed
+fn unreadable_file_warning(relative_path: &Path, error: &anyhow::Error) -> Warning {
+    let message = format!("cannot be read ({:#})", error);
+    let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, message).with_path(relative_path);
+    warning.with_error(FileError::from_error(ErrorPhase::Hash, error))
+}
+
+/// Hash a file, recording a warning if it cannot be read or changes while it is hashed
+fn hash_scanned_file(
+    full_path: &Path,
+    relative_path: &Path,
+    metadata: &fs::Metadata,
+    options: &DiffOptions,
+    errors: &ScanErrors,
+) -> Result<Option<(String, ContentKind)>> {
+    let hashed = match options.hash_file(full_path) {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            errors.record(unreadable_file_warning(relative_path, &e))?;
+            return Ok(None);
+        }
+    };
+    let unchanged = fs::metadata(full_path)
+        .is_ok_and(|after| after.len() == metadata.len() && after.modified().ok() == metadata.modified().ok());
+    if !unchanged {
+        let message = "changed while it was hashed; the recorded hash may not match its content";
+        let warning = Warning::new(WarningKind::UnstableFile, Severity::Warning, message).with_path(relative_path);
+        options.warnings.push(warning.with_error(unstable_error()));
+    }
+    Ok(Some(hashed))
+}
+
+/// Error context of a file whose size or modification time changed while it was hashed
+fn unstable_error() -> FileError {
+    FileError::new(ErrorPhase::Stability, "size or modification time changed while the file was read")
+}
+
+/// Scan directory and collect file information
+///
+/// With a snapshot directory set, hashes from the previous scan are reused where the
+/// file system's change journal allows (see `snapshot::scan_with_snapshot`); with a hash
+/// cache, those of files whose size, time and inode are unchanged.
+pub fn scan_directory(dir_path: &Path, options: &DiffOptions) -> Result<HashMap<PathBuf, FileInfo>> {
+    Ok(scan_directory_report(dir_path, options)?.files)
+}
+
+/// Scan a directory like `scan_directory`, also listing what could not be read
+///
+/// Unreadable files and directories are handled as `DiffOptions::error_policy` says.
+pub fn scan_directory_report(dir_path: &Path, options: &DiffOptions) -> Result<ScanReport> {
+    #[cfg(feature = "serde")]
+    if let Some(snapshot_dir) = &options.snapshot_dir
+        && !options.paranoid
+    {
+        let mut report = snapshot::scan_with_snapshot(dir_path, options, snapshot_dir)?;
+        annotate_files(dir_path, &mut report.files, options);
+        stats::record_tree(dir_path, &report.files);
+        return Ok(report);
+    }
+    #[cfg(feature = "serde")]
+    if let Some(cache_dir) = &options.hash_cache
+        && !options.paranoid
+    {
+        let mut report = hashcache::scan_with_cache(dir_path, options, cache_dir)?;
+        annotate_files(dir_path, &mut report.files, options);
+        stats::record_tree(dir_path, &report.files);
+        return Ok(report);
+    }
+    let mut report = scan_directory_reusing(dir_path, options, &HashMap::new(), |_| true)?;
+    annotate_files(dir_path, &mut report.files, options);
+    stats::record_tree(dir_path, &report.files);
+    Ok(report)
+}
+
+/// Tag every file of a scan of `dir_path` and run the annotator of `options`, if any
+#[cfg(not(feature = "serde"))]
+fn annotate_files(_dir_path: &Path, files: &mut HashMap<PathBuf, FileInfo>, options: &DiffOptions) {
+    tag_files(files, options);
+}
+
+#[cfg(feature = "serde")]
+fn annotate_files(dir_path: &Path, files: &mut HashMap<PathBuf, FileInfo>, options: &DiffOptions) {
+    tag_files(files, options);
+    let Some(Annotator(annotate)) = &options.annotator else {
+        return;
+    };
+    let _annotate = Phase::start(format!("annotate {}", dir_path.display()), options.thread_count());
+    utils::io_thread_pool(options.thread_count()).install(|| {
+        files.par_iter_mut().for_each(|(path, info)| annotate(&dir_path.join(path), info));
+    });
+}
+
+/// Set the tags of every file from the tag rules of `options`, if any
+fn tag_files(files: &mut HashMap<PathBuf, FileInfo>, options: &DiffOptions) {
+    if let Some(rules) = &options.tag_rules {
+        files.values_mut().for_each(|info| rules.tag(info));
+    }
+}
+
+/// Scan a directory, taking hashes from `previous` for files `may_have_changed` rules out
+///
+/// A previous entry is only reused if the file size still matches.
+pub fn scan_directory_reusing(
+    dir_path: &Path,
+    options: &DiffOptions,
+    previous: &HashMap<PathBuf, FileInfo>,
+    may_have_changed: impl Fn(&Path) -> bool + Sync,
+) -> Result<ScanReport> {
+    let known = |path: &Path, metadata: &fs::Metadata| {
+        previous.get(path).filter(|info| info.size == metadata.len() && !may_have_changed(path)).cloned()
+    };
+    scan_directory_with(dir_path, options, known, |_, _| {})
+}
+
+/// Scan a directory, taking the entry of a file from `known` where it has one instead
+/// of hashing the file
+///
+/// `known` gets the relative path and metadata of every regular file before it is
+/// hashed. `on_scanned` gets every file entry with the metadata read before hashing it
+/// (or reusing its known entry), e.g. to remember hashes for later scans; links are
+/// not passed to either.
+pub fn scan_directory_with(
+    dir_path: &Path,
+    options: &DiffOptions,
+    known: impl Fn(&Path, &fs::Metadata) -> Option<FileInfo> + Sync,
+    on_scanned: impl Fn(&FileInfo, &fs::Metadata) + Sync,
+) -> Result<ScanReport> {
+    let errors = ScanErrors::new(options);
+    let walk = Phase::start(format!("walk {}", dir_path.display()), 1);
+    let files_to_process = collect_scan_entries(dir_path, options, &errors)?;
+    walk.finish();
+    let counter = ScanCounter::start(
+        options.progress.as_ref(),
+        dir_path,
+        files_to_process.iter().map(|entry| entry.metadata().map_or(0, |metadata| metadata.len())),
+    );
+    
+    // Create a thread pool with limited threads to avoid I/O contention
+    let pool = utils::io_thread_pool(options.thread_count());
+    let hashing = Phase::start(format!("hash {}", dir_path.display()), options.thread_count());
+    
+    // Process files in parallel with the custom thread pool
+    let results = pool.install(|| {
+        files_to_process.par_iter().map(|entry| {
+            // Skip the remaining files once Ctrl-C was pressed or the scan was cancelled
+            if options.should_stop() {
+                return Ok(None);
+            }
+            let result = scan_entry(entry, dir_path, options, &known, &on_scanned, &errors);
+            counter.hashed(result.as_ref().ok().and_then(Option::as_ref).map_or(0, |(_, info)| info.size));
+            result
+        }).collect::<Vec<Result<_>>>()
+    });
+    hashing.finish();
+
+    // Stopped: hand back the files hashed so far with the stop error
+    if options.should_stop() {
+        let files = results.into_iter().filter_map(|result| result.ok().flatten()).collect();
+        let report = ScanReport { files, errors: errors.into_errors() };
+        return Err(interrupt::Partial::new(report, interrupt::stop_error(options.cancel())).into());
+    }
+    let results = results.into_iter().collect::<Result<Vec<_>>>()?;
+
+    // Add results to HashMap
+    let mut files_map = HashMap::with_capacity(results.len());
+    for result in results.into_iter().flatten() {
+        files_map.insert(result.0, result.1);
+    }
+    
+    Ok(ScanReport { files: files_map, errors: errors.into_errors() })
+}
+
+/// Entry for one file found by `scan_directory_with`, or `None` if it is skipped
+fn scan_entry(
+    entry: &walkdir::DirEntry,
+    dir_path: &Path,
+    options: &DiffOptions,
+    known: &(impl Fn(&Path, &fs::Metadata) -> Option<FileInfo> + Sync),
+    on_scanned: &(impl Fn(&FileInfo, &fs::Metadata) + Sync),
+    errors: &ScanErrors,
+) -> Result<Option<(PathBuf, FileInfo)>> {
+    let full_path = entry.path();
+    let Ok(relative_path) = full_path.strip_prefix(dir_path).map(Path::to_path_buf) else {
+        return Ok(None);
+    };
+    if entry.path_is_symlink() && !options.follow_symlinks {
+        return Ok(read_symlink(full_path, relative_path, options, errors)?.map(|info| (info.relative_path.clone(), info)));
+    }
+    
+    // Get metadata
+    let metadata = match fs::metadata(full_path) {
+        Ok(meta) => meta,
+        Err(e) => {
+            let message = format!("cannot be read ({})", e);
+            let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, message).with_path(&relative_path);
+            errors.record(warning.with_error(FileError::from_io(ErrorPhase::Metadata, &e)))?;
+            return Ok(None);
+        }
+    };
+    
+    if let Some(info) = known(&relative_path, &metadata) {
+        // A mode change leaves the content alone and may not count as a change
+        let info = info.with_mode(&metadata);
+        on_scanned(&info, &metadata);
+        return Ok(Some((relative_path, info)));
+    }
+
+    // Calculate hash
+    let Some((hash, content)) = hash_scanned_file(full_path, &relative_path, &metadata, options, errors)? else {
+        return Ok(None);
+    };
+    
+    let info = FileInfo::new(relative_path.clone(), hash, metadata.len()).with_mode(&metadata).with_content(content);
+    on_scanned(&info, &metadata);
+    Ok(Some((relative_path, info)))
+}
+
+/// Entry for a symbolic link found by a scan, recording a warning if it cannot be read
+fn read_symlink(full_path: &Path, relative_path: PathBuf, options: &DiffOptions, errors: &ScanErrors) -> Result<Option<FileInfo>> {
+    match fs::read_link(full_path) {
+        Ok(target) => Ok(Some(FileInfo::symlink(relative_path, target, options.hash_algorithm, options.digest_encoding))),
+        Err(e) => {
+            let message = format!("symbolic link cannot be read ({})", e);
+            let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, message).with_path(&relative_path);
+            errors.record(warning.with_error(FileError::from_io(ErrorPhase::ReadLink, &e)))?;
+            Ok(None)
+        }
+    }
+}
+
+/// List the files under `dir_path` with their sizes, leaving the hashes empty
+///
+/// Symbolic links (unless followed) get their hash right away, as it needs no file content.
+fn list_directory(dir_path: &Path, options: &DiffOptions) -> Result<HashMap<PathBuf, FileInfo>> {
+    let _walk = Phase::start(format!("walk {}", dir_path.display()), 1);
+    let errors = ScanErrors::new(options);
+    let mut files = HashMap::new();
+    for entry in collect_scan_entries(dir_path, options, &errors)? {
+        let Ok(relative_path) = entry.path().strip_prefix(dir_path).map(Path::to_path_buf) else {
+            continue;
+        };
+        if entry.path_is_symlink() && !options.follow_symlinks {
+            if let Some(info) = read_symlink(entry.path(), relative_path.clone(), options, &errors)? {
+                files.insert(relative_path, info);
+            }
+            continue;
+        }
+        match entry.metadata() {
+            Ok(metadata) => {
+                files.insert(relative_path.clone(), FileInfo::new(relative_path, String::new(), metadata.len()).with_mode(&metadata));
+            }
+            Err(e) => {
+                let message = format!("cannot be read ({})", e);
+                let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, message).with_path(&relative_path);
+                errors.record(warning.with_error(walk_file_error(ErrorPhase::Metadata, &e)))?;
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Hash the listed files of a `list_directory` result in place
+///
+/// Files that cannot be read are left out, as in a full scan, or fail the scan under
+/// `ErrorPolicy::FailFast`.
+fn fill_hashes(dir_path: &Path, files: &mut HashMap<PathBuf, FileInfo>, paths: &[PathBuf], options: &DiffOptions) -> Result<()> {
+    let _hashing = Phase::start(format!("hash {}", dir_path.display()), options.thread_count());
+    // Symbolic links were hashed when listed
+    let paths: Vec<&PathBuf> = paths.iter().filter(|path| !files.get(*path).is_some_and(FileInfo::is_symlink)).collect();
+    let size = |path: &PathBuf| files.get(path).map_or(0, |info| info.size);
+    let counter = ScanCounter::start(options.progress.as_ref(), dir_path, paths.iter().map(|path| size(path)));
+    let hashes: Vec<(PathBuf, Result<(String, ContentKind)>)> = utils::io_thread_pool(options.thread_count()).install(|| {
+        paths.par_iter().map(|path| {
+            let hashed = options.hash_file(&dir_path.join(path));
+            counter.hashed(size(path));
+            ((*path).clone(), hashed)
+        }).collect()
+    });
+    let errors = ScanErrors::new(options);
+    for (path, hashed) in hashes {
+        match hashed {
+            Ok((hash, content)) => {
+                if let Some(info) = files.get_mut(&path) {
+                    info.hash = hash;
+                    info.content = Some(content);
+                }
+            }
+            Err(e) => {
+                files.remove(&path);
+                errors.record(unreadable_file_warning(&path, &e))?;
             }
         }
     }
-    
-    false
+    Ok(())
 }
 
-/// Scan directory and collect file information
-pub fn scan_directory(
-    dir_path: &Path, 
-    exclude_extensions: Option<&[String]>, 
-    exclude_dirs: Option<&[String]>
-) -> Result<HashMap<PathBuf, FileInfo>> {
-    // Collect all valid files first
-    let files_to_process: Vec<_> = WalkDir::new(dir_path)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| {
-            let full_path = e.path();
-            let relative_path = full_path.strip_prefix(dir_path)
-                .unwrap_or_else(|_| Path::new(""))
-                .to_path_buf();
-                
-            // Skip hidden files and directories
-            if relative_path.components().any(|c| {
-                if let Some(s) = c.as_os_str().to_str() {
-                    s.starts_with('.')
-                } else {
-                    false
-                }
-            }) {
-                return false;
-            }
-            
-            // Skip files based on exclude patterns
-            !should_exclude(&relative_path, exclude_extensions, exclude_dirs)
+/// Number of files and bytes a scan of one directory will hash
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanEstimate {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Count the files and bytes under `dir_path` without hashing anything
+pub fn estimate_directory(dir_path: &Path, options: &DiffOptions) -> ScanEstimate {
+    collect_scan_entries(dir_path, options, &ScanErrors::lenient(options))
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|entry| entry.metadata().ok())
+        .fold(ScanEstimate::default(), |estimate, metadata| ScanEstimate {
+            files: estimate.files + 1,
+            bytes: estimate.bytes + metadata.len(),
         })
+}
+
+/// Bytes read from each sampled file when measuring hashing throughput
+const THROUGHPUT_SAMPLE_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Measure hashing throughput (bytes per second) on a sample of the files under `dir_path`
+///
+/// Hashes files spread across the tree with the configured thread count until `budget`
+/// is used up. Returns `None` if nothing could be read.
+pub fn measure_hash_throughput(dir_path: &Path, options: &DiffOptions, budget: Duration) -> Option<f64> {
+    let entries = collect_scan_entries(dir_path, options, &ScanErrors::lenient(options)).unwrap_or_default();
+    // Visit files in strides so the sample is not limited to the first directory
+    let stride = (entries.len() / 64).max(1);
+    let order: Vec<&walkdir::DirEntry> = (0..stride)
+        .flat_map(|offset| entries.iter().skip(offset).step_by(stride))
         .collect();
-    
-    // Create a thread pool with limited threads to avoid I/O contention
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(*IO_THREADS)
-        .build()
-        .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap());
-    
-    // Process files in parallel with the custom thread pool
-    let results = pool.install(|| {
-        files_to_process.par_iter().map(|entry| {
-            let full_path = entry.path();
-            let relative_path = match full_path.strip_prefix(dir_path) {
-                Ok(path) => path.to_path_buf(),
-                Err(_) => return None,
-            };
-            
-            // Get metadata
-            let metadata = match fs::metadata(full_path) {
-                Ok(meta) => meta,
-                Err(_) => return None,
-            };
-            
-            // Calculate hash
-            let hash = match calculate_file_hash(full_path) {
-                Ok(h) => h,
-                Err(_) => return None,
+
+    let hashed_bytes = AtomicU64::new(0);
+    let start = Instant::now();
+    utils::io_thread_pool(options.thread_count()).install(|| {
+        order.par_iter().with_max_len(1).for_each(|entry| {
+            if start.elapsed() >= budget || options.should_stop() {
+                return;
+            }
+            let Ok(file) = fs::File::open(entry.path()) else {
+                return;
             };
-            
-            Some((
-                relative_path.clone(),
-                FileInfo {
-                    relative_path,
-                    hash,
-                    size: metadata.len(),
-                }
-            ))
-        }).collect::<Vec<_>>()
+            let mut reader = BufReader::with_capacity(65536, file).take(THROUGHPUT_SAMPLE_LIMIT);
+            if let Ok(bytes) = std::io::copy(&mut reader, &mut Sha256::new()) {
+                hashed_bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+        });
     });
-    
-    // Add results to HashMap
-    let mut files_map = HashMap::with_capacity(results.len());
-    for result in results.into_iter().flatten() {
-        files_map.insert(result.0, result.1);
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let bytes = hashed_bytes.into_inner();
+    (bytes > 0 && elapsed > 0.0).then(|| bytes as f64 / elapsed)
+}
+
+/// Find groups of paths that differ only by letter case
+///
+/// Such paths collide on case-insensitive file systems (Windows, macOS), so a patch
+/// built from a case-sensitive tree may fail to install there.
+pub fn find_case_conflicts<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for path in paths {
+        let key = path.to_string_lossy().to_lowercase();
+        groups.entry(key).or_default().push(path.clone());
+    }
+
+    let mut conflicts: Vec<Vec<PathBuf>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect();
+    conflicts.sort();
+    conflicts
+}
+
+/// Record a warning for every group of paths that differ only by case
+fn report_case_conflicts(files: &HashMap<PathBuf, FileInfo>, options: &DiffOptions) {
+    for group in find_case_conflicts(files.keys()) {
+        let others: Vec<String> = group[1..].iter().map(|p| p.display().to_string()).collect();
+        let message = format!("differs only by case from {}; they collide on Windows/macOS", others.join(", "));
+        options.warnings.push(Warning::new(WarningKind::CaseConflict, Severity::Warning, message).with_path(&group[0]));
     }
-    
-    Ok(files_map)
 }
 
 /// Calculate file differences between two files
-pub fn calculate_file_diff(source_path: &Path, target_path: &Path, relative_path: &Path) -> Result<FileDiff> {
+pub fn calculate_file_diff(source_path: &Path, target_path: &Path, relative_path: &Path, algorithm: HashAlgorithm, encoding: DigestEncoding) -> Result<FileDiff> {
     // Read source file content
     let mut source_content = String::new();
     let mut source_file = fs::File::open(source_path)
@@ -223,8 +2014,8 @@ pub fn calculate_file_diff(source_path: &Path, target_path: &Path, relative_path
         .with_context(|| format!("Failed to read target file for diff: {}", target_path.display()))?;
     
     // Calculate hashes
-    let source_hash = calculate_file_hash(source_path)?;
-    let target_hash = calculate_file_hash(target_path)?;
+    let (source_hash, _) = hash_file(source_path, algorithm, encoding)?;
+    let (target_hash, _) = hash_file(target_path, algorithm, encoding)?;
     
     // Calculate diff
     let diff = TextDiff::from_lines(&source_content, &target_content);
@@ -276,55 +2067,905 @@ pub fn calculate_file_diff(source_path: &Path, target_path: &Path, relative_path
         hash: target_hash,
         original_hash: source_hash,
         changes,
+        mode: None,
+        tags: Vec::new(),
+        #[cfg(feature = "serde")]
+        extra: Extra::new(),
     };
     
     Ok(file_diff)
 }
 
+/// Collect the files of the source side of a comparison
+///
+/// The source is usually a directory. It can also be a patch file, in which case the
+/// target manifest embedded in the patch is used: the tree as it was right after that
+/// patch was applied. A disk image (ISO 9660 or UDF) or a ZIP archive is read as the
+/// directory tree it holds, without mounting or extracting it, and a manifest saved with
+/// `Manifest::save` stands for the tree it was made from. The manifest can also be given
+/// as an HTTP, HTTPS or S3 URL, where an updater publishes it (see `remote::RemoteFile`).
+/// An `sftp://` or `ssh://` URL names a directory on another machine, such as a deployed
+/// server, which is scanned over SSH (see `ssh::SshFs`).
+pub fn scan_source(source: &Path, options: &DiffOptions) -> Result<HashMap<PathBuf, FileInfo>> {
+    if crate::ssh::is_ssh_url(source) {
+        options.status(format_args!("{}", tr!("scan.remote", path = source.display())));
+        return scan_vfs(&crate::ssh::SshFs::open(&source.to_string_lossy())?, options);
+    }
+    #[cfg(feature = "serde")]
+    if crate::remote::is_url(source) {
+        let url = source.to_string_lossy();
+        options.status(format_args!("{}", tr!("scan.downloading_manifest", url = url)));
+        return manifest_files(crate::remote::load_manifest(&url)?, options);
+    }
+    if !source.is_file() {
+        options.status(format_args!("{}", tr!("scan.source_dir", path = source.display())));
+        return scan_directory(source, options);
+    }
+    if let Some(vfs) = vfs::open_archive(source)? {
+        options.status(format_args!("{}", tr!("scan.source", name = vfs.name())));
+        return scan_vfs(vfs.as_ref(), options);
+    }
+    #[cfg(feature = "serde")]
+    if crate::manifest::is_manifest_file(source) {
+        options.status(format_args!("{}", tr!("scan.reading_manifest", path = source.display())));
+        return manifest_files(Manifest::load(source)?, options);
+    }
+    scan_patch_source(source, options)
+}
+
+/// Files of a manifest, filtered and with hashes written as a scan with `options` would
+#[cfg(feature = "serde")]
+fn manifest_files(mut manifest: Manifest, options: &DiffOptions) -> Result<HashMap<PathBuf, FileInfo>> {
+    manifest.check_algorithm(options.hash_algorithm)?;
+    manifest.reencode(options.digest_encoding)?;
+    let mut files = manifest.into_file_map();
+    files.retain(|path, _| !options.is_excluded(path));
+    tag_files(&mut files, options);
+    Ok(files)
+}
+
+/// Files of `vfs` that a scan processes, after the hidden, exclude and size filters
+fn collect_vfs_entries(vfs: &dyn Vfs, options: &DiffOptions, errors: &ScanErrors) -> Result<Vec<(PathBuf, VfsMetadata)>> {
+    let mut entries = Vec::new();
+    for path in vfs.list()? {
+        if options.is_excluded(&path) {
+            continue;
+        }
+        let metadata = match vfs.metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                let message = format!("cannot be read ({:#})", e);
+                let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, message).with_path(&path);
+                errors.record(warning.with_error(FileError::from_error(ErrorPhase::Metadata, &e)))?;
+                continue;
+            }
+        };
+        if options.exceeds_max_file_size(metadata.size) {
+            let message = format!("larger than --max-file-size ({})", utils::format_size(metadata.size));
+            options.warnings.push(Warning::new(WarningKind::SkippedFile, Severity::Info, message).with_path(&path));
+            continue;
+        }
+        entries.push((path, metadata));
+    }
+    Ok(entries)
+}
+
+/// Scan any file tree, hashing files in parallel as `scan_directory` does
+pub fn scan_vfs(vfs: &dyn Vfs, options: &DiffOptions) -> Result<HashMap<PathBuf, FileInfo>> {
+    Ok(scan_vfs_report(vfs, options)?.files)
+}
+
+/// Scan any file tree like `scan_vfs`, also listing what could not be read
+pub fn scan_vfs_report(vfs: &dyn Vfs, options: &DiffOptions) -> Result<ScanReport> {
+    let errors = ScanErrors::new(options);
+    let walk = Phase::start(format!("walk {}", vfs.name()), 1);
+    let entries = collect_vfs_entries(vfs, options, &errors)?;
+    walk.finish();
+    let name = PathBuf::from(vfs.name());
+    let counter = ScanCounter::start(options.progress.as_ref(), &name, entries.iter().map(|(_, metadata)| metadata.size));
+
+    let hashing = Phase::start(format!("hash {}", vfs.name()), options.thread_count());
+    let paths: Vec<PathBuf> = entries.iter().map(|(path, _)| path.clone()).collect();
+    let results = if let Some(digests) = vfs.digests(&paths, options.hash_algorithm)? {
+        if digests.len() != entries.len() {
+            return Err(anyhow!("{} returned {} digests for {} files", vfs.name(), digests.len(), entries.len()));
+        }
+        entries.iter().zip(digests).map(|((path, metadata), digest)| {
+            counter.hashed(metadata.size);
+            let Some(digest) = digest else {
+                let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, "cannot be read").with_path(path);
+                errors.record(warning.with_error(FileError::from_error(ErrorPhase::Hash, &anyhow!("cannot be read"))))?;
+                return Ok(None);
+            };
+            let hash = options.digest_encoding.encode_as(options.hash_algorithm, &digest);
+            Ok(Some((path.clone(), FileInfo::new(path.clone(), hash, metadata.size), metadata)))
+        }).collect::<Result<Vec<_>>>()
+        .and_then(|digested| sniff_vfs_files(vfs, digested, options, &errors))
+    } else {
+        utils::io_thread_pool(options.thread_count()).install(|| {
+            entries.par_iter().map(|(path, metadata)| {
+                if options.should_stop() {
+                    return Ok(None);
+                }
+                let hashed = vfs.open(path).and_then(|reader| FileInfo::from_reader(path.clone(), reader, options.hash_algorithm, options.digest_encoding));
+                counter.hashed(metadata.size);
+                let info = match hashed {
+                    Ok(info) => info,
+                    Err(e) => {
+                        let message = format!("cannot be read ({:#})", e);
+                        let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, message).with_path(path);
+                        errors.record(warning.with_error(FileError::from_error(ErrorPhase::Hash, &e)))?;
+                        return Ok(None);
+                    }
+                };
+                if !vfs.metadata(path).is_ok_and(|after| after == *metadata) {
+                    let message = "changed while it was hashed; the recorded hash may not match its content";
+                    let warning = Warning::new(WarningKind::UnstableFile, Severity::Warning, message).with_path(path);
+                    options.warnings.push(warning.with_error(unstable_error()));
+                }
+                Ok(Some((path.clone(), FileInfo { mode: metadata.mode, ..info })))
+            }).collect::<Result<Vec<_>>>()
+        })
+    };
+    hashing.finish();
+    options.check_stop()?;
+
+    let mut files = results?.into_iter().flatten().collect();
+    annotate_files(&name, &mut files, options);
+    stats::record_tree(&name, &files);
+    Ok(ScanReport { files, errors: errors.into_errors() })
+}
+
+/// Fill in the mode and content kind of files whose digests `vfs` computed, reading only
+/// the first bytes of each
+fn sniff_vfs_files(
+    vfs: &dyn Vfs,
+    digested: Vec<Option<(PathBuf, FileInfo, &VfsMetadata)>>,
+    options: &DiffOptions,
+    errors: &ScanErrors,
+) -> Result<Vec<Option<(PathBuf, FileInfo)>>> {
+    let _sniff = Phase::start(format!("classify {}", vfs.name()), options.thread_count());
+    utils::io_thread_pool(options.thread_count()).install(|| {
+        digested.par_iter().map(|entry| {
+            let Some((path, info, metadata)) = entry else {
+                return Ok(None);
+            };
+            if options.should_stop() {
+                return Ok(None);
+            }
+            let mut head = Vec::with_capacity(SNIFF_LEN);
+            let read = vfs.open(path).and_then(|reader| {
+                reader.take(SNIFF_LEN as u64).read_to_end(&mut head).with_context(|| format!("Failed to read {}", path.display()))
+            });
+            if let Err(e) = read {
+                let warning = Warning::new(WarningKind::SkippedFile, Severity::Warning, format!("cannot be read ({:#})", e)).with_path(path);
+                errors.record(warning.with_error(FileError::from_error(ErrorPhase::Hash, &e)))?;
+                return Ok(None);
+            }
+            let info = FileInfo { mode: metadata.mode, content: Some(ContentKind::sniff(&head)), ..info.clone() };
+            Ok(Some((path.clone(), info)))
+        }).collect()
+    })
+}
+
+/// Count the files and bytes of `vfs` a scan will hash
+pub fn estimate_vfs(vfs: &dyn Vfs, options: &DiffOptions) -> Result<ScanEstimate> {
+    Ok(collect_vfs_entries(vfs, options, &ScanErrors::lenient(options))?.iter().fold(ScanEstimate::default(), |estimate, (_, metadata)| ScanEstimate {
+        files: estimate.files + 1,
+        bytes: estimate.bytes + metadata.size,
+    }))
+}
+
+#[cfg(not(feature = "package"))]
+fn scan_patch_source(source: &Path, _options: &DiffOptions) -> Result<HashMap<PathBuf, FileInfo>> {
+    Err(anyhow!("Comparing against a patch file needs the `package` feature: {}", source.display()))
+}
+
+#[cfg(feature = "package")]
+fn scan_patch_source(source: &Path, options: &DiffOptions) -> Result<HashMap<PathBuf, FileInfo>> {
+    options.status(format_args!("{}", tr!("scan.reading_patch_manifest", path = source.display())));
+    let _read = Phase::start(format!("read manifest {}", source.display()), 1);
+    let patch_data = crate::patch::read_patch_manifest(source)?;
+    let manifest = patch_data.target_manifest.ok_or_else(|| {
+        anyhow!("Patch has no embedded target manifest (created by an older version or with byte comparison): {}", source.display())
+    })?;
+    manifest_files(manifest, options)
+}
+
+/// Replace Removed/Added pairs that differ only by extension and have the same content
+/// with a single rename
+fn match_extension_renames(diffs: Vec<DiffType>, source_files: &HashMap<PathBuf, FileInfo>, options: &DiffOptions) -> Vec<DiffType> {
+    // Key removed files by path without extension and content hash; sorted so pairing
+    // does not depend on scan order
+    let mut removed: Vec<&PathBuf> = diffs.iter()
+        .filter_map(|d| match d {
+            DiffType::Removed(path) => Some(path),
+            _ => None,
+        })
+        .collect();
+    removed.sort();
+    let mut candidates: HashMap<(PathBuf, &str), Vec<PathBuf>> = HashMap::new();
+    for path in removed.into_iter().rev() {
+        if let Some(info) = source_files.get(path) {
+            candidates.entry((path.with_extension(""), info.hash.as_str())).or_default().push(path.clone());
+        }
+    }
+
+    let mut added: Vec<&FileInfo> = diffs.iter()
+        .filter_map(|d| match d {
+            // Links are recreated, not moved
+            DiffType::Added(info) if !info.is_symlink() => Some(info),
+            _ => None,
+        })
+        .collect();
+    added.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+    let mut renames = Vec::new();
+    for info in added {
+        let key = (info.relative_path.with_extension(""), info.hash.as_str());
+        if let Some(from) = candidates.get_mut(&key).and_then(Vec::pop) {
+            renames.push(FileRename { from, to: info.clone() });
+        }
+    }
+    if !renames.is_empty() {
+        options.status(format_args!("{}", tr!("scan.extension_renames", count = renames.len())));
+    }
+    replace_with_renames(diffs, renames)
+}
+
+/// Drop the Removed and Added entries that `renames` stand for and add the renames
+fn replace_with_renames(diffs: Vec<DiffType>, renames: Vec<FileRename>) -> Vec<DiffType> {
+    if renames.is_empty() {
+        return diffs;
+    }
+    let renamed_from: HashSet<&PathBuf> = renames.iter().map(|r| &r.from).collect();
+    let renamed_to: HashSet<&PathBuf> = renames.iter().map(|r| &r.to.relative_path).collect();
+    let mut result: Vec<DiffType> = diffs.iter()
+        .filter(|d| match d {
+            DiffType::Removed(path) => !renamed_from.contains(path),
+            DiffType::Added(info) => !renamed_to.contains(&info.relative_path),
+            _ => true,
+        })
+        .cloned()
+        .collect();
+    result.extend(renames.into_iter().map(DiffType::Renamed));
+    result
+}
+
+/// Replace Removed/Added pairs with the same content and size, wherever their paths
+/// are, with a single rename
+///
+/// Among removed files with the content of an added one, the first by path with the same
+/// file name is taken, otherwise the first by path.
+fn match_moved_files(diffs: Vec<DiffType>, source_files: &HashMap<PathBuf, FileInfo>, options: &DiffOptions) -> Vec<DiffType> {
+    let mut removed: Vec<&PathBuf> = diffs.iter()
+        .filter_map(|d| match d {
+            DiffType::Removed(path) => Some(path),
+            _ => None,
+        })
+        .collect();
+    removed.sort();
+    let mut candidates: HashMap<(&str, u64), Vec<PathBuf>> = HashMap::new();
+    for path in removed {
+        if let Some(info) = source_files.get(path)
+            && info.size >= options.rename_min_size.max(1)
+            && !info.hash.is_empty()
+        {
+            candidates.entry((info.hash.as_str(), info.size)).or_default().push(path.clone());
+        }
+    }
+
+    let mut added: Vec<&FileInfo> = diffs.iter()
+        .filter_map(|d| match d {
+            // Links are recreated, not moved
+            DiffType::Added(info) if !info.is_symlink() => Some(info),
+            _ => None,
+        })
+        .collect();
+    added.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+    let mut renames = Vec::new();
+    for info in added {
+        let Some(paths) = candidates.get_mut(&(info.hash.as_str(), info.size)).filter(|paths| !paths.is_empty()) else {
+            continue;
+        };
+        let index = paths.iter().position(|path| path.file_name() == info.relative_path.file_name()).unwrap_or(0);
+        renames.push(FileRename { from: paths.remove(index), to: info.clone() });
+    }
+    if !renames.is_empty() {
+        options.status(format_args!("{}", tr!("scan.renames", count = renames.len())));
+    }
+    replace_with_renames(diffs, renames)
+}
+
 /// Compare two directories and find file differences
-pub fn compare_directories(
-    source_dir: &Path, 
-    target_dir: &Path, 
-    exclude_extensions: Option<&[String]>, 
-    exclude_dirs: Option<&[String]>,
-    use_diff_patches: bool, // Add parameter to control whether to use diff patches
-) -> Result<Vec<DiffType>> {
-    println!("Scanning source directory: {}", source_dir.display());
-    let source_files = scan_directory(source_dir, exclude_extensions, exclude_dirs)?;
-    
-    println!("Scanning target directory: {}", target_dir.display());
-    let target_files = scan_directory(target_dir, exclude_extensions, exclude_dirs)?;
-    
+///
+/// `source_dir` may also be a patch file, see `scan_source`.
+pub fn compare_directories(source_dir: &Path, target_dir: &Path, options: &DiffOptions) -> Result<DiffReport> {
+    let mut diffs = Vec::new();
+    let mut report = compare_directories_streaming(source_dir, target_dir, options, |diff| {
+        diffs.push(diff);
+        Ok(())
+    })?;
+    report.diffs = diffs;
+    Ok(report)
+}
+
+/// Compare two directories like `compare_directories`, handing every difference to
+/// `on_diff` as soon as it is found instead of collecting them
+///
+/// Consumers can start copying or compressing while the comparison goes on, and the
+/// differences of trees with millions of files need not all be kept in memory. Only the
+/// differences are streamed: both trees are scanned first and their entries (one per
+/// file) are held until the end, as whether a file changed depends on both sides. With
+/// `diff_cache`, differences are written to the cache entry as they are found, and a
+/// cached comparison is replayed from it line by line. Differences come in no particular
+/// order; with rename matching, added and removed files are held back until the end,
+/// when they can be paired. An error from `on_diff` stops the comparison.
+///
+/// Returns the report without its `diffs`: warnings, statistics, archive members and the
+/// target manifest.
+pub fn compare_directories_streaming(
+    source_dir: &Path,
+    target_dir: &Path,
+    options: &DiffOptions,
+    mut on_diff: impl FnMut(DiffType) -> Result<()>,
+) -> Result<DiffReport> {
+    options.validate()?;
+    match options.strategy() {
+        CompareStrategy::Hash => {}
+        CompareStrategy::ByteCompare => return compare_bytes(source_dir, target_dir, options, &mut on_diff),
+        CompareStrategy::SizeAndMtime => return compare_stamps(source_dir, target_dir, options, &mut on_diff),
+    }
+
+    let lazy = options.lazy_hashing && !options.paranoid && source_dir.is_dir();
+    let (source_files, mut target_files) = if lazy {
+        scan_lazily(source_dir, target_dir, options)?
+    } else {
+        let source_files = scan_source(source_dir, options)?;
+        options.status(format_args!("{}", tr!("scan.target_dir", path = target_dir.display())));
+        let target_files = if options.reuses_clones() && source_dir.is_dir() {
+            scan_target_reusing_clones(source_dir, target_dir, &source_files, options)?
+        } else {
+            scan_directory(target_dir, options)?
+        };
+        (source_files, target_files)
+    };
+
+    #[cfg(feature = "serde")]
+    let mut cache_writer = match &options.diff_cache {
+        Some(cache_dir) if !lazy && !options.paranoid => {
+            let key = diffcache::cache_key(&source_files, &target_files, &options.comparison_fingerprint(source_dir.is_dir()))?;
+            if let Some(cached) = diffcache::load(cache_dir, &key) {
+                options.status(format_args!("{}", tr!("scan.cached_comparison", key = &key[..digest::ABBREV_LEN], path = cache_dir.display())));
+                return replay_cached(cache_dir, &key, cached, &source_files, target_files, options, &mut on_diff);
+            }
+            Some(EntryWriter::create(cache_dir, &key)?)
+        }
+        _ => None,
+    };
+    #[cfg(feature = "serde")]
+    let mut on_diff = |diff: DiffType| {
+        if let Some(writer) = &mut cache_writer {
+            writer.push(&diff)?;
+        }
+        on_diff(diff)
+    };
+
+    let stamp_only = if options.ignore_build_stamps && source_dir.is_dir() {
+        let changed = target_files.keys().filter(|path| source_files.get(*path).is_some_and(|info| info.hash != target_files[*path].hash));
+        find_stamp_only_changes(source_dir, target_dir, changed, options)?
+    } else {
+        HashSet::new()
+    };
+    let is_changed = |path: &PathBuf| source_files[path].hash != target_files[path].hash && !stamp_only.contains(path);
+    let mut report = build_report(Some((source_dir, target_dir)), &source_files, &target_files, options, is_changed, &mut on_diff)?;
+    // The patch leaves files that only differ in build stamps as they are
+    for path in &stamp_only {
+        target_files.insert(path.clone(), source_files[path].clone());
+    }
+    // Without the hashes of the added files there is no complete manifest
+    if !lazy {
+        let manifest = Manifest::from_files(&target_files);
+        manifest.validate()?;
+        report.target_manifest = Some(manifest);
+    }
+    #[cfg(feature = "serde")]
+    if let Some(writer) = cache_writer {
+        writer.finish(CachedComparison {
+            archives: report.archives.clone(),
+            stats: report.stats,
+            source_files: report.source_files.values().cloned().collect(),
+            stamp_only: stamp_only.into_iter().collect(),
+            ..CachedComparison::new()
+        })?;
+    }
+    Ok(report)
+}
+
+/// Hand the differences of a cached comparison to `on_diff` and rebuild its report
+#[cfg(feature = "serde")]
+fn replay_cached(
+    cache_dir: &Path,
+    key: &str,
+    cached: CachedComparison,
+    source_files: &HashMap<PathBuf, FileInfo>,
+    mut target_files: HashMap<PathBuf, FileInfo>,
+    options: &DiffOptions,
+    on_diff: &mut dyn FnMut(DiffType) -> Result<()>,
+) -> Result<DiffReport> {
+    // Warnings are not cached, so these are found again
+    if options.check_case_conflicts {
+        report_case_conflicts(&target_files, options);
+    }
+    diffcache::replay(cache_dir, key, on_diff)?;
+    for path in &cached.stamp_only {
+        if let Some(info) = source_files.get(path) {
+            target_files.insert(path.clone(), info.clone());
+        }
+    }
+    let manifest = Manifest::from_files(&target_files);
+    manifest.validate()?;
+
+    let mut report = DiffReport::new(Vec::new());
+    report.warnings = options.warnings.take();
+    report.archives = cached.archives;
+    report.stats = cached.stats;
+    report.source_files = cached.source_files.into_iter().map(|info| (info.relative_path.clone(), info)).collect();
+    report.target_manifest = Some(manifest);
+    Ok(report)
+}
+
+/// Scan the target directory like `scan_directory`, taking the entry of every file that
+/// is a clone of the source file at the same path from `source_files`
+fn scan_target_reusing_clones(
+    source_dir: &Path,
+    target_dir: &Path,
+    source_files: &HashMap<PathBuf, FileInfo>,
+    options: &DiffOptions,
+) -> Result<HashMap<PathBuf, FileInfo>> {
+    let detector = CloneDetector::new();
+    let reused = AtomicUsize::new(0);
+    let known = |path: &Path, metadata: &fs::Metadata| {
+        let source = source_files.get(path).filter(|info| !info.is_symlink() && info.size == metadata.len())?;
+        if !detector.same_data(&source_dir.join(path), &target_dir.join(path)) {
+            return None;
+        }
+        reused.fetch_add(1, Ordering::Relaxed);
+        Some(FileInfo { content: source.content, ..FileInfo::new(path.to_path_buf(), source.hash.clone(), source.size) })
+    };
+    let mut report = scan_directory_with(target_dir, options, known, |_, _| {})?;
+    options.status(format_args!("{}", tr!("scan.reused_clone_hashes", count = reused.into_inner())));
+    annotate_files(target_dir, &mut report.files, options);
+    stats::record_tree(target_dir, &report.files);
+    Ok(report.files)
+}
+
+/// Paths of `paths` whose target file is a clone of the source file at the same path
+fn find_clones(source_dir: &Path, target_dir: &Path, source_files: &HashMap<PathBuf, FileInfo>, target_files: &HashMap<PathBuf, FileInfo>, paths: &[PathBuf], options: &DiffOptions) -> HashSet<PathBuf> {
+    let detector = CloneDetector::new();
+    let is_clone = |path: &&PathBuf| {
+        let (Some(source), Some(target)) = (source_files.get(*path), target_files.get(*path)) else {
+            return false;
+        };
+        !source.is_symlink() && !target.is_symlink() && source.size == target.size
+            && detector.same_data(&source_dir.join(path), &target_dir.join(path))
+    };
+    utils::io_thread_pool(options.thread_count()).install(|| paths.par_iter().filter(is_clone).cloned().collect())
+}
+
+/// List both directories and hash only the files present on both sides
+///
+/// Added and removed files are classified by path alone; `hash_added_files` hashes the
+/// added ones when the report is packaged.
+fn scan_lazily(source_dir: &Path, target_dir: &Path, options: &DiffOptions) -> Result<(HashMap<PathBuf, FileInfo>, HashMap<PathBuf, FileInfo>)> {
+    options.status(format_args!("{}", tr!("scan.listing_source", path = source_dir.display())));
+    let mut source_files = list_directory(source_dir, options)?;
+    options.status(format_args!("{}", tr!("scan.listing_target", path = target_dir.display())));
+    let mut target_files = list_directory(target_dir, options)?;
+
+    // Renames are matched by content, so then the one-sided files need hashes too
+    let needs_hash = |path: &PathBuf, other: &HashMap<PathBuf, FileInfo>| options.matches_renames() || other.contains_key(path);
+    let source_paths: Vec<PathBuf> = source_files.keys().filter(|path| needs_hash(path, &target_files)).cloned().collect();
+    let mut target_paths: Vec<PathBuf> = target_files.keys().filter(|path| needs_hash(path, &source_files)).cloned().collect();
+    let skipped = source_files.len() + target_files.len() - source_paths.len() - target_paths.len();
+    // Clones of the source file take its hash once it is known
+    let clones = match options.reuses_clones() {
+        true => find_clones(source_dir, target_dir, &source_files, &target_files, &target_paths, options),
+        false => HashSet::new(),
+    };
+    target_paths.retain(|path| !clones.contains(path));
+    if options.reuses_clones() {
+        options.status(format_args!("{}", tr!("scan.reusing_clone_hashes", count = clones.len())));
+    }
+    options.status(format_args!("{}", tr!("scan.hashing_deferred", count = source_paths.len() + target_paths.len(), skipped = skipped)));
+    fill_hashes(source_dir, &mut source_files, &source_paths, options)?;
+    fill_hashes(target_dir, &mut target_files, &target_paths, options)?;
+    for path in &clones {
+        if let (Some(source), Some(target)) = (source_files.get(path), target_files.get_mut(path)) {
+            target.hash = source.hash.clone();
+            target.content = source.content;
+        }
+    }
+    annotate_files(source_dir, &mut source_files, options);
+    annotate_files(target_dir, &mut target_files, options);
+    stats::record_tree(source_dir, &source_files);
+    stats::record_tree(target_dir, &target_files);
+    Ok((source_files, target_files))
+}
+
+/// Hash the added files of a report that a lazy comparison left without a hash
+///
+/// Returns the number of files hashed; reports from other comparisons have none. Files
+/// that cannot be read are left out of the report with a warning in it, as in a full
+/// scan, or fail under `ErrorPolicy::FailFast`.
+pub fn hash_added_files(report: &mut DiffReport, target_dir: &Path, options: &DiffOptions) -> Result<usize> {
+    let mut pending: Vec<&mut FileInfo> = report.diffs.iter_mut().filter_map(|diff| match diff {
+        DiffType::Added(info) if info.hash.is_empty() => Some(info),
+        _ => None,
+    }).collect();
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    options.status(format_args!("{}", tr!("scan.hashing_added", count = pending.len())));
+    let _hashing = Phase::start(format!("hash {}", target_dir.display()), options.thread_count());
+    let hashes: Vec<Result<(String, ContentKind)>> = utils::io_thread_pool(options.thread_count()).install(|| {
+        pending.par_iter().map(|info| options.hash_file(&target_dir.join(&info.relative_path))).collect()
+    });
+    let errors = ScanErrors::new(options);
+    let mut unreadable = HashSet::new();
+    for (info, hashed) in pending.iter_mut().zip(hashes) {
+        match hashed {
+            Ok((hash, content)) => {
+                info.hash = hash;
+                info.content = Some(content);
+            }
+            Err(e) => {
+                errors.record(unreadable_file_warning(&info.relative_path, &e))?;
+                unreadable.insert(info.relative_path.clone());
+            }
+        }
+    }
+    let hashed = pending.len() - unreadable.len();
+    if !unreadable.is_empty() {
+        report.diffs.retain(|diff| !matches!(diff, DiffType::Added(info) if unreadable.contains(&info.relative_path)));
+        report.warnings.extend(options.warnings.take());
+    }
+    Ok(hashed)
+}
+
+/// Changed files (relative paths) that are executables differing only in build stamps
+fn find_stamp_only_changes<'a>(
+    source_dir: &Path,
+    target_dir: &Path,
+    changed: impl Iterator<Item = &'a PathBuf>,
+    options: &DiffOptions,
+) -> Result<HashSet<PathBuf>> {
+    // Recorded symbolic links have no content to compare
+    let is_link = |path: &Path| !options.follow_symlinks && path.is_symlink();
+    let candidates: Vec<&PathBuf> = changed
+        .filter(|path| !is_link(&source_dir.join(path)) && !is_link(&target_dir.join(path)))
+        .filter(|path| binary::looks_like_executable(&target_dir.join(path)))
+        .collect();
+    if candidates.is_empty() {
+        return Ok(HashSet::new());
+    }
+    options.status(format_args!("{}", tr!("scan.build_stamps", count = candidates.len())));
+    let _compare = Phase::start("compare build stamps", options.thread_count());
+    let stamp_only: HashSet<PathBuf> = utils::io_thread_pool(options.thread_count()).install(|| {
+        candidates.par_iter().filter(|path| {
+            !options.should_stop()
+                // Unreadable files stay changed; the patch reads them again and reports the error
+                && binary::equal_ignoring_stamps(&source_dir.join(path), &target_dir.join(path)).unwrap_or(false)
+        }).map(|path| (*path).clone()).collect()
+    });
+    options.check_stop()?;
+    if !stamp_only.is_empty() {
+        options.status(format_args!("{}", tr!("scan.stamp_only", count = stamp_only.len())));
+    }
+    Ok(stamp_only)
+}
+
+/// Compare two directories byte by byte instead of hashing everything
+///
+/// Files of different size are changed without reading them; files of equal size are
+/// compared until the first difference. Only added and changed target files are hashed.
+fn compare_bytes(source_dir: &Path, target_dir: &Path, options: &DiffOptions, on_diff: &mut dyn FnMut(DiffType) -> Result<()>) -> Result<DiffReport> {
+    if !source_dir.is_dir() {
+        return Err(anyhow!("Byte comparison needs a source directory: {}", source_dir.display()));
+    }
+
+    options.status(format_args!("{}", tr!("scan.listing_source", path = source_dir.display())));
+    let source_files = list_directory(source_dir, options)?;
+    options.status(format_args!("{}", tr!("scan.listing_target", path = target_dir.display())));
+    let target_files = list_directory(target_dir, options)?;
+
+    let common: Vec<&PathBuf> = target_files.keys().filter(|path| source_files.contains_key(*path)).collect();
+    options.status(format_args!("{}", tr!("scan.comparing_bytes", count = common.len())));
+    let comparing = Phase::start("byte-compare", options.thread_count());
+    let compared: Vec<(PathBuf, Result<bool>)> = utils::io_thread_pool(options.thread_count()).install(|| {
+        common.par_iter().map(|path| {
+            let changed = if options.should_stop() {
+                Ok(false)
+            } else if source_files[*path].size != target_files[*path].size {
+                Ok(true)
+            } else if source_files[*path].is_symlink() || target_files[*path].is_symlink() {
+                Ok(source_files[*path].link_target != target_files[*path].link_target)
+            } else {
+                files_equal(&source_dir.join(path), &target_dir.join(path)).map(|equal| !equal)
+            };
+            ((*path).clone(), changed)
+        }).collect()
+    });
+    comparing.finish();
+    options.check_stop()?;
+    let mut changed = HashSet::new();
+    for (path, result) in compared {
+        if result? {
+            changed.insert(path);
+        }
+    }
+    report_listed(source_dir, target_dir, source_files, target_files, changed, options, on_diff)
+}
+
+/// Compare two directories by file size and modification time instead of hashing everything
+///
+/// Files of equal size and modification time are unchanged without reading them; the
+/// other files of equal size are hashed on both sides. Only added and changed target
+/// files are hashed otherwise.
+fn compare_stamps(source_dir: &Path, target_dir: &Path, options: &DiffOptions, on_diff: &mut dyn FnMut(DiffType) -> Result<()>) -> Result<DiffReport> {
+    if !source_dir.is_dir() {
+        return Err(anyhow!("Size and modification time comparison needs a source directory: {}", source_dir.display()));
+    }
+
+    options.status(format_args!("{}", tr!("scan.listing_source", path = source_dir.display())));
+    let mut source_files = list_directory(source_dir, options)?;
+    options.status(format_args!("{}", tr!("scan.listing_target", path = target_dir.display())));
+    let mut target_files = list_directory(target_dir, options)?;
+
+    let common: Vec<PathBuf> = target_files.keys().filter(|path| source_files.contains_key(*path)).cloned().collect();
+    // Files of different size changed and links compare by target; the rest are candidates
+    let candidates: Vec<&PathBuf> = common.iter().filter(|path| {
+        let (source_info, target_info) = (&source_files[*path], &target_files[*path]);
+        source_info.size == target_info.size && !source_info.is_symlink() && !target_info.is_symlink()
+    }).collect();
+    // Candidates whose modification times differ (or cannot be read) need their content compared
+    let modified_time = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let touched: Vec<PathBuf> = utils::io_thread_pool(options.thread_count()).install(|| {
+        candidates.par_iter().filter(|path| {
+            let source_time = modified_time(&source_dir.join(path));
+            source_time.is_none() || source_time != modified_time(&target_dir.join(path))
+        }).map(|path| (*path).clone()).collect()
+    });
+    options.status(format_args!(
+        "{}",
+        tr!("scan.touched", same = candidates.len() - touched.len(), count = touched.len())
+    ));
+    fill_hashes(source_dir, &mut source_files, &touched, options)?;
+    fill_hashes(target_dir, &mut target_files, &touched, options)?;
+    options.check_stop()?;
+
+    let touched: HashSet<PathBuf> = touched.into_iter().collect();
+    let changed: HashSet<PathBuf> = common.into_iter().filter(|path| {
+        let (source_info, target_info) = (&source_files[path], &target_files[path]);
+        if source_info.is_symlink() || target_info.is_symlink() {
+            source_info.link_target != target_info.link_target
+        } else if touched.contains(path) {
+            source_info.hash != target_info.hash
+        } else {
+            source_info.size != target_info.size
+        }
+    }).collect();
+    report_listed(source_dir, target_dir, source_files, target_files, changed, options, on_diff)
+}
+
+/// Finish a comparison of two listed directories, given the paths on both sides that changed
+///
+/// Hashes the files the patch stores (and the removed files when renames are matched),
+/// annotates both sides and builds the report.
+fn report_listed(
+    source_dir: &Path,
+    target_dir: &Path,
+    mut source_files: HashMap<PathBuf, FileInfo>,
+    mut target_files: HashMap<PathBuf, FileInfo>,
+    mut changed: HashSet<PathBuf>,
+    options: &DiffOptions,
+    on_diff: &mut dyn FnMut(DiffType) -> Result<()>,
+) -> Result<DiffReport> {
+    if options.ignore_build_stamps {
+        let stamp_only = find_stamp_only_changes(source_dir, target_dir, changed.iter(), options)?;
+        changed.retain(|path| !stamp_only.contains(path));
+    }
+
+    // Hash what the patch stores, plus the removed files that rename matching looks at
+    let to_hash: Vec<PathBuf> = target_files.keys()
+        .filter(|path| (changed.contains(*path) && target_files[*path].hash.is_empty()) || !source_files.contains_key(*path))
+        .cloned()
+        .collect();
+    options.status(format_args!("{}", tr!("scan.hashing_changed", count = to_hash.len())));
+    fill_hashes(target_dir, &mut target_files, &to_hash, options)?;
+    if options.matches_renames() {
+        let removed: Vec<PathBuf> = source_files.keys().filter(|path| !target_files.contains_key(*path)).cloned().collect();
+        fill_hashes(source_dir, &mut source_files, &removed, options)?;
+    }
+    annotate_files(source_dir, &mut source_files, options);
+    annotate_files(target_dir, &mut target_files, options);
+
+    build_report(Some((source_dir, target_dir)), &source_files, &target_files, options, |path| changed.contains(path), on_diff)
+}
+
+/// Compare two file trees of any kind by content hash
+///
+/// Files are always stored whole, as diff patches and collision checks need directories.
+pub fn compare_vfs(source: &dyn Vfs, target: &dyn Vfs, options: &DiffOptions) -> Result<DiffReport> {
+    options.validate()?;
+    options.status(format_args!("{}", tr!("scan.source", name = source.name())));
+    let source_files = scan_vfs(source, options)?;
+    options.status(format_args!("{}", tr!("scan.target", name = target.name())));
+    let target_files = scan_vfs(target, options)?;
+
     let mut diffs = Vec::new();
+    let is_changed = |path: &PathBuf| source_files[path].hash != target_files[path].hash;
+    let mut report = build_report(None, &source_files, &target_files, options, is_changed, &mut |diff| {
+        diffs.push(diff);
+        Ok(())
+    })?;
+    report.diffs = diffs;
+    let manifest = Manifest::from_files(&target_files);
+    manifest.validate()?;
+    report.target_manifest = Some(manifest);
+    Ok(report)
+}
+
+/// Passes differences on as they are found, keeping what the report needs to know of them
+struct DiffStream<'a> {
+    on_diff: &'a mut dyn FnMut(DiffType) -> Result<()>,
+    source_files: &'a HashMap<PathBuf, FileInfo>,
+    target_files: &'a HashMap<PathBuf, FileInfo>,
+    /// Added and removed files held back for rename matching, if renames are matched
+    held: Option<Vec<DiffType>>,
+    stats: ChangeStats,
+    /// Changed archives, for `DiffOptions::inspect_archives`
+    archives: Vec<PathBuf>,
+    changed_sources: HashMap<PathBuf, FileInfo>,
+}
+
+impl DiffStream<'_> {
+    fn push(&mut self, diff: DiffType) -> Result<()> {
+        if let Some(held) = &mut self.held
+            && matches!(diff, DiffType::Added(_) | DiffType::Removed(_))
+        {
+            held.push(diff);
+            return Ok(());
+        }
+        self.pass_on(diff)
+    }
+
+    fn pass_on(&mut self, diff: DiffType) -> Result<()> {
+        self.stats.changed_files += 1;
+        self.stats.changed_bytes += match &diff {
+            DiffType::Added(info) | DiffType::Modified(info) => info.size,
+            DiffType::ModifiedDiff(file_diff) => self.target_files[&file_diff.relative_path].size,
+            _ => 0,
+        };
+        let source_path = match &diff {
+            DiffType::Added(_) => None,
+            DiffType::Renamed(rename) => Some(rename.from.as_path()),
+            _ => Some(diff.path()),
+        };
+        if let Some(info) = source_path.and_then(|path| self.source_files.get(path)) {
+            self.changed_sources.insert(info.relative_path.clone(), info.clone());
+        }
+        if matches!(diff, DiffType::Modified(_) | DiffType::ModifiedDiff(_)) && is_archive(diff.path()) {
+            self.archives.push(diff.path().to_path_buf());
+        }
+        (self.on_diff)(diff)
+    }
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ARCHIVE_EXTENSIONS.iter().any(|known| ext.eq_ignore_ascii_case(known)))
+}
+
+/// Turn two scanned trees into a report; `is_changed` decides for paths present on both sides
+///
+/// Every difference goes to `on_diff` as it is found (see `compare_directories_streaming`);
+/// the report returned has no `diffs`. With the (source, target) directories given,
+/// changed text files can become diff patches and equal hashes are checked against the
+/// content.
+fn build_report(
+    dirs: Option<(&Path, &Path)>,
+    source_files: &HashMap<PathBuf, FileInfo>,
+    target_files: &HashMap<PathBuf, FileInfo>,
+    options: &DiffOptions,
+    is_changed: impl Fn(&PathBuf) -> bool,
+    on_diff: &mut dyn FnMut(DiffType) -> Result<()>,
+) -> Result<DiffReport> {
+    let compare = Phase::start("compare", 1);
+    // Diff patches need the original file content, which only a directory has
+    let dirs = dirs.filter(|(source_dir, _)| source_dir.is_dir());
+    let use_diff_patches = options.use_diff_patches && dirs.is_some();
+
+    if options.check_case_conflicts {
+        report_case_conflicts(target_files, options);
+    }
+    
+    let mut stream = DiffStream {
+        on_diff,
+        source_files,
+        target_files,
+        // Renames pair added and removed files across the whole comparison
+        held: options.matches_renames().then(Vec::new),
+        stats: ChangeStats {
+            total_files: source_files.len() + target_files.keys().filter(|path| !source_files.contains_key(*path)).count(),
+            ..ChangeStats::default()
+        },
+        archives: Vec::new(),
+        changed_sources: HashMap::new(),
+    };
+    let mut unchanged = Vec::new();
     
     // Find modified and added files
-    for (path, target_info) in &target_files {
+    for (path, target_info) in target_files {
         match source_files.get(path) {
             Some(source_info) => {
-                if source_info.hash != target_info.hash {
-                    if use_diff_patches {
+                if !is_changed(path) {
+                    let mode_changed = source_info.mode.is_some() && target_info.mode.is_some() && source_info.mode != target_info.mode;
+                    if mode_changed && !options.ignore_permissions {
+                        stream.push(DiffType::MetadataChanged(target_info.clone()))?;
+                    // Files treated as unchanged despite other content are not collisions
+                    } else if source_info.hash == target_info.hash && !target_info.is_symlink() {
+                        unchanged.push(path);
+                    }
+                } else {
+                    let is_link = source_info.is_symlink() || target_info.is_symlink();
+                    // Files found to be binary when hashed are not read again for a diff
+                    let is_binary = source_info.content == Some(ContentKind::Binary) || target_info.content == Some(ContentKind::Binary);
+                    if use_diff_patches && !is_link && !is_binary && let Some((source_dir, target_dir)) = dirs {
                         // Check if it's a text file that we can diff
                         let source_path = source_dir.join(path);
                         let target_path = target_dir.join(path);
                         
                         // Try to create a diff
-                        match calculate_file_diff(&source_path, &target_path, path) {
+                        match calculate_file_diff(&source_path, &target_path, path, options.hash_algorithm, options.digest_encoding) {
                             Ok(file_diff) => {
-                                diffs.push(DiffType::ModifiedDiff(file_diff));
+                                let file_diff = FileDiff { tags: target_info.tags.clone(), mode: target_info.mode, ..file_diff };
+                                #[cfg(feature = "serde")]
+                                let file_diff = FileDiff { extra: target_info.extra.clone(), ..file_diff };
+                                stream.push(DiffType::ModifiedDiff(file_diff))?;
                             },
                             Err(_) => {
                                 // If diff fails (e.g., binary file), fall back to full file
-                                diffs.push(DiffType::Modified(target_info.clone()));
+                                stream.push(DiffType::Modified(target_info.clone()))?;
                             }
                         }
                     } else {
                         // Use full file mode
-                        diffs.push(DiffType::Modified(target_info.clone()));
+                        stream.push(DiffType::Modified(target_info.clone()))?;
                     }
                 }
             },
             None => {
-                diffs.push(DiffType::Added(target_info.clone()));
+                stream.push(DiffType::Added(target_info.clone()))?;
+            }
+        }
+    }
+    
+    // Equal hashes with different content mean a hash collision (or a very unlucky bug)
+    if let Some((source_dir, target_dir)) = dirs
+        && options.strategy() == CompareStrategy::Hash
+    {
+        let collisions = find_hash_collisions(source_dir, target_dir, unchanged, options)?;
+        if !collisions.is_empty() {
+            options.status(format_args!("{}", tr!("scan.collisions", count = collisions.len())));
+            for path in &collisions {
+                options.status(format_args!("  - {}", path.display()));
+            }
+            if options.collision_policy == CollisionPolicy::Abort {
+                return Err(anyhow!("Hash verification failed for {} files", collisions.len()));
+            }
+            for path in &collisions {
+                stream.push(DiffType::Modified(target_files[path].clone()))?;
             }
         }
     }
@@ -332,9 +2973,130 @@ pub fn compare_directories(
     // Find removed files
     for path in source_files.keys() {
         if !target_files.contains_key(path) {
-            diffs.push(DiffType::Removed(path.clone()));
+            stream.push(DiffType::Removed(path.clone()))?;
+        }
+    }
+
+    if let Some(mut held) = stream.held.take() {
+        if options.detect_renames {
+            held = match_moved_files(held, source_files, options);
+        }
+        if options.match_extension_renames {
+            held = match_extension_renames(held, source_files, options);
+        }
+        for diff in held {
+            stream.pass_on(diff)?;
         }
     }
     
-    Ok(diffs)
-} 
\ No newline at end of file
+    compare.finish();
+    let archives = match dirs {
+        Some((source_dir, target_dir)) if options.inspect_archives => inspect_archives(source_dir, target_dir, &stream.archives, options)?,
+        _ => Vec::new(),
+    };
+
+    let mut report = DiffReport::new(Vec::new());
+    report.warnings = options.warnings.take();
+    report.archives = archives;
+    report.stats = stream.stats;
+    report.source_files = stream.changed_sources;
+    Ok(report)
+}
+
+/// Compare the members of the modified archives at `archives`
+fn inspect_archives(source_dir: &Path, target_dir: &Path, archives: &[PathBuf], options: &DiffOptions) -> Result<Vec<ArchiveDiff>> {
+    if archives.is_empty() {
+        return Ok(Vec::new());
+    }
+    options.status(format_args!("{}", tr!("scan.archives", count = archives.len())));
+    let mut results = Vec::new();
+    for path in archives {
+        match compare_archive_members(&source_dir.join(path), &target_dir.join(path), path, options) {
+            Ok(Some(members)) => results.push(ArchiveDiff { path: path.to_path_buf(), members }),
+            // Not a ZIP archive after all (e.g. a pak file in another format)
+            Ok(None) => {}
+            Err(e) => {
+                options.check_stop()?;
+                let message = format!("members not compared ({:#})", e);
+                options.warnings.push(Warning::new(WarningKind::SkippedFile, Severity::Info, message).with_path(path));
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Member changes between two versions of an archive, or `None` if either is no archive
+fn compare_archive_members(source: &Path, target: &Path, relative_path: &Path, options: &DiffOptions) -> Result<Option<Vec<DiffType>>> {
+    let (Some(source_archive), Some(target_archive)) = (vfs::open_archive(source)?, vfs::open_archive(target)?) else {
+        return Ok(None);
+    };
+    // Filters apply to the compared trees, not to the content of archives
+    let member_options = DiffOptions::new().threads(options.thread_count()).digest_encoding(options.digest_encoding).hash_algorithm(options.hash_algorithm);
+    let source_files = scan_vfs(&*source_archive, &member_options)?;
+    let target_files = scan_vfs(&*target_archive, &member_options)?;
+    for warning in member_options.warnings.take() {
+        let path = warning.path.as_deref().map_or_else(|| relative_path.to_path_buf(), |member| relative_path.join(member));
+        options.warnings.push(Warning { path: Some(path), ..warning });
+    }
+
+    let mut members: Vec<DiffType> = target_files.iter().filter_map(|(path, info)| match source_files.get(path) {
+        None => Some(DiffType::Added(info.clone())),
+        Some(source_info) if source_info.hash != info.hash => Some(DiffType::Modified(info.clone())),
+        Some(_) => None,
+    }).collect();
+    members.extend(source_files.keys().filter(|path| !target_files.contains_key(*path)).map(|path| DiffType::Removed(path.clone())));
+    members.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(Some(members))
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_errors_in_excluded_directories_are_not_reported() {
+        let dir = TempDir::new();
+        dir.write("kept.txt", b"kept");
+        for parent in [".cache", "build", "visible"] {
+            fs::create_dir(dir.path().join(parent)).unwrap();
+            // Following the link leads back to an ancestor, a walk error
+            std::os::unix::fs::symlink("..", dir.path().join(parent).join("loop")).unwrap();
+        }
+        let options = DiffOptions::new().follow_symlinks(true).exclude_dirs(vec!["build".to_string()]);
+        let report = scan_directory_report(dir.path(), &options).unwrap();
+        assert_eq!(report.files.keys().collect::<Vec<_>>(), [Path::new("kept.txt")]);
+        let errors: Vec<_> = report.errors.iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(errors, [Path::new("visible/loop")]);
+    }
+
+    #[test]
+    fn unreadable_files_are_skipped_when_hashed_lazily() {
+        let dir = TempDir::new();
+        dir.write("kept.txt", b"kept");
+        let mut files: HashMap<PathBuf, FileInfo> = ["kept.txt", "gone.txt"].into_iter()
+            .map(|name| (PathBuf::from(name), FileInfo::new(PathBuf::from(name), String::new(), 4)))
+            .collect();
+        let paths: Vec<PathBuf> = files.keys().cloned().collect();
+        let options = DiffOptions::new();
+        fill_hashes(dir.path(), &mut files, &paths, &options).unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), [Path::new("kept.txt")]);
+        assert!(!files[Path::new("kept.txt")].hash.is_empty());
+        assert_eq!(options.warnings.take()[0].path.as_deref(), Some(Path::new("gone.txt")));
+
+        let options = DiffOptions::new().error_policy(ErrorPolicy::FailFast);
+        assert!(fill_hashes(dir.path(), &mut files.clone(), &[PathBuf::from("gone.txt")], &options).is_err());
+    }
+
+    #[test]
+    fn unreadable_added_files_leave_the_report() {
+        let dir = TempDir::new();
+        dir.write("kept.txt", b"kept");
+        let added = |name: &str| DiffType::Added(FileInfo::new(PathBuf::from(name), String::new(), 4));
+        let mut report = DiffReport::new(vec![added("kept.txt"), added("gone.txt")]);
+        assert_eq!(hash_added_files(&mut report, dir.path(), &DiffOptions::new()).unwrap(), 1);
+        assert!(matches!(&report.diffs[..], [DiffType::Added(info)] if info.relative_path == Path::new("kept.txt") && !info.hash.is_empty()));
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].path.as_deref(), Some(Path::new("gone.txt")));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::diff::{ArchiveDiff, ChangeStats, DiffType, FileInfo};
+use crate::manifest::Manifest;
+use crate::staging::StagingFile;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Version of the cache entries; entries of other versions are ignored
+const CACHE_VERSION: u32 = 2;
+
+/// Result of an earlier comparison apart from its differences, stored under the key of
+/// its inputs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CachedComparison {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub archives: Vec<ArchiveDiff>,
+    pub stats: ChangeStats,
+    /// Source entries of the changed files, see `DiffReport::source_files`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_files: Vec<FileInfo>,
+    /// Files that only differ in build stamps, see `DiffOptions::ignore_build_stamps`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stamp_only: Vec<PathBuf>,
+}
+
+impl CachedComparison {
+    pub fn new() -> Self {
+        CachedComparison { version: CACHE_VERSION, ..CachedComparison::default() }
+    }
+}
+
+/// Key of the comparison of two scanned trees with the options described by `fingerprint`
+///
+/// Made from the manifests of both trees, so any changed file, hash or permission gives
+/// another key, and from the version of this program, whose comparisons may differ.
+pub fn cache_key(source_files: &HashMap<PathBuf, FileInfo>, target_files: &HashMap<PathBuf, FileInfo>, fingerprint: &str) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    for files in [source_files, target_files] {
+        let mut manifest = Manifest::from_files(files);
+        manifest.make_reproducible();
+        let json = serde_json::to_vec(&manifest).context("Failed to serialize manifest")?;
+        hasher.update(Sha256::digest(&json));
+    }
+    hasher.update(fingerprint);
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// File in `cache_dir` holding the comparison stored under `key`
+pub fn entry_path(cache_dir: &Path, key: &str) -> PathBuf {
+    cache_dir.join(format!("{}.jsonl", key))
+}
+
+/// Line of a cache entry: the entry holds one difference per line and then the rest of
+/// the comparison, so neither writing nor replaying it keeps the differences in memory
+#[derive(Serialize, Deserialize)]
+enum EntryLine<'a> {
+    Diff(Cow<'a, DiffType>),
+    Summary(CachedComparison),
+}
+
+/// Cache entry being written while the comparison finds its differences
+pub struct EntryWriter {
+    out: BufWriter<File>,
+    staged: StagingFile,
+}
+
+impl EntryWriter {
+    /// Start the entry of `key`, which replaces any earlier one atomically on `finish`
+    pub fn create(cache_dir: &Path, key: &str) -> Result<Self> {
+        fs::create_dir_all(cache_dir).with_context(|| format!("Failed to create diff cache: {}", cache_dir.display()))?;
+        let staged = StagingFile::new(&entry_path(cache_dir, key))?;
+        let file = File::create(staged.path())
+            .with_context(|| format!("Failed to write temporary file: {}", staged.path().display()))?;
+        Ok(EntryWriter { out: BufWriter::new(file), staged })
+    }
+
+    pub fn push(&mut self, diff: &DiffType) -> Result<()> {
+        self.line(&EntryLine::Diff(Cow::Borrowed(diff)))
+    }
+
+    /// Write the rest of the comparison and store the entry
+    pub fn finish(mut self, summary: CachedComparison) -> Result<()> {
+        self.line(&EntryLine::Summary(summary))?;
+        self.out.flush().context("Failed to write diff cache entry")?;
+        drop(self.out);
+        self.staged.persist()
+    }
+
+    fn line(&mut self, line: &EntryLine) -> Result<()> {
+        serde_json::to_writer(&mut self.out, line).context("Failed to serialize cached comparison")?;
+        self.out.write_all(b"\n").context("Failed to write diff cache entry")
+    }
+}
+
+/// Read the lines of the entry stored under `key`, handing its differences to `on_diff`;
+/// returns the summary line, which must come last
+fn read_entry(cache_dir: &Path, key: &str, on_diff: &mut dyn FnMut(DiffType) -> Result<()>) -> Result<CachedComparison> {
+    let path = entry_path(cache_dir, key);
+    let file = File::open(&path).with_context(|| format!("Failed to read diff cache entry: {}", path.display()))?;
+    let mut summary = None;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read diff cache entry: {}", path.display()))?;
+        match serde_json::from_str(&line).context("Failed to parse diff cache entry")? {
+            EntryLine::Diff(_) if summary.is_some() => return Err(anyhow!("Diff cache entry continues after its end")),
+            EntryLine::Diff(diff) => on_diff(diff.into_owned())?,
+            EntryLine::Summary(entry) => summary = Some(entry),
+        }
+    }
+    let summary = summary.ok_or_else(|| anyhow!("Diff cache entry is incomplete"))?;
+    if summary.version != CACHE_VERSION {
+        return Err(anyhow!("Diff cache entry is of version {}", summary.version));
+    }
+    Ok(summary)
+}
+
+/// Comparison stored under `key` without its differences, if there is a complete and
+/// readable one; `replay` then hands out the differences
+///
+/// The whole entry is checked, one line at a time. Entries that cannot be read or parsed,
+/// e.g. from another version, count as missing.
+pub fn load(cache_dir: &Path, key: &str) -> Option<CachedComparison> {
+    read_entry(cache_dir, key, &mut |_| Ok(())).ok()
+}
+
+/// Hand the differences stored under `key` to `on_diff`, in the order they were found
+pub fn replay(cache_dir: &Path, key: &str, on_diff: &mut dyn FnMut(DiffType) -> Result<()>) -> Result<()> {
+    read_entry(cache_dir, key, on_diff).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{self, DiffOptions};
+    use crate::testutil::TempDir;
+
+    fn write_entry(cache_dir: &Path, diffs: &[DiffType]) {
+        let mut writer = EntryWriter::create(cache_dir, "key").unwrap();
+        for diff in diffs {
+            writer.push(diff).unwrap();
+        }
+        writer.finish(CachedComparison { stamp_only: vec![PathBuf::from("stamped")], ..CachedComparison::new() }).unwrap();
+    }
+
+    fn replayed(cache_dir: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        replay(cache_dir, "key", &mut |diff| {
+            paths.push(diff.path().to_path_buf());
+            Ok(())
+        }).unwrap();
+        paths
+    }
+
+    #[test]
+    fn entries_replay_in_order() {
+        let dir = TempDir::new();
+        let diffs = [DiffType::Removed(PathBuf::from("b")), DiffType::Removed(PathBuf::from("a"))];
+        write_entry(dir.path(), &diffs);
+        assert_eq!(load(dir.path(), "key").unwrap().stamp_only, [PathBuf::from("stamped")]);
+        assert_eq!(replayed(dir.path()), [PathBuf::from("b"), PathBuf::from("a")]);
+    }
+
+    #[test]
+    fn incomplete_entries_count_as_missing() {
+        let dir = TempDir::new();
+        assert!(load(dir.path(), "key").is_none());
+        write_entry(dir.path(), &[DiffType::Removed(PathBuf::from("a"))]);
+        let path = entry_path(dir.path(), "key");
+        let data = fs::read_to_string(&path).unwrap();
+        let (first_line, _) = data.split_once('\n').unwrap();
+        fs::write(&path, format!("{}\n", first_line)).unwrap();
+        assert!(load(dir.path(), "key").is_none());
+        fs::write(&path, format!("{}{}", data, first_line)).unwrap();
+        assert!(load(dir.path(), "key").is_none());
+        fs::write(&path, "{}").unwrap();
+        assert!(load(dir.path(), "key").is_none());
+    }
+
+    #[test]
+    fn streamed_comparison_is_replayed_from_the_cache() {
+        let (source, target, cache) = (TempDir::new(), TempDir::new(), TempDir::new());
+        source.write("same.txt", b"same");
+        source.write("old.txt", b"old");
+        target.write("same.txt", b"same");
+        target.write("new.txt", b"new");
+        let options = DiffOptions::new().diff_cache(cache.path().to_path_buf());
+        let compare = || {
+            let mut paths = Vec::new();
+            diff::compare_directories_streaming(source.path(), target.path(), &options, |diff| {
+                paths.push(diff.path().to_path_buf());
+                Ok(())
+            }).unwrap();
+            paths.sort();
+            paths
+        };
+        let first = compare();
+        assert_eq!(first, [PathBuf::from("new.txt"), PathBuf::from("old.txt")]);
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 1);
+        assert_eq!(compare(), first);
+    }
+}
@@ -0,0 +1,393 @@
+use crate::diff::HashAlgorithm;
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Length of a SHA-256 digest in bytes
+pub const DIGEST_LEN: usize = 32;
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// How content hashes are written in manifests, reports and patches
+///
+/// Hex SHA-256 hashes are written bare, as they always were. The other encodings carry
+/// their name in a prefix (`sha256:base64:...`), so a manifest says how to read its
+/// hashes; hashes made with another `HashAlgorithm` start with its name (`blake3:...`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DigestEncoding {
+    /// Lowercase hex, e.g. `2cf24d...`
+    #[default]
+    Hex,
+    /// Standard base64 with padding (RFC 4648), as in SRI hashes
+    Base64,
+    /// Base32 with padding (RFC 4648)
+    Base32,
+}
+
+impl DigestEncoding {
+    pub const ALL: [DigestEncoding; 3] = [DigestEncoding::Hex, DigestEncoding::Base64, DigestEncoding::Base32];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DigestEncoding::Hex => "hex",
+            DigestEncoding::Base64 => "base64",
+            DigestEncoding::Base32 => "base32",
+        }
+    }
+
+    /// Text form of a SHA-256 digest in this encoding
+    pub fn encode(&self, digest: &[u8]) -> String {
+        self.encode_as(HashAlgorithm::Sha256, digest)
+    }
+
+    /// Text form of a digest made with `algorithm` in this encoding
+    pub fn encode_as(&self, algorithm: HashAlgorithm, digest: &[u8]) -> String {
+        let text = match self {
+            DigestEncoding::Hex => encode_hex(digest),
+            DigestEncoding::Base64 => encode_base64(digest),
+            DigestEncoding::Base32 => encode_base32(digest),
+        };
+        format!("{}{}", self.prefix(algorithm), text)
+    }
+
+    /// What `encode_as` writes before the digest
+    fn prefix(&self, algorithm: HashAlgorithm) -> String {
+        match (algorithm, self) {
+            (HashAlgorithm::Sha256, DigestEncoding::Hex) => String::new(),
+            (_, DigestEncoding::Hex) => format!("{}:", algorithm.name()),
+            _ => format!("{}:{}:", algorithm.name(), self.name()),
+        }
+    }
+
+    /// Write `hash` (in any form `decode` reads) in this encoding, keeping its algorithm;
+    /// empty hashes stay empty
+    pub fn reencode(&self, hash: &str) -> Result<String> {
+        if hash.is_empty() || encoding_of(hash) == Some(*self) {
+            return Ok(hash.to_string());
+        }
+        let (algorithm, digest) = decode_any(hash)?;
+        Ok(self.encode_as(algorithm, &digest))
+    }
+}
+
+impl fmt::Display for DigestEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DigestEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        DigestEncoding::ALL.into_iter().find(|encoding| encoding.name() == name.trim()).ok_or_else(|| {
+            let names: Vec<&str> = DigestEncoding::ALL.iter().map(|encoding| encoding.name()).collect();
+            anyhow!("Unknown digest encoding: {} (expected one of {})", name, names.join(", "))
+        })
+    }
+}
+
+/// Encoding `hash` is written in, if it is in the form `DigestEncoding::encode_as` writes
+pub fn encoding_of(hash: &str) -> Option<DigestEncoding> {
+    let algorithm = algorithm_of(hash);
+    // Hex last, as its prefix starts the others
+    [DigestEncoding::Base64, DigestEncoding::Base32, DigestEncoding::Hex].into_iter().find(|encoding| {
+        hash.strip_prefix(encoding.prefix(algorithm).as_str()).is_some_and(|text| {
+            *encoding != DigestEncoding::Hex
+                || (text.len() == algorithm.digest_len() * 2 && text.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')))
+        })
+    })
+}
+
+/// Algorithm `hash` was made with: the one named in its prefix, SHA-256 if there is none
+pub fn algorithm_of(hash: &str) -> HashAlgorithm {
+    HashAlgorithm::ALL
+        .into_iter()
+        .find(|algorithm| hash.strip_prefix(algorithm.name()).is_some_and(|rest| rest.starts_with(':')))
+        .unwrap_or_default()
+}
+
+/// Raw SHA-256 digest of a hash written in any supported form
+///
+/// Accepts bare hex, `sha256:<hex>` (OCI), `sha256:<encoding>:<digest>` for every
+/// `DigestEncoding`, and `sha256-<base64>` (SRI).
+pub fn decode(hash: &str) -> Result<Vec<u8>> {
+    match decode_any(hash)? {
+        (HashAlgorithm::Sha256, digest) => Ok(digest),
+        (algorithm, _) => Err(anyhow!("Expected a SHA-256 digest, found a {} one: {}", algorithm, hash)),
+    }
+}
+
+/// Algorithm and raw digest of a hash made with any `HashAlgorithm`, in any form `decode`
+/// reads (with the name of the algorithm in place of `sha256`)
+pub fn decode_any(hash: &str) -> Result<(HashAlgorithm, Vec<u8>)> {
+    let algorithm = algorithm_of(hash);
+    let invalid = || anyhow!("Invalid {} digest: {}", algorithm, hash);
+    let prefixed = hash.strip_prefix(algorithm.name()).and_then(|rest| rest.strip_prefix(':'));
+    let (encoding, text) = if let Some(rest) = prefixed {
+        match rest.split_once(':') {
+            Some((name, text)) => (name.parse::<DigestEncoding>()?, text),
+            None => (DigestEncoding::Hex, rest),
+        }
+    } else if let Some(text) = hash.strip_prefix("sha256-") {
+        (DigestEncoding::Base64, text)
+    } else {
+        (DigestEncoding::Hex, hash)
+    };
+    let digest = match encoding {
+        DigestEncoding::Hex => decode_hex(text),
+        DigestEncoding::Base64 => decode_base(text, BASE64_ALPHABET, 6),
+        DigestEncoding::Base32 => decode_base(&text.to_ascii_uppercase(), BASE32_ALPHABET, 5),
+    }
+    .ok_or_else(invalid)?;
+    if digest.len() != algorithm.digest_len() {
+        return Err(invalid());
+    }
+    Ok((algorithm, digest))
+}
+
+/// Whether two hashes name the same digest, whatever their encodings; hashes made with
+/// different algorithms never do
+pub fn same_digest(a: &str, b: &str) -> bool {
+    a == b || matches!((decode_any(a), decode_any(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Digest characters `HashAbbreviations` shows at least
+pub const ABBREV_LEN: usize = 12;
+
+/// Shortest unambiguous forms of a set of hashes, for output read by people
+///
+/// As in git, every hash is cut to `ABBREV_LEN` characters of its digest, or to as many
+/// more as it takes to tell it from every other hash of the set. The prefix naming the
+/// algorithm and encoding (`blake3:`, `sha256:base64:`) is kept. Machine-readable
+/// output (JSON, CSV, manifests) should always carry the full hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashAbbreviations {
+    lengths: HashMap<String, usize>,
+}
+
+impl HashAbbreviations {
+    pub fn new<'a>(hashes: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut sorted: Vec<&str> = hashes.into_iter().filter(|hash| !hash.is_empty()).collect();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let common = |a: &str, b: &str| a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+        let lengths = sorted.iter().enumerate().map(|(index, hash)| {
+            // Only the neighbours in sorted order can share a longer prefix
+            let before = index.checked_sub(1).map_or(0, |prev| common(sorted[prev], hash));
+            let after = sorted.get(index + 1).map_or(0, |next| common(next, hash));
+            let length = (digest_start(hash) + ABBREV_LEN).max(before.max(after) + 1).min(hash.len());
+            (hash.to_string(), length)
+        }).collect();
+        HashAbbreviations { lengths }
+    }
+
+    /// `hash` cut to its unambiguous length; hashes outside the set get `ABBREV_LEN`
+    /// digest characters
+    pub fn abbreviate<'a>(&self, hash: &'a str) -> &'a str {
+        let length = self.lengths.get(hash).copied().unwrap_or_else(|| digest_start(hash) + ABBREV_LEN);
+        hash.get(..length).unwrap_or(hash)
+    }
+
+    /// Hash of the set that `prefix` (e.g. one printed by `abbreviate`) starts; fails,
+    /// as git does, if no hash or several hashes start with it
+    pub fn resolve(&self, prefix: &str) -> Result<&str> {
+        let matches: Vec<&str> = self.lengths.keys().map(String::as_str).filter(|hash| hash.starts_with(prefix)).collect();
+        match matches[..] {
+            [hash] => Ok(hash),
+            [] => Err(anyhow!("No hash starts with {}", prefix)),
+            _ => Err(anyhow!("Ambiguous hash prefix {}: {} hashes start with it", prefix, matches.len())),
+        }
+    }
+}
+
+/// Length of the `algorithm:encoding:` prefix of a hash
+fn digest_start(hash: &str) -> usize {
+    hash.rfind(':').map_or(0, |colon| colon + 1)
+}
+
+/// HMAC-SHA256 (RFC 2104) of `message` with `key`, e.g. to sign a record
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; DIGEST_LEN] {
+    const BLOCK_LEN: usize = 64;
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..DIGEST_LEN].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha256::new().chain_update(block.map(|b| b ^ 0x36)).chain_update(message).finalize();
+    Sha256::new().chain_update(block.map(|b| b ^ 0x5c)).chain_update(inner).finalize().into()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of the hex string `text` (either case)
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    encode_base(bytes, BASE64_ALPHABET, 6, 4)
+}
+
+fn encode_base32(bytes: &[u8]) -> String {
+    encode_base(bytes, BASE32_ALPHABET, 5, 8)
+}
+
+/// RFC 4648 encoding with `bits` bits per character, padded to a multiple of `group` characters
+fn encode_base(bytes: &[u8], alphabet: &[u8], bits: u32, group: usize) -> String {
+    let mask = (1u32 << bits) - 1;
+    let mut text = String::new();
+    let mut buffer = 0u32;
+    let mut buffered = 0u32;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        buffered += 8;
+        while buffered >= bits {
+            buffered -= bits;
+            text.push(alphabet[((buffer >> buffered) & mask) as usize] as char);
+        }
+        buffer &= (1 << buffered) - 1;
+    }
+    if buffered > 0 {
+        text.push(alphabet[((buffer << (bits - buffered)) & mask) as usize] as char);
+    }
+    while !text.len().is_multiple_of(group) {
+        text.push('=');
+    }
+    text
+}
+
+/// Reverse of `encode_base`; padding is optional
+fn decode_base(text: &str, alphabet: &[u8], bits: u32) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut buffered = 0u32;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = alphabet.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << bits) | value;
+        buffered += bits;
+        if buffered >= 8 {
+            buffered -= 8;
+            bytes.push((buffer >> buffered) as u8);
+            buffer &= (1 << buffered) - 1;
+        }
+    }
+    // Left-over bits are padding and must be zero
+    (buffer == 0).then_some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of `abc`
+    const ABC_HEX: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const ABC_BASE64: &str = "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=";
+    const ABC_BASE32: &str = "XJ4BNP4PAHH6UQKBIDPF3LRCEOYAGYNDSYLXVHFUCD7WD4QACWWQ====";
+
+    #[test]
+    fn encodings_match_rfc_4648() {
+        let digest = decode_hex(ABC_HEX).unwrap();
+        assert_eq!(DigestEncoding::Hex.encode(&digest), ABC_HEX);
+        assert_eq!(DigestEncoding::Base64.encode(&digest), format!("sha256:base64:{}", ABC_BASE64));
+        assert_eq!(DigestEncoding::Base32.encode(&digest), format!("sha256:base32:{}", ABC_BASE32));
+        assert_eq!(DigestEncoding::Hex.encode_as(HashAlgorithm::Blake3, &digest), format!("blake3:{}", ABC_HEX));
+    }
+
+    #[test]
+    fn every_written_form_decodes() {
+        let digest = decode_hex(ABC_HEX).unwrap();
+        for hash in [
+            ABC_HEX.to_string(),
+            ABC_HEX.to_ascii_uppercase(),
+            format!("sha256:{}", ABC_HEX),
+            format!("sha256:base64:{}", ABC_BASE64),
+            format!("sha256:base32:{}", ABC_BASE32.to_ascii_lowercase()),
+            format!("sha256:base32:{}", ABC_BASE32.trim_end_matches('=')),
+            format!("sha256-{}", ABC_BASE64),
+        ] {
+            assert_eq!(decode(&hash).unwrap(), digest, "{}", hash);
+        }
+    }
+
+    #[test]
+    fn encoding_and_algorithm_are_read_from_the_prefix() {
+        assert_eq!(encoding_of(ABC_HEX), Some(DigestEncoding::Hex));
+        assert_eq!(encoding_of(&format!("sha256:base64:{}", ABC_BASE64)), Some(DigestEncoding::Base64));
+        assert_eq!(encoding_of("not a hash"), None);
+        assert_eq!(algorithm_of(&format!("xxh3:{}", &ABC_HEX[..16])), HashAlgorithm::Xxh3);
+        assert_eq!(algorithm_of(ABC_HEX), HashAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn reencode_keeps_the_digest() {
+        let base32 = DigestEncoding::Base32.reencode(ABC_HEX).unwrap();
+        assert_eq!(base32, format!("sha256:base32:{}", ABC_BASE32));
+        assert_eq!(DigestEncoding::Hex.reencode(&base32).unwrap(), ABC_HEX);
+        assert!(same_digest(ABC_HEX, &base32));
+        assert_eq!(DigestEncoding::Base64.reencode("").unwrap(), "");
+        let blake3 = format!("blake3:{}", ABC_HEX);
+        assert!(!same_digest(ABC_HEX, &blake3));
+        assert_eq!(DigestEncoding::Hex.reencode(&DigestEncoding::Base64.reencode(&blake3).unwrap()).unwrap(), blake3);
+    }
+
+    #[test]
+    fn malformed_digests_are_rejected() {
+        for hash in [&ABC_HEX[1..], "zz", "sha256:base99:AAAA", "sha256:base64:!!!!", &format!("blake3:{}", &ABC_HEX[..16])] {
+            assert!(decode_any(hash).is_err(), "{}", hash);
+        }
+        assert!(decode(&format!("blake3:{}", ABC_HEX)).is_err());
+        assert!("base58".parse::<DigestEncoding>().is_err());
+        assert_eq!(" base32".parse::<DigestEncoding>().unwrap(), DigestEncoding::Base32);
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231_style_vectors() {
+        let hex = |bytes: [u8; DIGEST_LEN]| encode_hex(&bytes);
+        assert_eq!(
+            hex(hmac_sha256(b"key", b"The quick brown fox jumps over the lazy dog")),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        // Keys longer than a block are hashed first
+        assert_eq!(hex(hmac_sha256(&[b'k'; 100], b"msg")), "bd56a1782c2830e8abc6ed866a57a1230661e650b84c62f7ee3accc5fa5af491");
+    }
+
+    #[test]
+    fn abbreviations_are_unambiguous_and_resolve_back() {
+        let a = format!("{}0000", &ABC_HEX[..60]);
+        let b = format!("{}1111", &ABC_HEX[..60]);
+        let other = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let blake3 = format!("blake3:{}", other);
+        let abbreviations = HashAbbreviations::new([a.as_str(), b.as_str(), other, blake3.as_str(), ""]);
+
+        assert_eq!(abbreviations.abbreviate(other), &other[..ABBREV_LEN]);
+        assert_eq!(abbreviations.abbreviate(&blake3), &blake3[..7 + ABBREV_LEN]);
+        // Hashes sharing 60 characters are cut one past the shared prefix
+        assert_eq!(abbreviations.abbreviate(&a).len(), 61);
+        for hash in [&a, &b, other, &blake3] {
+            assert_eq!(abbreviations.resolve(abbreviations.abbreviate(hash)).unwrap(), hash);
+        }
+        // Hashes outside the set get the default length
+        assert_eq!(HashAbbreviations::default().abbreviate(ABC_HEX), &ABC_HEX[..ABBREV_LEN]);
+    }
+
+    #[test]
+    fn ambiguous_and_unknown_prefixes_are_errors() {
+        let a = format!("{}0000", &ABC_HEX[..60]);
+        let b = format!("{}1111", &ABC_HEX[..60]);
+        let abbreviations = HashAbbreviations::new([a.as_str(), b.as_str()]);
+        let error = abbreviations.resolve(&ABC_HEX[..ABBREV_LEN]).unwrap_err().to_string();
+        assert!(error.contains("Ambiguous hash prefix"), "{}", error);
+        assert!(abbreviations.resolve("ffff").is_err());
+        assert_eq!(abbreviations.resolve(&a).unwrap(), a);
+    }
+}
@@ -0,0 +1,379 @@
+use crate::paths;
+use crate::glob::Pattern;
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Whether a matching rule keeps or drops a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    Include,
+    Exclude,
+}
+
+impl fmt::Display for RuleKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RuleKind::Include => "+",
+            RuleKind::Exclude => "-",
+        })
+    }
+}
+
+/// One include/exclude pattern, interpreted like an rsync filter rule
+///
+/// - A leading `/` anchors the pattern to the root of the scanned tree.
+/// - A trailing `/` only matches directories (and so everything below them).
+/// - A trailing `/***` matches the directory itself and everything below it.
+/// - A pattern without `/` or `**` matches the last path component; otherwise it matches
+///   the full path, or any trailing part of it if not anchored.
+#[derive(Debug, Clone)]
+pub struct FilterRule {
+    pub kind: RuleKind,
+    text: String,
+    origin: Option<String>,
+    pattern: Pattern,
+    anchored: bool,
+    dir_only: bool,
+    with_contents: bool,
+    match_full_path: bool,
+}
+
+impl FilterRule {
+    pub fn new(kind: RuleKind, pattern: &str) -> Self {
+        let text = pattern.to_string();
+        let anchored = pattern.starts_with('/');
+        let (pattern, with_contents) = match pattern.strip_suffix("/***") {
+            Some(dir) => (dir, true),
+            None => (pattern, false),
+        };
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+        let compiled = Pattern::new(pattern);
+        FilterRule {
+            kind,
+            text,
+            origin: None,
+            match_full_path: anchored || with_contents || pattern.contains('/') || compiled.is_recursive(),
+            pattern: compiled,
+            anchored,
+            dir_only,
+            with_contents,
+        }
+    }
+
+    /// Parse a line of a `.gitignore` file; `None` for blank lines and comments
+    ///
+    /// A leading `!` makes an include rule. A pattern with a `/` anywhere but at its end
+    /// is anchored to the root of the scanned tree, otherwise it matches a name at any
+    /// depth; `**/` matches any number of directories, including none. A leading `\`
+    /// escapes `!` or `#`, and trailing spaces are dropped unless escaped.
+    pub fn gitignore(line: &str) -> Option<Self> {
+        let mut line = line.trim_end_matches('\r');
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (kind, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (RuleKind::Include, pattern),
+            None => (RuleKind::Exclude, line),
+        };
+        let pattern = pattern.strip_prefix('\\').filter(|p| p.starts_with(['!', '#'])).unwrap_or(pattern);
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+        let anchored = pattern.contains('/');
+        Some(FilterRule {
+            kind,
+            text: line.to_string(),
+            origin: None,
+            pattern: Pattern::gitignore(pattern.trim_start_matches('/')),
+            anchored,
+            dir_only,
+            with_contents: false,
+            match_full_path: anchored,
+        })
+    }
+
+    /// Parse an rsync-style rule: `+ PATTERN` includes, `- PATTERN` excludes
+    ///
+    /// The long forms `include PATTERN` and `exclude PATTERN` are accepted too.
+    pub fn parse(rule: &str) -> Result<Self> {
+        let (kind, pattern) = rule
+            .split_once(' ')
+            .and_then(|(prefix, pattern)| match prefix {
+                "+" | "include" => Some((RuleKind::Include, pattern)),
+                "-" | "exclude" => Some((RuleKind::Exclude, pattern)),
+                _ => None,
+            })
+            .filter(|(_, pattern)| !pattern.is_empty())
+            .ok_or_else(|| anyhow!("Invalid filter rule: {} (expected \"+ PATTERN\" or \"- PATTERN\")", rule))?;
+        Ok(FilterRule::new(kind, pattern))
+    }
+
+    /// Record where the rule was defined (e.g. `filters.txt:3`), shown by `explain`
+    pub fn with_origin(mut self, origin: impl Into<String>) -> Self {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_deref()
+    }
+
+    /// Whether the rule matches a file (relative path) or one of its parent directories
+    pub fn matches_path(&self, relative_path: &Path) -> bool {
+        let path = paths::to_portable(relative_path);
+        let dirs = path.match_indices('/').map(|(i, _)| (&path[..i], true));
+        dirs.chain(std::iter::once((path.as_str(), false))).any(|(prefix, is_dir)| self.matches(prefix, is_dir))
+    }
+
+    /// Match a `/`-separated relative path
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.with_contents {
+            // The directory itself or any of its parents
+            let parents = path.match_indices('/').map(|(i, _)| &path[..i]);
+            return std::iter::once(path).chain(parents).any(|prefix| self.matches_pattern(prefix));
+        }
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        self.matches_pattern(path)
+    }
+
+    fn matches_pattern(&self, path: &str) -> bool {
+        if !self.match_full_path {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            return self.pattern.matches(name);
+        }
+        if self.anchored {
+            return self.pattern.matches(path);
+        }
+        // Unanchored: the full path or any part of it starting at a component boundary
+        std::iter::once(path)
+            .chain(path.match_indices('/').map(|(i, _)| &path[i + 1..]))
+            .any(|suffix| self.pattern.matches(suffix))
+    }
+}
+
+impl fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.text.starts_with('!') {
+            // A gitignore negation already says what it does
+            return f.write_str(&self.text);
+        }
+        write!(f, "{} {}", self.kind, self.text)
+    }
+}
+
+/// Why a path is kept or filtered out, as found by `FilterList::explain`
+#[derive(Debug, Clone)]
+pub struct FilterDecision {
+    pub excluded: bool,
+    /// Rule that decided, `None` if no rule matched (paths are kept by default)
+    pub rule: Option<FilterRule>,
+    /// Path the rule matched: the file itself or one of its parent directories
+    pub matched: String,
+}
+
+impl fmt::Display for FilterDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = crate::tr!(if self.excluded { "explain.excluded" } else { "explain.included" });
+        match &self.rule {
+            Some(rule) => {
+                write!(f, "{}", crate::tr!("explain.by_rule", verdict = verdict, rule = rule))?;
+                if let Some(origin) = rule.origin() {
+                    write!(f, " ({})", origin)?;
+                }
+                write!(f, "{}", crate::tr!("explain.matching", path = self.matched))
+            }
+            None => write!(f, "{}", crate::tr!("explain.no_rule", verdict = verdict)),
+        }
+    }
+}
+
+/// Ordered list of filter rules; the first matching rule decides
+#[derive(Debug, Clone, Default)]
+pub struct FilterList {
+    rules: Vec<FilterRule>,
+}
+
+impl FilterList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn push(&mut self, rule: FilterRule) {
+        self.rules.push(rule);
+    }
+
+    /// Add one pattern of `kind` per line of `path`
+    ///
+    /// Empty lines and lines starting with `#` or `;` are ignored, as in rsync's
+    /// `--exclude-from`/`--include-from`.
+    pub fn add_from_file(&mut self, kind: RuleKind, path: &Path) -> Result<()> {
+        for (number, line) in read_rule_lines(path)? {
+            self.push(FilterRule::new(kind, &line).with_origin(format!("{}:{}", path.display(), number)));
+        }
+        Ok(())
+    }
+
+    /// Add the `+ PATTERN`/`- PATTERN` rules of `path`, one per line, in order
+    pub fn add_rules_from_file(&mut self, path: &Path) -> Result<()> {
+        for (number, line) in read_rule_lines(path)? {
+            let origin = format!("{}:{}", path.display(), number);
+            let rule = FilterRule::parse(&line).with_context(|| format!("In {}", origin))?;
+            self.push(rule.with_origin(origin));
+        }
+        Ok(())
+    }
+
+    /// Add rules in `.gitignore` syntax (see `FilterRule::gitignore`) with their origins
+    ///
+    /// Unlike the other rules, the last matching gitignore line decides, so `*.log`
+    /// followed by `!keep.log` keeps `keep.log`. The lines are added as one block after
+    /// the rules already in the list. As in git, a file in an excluded directory cannot
+    /// be included again.
+    pub fn add_gitignore(&mut self, lines: impl IntoIterator<Item = (String, String)>) {
+        let rules: Vec<FilterRule> = lines
+            .into_iter()
+            .filter_map(|(origin, line)| FilterRule::gitignore(&line).map(|rule| rule.with_origin(origin)))
+            .collect();
+        // Checking the block backwards makes the first match the last line that matches
+        self.rules.extend(rules.into_iter().rev());
+    }
+
+    /// Read the lines of a `.gitignore`-style file for `add_gitignore`
+    pub fn read_gitignore_file(path: &Path) -> Result<Vec<(String, String)>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read pattern file: {}", path.display()))?;
+        Ok(content
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (format!("{}:{}", path.display(), index + 1), line.to_string()))
+            .collect())
+    }
+
+    /// Whether a file (relative path) is filtered out
+    ///
+    /// Like rsync, the rules are checked against every parent directory first: a file
+    /// inside an excluded directory is excluded even if an include rule matches it.
+    pub fn is_excluded(&self, relative_path: &Path) -> bool {
+        !self.rules.is_empty() && self.decide(relative_path, false).0
+    }
+
+    /// Whether a directory (relative path) is filtered out, and with it everything below it
+    pub fn is_dir_excluded(&self, relative_path: &Path) -> bool {
+        !self.rules.is_empty() && self.decide(relative_path, true).0
+    }
+
+    /// Which rule keeps or filters out a file (relative path), for debugging filters
+    pub fn explain(&self, relative_path: &Path) -> FilterDecision {
+        let (excluded, matched) = self.decide(relative_path, false);
+        let (rule, matched) = match matched {
+            Some((rule, path)) => (Some(rule.clone()), path.to_string()),
+            None => (None, String::new()),
+        };
+        FilterDecision { excluded, rule, matched }
+    }
+
+    /// Whether the file, or the directory if `is_dir`, is excluded, with the deciding rule
+    /// and the path it matched
+    fn decide(&self, relative_path: &Path, is_dir: bool) -> (bool, Option<(&FilterRule, String)>) {
+        let path = paths::to_portable(relative_path);
+        let dirs = path.match_indices('/').map(|(i, _)| (&path[..i], true));
+        let mut decided = None;
+        for (prefix, is_dir) in dirs.chain(std::iter::once((path.as_str(), is_dir))) {
+            if let Some(rule) = self.rules.iter().find(|rule| rule.matches(prefix, is_dir)) {
+                if rule.kind == RuleKind::Exclude {
+                    return (true, Some((rule, prefix.to_string())));
+                }
+                decided = Some((rule, prefix.to_string()));
+            }
+        }
+        (false, decided)
+    }
+}
+
+/// Non-empty, non-comment lines of a rule file with their line numbers
+fn read_rule_lines(path: &Path) -> Result<Vec<(usize, String)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read pattern file: {}", path.display()))?;
+    Ok(content
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .enumerate()
+        .filter(|(_, line)| !(line.trim().is_empty() || line.starts_with('#') || line.starts_with(';')))
+        .map(|(index, line)| (index + 1, line.to_string()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(rules: &[&str]) -> FilterList {
+        let mut list = FilterList::new();
+        for rule in rules {
+            list.push(FilterRule::parse(rule).unwrap());
+        }
+        list
+    }
+
+    #[test]
+    fn directory_rules_exclude_directories_and_their_files() {
+        let list = filters(&["+ keep/", "- build/", "- *.log"]);
+        assert!(list.is_dir_excluded(Path::new("build")));
+        assert!(list.is_dir_excluded(Path::new("src/build")));
+        assert!(list.is_excluded(Path::new("build/out.txt")));
+        // A file named like an excluded directory is kept
+        assert!(!list.is_excluded(Path::new("build")));
+        assert!(!list.is_dir_excluded(Path::new("keep")));
+        assert!(list.is_dir_excluded(Path::new("keep/build")) && list.is_excluded(Path::new("keep/build/a.txt")));
+        // File rules leave directories to be entered
+        assert!(!list.is_dir_excluded(Path::new("src")));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let list = filters(&["+ important.log", "- *.log"]);
+        assert!(!list.is_excluded(Path::new("logs/important.log")));
+        assert!(list.is_excluded(Path::new("logs/other.log")));
+        assert!(!list.is_excluded(Path::new("logs/other.txt")));
+        assert!(!FilterList::new().is_excluded(Path::new("anything")));
+    }
+
+    #[test]
+    fn anchored_and_full_path_rules() {
+        let list = filters(&["- /top.txt", "- docs/*.md", "- cache/***"]);
+        assert!(list.is_excluded(Path::new("top.txt")));
+        assert!(!list.is_excluded(Path::new("sub/top.txt")));
+        assert!(list.is_excluded(Path::new("docs/a.md")));
+        assert!(list.is_excluded(Path::new("project/docs/a.md")));
+        assert!(!list.is_excluded(Path::new("docs/sub/a.md")));
+        assert!(list.is_excluded(Path::new("cache")));
+        assert!(list.is_excluded(Path::new("cache/deep/file")));
+    }
+
+    #[test]
+    fn gitignore_lines() {
+        let mut list = FilterList::new();
+        list.add_gitignore([(".gitignore:1", "*.tmp"), (".gitignore:2", "!keep.tmp"), (".gitignore:3", "# comment")]
+            .map(|(origin, line)| (origin.to_string(), line.to_string())));
+        assert!(list.is_excluded(Path::new("a.tmp")));
+        assert!(!list.is_excluded(Path::new("keep.tmp")));
+        assert!(!list.is_excluded(Path::new("# comment")));
+        assert!(FilterRule::parse("exclude *.o").is_ok());
+        assert!(FilterRule::parse("*.o").is_err());
+        assert!(FilterRule::parse("- ").is_err());
+    }
+}
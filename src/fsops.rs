@@ -0,0 +1,664 @@
+use crate::i18n::format_number;
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// How a file was placed into a mirrored tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    HardLink, // Shares storage with the original
+    Reflink,  // Copy-on-write clone, shares blocks until either side is written
+    Copy,     // Independent copy (neither links nor clones supported here)
+}
+
+/// Number of files placed into a mirrored tree, per placement kind
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MirrorStats {
+    pub linked: usize,
+    pub cloned: usize,
+    pub copied: usize,
+}
+
+/// Clone `src` to `dst` with the FICLONE ioctl (btrfs, XFS, ...)
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src_file = fs::File::open(src)?;
+    let dst_file = fs::File::create(dst)?;
+    // SAFETY: both descriptors are open for the duration of the call
+    let result = unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONE as _, src_file.as_raw_fd()) };
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        drop(dst_file);
+        let _ = fs::remove_file(dst);
+        return Err(err);
+    }
+    dst_file.set_permissions(src_file.metadata()?.permissions())
+}
+
+/// Clone `src` to `dst` with clonefile(2) (APFS)
+#[cfg(target_os = "macos")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dst = CString::new(dst.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings that outlive the call
+    if unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reflinks not supported"))
+}
+
+/// Copy `src` to `dst` as a copy-on-write reflink where the file system supports it,
+/// falling back to a regular copy
+///
+/// `dst` is replaced if it exists. The fallback uses `fs::copy`, which on Linux goes
+/// through `copy_file_range` and may still share blocks on some file systems.
+pub fn clone_or_copy(src: &Path, dst: &Path) -> io::Result<Placement> {
+    if fs::symlink_metadata(dst).is_ok() {
+        fs::remove_file(dst)?;
+    }
+    match reflink(src, dst) {
+        Ok(()) => Ok(Placement::Reflink),
+        Err(_) => fs::copy(src, dst).map(|_| Placement::Copy),
+    }
+}
+
+/// Hard-link `src` to `dst`, falling back to a reflink or regular copy
+pub fn link_or_copy(src: &Path, dst: &Path) -> io::Result<Placement> {
+    match fs::hard_link(src, dst) {
+        Ok(()) => Ok(Placement::HardLink),
+        Err(_) => clone_or_copy(src, dst),
+    }
+}
+
+/// Tells whether two files hold the same data without reading them, remembering which
+/// devices share a file system for the comparison it is made for
+#[derive(Debug, Default)]
+pub struct CloneDetector {
+    same_volume: std::sync::Mutex<std::collections::HashMap<(u64, u64), bool>>,
+}
+
+impl CloneDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `a` and `b` certainly hold the same data: hard links to one file, or
+    /// reflinked copies whose data is the very same shared extents on disk (btrfs, XFS,
+    /// bcachefs; Linux only)
+    ///
+    /// `false` means "cannot tell", not "different": regular copies, file systems
+    /// without extent maps (ZFS, tmpfs) and extents the check cannot vouch for, such as
+    /// compressed, inline or not yet allocated ones, all need hashing.
+    pub fn same_data(&self, a: &Path, b: &Path) -> bool {
+        let (Ok(meta_a), Ok(meta_b)) = (fs::metadata(a), fs::metadata(b)) else {
+            return false;
+        };
+        if !meta_a.is_file() || !meta_b.is_file() || meta_a.len() != meta_b.len() {
+            return false;
+        }
+        same_file(&meta_a, &meta_b) || self.shared_extents(a, b, &meta_a, &meta_b)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn shared_extents(&self, a: &Path, b: &Path, meta_a: &fs::Metadata, meta_b: &fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        if meta_a.len() == 0 {
+            return false;
+        }
+        // Physical offsets only mean the same thing within one file system; btrfs gives
+        // every subvolume and snapshot a device number of its own
+        if meta_a.dev() != meta_b.dev() {
+            let mut volumes = self.same_volume.lock().unwrap_or_else(|e| e.into_inner());
+            let same = *volumes.entry((meta_a.dev(), meta_b.dev())).or_insert_with(|| {
+                matches!((mount_source(a), mount_source(b)), (Some(x), Some(y)) if x == y)
+            });
+            if !same {
+                return false;
+            }
+        }
+        match (shared_extent_map(a), shared_extent_map(b)) {
+            (Ok(Some(x)), Ok(Some(y))) => !x.is_empty() && x == y,
+            _ => false,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn shared_extents(&self, _a: &Path, _b: &Path, _meta_a: &fs::Metadata, _meta_b: &fs::Metadata) -> bool {
+        false
+    }
+}
+
+/// Whether two metadata entries describe the same file, e.g. two hard links
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+#[cfg(target_os = "linux")]
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+#[cfg(target_os = "linux")]
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+#[cfg(target_os = "linux")]
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+/// Unknown location, delayed allocation, compressed, encrypted, unaligned, inline, tail
+/// packed or unwritten: the physical offset says nothing certain about the data
+#[cfg(target_os = "linux")]
+const FIEMAP_EXTENT_UNTRUSTED: u32 = 0x2 | 0x4 | 0x8 | 0x80 | 0x100 | 0x200 | 0x400 | 0x800;
+#[cfg(target_os = "linux")]
+const FIEMAP_BATCH: usize = 64;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+/// Extents of `path` as (logical offset, physical offset, length), or `None` if any of
+/// them is not shared or cannot be trusted
+#[cfg(target_os = "linux")]
+fn shared_extent_map(path: &Path) -> io::Result<Option<Vec<(u64, u64, u64)>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = fs::File::open(path)?;
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: FIEMAP_BATCH as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); FIEMAP_BATCH],
+        };
+        // SAFETY: `map` is a fiemap header followed by room for `extent_count` extents,
+        // and the descriptor is open for the duration of the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mapped = &map.extents[..(map.mapped_extents as usize).min(FIEMAP_BATCH)];
+        let Some(last) = mapped.last() else {
+            return Ok(Some(extents));
+        };
+        for extent in mapped {
+            if extent.flags & FIEMAP_EXTENT_SHARED == 0 || extent.flags & FIEMAP_EXTENT_UNTRUSTED != 0 {
+                return Ok(None);
+            }
+            extents.push((extent.logical, extent.physical, extent.length));
+        }
+        if last.flags & FIEMAP_EXTENT_LAST != 0 {
+            return Ok(Some(extents));
+        }
+        start = last.logical.saturating_add(last.length);
+    }
+}
+
+/// File system type and device of the mount holding `path`, from /proc/self/mountinfo;
+/// `None` for mounts not backed by a device
+#[cfg(target_os = "linux")]
+fn mount_source(path: &Path) -> Option<(String, String)> {
+    let path = fs::canonicalize(path).ok()?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let mut best: Option<(usize, String, String)> = None;
+    for (mount_point, fs_type, source) in mountinfo.lines().filter_map(parse_mount_line) {
+        let depth = mount_point.components().count();
+        // The last of equally deep mounts is the one on top
+        if path.starts_with(&mount_point) && best.as_ref().is_none_or(|(best, _, _)| depth >= *best) {
+            best = Some((depth, fs_type.to_string(), unescape_mount_field(source)));
+        }
+    }
+    best.filter(|(_, _, source)| source.starts_with('/')).map(|(_, fs_type, source)| (fs_type, source))
+}
+
+/// Mount point, file system type and source of a /proc/self/mountinfo line
+#[cfg(target_os = "linux")]
+fn parse_mount_line(line: &str) -> Option<(PathBuf, &str, &str)> {
+    let (mount, fs) = line.split_once(" - ")?;
+    let mut fs = fs.split(' ');
+    Some((PathBuf::from(unescape_mount_field(mount.split(' ').nth(4)?)), fs.next()?, fs.next()?))
+}
+
+/// Undo the octal escapes (`\040` for a space) of a /proc/self/mountinfo field
+#[cfg(target_os = "linux")]
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Create a symbolic link at `link` pointing to `target`, replacing a file or link at `link`
+///
+/// On Windows the link is a directory link if `target` (relative to the link's
+/// directory) is an existing directory, and creating links may need privileges or
+/// developer mode.
+pub fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(link) {
+        // An empty directory left by removed files may make way for a link, nothing more
+        if metadata.is_dir() {
+            fs::remove_dir(link)?;
+        } else {
+            fs::remove_file(link)?;
+        }
+    }
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        let resolved = link.parent().map_or_else(|| target.to_path_buf(), |parent| parent.join(target));
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = target;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "symbolic links not supported"))
+    }
+}
+
+/// Recreate the tree under `src` at `dst`, hard-linking files where possible
+///
+/// Files whose relative path is in `skip` are left out, e.g. because they are about to
+/// be replaced anyway. Files must be detached with `detach_file` before they are
+/// rewritten, otherwise the change shows up in `src` as well.
+pub fn mirror_tree(src: &Path, dst: &Path, skip: &HashSet<PathBuf>) -> Result<MirrorStats> {
+    let mut stats = MirrorStats::default();
+    fs::create_dir_all(dst).with_context(|| format!("Failed to create directory: {}", dst.display()))?;
+
+    for entry in WalkDir::new(src) {
+        let entry = entry.with_context(|| format!("Failed to read directory: {}", src.display()))?;
+        let relative_path = entry.path().strip_prefix(src).unwrap_or(entry.path());
+        let dest_path = dst.join(relative_path);
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest_path)
+                .with_context(|| format!("Failed to create directory: {}", dest_path.display()))?;
+            continue;
+        }
+        if skip.contains(relative_path) {
+            continue;
+        }
+        // Links are recreated, also when they point nowhere
+        if entry.path_is_symlink() {
+            let target = fs::read_link(entry.path()).with_context(|| format!("Failed to read link: {}", entry.path().display()))?;
+            create_symlink(&target, &dest_path).with_context(|| format!("Failed to mirror link: {}", entry.path().display()))?;
+            stats.copied += 1;
+            continue;
+        }
+
+        let placement = link_or_copy(entry.path(), &dest_path)
+            .with_context(|| format!("Failed to mirror file: {}", entry.path().display()))?;
+        match placement {
+            Placement::HardLink => stats.linked += 1,
+            Placement::Reflink => stats.cloned += 1,
+            Placement::Copy => stats.copied += 1,
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Unlink a file that may share storage with other hard links so it can be rewritten
+///
+/// Returns the permissions of the removed file so they can be restored on the new one,
+/// or `None` if the file did not exist.
+pub fn detach_file(path: &Path) -> io::Result<Option<fs::Permissions>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            fs::remove_file(path)?;
+            Ok(Some(metadata.permissions()))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Give `path` storage of its own if it may share it with other hard links, so its
+/// content and permissions can change without touching the other links
+///
+/// The file is copied to a sibling (a reflink where possible), which replaces it.
+pub fn unshare_file(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if fs::symlink_metadata(path)?.nlink() <= 1 {
+            return Ok(());
+        }
+    }
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let copy = path.with_file_name(format!(".{}.unshare", name.to_string_lossy()));
+    clone_or_copy(path, &copy)?;
+    fs::rename(&copy, path).inspect_err(|_| {
+        let _ = fs::remove_file(&copy);
+    })
+}
+
+/// Set the Unix permission bits of `path` (does nothing on other platforms)
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Windows attribute of read-only files
+#[cfg(windows)]
+const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+/// Windows attribute of hidden files
+#[cfg(windows)]
+const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+/// Windows attribute value meaning no other attribute is set
+#[cfg(windows)]
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+
+/// Permissions of a file, in the form of the platform it was scanned on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum FileMode {
+    /// Permission bits, including setuid, setgid and sticky (at most `0o7777`)
+    Unix(u32),
+    /// The attributes Windows keeps instead of permissions
+    Windows { readonly: bool, hidden: bool },
+}
+
+impl FileMode {
+    /// Mode of the file `metadata` describes
+    #[cfg(unix)]
+    pub fn of(metadata: &fs::Metadata) -> Option<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        Some(FileMode::Unix(metadata.permissions().mode() & 0o7777))
+    }
+
+    #[cfg(windows)]
+    pub fn of(metadata: &fs::Metadata) -> Option<Self> {
+        use std::os::windows::fs::MetadataExt;
+
+        let attributes = metadata.file_attributes();
+        Some(FileMode::Windows {
+            readonly: attributes & FILE_ATTRIBUTE_READONLY != 0,
+            hidden: attributes & FILE_ATTRIBUTE_HIDDEN != 0,
+        })
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    pub fn of(_metadata: &fs::Metadata) -> Option<Self> {
+        None
+    }
+
+    /// Give `path` this mode; whether it could, which it cannot for the mode of
+    /// another platform
+    pub fn apply(self, path: &Path) -> io::Result<bool> {
+        match self {
+            #[cfg(unix)]
+            FileMode::Unix(mode) => set_mode(path, mode).map(|_| true),
+            #[cfg(windows)]
+            FileMode::Windows { readonly, hidden } => set_attributes(path, readonly, hidden).map(|_| true),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl std::fmt::Display for FileMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            FileMode::Unix(mode) => write!(f, "{:04o}", mode),
+            FileMode::Windows { readonly: false, hidden: false } => f.write_str("normal"),
+            FileMode::Windows { readonly, hidden } => {
+                let attributes: Vec<&str> = [(readonly, "readonly"), (hidden, "hidden")]
+                    .into_iter()
+                    .filter(|(set, _)| *set)
+                    .map(|(_, name)| name)
+                    .collect();
+                f.write_str(&attributes.join(","))
+            }
+        }
+    }
+}
+
+/// Set the read-only and hidden attributes of `path`, keeping its other attributes
+#[cfg(windows)]
+fn set_attributes(path: &Path, readonly: bool, hidden: bool) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn SetFileAttributesW(file_name: *const u16, attributes: u32) -> i32;
+    }
+
+    let mut attributes = fs::metadata(path)?.file_attributes() & !(FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_NORMAL);
+    if readonly {
+        attributes |= FILE_ATTRIBUTE_READONLY;
+    }
+    if hidden {
+        attributes |= FILE_ATTRIBUTE_HIDDEN;
+    }
+    if attributes == 0 {
+        attributes = FILE_ATTRIBUTE_NORMAL;
+    }
+    let name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    // SAFETY: `name` is a NUL-terminated wide string that outlives the call
+    if unsafe { SetFileAttributesW(name.as_ptr(), attributes) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Atomically exchange two directories, where the platform supports it
+#[cfg(target_os = "linux")]
+fn exchange_dirs(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings that outlive the call
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange_dirs(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "atomic directory exchange not supported"))
+}
+
+/// Put `staged` in place of `live`, leaving the previous `live` tree at `staged`
+///
+/// Uses an atomic exchange where available. Otherwise falls back to two renames, so
+/// `live` is briefly missing but never half-updated.
+pub fn swap_dirs(live: &Path, staged: &Path) -> Result<()> {
+    if exchange_dirs(live, staged).is_ok() {
+        return Ok(());
+    }
+
+    let parked = sibling_path(live, "old")?;
+    if parked.exists() {
+        fs::remove_dir_all(&parked)
+            .with_context(|| format!("Failed to remove leftover directory: {}", parked.display()))?;
+    }
+
+    fs::rename(live, &parked)
+        .with_context(|| format!("Failed to move {} out of the way", live.display()))?;
+    if let Err(e) = fs::rename(staged, live) {
+        // Put the original tree back so the directory is never left missing
+        let _ = fs::rename(&parked, live);
+        return Err(e).with_context(|| format!("Failed to move staged tree to {}", live.display()));
+    }
+    fs::rename(&parked, staged)
+        .with_context(|| format!("Failed to move previous tree to {}", staged.display()))?;
+
+    Ok(())
+}
+
+/// Hidden path next to `dir`, e.g. `/opt/.app.staged` for `/opt/app`
+pub fn sibling_path(dir: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = dir
+        .file_name()
+        .ok_or_else(|| anyhow!("Directory has no name: {}", dir.display()))?;
+    let parent = dir
+        .parent()
+        .ok_or_else(|| anyhow!("Directory has no parent: {}", dir.display()))?;
+    Ok(parent.join(format!(".{}.{}", name.to_string_lossy(), suffix)))
+}
+
+/// A hard-linked copy of a directory that is updated on the side
+///
+/// A staged copy is swapped into place on commit; an extracted copy is simply kept,
+/// leaving the original untouched. Either way the copy is removed again if it is
+/// dropped without being committed.
+pub struct StagedTree {
+    live: PathBuf,
+    shadow: PathBuf,
+    swap: bool,
+    committed: bool,
+}
+
+impl StagedTree {
+    /// Mirror `live` into a sibling directory that replaces it on commit
+    pub fn create(live: &Path, skip: &HashSet<PathBuf>) -> Result<Self> {
+        let live = live
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve directory: {}", live.display()))?;
+        let shadow = sibling_path(&live, "staged")?;
+        if shadow.exists() {
+            fs::remove_dir_all(&shadow)
+                .with_context(|| format!("Failed to remove leftover staging directory: {}", shadow.display()))?;
+        }
+
+        Self::mirror(live, shadow, true, skip)
+    }
+
+    /// Mirror `live` into `dest`, a new directory that is kept on commit
+    pub fn extract(live: &Path, dest: &Path, skip: &HashSet<PathBuf>) -> Result<Self> {
+        if dest.exists() {
+            return Err(anyhow!("Extraction directory already exists: {}", dest.display()));
+        }
+        Self::mirror(live.to_path_buf(), dest.to_path_buf(), false, skip)
+    }
+
+    fn mirror(live: PathBuf, shadow: PathBuf, swap: bool, skip: &HashSet<PathBuf>) -> Result<Self> {
+        let staged = StagedTree { live, shadow, swap, committed: false };
+        let stats = mirror_tree(&staged.live, &staged.shadow, skip)?;
+        println!(
+            "{}",
+            crate::tr!(
+                "apply.copy_created",
+                path = staged.shadow.display(),
+                linked = format_number(stats.linked as u64),
+                cloned = format_number(stats.cloned as u64),
+                copied = format_number(stats.copied as u64),
+            )
+        );
+        Ok(staged)
+    }
+
+    /// Directory to apply changes to
+    pub fn path(&self) -> &Path {
+        &self.shadow
+    }
+
+    /// Keep the updated copy; a staged copy is swapped into place and the previous
+    /// version deleted
+    pub fn commit(mut self) -> Result<()> {
+        if !self.swap {
+            self.committed = true;
+            return Ok(());
+        }
+
+        swap_dirs(&self.live, &self.shadow)?;
+        self.committed = true;
+        fs::remove_dir_all(&self.shadow)
+            .with_context(|| format!("Failed to remove previous version: {}", self.shadow.display()))
+    }
+}
+
+impl Drop for StagedTree {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_dir_all(&self.shadow);
+        }
+    }
+}
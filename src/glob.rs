@@ -0,0 +1,180 @@
+/// A shell-style wildcard pattern matched against `/`-separated paths
+///
+/// Supports `*` (anything but `/`), `**` (anything, including `/`), `?` (one character
+/// but `/`), character classes like `[a-z]` or `[!0-9]`, and `\` to escape the next
+/// character.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    AnyChar,       // ?
+    AnySequence,   // *
+    AnyRecursive,  // **
+    AnyDirs,       // **/ in gitignore patterns: zero or more whole directories
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+impl Pattern {
+    /// Compile a pattern; an unterminated `[` is taken literally
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    tokens.push(Token::AnyRecursive);
+                    i += 2;
+                    while chars.get(i) == Some(&'*') {
+                        i += 1;
+                    }
+                    continue;
+                }
+                '*' => tokens.push(Token::AnySequence),
+                '?' => tokens.push(Token::AnyChar),
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    tokens.push(Token::Literal(chars[i]));
+                }
+                '[' => match parse_class(&chars[i + 1..]) {
+                    Some((token, consumed)) => {
+                        tokens.push(token);
+                        i += consumed + 1;
+                        continue;
+                    }
+                    None => tokens.push(Token::Literal('[')),
+                },
+                c => tokens.push(Token::Literal(c)),
+            }
+            i += 1;
+        }
+        Pattern { tokens }
+    }
+
+    /// Compile a `.gitignore` pattern, where `**/` at the start or after a `/` also
+    /// matches no directory at all (`a/**/b` matches `a/b`)
+    pub fn gitignore(pattern: &str) -> Self {
+        let mut tokens = Vec::new();
+        for part in pattern.split_inclusive('/') {
+            if part == "**/" {
+                tokens.push(Token::AnyDirs);
+            } else {
+                tokens.extend(Pattern::new(part).tokens);
+            }
+        }
+        Pattern { tokens }
+    }
+
+    /// Whether the pattern can match across `/` (contains `**`)
+    pub fn is_recursive(&self) -> bool {
+        self.tokens.iter().any(|token| matches!(token, Token::AnyRecursive | Token::AnyDirs))
+    }
+
+    /// Match the whole of `text`
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        match_tokens(&self.tokens, &text)
+    }
+}
+
+/// Parse a character class after the opening `[`, returning the token and the number of
+/// characters consumed including the closing `]`
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    let mut first = true;
+    while i < chars.len() {
+        let c = chars[i];
+        // A `]` right after the opening bracket is a literal
+        if c == ']' && !first {
+            return Some((Token::Class { negated, ranges }, i + 1));
+        }
+        first = false;
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&end| end != ']') {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+fn match_tokens(tokens: &[Token], text: &[char]) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return text.is_empty();
+    };
+    match token {
+        Token::Literal(c) => text.first() == Some(c) && match_tokens(rest, &text[1..]),
+        Token::AnyChar => text.first().is_some_and(|&c| c != '/') && match_tokens(rest, &text[1..]),
+        Token::Class { negated, ranges } => text.first().is_some_and(|&c| {
+            c != '/' && ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+        }) && match_tokens(rest, &text[1..]),
+        Token::AnySequence => {
+            // Try every split that does not cross a `/`
+            let limit = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=limit).any(|n| match_tokens(rest, &text[n..]))
+        }
+        Token::AnyRecursive => (0..=text.len()).any(|n| match_tokens(rest, &text[n..])),
+        Token::AnyDirs => {
+            // Nothing, or everything up to and including any `/`
+            match_tokens(rest, text)
+                || text.iter().enumerate().any(|(i, &c)| c == '/' && match_tokens(rest, &text[i + 1..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).matches(text)
+    }
+
+    #[test]
+    fn wildcards_stop_at_slashes() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(matches("src/?.rs", "src/a.rs"));
+        assert!(!matches("src/?.rs", "src/ab.rs"));
+        assert!(!matches("a?b", "a/b"));
+        assert!(matches("**.rs", "src/bin/main.rs"));
+        assert!(matches("src/**/main.rs", "src/bin/main.rs"));
+        assert!(!matches("src/**/main.rs", "src/main.rs"));
+        assert!(matches("*", ""));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches("file[0-9].txt", "file7.txt"));
+        assert!(!matches("file[!0-9].txt", "file7.txt"));
+        assert!(matches("file[!0-9].txt", "fileA.txt"));
+        assert!(matches("[ab]", "b"));
+        assert!(!matches("[a-c]", "/"));
+        assert!(matches(r"\*.txt", "*.txt"));
+        assert!(!matches(r"\*.txt", "a.txt"));
+        // An unterminated class is taken literally
+        assert!(matches("[abc", "[abc"));
+    }
+
+    #[test]
+    fn gitignore_double_star_matches_no_directory() {
+        assert!(Pattern::gitignore("a/**/b").matches("a/b"));
+        assert!(Pattern::gitignore("a/**/b").matches("a/x/y/b"));
+        assert!(Pattern::gitignore("**/b").matches("b"));
+        assert!(!Pattern::gitignore("a/**/b").matches("ab"));
+        assert!(Pattern::gitignore("a/**/b").is_recursive());
+        assert!(!Pattern::new("a/*/b").is_recursive());
+    }
+}
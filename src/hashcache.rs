@@ -0,0 +1,272 @@
+use crate::content::ContentKind;
+use crate::diff::{self, DiffOptions, FileInfo, HashAlgorithm, ScanReport};
+use crate::interrupt;
+use crate::staging;
+use crate::tr;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Hash cache used when none is given explicitly
+pub const HASH_CACHE_VAR: &str = "DIFFPATCH_HASH_CACHE";
+
+/// Version of the cache files; files of other versions are ignored
+const HASH_CACHE_VERSION: u32 = 1;
+
+/// Files modified this shortly before a scan are not remembered: another write within
+/// the same tick of a coarse file system clock would leave their time unchanged
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// The cache directory to use: an explicit one, else `DIFFPATCH_HASH_CACHE` if set
+pub fn cache_dir(explicit: Option<&Path>) -> Option<PathBuf> {
+    explicit
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(HASH_CACHE_VAR).filter(|dir| !dir.is_empty()).map(PathBuf::from))
+}
+
+/// Remembered hash of one file, valid while the file looks the same
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedHash {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub mtime_ns: u64,
+    /// Inode number (Unix only), so a file replaced by another with the same size and
+    /// time is still hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<ContentKind>,
+}
+
+impl CachedHash {
+    /// Entry for a scanned file, `None` if its modification time is unknown
+    fn new(info: &FileInfo, metadata: &fs::Metadata) -> Option<Self> {
+        Some(CachedHash {
+            size: metadata.len(),
+            mtime_ns: mtime_ns(metadata)?,
+            inode: inode(metadata),
+            hash: info.hash.clone(),
+            content: info.content,
+        })
+    }
+
+    /// Whether the file with `metadata` still looks like the one that was hashed
+    fn matches(&self, metadata: &fs::Metadata) -> bool {
+        self.size == metadata.len() && Some(self.mtime_ns) == mtime_ns(metadata) && self.inode == inode(metadata)
+    }
+}
+
+/// Modification time in nanoseconds since the Unix epoch, if the file system keeps one
+pub fn mtime_ns(metadata: &fs::Metadata) -> Option<u64> {
+    mtime_ns_of(metadata.modified().ok()?)
+}
+
+fn mtime_ns_of(time: SystemTime) -> Option<u64> {
+    u64::try_from(time.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()
+}
+
+/// Inode number of the file (Unix only)
+#[cfg(unix)]
+pub fn inode(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+pub fn inode(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Remembered hashes of the files of one directory, made with one hash algorithm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HashCacheFile {
+    pub version: u32,
+    /// Canonical path of the directory
+    pub root: PathBuf,
+    pub hash_algorithm: HashAlgorithm,
+    /// Entries by path relative to `root`
+    pub files: HashMap<PathBuf, CachedHash>,
+}
+
+impl HashCacheFile {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self).context("Failed to serialize hash cache")?;
+        staging::write_atomic(path, &json).with_context(|| format!("Failed to write hash cache: {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read hash cache: {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse hash cache: {}", path.display()))
+    }
+}
+
+/// Start of the names of the cache files of the directory at `root` (a canonical path)
+fn file_prefix(root: &Path) -> String {
+    let digest = Sha256::digest(root.to_string_lossy().as_bytes());
+    digest.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// File in `cache_dir` holding the `algorithm` hashes of the directory at `root`
+pub fn cache_path(cache_dir: &Path, root: &Path, algorithm: HashAlgorithm) -> PathBuf {
+    cache_dir.join(format!("{}-{}.json", file_prefix(root), algorithm))
+}
+
+/// Scan `dir`, hashing only the files that are new or changed since the hashes in
+/// `cache_dir` were remembered, and remember the hashes of this scan
+///
+/// A file counts as changed when its size, modification time or inode (on Unix)
+/// differs. Tools that rewrite a file within the same time tick and keep its size
+/// defeat this, as they do `make`; `DiffOptions::paranoid` scans leave the cache alone.
+pub fn scan_with_cache(dir: &Path, options: &DiffOptions, cache_dir: &Path) -> Result<ScanReport> {
+    let root = dir.canonicalize().with_context(|| format!("Failed to resolve directory: {}", dir.display()))?;
+    let path = cache_path(cache_dir, &root, options.algorithm());
+    let mut previous = match path.exists().then(|| HashCacheFile::load(&path)) {
+        Some(Ok(cache)) if cache.version == HASH_CACHE_VERSION && cache.root == root && cache.hash_algorithm == options.algorithm() => cache.files,
+        Some(Err(e)) => {
+            options.status(format_args!("{}", tr!("hash_cache.ignoring", error = format!("{:#}", e))));
+            HashMap::new()
+        }
+        _ => HashMap::new(),
+    };
+    // Hashes written by a run with another --digest-encoding
+    for entry in previous.values_mut() {
+        entry.hash = options.encoding().reencode(&entry.hash)?;
+    }
+
+    let settled_ns = mtime_ns_of(SystemTime::now() - RACY_WINDOW).unwrap_or(0);
+    let scanned = Mutex::new(HashMap::with_capacity(previous.len()));
+    let known = |path: &Path, metadata: &fs::Metadata| {
+        let cached = previous.get(path).filter(|cached| cached.matches(metadata))?;
+        let info = FileInfo::new(path.to_path_buf(), cached.hash.clone(), cached.size);
+        Some(match cached.content {
+            Some(content) => info.with_content(content),
+            None => info,
+        })
+    };
+    let remember = |info: &FileInfo, metadata: &fs::Metadata| {
+        if let Some(entry) = CachedHash::new(info, metadata).filter(|entry| entry.mtime_ns < settled_ns) {
+            scanned.lock().unwrap().insert(info.relative_path.clone(), entry);
+        }
+    };
+    let report = match diff::scan_directory_with(dir, options, known, remember) {
+        Ok(report) => report,
+        // Stopped by Ctrl-C: keep the hashes computed so far for the next run
+        Err(e) if interrupt::partial_result::<ScanReport>(&e).is_some() => {
+            let mut files = previous;
+            files.extend(scanned.into_inner().unwrap());
+            fs::create_dir_all(cache_dir).with_context(|| format!("Failed to create hash cache: {}", cache_dir.display()))?;
+            HashCacheFile { version: HASH_CACHE_VERSION, root, hash_algorithm: options.algorithm(), files }.save(&path)?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let files = scanned.into_inner().unwrap();
+    let reused = files.iter().filter(|(path, entry)| previous.get(*path) == Some(*entry)).count();
+    options.status(format_args!("{}", tr!("hash_cache.reused", reused = reused, count = report.files.len(), path = dir.display())));
+    fs::create_dir_all(cache_dir).with_context(|| format!("Failed to create hash cache: {}", cache_dir.display()))?;
+    HashCacheFile { version: HASH_CACHE_VERSION, root, hash_algorithm: options.algorithm(), files }.save(&path)?;
+    Ok(report)
+}
+
+/// One cache file in a hash cache directory, as listed by `list`
+#[derive(Debug, Clone)]
+pub struct CacheSummary {
+    pub path: PathBuf,
+    pub root: PathBuf,
+    pub hash_algorithm: HashAlgorithm,
+    pub files: usize,
+    /// Size of the cache file
+    pub bytes: u64,
+}
+
+/// The cache files in `cache_dir`, sorted by directory; unreadable ones are left out
+pub fn list(cache_dir: &Path) -> Result<Vec<CacheSummary>> {
+    let mut summaries = Vec::new();
+    for path in cache_files(cache_dir)? {
+        let Ok(cache) = HashCacheFile::load(&path) else {
+            continue;
+        };
+        let bytes = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        summaries.push(CacheSummary { path, root: cache.root, hash_algorithm: cache.hash_algorithm, files: cache.files.len(), bytes });
+    }
+    summaries.sort_by(|a, b| a.root.cmp(&b.root).then_with(|| a.hash_algorithm.name().cmp(b.hash_algorithm.name())));
+    Ok(summaries)
+}
+
+/// Forget the remembered hashes of the directory `dir`, or of every directory if `None`,
+/// so their next scan hashes every file; returns the number of cache files removed
+pub fn clear(cache_dir: &Path, dir: Option<&Path>) -> Result<usize> {
+    // A directory that no longer exists can still be forgotten by its absolute path
+    let prefix = match dir {
+        Some(dir) => Some(file_prefix(&dir.canonicalize().or_else(|_| std::path::absolute(dir))?)),
+        None => None,
+    };
+    let mut removed = 0;
+    for path in cache_files(cache_dir)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if prefix.as_ref().is_none_or(|prefix| name.starts_with(&format!("{}-", prefix))) {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove hash cache: {}", path.display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// The `.json` files of a hash cache directory; none if it does not exist
+fn cache_files(cache_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !cache_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let entries = fs::read_dir(cache_dir).with_context(|| format!("Failed to read hash cache: {}", cache_dir.display()))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry.with_context(|| format!("Failed to read hash cache: {}", cache_dir.display()))?.path();
+        if path.extension().is_some_and(|ext| ext == "json") && path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::CancelToken;
+    use crate::progress::ProgressHook;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn stopped_scan_keeps_the_hashes_it_computed() {
+        let dir = TempDir::new();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            let path = dir.write(&format!("tree/{}", name), name.as_bytes());
+            // Files changed within the racy window are not cached
+            let settled = SystemTime::now() - Duration::from_secs(3600);
+            fs::File::options().write(true).open(&path).unwrap().set_modified(settled).unwrap();
+        }
+        let (tree, cache_dir) = (dir.path().join("tree"), dir.path().join("cache"));
+        let token = CancelToken::new();
+        let stop = token.clone();
+        let progress = ProgressHook::new(move |progress| {
+            if progress.files_hashed > 0 {
+                stop.cancel();
+            }
+        });
+        let options = DiffOptions::new().threads(1).cancel_token(token).progress(progress);
+        let error = scan_with_cache(&tree, &options, &cache_dir).unwrap_err();
+        assert!(interrupt::is_cancellation(&error));
+        let partial = interrupt::partial_result::<ScanReport>(&error).unwrap();
+        assert_eq!(partial.files.len(), 1);
+
+        let root = tree.canonicalize().unwrap();
+        let cache = HashCacheFile::load(&cache_path(&cache_dir, &root, options.algorithm())).unwrap();
+        assert_eq!(cache.files.keys().collect::<Vec<_>>(), partial.files.keys().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,575 @@
+use std::io::{self, Write};
+
+/// Streaming BLAKE3 with the default 32-byte output (portable implementation of the
+/// reference algorithm)
+#[derive(Clone)]
+pub struct Blake3 {
+    chunk: ChunkState,
+    /// Chaining values of completed subtrees, one per set bit of the chunk count
+    stack: Vec<[u32; 8]>,
+}
+
+const BLAKE3_IV: [u32; 8] = [0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19];
+const BLAKE3_MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+const BLAKE3_BLOCK_LEN: usize = 64;
+const BLAKE3_CHUNK_LEN: usize = 1024;
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+
+fn blake3_g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn blake3_round(state: &mut [u32; 16], m: &[u32; 16]) {
+    blake3_g(state, 0, 4, 8, 12, m[0], m[1]);
+    blake3_g(state, 1, 5, 9, 13, m[2], m[3]);
+    blake3_g(state, 2, 6, 10, 14, m[4], m[5]);
+    blake3_g(state, 3, 7, 11, 15, m[6], m[7]);
+    blake3_g(state, 0, 5, 10, 15, m[8], m[9]);
+    blake3_g(state, 1, 6, 11, 12, m[10], m[11]);
+    blake3_g(state, 2, 7, 8, 13, m[12], m[13]);
+    blake3_g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+/// The first 8 words of the compression output, which is all a 32-byte hash needs
+fn blake3_compress(cv: &[u32; 8], block: &[u32; 16], counter: u64, block_len: u32, flags: u32) -> [u32; 8] {
+    let mut state = [
+        cv[0], cv[1], cv[2], cv[3], cv[4], cv[5], cv[6], cv[7],
+        BLAKE3_IV[0], BLAKE3_IV[1], BLAKE3_IV[2], BLAKE3_IV[3],
+        counter as u32, (counter >> 32) as u32, block_len, flags,
+    ];
+    let mut block = *block;
+    for round in 0..7 {
+        blake3_round(&mut state, &block);
+        if round < 6 {
+            block = BLAKE3_MSG_PERMUTATION.map(|i| block[i]);
+        }
+    }
+    std::array::from_fn(|i| state[i] ^ state[i + 8])
+}
+
+fn blake3_words(bytes: &[u8; BLAKE3_BLOCK_LEN]) -> [u32; 16] {
+    std::array::from_fn(|i| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap()))
+}
+
+fn blake3_parent(left: &[u32; 8], right: &[u32; 8], flags: u32) -> [u32; 8] {
+    let block: [u32; 16] = std::array::from_fn(|i| if i < 8 { left[i] } else { right[i - 8] });
+    blake3_compress(&BLAKE3_IV, &block, 0, BLAKE3_BLOCK_LEN as u32, PARENT | flags)
+}
+
+#[derive(Clone)]
+struct ChunkState {
+    cv: [u32; 8],
+    counter: u64,
+    block: [u8; BLAKE3_BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(counter: u64) -> Self {
+        ChunkState { cv: BLAKE3_IV, counter, block: [0; BLAKE3_BLOCK_LEN], block_len: 0, blocks_compressed: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.blocks_compressed * BLAKE3_BLOCK_LEN + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 { CHUNK_START } else { 0 }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // A full block is only compressed once more input follows, as the last one is special
+            if self.block_len == BLAKE3_BLOCK_LEN {
+                let words = blake3_words(&self.block);
+                self.cv = blake3_compress(&self.cv, &words, self.counter, BLAKE3_BLOCK_LEN as u32, self.start_flag());
+                self.blocks_compressed += 1;
+                self.block_len = 0;
+            }
+            let take = (BLAKE3_BLOCK_LEN - self.block_len).min(input.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    /// Chaining value of the chunk, with `flags` added for the last block
+    fn finish(&self, flags: u32) -> [u32; 8] {
+        let mut block = [0u8; BLAKE3_BLOCK_LEN];
+        block[..self.block_len].copy_from_slice(&self.block[..self.block_len]);
+        let flags = self.start_flag() | CHUNK_END | flags;
+        blake3_compress(&self.cv, &blake3_words(&block), self.counter, self.block_len as u32, flags)
+    }
+}
+
+impl Default for Blake3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Blake3 {
+    pub fn new() -> Self {
+        Blake3 { chunk: ChunkState::new(0), stack: Vec::new() }
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // Like a block, a full chunk is only finished once more input follows
+            if self.chunk.len() == BLAKE3_CHUNK_LEN {
+                let mut cv = self.chunk.finish(0);
+                let mut total_chunks = self.chunk.counter + 1;
+                // Merge completed subtrees: one for every trailing zero bit of the chunk count
+                while total_chunks & 1 == 0 {
+                    cv = blake3_parent(&self.stack.pop().expect("a subtree for every set bit"), &cv, 0);
+                    total_chunks >>= 1;
+                }
+                self.stack.push(cv);
+                self.chunk = ChunkState::new(self.chunk.counter + 1);
+            }
+            let take = (BLAKE3_CHUNK_LEN - self.chunk.len()).min(input.len());
+            self.chunk.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    pub fn finalize(&self) -> [u8; 32] {
+        let root = if self.stack.is_empty() {
+            self.chunk.finish(ROOT)
+        } else {
+            let mut cv = self.chunk.finish(0);
+            for (i, left) in self.stack.iter().enumerate().rev() {
+                cv = blake3_parent(left, &cv, if i == 0 { ROOT } else { 0 });
+            }
+            cv
+        };
+        let mut out = [0u8; 32];
+        for (i, word) in root.iter().enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+impl Write for Blake3 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streaming XXH3 (64-bit, seed 0, default secret), a fast non-cryptographic hash
+///
+/// Gives the same result as the one-shot `XXH3_64bits` of the reference library.
+#[derive(Clone)]
+pub struct Xxh3 {
+    acc: [u64; 8],
+    /// Input not yet accumulated, after the last 64 accumulated bytes (needed for the
+    /// final stripe, which may overlap them)
+    buffer: Vec<u8>,
+    /// Start of the unaccumulated input in `buffer`
+    pos: usize,
+    /// Stripes accumulated in the current block
+    stripes: usize,
+    total: u64,
+}
+
+const XXH_PRIME32_1: u64 = 0x9E3779B1;
+const XXH_PRIME32_2: u64 = 0x85EBCA77;
+const XXH_PRIME32_3: u64 = 0xC2B2AE3D;
+const XXH_PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const XXH_PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const XXH_PRIME64_3: u64 = 0x165667B19E3779F9;
+const XXH_PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const XXH_PRIME64_5: u64 = 0x27D4EB2F165667C5;
+const XXH_PRIME_MX1: u64 = 0x165667919E3779F9;
+const XXH_PRIME_MX2: u64 = 0x9FB21C651E98DF25;
+const XXH3_MIDSIZE_MAX: usize = 240;
+const XXH_STRIPE_LEN: usize = 64;
+const XXH_SECRET_CONSUME_RATE: usize = 8;
+const XXH_STRIPES_PER_BLOCK: usize = (XXH3_SECRET.len() - XXH_STRIPE_LEN) / XXH_SECRET_CONSUME_RATE;
+const XXH3_INIT_ACC: [u64; 8] =
+    [XXH_PRIME32_3, XXH_PRIME64_1, XXH_PRIME64_2, XXH_PRIME64_3, XXH_PRIME64_4, XXH_PRIME32_2, XXH_PRIME64_5, XXH_PRIME32_1];
+
+const XXH3_SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+fn read32(bytes: &[u8], offset: usize) -> u64 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as u64
+}
+
+fn read64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn mul128_fold64(lhs: u64, rhs: u64) -> u64 {
+    let product = lhs as u128 * rhs as u128;
+    product as u64 ^ (product >> 64) as u64
+}
+
+fn xxh64_avalanche(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(XXH_PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(XXH_PRIME64_3);
+    hash ^ (hash >> 32)
+}
+
+fn xxh3_avalanche(mut hash: u64) -> u64 {
+    hash ^= hash >> 37;
+    hash = hash.wrapping_mul(XXH_PRIME_MX1);
+    hash ^ (hash >> 32)
+}
+
+fn xxh3_rrmxmx(mut hash: u64, len: u64) -> u64 {
+    hash ^= hash.rotate_left(49) ^ hash.rotate_left(24);
+    hash = hash.wrapping_mul(XXH_PRIME_MX2);
+    hash ^= (hash >> 35).wrapping_add(len);
+    hash = hash.wrapping_mul(XXH_PRIME_MX2);
+    hash ^ (hash >> 28)
+}
+
+fn xxh3_mix16(input: &[u8], offset: usize, secret_offset: usize) -> u64 {
+    mul128_fold64(
+        read64(input, offset) ^ read64(&XXH3_SECRET, secret_offset),
+        read64(input, offset + 8) ^ read64(&XXH3_SECRET, secret_offset + 8),
+    )
+}
+
+/// One-shot hash of inputs of at most `XXH3_MIDSIZE_MAX` bytes
+fn xxh3_short(input: &[u8]) -> u64 {
+    let secret = &XXH3_SECRET;
+    let len = input.len();
+    match len {
+        0 => xxh64_avalanche(read64(secret, 56) ^ read64(secret, 64)),
+        1..=3 => {
+            let combined = ((input[0] as u64) << 16) | ((input[len >> 1] as u64) << 24) | input[len - 1] as u64 | ((len as u64) << 8);
+            xxh64_avalanche(combined ^ (read32(secret, 0) ^ read32(secret, 4)))
+        }
+        4..=8 => {
+            let input64 = read32(input, len - 4).wrapping_add(read32(input, 0) << 32);
+            xxh3_rrmxmx(input64 ^ (read64(secret, 8) ^ read64(secret, 16)), len as u64)
+        }
+        9..=16 => {
+            let low = read64(input, 0) ^ (read64(secret, 24) ^ read64(secret, 32));
+            let high = read64(input, len - 8) ^ (read64(secret, 40) ^ read64(secret, 48));
+            let acc = (len as u64).wrapping_add(low.swap_bytes()).wrapping_add(high).wrapping_add(mul128_fold64(low, high));
+            xxh3_avalanche(acc)
+        }
+        17..=128 => {
+            let mut acc = (len as u64).wrapping_mul(XXH_PRIME64_1);
+            for i in 0..=(len - 1) / 32 {
+                acc = acc.wrapping_add(xxh3_mix16(input, 16 * i, 32 * i));
+                acc = acc.wrapping_add(xxh3_mix16(input, len - 16 * (i + 1), 32 * i + 16));
+            }
+            xxh3_avalanche(acc)
+        }
+        _ => {
+            let mut acc = (len as u64).wrapping_mul(XXH_PRIME64_1);
+            for i in 0..8 {
+                acc = acc.wrapping_add(xxh3_mix16(input, 16 * i, 16 * i));
+            }
+            let mut acc_end = xxh3_mix16(input, len - 16, 136 - 17);
+            acc = xxh3_avalanche(acc);
+            for i in 8..len / 16 {
+                acc_end = acc_end.wrapping_add(xxh3_mix16(input, 16 * i, 16 * (i - 8) + 3));
+            }
+            xxh3_avalanche(acc.wrapping_add(acc_end))
+        }
+    }
+}
+
+fn xxh3_accumulate(acc: &mut [u64; 8], stripe: &[u8], secret_offset: usize) {
+    for lane in 0..8 {
+        let value = read64(stripe, lane * 8);
+        let key = value ^ read64(&XXH3_SECRET, secret_offset + lane * 8);
+        acc[lane ^ 1] = acc[lane ^ 1].wrapping_add(value);
+        acc[lane] = acc[lane].wrapping_add((key & 0xFFFF_FFFF).wrapping_mul(key >> 32));
+    }
+}
+
+fn xxh3_scramble(acc: &mut [u64; 8]) {
+    for (lane, value) in acc.iter_mut().enumerate() {
+        let key = read64(&XXH3_SECRET, XXH3_SECRET.len() - XXH_STRIPE_LEN + lane * 8);
+        *value = ((*value ^ (*value >> 47)) ^ key).wrapping_mul(XXH_PRIME32_1);
+    }
+}
+
+impl Default for Xxh3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Xxh3 {
+    pub fn new() -> Self {
+        Xxh3 { acc: XXH3_INIT_ACC, buffer: Vec::new(), pos: 0, stripes: 0, total: 0 }
+    }
+
+    pub fn update(&mut self, input: &[u8]) {
+        self.buffer.extend_from_slice(input);
+        self.total += input.len() as u64;
+        // Short inputs are hashed whole at the end
+        if self.total <= XXH3_MIDSIZE_MAX as u64 {
+            return;
+        }
+        // A stripe is accumulated normally only if input follows it; the last one is special
+        while self.buffer.len() - self.pos > XXH_STRIPE_LEN {
+            let stripe = &self.buffer[self.pos..self.pos + XXH_STRIPE_LEN];
+            xxh3_accumulate(&mut self.acc, stripe, self.stripes * XXH_SECRET_CONSUME_RATE);
+            self.pos += XXH_STRIPE_LEN;
+            self.stripes += 1;
+            if self.stripes == XXH_STRIPES_PER_BLOCK {
+                xxh3_scramble(&mut self.acc);
+                self.stripes = 0;
+            }
+        }
+        // Keep the last accumulated stripe, which the final one may overlap
+        let keep_from = self.pos.saturating_sub(XXH_STRIPE_LEN);
+        if keep_from > 0 {
+            self.buffer.drain(..keep_from);
+            self.pos -= keep_from;
+        }
+    }
+
+    pub fn finalize(&self) -> u64 {
+        if self.total <= XXH3_MIDSIZE_MAX as u64 {
+            return xxh3_short(&self.buffer);
+        }
+        let mut acc = self.acc;
+        let last = &self.buffer[self.buffer.len() - XXH_STRIPE_LEN..];
+        xxh3_accumulate(&mut acc, last, XXH3_SECRET.len() - XXH_STRIPE_LEN - 7);
+        let mut result = self.total.wrapping_mul(XXH_PRIME64_1);
+        for i in 0..4 {
+            result = result.wrapping_add(mul128_fold64(
+                acc[2 * i] ^ read64(&XXH3_SECRET, 11 + 16 * i),
+                acc[2 * i + 1] ^ read64(&XXH3_SECRET, 11 + 16 * i + 8),
+            ));
+        }
+        xxh3_avalanche(result)
+    }
+}
+
+impl Write for Xxh3 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streaming XXH32 (seed 0), the checksum of the LZ4 frame format
+#[derive(Clone)]
+pub struct Xxh32 {
+    acc: [u32; 4],
+    /// Input not yet accumulated, less than one 16-byte stripe
+    buffer: Vec<u8>,
+    total: u64,
+}
+
+const XXH32_PRIME_1: u32 = XXH_PRIME32_1 as u32;
+const XXH32_PRIME_2: u32 = XXH_PRIME32_2 as u32;
+const XXH32_PRIME_3: u32 = XXH_PRIME32_3 as u32;
+const XXH32_PRIME_4: u32 = 0x27D4EB2F;
+const XXH32_PRIME_5: u32 = 0x165667B1;
+
+impl Default for Xxh32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn xxh32_round(acc: u32, input: u32) -> u32 {
+    acc.wrapping_add(input.wrapping_mul(XXH32_PRIME_2)).rotate_left(13).wrapping_mul(XXH32_PRIME_1)
+}
+
+impl Xxh32 {
+    pub fn new() -> Self {
+        Xxh32 {
+            acc: [
+                XXH32_PRIME_1.wrapping_add(XXH32_PRIME_2),
+                XXH32_PRIME_2,
+                0,
+                0u32.wrapping_sub(XXH32_PRIME_1),
+            ],
+            buffer: Vec::with_capacity(16),
+            total: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        self.total += input.len() as u64;
+        if !self.buffer.is_empty() {
+            let take = (16 - self.buffer.len()).min(input.len());
+            self.buffer.extend_from_slice(&input[..take]);
+            input = &input[take..];
+            if self.buffer.len() < 16 {
+                return;
+            }
+            let stripe = std::mem::take(&mut self.buffer);
+            self.accumulate(&stripe);
+            self.buffer = stripe;
+            self.buffer.clear();
+        }
+        let mut stripes = input.chunks_exact(16);
+        for stripe in &mut stripes {
+            self.accumulate(stripe);
+        }
+        self.buffer.extend_from_slice(stripes.remainder());
+    }
+
+    fn accumulate(&mut self, stripe: &[u8]) {
+        for (lane, acc) in self.acc.iter_mut().enumerate() {
+            *acc = xxh32_round(*acc, read32(stripe, lane * 4) as u32);
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        let mut hash = if self.total >= 16 {
+            let [v1, v2, v3, v4] = self.acc;
+            v1.rotate_left(1).wrapping_add(v2.rotate_left(7)).wrapping_add(v3.rotate_left(12)).wrapping_add(v4.rotate_left(18))
+        } else {
+            XXH32_PRIME_5
+        };
+        hash = hash.wrapping_add(self.total as u32);
+        let mut words = self.buffer.chunks_exact(4);
+        for word in &mut words {
+            hash = hash.wrapping_add((read32(word, 0) as u32).wrapping_mul(XXH32_PRIME_3)).rotate_left(17).wrapping_mul(XXH32_PRIME_4);
+        }
+        for &byte in words.remainder() {
+            hash = hash.wrapping_add(u32::from(byte).wrapping_mul(XXH32_PRIME_5)).rotate_left(11).wrapping_mul(XXH32_PRIME_1);
+        }
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(XXH32_PRIME_2);
+        hash ^= hash >> 13;
+        hash = hash.wrapping_mul(XXH32_PRIME_3);
+        hash ^ (hash >> 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Input of the official BLAKE3 test vectors
+    fn blake3_input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Input of the xxHash sanity checks
+    fn xxhash_input(len: usize) -> Vec<u8> {
+        let mut generator: u64 = 2654435761;
+        (0..len).map(|_| {
+            let byte = (generator >> 56) as u8;
+            generator = generator.wrapping_mul(11400714785074694797);
+            byte
+        }).collect()
+    }
+
+    #[test]
+    fn blake3_matches_the_test_vectors() {
+        let vectors = [
+            (0, "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+            (1, "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"),
+            (1023, "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11"),
+            (1024, "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+            (1025, "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+            (2048, "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"),
+            (2049, "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030"),
+            (3072, "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2"),
+            (4096, "015094013f57a5277b59d8475c0501042c0b642e531b0a1c8f58d2163229e969"),
+            (8192, "aae792484c8efe4f19e2ca7d371d8c467ffb10748d8a5a1ae579948f718a2a63"),
+            (31744, "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47"),
+            (102400, "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"),
+        ];
+        for (len, expected) in vectors {
+            let mut hasher = Blake3::new();
+            hasher.update(&blake3_input(len));
+            assert_eq!(hex(&hasher.finalize()), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn xxh3_matches_the_test_vectors() {
+        let vectors = [
+            (0, 0x2D06800538D394C2),
+            (1, 0xC44BDFF4074EECDB),
+            (6, 0x27B56A84CD2D7325),
+            (12, 0xA713DAF0DFBB77E7),
+            (24, 0xA3FE70BF9D3510EB),
+            (48, 0x397DA259ECBA1F11),
+            (80, 0xBCDEFBBB2C47C90A),
+            (195, 0xCD94217EE362EC3A),
+            (403, 0xCDEB804D65C6DEA4),
+            (512, 0x617E49599013CB6B),
+            (2048, 0xDD59E2C3A5F038E0),
+            (2240, 0x6E73A90539CF2948),
+            (2367, 0xCB37AEB9E5D361ED),
+        ];
+        for (len, expected) in vectors {
+            let mut hasher = Xxh3::new();
+            hasher.update(&xxhash_input(len));
+            assert_eq!(hasher.finalize(), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn xxh32_matches_the_test_vectors() {
+        let mut hasher = Xxh32::new();
+        assert_eq!(hasher.finalize(), 0x02CC5D05);
+        hasher.update(b"abc");
+        assert_eq!(hasher.finalize(), 0x32D153FF);
+    }
+
+    #[test]
+    fn streamed_input_hashes_like_one_piece() {
+        let input = blake3_input(5000);
+        let (mut blake3, mut xxh3, mut xxh32) = (Blake3::new(), Xxh3::new(), Xxh32::new());
+        for piece in input.chunks(333) {
+            blake3.update(piece);
+            xxh3.update(piece);
+            xxh32.update(piece);
+        }
+        let (mut whole3, mut whole_xxh3, mut whole_xxh32) = (Blake3::new(), Xxh3::new(), Xxh32::new());
+        whole3.update(&input);
+        whole_xxh3.update(&input);
+        whole_xxh32.update(&input);
+        assert_eq!(blake3.finalize(), whole3.finalize());
+        assert_eq!(xxh3.finalize(), whole_xxh3.finalize());
+        assert_eq!(xxh32.finalize(), whole_xxh32.finalize());
+    }
+}
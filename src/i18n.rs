@@ -0,0 +1,661 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Language of the human-readable output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    English,
+    Chinese,
+}
+
+impl Language {
+    /// Match a locale name like `zh_CN.UTF-8`, `en-US` or `zh`
+    fn from_locale(locale: &str) -> Option<Self> {
+        let code = locale.split(['_', '-', '.', '@']).next()?.to_ascii_lowercase();
+        match code.as_str() {
+            "en" | "c" | "posix" => Some(Language::English),
+            "zh" => Some(Language::Chinese),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Language::from_locale(value).ok_or_else(|| anyhow!("Unsupported language: {} (expected en or zh)", value))
+    }
+}
+
+static LANGUAGE: AtomicU8 = AtomicU8::new(0);
+
+/// Select the language used by `translate`
+pub fn set_language(language: Language) {
+    LANGUAGE.store(language as u8, Ordering::Relaxed);
+}
+
+pub fn language() -> Language {
+    match LANGUAGE.load(Ordering::Relaxed) {
+        1 => Language::Chinese,
+        _ => Language::English,
+    }
+}
+
+/// Language of the system locale
+///
+/// `DIFFPATCH_LANG` wins, then the POSIX `LC_ALL`, `LC_MESSAGES` and `LANG` variables,
+/// then (on Windows) the user's UI language. Unknown locales fall back to English.
+pub fn detect_language() -> Language {
+    ["DIFFPATCH_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok().filter(|value| !value.is_empty()))
+        .find_map(|value| Language::from_locale(&value))
+        .or_else(platform::ui_language)
+        .unwrap_or_default()
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Language;
+
+    const LANG_CHINESE: u16 = 0x04;
+
+    unsafe extern "system" {
+        fn GetUserDefaultUILanguage() -> u16;
+    }
+
+    pub fn ui_language() -> Option<Language> {
+        // SAFETY: takes no arguments and only reads the user's settings
+        let lang_id = unsafe { GetUserDefaultUILanguage() };
+        // The low 10 bits hold the primary language
+        Some(if lang_id & 0x3ff == LANG_CHINESE { Language::Chinese } else { Language::English })
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use super::Language;
+
+    pub fn ui_language() -> Option<Language> {
+        None
+    }
+}
+
+/// Message key, English text and Chinese text; `{name}` marks an argument
+const MESSAGES: &[(&str, &str, &str)] = &[
+    ("interrupted", "Interrupted by user", "已被用户中断"),
+    ("cancelled", "Cancelled", "已取消"),
+    ("confirm.operation_cancelled", "Operation cancelled.", "操作已取消。"),
+    // Creating patches
+    ("create.run_id", "Run ID: {run}", "运行 ID：{run}"),
+    ("create.workflow", "Workflow: {name}", "工作流程：{name}"),
+    ("create.excluding_extensions", "Excluding file extensions:", "排除的文件扩展名："),
+    ("create.excluding_dirs", "Excluding directories:", "排除的目录："),
+    ("create.max_file_size", "Skipping files larger than {size}", "忽略大于 {size} 的文件"),
+    ("create.report_saved", "Diff report saved to: {path}", "差异报告已保存到：{path}"),
+    ("create.html_report_saved", "HTML report saved to: {path}", "HTML报告已保存到：{path}"),
+    ("create.no_differences", "No differences found, no need to create a patch.", "未发现差异，无需创建补丁。"),
+    ("create.found_differences", "Found {count} file differences:", "发现 {count} 个文件差异："),
+    ("create.count_added", "  Added: {count} files", "  新增：{count} 个文件"),
+    ("create.count_modified", "  Modified (full files): {count} files", "  修改（完整文件）：{count} 个文件"),
+    ("create.count_modified_diff", "  Modified (diff patches): {count} files", "  修改（差异补丁）：{count} 个文件"),
+    ("create.count_deleted", "  Deleted: {count} files", "  删除：{count} 个文件"),
+    ("create.count_permissions", "  Permissions changed: {count} files", "  权限变更：{count} 个文件"),
+    ("create.count_content", "  Content: {text} text, {binary} binary files", "  内容：{text} 个文本文件，{binary} 个二进制文件"),
+    ("create.count_symlinks", "  Symbolic links: {count}", "  符号链接：{count} 个"),
+    ("create.count_renamed", "  Renamed or moved: {count} files", "  重命名或移动：{count} 个文件"),
+    ("create.tag_summary", "Changes by tag:", "按标签统计的更改："),
+    (
+        "create.tag_counts",
+        "  {tag}: {added} added, {modified} modified, {removed} deleted, {renamed} renamed",
+        "  {tag}：新增 {added}，修改 {modified}，删除 {removed}，重命名 {renamed}",
+    ),
+    ("create.untagged", "(untagged)", "（无标签）"),
+    (
+        "create.only_tags",
+        "Keeping the changes tagged {tags}: {kept} of {total}",
+        "仅保留带有标签 {tags} 的更改：{kept} / {total}",
+    ),
+    ("create.archive_members", "  Changed entries in {path}: {count}", "  {path} 中更改的条目：{count} 个"),
+    ("create.archive_members_more", "    ... and {count} more (see --save-report)", "    ……另有 {count} 个（参见 --save-report）"),
+    ("create.warnings", "{count} warnings:", "{count} 个警告："),
+    (
+        "create.change_limit_exceeded",
+        "The change is larger than allowed: {limits}. Check the source directory, or pass --on-change-limit warn to create the patch anyway",
+        "更改量超出限制：{limits}。请检查源目录，或使用 --on-change-limit warn 仍然创建补丁",
+    ),
+    (
+        "create.warnings_as_errors",
+        "{count} warnings treated as errors (--warnings-as-errors)",
+        "{count} 个警告被视为错误（--warnings-as-errors）",
+    ),
+    ("create.check_file_missing", "Warning: Verification file does not exist: {path}", "警告：验证文件不存在：{path}"),
+    (
+        "create.no_check_files",
+        "Warning: No verification files specified, patch will be applied to any directory.",
+        "警告：未指定验证文件，补丁可应用于任意目录。",
+    ),
+    ("create.check_files", "Specified verification files:", "指定的验证文件："),
+    ("create.hook_missing", "Warning: Hook script not found in target directory: {path}", "警告：目标目录中未找到钩子脚本：{path}"),
+    ("create.confirm", "Confirm creating patch file?", "确认创建补丁文件？"),
+    ("create.creating", "Creating patch file in target directory: {path}", "正在目标目录中创建补丁文件：{path}"),
+    ("create.platform_files", "  Only for {platform}: {count} files", "  仅用于 {platform}：{count} 个文件"),
+    ("create.conditional_files", "  Only if {conditions}: {count} files", "  仅当 {conditions} 时：{count} 个文件"),
+    ("create.target", "Patch is for {platform} ({arch})", "补丁适用于 {platform}（{arch}）"),
+    ("create.target_any", "any", "任意"),
+    (
+        "create.variables",
+        "Paths or hooks use variables resolved when the patch is applied: {names}",
+        "路径或钩子使用了在应用补丁时解析的变量：{names}",
+    ),
+    ("create.computing_deltas", "Computing binary deltas for {count} modified files...", "正在为 {count} 个修改的文件计算二进制增量..."),
+    (
+        "create.delta_budget",
+        "Delta time budget of {budget} used up: storing {count} modified files whole",
+        "二进制增量的时间预算 {budget} 已用完：{count} 个修改的文件以完整文件存储",
+    ),
+    ("create.uploading", "Uploading the patch to {url}...", "正在将补丁上传到 {url}..."),
+    ("create.uploaded", "Patch uploaded: {url}", "补丁已上传：{url}"),
+    ("create.codec", "Compressing the content with codec {codec}", "使用编解码器 {codec} 压缩内容"),
+    (
+        "create.codec_not_builtin",
+        "Codec {codec} is not built into diffpatch, so a patch executable could not apply its entries (built-in: zstd, deflate, lz4)",
+        "编解码器 {codec} 不是 diffpatch 内置的，补丁可执行文件无法应用其条目（内置：zstd、deflate、lz4）",
+    ),
+    ("create.deltas", "Storing {count} modified files as binary deltas ({size} saved)", "{count} 个修改的文件以二进制增量存储（节省 {size}）"),
+    ("create.deduplicated", "Storing {count} files with duplicate content once ({size} saved)", "{count} 个内容重复的文件只存储一份（节省 {size}）"),
+    ("create.copy_done", "File copying complete", "文件复制完成"),
+    (
+        "create.object_cache",
+        "Object cache: {reused} files copied already compressed, {stored} new objects stored",
+        "对象缓存：{reused} 个文件直接复制已压缩的内容，新存储 {stored} 个对象",
+    ),
+    ("create.compressing", "Compressing {count} files...", "正在压缩 {count} 个文件..."),
+    ("create.read_done", "File reading complete", "文件读取完成"),
+    ("create.creating_archive", "Creating archive...", "正在创建压缩包..."),
+    ("create.archive_done", "Archive creation complete", "压缩包创建完成"),
+    ("create.created", "Patch file created successfully:", "补丁文件创建成功："),
+    ("create.location", "  Location: {path}", "  位置：{path}"),
+    ("create.statistics", "File statistics:", "文件统计："),
+    ("create.stat_modified", "  Modified: {count} files", "  修改：{count} 个文件"),
+    ("create.stat_modified_delta", "  Modified (binary deltas): {count} files", "  修改（二进制增量）：{count} 个文件"),
+    ("create.fingerprint", "Manifest fingerprint (SHA256): {hash}", "清单指纹（SHA256）：{hash}"),
+    // Applying patches
+    ("apply.patch_mode", "Running in patch mode with parallel processing...", "正在以补丁模式运行（并行处理）..."),
+    ("apply.start", "Applying patch to directory: {path}", "正在将补丁应用到目录：{path}"),
+    ("apply.run_id", "Run ID: {run}", "运行 ID：{run}"),
+    ("apply.patch_run_id", "Patch created by run: {run}", "补丁由以下运行创建：{run}"),
+    ("apply.verifying", "Verifying directory...", "正在验证目录..."),
+    ("apply.check_file_missing", "Verification file not found: {path}", "未找到验证文件：{path}"),
+    (
+        "apply.verification_failed",
+        "Directory verification failed. This patch cannot be applied here.",
+        "目录验证失败，此补丁无法应用于此目录。",
+    ),
+    ("apply.verified", "Directory verification successful.", "目录验证成功。"),
+    (
+        "apply.no_check_files",
+        "Warning: No verification files specified. Applying patch without verification.",
+        "警告：未指定验证文件，将在不验证目录的情况下应用补丁。",
+    ),
+    ("apply.confirm", "Continue with patch application?", "是否继续应用补丁？"),
+    (
+        "apply.hook_skipped",
+        "Warning: Patch defines a {stage} hook ({script}), skipped. Run with --allow-hooks to execute it.",
+        "警告：补丁定义了 {stage} 钩子（{script}），已跳过。使用 --allow-hooks 运行以执行该钩子。",
+    ),
+    ("apply.hook_running", "Running {stage} hook: {script}", "正在运行 {stage} 钩子：{script}"),
+    (
+        "apply.copy_created",
+        "Copy created at {path} ({linked} files linked, {cloned} cloned, {copied} copied)",
+        "已在 {path} 创建副本（硬链接 {linked} 个文件，克隆 {cloned} 个，复制 {copied} 个）",
+    ),
+    ("apply.modes_ignored", "Note: --file-mode only applies on Unix, ignoring it", "提示：--file-mode 仅在 Unix 上有效，已忽略"),
+    (
+        "apply.platform",
+        "Applying the files for {platform} ({arch}), skipping {count} files of other platforms or components",
+        "应用 {platform}（{arch}）的文件，跳过其他平台或组件的 {count} 个文件",
+    ),
+    (
+        "apply.target_mismatch",
+        "This patch was built for {platform} ({arch}), which does not match this installation:",
+        "此补丁适用于 {platform}（{arch}），与当前安装不匹配：",
+    ),
+    ("apply.target_platform_mismatch", "the patch is for {patch}, this system runs {system}", "补丁适用于 {patch}，当前系统为 {system}"),
+    ("apply.target_arch_mismatch", "the patch is for {patch}, this system is {system}", "补丁适用于 {patch}，当前系统架构为 {system}"),
+    ("apply.target_installed_mismatch", "installed {path} is built for {found}", "已安装的 {path} 适用于 {found}"),
+    (
+        "apply.target_refused",
+        "Refusing to apply a patch for another platform or architecture (use --force to apply anyway)",
+        "拒绝应用适用于其他平台或架构的补丁（使用 --force 强制应用）",
+    ),
+    ("apply.target_forced", "Applying anyway (--force)", "仍然应用（--force）"),
+    ("apply.checking_base", "Checking {count} files against the versions the patch was made from...", "正在将 {count} 个文件与补丁所基于的版本进行比对..."),
+    ("apply.base_mismatch", "{count} files are not the version the patch was made from:", "{count} 个文件不是补丁所基于的版本："),
+    (
+        "apply.base_refused",
+        "Refusing to patch files of another version (use --force to apply anyway)",
+        "拒绝修补其他版本的文件（使用 --force 强制应用）",
+    ),
+    ("apply.variables", "Resolved variables in {count} paths", "已解析 {count} 个路径中的变量"),
+    ("apply.processing", "Processing {count} files...", "正在处理 {count} 个文件..."),
+    (
+        "apply.object_cache",
+        "Object cache: {reused} files taken from the cache, {stored} new objects stored",
+        "对象缓存：{reused} 个文件取自缓存，新存储 {stored} 个对象",
+    ),
+    ("apply.extract_done", "Files extracted successfully", "文件解压完成"),
+    ("dry_run.apply", "Dry run; these changes would be made:", "演练模式，将进行以下更改："),
+    ("dry_run.create", "Dry run; the patch would make these changes:", "演练模式，补丁将进行以下更改："),
+    ("dry_run.add", "  add      {path} ({size})", "  新增     {path}（{size}）"),
+    ("dry_run.replace", "  replace  {path} ({size})", "  替换     {path}（{size}）"),
+    ("dry_run.patch_text", "  patch    {path} (text diff)", "  修补     {path}（文本差异）"),
+    ("dry_run.patch_delta", "  patch    {path} (binary delta, {size})", "  修补     {path}（二进制增量，{size}）"),
+    ("dry_run.link", "  link     {path} -> {target}", "  链接     {path} -> {target}"),
+    ("dry_run.rename", "  rename   {from} -> {to}", "  重命名   {from} -> {to}"),
+    ("dry_run.permissions", "  chmod    {path} ({mode})", "  改权限   {path}（{mode}）"),
+    ("dry_run.delete", "  delete   {path}", "  删除     {path}"),
+    ("dry_run.pre_apply_hook", "Would run {script} before the changes", "将在更改前运行 {script}"),
+    ("dry_run.post_apply_hook", "Would run {script} after the changes", "将在更改后运行 {script}"),
+    ("dry_run.done", "Dry run finished; nothing was changed", "演练完成，未做任何更改"),
+    ("apply.simulating", "Simulating the apply; no file is changed...", "正在模拟应用补丁，不会修改任何文件..."),
+    (
+        "apply.simulation_summary",
+        "Would write {written} files ({write_size}), rename {renamed} and remove {removed} ({delete_size})",
+        "将写入 {written} 个文件（{write_size}），重命名 {renamed} 个，删除 {removed} 个（{delete_size}）",
+    ),
+    ("apply.simulation_space", "  {strategy}: needs {size} of temporary space", "  {strategy}：需要 {size} 临时空间"),
+    (
+        "apply.simulation_space_short",
+        "  {strategy}: needs {size} of temporary space, more than is free",
+        "  {strategy}：需要 {size} 临时空间，超过可用空间",
+    ),
+    (
+        "apply.simulation_check_files",
+        "{count} check files are missing; the apply would refuse to run here",
+        "缺少 {count} 个验证文件，在此目录中将拒绝应用补丁",
+    ),
+    (
+        "apply.simulation_conflicts",
+        "{count} files are not in the expected state ({failing} would stop the apply)",
+        "{count} 个文件不是预期的状态（其中 {failing} 个会导致应用中止）",
+    ),
+    ("apply.simulation_time", "Estimated duration: {duration}", "预计耗时：{duration}"),
+    ("apply.simulation_saved", "Simulation saved to: {path}", "模拟结果已保存到：{path}"),
+    ("apply.renaming", "Renaming {count} files...", "正在重命名 {count} 个文件..."),
+    ("apply.delta_mismatch", "{path} is not the version the patch was made for, its binary delta cannot be applied", "{path} 不是补丁所基于的版本，无法应用其二进制增量"),
+    ("apply.delta_failed", "Rebuilding {path} from its binary delta gave different content than expected", "从二进制增量重建 {path} 的内容与预期不符"),
+    ("apply.rename_failed", "Warning: Failed to rename {from} to {to}: {error}", "警告：无法将 {from} 重命名为 {to}：{error}"),
+    ("apply.applying_diffs", "Applying {count} file diffs...", "正在应用 {count} 个文件差异..."),
+    ("apply.diff_failed", "Skipped {path}: {error}", "已跳过 {path}：{error}"),
+    ("apply.diffs_done", "File diffs applied successfully", "文件差异应用完成"),
+    ("apply.copying", "Copying {count} files to target directory...", "正在将 {count} 个文件复制到目标目录..."),
+    ("apply.writing", "Writing {count} files one at a time...", "正在逐个写入 {count} 个文件..."),
+    (
+        "apply.low_space",
+        "Low disk space: extracting everything first would need {needed} ({available} available), applying file by file instead",
+        "磁盘空间不足：先完整解压需要 {needed}（可用 {available}），改为逐个文件应用",
+    ),
+    (
+        "apply.not_enough_space",
+        "Not enough disk space in {path}: applying the patch needs {needed}, only {available} available",
+        "{path} 磁盘空间不足：应用补丁需要 {needed}，仅有 {available} 可用",
+    ),
+    ("apply.copy_done", "Files copied successfully", "文件复制完成"),
+    ("apply.symlinks", "Creating {count} symbolic links...", "正在创建 {count} 个符号链接..."),
+    (
+        "apply.symlink_failed",
+        "Failed to create link {path} -> {target}: {error}",
+        "无法创建链接 {path} -> {target}：{error}",
+    ),
+    ("apply.permissions", "Set the recorded permissions of {count} files", "已设置 {count} 个文件的记录权限"),
+    (
+        "apply.permissions_other_platform",
+        "Kept the permissions of {count} files, recorded on another platform",
+        "保留了 {count} 个文件的权限（记录于其他平台）",
+    ),
+    ("apply.mode_failed", "Failed to set permissions of {path}: {error}", "无法设置 {path} 的权限：{error}"),
+    (
+        "apply.verify_no_manifest",
+        "The patch has no target manifest, the result will not be verified",
+        "补丁不含目标清单，将不会验证结果",
+    ),
+    ("apply.backing_up", "Backing up {count} paths for rollback...", "正在备份 {count} 个路径以便回滚..."),
+    (
+        "apply.verifying_result",
+        "Verifying the result against the target manifest ({count} files)...",
+        "正在根据目标清单验证结果（{count} 个文件）...",
+    ),
+    (
+        "apply.verify_passed",
+        "Verification passed: {count} files match the target manifest",
+        "验证通过：{count} 个文件与目标清单一致",
+    ),
+    (
+        "apply.verify_failed",
+        "Verification failed: {mismatched} files differ, {missing} missing, {not_removed} not removed",
+        "验证失败：{mismatched} 个文件不一致，{missing} 个缺失，{not_removed} 个未删除",
+    ),
+    ("apply.verify_mismatched", "  Differs: {path}", "  不一致：{path}"),
+    ("apply.verify_missing", "  Missing: {path}", "  缺失：{path}"),
+    ("apply.verify_not_removed", "  Not removed: {path}", "  未删除：{path}"),
+    ("apply.verify_more", "  ... and {count} more", "  ……另有 {count} 个"),
+    ("apply.rolling_back", "Rolling back the changes...", "正在回滚更改..."),
+    ("apply.rolled_back", "Restored {count} paths to their state before the patch", "已将 {count} 个路径恢复到应用补丁前的状态"),
+    ("apply.staged_discarded", "Discarded the updated copy. {path} was not changed.", "已丢弃更新后的副本。{path} 未被修改。"),
+    ("apply.verification_saved", "Verification record saved to: {path}", "验证记录已保存到：{path}"),
+    (
+        "apply.verification_error",
+        "The patched directory does not match the target manifest of the patch",
+        "打补丁后的目录与补丁的目标清单不一致",
+    ),
+    ("apply.removing", "Removing {count} files...", "正在删除 {count} 个文件..."),
+    ("apply.remove_done", "Files removed successfully", "文件删除完成"),
+    (
+        "apply.interrupted_discarded",
+        "Interrupted, discarding the updated copy. {path} was not changed.",
+        "已中断，更新后的副本已丢弃。{path} 未被修改。",
+    ),
+    (
+        "apply.interrupted_journal",
+        "Interrupted after changing {done} of {total} files, see {path}",
+        "已中断：{total} 个文件中已修改 {done} 个，详见 {path}",
+    ),
+    (
+        "apply.resuming",
+        "Resuming an interrupted apply: {done} files were already changed, see {path}",
+        "继续被中断的应用：已修改 {done} 个文件，详见 {path}",
+    ),
+    (
+        "apply.journal_other_patch",
+        "Ignoring {path}: it belongs to another patch",
+        "忽略 {path}：它属于另一个补丁",
+    ),
+    ("apply.swapping", "Swapping staged directory into place...", "正在用暂存目录替换原目录..."),
+    ("apply.done", "Patch applied successfully!", "补丁应用成功！"),
+    ("apply.summary", "Summary:", "摘要："),
+    ("apply.count_added", "  Added files: {count}", "  新增文件：{count}"),
+    ("apply.count_modified", "  Modified files (full): {count}", "  修改文件（完整）：{count}"),
+    ("apply.count_modified_diff", "  Modified files (diff): {count}", "  修改文件（差异）：{count}"),
+    ("apply.count_modified_delta", "  Modified files (binary delta): {count}", "  修改文件（二进制增量）：{count}"),
+    ("apply.count_removed", "  Removed files: {count}", "  删除文件：{count}"),
+    ("apply.count_symlinks", "  Symbolic links: {count}", "  符号链接：{count}"),
+    ("apply.count_permissions", "  Permission changes: {count}", "  权限变更：{count}"),
+    // Other commands
+    ("diff.no_differences", "No differences.", "没有差异。"),
+    ("bundle.writing", "Writing {format} bundle: {path}", "正在写入 {format} 包：{path}"),
+    (
+        "bundle.saved",
+        "Bundle saved to {path}: {files} files ({size}) in {archive_size}, {removed} paths to remove",
+        "包已保存到 {path}：{files} 个文件（{size}），共 {archive_size}，{removed} 个路径待删除",
+    ),
+    (
+        "convert.done",
+        "Converted {input} ({from}) to {output} ({to}): {entries} content files, {size}",
+        "已将 {input}（{from}）转换为 {output}（{to}）：{entries} 个内容文件，{size}",
+    ),
+    ("compare_reports.identical", "Reports are identical ({count} entries).", "报告相同（{count} 个条目）。"),
+    ("compare_reports.only_in", "Entries only in {path}: {count}", "仅在 {path} 中的条目：{count}"),
+    ("compare_reports.differ", "Reports differ", "报告不同"),
+    (
+        "verify_record.valid",
+        "Signature valid ({algorithm}): patch {patch} applied to {path}, verification {result}",
+        "签名有效（{algorithm}）：补丁 {patch} 已应用到 {path}，验证{result}",
+    ),
+    ("verify_record.passed", "passed", "通过"),
+    ("verify_record.failed", "failed", "未通过"),
+    ("verify_record.rolled_back", ", changes rolled back", "，更改已回滚"),
+    (
+        "apply_report.done",
+        "Applied {count} entries: {copied} copied, {patched} patched, {renamed} renamed, {removed} removed ({verified} verified against their hash, {permissions} given their permissions)",
+        "已应用 {count} 个条目：复制 {copied}，修补 {patched}，重命名 {renamed}，删除 {removed}（{verified} 个已按哈希校验，{permissions} 个已设置权限）",
+    ),
+    (
+        "download_plan.saved",
+        "Download plan saved to {path}: {needed} files to download ({size}), {reuse} to reuse, {delete} to delete",
+        "下载计划已保存到 {path}：需下载 {needed} 个文件（{size}），可复用 {reuse} 个，需删除 {delete} 个",
+    ),
+    ("manifest.scanning", "Scanning directory: {path}", "正在扫描目录：{path}"),
+    ("manifest.saved", "Manifest saved to {path}: {count} files ({size})", "清单已保存到 {path}：{count} 个文件（{size}）"),
+    (
+        "cache.gc_done",
+        "Removed {objects} objects ({size}) and {releases} releases from {path}",
+        "已从 {path} 删除 {objects} 个对象（{size}）和 {releases} 个版本",
+    ),
+    ("cache.stats_title", "Object cache: {path}", "对象缓存：{path}"),
+    ("cache.stats_objects", "  Objects: {count} ({size})", "  对象：{count}（{size}）"),
+    ("cache.stats_blobs", "    blobs: {count} ({size})", "    未压缩：{count}（{size}）"),
+    ("cache.stats_packed", "    packed {compression}: {count} ({size})", "    {compression} 压缩：{count}（{size}）"),
+    ("cache.stats_releases", "  Releases: {count} (newest: {name})", "  版本：{count}（最新：{name}）"),
+    ("cache.stats_no_releases", "  Releases: 0", "  版本：0"),
+    ("cache.stats_hit_rate", "  Hit rate ({label}): {rate}% ({hits} of {lookups})", "  命中率（{label}）：{rate}%（{lookups} 次中 {hits} 次）"),
+    ("cache.stats_no_lookups", "  Hit rate ({label}): no lookups yet", "  命中率（{label}）：尚无查询"),
+    ("hash_cache.title", "Hash cache: {path}", "哈希缓存：{path}"),
+    ("hash_cache.empty", "No remembered hashes.", "没有记住的哈希。"),
+    ("hash_cache.entry", "  {root} ({algorithm}): {files} files, {size}", "  {root}（{algorithm}）：{files} 个文件，{size}"),
+    ("hash_cache.cleared", "Forgot the hashes of {path} ({count} cache files removed)", "已忘记 {path} 的哈希（删除了 {count} 个缓存文件）"),
+    (
+        "hash_cache.cleared_all",
+        "Forgot all remembered hashes ({count} cache files removed from {path})",
+        "已忘记所有记住的哈希（从 {path} 删除了 {count} 个缓存文件）",
+    ),
+    ("blocks.hashes_saved", "Block hashes of {file} saved to {path}: {count} blocks of {size}", "{file} 的块哈希已保存到 {path}：{count} 个块，每块 {size}"),
+    (
+        "blocks.delta_saved",
+        "{changed} of {count} blocks changed ({size}), block delta saved to {path} ({delta_size})",
+        "{count} 个块中有 {changed} 个已更改（{size}），块增量已保存到 {path}（{delta_size}）",
+    ),
+    ("blocks.applied", "Wrote {count} blocks ({size}) to {path}, now {file_size}", "已向 {path} 写入 {count} 个块（{size}），文件现为 {file_size}"),
+    (
+        "stats.off",
+        "Usage statistics are off; set {var} or the stats_file setting to a file to keep them.",
+        "使用统计已关闭；将 {var} 或 stats_file 设置为一个文件即可记录。",
+    ),
+    ("stats.none_yet", "No usage statistics recorded yet in {path}", "{path} 中尚未记录使用统计"),
+    ("stats.no_runs", "No runs recorded.", "没有记录的运行。"),
+    ("stats.runs", "Runs: {count} ({failed} failed)", "运行：{count} 次（{failed} 次失败）"),
+    ("stats.column_started", "Started (UTC)", "开始时间 (UTC)"),
+    ("stats.column_command", "Command", "命令"),
+    ("stats.column_duration", "Duration", "耗时"),
+    ("stats.column_files", "Files", "文件数"),
+    ("stats.column_size", "Size", "大小"),
+    ("stats.column_status", "Status", "状态"),
+    ("stats.status_ok", "ok", "成功"),
+    ("stats.status_failed", "failed", "失败"),
+    ("stats.trends", "Trends (mean of the older half of successful runs -> the newer half):", "趋势（成功运行中较早一半的平均值 -> 较新一半）："),
+    (
+        "stats.trend",
+        "  {command}: {before} -> {after}{change}, trees {bytes_before} -> {bytes_after}{bytes_change}",
+        "  {command}：{before} -> {after}{change}，目录树 {bytes_before} -> {bytes_after}{bytes_change}",
+    ),
+    ("stats.record_failed", "Warning: Failed to record usage statistics: {error}", "警告：无法记录使用统计：{error}"),
+    // Explain and config
+    ("explain.not_a_file", "Note: {path} is not a file, the size limit was not checked", "注意：{path} 不是文件，未检查大小限制"),
+    ("explain.excluded", "excluded", "已排除"),
+    ("explain.included", "included", "已包含"),
+    ("explain.by_rule", "{verdict} by rule `{rule}`", "{verdict}，依据规则 `{rule}`"),
+    ("explain.matching", " matching {path}", "，匹配 {path}"),
+    ("explain.no_rule", "{verdict}, no rule matches", "{verdict}，没有匹配的规则"),
+    (
+        "explain.hidden",
+        "excluded as hidden (`{name}` starts with a dot; see --include-hidden)",
+        "已排除：隐藏文件（`{name}` 以点开头；参见 --include-hidden）",
+    ),
+    ("explain.temporary", "excluded as a temporary file of diffpatch (`{name}`)", "已排除：diffpatch 的临时文件（`{name}`）"),
+    ("explain.extension", "excluded by --exclude-extensions {extension}", "已被 --exclude-extensions {extension} 排除"),
+    ("explain.dir", "excluded by --exclude-dirs {dir}", "已被 --exclude-dirs {dir} 排除"),
+    ("explain.not_included", "excluded, matches no --include-pattern", "已排除：不匹配任何 --include-pattern"),
+    ("explain.too_large", "excluded by --max-file-size {max} (file is {size})", "已被 --max-file-size {max} 排除（文件大小为 {size}）"),
+    ("config.found", "found", "已找到"),
+    ("config.not_found", "not found", "未找到"),
+    ("config.no_file", "No configuration file found; built-in defaults apply.", "未找到配置文件；使用内置默认设置。"),
+    ("config.settings", "Settings:", "设置："),
+    // Compression benchmark and timings
+    ("benchmark.no_files", "The patch contains no full files, nothing to benchmark.", "补丁中没有完整文件，无需进行基准测试。"),
+    (
+        "benchmark.running",
+        "Benchmarking compression on {files} of {total} files ({size} sample)...",
+        "正在对 {total} 个文件中的 {files} 个进行压缩基准测试（样本 {size}）...",
+    ),
+    ("benchmark.column_setting", "Setting", "设置"),
+    ("benchmark.column_size", "Size", "大小"),
+    ("benchmark.column_ratio", "Ratio", "压缩率"),
+    ("benchmark.column_time", "Time", "时间"),
+    ("benchmark.column_speed", "Speed", "速度"),
+    ("benchmark.hint", "Use --compression <METHOD[:LEVEL]> to pick a setting.", "使用 --compression <METHOD[:LEVEL]> 选择设置。"),
+    ("timings.title", "Timings:", "耗时："),
+    ("timings.column_phase", "Phase", "阶段"),
+    ("timings.column_wall", "Wall time", "实际耗时"),
+    ("timings.column_threads", "Threads", "线程数"),
+    ("timings.column_cpu", "CPU/thread", "CPU/线程"),
+    (
+        "timings.hint",
+        "CPU/thread near 100% means CPU bound; low values mean the threads mostly waited for I/O.",
+        "CPU/线程接近 100% 表示受 CPU 限制；数值较低表示线程主要在等待 I/O。",
+    ),
+    // Estimate
+    ("estimate.start", "Estimating (walking without hashing)...", "正在估算（仅遍历，不计算哈希）..."),
+    ("estimate.source", "Source", "源"),
+    ("estimate.target", "Target", "目标"),
+    ("estimate.side", "  {label}: {files} files, {size} ({path})", "  {label}：{files} 个文件，{size}（{path}）"),
+    ("estimate.source_error", "  Source: {error}", "  源：{error}"),
+    (
+        "estimate.source_ssh",
+        "  Source: remote directory, listed and hashed over SSH ({path})",
+        "  源：远程目录，通过 SSH 列出并计算哈希（{path}）",
+    ),
+    ("estimate.source_manifest", "  Source: patch manifest, nothing to hash ({path})", "  源：补丁清单，无需计算哈希（{path}）"),
+    ("estimate.total", "  Total: {files} files, {size}", "  总计：{files} 个文件，{size}"),
+    ("estimate.throughput", "  Measured throughput: {rate}/s (I/O threads: {threads})", "  实测吞吐量：{rate}/s（I/O 线程：{threads}）"),
+    ("estimate.time", "  Estimated hashing time: ~{duration}", "  预计哈希耗时：约 {duration}"),
+    ("estimate.no_throughput", "  Throughput could not be measured (no readable files in target)", "  无法测量吞吐量（目标中没有可读文件）"),
+    // Scanning
+    ("scan.verifying_unchanged", "Verifying {count} unchanged files byte by byte...", "正在逐字节校验 {count} 个未更改的文件..."),
+    ("scan.remote", "Scanning remote directory: {path}", "正在扫描远程目录：{path}"),
+    ("scan.downloading_manifest", "Downloading manifest: {url}", "正在下载清单：{url}"),
+    ("scan.source_dir", "Scanning source directory: {path}", "正在扫描源目录：{path}"),
+    ("scan.source", "Scanning source: {name}", "正在扫描源：{name}"),
+    ("scan.reading_manifest", "Reading manifest: {path}", "正在读取清单：{path}"),
+    ("scan.reading_patch_manifest", "Reading target manifest from patch: {path}", "正在从补丁读取目标清单：{path}"),
+    ("scan.extension_renames", "Matched {count} files renamed with a different extension", "匹配到 {count} 个改了扩展名的重命名文件"),
+    ("scan.renames", "Matched {count} moved or renamed files", "匹配到 {count} 个移动或重命名的文件"),
+    ("scan.target_dir", "Scanning target directory: {path}", "正在扫描目标目录：{path}"),
+    ("scan.target", "Scanning target: {name}", "正在扫描目标：{name}"),
+    ("scan.cached_comparison", "Reusing cached comparison {key} from {path}", "正在复用 {path} 中缓存的比较结果 {key}"),
+    ("scan.reused_clone_hashes", "Reused the hashes of {count} files cloned from the source", "已复用 {count} 个从源克隆的文件的哈希"),
+    ("scan.reusing_clone_hashes", "Reusing the hashes of {count} files cloned from the source", "正在复用 {count} 个从源克隆的文件的哈希"),
+    ("scan.listing_source", "Listing source directory: {path}", "正在列出源目录：{path}"),
+    ("scan.listing_target", "Listing target directory: {path}", "正在列出目标目录：{path}"),
+    (
+        "scan.hashing_deferred",
+        "Hashing {count} files ({skipped} files only on one side left for later)...",
+        "正在计算 {count} 个文件的哈希（仅存在于一侧的 {skipped} 个文件留待稍后处理）...",
+    ),
+    ("scan.hashing_added", "Hashing {count} added files...", "正在计算 {count} 个新增文件的哈希..."),
+    ("scan.hashing_changed", "Hashing {count} changed target files...", "正在计算 {count} 个已更改目标文件的哈希..."),
+    (
+        "scan.build_stamps",
+        "Comparing {count} changed executables without build stamps...",
+        "正在忽略构建时间戳比较 {count} 个已更改的可执行文件...",
+    ),
+    (
+        "scan.stamp_only",
+        "{count} executables differ only in build stamps and are treated as unchanged",
+        "{count} 个可执行文件仅构建时间戳不同，视为未更改",
+    ),
+    ("scan.comparing_bytes", "Comparing {count} files byte by byte...", "正在逐字节比较 {count} 个文件..."),
+    (
+        "scan.touched",
+        "{same} files have the same size and modification time on both sides, hashing {count} touched files of the same size...",
+        "{same} 个文件两侧大小和修改时间相同，正在计算 {count} 个大小相同但修改时间不同的文件的哈希...",
+    ),
+    ("scan.collisions", "CRITICAL: {count} files have equal hashes but different content:", "严重：{count} 个文件哈希相同但内容不同："),
+    ("scan.archives", "Comparing members of {count} changed archives...", "正在比较 {count} 个已更改归档的成员..."),
+    ("image.iso9660_fallback", "Note: reading the ISO 9660 side of {path} ({error})", "注意：改为读取 {path} 的 ISO 9660 部分（{error}）"),
+    ("hash_cache.ignoring", "Warning: Ignoring hash cache: {error}", "警告：忽略哈希缓存：{error}"),
+    (
+        "hash_cache.reused",
+        "Hash cache: {reused} of {count} files of {path} unchanged since the last scan",
+        "哈希缓存：{path} 的 {count} 个文件中有 {reused} 个自上次扫描以来未更改",
+    ),
+    // Snapshots, watching and block hashes
+    ("snapshot.other_algorithm", "Ignoring scan snapshot with {old} hashes, rescanning with {new}", "忽略使用 {old} 哈希的扫描快照，改用 {new} 重新扫描"),
+    ("snapshot.ignoring", "Warning: Ignoring scan snapshot: {error}", "警告：忽略扫描快照：{error}"),
+    ("snapshot.using_watched", "Using the watched snapshot of {path}", "正在使用 {path} 的监视快照"),
+    (
+        "snapshot.watched_other_options",
+        "The watched snapshot of {path} was made with other hidden-file, link or filter options, scanning the directory",
+        "{path} 的监视快照使用了不同的隐藏文件、链接或过滤选项，将扫描该目录",
+    ),
+    (
+        "snapshot.journal",
+        "Change journal lists {count} changed names since the last scan of {path}",
+        "自上次扫描 {path} 以来，变更日志列出了 {count} 个已更改的名称",
+    ),
+    ("snapshot.scanning", "Scanning {path}...", "正在扫描 {path}..."),
+    ("snapshot.watching", "Watching {path} ({count} files), press Ctrl-C to stop", "正在监视 {path}（{count} 个文件），按 Ctrl-C 停止"),
+    ("snapshot.updated", "Updated {count} changed paths ({files} files)", "已更新 {count} 个已更改的路径（共 {files} 个文件）"),
+    (
+        "watch.diff_watching",
+        "Watching {path} ({count} differences from the source), press Ctrl-C to stop",
+        "正在监视 {path}（与源有 {count} 处差异），按 Ctrl-C 停止",
+    ),
+    ("watch.rescanning", "File system events were lost, rescanning {path}...", "文件系统事件丢失，正在重新扫描 {path}..."),
+    ("watch.stopped", "Stopped watching {path}", "已停止监视 {path}"),
+    ("blocks.ignoring_saved", "Warning: Ignoring saved block hashes: {error}", "警告：忽略已保存的块哈希：{error}"),
+    ("blocks.unchanged", "Block hashes of {path} unchanged since the last run", "{path} 的块哈希自上次运行以来未更改"),
+    ("blocks.resuming", "Resuming hashing of {path} at block {block} of {count}", "从第 {block} 块（共 {count} 块）继续计算 {path} 的哈希"),
+    ("blocks.already_target", "{path} is already the target version", "{path} 已是目标版本"),
+];
+
+/// Look up `key` in the current language and fill in its `{name}` arguments
+///
+/// Unknown keys are returned as is, so a missing entry shows up in the output rather
+/// than failing.
+pub fn translate(key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    let Some(&(_, english, chinese)) = MESSAGES.iter().find(|(k, _, _)| *k == key) else {
+        return key.to_string();
+    };
+    let mut message = match language() {
+        Language::English => english,
+        Language::Chinese => chinese,
+    }
+    .to_string();
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    message
+}
+
+/// Translate a message key, with optional `name = value` arguments
+///
+/// ```ignore
+/// println!("{}", tr!("apply.processing", count = files.len()));
+/// ```
+#[macro_export]
+macro_rules! tr {
+    ($key:expr) => {
+        $crate::i18n::translate($key, &[])
+    };
+    ($key:expr, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::i18n::translate($key, &[$((stringify!($name), &$value as &dyn std::fmt::Display)),+])
+    };
+}
+
+/// Format a count with digit grouping, e.g. `12,345`
+pub fn format_number(value: u64) -> String {
+    let digits = value.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
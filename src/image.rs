@@ -0,0 +1,722 @@
+use crate::vfs::{Vfs, VfsMetadata};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Sector size of optical disc images
+const SECTOR_SIZE: u64 = 2048;
+
+/// First sector of the volume descriptors (ISO 9660) and volume recognition sequence (UDF)
+const FIRST_DESCRIPTOR_SECTOR: u64 = 16;
+
+/// Upper limit on descriptors read, so a corrupt image cannot keep the reader busy
+const MAX_DESCRIPTORS: u64 = 64;
+
+/// Deepest directory nesting followed
+const MAX_DEPTH: usize = 64;
+
+/// Largest directory read, so a corrupt size cannot make the reader allocate gigabytes;
+/// far above what even directories with hundreds of thousands of entries take
+const MAX_DIRECTORY_SIZE: u64 = 64 << 20;
+
+/// File system a disk image is read through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// ISO 9660 with Rock Ridge names (most Linux images)
+    RockRidge,
+    /// ISO 9660 with Joliet names (most Windows-made CDs)
+    Joliet,
+    /// Plain ISO 9660; names are upper case and may be shortened
+    Iso9660,
+    /// UDF (DVD images, Windows installation media)
+    Udf,
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ImageFormat::RockRidge => "ISO 9660 with Rock Ridge",
+            ImageFormat::Joliet => "ISO 9660 with Joliet",
+            ImageFormat::Iso9660 => "ISO 9660",
+            ImageFormat::Udf => "UDF",
+        })
+    }
+}
+
+/// A piece of the contents of a file in an image
+#[derive(Debug, Clone)]
+enum Extent {
+    /// `length` bytes stored at byte `offset` of the image
+    Stored { offset: u64, length: u64 },
+    /// `length` zero bytes that take no space in the image
+    Zeros { length: u64 },
+    /// Contents kept inside the file's own descriptor (small UDF files)
+    Inline(Vec<u8>),
+}
+
+impl Extent {
+    fn len(&self) -> u64 {
+        match self {
+            Extent::Stored { length, .. } | Extent::Zeros { length } => *length,
+            Extent::Inline(data) => data.len() as u64,
+        }
+    }
+}
+
+/// A regular file inside a disk image
+#[derive(Debug, Clone)]
+pub struct ImageFile {
+    /// Path relative to the root of the image
+    pub path: PathBuf,
+    pub size: u64,
+    extents: Vec<Extent>,
+}
+
+/// Disk image (`.iso`, `.img`) read as a directory tree, without mounting it
+#[derive(Debug)]
+pub struct DiskImage {
+    path: PathBuf,
+    format: ImageFormat,
+    files: Vec<ImageFile>,
+}
+
+impl DiskImage {
+    /// Read the directory tree of the image at `path`
+    ///
+    /// Images carrying both file systems are read through UDF, falling back to ISO 9660
+    /// if the UDF side uses features not supported here (e.g. metadata partitions).
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open disk image: {}", path.display()))?;
+        let (iso9660, udf) = detect(&mut file)?;
+        let read = if udf {
+            match read_udf(&mut file) {
+                Ok(files) => Ok((ImageFormat::Udf, files)),
+                Err(e) if iso9660 => {
+                    println!("{}", crate::tr!("image.iso9660_fallback", path = path.display(), error = format!("{:#}", e)));
+                    read_iso9660(&mut file)
+                }
+                Err(e) => Err(e),
+            }
+        } else if iso9660 {
+            read_iso9660(&mut file)
+        } else {
+            Err(anyhow!("Not an ISO 9660 or UDF image"))
+        };
+        let (format, mut files) = read.with_context(|| format!("Failed to read disk image: {}", path.display()))?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(DiskImage { path: path.to_path_buf(), format, files })
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Regular files of the image, sorted by path
+    pub fn files(&self) -> &[ImageFile] {
+        &self.files
+    }
+
+    /// Read the contents of `file`, which must belong to this image
+    ///
+    /// Every reader has its own handle on the image, so files can be read in parallel.
+    pub fn open_file(&self, file: &ImageFile) -> Result<ImageFileReader> {
+        let image = File::open(&self.path).with_context(|| format!("Failed to open disk image: {}", self.path.display()))?;
+        Ok(ImageFileReader { image, extents: file.extents.clone(), index: 0, position: 0, remaining: file.size })
+    }
+
+    /// The file at `path` (relative to the root of the image)
+    pub fn find(&self, path: &Path) -> Result<&ImageFile> {
+        self.files
+            .binary_search_by(|file| file.path.as_path().cmp(path))
+            .map(|index| &self.files[index])
+            .map_err(|_| anyhow!("No such file in disk image: {}", path.display()))
+    }
+}
+
+impl Vfs for DiskImage {
+    fn name(&self) -> String {
+        format!("{} ({})", self.path.display(), self.format)
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.files.iter().map(|file| file.path.clone()).collect())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata> {
+        Ok(VfsMetadata { size: self.find(path)?.size, modified: None, mode: None })
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(self.open_file(self.find(path)?)?))
+    }
+}
+
+/// Whether `path` is a file holding an ISO 9660 or UDF image
+pub fn is_disk_image(path: &Path) -> bool {
+    path.is_file() && File::open(path).ok().and_then(|mut file| detect(&mut file).ok()).is_some_and(|(iso, udf)| iso || udf)
+}
+
+/// Contents of one file of a `DiskImage`
+#[derive(Debug)]
+pub struct ImageFileReader {
+    image: File,
+    extents: Vec<Extent>,
+    index: usize,    // Extent being read
+    position: u64,   // Bytes of it read so far
+    remaining: u64,  // Bytes of the file left to read
+}
+
+impl Read for ImageFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining > 0 {
+            let Some(extent) = self.extents.get(self.index) else {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file extends past its recorded extents"));
+            };
+            let left = extent.len() - self.position;
+            if left == 0 {
+                self.index += 1;
+                self.position = 0;
+                continue;
+            }
+            let want = (buf.len() as u64).min(left).min(self.remaining) as usize;
+            let read = match extent {
+                Extent::Stored { offset, .. } => {
+                    self.image.seek(SeekFrom::Start(offset + self.position))?;
+                    let read = self.image.read(&mut buf[..want])?;
+                    if read == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file extends past the end of the image"));
+                    }
+                    read
+                }
+                Extent::Zeros { .. } => {
+                    buf[..want].fill(0);
+                    want
+                }
+                Extent::Inline(data) => {
+                    let start = self.position as usize;
+                    buf[..want].copy_from_slice(&data[start..start + want]);
+                    want
+                }
+            };
+            self.position += read as u64;
+            self.remaining -= read as u64;
+            return Ok(read);
+        }
+        Ok(0)
+    }
+}
+
+fn corrupt(what: &str) -> anyhow::Error {
+    anyhow!("Corrupt disk image: {}", what)
+}
+
+fn read_at(file: &mut File, offset: u64, length: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0; length];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data).map_err(|_| corrupt("descriptor past the end of the image"))?;
+    Ok(data)
+}
+
+/// The `N` bytes at `offset`, or an error if the structure is cut short
+fn bytes_at<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N]> {
+    data.get(offset..offset.saturating_add(N))
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| corrupt("field past the end of its structure"))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    bytes_at(data, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32> {
+    bytes_at(data, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64> {
+    bytes_at(data, offset).map(u64::from_le_bytes)
+}
+
+/// Which file systems the image carries: (ISO 9660, UDF)
+fn detect(file: &mut File) -> Result<(bool, bool)> {
+    let (mut iso9660, mut udf) = (false, false);
+    for sector in FIRST_DESCRIPTOR_SECTOR..FIRST_DESCRIPTOR_SECTOR + MAX_DESCRIPTORS {
+        let mut header = [0u8; 6];
+        if file.seek(SeekFrom::Start(sector * SECTOR_SIZE)).is_err() || file.read_exact(&mut header).is_err() {
+            break;
+        }
+        match &header[1..6] {
+            b"CD001" => iso9660 = true,
+            b"NSR02" | b"NSR03" => udf = true,
+            // The UDF recognition sequence may follow the ISO 9660 terminator
+            b"BEA01" | b"BOOT2" | b"CDW02" => {}
+            _ => break,
+        }
+    }
+    Ok((iso9660, udf))
+}
+
+// ---- ISO 9660 ----
+
+/// How names in ISO 9660 directory records are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IsoNames {
+    RockRidge,
+    Joliet,
+    Plain,
+}
+
+/// A parsed ISO 9660 directory record
+struct DirRecord<'a> {
+    extent: u32,
+    length: u32,
+    flags: u8,
+    name: &'a [u8],
+    system_use: &'a [u8],
+}
+
+impl<'a> DirRecord<'a> {
+    fn parse(record: &'a [u8]) -> Result<Self> {
+        if record.len() < 34 {
+            return Err(corrupt("directory record too short"));
+        }
+        let name_len = record[32] as usize;
+        let name_end = 33 + name_len;
+        if name_end > record.len() {
+            return Err(corrupt("directory record name too long"));
+        }
+        // A padding byte keeps the system use area at an even offset
+        let system_use_start = (name_end + (name_len + 1) % 2).min(record.len());
+        Ok(DirRecord {
+            extent: u32_at(record, 2)?,
+            length: u32_at(record, 10)?,
+            flags: record[25],
+            name: &record[33..name_end],
+            system_use: &record[system_use_start..],
+        })
+    }
+
+    fn is_directory(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    /// The file continues in the next record
+    fn is_multi_extent(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    /// `.` and `..` entries
+    fn is_self_or_parent(&self) -> bool {
+        self.name == [0] || self.name == [1]
+    }
+
+    fn decoded_name(&self, names: IsoNames) -> String {
+        if names == IsoNames::RockRidge
+            && let Some(name) = rock_ridge_name(self.system_use)
+        {
+            return name;
+        }
+        let name = match names {
+            IsoNames::Joliet => {
+                let units: Vec<u16> = self.name.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+                String::from_utf16_lossy(&units)
+            }
+            _ => String::from_utf8_lossy(self.name).into_owned(),
+        };
+        // Strip the version (`;1`) and the dot of names without extension
+        let name = name.split_once(';').map_or(name.as_str(), |(name, _)| name);
+        let name = if self.is_directory() { name } else { name.strip_suffix('.').unwrap_or(name) };
+        name.to_string()
+    }
+}
+
+/// Name from the Rock Ridge `NM` entries of a system use area
+fn rock_ridge_name(mut area: &[u8]) -> Option<String> {
+    let mut name = Vec::new();
+    let mut found = false;
+    while area.len() >= 4 {
+        let length = area[2] as usize;
+        if length < 4 || length > area.len() {
+            break;
+        }
+        let (entry, rest) = area.split_at(length);
+        if &entry[..2] == b"NM" && length >= 5 {
+            let flags = entry[4];
+            if flags & 0x06 != 0 {
+                return None; // `.` or `..`
+            }
+            name.extend_from_slice(&entry[5..]);
+            found = true;
+            if flags & 0x01 == 0 {
+                break; // No continuation
+            }
+        } else if &entry[..2] == b"ST" {
+            break;
+        }
+        area = rest;
+    }
+    found.then(|| String::from_utf8_lossy(&name).into_owned())
+}
+
+fn read_iso9660(file: &mut File) -> Result<(ImageFormat, Vec<ImageFile>)> {
+    let mut primary_root = None;
+    let mut joliet_root = None;
+    for sector in FIRST_DESCRIPTOR_SECTOR..FIRST_DESCRIPTOR_SECTOR + MAX_DESCRIPTORS {
+        let descriptor = read_at(file, sector * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+        if descriptor[1..6] != *b"CD001" {
+            break;
+        }
+        match descriptor[0] {
+            1 => primary_root = Some(descriptor[156..190].to_vec()),
+            // Supplementary descriptor with a UCS-2 escape sequence
+            2 if descriptor[88..90] == *b"%/" && matches!(descriptor[90], b'@' | b'C' | b'E') => {
+                joliet_root = Some(descriptor[156..190].to_vec())
+            }
+            255 => break,
+            _ => {}
+        }
+    }
+    let primary_root = primary_root.ok_or_else(|| corrupt("no primary volume descriptor"))?;
+
+    // Rock Ridge images announce themselves with an `SP` entry on the root's `.` record
+    let root = DirRecord::parse(&primary_root)?;
+    let root_data = read_at(file, root.extent as u64 * SECTOR_SIZE, (root.length as usize).min(SECTOR_SIZE as usize))?;
+    let rock_ridge = root_data.first().is_some_and(|&length| {
+        DirRecord::parse(&root_data[..(length as usize).min(root_data.len())])
+            .is_ok_and(|dot| dot.system_use.len() >= 7 && dot.system_use[..2] == *b"SP" && dot.system_use[4..6] == [0xBE, 0xEF])
+    });
+
+    let (format, names, root) = match (rock_ridge, joliet_root) {
+        (true, _) => (ImageFormat::RockRidge, IsoNames::RockRidge, primary_root),
+        (false, Some(joliet_root)) => (ImageFormat::Joliet, IsoNames::Joliet, joliet_root),
+        (false, None) => (ImageFormat::Iso9660, IsoNames::Plain, primary_root),
+    };
+    let root = DirRecord::parse(&root)?;
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    walk_iso_directory(file, root.extent, root.length, names, &PathBuf::new(), 0, &mut visited, &mut files)?;
+    Ok((format, files))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn walk_iso_directory(
+    file: &mut File,
+    extent: u32,
+    length: u32,
+    names: IsoNames,
+    dir: &Path,
+    depth: usize,
+    visited: &mut HashSet<u32>,
+    files: &mut Vec<ImageFile>,
+) -> Result<()> {
+    if depth > MAX_DEPTH || !visited.insert(extent) {
+        return Err(corrupt("directory loop"));
+    }
+    if length as u64 > MAX_DIRECTORY_SIZE {
+        return Err(corrupt("directory too large"));
+    }
+    let data = read_at(file, extent as u64 * SECTOR_SIZE, length as usize)?;
+    let mut subdirs = Vec::new();
+    // Parts of a multi-extent file seen so far
+    let mut pending: Option<ImageFile> = None;
+    let mut position = 0;
+    while position < data.len() {
+        let record_len = data[position] as usize;
+        if record_len == 0 {
+            // Records do not cross sectors; the rest of this one is padding
+            position = (position / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+            continue;
+        }
+        let record = DirRecord::parse(data.get(position..position + record_len).ok_or_else(|| corrupt("directory record past its directory"))?)?;
+        position += record_len;
+        if record.is_self_or_parent() {
+            continue;
+        }
+        let path = dir.join(record.decoded_name(names));
+        if record.is_directory() {
+            subdirs.push((record.extent, record.length, path));
+            continue;
+        }
+        let extent = Extent::Stored { offset: record.extent as u64 * SECTOR_SIZE, length: record.length as u64 };
+        let mut entry = match pending.take() {
+            Some(mut entry) if entry.path == path => {
+                entry.size += record.length as u64;
+                entry.extents.push(extent);
+                entry
+            }
+            _ => ImageFile { path, size: record.length as u64, extents: vec![extent] },
+        };
+        if record.is_multi_extent() {
+            pending = Some(entry);
+        } else {
+            entry.extents.shrink_to_fit();
+            files.push(entry);
+        }
+    }
+    files.extend(pending);
+    for (extent, length, path) in subdirs {
+        walk_iso_directory(file, extent, length, names, &path, depth + 1, visited, files)?;
+    }
+    Ok(())
+}
+
+// ---- UDF ----
+
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+/// Location of a block: (partition reference, logical block number)
+type BlockAddress = (u16, u32);
+
+struct Udf<'a> {
+    file: &'a mut File,
+    /// First sector of each partition, by partition reference number
+    partition_starts: Vec<u32>,
+    visited: HashSet<BlockAddress>,
+}
+
+/// What a UDF file entry describes
+struct FileEntry {
+    is_directory: bool,
+    size: u64,
+    extents: Vec<Extent>,
+}
+
+fn tag_id(data: &[u8]) -> Result<u16> {
+    u16_at(data, 0)
+}
+
+fn read_udf(file: &mut File) -> Result<Vec<ImageFile>> {
+    let anchor = read_at(file, 256 * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+    if tag_id(&anchor)? != TAG_ANCHOR {
+        return Err(corrupt("no UDF anchor volume descriptor at sector 256"));
+    }
+    let (sequence_length, sequence_start) = (u32_at(&anchor, 16)? as u64, u32_at(&anchor, 20)? as u64);
+
+    let mut partitions: HashMap<u16, u32> = HashMap::new();
+    let mut logical_volume = None;
+    for sector in sequence_start..sequence_start + (sequence_length / SECTOR_SIZE).min(MAX_DESCRIPTORS) {
+        let descriptor = read_at(file, sector * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+        match tag_id(&descriptor)? {
+            TAG_PARTITION => {
+                partitions.insert(u16_at(&descriptor, 22)?, u32_at(&descriptor, 188)?);
+            }
+            TAG_LOGICAL_VOLUME => logical_volume = Some(descriptor),
+            TAG_TERMINATING => break,
+            _ => {}
+        }
+    }
+    let volume = logical_volume.ok_or_else(|| corrupt("no UDF logical volume descriptor"))?;
+    let block_size = u32_at(&volume, 212)?;
+    if block_size as u64 != SECTOR_SIZE {
+        return Err(anyhow!("UDF block size {} is not supported", block_size));
+    }
+
+    // Partition maps turn partition reference numbers into partitions
+    let map_count = u32_at(&volume, 268)? as usize;
+    let maps = volume.get(440..440usize.saturating_add(u32_at(&volume, 264)? as usize)).ok_or_else(|| corrupt("UDF partition maps"))?;
+    let mut partition_starts = Vec::new();
+    let mut position = 0;
+    for _ in 0..map_count {
+        let [map_type, map_len] = bytes_at(maps, position).map_err(|_| corrupt("UDF partition maps"))?;
+        if map_type != 1 {
+            return Err(anyhow!("UDF virtual, sparable and metadata partitions are not supported"));
+        }
+        let number = u16_at(maps, position + 4)?;
+        let map_len = map_len as usize;
+        partition_starts.push(*partitions.get(&number).ok_or_else(|| corrupt("UDF partition map names a missing partition"))?);
+        position += map_len.max(6);
+    }
+
+    let mut udf = Udf { file, partition_starts, visited: HashSet::new() };
+    let file_set_address = (u16_at(&volume, 256)?, u32_at(&volume, 252)?);
+    let file_set = udf.read_block(file_set_address)?;
+    if tag_id(&file_set)? != TAG_FILE_SET {
+        return Err(corrupt("no UDF file set descriptor"));
+    }
+    let root = (u16_at(&file_set, 408)?, u32_at(&file_set, 404)?);
+    let mut files = Vec::new();
+    udf.walk_directory(root, &PathBuf::new(), 0, &mut files)?;
+    Ok(files)
+}
+
+impl Udf<'_> {
+    fn offset(&self, (partition, block): BlockAddress) -> Result<u64> {
+        let start = self.partition_starts.get(partition as usize).ok_or_else(|| corrupt("UDF partition reference"))?;
+        Ok((*start as u64 + block as u64) * SECTOR_SIZE)
+    }
+
+    fn read_block(&mut self, address: BlockAddress) -> Result<Vec<u8>> {
+        let offset = self.offset(address)?;
+        read_at(self.file, offset, SECTOR_SIZE as usize)
+    }
+
+    fn read_file_entry(&mut self, address: BlockAddress) -> Result<FileEntry> {
+        let entry = self.read_block(address)?;
+        let (ea_len_at, descriptors_at) = match tag_id(&entry)? {
+            TAG_FILE_ENTRY => (168, 176usize),
+            TAG_EXTENDED_FILE_ENTRY => (208, 216),
+            _ => return Err(corrupt("UDF file entry")),
+        };
+        let is_directory = entry[16 + 11] == 4;
+        let allocation = u16_at(&entry, 16 + 18)? & 0x07;
+        let size = u64_at(&entry, 56)?;
+        let start = descriptors_at.saturating_add(u32_at(&entry, ea_len_at)? as usize);
+        let descriptors = entry
+            .get(start..start.saturating_add(u32_at(&entry, ea_len_at + 4)? as usize))
+            .ok_or_else(|| corrupt("UDF allocation descriptors"))?;
+
+        let mut extents = Vec::new();
+        let mut push = |udf: &Self, length: u32, address: BlockAddress| -> Result<()> {
+            let (kind, length) = (length >> 30, (length & 0x3FFF_FFFF) as u64);
+            match kind {
+                0 => extents.push(Extent::Stored { offset: udf.offset(address)?, length }),
+                1 | 2 => extents.push(Extent::Zeros { length }),
+                _ => return Err(anyhow!("UDF files with continued allocation descriptors are not supported")),
+            }
+            Ok(())
+        };
+        match allocation {
+            // Short descriptors, in the partition of the file entry
+            0 => {
+                for ad in descriptors.chunks_exact(8) {
+                    push(self, u32_at(ad, 0)?, (address.0, u32_at(ad, 4)?))?;
+                }
+            }
+            // Long descriptors
+            1 => {
+                for ad in descriptors.chunks_exact(16) {
+                    push(self, u32_at(ad, 0)?, (u16_at(ad, 8)?, u32_at(ad, 4)?))?;
+                }
+            }
+            3 => extents.push(Extent::Inline(descriptors.to_vec())),
+            _ => return Err(anyhow!("UDF extended allocation descriptors are not supported")),
+        }
+        Ok(FileEntry { is_directory, size, extents })
+    }
+
+    fn walk_directory(&mut self, address: BlockAddress, dir: &Path, depth: usize, files: &mut Vec<ImageFile>) -> Result<()> {
+        if depth > MAX_DEPTH || !self.visited.insert(address) {
+            return Err(corrupt("directory loop"));
+        }
+        let entry = self.read_file_entry(address)?;
+        if entry.size > MAX_DIRECTORY_SIZE {
+            return Err(corrupt("UDF directory too large"));
+        }
+        let mut data = Vec::new();
+        let image = ImageFileReader {
+            image: self.file.try_clone()?,
+            extents: entry.extents,
+            index: 0,
+            position: 0,
+            remaining: entry.size,
+        };
+        image.take(entry.size).read_to_end(&mut data).map_err(|_| corrupt("UDF directory past the end of the image"))?;
+
+        let mut position = 0;
+        while position + 38 <= data.len() {
+            let fid = &data[position..];
+            if tag_id(fid)? != TAG_FILE_IDENTIFIER {
+                return Err(corrupt("UDF file identifier"));
+            }
+            let characteristics = fid[18];
+            let name_len = fid[19] as usize;
+            let implementation_len = u16_at(fid, 36)? as usize;
+            let record_len = (38 + implementation_len + name_len + 3) & !3;
+            let name = fid.get(38 + implementation_len..38 + implementation_len + name_len).ok_or_else(|| corrupt("UDF file name"))?;
+            let child = (u16_at(fid, 28)?, u32_at(fid, 24)?);
+            position += record_len;
+
+            // Parent and deleted entries
+            if characteristics & 0x0C != 0 {
+                continue;
+            }
+            let path = dir.join(osta_name(name)?);
+            if characteristics & 0x02 != 0 {
+                self.walk_directory(child, &path, depth + 1, files)?;
+            } else {
+                let entry = self.read_file_entry(child)?;
+                if !entry.is_directory {
+                    files.push(ImageFile { path, size: entry.size, extents: entry.extents });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode an OSTA compressed Unicode name: 8 bits or UTF-16BE per character
+fn osta_name(name: &[u8]) -> Result<String> {
+    match name.split_first() {
+        Some((8, chars)) => Ok(chars.iter().map(|&c| c as char).collect()),
+        Some((16, chars)) => {
+            let units: Vec<u16> = chars.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            Ok(String::from_utf16_lossy(&units))
+        }
+        _ => Err(corrupt("UDF file name encoding")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::fs;
+
+    /// UDF image whose logical volume lists `map_count` partition maps in a table of
+    /// `map_table_len` bytes
+    fn udf_image(map_count: u32, map_table_len: u32) -> Vec<u8> {
+        let sector = SECTOR_SIZE as usize;
+        let mut image = vec![0u8; 260 * sector];
+        for (index, id) in [b"BEA01", b"NSR02", b"TEA01"].into_iter().enumerate() {
+            image[(16 + index) * sector + 1..][..5].copy_from_slice(id);
+        }
+        let anchor = 256 * sector;
+        image[anchor..][..2].copy_from_slice(&TAG_ANCHOR.to_le_bytes());
+        image[anchor + 16..][..4].copy_from_slice(&(3 * SECTOR_SIZE as u32).to_le_bytes());
+        image[anchor + 20..][..4].copy_from_slice(&257u32.to_le_bytes());
+        let partition = 257 * sector;
+        image[partition..][..2].copy_from_slice(&TAG_PARTITION.to_le_bytes());
+        let volume = 258 * sector;
+        image[volume..][..2].copy_from_slice(&TAG_LOGICAL_VOLUME.to_le_bytes());
+        image[volume + 212..][..4].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        image[volume + 264..][..4].copy_from_slice(&map_table_len.to_le_bytes());
+        image[volume + 268..][..4].copy_from_slice(&map_count.to_le_bytes());
+        image[volume + 440] = 1;
+        image[volume + 441] = 6;
+        image
+    }
+
+    #[test]
+    fn field_past_the_end_is_an_error() {
+        assert_eq!(u16_at(&[1, 2], 0).unwrap(), 0x0201);
+        assert!(u16_at(&[1, 2], 1).is_err());
+        assert!(u32_at(&[0; 4], usize::MAX).is_err());
+        assert!(u64_at(&[0; 7], 0).is_err());
+    }
+
+    #[test]
+    fn more_partition_maps_than_the_table_holds_is_an_error() {
+        let dir = TempDir::new();
+        let path = dir.write("bad.iso", &udf_image(1000, 6));
+        let error = DiskImage::open(&path).unwrap_err();
+        assert!(format!("{:#}", error).contains("UDF partition maps"), "{:#}", error);
+    }
+
+    #[test]
+    fn partition_map_table_past_the_descriptor_is_an_error() {
+        let dir = TempDir::new();
+        let path = dir.path().join("bad.iso");
+        fs::write(&path, udf_image(1, u32::MAX)).unwrap();
+        assert!(DiskImage::open(&path).is_err());
+    }
+}
+
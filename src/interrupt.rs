@@ -0,0 +1,204 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code used when a run is stopped by Ctrl-C (128 + SIGINT, as shells report it)
+pub const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Error returned by long-running operations that stopped because of Ctrl-C
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", crate::tr!("interrupted"))
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Error returned by long-running operations stopped through their `CancelToken`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", crate::tr!("cancelled"))
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Flag that stops the operations it is given to, e.g. from the cancel button of a GUI
+/// or the shutdown of a service
+///
+/// Clones share the flag. Scans, comparisons and applies check it wherever they check
+/// for Ctrl-C: they stop starting new files, finish the ones in progress and fail with
+/// `Cancelled` (an apply first cleans up as on Ctrl-C). What was handed out before stays
+/// valid, so the differences `diff::compare_directories_streaming` passed on and the
+/// files `diff::scan_directory_with` reported are the partial result; the scan also
+/// returns the files it hashed in its error (see `Partial`).
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token set through an existing flag, e.g. one a GUI already keeps
+    pub fn from_flag(flag: Arc<AtomicBool>) -> Self {
+        CancelToken(flag)
+    }
+
+    /// Ask the operations holding this token to stop; there is no way back
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Whether an operation given `token` should stop: Ctrl-C was pressed or the token was
+/// cancelled
+pub fn should_stop(token: Option<&CancelToken>) -> bool {
+    is_interrupted() || token.is_some_and(CancelToken::is_cancelled)
+}
+
+/// Fail with `Interrupted` if Ctrl-C has been pressed, or with `Cancelled` if `token`
+/// was cancelled
+pub fn check_stop(token: Option<&CancelToken>) -> anyhow::Result<()> {
+    check()?;
+    match token.is_some_and(CancelToken::is_cancelled) {
+        true => Err(Cancelled.into()),
+        false => Ok(()),
+    }
+}
+
+/// The error of an operation stopped because `should_stop` returned true
+pub fn stop_error(token: Option<&CancelToken>) -> anyhow::Error {
+    match is_interrupted() || token.is_none() {
+        true => Interrupted.into(),
+        false => Cancelled.into(),
+    }
+}
+
+/// Error of an operation that stopped early, carrying what it had done so far, e.g. the
+/// files a scan hashed before Ctrl-C
+///
+/// It displays as the stop error it wraps, which stays in its chain, so
+/// `is_interruption` and `is_cancellation` see through it; take the result with
+/// `partial_result`.
+#[derive(Debug)]
+pub struct Partial<T> {
+    pub result: T,
+    stop: anyhow::Error,
+}
+
+impl<T> Partial<T> {
+    pub fn new(result: T, stop: anyhow::Error) -> Self {
+        Partial { result, stop }
+    }
+}
+
+impl<T> fmt::Display for Partial<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.stop)
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for Partial<T> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.stop.as_ref())
+    }
+}
+
+/// Partial result of type `T` carried by a stop error, if it has one
+pub fn partial_result<T: fmt::Debug + Send + Sync + 'static>(error: &anyhow::Error) -> Option<&T> {
+    error.downcast_ref::<Partial<T>>().map(|partial| &partial.result)
+}
+
+/// Catch Ctrl-C so running operations can stop cleanly
+///
+/// The first Ctrl-C only sets a flag that workers poll with `is_interrupted`; a second
+/// one terminates the process as usual.
+pub fn install_handler() {
+    platform::install();
+}
+
+/// Whether Ctrl-C has been pressed
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fail with `Interrupted` if Ctrl-C has been pressed
+pub fn check() -> Result<(), Interrupted> {
+    if is_interrupted() { Err(Interrupted) } else { Ok(()) }
+}
+
+/// Whether an error (or one of its causes) is an interruption
+pub fn is_interruption(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<Interrupted>())
+}
+
+/// Whether an error (or one of its causes) is a cancellation through a `CancelToken`
+pub fn is_cancellation(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<Cancelled>())
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::INTERRUPTED;
+    use std::sync::atomic::Ordering;
+
+    extern "C" fn handle_sigint(_signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    pub fn install() {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // Restore the default action after the first signal, so a second Ctrl-C kills
+            action.sa_flags = libc::SA_RESETHAND | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::INTERRUPTED;
+    use std::sync::atomic::Ordering;
+
+    const CTRL_C_EVENT: u32 = 0;
+
+    type HandlerRoutine = unsafe extern "system" fn(u32) -> i32;
+
+    unsafe extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+    }
+
+    unsafe extern "system" fn handle_ctrl(ctrl_type: u32) -> i32 {
+        // Handle only the first Ctrl-C; returning 0 passes later ones to the default handler
+        if ctrl_type == CTRL_C_EVENT && !INTERRUPTED.swap(true, Ordering::SeqCst) { 1 } else { 0 }
+    }
+
+    pub fn install() {
+        // SAFETY: registers a handler that only touches an atomic
+        unsafe {
+            SetConsoleCtrlHandler(Some(handle_ctrl), 1);
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    pub fn install() {}
+}
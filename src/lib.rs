@@ -0,0 +1,71 @@
+//! DiffPatch - compare directories and create executable patches
+
+pub mod apply;
+pub mod binary;
+#[cfg(feature = "serde")]
+pub mod blocks;
+#[cfg(feature = "package")]
+pub mod bundle;
+#[cfg(feature = "package")]
+pub mod cache;
+#[cfg(feature = "package")]
+pub mod compression;
+pub mod condition;
+#[cfg(feature = "package")]
+pub mod container;
+pub mod content;
+pub mod config;
+pub mod delta;
+pub mod diff;
+#[cfg(feature = "serde")]
+pub mod diffcache;
+pub mod digest;
+pub mod filter;
+pub mod fsops;
+pub mod glob;
+#[cfg(feature = "serde")]
+pub mod hashcache;
+pub mod hashes;
+pub mod i18n;
+pub mod image;
+pub mod interrupt;
+pub mod limits;
+#[cfg(feature = "serde")]
+pub mod livediff;
+pub mod lz4;
+pub mod manifest;
+pub mod nonblocking;
+pub mod par;
+#[cfg(feature = "package")]
+pub mod patch;
+pub mod paths;
+pub mod portability;
+pub mod progress;
+#[cfg(feature = "serde")]
+pub mod remote;
+pub mod report;
+pub mod runid;
+pub mod sink;
+#[cfg(feature = "serde")]
+pub mod snapshot;
+pub mod space;
+pub mod ssh;
+pub mod staging;
+pub mod stats;
+pub mod tags;
+pub mod tar;
+pub mod template;
+#[cfg(test)]
+mod testutil;
+pub mod timings;
+pub mod units;
+pub mod utils;
+pub mod vars;
+#[cfg(feature = "serde")]
+pub mod verify;
+pub mod vfs;
+pub mod warning;
+#[cfg(feature = "serde")]
+pub mod watch;
+pub mod workflow;
+pub mod writebehind;
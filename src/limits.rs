@@ -0,0 +1,47 @@
+use crate::diff::ChangeStats;
+use crate::utils;
+
+/// Upper bounds on how much a release may change
+///
+/// A patch replacing most of the tree usually means an accidental full rebuild or a
+/// wrong source directory; checking the report against limits catches that before the
+/// patch is published.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChangeLimits {
+    /// Most files that may change, as a percentage of the files in both trees
+    pub max_changed_percent: Option<f64>,
+    /// Most bytes of added and modified content
+    pub max_changed_bytes: Option<u64>,
+}
+
+impl ChangeLimits {
+    pub fn is_empty(&self) -> bool {
+        self.max_changed_percent.is_none() && self.max_changed_bytes.is_none()
+    }
+
+    /// A description of every limit `stats` goes over
+    pub fn exceeded(&self, stats: &ChangeStats) -> Vec<String> {
+        let mut exceeded = Vec::new();
+        if let Some(limit) = self.max_changed_percent
+            && stats.changed_percent() > limit
+        {
+            exceeded.push(format!(
+                "{} of {} files changed ({:.1}%), more than the limit of {}%",
+                stats.changed_files,
+                stats.total_files,
+                stats.changed_percent(),
+                limit
+            ));
+        }
+        if let Some(limit) = self.max_changed_bytes
+            && stats.changed_bytes > limit
+        {
+            exceeded.push(format!(
+                "{} of added and modified files, more than the limit of {}",
+                utils::format_size(stats.changed_bytes),
+                utils::format_size(limit)
+            ));
+        }
+        exceeded
+    }
+}
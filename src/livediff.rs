@@ -0,0 +1,188 @@
+use crate::diff::{self, DiffOptions, DiffType, FileInfo};
+use crate::snapshot::{self, WATCH_SETTLE_TIME};
+use crate::tr;
+use crate::watch::Watcher;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// A difference between the source and the watched target that appeared, changed or
+/// went away
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LiveChange {
+    /// The file now differs from the source: newly, or in another way than before
+    Changed(DiffType),
+    /// The file matches the source again (relative path)
+    Reverted(PathBuf),
+}
+
+impl LiveChange {
+    pub fn path(&self) -> &Path {
+        match self {
+            LiveChange::Changed(diff) => diff.path(),
+            LiveChange::Reverted(path) => path,
+        }
+    }
+}
+
+/// The differences between fixed source entries and a target tree that keeps changing
+///
+/// Renames are not detected; a moved file is a removed and an added one.
+#[derive(Debug)]
+pub struct LiveDiff<'a> {
+    source: &'a HashMap<PathBuf, FileInfo>,
+    target: HashMap<PathBuf, FileInfo>,
+    diffs: HashMap<PathBuf, DiffType>,
+    ignore_permissions: bool,
+    stale: bool,
+}
+
+impl<'a> LiveDiff<'a> {
+    pub fn new(source: &'a HashMap<PathBuf, FileInfo>, target: HashMap<PathBuf, FileInfo>, options: &DiffOptions) -> Self {
+        let mut live = LiveDiff { source, target, diffs: HashMap::new(), ignore_permissions: options.permissions_ignored(), stale: false };
+        let paths: BTreeSet<PathBuf> = live.source.keys().chain(live.target.keys()).cloned().collect();
+        live.refresh(paths);
+        live
+    }
+
+    /// Current differences, sorted by path
+    pub fn diffs(&self) -> Vec<&DiffType> {
+        let mut diffs: Vec<&DiffType> = self.diffs.values().collect();
+        diffs.sort_by(|a, b| a.path().cmp(b.path()));
+        diffs
+    }
+
+    pub fn target(&self) -> &HashMap<PathBuf, FileInfo> {
+        &self.target
+    }
+
+    /// Whether `update` kept old entries of files it could not hash, so the target is out
+    /// of date until the next `replace_target`
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Re-hash the changed absolute `paths` under `root` (files, or directories with
+    /// everything below them) and return how the differences changed
+    pub fn update(&mut self, root: &Path, options: &DiffOptions, paths: Vec<PathBuf>) -> Vec<LiveChange> {
+        let prefixes: Vec<PathBuf> = paths.iter().filter_map(|path| path.strip_prefix(root).ok()).map(Path::to_path_buf).collect();
+        let under = |target: &HashMap<PathBuf, FileInfo>| -> Vec<PathBuf> {
+            target.keys().filter(|path| prefixes.iter().any(|prefix| path.starts_with(prefix))).cloned().collect()
+        };
+        let mut affected: BTreeSet<PathBuf> = under(&self.target).into_iter().collect();
+        let update = snapshot::update_changed(root, options, &mut self.target, paths);
+        self.stale |= !update.unreadable.is_empty();
+        affected.extend(under(&self.target));
+        self.refresh(affected)
+    }
+
+    /// Replace the whole target, e.g. after a rescan, and return how the differences changed
+    pub fn replace_target(&mut self, target: HashMap<PathBuf, FileInfo>) -> Vec<LiveChange> {
+        let affected: BTreeSet<PathBuf> = self.target.keys().chain(target.keys()).cloned().collect();
+        self.target = target;
+        self.stale = false;
+        self.refresh(affected)
+    }
+
+    fn refresh(&mut self, paths: BTreeSet<PathBuf>) -> Vec<LiveChange> {
+        let mut changes = Vec::new();
+        for path in paths {
+            let diff = self.entry_diff(&path);
+            if diff.as_ref() == self.diffs.get(&path) {
+                continue;
+            }
+            match diff {
+                Some(diff) => {
+                    changes.push(LiveChange::Changed(diff.clone()));
+                    self.diffs.insert(path, diff);
+                }
+                None => {
+                    self.diffs.remove(&path);
+                    changes.push(LiveChange::Reverted(path));
+                }
+            }
+        }
+        changes
+    }
+
+    /// Difference of one path, compared as `diff::compare_directories` does without
+    /// diff patches or renames
+    fn entry_diff(&self, path: &Path) -> Option<DiffType> {
+        match (self.source.get(path), self.target.get(path)) {
+            (None, Some(target)) => Some(DiffType::Added(target.clone())),
+            (Some(_), None) => Some(DiffType::Removed(path.to_path_buf())),
+            (Some(source), Some(target)) if source.hash != target.hash => Some(DiffType::Modified(target.clone())),
+            (Some(source), Some(target)) => {
+                let mode_changed = source.mode.is_some() && target.mode.is_some() && source.mode != target.mode;
+                (mode_changed && !self.ignore_permissions).then(|| DiffType::MetadataChanged(target.clone()))
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+/// Watch `target_dir` until Ctrl-C is pressed or the cancel token of `options` is
+/// cancelled, passing every change of its differences from `source` to `on_change` as
+/// it happens
+///
+/// The differences found by the first scan are passed as `LiveChange::Changed` first.
+/// Afterwards only the files the file system reports changed are hashed again, once the
+/// tree has been quiet for a moment, so a file written in several steps is reported
+/// once. If events were lost the whole directory is rescanned. Warnings are printed as
+/// they come, as nothing else collects them.
+pub fn watch_diff(
+    source: &HashMap<PathBuf, FileInfo>,
+    target_dir: &Path,
+    options: &DiffOptions,
+    mut on_change: impl FnMut(&LiveChange) -> Result<()>,
+) -> Result<()> {
+    let root = target_dir.canonicalize().with_context(|| format!("Failed to resolve directory: {}", target_dir.display()))?;
+    let print_warnings = || {
+        for warning in options.warnings().take() {
+            options.status(format_args!("{}", warning));
+        }
+    };
+
+    // Started before the scan, so changes made while scanning are not missed
+    let mut watcher = Watcher::new(&root)?;
+    options.status(format_args!("{}", tr!("scan.target_dir", path = target_dir.display())));
+    let target = diff::scan_directory_reusing(&root, options, &HashMap::new(), |_| true)?.files;
+    print_warnings();
+    let mut live = LiveDiff::new(source, target, options);
+    for diff in live.diffs() {
+        on_change(&LiveChange::Changed(diff.clone()))?;
+    }
+    options.status(format_args!("{}", tr!("watch.diff_watching", path = target_dir.display(), count = live.diffs.len())));
+
+    let mut changed = HashSet::new();
+    let mut rescan = false;
+    while !options.should_stop() {
+        let events = watcher.wait(WATCH_SETTLE_TIME)?;
+        if !events.is_empty() {
+            rescan |= events.overflow;
+            changed.extend(events.paths);
+            continue;
+        }
+        let changes = if rescan {
+            options.status(format_args!("{}", tr!("watch.rescanning", path = target_dir.display())));
+            rescan = false;
+            changed.clear();
+            live.replace_target(diff::scan_directory_reusing(&root, options, &HashMap::new(), |_| true)?.files)
+        } else if !changed.is_empty() {
+            let changes = live.update(&root, options, changed.drain().collect());
+            rescan = live.is_stale();
+            changes
+        } else {
+            continue;
+        };
+        print_warnings();
+        for change in &changes {
+            on_change(change)?;
+        }
+    }
+
+    options.status(format_args!("{}", tr!("watch.stopped", path = target_dir.display())));
+    Ok(())
+}
@@ -0,0 +1,433 @@
+use crate::hashes::Xxh32;
+use std::io::{self, Read, Write};
+
+/// Start of every LZ4 frame
+const FRAME_MAGIC: u32 = 0x184D2204;
+/// Skippable frames (magic `0x184D2A50` to `0x184D2A5F`) are skipped when reading
+const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+const BLOCK_SIZE: usize = 4 << 20;
+/// FLG byte: version 01, independent blocks, content checksum
+const FRAME_FLAGS: u8 = 0b0110_0100;
+/// BD byte: 4 MiB blocks
+const FRAME_BLOCK_DESCRIPTOR: u8 = 7 << 4;
+/// Block size bit meaning the block is stored uncompressed
+const UNCOMPRESSED_BLOCK: u32 = 1 << 31;
+
+const MIN_MATCH: usize = 4;
+/// The last literals of a block and the last match start are kept apart from its end
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+const HASH_BITS: u32 = 16;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid LZ4 data: {}", message))
+}
+
+/// Compress `input` into one LZ4 block appended to `output`
+pub fn compress_block(input: &[u8], output: &mut Vec<u8>) {
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let hash = |sequence: u32| (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+    let read = |pos: usize| u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap());
+    let mut anchor = 0;
+    let mut pos = 0;
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        let mut misses = 0usize;
+        while pos < match_limit {
+            let sequence = read(pos);
+            let slot = &mut table[hash(sequence)];
+            let candidate = std::mem::replace(slot, pos);
+            if candidate == usize::MAX || pos - candidate > MAX_OFFSET || read(candidate) != sequence {
+                // Skip faster through data that does not compress
+                misses += 1;
+                pos += 1 + (misses >> 6);
+                continue;
+            }
+            misses = 0;
+            let end = input.len() - LAST_LITERALS;
+            let mut length = MIN_MATCH;
+            while pos + length < end && input[pos + length] == input[candidate + length] {
+                length += 1;
+            }
+            write_sequence(output, &input[anchor..pos], Some((pos - candidate, length)));
+            pos += length;
+            anchor = pos;
+        }
+    }
+    write_sequence(output, &input[anchor..], None);
+}
+
+/// One token with its literals and, except for the last sequence of a block, a match
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_code = matched.map_or(0, |(_, length)| length - MIN_MATCH);
+    output.push((literals.len().min(15) << 4 | match_code.min(15)) as u8);
+    write_length(output, literals.len());
+    output.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        write_length(output, match_code);
+    }
+}
+
+/// The bytes of a length beyond the 15 its token holds
+fn write_length(output: &mut Vec<u8>, length: usize) {
+    if length < 15 {
+        return;
+    }
+    let mut rest = length - 15;
+    while rest >= 255 {
+        output.push(255);
+        rest -= 255;
+    }
+    output.push(rest as u8);
+}
+
+/// Decompress one LZ4 block, appending to `output`; matches may reach back into what
+/// `output` already holds, at most `limit` bytes are added
+pub fn decompress_block(input: &[u8], output: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    let end = output.len() + limit;
+    let mut pos = 0;
+    loop {
+        let token = read_byte(input, &mut pos)?;
+        let literals = read_length(input, &mut pos, usize::from(token >> 4))?;
+        let literal_bytes = input.get(pos..pos + literals).ok_or_else(|| invalid("literals past the end of the block"))?;
+        if output.len() + literals > end {
+            return Err(invalid("block larger than its frame allows"));
+        }
+        output.extend_from_slice(literal_bytes);
+        pos += literals;
+        if pos == input.len() {
+            return Ok(());
+        }
+        let offset = usize::from(u16::from_le_bytes([read_byte(input, &mut pos)?, read_byte(input, &mut pos)?]));
+        let length = read_length(input, &mut pos, usize::from(token & 15))? + MIN_MATCH;
+        if offset == 0 || offset > output.len() {
+            return Err(invalid("match offset out of range"));
+        }
+        if output.len() + length > end {
+            return Err(invalid("block larger than its frame allows"));
+        }
+        // Byte by byte, as a match may overlap the bytes it produces
+        let start = output.len() - offset;
+        for index in start..start + length {
+            output.push(output[index]);
+        }
+    }
+}
+
+fn read_byte(input: &[u8], pos: &mut usize) -> io::Result<u8> {
+    let byte = *input.get(*pos).ok_or_else(|| invalid("block ends early"))?;
+    *pos += 1;
+    Ok(byte)
+}
+
+/// A length from its token, continued by further bytes if the token holds 15
+fn read_length(input: &[u8], pos: &mut usize, mut length: usize) -> io::Result<usize> {
+    if length == 15 {
+        loop {
+            let byte = read_byte(input, pos)?;
+            length += usize::from(byte);
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+/// Writes an LZ4 frame of everything written to it (portable implementation of the
+/// reference format, readable by the `lz4` tool); call `finish` to end the frame
+///
+/// Frames have independent 4 MiB blocks and a content checksum.
+pub struct FrameEncoder<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    compressed: Vec<u8>,
+    checksum: Xxh32,
+    started: bool,
+}
+
+impl<W: Write> FrameEncoder<W> {
+    pub fn new(inner: W) -> Self {
+        FrameEncoder { inner, buffer: Vec::new(), compressed: Vec::new(), checksum: Xxh32::new(), started: false }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        let mut descriptor = Xxh32::new();
+        descriptor.update(&[FRAME_FLAGS, FRAME_BLOCK_DESCRIPTOR]);
+        let header_checksum = (descriptor.finalize() >> 8) as u8;
+        self.inner.write_all(&FRAME_MAGIC.to_le_bytes())?;
+        self.inner.write_all(&[FRAME_FLAGS, FRAME_BLOCK_DESCRIPTOR, header_checksum])
+    }
+
+    /// Compress and write the buffered input as one block, stored as it is if it does
+    /// not get smaller
+    fn write_block(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.checksum.update(&self.buffer);
+        self.compressed.clear();
+        compress_block(&self.buffer, &mut self.compressed);
+        if self.compressed.len() < self.buffer.len() {
+            self.inner.write_all(&(self.compressed.len() as u32).to_le_bytes())?;
+            self.inner.write_all(&self.compressed)?;
+        } else {
+            self.inner.write_all(&(self.buffer.len() as u32 | UNCOMPRESSED_BLOCK).to_le_bytes())?;
+            self.inner.write_all(&self.buffer)?;
+        }
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Write the last block, the end mark and the content checksum
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        self.inner.write_all(&0u32.to_le_bytes())?;
+        self.inner.write_all(&self.checksum.finalize().to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for FrameEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        if self.buffer.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Settings of the frame being read
+struct FrameState {
+    independent: bool,
+    block_checksums: bool,
+    content_checksum: Option<Xxh32>,
+    block_size: usize,
+}
+
+/// Reads the decompressed content of one or more concatenated LZ4 frames
+///
+/// Any frame the format allows can be read, except ones that need a preset dictionary.
+pub struct FrameDecoder<R: Read> {
+    inner: R,
+    frame: Option<FrameState>,
+    /// Decompressed data; before `pos` are bytes already read, kept as the window
+    /// dependent blocks refer back to
+    output: Vec<u8>,
+    pos: usize,
+    block: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> FrameDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        FrameDecoder { inner, frame: None, output: Vec::new(), pos: 0, block: Vec::new(), done: false }
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0u8; 4];
+        self.inner.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Read the next frame header; `false` at the end of the input
+    fn start_frame(&mut self) -> io::Result<bool> {
+        loop {
+            let mut magic = [0u8; 4];
+            let mut read = 0;
+            while read < 4 {
+                match self.inner.read(&mut magic[read..])? {
+                    0 if read == 0 => return Ok(false),
+                    0 => return Err(invalid("truncated frame")),
+                    n => read += n,
+                }
+            }
+            let magic = u32::from_le_bytes(magic);
+            if magic & 0xFFFF_FFF0 == SKIPPABLE_MAGIC {
+                let size = self.read_u32()?;
+                io::copy(&mut (&mut self.inner).take(u64::from(size)), &mut io::sink())?;
+                continue;
+            }
+            if magic != FRAME_MAGIC {
+                return Err(invalid("not an LZ4 frame"));
+            }
+            break;
+        }
+        let mut descriptor = [0u8; 2];
+        self.inner.read_exact(&mut descriptor)?;
+        let [flags, block_descriptor] = descriptor;
+        if flags >> 6 != 1 {
+            return Err(invalid("unsupported frame version"));
+        }
+        if flags & 1 != 0 {
+            return Err(invalid("frames with a preset dictionary are not supported"));
+        }
+        let mut header = descriptor.to_vec();
+        if flags & 0b1000 != 0 {
+            let mut content_size = [0u8; 8];
+            self.inner.read_exact(&mut content_size)?;
+            header.extend_from_slice(&content_size);
+        }
+        let mut checksum = [0u8; 1];
+        self.inner.read_exact(&mut checksum)?;
+        let mut hasher = Xxh32::new();
+        hasher.update(&header);
+        if (hasher.finalize() >> 8) as u8 != checksum[0] {
+            return Err(invalid("frame header checksum mismatch"));
+        }
+        let block_size = match (block_descriptor >> 4) & 7 {
+            4 => 64 << 10,
+            5 => 256 << 10,
+            6 => 1 << 20,
+            7 => 4 << 20,
+            _ => return Err(invalid("unsupported block size")),
+        };
+        self.frame = Some(FrameState {
+            independent: flags & 0b10_0000 != 0,
+            block_checksums: flags & 0b1_0000 != 0,
+            content_checksum: (flags & 0b100 != 0).then(Xxh32::new),
+            block_size,
+        });
+        self.output.clear();
+        self.pos = 0;
+        Ok(true)
+    }
+
+    /// Decompress the next block into `output`; `false` at the end of the input
+    fn next_block(&mut self) -> io::Result<bool> {
+        if self.frame.is_none() && !self.start_frame()? {
+            return Ok(false);
+        }
+        let size = self.read_u32()?;
+        let frame = self.frame.as_mut().unwrap();
+        if size == 0 {
+            if let Some(checksum) = frame.content_checksum.take() {
+                let mut stored = [0u8; 4];
+                self.inner.read_exact(&mut stored)?;
+                if checksum.finalize() != u32::from_le_bytes(stored) {
+                    return Err(invalid("content checksum mismatch"));
+                }
+            }
+            self.frame = None;
+            return Ok(true);
+        }
+        let length = (size & !UNCOMPRESSED_BLOCK) as usize;
+        if length > frame.block_size {
+            return Err(invalid("block larger than its frame allows"));
+        }
+        let (block_size, block_checksums, independent) = (frame.block_size, frame.block_checksums, frame.independent);
+        self.block.resize(length, 0);
+        self.inner.read_exact(&mut self.block)?;
+        if block_checksums {
+            let stored = self.read_u32()?;
+            let mut hasher = Xxh32::new();
+            hasher.update(&self.block);
+            if hasher.finalize() != stored {
+                return Err(invalid("block checksum mismatch"));
+            }
+        }
+        // Dependent blocks may refer to the previous 64 KiB of output
+        let keep = if independent { 0 } else { self.output.len().min(MAX_OFFSET) };
+        self.output.drain(..self.output.len() - keep);
+        self.pos = keep;
+        if size & UNCOMPRESSED_BLOCK != 0 {
+            self.output.extend_from_slice(&self.block);
+        } else {
+            decompress_block(&self.block, &mut self.output, block_size)?;
+        }
+        if let Some(checksum) = &mut self.frame.as_mut().unwrap().content_checksum {
+            checksum.update(&self.output[keep..]);
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for FrameDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.output.len() {
+            if self.done || !self.next_block()? {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let count = buf.len().min(self.output.len() - self.pos);
+        buf[..count].copy_from_slice(&self.output[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Repetitive text with some noise, so blocks hold both literals and matches
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x9E37_79B9;
+        (0..len).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if state.is_multiple_of(8) { state as u8 } else { b"the quick brown fox "[i % 20] }
+        }).collect()
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        for len in [0, 1, 11, 12, 13, 100, 70_000, 300_000] {
+            let input = sample(len);
+            let mut compressed = Vec::new();
+            compress_block(&input, &mut compressed);
+            let mut output = Vec::new();
+            decompress_block(&compressed, &mut output, input.len()).unwrap();
+            assert_eq!(output, input, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn block_over_its_limit_is_an_error() {
+        let input = sample(1000);
+        let mut compressed = Vec::new();
+        compress_block(&input, &mut compressed);
+        assert!(decompress_block(&compressed, &mut Vec::new(), input.len() - 1).is_err());
+        assert!(decompress_block(&compressed[..compressed.len() / 2], &mut Vec::new(), input.len()).is_err());
+    }
+
+    #[test]
+    fn frames_round_trip_across_blocks() {
+        let input = sample(BLOCK_SIZE + 12345);
+        let mut encoder = FrameEncoder::new(Vec::new());
+        for piece in input.chunks(100_000) {
+            encoder.write_all(piece).unwrap();
+        }
+        let frame = encoder.finish().unwrap();
+        let mut output = Vec::new();
+        FrameDecoder::new(frame.as_slice()).read_to_end(&mut output).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn corrupt_frame_is_an_error() {
+        let mut encoder = FrameEncoder::new(Vec::new());
+        encoder.write_all(&sample(5000)).unwrap();
+        let mut frame = encoder.finish().unwrap();
+        let last = frame.len() - 1;
+        frame[last] ^= 1;
+        assert!(FrameDecoder::new(frame.as_slice()).read_to_end(&mut Vec::new()).is_err());
+        assert!(FrameDecoder::new(&b"not lz4"[..]).read_to_end(&mut Vec::new()).is_err());
+    }
+}
@@ -1,139 +1,1370 @@
 mod cli;
-mod diff;
-mod patch;
-mod utils;
 
-use anyhow::{Context, Result};
-use cli::{Commands, parse_args};
+use anyhow::{anyhow, Context, Result};
+use cli::{BlockArgs, BlocksCommand, CacheCommand, ChangeLimitMode, Commands, CreateArgs, FilterArgs, HashCacheCommand, OutputFormat, OutputStyle, WindowsPaths, parse_args, parse_patch_args};
+use diffpatch::blocks::{self, BlockHashes, BlockOptions};
+use diffpatch::bundle::{BundleFormat, BundleWriter};
+use diffpatch::config::{self, Config};
+use diffpatch::container::PatchFormat;
+use diffpatch::digest::HashAbbreviations;
+use diffpatch::paths::PathOrder;
+use diffpatch::progress::ProgressHook;
+use diffpatch::report::HtmlReport;
+use diffpatch::filter::{FilterList, FilterRule, RuleKind};
+use diffpatch::limits::ChangeLimits;
+use diffpatch::manifest::{DownloadPlan, Manifest};
+use diffpatch::i18n::{self, format_number};
+use diffpatch::ssh::SshFs;
+use diffpatch::sink::{self, ChangeListWriter, DiffCounter, DiffSink, ReportWriter};
+use diffpatch::tags::{self, TagRules, TagSummary};
+use diffpatch::template::{self, TemplateVars};
+use diffpatch::units::{ByteSize, Span};
+use diffpatch::warning::{self, Severity, Warning, WarningKind};
+use diffpatch::workflow::Workflow;
+use diffpatch::{apply, cache, compression, container, diff, hashcache, interrupt, livediff, patch, portability, remote, runid, snapshot, ssh, stats, timings, tr, utils, verify, vfs};
 use std::env;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use utils::{check_is_directory, check_path_exists};
 
 fn main() -> Result<()> {
+    interrupt::install_handler();
+
+    // Temporary files are cleaned up on the way out, so exit only after `run` returned
+    match run() {
+        Err(e) if interrupt::is_interruption(&e) => {
+            eprintln!("{}", e);
+            std::process::exit(interrupt::EXIT_CODE);
+        }
+        result => result,
+    }
+}
+
+fn run() -> Result<()> {
     // Check if running in patch mode
     if is_patch_executable() {
-        println!("Running in patch mode with parallel processing...");
+        let args = parse_patch_args();
+        i18n::set_language(args.lang.unwrap_or_else(i18n::detect_language));
+        println!("{}", tr!("apply.patch_mode"));
         let current_dir = env::current_dir().context("Failed to get current directory")?;
-        return patch::apply_patch(&current_dir);
+        return patch::apply_patch(&current_dir, &args.options.to_options());
     }
 
     // Parse command line arguments
     let args = parse_args();
+    i18n::set_language(args.lang.unwrap_or_else(i18n::detect_language));
+    if let Some(run_id) = &args.run_id {
+        runid::set(run_id)?;
+    }
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let config = if args.no_config { Config::default() } else { Config::discover(&current_dir)? };
 
-    match args.command {
-        Commands::Create {
-            source,
-            target,
-            output,
-            check_files,
-            exclude_extensions,
-            exclude_dirs,
-            use_diff_patches,
-        } => {
-            // Validate arguments
-            check_path_exists(&source, "Source directory").context("Source directory check failed")?;
-            check_is_directory(&source).context("Source directory check failed")?;
-            
-            check_path_exists(&target, "Target directory").context("Target directory check failed")?;
-            check_is_directory(&target).context("Target directory check failed")?;
-            
-            // Display exclude patterns if specified
-            if let Some(exts) = &exclude_extensions {
-                if !exts.is_empty() {
-                    println!("Excluding file extensions:");
-                    for ext in exts {
-                        println!("  - {}", ext);
-                    }
-                }
+    // Usage statistics are kept only where enabled, and only for the commands that scan trees
+    let recording = stats_command(&args.command).zip(stats::stats_file(config.stats_file.as_deref()));
+    if recording.is_some() {
+        stats::enable();
+    }
+    let started = SystemTime::now();
+    let result = run_command(args.command, &config, &current_dir);
+    if let Some((command, path)) = recording
+        && let Err(e) = stats::RunStats::finish(command, started, result.is_ok()).append(&path)
+    {
+        eprintln!("{}", tr!("stats.record_failed", error = format!("{:#}", e)));
+    }
+    result
+}
+
+/// Run a subcommand of the command line
+fn run_command(command: Commands, config: &Config, current_dir: &Path) -> Result<()> {
+    match command {
+        Commands::Create(mut args) => {
+            add_config_filters(&mut args.filters, config);
+            args.threads = args.threads.or(config.threads);
+            args.digest_encoding = args.digest_encoding.or(config.digest_encoding);
+            args.hash_algorithm = args.hash_algorithm.or(config.hash_algorithm);
+            let show_timings = args.timings;
+            if show_timings {
+                timings::enable();
             }
-            
-            if let Some(dirs) = &exclude_dirs {
-                if !dirs.is_empty() {
-                    println!("Excluding directories:");
-                    for dir in dirs {
-                        println!("  - {}", dir);
-                    }
+            let result = create(*args);
+            // Also shown for failed runs, which are often the ones worth profiling
+            if show_timings {
+                print_timings();
+            }
+            result?
+        }
+        
+        Commands::Diff { source, target, format, output_style, hash_algorithm, threads, detect_renames, diff_cache, hash_cache, watch, mut filters } => {
+            check_source_exists(&source, "Source tree")?;
+            check_path_exists(&target, "Target tree")?;
+            add_config_filters(&mut filters, config);
+            // Output for scripts keeps stdout to the list itself
+            let for_scripts = format != OutputFormat::Text || output_style == OutputStyle::Paths;
+            let options = diff::DiffOptions::new()
+                .hash_algorithm(hash_algorithm.or(config.hash_algorithm).unwrap_or_default())
+                .detect_renames(detect_renames)
+                .status_to_stderr(for_scripts)
+                .progress(scan_progress_bar());
+            let mut options = add_filter_args(options, filters)?;
+            if let Some(threads) = threads.or(config.threads) {
+                options = options.threads(threads);
+            }
+            if let Some(dir) = diff_cache {
+                options = options.diff_cache(dir);
+            }
+            if let Some(dir) = hashcache::cache_dir(hash_cache.as_deref()) {
+                options = options.hash_cache(dir);
+            }
+            options.validate()?;
+            if watch {
+                check_is_directory(&target)?;
+                let source_files = diff::scan_source(&source, &options)?;
+                let mut writer = ChangeListWriter::new(std::io::stdout().lock(), format.into())
+                    .style(output_style.into())
+                    .sources(source_files.clone());
+                return livediff::watch_diff(&source_files, &target, &options, |change| writer.write_change(change));
+            }
+            let mut report = diff::compare_directories(&source, &target, &options)?;
+            // The list should not have entries without hashes
+            diff::hash_added_files(&mut report, &target, &options)?;
+            report.warnings.extend(options.warnings().take());
+            if for_scripts {
+                for warning in &report.warnings {
+                    eprintln!("warning: {}", warning);
                 }
+            } else {
+                print_warnings(&report.warnings);
             }
-            
-            // Display if using diff patches
-            if use_diff_patches {
+            let mut writer = ChangeListWriter::new(std::io::stdout().lock(), format.into())
+                .style(output_style.into())
+                .abbreviate_hashes(report.hash_abbreviations())
+                .sources(std::mem::take(&mut report.source_files));
+            sink::run_sinks(&report, &mut [&mut writer])?;
+            if !for_scripts && writer.written() == 0 {
+                println!("{}", tr!("diff.no_differences"));
             }
-            
-            // Create patch
-            let diffs = diff::compare_directories(&source, &target, exclude_extensions.as_deref(), exclude_dirs.as_deref(), use_diff_patches)?;
-            
-            if diffs.is_empty() {
-                println!("No differences found, no need to create a patch.");
+        }
+
+        Commands::Bundle { source, target, output, bundle_format, level, hash_algorithm, threads, detect_renames, mut filters } => {
+            check_source_exists(&source, "Source tree")?;
+            check_is_directory(&target)?;
+            add_config_filters(&mut filters, config);
+            let format = bundle_format.or_else(|| BundleFormat::from_path(&output)).unwrap_or_default();
+            let options = diff::DiffOptions::new()
+                .hash_algorithm(hash_algorithm.or(config.hash_algorithm).unwrap_or_default())
+                .detect_renames(detect_renames)
+                .progress(scan_progress_bar());
+            let mut options = add_filter_args(options, filters)?;
+            if let Some(threads) = threads.or(config.threads) {
+                options = options.threads(threads);
+            }
+            options.validate()?;
+            let mut report = diff::compare_directories(&source, &target, &options)?;
+            report.warnings.extend(options.warnings().take());
+            print_warnings(&report.warnings);
+            let output = remote::OutputFile::new(&output)?;
+            println!("{}", tr!("bundle.writing", format = format, path = output.display()));
+            let mut writer = BundleWriter::create(output.path(), &target, format, level)?;
+            sink::run_sinks(&report, &mut [&mut writer])?;
+            let (files, bytes) = writer.stored();
+            let archive_size = std::fs::metadata(output.path()).map(|m| m.len()).unwrap_or(0);
+            let saved_to = output.display();
+            output.finish()?;
+            println!(
+                "{}",
+                tr!(
+                    "bundle.saved",
+                    path = saved_to,
+                    files = files,
+                    size = utils::format_size(bytes),
+                    archive_size = utils::format_size(archive_size),
+                    removed = writer.manifest().removed.len()
+                )
+            );
+        }
+
+        Commands::Convert { input, output, format, program, compression, level } => {
+            check_path_exists(&input, "Patch")?;
+            let format = format.or_else(|| PatchFormat::from_path(&output)).ok_or_else(|| {
+                anyhow!("Cannot tell the format from the output name {}; use --format exe, zip or tar.zst", output.display())
+            })?;
+            let output = remote::OutputFile::new(&output)?;
+            let summary = container::convert(&input, output.path(), format, program.as_deref(), compression, level)?;
+            let saved_to = output.display();
+            output.finish()?;
+            println!(
+                "{}",
+                tr!(
+                    "convert.done",
+                    input = input.display(),
+                    from = summary.from,
+                    output = saved_to,
+                    to = summary.to,
+                    entries = summary.entries,
+                    size = utils::format_size(summary.size)
+                )
+            );
+        }
+
+        Commands::CompareReports { first, second, sort } => {
+            let first_report = diff::DiffReport::load(&first)?;
+            let second_report = diff::DiffReport::load(&second)?;
+            if let (Some(a), Some(b)) = (first_report.hash_algorithm(), second_report.hash_algorithm())
+                && a != b
+            {
+                return Err(anyhow::anyhow!("The reports use different hash algorithms ({} and {}), so their hashes cannot be compared", a, b));
+            }
+            let mut comparison = first_report.compare(&second_report);
+            let order = PathOrder::from(sort);
+            comparison.only_in_self.sort_by(|a, b| order.compare(a.path(), b.path()));
+            comparison.only_in_other.sort_by(|a, b| order.compare(a.path(), b.path()));
+
+            if comparison.is_identical() {
+                println!("{}", tr!("compare_reports.identical", count = first_report.diffs.len()));
                 return Ok(());
             }
-            
-            let add_count = diffs.iter().filter(|d| matches!(d, diff::DiffType::Added(_))).count();
-            let mod_count = diffs.iter().filter(|d| matches!(d, diff::DiffType::Modified(_))).count();
-            let mod_diff_count = diffs.iter().filter(|d| matches!(d, diff::DiffType::ModifiedDiff(_))).count();
-            let del_count = diffs.iter().filter(|d| matches!(d, diff::DiffType::Removed(_))).count();
-            
-            println!("Found {} file differences:", diffs.len());
-            println!("  Added: {} files", add_count);
-            println!("  Modified (full files): {} files", mod_count);
-            if use_diff_patches {
-                println!("  Modified (diff patches): {} files", mod_diff_count);
-            }
-            println!("  Deleted: {} files", del_count);
-            
-            // Check verification file list
-            for check_file in &check_files {
-                let check_path = source.join(check_file);
-                if !check_path.exists() {
-                    println!("Warning: Verification file does not exist: {}", check_path.display());
+
+            let abbreviations = HashAbbreviations::new(
+                first_report.diffs.iter().chain(&second_report.diffs).filter_map(diff::DiffType::hash),
+            );
+            for (report_path, entries) in [(&first, &comparison.only_in_self), (&second, &comparison.only_in_other)] {
+                println!("{}", tr!("compare_reports.only_in", path = report_path.display(), count = entries.len()));
+                for diff in entries {
+                    let hash = diff.hash().map_or("", |hash| abbreviations.abbreviate(hash));
+                    println!("  - {}: {} {}", diff.kind(), diff.path().display(), hash);
                 }
             }
-            
-            if check_files.is_empty() {
-                println!("Warning: No verification files specified, patch will be applied to any directory.");
-            } else {
-                println!("Specified verification files:");
-                for file in &check_files {
-                    println!("  - {}", file);
+            return Err(anyhow::anyhow!(tr!("compare_reports.differ")));
+        }
+
+        Commands::VerifyRecord { record, signing_key } => {
+            let key = signing_key.as_deref().map(verify::load_key).transpose()?;
+            let record = verify::VerificationRecord::load(&record)?;
+            record.verify_signature(key.as_deref())?;
+            println!(
+                "{}{}",
+                tr!(
+                    "verify_record.valid",
+                    algorithm = record.signature_algorithm,
+                    patch = record.patch,
+                    path = record.directory.display(),
+                    result = tr!(if record.passed { "verify_record.passed" } else { "verify_record.failed" })
+                ),
+                if record.rolled_back { tr!("verify_record.rolled_back") } else { String::new() }
+            );
+            return Ok(());
+        }
+
+        Commands::ApplyReport { report, payload, dir } => {
+            check_is_directory(&payload)?;
+            check_is_directory(&dir)?;
+            let report = diff::DiffReport::load(&report)?;
+            let summary = apply::apply_report(&report, &payload, &dir)?;
+            println!(
+                "{}",
+                tr!(
+                    "apply_report.done",
+                    count = report.diffs.len(),
+                    copied = summary.copied,
+                    patched = summary.patched,
+                    renamed = summary.renamed,
+                    removed = summary.removed,
+                    verified = summary.verified,
+                    permissions = summary.permissions
+                )
+            );
+            return Ok(());
+        }
+
+        Commands::DownloadPlan { local, target, output, hash_algorithm } => {
+            check_source_exists(&local, "Local tree")?;
+            check_source_exists(&target, "Target tree")?;
+            let options = diff::DiffOptions::new().hash_algorithm(hash_algorithm.or(config.hash_algorithm).unwrap_or_default());
+            let local_manifest = Manifest::from_files(&diff::scan_source(&local, &options)?);
+            let target_manifest = Manifest::from_files(&diff::scan_source(&target, &options)?);
+
+            let plan = DownloadPlan::new(&local_manifest, &target_manifest);
+            plan.save(&output)?;
+            println!(
+                "{}",
+                tr!(
+                    "download_plan.saved",
+                    path = output.display(),
+                    needed = plan.needed.len(),
+                    size = utils::format_size(plan.download_size()),
+                    reuse = plan.reuse.len(),
+                    delete = plan.delete.len()
+                )
+            );
+        }
+
+        Commands::Manifest { dir, output, digest_encoding, hash_algorithm, threads, on_read_error, mut filters } => {
+            if !ssh::is_ssh_url(&dir) {
+                check_is_directory(&dir)?;
+            }
+            add_config_filters(&mut filters, config);
+            let digest_encoding = digest_encoding.or(config.digest_encoding).unwrap_or_default();
+            let hash_algorithm = hash_algorithm.or(config.hash_algorithm).unwrap_or_default();
+            let options = diff::DiffOptions::new()
+                .digest_encoding(digest_encoding)
+                .hash_algorithm(hash_algorithm)
+                .error_policy(on_read_error.into())
+                .progress(scan_progress_bar());
+            let mut options = add_filter_args(options, filters)?;
+            if let Some(threads) = threads.or(config.threads) {
+                options = options.threads(threads);
+            }
+            options.validate()?;
+            println!("{}", tr!("manifest.scanning", path = dir.display()));
+            let files = match ssh::is_ssh_url(&dir) {
+                true => diff::scan_vfs(&SshFs::open(&dir.to_string_lossy())?, &options)?,
+                false => diff::scan_directory(&dir, &options)?,
+            };
+            let mut manifest = Manifest::from_files(&files);
+            print_warnings(&options.warnings().take());
+            manifest.make_reproducible();
+            let output = remote::OutputFile::new(&output)?;
+            manifest.save(output.path())?;
+            let saved_to = output.display();
+            output.finish()?;
+            let total: u64 = manifest.files.iter().map(|info| info.size).sum();
+            println!("{}", tr!("manifest.saved", path = saved_to, count = manifest.files.len(), size = utils::format_size(total)));
+        }
+
+        Commands::Watch { dir, snapshot_dir, hash_algorithm, threads } => {
+            check_is_directory(&dir)?;
+            let mut options = diff::DiffOptions::new().hash_algorithm(hash_algorithm.or(config.hash_algorithm).unwrap_or_default());
+            if let Some(threads) = threads {
+                options = options.threads(threads);
+            }
+            options.validate()?;
+            snapshot::watch_directory(&dir, &options, &snapshot_dir)?;
+        }
+
+        Commands::Explain { path, dir, mut filters } => {
+            add_config_filters(&mut filters, config);
+            let options = add_filter_args(diff::DiffOptions::new(), filters)?;
+            // Accept paths relative to DIR as well as paths that point into it
+            let relative: PathBuf = path.strip_prefix(&dir).unwrap_or(&path).components().filter(|c| *c != std::path::Component::CurDir).collect();
+            let full_path = dir.join(&relative);
+            let size = std::fs::metadata(&full_path).ok().filter(|m| m.is_file()).map(|m| m.len());
+            if size.is_none() {
+                println!("{}", tr!("explain.not_a_file", path = full_path.display()));
+            }
+            println!("{}: {}", relative.display(), options.explain(&relative, size));
+        }
+
+        Commands::Config => {
+            for (scope, path) in config::config_paths(current_dir) {
+                let state = tr!(if path.is_file() { "config.found" } else { "config.not_found" });
+                println!("{:<8} {} ({})", format!("{:?}:", scope), path.display(), state);
+            }
+            print_config(config);
+        }
+
+        Commands::Hash { files, encoding, hash_algorithm } => {
+            let algorithm = hash_algorithm.or(config.hash_algorithm).unwrap_or_default();
+            for file in files {
+                let hash = if file.as_os_str() == "-" {
+                    diff::hash_reader_as(std::io::stdin().lock(), algorithm, encoding).context("Failed to hash standard input")?
+                } else {
+                    diff::hash_file(&file, algorithm, encoding)?.0
+                };
+                println!("{}  {}", hash, file.display());
+            }
+        }
+
+        Commands::Cache { object_cache, command } => {
+            let dir = cache::cache_dir(object_cache.as_deref()).ok_or_else(|| {
+                anyhow::anyhow!("No object cache given (use --object-cache or set {})", cache::CACHE_DIR_VAR)
+            })?;
+            check_is_directory(&dir)?;
+            let cache = cache::ObjectCache::open(&dir)?;
+            match command {
+                CacheCommand::Gc { keep_releases } => {
+                    let summary = cache.collect_garbage(keep_releases)?;
+                    println!(
+                        "{}",
+                        tr!(
+                            "cache.gc_done",
+                            objects = format_number(summary.objects),
+                            size = utils::format_size(summary.bytes),
+                            releases = format_number(summary.releases),
+                            path = dir.display()
+                        )
+                    );
+                }
+                CacheCommand::Stats => print_cache_stats(&cache)?,
+            }
+        }
+
+        Commands::HashCache { hash_cache, command } => {
+            let dir = hashcache::cache_dir(hash_cache.as_deref()).ok_or_else(|| {
+                anyhow::anyhow!("No hash cache given (use --hash-cache or set {})", hashcache::HASH_CACHE_VAR)
+            })?;
+            match command {
+                HashCacheCommand::List => {
+                    let caches = hashcache::list(&dir)?;
+                    println!("{}", tr!("hash_cache.title", path = dir.display()));
+                    if caches.is_empty() {
+                        println!("{}", tr!("hash_cache.empty"));
+                    }
+                    for cache in &caches {
+                        println!(
+                            "{}",
+                            tr!(
+                                "hash_cache.entry",
+                                root = cache.root.display(),
+                                algorithm = cache.hash_algorithm,
+                                files = format_number(cache.files as u64),
+                                size = utils::format_size(cache.bytes)
+                            )
+                        );
+                    }
+                }
+                HashCacheCommand::Clear { dir: cleared } => {
+                    let removed = hashcache::clear(&dir, cleared.as_deref())?;
+                    match cleared {
+                        Some(cleared) => println!("{}", tr!("hash_cache.cleared", path = cleared.display(), count = removed)),
+                        None => println!("{}", tr!("hash_cache.cleared_all", count = removed, path = dir.display())),
+                    }
                 }
             }
-            
-            // Confirm patch creation
-            if !utils::confirm_action("Confirm creating patch file?")? {
-                println!("Operation cancelled.");
+        }
+
+        Commands::Blocks { command } => match command {
+            BlocksCommand::Hash { file, output, options } => {
+                let hashes = blocks::hash_blocks(&file, &block_options(options, config))?;
+                hashes.save(&output)?;
+                println!(
+                    "{}",
+                    tr!(
+                        "blocks.hashes_saved",
+                        file = file.display(),
+                        path = output.display(),
+                        count = format_number(hashes.block_count()),
+                        size = utils::format_size(hashes.block_size)
+                    )
+                );
+            }
+            BlocksCommand::Diff { source, target, output, from_hashes, save_hashes, options } => {
+                let options = block_options(options, config);
+                let source_hashes = if from_hashes {
+                    BlockHashes::load(&source)?
+                } else {
+                    blocks::hash_blocks(&source, &options)?
+                };
+                // Saved hashes decide the block size and algorithm of the comparison
+                let options = options.block_size(source_hashes.block_size).hash_algorithm(source_hashes.hash_algorithm);
+                let target_hashes = blocks::hash_blocks(&target, &options)?;
+                let changes = blocks::compare_blocks(&source_hashes, &target_hashes)?;
+                let size = blocks::write_block_delta(&target, &source_hashes, &target_hashes, &output)?;
+                println!(
+                    "{}",
+                    tr!(
+                        "blocks.delta_saved",
+                        changed = format_number(changes.changed.len() as u64),
+                        count = format_number(changes.block_count),
+                        size = utils::format_size(changes.changed_bytes),
+                        path = output.display(),
+                        delta_size = utils::format_size(size)
+                    )
+                );
+                if let Some(save_hashes) = save_hashes {
+                    target_hashes.save(&save_hashes)?;
+                }
+            }
+            BlocksCommand::Apply { file, delta, no_check, state_dir } => {
+                let options = BlockOptions::new().state_dir(state_dir).progress(file_progress_bar());
+                let summary = blocks::apply_block_delta(&file, &delta, (!no_check).then_some(&options))?;
+                println!(
+                    "{}",
+                    tr!(
+                        "blocks.applied",
+                        count = format_number(summary.blocks),
+                        size = utils::format_size(summary.bytes),
+                        path = file.display(),
+                        file_size = utils::format_size(summary.size)
+                    )
+                );
+            }
+        },
+
+        Commands::Stats { stats_file, command, last, since } => {
+            let Some(path) = stats::stats_file(stats_file.as_deref().or(config.stats_file.as_deref())) else {
+                println!("{}", tr!("stats.off", var = stats::STATS_VAR));
+                return Ok(());
+            };
+            if !path.exists() {
+                println!("{}", tr!("stats.none_yet", path = path.display()));
                 return Ok(());
             }
-            
-            patch::create_patch(&source, &target, &output, diffs, check_files)?;
+            let mut runs = stats::load(&path)?;
+            if let Some(command) = &command {
+                runs.retain(|run| run.command == *command);
+            }
+            if let Some(since) = since {
+                let start = SystemTime::now().checked_sub(since.duration()).unwrap_or(UNIX_EPOCH);
+                let start = start.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+                runs.retain(|run| run.started_at >= start);
+            }
+            print_run_stats(&runs, last);
         }
-        
-        Commands::Apply { patch_data: _ } => {
+
+        Commands::Apply { patch_data, options } => {
             // Apply patch, typically called directly by the generated patch program, not by users
-            let current_dir = env::current_dir().context("Failed to get current directory")?;
-            patch::apply_patch(&current_dir)?;
+            let mut options = options.to_options();
+            options.package = Some(patch_data);
+            patch::apply_patch(current_dir, &options)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Name under which runs of `command` are kept in the usage statistics; `None` for
+/// commands that scan no trees or run until stopped
+fn stats_command(command: &Commands) -> Option<&'static str> {
+    Some(match command {
+        Commands::Create(_) => "create",
+        Commands::Diff { watch: false, .. } => "diff",
+        Commands::Bundle { .. } => "bundle",
+        Commands::Convert { .. } => "convert",
+        Commands::Manifest { .. } => "manifest",
+        Commands::DownloadPlan { .. } => "download-plan",
+        Commands::Apply { .. } => "apply",
+        _ => return None,
+    })
+}
+
+/// Fail unless `path` exists; an HTTP, HTTPS or S3 URL of a manifest and an SFTP URL of a
+/// remote directory are taken as they are
+fn check_source_exists(path: &Path, path_type: &str) -> Result<()> {
+    if remote::is_url(path) || ssh::is_ssh_url(path) {
+        return Ok(());
+    }
+    check_path_exists(path, path_type)
+}
+
+/// Put the settings of the configuration files under the filter options of the command line
+///
+/// Exclude lists and ignore patterns add up; the include patterns and size limit of
+/// the command line replace the configured ones.
+fn add_config_filters(filters: &mut FilterArgs, config: &Config) {
+    let prepend = |configured: &[String], given: Option<Vec<String>>| match given {
+        None if configured.is_empty() => None,
+        given => Some(configured.iter().cloned().chain(given.into_iter().flatten()).collect()),
+    };
+    filters.exclude_extensions = prepend(&config.exclude_extensions, filters.exclude_extensions.take());
+    filters.exclude_dirs = prepend(&config.exclude_dirs, filters.exclude_dirs.take());
+    filters.ignore.splice(0..0, config.ignore.iter().cloned());
+    if filters.include_pattern.is_empty()
+        && let Some(patterns) = &config.include_patterns
+    {
+        filters.include_pattern = patterns.clone();
+    }
+    filters.max_file_size = filters.max_file_size.or(config.max_file_size);
+}
+
+/// Print the number of changes per tag
+fn print_tag_summary(summary: &TagSummary) {
+    println!("{}", tr!("create.tag_summary"));
+    let untagged = (summary.untagged.total() > 0).then(|| (tr!("create.untagged"), &summary.untagged));
+    for (tag, counter) in summary.by_tag.iter().map(|(tag, counter)| (tag.clone(), counter)).chain(untagged) {
+        println!(
+            "{}",
+            tr!(
+                "create.tag_counts",
+                tag = tag,
+                added = format_number(counter.added as u64),
+                modified = format_number((counter.modified + counter.modified_diff) as u64),
+                removed = format_number(counter.removed as u64),
+                renamed = format_number(counter.renamed as u64),
+            )
+        );
+    }
+}
+
+/// Print the merged settings of the configuration files
+fn print_config(config: &Config) {
+    if config.sources.is_empty() {
+        println!("{}", tr!("config.no_file"));
+        return;
+    }
+    let list = |items: &[String]| if items.is_empty() { "-".to_string() } else { items.join(", ") };
+    let or_default = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    println!("{}", tr!("config.settings"));
+    println!("  exclude_extensions: {}", list(&config.exclude_extensions));
+    println!("  exclude_dirs:       {}", list(&config.exclude_dirs));
+    println!("  ignore:             {}", list(&config.ignore));
+    println!("  include_patterns:   {}", config.include_patterns.as_deref().map_or("-".to_string(), list));
+    println!("  max_file_size:      {}", or_default(config.max_file_size.map(|size| size.to_string())));
+    println!("  digest_encoding:    {}", or_default(config.digest_encoding.map(|encoding| encoding.name().to_string())));
+    println!("  hash_algorithm:     {}", or_default(config.hash_algorithm.map(|algorithm| algorithm.name().to_string())));
+    println!("  threads:            {}", or_default(config.threads.map(|threads| threads.to_string())));
+    println!("  stats_file:         {}", or_default(config.stats_file.as_ref().map(|path| path.display().to_string())));
+}
+
+/// Add the exclude, filter rule and size limit options to `options`
+fn add_filter_args(mut options: diff::DiffOptions, args: FilterArgs) -> Result<diff::DiffOptions> {
+    if let Some(exts) = args.exclude_extensions {
+        options = options.exclude_extensions(exts);
+    }
+    if let Some(dirs) = args.exclude_dirs {
+        options = options.exclude_dirs(dirs);
+    }
+    if let Some(size) = args.max_file_size {
+        options = options.max_file_size(size.bytes());
+    }
+    if let Some(rate) = args.throttle {
+        options = options.throttle(rate.bytes_per_second());
+    }
+    if args.include_hidden {
+        options = options.include_hidden(true);
+    }
+    if args.follow_symlinks {
+        options = options.follow_symlinks(true);
+    }
+    if !args.include_pattern.is_empty() {
+        options = options.include_patterns(args.include_pattern);
+    }
+    // Rules are checked in order: --filter, --filter-from, then includes before excludes,
+    // as with rsync's --include-from before --exclude-from, then the gitignore patterns
+    let mut filters = FilterList::new();
+    for (index, rule) in args.filter.iter().enumerate() {
+        filters.push(FilterRule::parse(rule)?.with_origin(format!("--filter #{}", index + 1)));
+    }
+    for path in &args.filter_from {
+        filters.add_rules_from_file(path)?;
+    }
+    for path in &args.include_from {
+        filters.add_from_file(RuleKind::Include, path)?;
+    }
+    for path in &args.exclude_from {
+        filters.add_from_file(RuleKind::Exclude, path)?;
+    }
+    // Gitignore patterns form one block in which the last match wins: files first, then --ignore
+    let mut ignore_lines = Vec::new();
+    for path in &args.ignore_from {
+        ignore_lines.extend(FilterList::read_gitignore_file(path)?);
+    }
+    ignore_lines.extend(args.ignore.iter().enumerate().map(|(index, pattern)| (format!("--ignore #{}", index + 1), pattern.clone())));
+    filters.add_gitignore(ignore_lines);
+    Ok(options.filters(filters))
+}
+
+/// Compare the directories and create a patch file
+#[allow(clippy::collapsible_if)]
+fn create(args: CreateArgs) -> Result<()> {
+    let CreateArgs {
+        source,
+        target,
+        output,
+        upload,
+        from_version,
+        to_version,
+        check_files,
+        workflow,
+        mut filters,
+        use_diff_patches,
+        check_case_conflicts,
+        windows_paths,
+        windows_max_path,
+        max_changed_files,
+        max_changed_size,
+        on_change_limit,
+        save_report,
+        html_report,
+        reproducible,
+        tag_rules,
+        only_tags,
+        sort,
+        pre_apply,
+        pre_apply_args,
+        post_apply,
+        post_apply_args,
+        threads,
+        estimate,
+        estimate_time,
+        snapshot_dir,
+        diff_cache,
+        hash_cache,
+        match_extension_renames,
+        detect_renames,
+        rename_min_size,
+        ignore_build_stamps,
+        ignore_permissions,
+        inspect_archives,
+        lazy_hash,
+        detect_clones,
+        binary_delta,
+        delta_time_budget,
+        max_delta_file_size,
+        compare,
+        paranoid,
+        digest_encoding,
+        hash_algorithm,
+        verify_unchanged,
+        on_hash_collision,
+        on_read_error,
+        compression,
+        codec,
+        benchmark,
+        dry_run,
+        timings: _,
+        platform_files,
+        apply_if,
+        target_platform,
+        target_arch,
+        warnings_as_errors,
+        suppress_warnings,
+        object_cache,
+    } = args;
+
+    // Explicit options add to the workflow preset
+    let workflow: Option<Workflow> = workflow.map(Into::into);
+    let preset = workflow.map(Workflow::preset).unwrap_or_default();
+    println!("{}", tr!("create.run_id", run = runid::current()));
+    if let Some(workflow) = workflow {
+        println!("{}", tr!("create.workflow", name = workflow));
+    }
+    if !preset.exclude_extensions.is_empty() {
+        filters.exclude_extensions.get_or_insert_default().extend(preset.exclude_extensions.iter().cloned());
+    }
+    if !preset.exclude_dirs.is_empty() {
+        filters.exclude_dirs.get_or_insert_default().extend(preset.exclude_dirs.iter().cloned());
+    }
+    let reproducible = reproducible || preset.reproducible;
+    let binary_delta = binary_delta || preset.binary_delta;
+    let warnings_as_errors = warnings_as_errors || preset.warnings_as_errors;
+    let verify_unchanged = verify_unchanged.unwrap_or(preset.verify_unchanged);
+
+    // Validate arguments
+    // The source may also be a previously created patch file, a disk image or a manifest URL
+    check_source_exists(&source, "Source directory").context("Source directory check failed")?;
+    if !source.is_file() && !remote::is_url(&source) && !ssh::is_ssh_url(&source) {
+        check_is_directory(&source).context("Source directory check failed")?;
+    }
+    
+    check_path_exists(&target, "Target directory").context("Target directory check failed")?;
+    check_is_directory(&target).context("Target directory check failed")?;
+
+    // Name the artifacts from the versions and the current time
+    let template_vars = TemplateVars::new(&source, &target, from_version.as_deref(), to_version.as_deref());
+    let output = template::expand_path(&output, &template_vars)?;
+    if let Some(url) = &upload {
+        remote::remote_file(url)?;
+    }
+    let save_report = save_report.map(|path| template::expand_path(&path, &template_vars)).transpose()?;
+    let html_report = html_report.map(|path| template::expand_path(&path, &template_vars)).transpose()?;
+    // A dry run writes nothing but the caches it was given
+    let (save_report, html_report) = if dry_run { (None, None) } else { (save_report, html_report) };
+    
+    // Display exclude patterns if specified
+    if let Some(exts) = &filters.exclude_extensions {
+        if !exts.is_empty() {
+            println!("{}", tr!("create.excluding_extensions"));
+            for ext in exts {
+                println!("  - {}", ext);
+            }
+        }
+    }
+    
+    if let Some(dirs) = &filters.exclude_dirs {
+        if !dirs.is_empty() {
+            println!("{}", tr!("create.excluding_dirs"));
+            for dir in dirs {
+                println!("  - {}", dir);
+            }
+        }
+    }
+    
+    // Create patch
+    let mut diff_options = diff::DiffOptions::new()
+        .use_diff_patches(use_diff_patches)
+        .check_case_conflicts(check_case_conflicts || preset.check_case_conflicts)
+        .match_extension_renames(match_extension_renames || preset.match_extension_renames)
+        .detect_renames(detect_renames || preset.detect_renames)
+        .ignore_build_stamps(ignore_build_stamps || preset.ignore_build_stamps)
+        .ignore_permissions(ignore_permissions)
+        .inspect_archives(inspect_archives || preset.inspect_archives)
+        .verify_unchanged(verify_unchanged)
+        .lazy_hashing(lazy_hash)
+        .detect_clones(detect_clones)
+        .compare_strategy(compare.into())
+        .paranoid(paranoid)
+        .digest_encoding(digest_encoding.unwrap_or_default())
+        .hash_algorithm(hash_algorithm.unwrap_or_default())
+        .collision_policy(on_hash_collision.into())
+        .error_policy(on_read_error.into())
+        .suppress_warnings(suppress_warnings.into_iter().collect())
+        .progress(scan_progress_bar());
+    if let Some(size) = filters.max_file_size {
+        println!("{}", tr!("create.max_file_size", size = size));
+    }
+    diff_options = add_filter_args(diff_options, filters)?;
+    if let Some(size) = rename_min_size {
+        diff_options = diff_options.rename_min_size(size.bytes());
+    }
+    if let Some(threads) = threads {
+        diff_options = diff_options.threads(threads);
+    }
+    if let Some(dir) = snapshot_dir {
+        diff_options = diff_options.snapshot_dir(dir);
+    }
+    if let Some(dir) = diff_cache {
+        diff_options = diff_options.diff_cache(dir);
+    }
+    if let Some(dir) = hashcache::cache_dir(hash_cache.as_deref()) {
+        diff_options = diff_options.hash_cache(dir);
+    }
+    let tag_rules = tag_rules.map(|path| TagRules::load(&path)).transpose()?;
+    if let Some(rules) = &tag_rules {
+        diff_options = diff_options.tag_rules(rules.clone());
+    }
+    for tag in &only_tags {
+        tags::validate_tag(tag)?;
+    }
+    diff_options.validate()?;
+
+    if estimate {
+        print_estimate(&source, &target, &diff_options, estimate_time.duration());
+        return Ok(());
+    }
+
+    let mut report = diff::compare_directories(&source, &target, &diff_options)?;
+    // A saved report should not have entries without hashes
+    if save_report.is_some() {
+        diff::hash_added_files(&mut report, &target, &diff_options)?;
+    }
+    
+    if let Some(rules) = &tag_rules
+        && !only_tags.is_empty()
+    {
+        let before = report.diffs.len();
+        rules.retain_tagged(&mut report, &only_tags);
+        println!(
+            "{}",
+            tr!(
+                "create.only_tags",
+                tags = only_tags.join(", "),
+                kept = format_number(report.diffs.len() as u64),
+                total = format_number(before as u64),
+            )
+        );
+    }
+    if reproducible {
+        report.make_reproducible();
+    }
+    if let Some(order) = sort {
+        report.sort(order.into());
+    }
+
+    // Check that every file written by the patch can exist on Windows
+    if windows_paths != WindowsPaths::Off {
+        let written = report.diffs.iter()
+            .filter(|d| !matches!(d, diff::DiffType::Removed(_)))
+            .map(|d| d.path());
+        let problems = portability::check_windows_paths(written, windows_max_path);
+        for problem in &problems {
+            let issues: Vec<String> = problem.issues.iter().map(|i| i.to_string()).collect();
+            let message = format!("cannot be created on Windows ({})", issues.join(", "));
+            diff_options.warnings().push(Warning::new(WarningKind::LongPath, Severity::Warning, message).with_path(&problem.path));
+        }
+        report.warnings.extend(diff_options.warnings().take());
+        if windows_paths == WindowsPaths::Deny && !problems.is_empty() {
+            print_warnings(&report.warnings);
+            return Err(anyhow::anyhow!("Patch contains {} paths that cannot be created on Windows", problems.len()));
+        }
+    }
+    // Catch accidental full rebuilds and wrong source directories before the patch is made
+    let change_limits = ChangeLimits {
+        max_changed_percent: max_changed_files.map(|percent| percent.value()),
+        max_changed_bytes: max_changed_size.map(|size| size.bytes()),
+    };
+    let exceeded = change_limits.exceeded(&report.stats);
+    if !exceeded.is_empty() {
+        if on_change_limit == ChangeLimitMode::Deny {
+            print_warnings(&report.warnings);
+            return Err(anyhow::anyhow!(tr!("create.change_limit_exceeded", limits = exceeded.join("; "))));
+        }
+        for message in exceeded {
+            diff_options.warnings().push(Warning::new(WarningKind::ChangeLimit, Severity::Warning, message));
+        }
+        report.warnings.extend(diff_options.warnings().take());
+    }
+    print_warnings(&report.warnings);
+    let failing = warning::count_failing(&report.warnings);
+    if warnings_as_errors && failing > 0 {
+        return Err(anyhow::anyhow!(tr!("create.warnings_as_errors", count = format_number(failing as u64))));
+    }
+
+    let mut counts = DiffCounter::default();
+    // The report is written while the patch is prepared and created
+    let mut report_writer = save_report.map(|path| ReportWriter::new(path).in_background());
+    let mut sinks: Vec<&mut dyn DiffSink> = vec![&mut counts];
+    if let Some(writer) = &mut report_writer {
+        sinks.push(writer);
+    }
+    let mut html_writer = html_report.map(|path| HtmlReport::new(path).title(format!("Changes from {} to {}", template_vars.from, template_vars.to)));
+    if let Some(writer) = &mut html_writer {
+        sinks.push(writer);
+    }
+    sink::run_sinks(&report, &mut sinks)?;
+    if let Some(writer) = &html_writer {
+        println!("{}", tr!("create.html_report_saved", path = writer.path().display()));
+    }
+
+    let diffs = &report.diffs;
+    if diffs.is_empty() {
+        println!("{}", tr!("create.no_differences"));
+        return finish_report(report_writer);
+    }
+    
+    println!("{}", tr!("create.found_differences", count = format_number(counts.total() as u64)));
+    println!("{}", tr!("create.count_added", count = format_number(counts.added as u64)));
+    println!("{}", tr!("create.count_modified", count = format_number(counts.modified as u64)));
+    if use_diff_patches {
+        println!("{}", tr!("create.count_modified_diff", count = format_number(counts.modified_diff as u64)));
+    }
+    println!("{}", tr!("create.count_deleted", count = format_number(counts.removed as u64)));
+    if match_extension_renames || detect_renames || preset.match_extension_renames || preset.detect_renames {
+        println!("{}", tr!("create.count_renamed", count = format_number(counts.renamed as u64)));
+    }
+    if counts.metadata_changed > 0 {
+        println!("{}", tr!("create.count_permissions", count = format_number(counts.metadata_changed as u64)));
+    }
+    if counts.text + counts.binary > 0 {
+        println!(
+            "{}",
+            tr!("create.count_content", text = format_number(counts.text as u64), binary = format_number(counts.binary as u64))
+        );
+    }
+    if let Some(rules) = &tag_rules {
+        print_tag_summary(&rules.summarize(&report));
+    }
+    print_archive_members(&report.archives);
+
+    if dry_run {
+        println!("{}", tr!("dry_run.create"));
+        patch::print_planned_changes(&patch::PatchData::from_diffs(report.diffs, Vec::new()), None);
+        println!("{}", tr!("dry_run.done"));
+        return Ok(());
+    }
+
+    if benchmark {
+        benchmark_compression(&target, diffs)?;
+        return finish_report(report_writer);
+    }
+
+    // Check verification file list
+    for check_file in check_files.iter().filter(|_| source.is_dir()) {
+        let check_path = source.join(check_file);
+        if !check_path.exists() {
+            println!("{}", tr!("create.check_file_missing", path = check_path.display()));
+        }
+    }
+    
+    if check_files.is_empty() {
+        println!("{}", tr!("create.no_check_files"));
+    } else {
+        println!("{}", tr!("create.check_files"));
+        for file in &check_files {
+            println!("  - {}", file);
+        }
+    }
+    
+    // Hook scripts run from the patched directory, so they should exist in the target tree
+    let pre_apply_hook = pre_apply.map(|script| patch::HookCommand { script, args: pre_apply_args });
+    let post_apply_hook = post_apply.map(|script| patch::HookCommand { script, args: post_apply_args });
+    for hook in pre_apply_hook.iter().chain(post_apply_hook.iter()) {
+        hook.validate()?;
+        if !target.join(&hook.script).is_file() {
+            println!("{}", tr!("create.hook_missing", path = hook.script.display()));
+        }
+    }
+
+    // Confirm patch creation
+    if !utils::confirm_action(&tr!("create.confirm"))? {
+        println!("{}", tr!("confirm.operation_cancelled"));
+        return finish_report(report_writer);
+    }
+    diff::hash_added_files(&mut report, &target, &diff_options)?;
+    
+    let options = patch::PatchOptions {
+        check_files,
+        pre_apply_hook,
+        post_apply_hook,
+        reproducible,
+        threads,
+        compression,
+        codec,
+        platform_files,
+        conditional_files: apply_if,
+        object_cache: cache::cache_dir(object_cache.as_deref()),
+        binary_delta,
+        delta_time_budget: delta_time_budget.map(Span::duration),
+        max_delta_file_size: max_delta_file_size.map(ByteSize::bytes),
+        target_platform,
+        target_arch,
+    };
+    patch::create_patch(&source, &target, &output, report, options)?;
+    if let Some(url) = &upload {
+        let patch_file = patch::output_path(&target, &output)?;
+        println!("{}", tr!("create.uploading", url = url));
+        let uploaded = remote::upload(&patch_file, url)?;
+        println!("{}", tr!("create.uploaded", url = uploaded));
+    }
+    finish_report(report_writer)
+}
+
+/// Wait for the report written in the background, if any
+fn finish_report(writer: Option<ReportWriter>) -> Result<()> {
+    if let Some(mut writer) = writer {
+        writer.wait()?;
+        println!("{}", tr!("create.report_saved", path = writer.path().display()));
+    }
+    Ok(())
+}
+
+/// Progress bar of the bytes hashed by scans, one per scanned directory
+fn scan_progress_bar() -> ProgressHook {
+    let current: Mutex<Option<ProgressBar>> = Mutex::new(None);
+    ProgressHook::new(move |progress| {
+        let Ok(mut current) = current.lock() else {
+            return;
+        };
+        if progress.files_hashed == 0 {
+            let pb = ProgressBar::new(progress.bytes_discovered);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            *current = Some(pb);
+        }
+        if let Some(pb) = current.as_ref() {
+            pb.set_position(progress.bytes_hashed);
+            if progress.is_done() {
+                pb.finish_and_clear();
+            }
         }
+    })
+}
+
+/// Options of the `blocks` commands, showing a progress bar for each file
+fn block_options(args: BlockArgs, config: &Config) -> BlockOptions {
+    BlockOptions::new()
+        .block_size(args.block_size.map_or(blocks::DEFAULT_BLOCK_SIZE, ByteSize::bytes))
+        .hash_algorithm(args.hash_algorithm.or(config.hash_algorithm).unwrap_or_default())
+        .state_dir(args.state_dir)
+        .progress(file_progress_bar())
+}
+
+/// Progress bar for hashing one large file after another, named after the file
+fn file_progress_bar() -> ProgressHook {
+    let current: Mutex<Option<(PathBuf, ProgressBar)>> = Mutex::new(None);
+    ProgressHook::new(move |progress| {
+        let Ok(mut current) = current.lock() else {
+            return;
+        };
+        if current.as_ref().is_none_or(|(path, _)| path != progress.dir) {
+            let pb = ProgressBar::new(progress.bytes_discovered);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} {msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, {eta})")
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            pb.set_message(progress.dir.file_name().unwrap_or_default().to_string_lossy().into_owned());
+            *current = Some((progress.dir.to_path_buf(), pb));
+        }
+        if let Some((_, pb)) = current.as_ref() {
+            pb.set_position(progress.bytes_hashed);
+            if progress.is_done() {
+                pb.finish_and_clear();
+                *current = None;
+            }
+        }
+    })
+}
+
+/// Print the warnings of a comparison
+fn print_warnings(warnings: &[Warning]) {
+    if warnings.is_empty() {
+        return;
+    }
+    println!("{}", tr!("create.warnings", count = format_number(warnings.len() as u64)));
+    for warning in warnings {
+        println!("  - {}", warning);
+    }
+}
+
+/// Upper limit of the patch content sampled by `--benchmark`
+const BENCHMARK_SAMPLE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Compress a sample of the files the patch would contain with several settings and
+/// print the size/time trade-offs
+fn benchmark_compression(target: &Path, diffs: &[diff::DiffType]) -> Result<()> {
+    let files: Vec<PathBuf> = diffs.iter()
+        .filter(|d| matches!(d, diff::DiffType::Added(_) | diff::DiffType::Modified(_)))
+        .map(|d| d.path().to_path_buf())
+        .collect();
+    if files.is_empty() {
+        println!("{}", tr!("benchmark.no_files"));
+        return Ok(());
     }
 
+    let sample = compression::BenchmarkSample::read(target, &files, BENCHMARK_SAMPLE_SIZE)?;
+    println!(
+        "{}",
+        tr!(
+            "benchmark.running",
+            files = sample.files(),
+            total = files.len(),
+            size = utils::format_size(sample.size())
+        )
+    );
+    let results = sample.benchmark(&compression::benchmark_candidates())?;
+
+    println!(
+        "  {:<12} {:>12} {:>8} {:>10} {:>12}",
+        tr!("benchmark.column_setting"),
+        tr!("benchmark.column_size"),
+        tr!("benchmark.column_ratio"),
+        tr!("benchmark.column_time"),
+        tr!("benchmark.column_speed")
+    );
+    for result in &results {
+        let seconds = result.duration.as_secs_f64().max(f64::EPSILON);
+        println!(
+            "  {:<12} {:>12} {:>7.1}% {:>9.2}s {:>10}/s",
+            result.settings.to_string(),
+            utils::format_size(result.compressed_size),
+            result.compressed_size as f64 * 100.0 / sample.size().max(1) as f64,
+            seconds,
+            utils::format_size((sample.size() as f64 / seconds) as u64)
+        );
+    }
+    println!("{}", tr!("benchmark.hint"));
     Ok(())
 }
 
+/// Changed members of each inspected archive, the first few by name
+fn print_archive_members(archives: &[diff::ArchiveDiff]) {
+    const SHOWN: usize = 20;
+    for archive in archives {
+        println!("{}", tr!("create.archive_members", path = archive.path.display(), count = format_number(archive.members.len() as u64)));
+        for member in archive.members.iter().take(SHOWN) {
+            println!("    {}: {}", member.kind(), member.path().display());
+        }
+        if archive.members.len() > SHOWN {
+            println!("{}", tr!("create.archive_members_more", count = format_number((archive.members.len() - SHOWN) as u64)));
+        }
+    }
+}
+
+/// Print the recorded phase timings
+fn print_timings() {
+    let phases = timings::take();
+    if phases.is_empty() {
+        return;
+    }
+    println!("{}", tr!("timings.title"));
+    println!(
+        "  {:<40} {:>12} {:>8} {:>11}",
+        tr!("timings.column_phase"),
+        tr!("timings.column_wall"),
+        tr!("timings.column_threads"),
+        tr!("timings.column_cpu")
+    );
+    for phase in &phases {
+        let utilization = match phase.utilization() {
+            Some(utilization) => format!("{:.0}%", utilization * 100.0),
+            None => "-".to_string(),
+        };
+        println!("  {:<40} {:>12} {:>8} {:>11}", phase.name, format!("{:.2?}", phase.wall), phase.threads, utilization);
+    }
+    println!("{}", tr!("timings.hint"));
+}
+
+/// Print the last `last` runs and, per command, how durations and tree sizes changed
+fn print_run_stats(runs: &[stats::RunStats], last: usize) {
+    if runs.is_empty() {
+        println!("{}", tr!("stats.no_runs"));
+        return;
+    }
+    let failed = runs.iter().filter(|run| !run.success).count();
+    println!("{}", tr!("stats.runs", count = format_number(runs.len() as u64), failed = format_number(failed as u64)));
+    println!(
+        "  {:<16} {:<14} {:>10} {:>12} {:>11}  {}",
+        tr!("stats.column_started"),
+        tr!("stats.column_command"),
+        tr!("stats.column_duration"),
+        tr!("stats.column_files"),
+        tr!("stats.column_size"),
+        tr!("stats.column_status")
+    );
+    for run in &runs[runs.len().saturating_sub(last)..] {
+        let (days, secs_of_day) = (run.started_at / 86400, run.started_at % 86400);
+        let (year, month, day) = template::civil_from_days(days as i64);
+        let started = format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60);
+        println!(
+            "  {:<16} {:<14} {:>10} {:>12} {:>11}  {}",
+            started,
+            run.command,
+            format_run_duration(run.duration()),
+            format_number(run.files() as u64),
+            utils::format_size(run.bytes()),
+            tr!(if run.success { "stats.status_ok" } else { "stats.status_failed" })
+        );
+    }
+
+    let trends = stats::Trend::of_runs(runs);
+    if trends.is_empty() {
+        return;
+    }
+    let change = |before: f64, after: f64| if before > 0.0 { format!(" ({:+.0}%)", (after / before - 1.0) * 100.0) } else { String::new() };
+    println!("{}", tr!("stats.trends"));
+    for trend in &trends {
+        let ((earlier_duration, earlier_bytes), (later_duration, later_bytes)) = (trend.earlier, trend.later);
+        println!(
+            "{}",
+            tr!(
+                "stats.trend",
+                command = trend.command,
+                before = format_run_duration(earlier_duration),
+                after = format_run_duration(later_duration),
+                change = change(earlier_duration.as_secs_f64(), later_duration.as_secs_f64()),
+                bytes_before = utils::format_size(earlier_bytes),
+                bytes_after = utils::format_size(later_bytes),
+                bytes_change = change(earlier_bytes as f64, later_bytes as f64)
+            )
+        );
+    }
+}
+
+/// Duration of a run, with tenths of a second below a minute
+fn format_run_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(60) {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        utils::format_duration(duration.as_secs_f64())
+    }
+}
+
+/// Print the object counts and sizes, releases and hit rates of an object cache
+fn print_cache_stats(cache: &cache::ObjectCache) -> Result<()> {
+    let usage = cache.usage()?;
+    let total = usage.total();
+    println!("{}", tr!("cache.stats_title", path = cache.root().display()));
+    println!("{}", tr!("cache.stats_objects", count = format_number(total.count), size = utils::format_size(total.bytes)));
+    println!("{}", tr!("cache.stats_blobs", count = format_number(usage.blobs.count), size = utils::format_size(usage.blobs.bytes)));
+    for (compression, packed) in &usage.packed {
+        println!(
+            "{}",
+            tr!("cache.stats_packed", compression = compression, count = format_number(packed.count), size = utils::format_size(packed.bytes))
+        );
+    }
+    match usage.releases.last() {
+        Some(newest) => println!("{}", tr!("cache.stats_releases", count = format_number(usage.releases.len() as u64), name = newest.name)),
+        None => println!("{}", tr!("cache.stats_no_releases")),
+    }
+    for (label, counts) in [("create", usage.counters.create), ("apply", usage.counters.apply)] {
+        match counts.hit_rate() {
+            Some(rate) => println!(
+                "{}",
+                tr!(
+                    "cache.stats_hit_rate",
+                    label = label,
+                    rate = format!("{:.1}", rate * 100.0),
+                    hits = format_number(counts.hits),
+                    lookups = format_number(counts.hits + counts.misses)
+                )
+            ),
+            None => println!("{}", tr!("cache.stats_no_lookups", label = label)),
+        }
+    }
+    Ok(())
+}
+
+/// Print the file count and size of one side of the comparison
+fn print_scan_estimate(label: &str, path: &Path, estimate: diff::ScanEstimate) {
+    println!(
+        "{}",
+        tr!("estimate.side", label = label, files = estimate.files, size = utils::format_size(estimate.bytes), path = path.display())
+    );
+}
+
+/// Estimate and print a disk image or archive source, if `source` is one
+fn estimate_archive(source: &Path, options: &diff::DiffOptions) -> Option<diff::ScanEstimate> {
+    let estimate = vfs::open_archive(source).and_then(|vfs| vfs.map(|vfs| diff::estimate_vfs(vfs.as_ref(), options)).transpose());
+    match estimate {
+        Ok(Some(estimate)) => {
+            print_scan_estimate(&tr!("estimate.source"), source, estimate);
+            Some(estimate)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            println!("{}", tr!("estimate.source_error", error = format!("{:#}", e)));
+            Some(diff::ScanEstimate::default())
+        }
+    }
+}
+
+/// Print the files and bytes each side will hash, plus the expected run time
+fn print_estimate(source: &Path, target: &Path, options: &diff::DiffOptions, sample_time: Duration) {
+    println!("{}", tr!("estimate.start"));
+    // A patch source is read from its manifest and needs no hashing
+    let source_estimate = if source.is_dir() {
+        let estimate = diff::estimate_directory(source, options);
+        print_scan_estimate(&tr!("estimate.source"), source, estimate);
+        estimate
+    } else if ssh::is_ssh_url(source) {
+        println!("{}", tr!("estimate.source_ssh", path = source.display()));
+        diff::ScanEstimate::default()
+    } else if let Some(estimate) = estimate_archive(source, options) {
+        estimate
+    } else {
+        println!("{}", tr!("estimate.source_manifest", path = source.display()));
+        diff::ScanEstimate::default()
+    };
+    let target_estimate = diff::estimate_directory(target, options);
+    print_scan_estimate(&tr!("estimate.target"), target, target_estimate);
+
+    let total_bytes = source_estimate.bytes + target_estimate.bytes;
+    println!(
+        "{}",
+        tr!("estimate.total", files = source_estimate.files + target_estimate.files, size = utils::format_size(total_bytes))
+    );
+
+    match diff::measure_hash_throughput(target, options, sample_time) {
+        Some(throughput) => {
+            println!(
+                "{}",
+                tr!("estimate.throughput", rate = utils::format_size(throughput as u64), threads = options.thread_count())
+            );
+            println!("{}", tr!("estimate.time", duration = utils::format_duration(total_bytes as f64 / throughput)));
+        }
+        None => println!("{}", tr!("estimate.no_throughput")),
+    }
+}
+
 // Check if running as a patch executable
+#[allow(clippy::collapsible_if)]
 fn is_patch_executable() -> bool {
     // Check command line arguments and executable size/end marker
     match std::env::current_exe() {
         Ok(exe_path) => {
             if let Ok(metadata) = std::fs::metadata(&exe_path) {
-                // If file is large enough and has no specific command line arguments, assume it's a patch file
-                if metadata.len() > 1024 * 1024 && env::args().len() <= 1 {
+                // If file is large enough, it may be a patch file (which accepts its own arguments)
+                if metadata.len() > 1024 * 1024 {
                     // Further check if the end has a PATCH_END marker
                     if let Ok(mut file) = std::fs::File::open(&exe_path) {
-                        use std::io::{Read, Seek, SeekFrom};
-                        
-                        if let Ok(_) = file.seek(SeekFrom::End(-9)) {
-                            let mut buffer = [0u8; 9];
-                            if let Ok(_) = file.read_exact(&mut buffer) {
-                                return &buffer == b"PATCH_END";
-                            }
-                        }
+                        return patch::read_patch_header(&mut file).is_ok();
                     }
                 }
             }
@@ -0,0 +1,417 @@
+use crate::diff::{self, normalize_separators, FileInfo, HashAlgorithm};
+use crate::digest::{self, DigestEncoding};
+use crate::par::*;
+#[cfg(feature = "serde")]
+use crate::staging;
+use crate::runid;
+use crate::utils;
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+/// Current manifest format version
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Maximum number of problems listed in a `Manifest::validate` error
+const MAX_REPORTED_PROBLEMS: usize = 10;
+
+/// An internal inconsistency in a manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestProblem {
+    UnsupportedVersion(u32),  // Written by a newer version of the format
+    DuplicatePath(PathBuf),   // Same path listed more than once
+    UnsafePath(PathBuf),      // Absolute path or `..` component, would escape the tree
+    EmptyHash(PathBuf),       // File without a content hash
+    OtherAlgorithm(PathBuf),  // Hash made with another algorithm than the manifest's
+    TotalSizeOverflow,        // File sizes add up to more than u64::MAX
+}
+
+impl fmt::Display for ManifestProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestProblem::UnsupportedVersion(v) => write!(f, "unsupported manifest version {}", v),
+            ManifestProblem::DuplicatePath(path) => write!(f, "duplicate path '{}'", path.display()),
+            ManifestProblem::UnsafePath(path) => write!(f, "path escapes the tree '{}'", path.display()),
+            ManifestProblem::EmptyHash(path) => write!(f, "empty hash for '{}'", path.display()),
+            ManifestProblem::OtherAlgorithm(path) => write!(f, "hash of '{}' made with another algorithm", path.display()),
+            ManifestProblem::TotalSizeOverflow => write!(f, "total file size overflows"),
+        }
+    }
+}
+
+/// Snapshot of a directory tree: every file with its hash and size
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Manifest {
+    pub version: u32,
+    /// Run that scanned the tree (see `runid::current`), if known
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub run_id: Option<String>,
+    /// Algorithm of every hash in the manifest; not written for SHA-256, so manifests
+    /// from before there was a choice read as what they are
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_sha256"))]
+    pub hash_algorithm: HashAlgorithm,
+    pub files: Vec<FileInfo>,
+}
+
+#[cfg(feature = "serde")]
+fn is_sha256(algorithm: &HashAlgorithm) -> bool {
+    *algorithm == HashAlgorithm::Sha256
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            run_id: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            files: Vec::new(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Build a manifest from a scanned file map, sorted by path; the hash algorithm is
+    /// taken from the files
+    pub fn from_files(files: &HashMap<PathBuf, FileInfo>) -> Self {
+        let mut files: Vec<FileInfo> = files.values().cloned().collect();
+        files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        Manifest {
+            version: MANIFEST_VERSION,
+            run_id: Some(runid::current().to_string()),
+            hash_algorithm: files.iter().find(|info| !info.hash.is_empty()).map(FileInfo::hash_algorithm).unwrap_or_default(),
+            files,
+        }
+    }
+
+    /// Fail unless the hashes can be compared with those made by `algorithm`
+    ///
+    /// Hashes of different algorithms never match, so comparing them would report every
+    /// file as changed. A manifest without files has nothing to compare.
+    pub fn check_algorithm(&self, algorithm: HashAlgorithm) -> Result<()> {
+        if self.hash_algorithm == algorithm || self.files.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "The manifest hashes are {} and cannot be compared with {} hashes; use --hash-algorithm {}",
+            self.hash_algorithm,
+            algorithm,
+            self.hash_algorithm
+        ))
+    }
+
+    /// Rewrite every hash in `encoding`, so hashes compare equal to those of a scan using it
+    pub fn reencode(&mut self, encoding: DigestEncoding) -> Result<()> {
+        for info in &mut self.files {
+            info.hash = encoding
+                .reencode(&info.hash)
+                .with_context(|| format!("Bad hash for '{}'", info.relative_path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Turn the manifest back into a file map keyed by relative path
+    pub fn into_file_map(self) -> HashMap<PathBuf, FileInfo> {
+        self.files
+            .into_iter()
+            .map(|info| (info.relative_path.clone(), info))
+            .collect()
+    }
+
+    /// List internal inconsistencies: duplicate or unsafe paths, empty hashes or hashes of
+    /// another algorithm, and sizes that cannot be added up
+    pub fn problems(&self) -> Vec<ManifestProblem> {
+        let mut problems = Vec::new();
+        if self.version > MANIFEST_VERSION {
+            problems.push(ManifestProblem::UnsupportedVersion(self.version));
+        }
+
+        let mut seen = HashSet::new();
+        let mut total: Option<u64> = Some(0);
+        for info in &self.files {
+            let path = &info.relative_path;
+            if !seen.insert(normalize_separators(path)) {
+                problems.push(ManifestProblem::DuplicatePath(path.clone()));
+            }
+            let is_safe = path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if path.as_os_str().is_empty() || !is_safe {
+                problems.push(ManifestProblem::UnsafePath(path.clone()));
+            }
+            if info.hash.is_empty() {
+                problems.push(ManifestProblem::EmptyHash(path.clone()));
+            } else if info.hash_algorithm() != self.hash_algorithm {
+                problems.push(ManifestProblem::OtherAlgorithm(path.clone()));
+            }
+            total = total.and_then(|sum| sum.checked_add(info.size));
+        }
+        if total.is_none() {
+            problems.push(ManifestProblem::TotalSizeOverflow);
+        }
+        problems
+    }
+
+    /// Fail if the manifest is internally inconsistent (see `problems`)
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let listed: Vec<String> = problems.iter().take(MAX_REPORTED_PROBLEMS).map(|p| p.to_string()).collect();
+        let more = problems.len().saturating_sub(MAX_REPORTED_PROBLEMS);
+        Err(anyhow!(
+            "Invalid manifest ({} problems): {}{}",
+            problems.len(),
+            listed.join("; "),
+            if more > 0 { format!("; and {} more", more) } else { String::new() }
+        ))
+    }
+
+    /// Normalize path separators to `/` and sort entries by path
+    pub fn make_reproducible(&mut self) {
+        for info in &mut self.files {
+            info.relative_path = normalize_separators(&info.relative_path);
+        }
+        self.files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+    }
+
+    /// Check that the files of the manifest exist in a directory with the recorded content
+    ///
+    /// `locate` gives where a manifest path is found, or `None` to leave the file out.
+    /// Files the manifest does not list are not looked at. Hashes are compared whatever
+    /// their encoding, and links by their target.
+    pub fn check_directory(&self, threads: usize, locate: impl Fn(&Path) -> Option<PathBuf> + Sync) -> ManifestCheck {
+        let files: Vec<(&FileInfo, PathBuf)> = self.files.iter().filter_map(|info| Some((info, locate(&info.relative_path)?))).collect();
+        let results: Vec<(&PathBuf, Option<bool>)> = utils::io_thread_pool(threads).install(|| {
+            files.par_iter().map(|(info, path)| (&info.relative_path, file_matches(info, path))).collect()
+        });
+        let mut check = ManifestCheck { checked: results.len(), ..ManifestCheck::default() };
+        for (path, result) in results {
+            match result {
+                Some(true) => {}
+                Some(false) => check.mismatched.push(path.clone()),
+                None => check.missing.push(path.clone()),
+            }
+        }
+        check
+    }
+
+    /// Save the manifest as JSON, e.g. to compare against a build that is no longer on disk
+    #[cfg(feature = "serde")]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.validate()?;
+        let mut json = serde_json::to_string_pretty(self).context("Failed to serialize manifest")?;
+        json.push('\n');
+        staging::write_atomic(path, json.as_bytes()).with_context(|| format!("Failed to write manifest: {}", path.display()))
+    }
+
+    /// Load a manifest saved with `save`, checking it with `validate`
+    #[cfg(feature = "serde")]
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read manifest: {}", path.display()))?;
+        let manifest: Manifest = serde_json::from_slice(&data).with_context(|| format!("Failed to parse manifest: {}", path.display()))?;
+        manifest.validate().with_context(|| format!("Invalid manifest file: {}", path.display()))?;
+        Ok(manifest)
+    }
+}
+
+/// Result of `Manifest::check_directory`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ManifestCheck {
+    /// Number of manifest files looked for
+    pub checked: usize,
+    /// Files whose content or link target differs from the manifest
+    pub mismatched: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+}
+
+impl ManifestCheck {
+    pub fn passed(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Whether the file at `path` has the content `info` records; `None` if there is none
+fn file_matches(info: &FileInfo, path: &Path) -> Option<bool> {
+    if let Some(target) = &info.link_target {
+        fs::symlink_metadata(path).ok()?;
+        return Some(fs::read_link(path).is_ok_and(|actual| actual == *target));
+    }
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_file() || metadata.len() != info.size {
+        return Some(false);
+    }
+    if info.hash.is_empty() {
+        return Some(true);
+    }
+    Some(diff::calculate_file_hash_like(path, &info.hash).is_ok_and(|hash| digest::same_digest(&hash, &info.hash)))
+}
+
+/// Whether `path` is a saved manifest (a JSON file) rather than a patch file or an archive
+pub fn is_manifest_file(path: &Path) -> bool {
+    let mut start = [0u8; 64];
+    let Ok(read) = File::open(path).and_then(|mut file| file.read(&mut start)) else {
+        return false;
+    };
+    path.is_file() && start[..read].iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+}
+
+/// A target file whose content already exists locally under another path
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReusedFile {
+    pub from: PathBuf, // Local file with the same content
+    pub to: FileInfo,  // Target file to create from it
+}
+
+/// What a client has to do to turn its local tree into the target tree
+///
+/// Produced from two manifests so downloaders can handle the transport themselves.
+/// Files that are already in place are not listed. Reused files must be copied before
+/// deleting, since a reuse source may itself be deleted.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DownloadPlan {
+    pub needed: Vec<FileInfo>,   // Files to download
+    pub reuse: Vec<ReusedFile>,  // Files to copy from local content
+    pub delete: Vec<PathBuf>,    // Local files not in the target
+}
+
+impl DownloadPlan {
+    /// Plan the update from `local` to `target`, sorted by path
+    pub fn new(local: &Manifest, target: &Manifest) -> Self {
+        let local_by_path: HashMap<&PathBuf, &FileInfo> =
+            local.files.iter().map(|info| (&info.relative_path, info)).collect();
+        // First local path per hash, in path order so the plan is deterministic
+        let mut local_by_hash: HashMap<&str, &PathBuf> = HashMap::new();
+        let mut sorted_local: Vec<&FileInfo> = local.files.iter().collect();
+        sorted_local.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        for info in sorted_local {
+            local_by_hash.entry(info.hash.as_str()).or_insert(&info.relative_path);
+        }
+
+        let mut plan = DownloadPlan::default();
+        for info in &target.files {
+            match local_by_path.get(&info.relative_path) {
+                Some(existing) if existing.hash == info.hash => {}
+                _ => match local_by_hash.get(info.hash.as_str()) {
+                    Some(from) => plan.reuse.push(ReusedFile { from: (*from).clone(), to: info.clone() }),
+                    None => plan.needed.push(info.clone()),
+                },
+            }
+        }
+
+        let target_paths: HashSet<&PathBuf> = target.files.iter().map(|info| &info.relative_path).collect();
+        plan.delete = local.files.iter()
+            .filter(|info| !target_paths.contains(&info.relative_path))
+            .map(|info| info.relative_path.clone())
+            .collect();
+
+        plan.needed.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        plan.reuse.sort_by(|a, b| a.to.relative_path.cmp(&b.to.relative_path));
+        plan.delete.sort();
+        plan
+    }
+
+    /// Total size of the files to download
+    pub fn download_size(&self) -> u64 {
+        self.needed.iter().map(|info| info.size).sum()
+    }
+
+    /// Save the plan as JSON
+    #[cfg(feature = "serde")]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self).context("Failed to serialize download plan")?;
+        json.push('\n');
+        staging::write_atomic(path, json.as_bytes()).with_context(|| format!("Failed to write download plan: {}", path.display()))
+    }
+
+    /// Load a plan saved with `save`
+    #[cfg(feature = "serde")]
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read download plan: {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse download plan: {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, &str, u64)]) -> Manifest {
+        Manifest {
+            files: files.iter().map(|(path, hash, size)| FileInfo::new(PathBuf::from(path), hash.to_string(), *size)).collect(),
+            ..Manifest::default()
+        }
+    }
+
+    #[test]
+    fn consistent_manifest_has_no_problems() {
+        let manifest = manifest(&[("a.txt", "aa", 1), ("./dir/b.txt", "bb", 2)]);
+        assert!(manifest.problems().is_empty());
+        assert!(manifest.validate().is_ok());
+    }
+
+    #[test]
+    fn duplicate_unsafe_and_empty_entries() {
+        let manifest = manifest(&[
+            ("dir/a.txt", "aa", 1),
+            ("dir/a.txt", "bb", 2),
+            ("../escape", "bb", 1),
+            ("/abs", "cc", 1),
+            ("", "dd", 1),
+            ("empty", "", 0),
+            ("other", "blake3:00", 1),
+        ]);
+        let problems = manifest.problems();
+        assert!(problems.contains(&ManifestProblem::DuplicatePath(PathBuf::from("dir/a.txt"))));
+        assert!(problems.contains(&ManifestProblem::UnsafePath(PathBuf::from("../escape"))));
+        assert!(problems.contains(&ManifestProblem::UnsafePath(PathBuf::from("/abs"))));
+        assert!(problems.contains(&ManifestProblem::UnsafePath(PathBuf::new())));
+        assert!(problems.contains(&ManifestProblem::EmptyHash(PathBuf::from("empty"))));
+        assert!(problems.contains(&ManifestProblem::OtherAlgorithm(PathBuf::from("other"))));
+    }
+
+    #[test]
+    fn newer_version_and_size_overflow() {
+        let mut manifest = manifest(&[("a", "aa", u64::MAX), ("b", "bb", 1)]);
+        manifest.version = MANIFEST_VERSION + 1;
+        assert_eq!(
+            manifest.problems(),
+            vec![ManifestProblem::UnsupportedVersion(MANIFEST_VERSION + 1), ManifestProblem::TotalSizeOverflow]
+        );
+    }
+
+    #[test]
+    fn validate_lists_a_bounded_number_of_problems() {
+        let names: Vec<String> = (0..MAX_REPORTED_PROBLEMS + 3).map(|i| format!("f{}", i)).collect();
+        let files: Vec<(&str, &str, u64)> = names.iter().map(|name| (name.as_str(), "", 0)).collect();
+        let message = manifest(&files).validate().unwrap_err().to_string();
+        assert!(message.starts_with(&format!("Invalid manifest ({} problems)", MAX_REPORTED_PROBLEMS + 3)), "{}", message);
+        assert!(message.ends_with("; and 3 more"), "{}", message);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn save_and_load_refuse_invalid_manifests() {
+        let dir = crate::testutil::TempDir::new();
+        let path = dir.path().join("manifest.json");
+        assert!(manifest(&[("a", "", 0)]).save(&path).is_err());
+        assert!(!path.exists());
+
+        let valid = manifest(&[("a", "aa", 1)]);
+        valid.save(&path).unwrap();
+        assert_eq!(Manifest::load(&path).unwrap(), valid);
+
+        let tampered = fs::read_to_string(&path).unwrap().replace("\"a\"", "\"../a\"");
+        fs::write(&path, tampered).unwrap();
+        let error = format!("{:#}", Manifest::load(&path).unwrap_err());
+        assert!(error.contains("path escapes the tree '../a'"), "{}", error);
+    }
+}
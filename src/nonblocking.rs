@@ -0,0 +1,322 @@
+use crate::diff::{self, DiffOptions, DiffReport, FileComparison, FileInfo, ScanReport};
+use crate::interrupt::CancelToken;
+use crate::vfs::Vfs;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+/// How long a worker thread waits for another task before it exits
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads shared by all blocking tasks, started as tasks need them
+///
+/// At most one thread per CPU is started; further tasks wait in the queue for a free
+/// thread, so a burst of tasks cannot start a burst of threads.
+struct WorkerPool {
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    jobs: VecDeque<Job>,
+    workers: usize,
+    idle: usize,
+}
+
+static POOL: WorkerPool = WorkerPool {
+    state: Mutex::new(PoolState { jobs: VecDeque::new(), workers: 0, idle: 0 }),
+    available: Condvar::new(),
+};
+
+impl WorkerPool {
+    fn max_workers() -> usize {
+        thread::available_parallelism().map_or(4, |threads| threads.get())
+    }
+
+    /// Queue `job`, starting a worker if none is idle and there is room for one
+    fn submit(&'static self, job: Job) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.jobs.push_back(job);
+        if state.idle >= state.jobs.len() {
+            self.available.notify_one();
+            return Ok(());
+        }
+        if state.workers < Self::max_workers() {
+            let spawned = thread::Builder::new().name("diffpatch-worker".to_string()).spawn(move || self.work());
+            match spawned {
+                Ok(_) => state.workers += 1,
+                // The job still runs once a running worker is free
+                Err(_) if state.workers > 0 => {}
+                Err(e) => {
+                    state.jobs.pop_back();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run queued jobs until none arrives for `WORKER_IDLE_TIMEOUT`
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                job();
+                state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+            state.idle += 1;
+            let (next, timeout) = self.available.wait_timeout(state, WORKER_IDLE_TIMEOUT).unwrap_or_else(|e| e.into_inner());
+            state = next;
+            state.idle -= 1;
+            if timeout.timed_out() && state.jobs.is_empty() {
+                state.workers -= 1;
+                return;
+            }
+        }
+    }
+}
+
+/// Blocking work running on a shared worker thread, awaited as a future
+///
+/// Works with any async runtime (tokio, async-std, smol or a hand-written executor): the
+/// future only waits to be woken by the worker, so awaiting it never blocks the runtime.
+/// Workers are shared by all tasks and bounded by the number of CPUs; tasks started
+/// while all of them are busy wait for one to be free. Hashing within the work still
+/// uses the crate's thread pool when the `parallel` feature is on. Dropping the future
+/// before it completes cancels its `CancelToken`, so the work stops at the next file
+/// instead of running on unobserved and holding a worker.
+pub struct BlockingTask<T: Send + 'static> {
+    state: Arc<Mutex<TaskState<T>>>,
+    cancel: Option<CancelToken>,
+    done: bool,
+}
+
+struct TaskState<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T: Send + 'static> BlockingTask<T> {
+    /// Start `work` on a worker thread; `name` names the task in errors
+    pub fn spawn(name: &str, work: impl FnOnce() -> Result<T> + Send + 'static) -> Self {
+        Self::spawn_cancellable(name, None, work)
+    }
+
+    /// Start `work` like `spawn`, cancelling `cancel` when the future is dropped early
+    pub fn spawn_cancellable(name: &str, cancel: Option<CancelToken>, work: impl FnOnce() -> Result<T> + Send + 'static) -> Self {
+        let state = Arc::new(Mutex::new(TaskState { result: None, waker: None }));
+        let shared = Arc::clone(&state);
+        let submitted = POOL.submit(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(work))
+                .unwrap_or_else(|_| Err(anyhow!("Background task panicked")));
+            let mut state = shared.lock().unwrap_or_else(|e| e.into_inner());
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }));
+        if let Err(e) = submitted {
+            let error = Err(e).with_context(|| format!("Failed to start background thread: {}", name));
+            state.lock().unwrap_or_else(|e| e.into_inner()).result = Some(error);
+        }
+        BlockingTask { state, cancel, done: false }
+    }
+}
+
+impl<T: Send + 'static> Future for BlockingTask<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<T>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.result.take() {
+            Some(result) => {
+                drop(state);
+                self.done = true;
+                Poll::Ready(result)
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for BlockingTask<T> {
+    fn drop(&mut self) {
+        if !self.done && let Some(cancel) = &self.cancel {
+            cancel.cancel();
+        }
+    }
+}
+
+impl<T: Send + 'static> fmt::Debug for BlockingTask<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingTask").field("done", &self.done).finish()
+    }
+}
+
+/// Run `work` with `options` on a worker thread
+///
+/// Options without a cancel token get one of their own, which the task cancels when it
+/// is dropped early; a token set by the caller is left to the caller.
+fn spawn_with<T: Send + 'static>(
+    name: &str,
+    options: DiffOptions,
+    work: impl FnOnce(&DiffOptions) -> Result<T> + Send + 'static,
+) -> BlockingTask<T> {
+    let (options, cancel) = match options.cancel() {
+        Some(_) => (options, None),
+        None => {
+            let token = CancelToken::new();
+            (options.cancel_token(token.clone()), Some(token))
+        }
+    };
+    BlockingTask::spawn_cancellable(name, cancel, move || work(&options))
+}
+
+/// Async `diff::scan_directory`
+pub fn scan_directory(dir_path: impl Into<PathBuf>, options: DiffOptions) -> BlockingTask<HashMap<PathBuf, FileInfo>> {
+    let dir_path = dir_path.into();
+    spawn_with("scan", options, move |options| diff::scan_directory(&dir_path, options))
+}
+
+/// Async `diff::scan_directory_report`
+pub fn scan_directory_report(dir_path: impl Into<PathBuf>, options: DiffOptions) -> BlockingTask<ScanReport> {
+    let dir_path = dir_path.into();
+    spawn_with("scan", options, move |options| diff::scan_directory_report(&dir_path, options))
+}
+
+/// Async `diff::scan_source`: a directory, patch, image, archive, manifest or remote tree
+pub fn scan_source(source: impl Into<PathBuf>, options: DiffOptions) -> BlockingTask<HashMap<PathBuf, FileInfo>> {
+    let source = source.into();
+    spawn_with("scan", options, move |options| diff::scan_source(&source, options))
+}
+
+/// Async `diff::scan_vfs`; the task owns `vfs` until it completes
+pub fn scan_vfs<V: Vfs + Send + 'static>(vfs: V, options: DiffOptions) -> BlockingTask<HashMap<PathBuf, FileInfo>> {
+    spawn_with("scan", options, move |options| diff::scan_vfs(&vfs, options))
+}
+
+/// Async `diff::compare_directories`
+pub fn compare_directories(source_dir: impl Into<PathBuf>, target_dir: impl Into<PathBuf>, options: DiffOptions) -> BlockingTask<DiffReport> {
+    let (source_dir, target_dir) = (source_dir.into(), target_dir.into());
+    spawn_with("compare", options, move |options| diff::compare_directories(&source_dir, &target_dir, options))
+}
+
+/// Async `diff::compare_directories_streaming`
+///
+/// `on_diff` runs on the task's worker thread, so it should hand differences on (e.g.
+/// through a channel) rather than block for long and keep the worker from other tasks.
+pub fn compare_directories_streaming(
+    source_dir: impl Into<PathBuf>,
+    target_dir: impl Into<PathBuf>,
+    options: DiffOptions,
+    mut on_diff: impl FnMut(diff::DiffType) -> Result<()> + Send + 'static,
+) -> BlockingTask<DiffReport> {
+    let (source_dir, target_dir) = (source_dir.into(), target_dir.into());
+    spawn_with("compare", options, move |options| {
+        diff::compare_directories_streaming(&source_dir, &target_dir, options, &mut on_diff)
+    })
+}
+
+/// Async `diff::compare_vfs`; the task owns both trees until it completes
+pub fn compare_vfs<S, T>(source: S, target: T, options: DiffOptions) -> BlockingTask<DiffReport>
+where
+    S: Vfs + Send + 'static,
+    T: Vfs + Send + 'static,
+{
+    spawn_with("compare", options, move |options| diff::compare_vfs(&source, &target, options))
+}
+
+/// Async `diff::compare_files`
+pub fn compare_files(a: impl Into<PathBuf>, b: impl Into<PathBuf>, options: DiffOptions) -> BlockingTask<FileComparison> {
+    let (a, b) = (a.into(), b.into());
+    spawn_with("compare", options, move |options| diff::compare_files(&a, &b, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::task::Wake;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Minimal executor: poll `future` on this thread, parking until it is woken
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = TaskContext::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn tasks_share_a_bounded_pool() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..WorkerPool::max_workers() * 4).map(|i| {
+            let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+            BlockingTask::spawn("test", move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(i)
+            })
+        }).collect();
+        let results: Vec<_> = tasks.into_iter().map(|task| block_on(task).unwrap()).collect();
+        assert_eq!(results, (0..results.len()).collect::<Vec<_>>());
+        assert!(most.load(Ordering::SeqCst) <= WorkerPool::max_workers());
+        assert!(POOL.state.lock().unwrap().workers <= WorkerPool::max_workers());
+    }
+
+    #[test]
+    fn panics_fail_the_task_and_keep_the_worker() {
+        let error = block_on(BlockingTask::<()>::spawn("test", || panic!("boom"))).unwrap_err();
+        assert!(error.to_string().contains("panicked"));
+        assert_eq!(block_on(BlockingTask::spawn("test", || Ok(7))).unwrap(), 7);
+    }
+
+    #[test]
+    fn dropping_a_task_cancels_it() {
+        let cancel = CancelToken::new();
+        let (started, wait) = mpsc::channel();
+        let token = cancel.clone();
+        let task = BlockingTask::spawn_cancellable("test", Some(cancel.clone()), move || {
+            started.send(()).unwrap();
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Ok(())
+        });
+        wait.recv().unwrap();
+        drop(task);
+        assert!(cancel.is_cancelled());
+    }
+}
@@ -0,0 +1,71 @@
+#[cfg(feature = "parallel")]
+pub use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+pub use rayon::ThreadPool;
+
+#[cfg(not(feature = "parallel"))]
+pub use sequential::*;
+
+/// Without the `parallel` feature: the parts of rayon used here, as plain iterators on
+/// the calling thread, so the same code compiles in both configurations
+#[cfg(not(feature = "parallel"))]
+mod sequential {
+    /// Stand-in for rayon's thread pool that runs everything on the calling thread
+    #[derive(Debug, Default)]
+    pub struct ThreadPool;
+
+    impl ThreadPool {
+        pub fn install<R>(&self, op: impl FnOnce() -> R) -> R {
+            op()
+        }
+
+        pub fn current_num_threads(&self) -> usize {
+            1
+        }
+    }
+
+    /// `par_iter()` for anything that can be iterated by reference
+    pub trait IntoParallelRefIterator<'data> {
+        type Iter: Iterator;
+
+        fn par_iter(&'data self) -> Self::Iter;
+    }
+
+    impl<'data, I: 'data + ?Sized> IntoParallelRefIterator<'data> for I
+    where
+        &'data I: IntoIterator,
+    {
+        type Iter = <&'data I as IntoIterator>::IntoIter;
+
+        fn par_iter(&'data self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+
+    /// `par_iter_mut()` for anything that can be iterated by mutable reference
+    pub trait IntoParallelRefMutIterator<'data> {
+        type Iter: Iterator;
+
+        fn par_iter_mut(&'data mut self) -> Self::Iter;
+    }
+
+    impl<'data, I: 'data + ?Sized> IntoParallelRefMutIterator<'data> for I
+    where
+        &'data mut I: IntoIterator,
+    {
+        type Iter = <&'data mut I as IntoIterator>::IntoIter;
+
+        fn par_iter_mut(&'data mut self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+
+    /// Scheduling hints of rayon's indexed iterators, which mean nothing here
+    pub trait IndexedParallelIterator: Iterator + Sized {
+        fn with_max_len(self, _max: usize) -> Self {
+            self
+        }
+    }
+
+    impl<I: Iterator> IndexedParallelIterator for I {}
+}
@@ -1,38 +1,585 @@
-use crate::diff::{DiffType, FileInfo, FileDiff, DiffChangeTag};
+use crate::cache::{self, CacheUser, HitCounts, ObjectCache};
+use crate::compression::{self, CodecSettings, CompressionSettings};
+use crate::apply;
+use crate::binary;
+use crate::diff::{normalize_separators, DiffReport, DiffType, FileInfo, FileDiff, FileRename};
+use crate::condition::{self, ApplyEnvironment, Condition};
+use crate::container::{self, PatchContent, SectionReader};
+use crate::content::ContentKind;
+use crate::delta;
+use crate::diff;
+use crate::digest;
+use crate::filter::FilterList;
+use crate::fsops::{self, FileMode, StagedTree};
+use crate::i18n::format_number;
+use crate::interrupt::{self, CancelToken};
+use crate::tr;
+use crate::manifest::Manifest;
+use crate::paths;
+use crate::runid;
+use crate::sink::DiffSink;
+use crate::space::{self, PlannedWrite, SpacePlan, SPACE_RESERVE};
+use crate::staging::{self, StagingDir, StagingFile};
+use crate::timings::Phase;
+use crate::units::Span;
+use crate::utils;
+use crate::vars::{self, ApplyVars, ResolvedPaths};
+use crate::verify::{self, PriorState, RollbackBackup, VerificationRecord};
+use crate::writebehind::WriteBehind;
+use crate::par::*;
+use sha2::{Digest, Sha256};
 use anyhow::{Context, Result, anyhow};
 use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{BufWriter, BufReader, Read, Seek, Write};
-use std::path::{Path, PathBuf};
-use tempfile::tempdir;
-use zip::{write::FileOptions, ZipWriter};
-use rayon::prelude::*;
+use std::io::{BufWriter, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use zip::ZipWriter;
 use std::sync::{Arc, Mutex};
-use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-/// Get IO thread count from environment or use reasonable default
-fn get_io_thread_count() -> usize {
-    match env::var("DIFFPATCH_IO_THREADS") {
-        Ok(val) => val.parse().unwrap_or_else(|_| {
-            let cpus = num_cpus::get();
-            std::cmp::min(cpus, 4)
-        }),
-        Err(_) => {
-            let cpus = num_cpus::get();
-            std::cmp::min(cpus, 4)
+/// Magic marker written at the very end of every patch file
+pub const PATCH_END_MARKER: &[u8; 9] = b"PATCH_END";
+
+/// Trailer size: patch data size (u64) + content size (u64) + end marker
+pub const PATCH_TRAILER_LEN: usize = 8 + 8 + PATCH_END_MARKER.len();
+
+/// Section sizes recorded in the trailer of a patch file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchHeader {
+    pub manifest_size: u64,
+    pub content_size: u64,
+}
+
+impl PatchHeader {
+    /// Offset of the patch data within a patch file of `file_size` bytes
+    pub fn manifest_offset(&self, file_size: u64) -> Result<u64> {
+        file_size
+            .checked_sub(PATCH_TRAILER_LEN as u64)
+            .and_then(|end| end.checked_sub(self.manifest_size))
+            .and_then(|end| end.checked_sub(self.content_size))
+            .ok_or_else(|| anyhow!("Invalid patch file: section sizes exceed file size"))
+    }
+}
+
+/// A script run by the applier before or after the patch is applied
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HookCommand {
+    pub script: PathBuf,   // Relative to the directory being patched
+    pub args: Vec<String>,
+}
+
+impl HookCommand {
+    /// Make sure the script stays inside the directory being patched
+    pub fn validate(&self) -> Result<()> {
+        let is_relative = self.script.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !is_relative || self.script.as_os_str().is_empty() {
+            return Err(anyhow!("Hook script must be a relative path inside the patched directory: {}", self.script.display()));
+        }
+        Ok(())
+    }
+
+    /// Copy with the `${NAME}` placeholders of the script path and arguments resolved
+    pub fn resolve(&self, vars: &ApplyVars) -> Result<HookCommand> {
+        Ok(HookCommand {
+            script: vars.resolve_path(&self.script)?,
+            args: self.args.iter().map(|arg| vars.substitute(arg)).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Run the script with the patched directory as working directory
+    fn run(&self, stage: &str, dir: &Path) -> Result<()> {
+        self.validate()?;
+        let script = dir.join(&self.script);
+        println!("{}", tr!("apply.hook_running", stage = stage, script = script.display()));
+
+        let status = Command::new(&script)
+            .args(&self.args)
+            .current_dir(dir)
+            .status()
+            .with_context(|| format!("Failed to run {} hook: {}", stage, script.display()))?;
+        if !status.success() {
+            return Err(anyhow!("{} hook failed ({}): {}", stage, status, script.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Options for creating a patch
+#[derive(Debug, Clone, Default)]
+pub struct PatchOptions {
+    /// Files that must exist in a directory for the patch to be applied there
+    pub check_files: Vec<String>,
+    pub pre_apply_hook: Option<HookCommand>,
+    pub post_apply_hook: Option<HookCommand>,
+    /// Sort entries, normalize separators and fix timestamps (see `PatchData::make_reproducible`)
+    pub reproducible: bool,
+    /// I/O thread count (see `utils::io_thread_count`)
+    pub threads: Option<usize>,
+    /// Compression of the patch content
+    pub compression: CompressionSettings,
+    /// Codec to compress the content with instead of the ZIP compression (see
+    /// `compression::Codec`); the object cache is not used with one
+    pub codec: Option<CodecSettings>,
+    /// Files that are only applied on one platform; all others are applied everywhere
+    pub platform_files: Vec<PlatformFiles>,
+    /// Files that are only applied where their conditions hold (architecture, installed components, ...)
+    pub conditional_files: Vec<ConditionalFiles>,
+    /// Local object cache to take already compressed contents from and add new ones to
+    pub object_cache: Option<PathBuf>,
+    /// Store modified files as binary deltas against their source version where that is
+    /// much smaller (see `delta::create`)
+    pub binary_delta: bool,
+    /// Time all binary deltas together may take; files not done by then are stored whole
+    pub delta_time_budget: Option<Duration>,
+    /// Largest modified file a binary delta is computed for (at most `delta::MAX_FILE_SIZE`)
+    pub max_delta_file_size: Option<u64>,
+    /// Platform the patch is for; detected from the executables it ships if not given
+    /// (see `PatchData::detect_target`)
+    pub target_platform: Option<String>,
+    /// CPU architecture the patch is for; detected like `target_platform` if not given
+    pub target_arch: Option<String>,
+}
+
+/// Modified files smaller than this are always stored whole
+const MIN_DELTA_FILE_SIZE: u64 = 16 * 1024;
+
+/// A binary delta is only stored if it is at most this fraction of the file size
+const MAX_DELTA_RATIO: f64 = 0.5;
+
+/// Patterns of the files that belong to one platform only
+#[derive(Debug, Clone)]
+pub struct PlatformFiles {
+    pub platform: String,
+    /// Matched like exclude rules: a file matches if any rule matches it or a parent
+    pub patterns: FilterList,
+}
+
+/// Patterns of the files that are only applied where all conditions hold
+#[derive(Debug, Clone)]
+pub struct ConditionalFiles {
+    pub when: Vec<Condition>,
+    /// Matched like exclude rules, as in `PlatformFiles`
+    pub patterns: FilterList,
+}
+
+/// Unix permissions given to the files matching `patterns` when an apply writes them
+#[derive(Debug, Clone)]
+pub struct ModeRule {
+    /// Matched like exclude rules, as in `PlatformFiles`
+    pub patterns: FilterList,
+    pub mode: u32,
+}
+
+/// Largest permission value a mode rule accepts (setuid, setgid, sticky and rwx bits)
+pub const MAX_MODE: u32 = 0o7777;
+
+/// Parse an octal mode like `755` or `0644`
+pub fn parse_mode(text: &str) -> Result<u32> {
+    let digits = text.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= MAX_MODE)
+        .ok_or_else(|| anyhow!("Invalid file mode: {} (expected octal digits like 755 or 0644)", text))
+}
+
+/// Most problems of each kind listed by `print_verification_problems` and `check_base`
+const MAX_LISTED_PROBLEMS: usize = 20;
+
+/// List the files that failed the check after applying
+fn print_verification_problems(record: &VerificationRecord) {
+    println!(
+        "{}",
+        tr!(
+            "apply.verify_failed",
+            mismatched = format_number(record.after.mismatched.len() as u64),
+            missing = format_number(record.after.missing.len() as u64),
+            not_removed = format_number(record.not_removed.len() as u64),
+        )
+    );
+    let kinds = [
+        ("apply.verify_mismatched", &record.after.mismatched),
+        ("apply.verify_missing", &record.after.missing),
+        ("apply.verify_not_removed", &record.not_removed),
+    ];
+    for (key, paths) in kinds {
+        for path in paths.iter().take(MAX_LISTED_PROBLEMS) {
+            println!("{}", tr!(key, path = path.display()));
+        }
+        if paths.len() > MAX_LISTED_PROBLEMS {
+            println!("{}", tr!("apply.verify_more", count = format_number((paths.len() - MAX_LISTED_PROBLEMS) as u64)));
+        }
+    }
+}
+
+/// Mode of the first rule matching the file, if any
+fn templated_mode(rules: &[ModeRule], relative_path: &Path) -> Option<u32> {
+    rules.iter().find(|rule| rule.patterns.is_excluded(relative_path)).map(|rule| rule.mode)
+}
+
+/// Entries of a patch that are only applied where all conditions hold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConditionalEntries {
+    pub when: Vec<Condition>,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Where the patch writes its changes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ApplyStrategy {
+    /// Change files directly in the target directory
+    #[default]
+    InPlace,
+    /// Update a hard-linked copy of the directory, then swap it into place
+    Staged,
+    /// Write the updated tree to a new directory (hard-linking unchanged files) and
+    /// leave the target directory untouched
+    Extract(PathBuf),
+}
+
+/// Options for applying a patch
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Execute the hook scripts defined in the patch (skipped otherwise)
+    pub allow_hooks: bool,
+    pub strategy: ApplyStrategy,
+    /// I/O thread count (see `utils::io_thread_count`)
+    pub threads: Option<usize>,
+    /// Platform whose files to apply (default: the one this program runs on)
+    pub platform: Option<String>,
+    /// Architecture conditions are checked against (default: the one this program runs on)
+    pub arch: Option<String>,
+    /// Optional components installed in the patched directory
+    pub components: Vec<String>,
+    /// Read the patch from this file instead of the running executable
+    pub package: Option<PathBuf>,
+    /// Permissions for the files written from the archive; the first matching rule
+    /// wins, other files get the mode recorded in the patch or else keep the permissions
+    /// of the file they replace (Unix only)
+    pub file_modes: Vec<ModeRule>,
+    /// Local object cache to take known contents from instead of decompressing them,
+    /// and to add the extracted contents to
+    pub object_cache: Option<PathBuf>,
+    /// `NAME=VALUE` file with the values of the `${NAME}` placeholders in the patch
+    pub vars_file: Option<PathBuf>,
+    /// Environment variables placeholders may take their value from, if the vars file
+    /// does not set them
+    pub allow_env: Vec<String>,
+    /// Write an `ApplySimulation` of the apply to this JSON file instead of applying
+    pub simulate: Option<PathBuf>,
+    /// List every file the apply would write, patch, rename or delete, and change nothing
+    pub dry_run: bool,
+    /// Do not check the patched files against the target manifest of the patch
+    pub skip_verification: bool,
+    /// Undo the changes if the patched files fail verification: an in-place apply backs
+    /// up the paths it changes first, a staged or extracted copy is discarded
+    pub rollback_on_failure: bool,
+    /// Write the `VerificationRecord` to this JSON file
+    pub verification_record: Option<PathBuf>,
+    /// Key file to sign the verification record with (HMAC-SHA256); without one the
+    /// record only carries a SHA-256 checksum
+    pub signing_key: Option<PathBuf>,
+    /// Apply a patch built for another platform or architecture (see `PatchData::target_platform`),
+    /// or to files of other versions than it was made from (see `PatchData::checksums`)
+    pub force: bool,
+    /// Stop the apply once this token is cancelled, cleaning up as on Ctrl-C, and fail
+    /// with `interrupt::Cancelled`
+    pub cancel: Option<CancelToken>,
+}
+
+impl ApplyOptions {
+    /// Whether the apply should stop: Ctrl-C was pressed or the cancel token was cancelled
+    pub fn should_stop(&self) -> bool {
+        interrupt::should_stop(self.cancel.as_ref())
+    }
+
+    /// Fail with `interrupt::Interrupted` or `interrupt::Cancelled` if the apply should stop
+    pub fn check_stop(&self) -> Result<()> {
+        interrupt::check_stop(self.cancel.as_ref())
+    }
+
+    /// Values for the placeholders in the patch
+    pub fn variables(&self) -> Result<ApplyVars> {
+        let mut vars = match &self.vars_file {
+            Some(path) => ApplyVars::load(path)?,
+            None => ApplyVars::new(),
+        };
+        vars.add_env(&self.allow_env)?;
+        Ok(vars)
+    }
+
+    /// The system conditional entries are checked against
+    pub fn environment(&self) -> Result<ApplyEnvironment> {
+        let mut env = ApplyEnvironment::current();
+        if let Some(platform) = &self.platform {
+            env.platform = condition::normalize_platform(platform)?;
         }
+        if let Some(arch) = &self.arch {
+            env.arch = condition::normalize_arch(arch)?;
+        }
+        for component in &self.components {
+            env.components.insert(condition::normalize_component(component)?);
+        }
+        Ok(env)
+    }
+}
+
+/// Forecast of an apply, written by `ApplyOptions::simulate` without changing any file
+///
+/// Meant as the record a change-management approval is based on: how much is
+/// transferred, written and deleted, how much temporary space each strategy needs, which
+/// files are not in the state the patch expects, and how long the apply should take.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApplySimulation {
+    pub directory: PathBuf,
+    /// Check files of the patch that do not exist in the directory; the apply refuses to
+    /// run unless this is empty
+    pub missing_check_files: Vec<String>,
+    pub files_written: usize,
+    pub files_renamed: usize,
+    pub files_removed: usize,
+    /// Entries left out for another platform or for components that are not installed
+    pub files_skipped: usize,
+    /// Compressed size of the patch content, what downloading the patch transfers
+    pub download_bytes: u64,
+    /// Bytes decompressed from the patch
+    pub extract_bytes: u64,
+    /// Size of the files written into place
+    pub write_bytes: u64,
+    /// Size of the installed files the patch removes
+    pub delete_bytes: u64,
+    /// Free space on the volume written to, if the platform tells
+    pub available_bytes: Option<u64>,
+    pub strategies: Vec<StrategyForecast>,
+    pub conflicts: Vec<ApplyConflict>,
+    /// Measured decompression and disk write throughput, in bytes per second
+    pub extract_throughput: Option<f64>,
+    pub write_throughput: Option<f64>,
+    pub estimated_seconds: Option<f64>,
+}
+
+impl ApplySimulation {
+    /// Whether the apply would stop with an error: missing check files, too little space
+    /// for the chosen strategy, or a conflict that fails the apply
+    pub fn is_blocked(&self) -> bool {
+        !self.missing_check_files.is_empty()
+            || self.strategies.iter().any(|s| s.selected && s.fits == Some(false))
+            || self.conflicts.iter().any(|c| c.outcome == ConflictOutcome::Fails)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_vec_pretty(self).context("Failed to serialize apply simulation")?;
+        json.push(b'\n');
+        staging::write_atomic(path, &json).with_context(|| format!("Failed to write apply simulation: {}", path.display()))
+    }
+}
+
+/// Disk space one way of applying the patch needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StrategyForecast {
+    /// `in-place`, `staged` or `extract`
+    pub strategy: String,
+    /// Whether the apply was asked to use this strategy
+    pub selected: bool,
+    /// Most temporary space used at any point
+    pub peak_bytes: u64,
+    /// Whether an in-place apply would have to write file by file to fit
+    pub file_by_file: bool,
+    /// Whether the peak fits into the free space, if known
+    pub fits: Option<bool>,
+}
+
+/// A file that is not in the state the patch expects
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApplyConflict {
+    pub path: PathBuf,
+    pub kind: ConflictKind,
+    pub outcome: ConflictOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictKind {
+    /// The file already has the content the patch writes
+    AlreadyUpToDate,
+    /// A file the patch adds exists with other content
+    ExistsWithOtherContent,
+    /// A file the patch changes from a known version has other content
+    LocallyModified,
+    /// A file the patch changes or moves does not exist
+    Missing,
+}
+
+/// What the apply does with a conflicting file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictOutcome {
+    /// The file is written with the patched content
+    Overwritten,
+    /// The file is left as it is and the apply continues
+    Skipped,
+    /// The apply stops before changing anything
+    Fails,
+}
+
+/// Decompression and disk write throughput are measured for at most this long each
+const SIMULATION_SAMPLE_TIME: Duration = Duration::from_millis(500);
+
+/// Largest test file written to measure disk write throughput
+const SIMULATION_WRITE_SAMPLE: usize = 32 * 1024 * 1024;
+
+/// File written next to the patched files when an in-place apply is interrupted
+pub const JOURNAL_FILE_NAME: &str = ".diffpatch-journal.json";
+
+/// Files an interrupted in-place apply had already changed, and the ones it had not
+///
+/// Applying the same patch again reads it to carry on with the pending files, and
+/// deletes it once the apply succeeded.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ApplyJournal {
+    /// SHA-256 of the patch manifest, so only the same patch resumes from the journal
+    #[serde(default)]
+    pub patch: String,
+    pub completed: Vec<PathBuf>,
+    pub pending: Vec<PathBuf>,
+}
+
+impl ApplyJournal {
+    /// Split the files touched by `patch_data` into completed and pending ones
+    fn new(patch_data: &PatchData, patch: String, completed: Vec<PathBuf>) -> Self {
+        let done: HashSet<&PathBuf> = completed.iter().collect();
+        let mut pending: Vec<PathBuf> = patch_data.added_files.iter()
+            .chain(patch_data.modified_files.iter())
+            .map(|info| &info.relative_path)
+            .chain(patch_data.modified_diffs.iter().map(|diff| &diff.relative_path))
+            .chain(patch_data.delta_files.iter().map(|delta| &delta.file.relative_path))
+            .chain(patch_data.removed_files.iter())
+            .chain(patch_data.renamed_files.iter().map(|rename| &rename.to.relative_path))
+            .chain(patch_data.symlinks.iter().map(|link| &link.relative_path))
+            .chain(patch_data.metadata_files.iter().map(|info| &info.relative_path))
+            .chain(patch_data.duplicate_files.iter().map(|duplicate| &duplicate.path))
+            .filter(|path| !done.contains(path))
+            .cloned()
+            .collect();
+        pending.sort();
+        pending.dedup();
+        let mut completed = completed;
+        completed.sort();
+        completed.dedup();
+        ApplyJournal { patch, completed, pending }
+    }
+
+    /// Read the journal at `path`, or `None` if there is none
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let json = match fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read apply journal: {}", path.display())),
+        };
+        serde_json::from_slice(&json).map(Some).with_context(|| format!("Failed to parse apply journal: {}", path.display()))
     }
+
+    /// Write the journal as pretty JSON
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_vec_pretty(self).context("Failed to serialize apply journal")?;
+        json.push(b'\n');
+        staging::write_atomic(path, &json).with_context(|| format!("Failed to write apply journal: {}", path.display()))
+    }
+}
+
+/// An added or modified file whose content is stored in the patch under another path
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateFile {
+    pub path: PathBuf,
+    /// Path of the identical file whose content the archive holds
+    pub content: PathBuf,
 }
 
-/// Patch data structure
+/// A modified file stored as a binary delta against the version it replaces
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeltaFile {
+    /// The file after the patch; the archive entry at its path holds the delta
+    pub file: FileInfo,
+    /// Hash of the installed file the delta applies to
+    pub original_hash: String,
+}
+
+/// Expected content of a file the patch writes, before and after it is applied
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileChecksum {
+    pub path: PathBuf,
+    /// Hash of the version the patch replaces; `None` for added files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_hash: Option<String>,
+    /// Hash of the file after the patch
+    pub hash: String,
+    /// Size of the file after the patch
+    pub size: u64,
+}
+
+/// Patch data structure (the patch manifest embedded in every patch file)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PatchData {
+    /// Run that created the patch (see `runid::current`); `None` in reproducible patches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
     pub check_files: Vec<String>,
     pub added_files: Vec<FileInfo>,
     pub modified_files: Vec<FileInfo>,
     pub modified_diffs: Vec<FileDiff>,
+    /// Modified files stored as binary deltas against the installed version
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delta_files: Vec<DeltaFile>,
     pub removed_files: Vec<PathBuf>,
+    /// Files moved to a new path without content changes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renamed_files: Vec<FileRename>,
+    /// Added/modified files left out of the archive because another file has the same content
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub duplicate_files: Vec<DuplicateFile>,
+    /// Added or changed symbolic links, created with their `link_target`; the archive
+    /// holds nothing for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub symlinks: Vec<FileInfo>,
+    /// Files whose content is unchanged but whose permissions (`FileInfo::mode`) changed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub metadata_files: Vec<FileInfo>,
+    /// Paths of entries applied only on some platforms, by platform; entries not listed
+    /// here are applied on every platform
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub platform_files: BTreeMap<String, Vec<PathBuf>>,
+    /// Entries applied only where their conditions hold; a path listed by several is
+    /// applied if any of them holds
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditional_files: Vec<ConditionalEntries>,
+    /// Every file of the target tree, so the patch can later serve as a comparison source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_manifest: Option<Manifest>,
+    /// Hash and size of every file the patch writes, with the hash of the version each
+    /// modified file replaces; the apply checks the directory against them before
+    /// changing anything, and the result against them if there is no `target_manifest`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<FileChecksum>,
+    /// Codec ID of each archive entry compressed with a codec (see `compression::Codec`),
+    /// by entry name; other entries use the ZIP compression only
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub entry_codecs: BTreeMap<String, String>,
+    /// Script to run before any file is changed (e.g. to stop a service)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_apply_hook: Option<HookCommand>,
+    /// Script to run after all files are changed (e.g. to restart a service)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_apply_hook: Option<HookCommand>,
+    /// Platform the patched files are built for; applying elsewhere needs `ApplyOptions::force`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_platform: Option<String>,
+    /// CPU architecture the patched files are built for, checked like `target_platform`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_arch: Option<String>,
 }
 
 impl PatchData {
@@ -41,35 +588,436 @@ impl PatchData {
         let mut modified_files = Vec::new();
         let mut modified_diffs = Vec::new();
         let mut removed_files = Vec::new();
+        let mut renamed_files = Vec::new();
+        let mut symlinks = Vec::new();
+        let mut metadata_files = Vec::new();
 
         for diff in diffs {
             match diff {
+                DiffType::Added(file_info) | DiffType::Modified(file_info) if file_info.is_symlink() => symlinks.push(file_info),
                 DiffType::Added(file_info) => added_files.push(file_info),
                 DiffType::Modified(file_info) => modified_files.push(file_info),
                 DiffType::ModifiedDiff(file_diff) => modified_diffs.push(file_diff),
                 DiffType::Removed(path) => removed_files.push(path),
+                DiffType::Renamed(rename) => renamed_files.push(rename),
+                DiffType::MetadataChanged(file_info) => metadata_files.push(file_info),
             }
         }
 
         PatchData {
+            run_id: Some(runid::current().to_string()),
             check_files,
             added_files,
             modified_files,
             modified_diffs,
+            delta_files: Vec::new(),
             removed_files,
+            renamed_files,
+            duplicate_files: Vec::new(),
+            symlinks,
+            metadata_files,
+            platform_files: BTreeMap::new(),
+            conditional_files: Vec::new(),
+            target_manifest: None,
+            checksums: Vec::new(),
+            entry_codecs: BTreeMap::new(),
+            pre_apply_hook: None,
+            post_apply_hook: None,
+            target_platform: None,
+            target_arch: None,
+        }
+    }
+
+    /// Sort all entry lists by path, normalize path separators to `/` and drop the run IDs
+    pub fn make_reproducible(&mut self) {
+        self.run_id = None;
+        for info in self.added_files.iter_mut().chain(self.modified_files.iter_mut()).chain(self.symlinks.iter_mut()).chain(self.metadata_files.iter_mut()) {
+            info.relative_path = normalize_separators(&info.relative_path);
+        }
+        for diff in &mut self.modified_diffs {
+            diff.relative_path = normalize_separators(&diff.relative_path);
+        }
+        for delta in &mut self.delta_files {
+            delta.file.relative_path = normalize_separators(&delta.file.relative_path);
+        }
+        for path in &mut self.removed_files {
+            *path = normalize_separators(path);
+        }
+        for rename in &mut self.renamed_files {
+            rename.from = normalize_separators(&rename.from);
+            rename.to.relative_path = normalize_separators(&rename.to.relative_path);
+        }
+        for duplicate in &mut self.duplicate_files {
+            duplicate.path = normalize_separators(&duplicate.path);
+            duplicate.content = normalize_separators(&duplicate.content);
+        }
+        for checksum in &mut self.checksums {
+            checksum.path = normalize_separators(&checksum.path);
+        }
+        for paths in self.platform_files.values_mut() {
+            for path in paths.iter_mut() {
+                *path = normalize_separators(path);
+            }
+            paths.sort();
+        }
+        for entries in &mut self.conditional_files {
+            for path in entries.paths.iter_mut() {
+                *path = normalize_separators(path);
+            }
+            entries.paths.sort();
+        }
+
+        self.added_files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        self.modified_files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        self.modified_diffs.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        self.delta_files.sort_by(|a, b| a.file.relative_path.cmp(&b.file.relative_path));
+        self.removed_files.sort();
+        self.renamed_files.sort_by(|a, b| a.to.relative_path.cmp(&b.to.relative_path));
+        self.duplicate_files.sort_by(|a, b| a.path.cmp(&b.path));
+        self.symlinks.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        self.metadata_files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        self.checksums.sort_by(|a, b| a.path.cmp(&b.path));
+        if let Some(manifest) = &mut self.target_manifest {
+            manifest.make_reproducible();
+            manifest.run_id = None;
+        }
+    }
+
+    /// List the hash and size of every file the patch writes in `checksums`
+    ///
+    /// The hashes of the replaced versions come from the diffs and deltas, and for files
+    /// stored whole from `source_files` (see `DiffReport::source_files`). Sizes of files
+    /// stored as text diffs come from the target manifest, else from `target_dir`.
+    pub fn record_checksums(&mut self, source_files: &HashMap<PathBuf, FileInfo>, target_dir: &Path) {
+        let manifest_sizes: HashMap<&Path, u64> = self.target_manifest.iter()
+            .flat_map(|manifest| &manifest.files)
+            .map(|info| (info.relative_path.as_path(), info.size))
+            .collect();
+        let checksum = |info: &FileInfo, original_hash: Option<String>| FileChecksum {
+            path: info.relative_path.clone(),
+            original_hash,
+            hash: info.hash.clone(),
+            size: info.size,
+        };
+        let mut checksums: Vec<FileChecksum> = self.added_files.iter().map(|info| checksum(info, None)).collect();
+        for info in &self.modified_files {
+            let original_hash = source_files.get(&info.relative_path).map(|source| source.hash.clone()).filter(|hash| !hash.is_empty());
+            checksums.push(checksum(info, original_hash));
+        }
+        for delta in &self.delta_files {
+            checksums.push(checksum(&delta.file, Some(delta.original_hash.clone())));
+        }
+        for file_diff in &self.modified_diffs {
+            let size = manifest_sizes.get(file_diff.relative_path.as_path()).copied()
+                .or_else(|| fs::metadata(target_dir.join(&file_diff.relative_path)).ok().map(|metadata| metadata.len()));
+            checksums.push(FileChecksum {
+                path: file_diff.relative_path.clone(),
+                original_hash: Some(file_diff.original_hash.clone()),
+                hash: file_diff.hash.clone(),
+                size: size.unwrap_or_default(),
+            });
+        }
+        self.checksums = checksums;
+    }
+
+    /// The files the patched directory must hold: the target manifest, else the files
+    /// listed in `checksums`; `None` if the patch has neither
+    pub fn result_manifest(&self) -> Option<Manifest> {
+        if let Some(manifest) = &self.target_manifest {
+            return Some(manifest.clone());
+        }
+        if self.checksums.is_empty() {
+            return None;
+        }
+        let files: HashMap<PathBuf, FileInfo> = self.checksums.iter()
+            .map(|checksum| (checksum.path.clone(), FileInfo::new(checksum.path.clone(), checksum.hash.clone(), checksum.size)))
+            .collect();
+        Some(Manifest::from_files(&files))
+    }
+
+    /// Record `codec` for every entry the archive will hold: the added and modified files
+    /// not left out as duplicates, and the binary deltas
+    pub fn record_entry_codecs(&mut self, codec: &str) {
+        let duplicates: HashSet<&PathBuf> = self.duplicate_files.iter().map(|d| &d.path).collect();
+        self.entry_codecs = self.added_files.iter()
+            .chain(&self.modified_files)
+            .map(|info| &info.relative_path)
+            .filter(|path| !duplicates.contains(path))
+            .chain(self.delta_files.iter().map(|delta| &delta.file.relative_path))
+            .map(|path| (zip_entry_name(path), codec.to_string()))
+            .collect();
+    }
+
+    /// Fail if an entry needs a codec this program does not have
+    pub fn check_codecs(&self) -> Result<()> {
+        let ids: BTreeSet<&String> = self.entry_codecs.values().collect();
+        for id in ids {
+            compression::find_codec(id).context("The patch was compressed with a codec that is not available")?;
+        }
+        Ok(())
+    }
+
+    /// Fail if an entry needs a codec added with `compression::register_codec`, which the
+    /// program of a patch executable may lack
+    pub fn check_builtin_codecs(&self) -> Result<()> {
+        match self.entry_codecs.values().find(|id| !compression::is_builtin_codec(id)) {
+            Some(id) => Err(anyhow!(tr!("create.codec_not_builtin", codec = id))),
+            None => Ok(()),
+        }
+    }
+
+    /// Reader of the content of an archive entry, decoded with its codec if it has one
+    fn entry_reader<'a>(&self, entry: zip::read::ZipFile<'a>) -> Result<Box<dyn Read + 'a>> {
+        match self.entry_codecs.get(entry.name()) {
+            Some(id) => {
+                let name = entry.name().to_string();
+                compression::find_codec(id)?.decompressor(Box::new(entry))
+                    .with_context(|| format!("Failed to decode {} with codec {}", name, id))
+            }
+            None => Ok(Box::new(entry)),
+        }
+    }
+
+    /// Size of the content of each archive entry compressed with a codec, whose ZIP entry
+    /// size is the compressed one, by path
+    fn decoded_sizes(&self) -> HashMap<&Path, u64> {
+        if self.entry_codecs.is_empty() {
+            return HashMap::new();
+        }
+        self.checksums.iter()
+            .filter(|checksum| self.entry_codecs.contains_key(&zip_entry_name(&checksum.path)))
+            .map(|checksum| (checksum.path.as_path(), checksum.size))
+            .collect()
+    }
+
+    /// Store the content of identical added/modified files only once
+    ///
+    /// Of each group of files with the same hash, the first path (in sort order) keeps
+    /// its content in the archive and the others are listed in `duplicate_files`.
+    /// Returns the number of bytes saved.
+    pub fn deduplicate_content(&mut self) -> u64 {
+        let mut files: Vec<&FileInfo> = self.added_files.iter()
+            .chain(self.modified_files.iter())
+            .filter(|info| !info.hash.is_empty())
+            .collect();
+        files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+        let mut stored: HashMap<&str, &PathBuf> = HashMap::new();
+        let mut duplicates = Vec::new();
+        let mut saved = 0;
+        for info in files {
+            match stored.get(info.hash.as_str()) {
+                Some(content) => {
+                    duplicates.push(DuplicateFile { path: info.relative_path.clone(), content: (*content).clone() });
+                    saved += info.size;
+                }
+                None => {
+                    stored.insert(&info.hash, &info.relative_path);
+                }
+            }
+        }
+        self.duplicate_files = duplicates;
+        saved
+    }
+
+    /// Paths of all entries, as written (or removed) in the patched tree
+    fn entry_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.added_files.iter()
+            .chain(self.modified_files.iter())
+            .map(|info| &info.relative_path)
+            .chain(self.modified_diffs.iter().map(|diff| &diff.relative_path))
+            .chain(self.delta_files.iter().map(|delta| &delta.file.relative_path))
+            .chain(self.removed_files.iter())
+            .chain(self.renamed_files.iter().map(|rename| &rename.to.relative_path))
+            .chain(self.symlinks.iter().map(|link| &link.relative_path))
+            .chain(self.metadata_files.iter().map(|info| &info.relative_path))
+    }
+
+    /// Record which entries belong to which platform; a path may belong to several
+    pub fn assign_platforms(&mut self, platforms: &[PlatformFiles]) {
+        let mut platform_files: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for path in self.entry_paths() {
+            for files in platforms.iter().filter(|files| files.patterns.is_excluded(path)) {
+                platform_files.entry(files.platform.clone()).or_default().push(path.clone());
+            }
+        }
+        self.platform_files = platform_files;
+    }
+
+    /// Platform and architecture shared by the executables the patch writes on every
+    /// system, read from their headers in `target_dir` (see `binary::sniff_target`)
+    ///
+    /// Entries only applied on some platforms or under conditions are left out. Either is
+    /// `None` if no executable tells it or the executables disagree.
+    pub fn detect_target(&self, target_dir: &Path) -> (Option<String>, Option<String>) {
+        let restricted: HashSet<&PathBuf> = self.platform_files.values()
+            .flatten()
+            .chain(self.conditional_files.iter().flat_map(|entries| &entries.paths))
+            .collect();
+        let (mut platforms, mut archs) = (BTreeSet::new(), BTreeSet::new());
+        for info in self.added_files.iter().chain(&self.modified_files) {
+            if info.content == Some(ContentKind::Text) || restricted.contains(&info.relative_path) {
+                continue;
+            }
+            if let Ok(Some(target)) = binary::executable_target(&target_dir.join(&info.relative_path)) {
+                platforms.insert(target.platform);
+                archs.extend(target.arch);
+            }
+        }
+        let single = |names: BTreeSet<&str>| (names.len() == 1).then(|| names.into_iter().next().unwrap_or_default().to_string());
+        (single(platforms), single(archs))
+    }
+
+    /// Record which entries are only applied under conditions
+    pub fn assign_conditions(&mut self, conditional: &[ConditionalFiles]) {
+        let mut conditional_files: Vec<ConditionalEntries> = conditional.iter()
+            .map(|files| ConditionalEntries { when: files.when.clone(), paths: Vec::new() })
+            .collect();
+        let paths: Vec<PathBuf> = self.entry_paths().cloned().collect();
+        for path in paths {
+            for (files, entries) in conditional.iter().zip(conditional_files.iter_mut()) {
+                if files.patterns.is_excluded(&path) {
+                    entries.paths.push(path.clone());
+                }
+            }
+        }
+        conditional_files.retain(|entries| !entries.paths.is_empty());
+        self.conditional_files = conditional_files;
+    }
+
+    /// Paths of the patched directory the entries write, move or remove, as written in the patch
+    pub fn destination_paths(&self) -> impl Iterator<Item = &Path> {
+        self.added_files.iter()
+            .chain(self.modified_files.iter())
+            .map(|info| info.relative_path.as_path())
+            .chain(self.modified_diffs.iter().map(|diff| diff.relative_path.as_path()))
+            .chain(self.delta_files.iter().map(|delta| delta.file.relative_path.as_path()))
+            .chain(self.removed_files.iter().map(PathBuf::as_path))
+            .chain(self.renamed_files.iter().flat_map(|rename| [rename.from.as_path(), rename.to.relative_path.as_path()]))
+            .chain(self.duplicate_files.iter().map(|duplicate| duplicate.path.as_path()))
+            .chain(self.symlinks.iter().map(|link| link.relative_path.as_path()))
+            .chain(self.metadata_files.iter().map(|info| info.relative_path.as_path()))
+    }
+
+    /// Names of the `${NAME}` placeholders used by paths, verification files and hooks
+    pub fn variables(&self) -> BTreeSet<String> {
+        let paths = self.destination_paths().map(|path| path.to_string_lossy().into_owned());
+        let hooks = [&self.pre_apply_hook, &self.post_apply_hook]
+            .into_iter()
+            .flatten()
+            .flat_map(|hook| std::iter::once(hook.script.to_string_lossy().into_owned()).chain(hook.args.iter().cloned()));
+        paths.chain(self.check_files.iter().cloned()).chain(hooks).flat_map(|text| vars::placeholders(&text)).collect()
+    }
+
+    /// Whether some entries are only applied on some systems
+    pub fn has_conditions(&self) -> bool {
+        !self.platform_files.is_empty() || !self.conditional_files.is_empty()
+    }
+
+    /// Drop the entries of other platforms and those whose conditions do not hold in
+    /// `env`; returns their paths
+    ///
+    /// A path listed for platforms is only kept on one of them, and a path listed with
+    /// conditions only if one of its condition sets holds.
+    pub fn select_entries(&mut self, env: &ApplyEnvironment) -> HashSet<PathBuf> {
+        let mut skipped = HashSet::new();
+        let groups = [
+            self.platform_files.iter()
+                .map(|(platform, paths)| (*platform == env.platform, paths))
+                .collect::<Vec<_>>(),
+            self.conditional_files.iter()
+                .map(|entries| (condition::all_hold(&entries.when, env), &entries.paths))
+                .collect(),
+        ];
+        for group in groups {
+            let wanted: HashSet<&PathBuf> = group.iter().filter(|(holds, _)| *holds).flat_map(|(_, paths)| paths.iter()).collect();
+            for (_, paths) in group {
+                skipped.extend(paths.iter().filter(|path| !wanted.contains(path)).cloned());
+            }
+        }
+        if skipped.is_empty() {
+            return skipped;
         }
+        self.added_files.retain(|info| !skipped.contains(&info.relative_path));
+        self.modified_files.retain(|info| !skipped.contains(&info.relative_path));
+        self.modified_diffs.retain(|diff| !skipped.contains(&diff.relative_path));
+        self.delta_files.retain(|delta| !skipped.contains(&delta.file.relative_path));
+        self.removed_files.retain(|path| !skipped.contains(path));
+        self.renamed_files.retain(|rename| !skipped.contains(&rename.to.relative_path));
+        self.duplicate_files.retain(|duplicate| !skipped.contains(&duplicate.path));
+        self.symlinks.retain(|link| !skipped.contains(&link.relative_path));
+        self.metadata_files.retain(|info| !skipped.contains(&info.relative_path));
+        self.checksums.retain(|checksum| !skipped.contains(&checksum.path));
+        skipped
+    }
+
+    /// Drop the entries an interrupted apply already carried out (see `ApplyJournal`);
+    /// returns their paths
+    ///
+    /// Recorded permissions are only set once every file is in place, so written files
+    /// that have one stay as permission-only entries.
+    pub fn skip_completed(&mut self, completed: &[PathBuf]) -> HashSet<PathBuf> {
+        let listed: HashSet<&PathBuf> = self.entry_paths().chain(self.duplicate_files.iter().map(|duplicate| &duplicate.path)).collect();
+        let done: HashSet<PathBuf> = completed.iter().filter(|path| listed.contains(path)).cloned().collect();
+        if done.is_empty() {
+            return done;
+        }
+        let with_mode = |path: &PathBuf, hash: &String, size: u64, mode: Option<FileMode>| {
+            mode.map(|mode| FileInfo { mode: Some(mode), ..FileInfo::new(path.clone(), hash.clone(), size) })
+        };
+        let modes: Vec<FileInfo> = self.added_files.iter()
+            .chain(self.modified_files.iter())
+            .chain(self.delta_files.iter().map(|delta| &delta.file))
+            .chain(self.renamed_files.iter().map(|rename| &rename.to))
+            .filter(|info| done.contains(&info.relative_path))
+            .filter_map(|info| with_mode(&info.relative_path, &info.hash, info.size, info.mode))
+            .chain(self.modified_diffs.iter()
+                .filter(|diff| done.contains(&diff.relative_path))
+                .filter_map(|diff| with_mode(&diff.relative_path, &diff.hash, 0, diff.mode)))
+            .collect();
+        self.added_files.retain(|info| !done.contains(&info.relative_path));
+        self.modified_files.retain(|info| !done.contains(&info.relative_path));
+        self.modified_diffs.retain(|diff| !done.contains(&diff.relative_path));
+        self.delta_files.retain(|delta| !done.contains(&delta.file.relative_path));
+        self.removed_files.retain(|path| !done.contains(path));
+        self.renamed_files.retain(|rename| !done.contains(&rename.to.relative_path));
+        self.duplicate_files.retain(|duplicate| !done.contains(&duplicate.path));
+        self.symlinks.retain(|link| !done.contains(&link.relative_path));
+        self.metadata_files.retain(|info| !done.contains(&info.relative_path));
+        self.metadata_files.extend(modes);
+        self.metadata_files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        done
+    }
+
+    /// Serialize the patch manifest
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        if let Some(manifest) = &self.target_manifest {
+            manifest.validate()?;
+        }
+        let mut json = serde_json::to_vec_pretty(self).context("Failed to serialize patch data")?;
+        json.push(b'\n');
+        Ok(json)
     }
 }
 
-/// Create a patch file
-pub fn create_patch(
-    _source_dir: &Path,
-    target_dir: &Path,
-    output_file: &Path,
-    diffs: Vec<DiffType>,
-    check_files: Vec<String>,
-) -> Result<()> {
-    // Modify output_file to be in the target directory
+/// Sink that packages the finished comparison into a patch file with `create_patch`
+#[derive(Debug, Clone)]
+pub struct PatchPackager {
+    pub source_dir: PathBuf,
+    pub target_dir: PathBuf,
+    pub output_file: PathBuf,
+    pub options: PatchOptions,
+}
+
+impl DiffSink for PatchPackager {
+    fn on_finish(&mut self, report: &DiffReport) -> Result<()> {
+        create_patch(&self.source_dir, &self.target_dir, &self.output_file, report.clone(), self.options.clone())
+    }
+}
+
+/// Where `create_patch` writes the patch: the file name of `output_file` in the target
+/// directory, with `.exe` added if it does not end with it
+pub fn output_path(target_dir: &Path, output_file: &Path) -> Result<PathBuf> {
     let output_filename = output_file.file_name().ok_or_else(|| anyhow!("Invalid output filename"))?;
     
     // Check if output filename has .exe extension, if not, add it
@@ -80,21 +1028,115 @@ pub fn create_patch(
         output_filename_str.to_string()
     };
     
-    let target_output_file = target_dir.join(output_filename_with_exe);
+    Ok(target_dir.join(output_filename_with_exe))
+}
+
+/// Create a patch file
+#[allow(clippy::collapsible_if)]
+pub fn create_patch(
+    source_dir: &Path,
+    target_dir: &Path,
+    output_file: &Path,
+    report: DiffReport,
+    options: PatchOptions,
+) -> Result<()> {
+    let reproducible = options.reproducible;
+    if let Some(threads) = options.threads {
+        utils::validate_thread_count(threads)?;
+    }
+    let threads = utils::io_thread_count(options.threads);
+    // The patch is an executable, which only has the built-in codecs
+    if let Some(codec) = options.codec.as_ref().filter(|codec| !compression::is_builtin_codec(&codec.id)) {
+        return Err(anyhow!(tr!("create.codec_not_builtin", codec = codec.id)));
+    }
+    let cache = options.object_cache.as_deref().map(ObjectCache::open).transpose()?;
+    let target_output_file = output_path(target_dir, output_file)?;
     
-    println!("Creating patch file in target directory: {}", target_output_file.display());
+    println!("{}", tr!("create.creating", path = target_output_file.display()));
 
-    // Create temporary directory to store patch data
-    let temp_dir = tempdir().context("Failed to create temporary directory")?;
+    // Stage the content next to the output, so files can be cloned instead of copied
+    staging::remove_stale(target_dir);
+    let temp_dir = StagingDir::new_in(target_dir, "package")?;
     let patch_data_path = temp_dir.path().join("patch_data.json");
     let content_dir = temp_dir.path().join("content");
     fs::create_dir(&content_dir).context("Failed to create content directory")?;
 
     // Save patch data
-    let patch_data = PatchData::from_diffs(diffs, check_files);
-    let patch_json = serde_json::to_string_pretty(&patch_data)
-        .context("Failed to serialize patch data")?;
-    fs::write(&patch_data_path, patch_json).context("Failed to write patch data")?;
+    let serializing = Phase::start("prepare patch data", 1);
+    let mut patch_data = PatchData::from_diffs(report.diffs, options.check_files);
+    patch_data.target_manifest = report.target_manifest;
+    patch_data.pre_apply_hook = options.pre_apply_hook;
+    patch_data.post_apply_hook = options.post_apply_hook;
+    patch_data.assign_platforms(&options.platform_files);
+    for (platform, paths) in &patch_data.platform_files {
+        println!("{}", tr!("create.platform_files", platform = platform, count = format_number(paths.len() as u64)));
+    }
+    patch_data.assign_conditions(&options.conditional_files);
+    for entries in &patch_data.conditional_files {
+        let when: Vec<String> = entries.when.iter().map(|c| c.to_string()).collect();
+        println!("{}", tr!("create.conditional_files", conditions = when.join(", "), count = format_number(entries.paths.len() as u64)));
+    }
+    let (platform, arch) = match (options.target_platform, options.target_arch) {
+        (Some(platform), Some(arch)) => (Some(platform), Some(arch)),
+        (platform, arch) => {
+            let (detected_platform, detected_arch) = patch_data.detect_target(target_dir);
+            (platform.or(detected_platform), arch.or(detected_arch))
+        }
+    };
+    if platform.is_some() || arch.is_some() {
+        let any = || tr!("create.target_any");
+        println!("{}", tr!("create.target", platform = platform.clone().unwrap_or_else(any), arch = arch.clone().unwrap_or_else(any)));
+    }
+    patch_data.target_platform = platform;
+    patch_data.target_arch = arch;
+    let variables = patch_data.variables();
+    if !variables.is_empty() {
+        let names: Vec<String> = variables.iter().map(|name| format!("${{{}}}", name)).collect();
+        println!("{}", tr!("create.variables", names = names.join(", ")));
+    }
+    if options.binary_delta {
+        let limits = DeltaLimits { time_budget: options.delta_time_budget, max_file_size: options.max_delta_file_size };
+        let saved = create_deltas(&mut patch_data, source_dir, target_dir, &content_dir, threads, limits)?;
+        if !patch_data.delta_files.is_empty() {
+            println!(
+                "{}",
+                tr!("create.deltas", count = format_number(patch_data.delta_files.len() as u64), size = utils::format_size(saved))
+            );
+        }
+    }
+    let deduplicated_bytes = patch_data.deduplicate_content();
+    if !patch_data.duplicate_files.is_empty() {
+        println!(
+            "{}",
+            tr!(
+                "create.deduplicated",
+                count = format_number(patch_data.duplicate_files.len() as u64),
+                size = utils::format_size(deduplicated_bytes),
+            )
+        );
+    }
+    patch_data.record_checksums(&report.source_files, target_dir);
+    if let Some(codec) = &options.codec {
+        patch_data.record_entry_codecs(&codec.id);
+        println!("{}", tr!("create.codec", codec = codec));
+    }
+    if reproducible {
+        patch_data.make_reproducible();
+    }
+    serializing.finish();
+
+    // Serialize the patch data while the content is copied and compressed; for large
+    // trees the manifest alone takes seconds to write
+    let patch_data = Arc::new(patch_data);
+    let patch_json = {
+        let (patch_data, patch_data_path) = (Arc::clone(&patch_data), patch_data_path.clone());
+        WriteBehind::spawn("patch-data", move || {
+            let _writing = Phase::start("serialize (background)", 1);
+            let patch_json = patch_data.to_json()?;
+            fs::write(&patch_data_path, &patch_json).context("Failed to write patch data")?;
+            Ok(patch_json)
+        })?
+    };
 
     // Copy added and modified files
     let pb = ProgressBar::new((patch_data.added_files.len() + patch_data.modified_files.len()) as u64);
@@ -105,28 +1147,55 @@ pub fn create_patch(
             .progress_chars("#>-"),
     );
 
-    // Create a list of all files to copy
+    // Create a list of all files to copy, storing duplicated content once
+    let duplicates: HashSet<&PathBuf> = patch_data.duplicate_files.iter().map(|d| &d.path).collect();
     let files_to_copy: Vec<&FileInfo> = patch_data.added_files.iter()
         .chain(patch_data.modified_files.iter())
+        .filter(|info| !duplicates.contains(&info.relative_path))
         .collect();
+    // Contents the object cache holds with this compression are copied from there as they are
+    let mut cached: Vec<(String, PathBuf)> = Vec::new();
+    let cache = cache.filter(|_| options.codec.is_none());
+    let files_to_copy: Vec<&FileInfo> = match &cache {
+        Some(cache) => files_to_copy
+            .into_iter()
+            .filter(|info| {
+                let object = cache.find_packed(&info.hash, options.compression).filter(|_| info.relative_path.to_str().is_some());
+                match object {
+                    Some(object) => {
+                        cached.push((zip_entry_name(&info.relative_path), object));
+                        false
+                    }
+                    None => true,
+                }
+            })
+            .collect(),
+        None => files_to_copy,
+    };
+    let cached_objects: Vec<PathBuf> = cached.iter().map(|(_, object)| object.clone()).collect();
+    pb.set_length(files_to_copy.len() as u64);
     
     // Use atomic counter for progress
     let progress_counter = Arc::new(Mutex::new(0));
     
     // Perform copying in parallel
-    files_to_copy.par_iter().for_each(|file_info| {
+    let copying = Phase::start("copy files", threads);
+    utils::io_thread_pool(threads).install(|| files_to_copy.par_iter().for_each(|file_info| {
+        if interrupt::is_interrupted() {
+            return;
+        }
         let source_file = target_dir.join(&file_info.relative_path);
         let dest_file = content_dir.join(&file_info.relative_path);
 
         // Create target directory
         if let Some(parent) = dest_file.parent() {
-            if let Err(_) = fs::create_dir_all(parent) {
+            if fs::create_dir_all(parent).is_err() {
                 return; // Skip this file on error
             }
         }
 
-        // Copy file
-        if let Err(_) = fs::copy(&source_file, &dest_file) {
+        // Copy file, as a reflink where possible
+        if fsops::clone_or_copy(&source_file, &dest_file).is_err() {
             return; // Skip this file on error
         }
 
@@ -134,47 +1203,233 @@ pub fn create_patch(
         let mut counter = progress_counter.lock().unwrap();
         *counter += 1;
         pb.set_position(*counter);
-    });
+    }));
     
-    pb.finish_with_message("File copying complete");
+    pb.finish_with_message(tr!("create.copy_done"));
+    copying.finish();
+    interrupt::check()?;
 
     // Create ZIP archive
     let zip_path = temp_dir.path().join("patch_content.zip");
-    create_zip_archive(&content_dir, &zip_path)?;
+    create_zip_archive(&content_dir, &zip_path, cached, options.compression, options.codec.as_ref(), reproducible, threads)?;
+    if let Some(cache) = &cache {
+        let stored = store_packed_objects(cache, &zip_path, &files_to_copy, options.compression)?;
+        println!("{}", tr!("create.object_cache", reused = format_number(cached_objects.len() as u64), stored = format_number(stored)));
+        let counts = HitCounts { hits: cached_objects.len() as u64, misses: files_to_copy.len() as u64 };
+        let objects: Vec<PathBuf> = cached_objects
+            .into_iter()
+            .chain(files_to_copy.iter().filter_map(|info| cache.find_packed(&info.hash, options.compression)))
+            .collect();
+        let name = target_output_file.file_name().unwrap_or_default().to_string_lossy();
+        cache.record_release(CacheUser::Create, &name, &objects, counts)?;
+    }
 
+    let patch_json = patch_json.wait()?;
+    let packaging = Phase::start("write executable", 1);
     // Get current executable path
     let current_exe = std::env::current_exe().context("Failed to get current executable path")?;
     
-    // Copy current executable to target directory
-    fs::copy(&current_exe, &target_output_file).with_context(|| {
+    // Copy current executable next to the output, which is only replaced once complete
+    let staged_output = StagingFile::new(&target_output_file)?;
+    fs::copy(&current_exe, staged_output.path()).with_context(|| {
         format!(
             "Failed to copy executable from {} to {}",
             current_exe.display(),
-            target_output_file.display()
+            staged_output.path().display()
         )
     })?;
 
     // Append patch data and content to the end of executable
-    append_data_to_exe(&target_output_file, &patch_data_path, &zip_path)?;
+    append_data_to_exe(staged_output.path(), &patch_data_path, &zip_path)?;
+    staged_output.persist()?;
+    packaging.finish();
 
-    println!("Patch file created successfully:");
-    println!("  Location: {}", target_output_file.display());
-    println!("File statistics:");
-    println!("  Added: {} files", patch_data.added_files.len());
-    println!("  Modified: {} files", patch_data.modified_files.len());
-    println!("  Deleted: {} files", patch_data.removed_files.len());
+    println!("{}", tr!("create.created"));
+    println!("{}", tr!("create.location", path = target_output_file.display()));
+    println!("{}", tr!("create.statistics"));
+    println!("{}", tr!("create.count_added", count = format_number(patch_data.added_files.len() as u64)));
+    println!("{}", tr!("create.stat_modified", count = format_number(patch_data.modified_files.len() as u64)));
+    if !patch_data.delta_files.is_empty() {
+        println!("{}", tr!("create.stat_modified_delta", count = format_number(patch_data.delta_files.len() as u64)));
+    }
+    println!("{}", tr!("create.count_deleted", count = format_number(patch_data.removed_files.len() as u64)));
+    if !patch_data.symlinks.is_empty() {
+        println!("{}", tr!("create.count_symlinks", count = format_number(patch_data.symlinks.len() as u64)));
+    }
+    if !patch_data.metadata_files.is_empty() {
+        println!("{}", tr!("create.count_permissions", count = format_number(patch_data.metadata_files.len() as u64)));
+    }
+    if reproducible {
+        println!("{}", tr!("create.fingerprint", hash = format!("{:x}", Sha256::digest(&patch_json))));
+    }
 
     Ok(())
 }
 
-/// Create ZIP archive
-fn create_zip_archive(source_dir: &Path, zip_path: &Path) -> Result<()> {
+/// Move the modified files whose binary delta against the source version is small
+/// enough to `delta_files`, writing the deltas to `content_dir`; returns the bytes saved
+///
+/// Files whose source version cannot be read stay in `modified_files` and are stored whole.
+/// Bounds on the work `create_deltas` does
+#[derive(Debug, Clone, Copy, Default)]
+struct DeltaLimits {
+    time_budget: Option<Duration>,
+    max_file_size: Option<u64>,
+}
+
+fn create_deltas(patch_data: &mut PatchData, source_dir: &Path, target_dir: &Path, content_dir: &Path, threads: usize, limits: DeltaLimits) -> Result<u64> {
+    // Patch files and disk images as sources have no content to make deltas against
+    if !source_dir.is_dir() {
+        return Ok(0);
+    }
+    let max_file_size = limits.max_file_size.map_or(delta::MAX_FILE_SIZE, |size| size.min(delta::MAX_FILE_SIZE));
+    let candidates: Vec<&FileInfo> = patch_data.modified_files.iter()
+        .filter(|info| (MIN_DELTA_FILE_SIZE..=max_file_size).contains(&info.size))
+        .collect();
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+    println!("{}", tr!("create.computing_deltas", count = format_number(candidates.len() as u64)));
+    let _deltas = Phase::start("binary deltas", threads);
+    let deadline = limits.time_budget.map(|budget| Instant::now() + budget);
+    let over_budget = AtomicUsize::new(0);
+    let results: Vec<Option<(PathBuf, String, u64)>> = utils::io_thread_pool(threads).install(|| {
+        candidates.par_iter().map(|info| {
+            if interrupt::is_interrupted() {
+                return Ok(None);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                over_budget.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            }
+            let (Ok(source), Ok(target)) = (fs::read(source_dir.join(&info.relative_path)), fs::read(target_dir.join(&info.relative_path))) else {
+                return Ok(None);
+            };
+            if source.len() as u64 > max_file_size {
+                return Ok(None);
+            }
+            let Some(delta) = delta::create_until(&source, &target, deadline) else {
+                over_budget.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            };
+            if delta.len() as f64 > target.len() as f64 * MAX_DELTA_RATIO {
+                return Ok(None);
+            }
+            let dest = content_dir.join(&info.relative_path);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+            }
+            fs::write(&dest, &delta).with_context(|| format!("Failed to write file: {}", dest.display()))?;
+            let original_hash = diff::hash_reader_like(source.as_slice(), &info.hash)?;
+            Ok(Some((info.relative_path.clone(), original_hash, (target.len() - delta.len()) as u64)))
+        }).collect::<Result<Vec<_>>>()
+    })?;
+    interrupt::check()?;
+    let over_budget = over_budget.into_inner();
+    if over_budget > 0
+        && let Some(budget) = limits.time_budget
+    {
+        println!("{}", tr!("create.delta_budget", count = format_number(over_budget as u64), budget = Span(budget)));
+    }
+
+    let mut original_hashes: HashMap<PathBuf, String> = HashMap::new();
+    let mut saved = 0;
+    for (path, original_hash, saved_bytes) in results.into_iter().flatten() {
+        original_hashes.insert(path, original_hash);
+        saved += saved_bytes;
+    }
+    let (deltas, full): (Vec<FileInfo>, Vec<FileInfo>) = std::mem::take(&mut patch_data.modified_files)
+        .into_iter()
+        .partition(|info| original_hashes.contains_key(&info.relative_path));
+    patch_data.modified_files = full;
+    patch_data.delta_files = deltas.into_iter()
+        .map(|file| {
+            let original_hash = original_hashes.remove(&file.relative_path).unwrap_or_default();
+            DeltaFile { file, original_hash }
+        })
+        .collect();
+    Ok(saved)
+}
+
+/// Whether the installed file is the version a delta was made against
+fn delta_source_matches(delta: &DeltaFile, installed: &[u8]) -> Result<bool> {
+    Ok(digest::same_digest(&diff::hash_reader_like(installed, &delta.original_hash)?, &delta.original_hash))
+}
+
+/// Rebuild a file stored as a binary delta from the installed version at `installed`
+fn rebuild_delta_file(delta: &DeltaFile, installed: &Path, stored: &[u8]) -> Result<Vec<u8>> {
+    let source = fs::read(installed).with_context(|| format!("Failed to read file: {}", installed.display()))?;
+    if !delta_source_matches(delta, &source)? {
+        return Err(anyhow!(tr!("apply.delta_mismatch", path = installed.display())));
+    }
+    let content = delta::apply(&source, stored).with_context(|| format!("Failed to apply binary delta to {}", installed.display()))?;
+    if !digest::same_digest(&diff::hash_reader_like(content.as_slice(), &delta.file.hash)?, &delta.file.hash) {
+        return Err(anyhow!(tr!("apply.delta_failed", path = installed.display())));
+    }
+    Ok(content)
+}
+
+/// Name of the archive entry holding the content of `relative_path`
+fn zip_entry_name(relative_path: &Path) -> String {
+    paths::to_portable(relative_path)
+}
+
+/// Add the compressed content of `files` in the finished archive at `zip_path` to the
+/// object cache; returns the number of new objects
+fn store_packed_objects(cache: &ObjectCache, zip_path: &Path, files: &[&FileInfo], compression: CompressionSettings) -> Result<u64> {
+    let file = File::open(zip_path).context("Failed to open zip file")?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file)).context("Failed to read zip archive")?;
+    let mut stored = 0;
+    for info in files {
+        let Ok(entry) = archive.by_name(&zip_entry_name(&info.relative_path)) else {
+            continue; // Left out of the archive (unreadable or not valid UTF-8)
+        };
+        if cache.store_packed(&info.hash, compression, entry)? {
+            stored += 1;
+        }
+    }
+    Ok(stored)
+}
+
+/// Where the data of a ZIP archive entry comes from
+#[derive(Debug)]
+enum ZipContent {
+    /// File contents to compress
+    Data(Vec<u8>),
+    /// File larger than `IN_MEMORY_LIMIT`, compressed straight from disk
+    File(PathBuf, u64),
+    /// Packed object in the object cache, copied as it is
+    Cached(PathBuf),
+}
+
+/// Files up to this size are read in parallel ahead of compression; larger ones are
+/// streamed from disk when they are compressed, so large assets are never held in memory
+const IN_MEMORY_LIMIT: u64 = 64 << 20;
+
+/// Relative path and content of a file to be stored in the ZIP archive
+type ZipEntry = (String, ZipContent);
+
+/// Create ZIP archive of the files in `source_dir` and the `cached` (entry name,
+/// packed object) pairs; with a `codec`, files are compressed with it and stored as
+/// they are
+fn create_zip_archive(
+    source_dir: &Path,
+    zip_path: &Path,
+    cached: Vec<(String, PathBuf)>,
+    compression: CompressionSettings,
+    codec: Option<&CodecSettings>,
+    reproducible: bool,
+    threads: usize,
+) -> Result<()> {
     let file = File::create(zip_path).context("Failed to create zip file")?;
     let writer = BufWriter::new(file);
     let mut zip = ZipWriter::new(writer);
-    let options = FileOptions::<()>::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .unix_permissions(0o755);
+    let compression = if codec.is_some() { CompressionSettings::new(zip::CompressionMethod::Stored, None) } else { compression };
+    let mut options = compression.file_options().unix_permissions(0o755);
+    if reproducible {
+        // Fixed timestamp so the archive does not depend on when it was built
+        options = options.last_modified_time(zip::DateTime::default());
+    }
 
     // Collect all files from the directory in parallel
     let files: Vec<_> = walkdir::WalkDir::new(source_dir)
@@ -183,8 +1438,8 @@ fn create_zip_archive(source_dir: &Path, zip_path: &Path) -> Result<()> {
         .filter(|e| e.file_type().is_file())
         .collect();
     
-    if !files.is_empty() {
-        println!("Compressing {} files...", files.len());
+    if !files.is_empty() || !cached.is_empty() {
+        println!("{}", tr!("create.compressing", count = format_number(files.len() as u64)));
         let pb = ProgressBar::new(files.len() as u64);
         pb.set_style(
             ProgressStyle::default_bar()
@@ -194,57 +1449,78 @@ fn create_zip_archive(source_dir: &Path, zip_path: &Path) -> Result<()> {
         );
     
         // Create a thread pool with limited threads to avoid I/O contention
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(get_io_thread_count())
-            .build()
-            .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap());
+        let pool = utils::io_thread_pool(threads);
         
         // Process files in parallel to prepare content
-        let file_contents: Arc<Mutex<Vec<(String, Vec<u8>)>>> = Arc::new(Mutex::new(Vec::with_capacity(files.len())));
+        let reading = Phase::start("read files", threads);
+        let file_contents: Arc<Mutex<Vec<ZipEntry>>> = Arc::new(Mutex::new(Vec::with_capacity(files.len())));
         let progress_counter = Arc::new(Mutex::new(0));
+        // The first file that could not be read; leaving it out would break the patch
+        let read_error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
         
         pool.install(|| {
             files.par_iter().for_each(|entry| {
+                if interrupt::is_interrupted() {
+                    return;
+                }
                 let path = entry.path();
                 let relative_path = match path.strip_prefix(source_dir) {
                     Ok(rel_path) => match rel_path.to_str() {
-                        Some(path_str) => path_str.to_string(),
+                        Some(_) => zip_entry_name(rel_path),
                         None => return, // Skip files with invalid UTF-8 paths
                     },
                     Err(_) => return, // Skip if we can't get relative path
                 };
 
                 // Read file content with buffered IO
-                let mut buffer = Vec::new();
-                let result = (|| -> Result<(), std::io::Error> {
+                let result = (|| -> Result<ZipContent, std::io::Error> {
                     let file = File::open(path)?;
-                    let mut reader = BufReader::with_capacity(65536, file);
-                    reader.read_to_end(&mut buffer)?;
-                    Ok(())
+                    let size = file.metadata()?.len();
+                    if size > IN_MEMORY_LIMIT {
+                        return Ok(ZipContent::File(path.to_path_buf(), size));
+                    }
+                    let mut buffer = Vec::with_capacity(size as usize);
+                    BufReader::with_capacity(65536, file).read_to_end(&mut buffer)?;
+                    Ok(ZipContent::Data(buffer))
                 })();
                 
-                if result.is_ok() {
-                    let mut contents = file_contents.lock().unwrap();
-                    contents.push((relative_path, buffer));
-                    
-                    // Update progress
-                    let mut counter = progress_counter.lock().unwrap();
-                    *counter += 1;
-                    pb.set_position(*counter);
+                match result {
+                    Ok(content) => {
+                        let mut contents = file_contents.lock().unwrap();
+                        contents.push((relative_path, content));
+
+                        // Update progress
+                        let mut counter = progress_counter.lock().unwrap();
+                        *counter += 1;
+                        pb.set_position(*counter);
+                    }
+                    Err(e) => {
+                        let error = anyhow::Error::new(e).context(format!("Failed to read file: {}", path.display()));
+                        read_error.lock().unwrap().get_or_insert(error);
+                    }
                 }
             });
         });
+        if let Some(error) = read_error.into_inner().unwrap() {
+            return Err(error);
+        }
         
         // Extract contents from the mutex
-        let contents = Arc::try_unwrap(file_contents)
+        let mut contents = Arc::try_unwrap(file_contents)
             .unwrap()
             .into_inner()
             .unwrap();
+        contents.extend(cached.into_iter().map(|(name, object)| (name, ZipContent::Cached(object))));
+        // Parallel reads finish in any order, keep the archive layout stable
+        contents.sort_by(|a, b| a.0.cmp(&b.0));
         
-        pb.finish_with_message("File reading complete");
+        pb.finish_with_message(tr!("create.read_done"));
+        reading.finish();
+        interrupt::check()?;
         
         // Add files to the zip sequentially (ZipWriter is not thread-safe)
-        println!("Creating archive...");
+        println!("{}", tr!("create.creating_archive"));
+        let compressing = Phase::start("compress", 1);
         let zip_pb = ProgressBar::new(contents.len() as u64);
         zip_pb.set_style(
             ProgressStyle::default_bar()
@@ -253,17 +1529,39 @@ fn create_zip_archive(source_dir: &Path, zip_path: &Path) -> Result<()> {
                 .progress_chars("#>-"),
         );
         
-        for (i, (relative_path, buffer)) in contents.into_iter().enumerate() {
-            zip.start_file(&relative_path, options)
-                .with_context(|| format!("Failed to start zip file: {}", relative_path))?;
-            
-            zip.write_all(&buffer)
-                .with_context(|| format!("Failed to write to zip: {}", relative_path))?;
+        for (i, (relative_path, content)) in contents.into_iter().enumerate() {
+            interrupt::check()?;
+            match content {
+                ZipContent::Data(buffer) => {
+                    let buffer = match codec {
+                        Some(codec) => codec.compress(&mut buffer.as_slice())?,
+                        None => buffer,
+                    };
+                    zip.start_file(&relative_path, options.large_file(compression::needs_zip64(buffer.len() as u64)))
+                        .with_context(|| format!("Failed to start zip file: {}", relative_path))?;
+                    zip.write_all(&buffer)
+                        .with_context(|| format!("Failed to write to zip: {}", relative_path))?;
+                }
+                ZipContent::File(path, size) => {
+                    let mut file = BufReader::with_capacity(65536, File::open(&path).with_context(|| format!("Failed to read file: {}", path.display()))?);
+                    zip.start_file(&relative_path, options.large_file(compression::needs_zip64(size)))
+                        .with_context(|| format!("Failed to start zip file: {}", relative_path))?;
+                    match codec {
+                        Some(codec) => codec.codec()?.compress(&mut file, &mut zip, codec.level)
+                            .with_context(|| format!("Failed to compress with codec {}", codec))?,
+                        None => {
+                            std::io::copy(&mut file, &mut zip).with_context(|| format!("Failed to write to zip: {}", relative_path))?;
+                        }
+                    }
+                }
+                ZipContent::Cached(object) => cache::copy_packed(&object, &mut zip, &relative_path)?,
+            }
                 
             zip_pb.set_position(i as u64 + 1);
         }
         
-        zip_pb.finish_with_message("Archive creation complete");
+        zip_pb.finish_with_message(tr!("create.archive_done"));
+        compressing.finish();
     }
 
     zip.finish().context("Failed to finish zip file")?;
@@ -284,25 +1582,20 @@ fn append_data_to_exe(exe_path: &Path, patch_data_path: &Path, zip_path: &Path)
         .read_to_end(&mut patch_data)
         .context("Failed to read patch data")?;
 
-    // Write content files
-    let mut zip_data = Vec::new();
-    File::open(zip_path)
-        .context("Failed to open zip file")?
-        .read_to_end(&mut zip_data)
-        .context("Failed to read zip data")?;
+    exe_file.write_all(&patch_data).context("Failed to write patch data to executable")?;
+
+    // Stream content files, which may be larger than memory
+    let mut zip_file = File::open(zip_path).context("Failed to open zip file")?;
+    let zip_data_size = std::io::copy(&mut zip_file, &mut exe_file).context("Failed to write zip data to executable")?;
 
     // Write end markers and offsets
     let patch_data_size = patch_data.len() as u64;
-    let zip_data_size = zip_data.len() as u64;
-
-    exe_file.write_all(&patch_data).context("Failed to write patch data to executable")?;
-    exe_file.write_all(&zip_data).context("Failed to write zip data to executable")?;
     
     exe_file.write_all(&patch_data_size.to_le_bytes()).context("Failed to write patch data size")?;
     exe_file.write_all(&zip_data_size.to_le_bytes()).context("Failed to write zip data size")?;
     
     // Write magic marker
-    exe_file.write_all(b"PATCH_END").context("Failed to write end marker")?;
+    exe_file.write_all(PATCH_END_MARKER).context("Failed to write end marker")?;
 
     Ok(())
 }
@@ -312,81 +1605,750 @@ pub fn verify_directory(check_files: &[String], current_dir: &Path) -> Result<bo
     for file in check_files {
         let file_path = current_dir.join(file);
         if !file_path.exists() {
-            println!("Verification file not found: {}", file_path.display());
+            println!("{}", tr!("apply.check_file_missing", path = file_path.display()));
             return Ok(false);
         }
     }
     Ok(true)
 }
 
-/// Extract patch data from executable
-pub fn extract_patch_data_from_exe() -> Result<(PatchData, Vec<u8>)> {
-    let current_exe = std::env::current_exe().context("Failed to get current executable path")?;
-    
-    let mut file = File::open(&current_exe).with_context(|| {
-        format!("Failed to open executable file: {}", current_exe.display())
-    })?;
-    
-    let file_size = file.metadata().context("Failed to get file metadata")?.len();
-    
-    // Check if file is large enough to contain patch data
-    if file_size < 24 {  // 8 (patch_size) + 8 (zip_size) + 9 (PATCH_END)
+/// Parse the trailer at the end of a patch file
+///
+/// `trailer` holds the last bytes of the file (at least `PATCH_TRAILER_LEN`) and
+/// `file_size` is the total size of that file, used to reject section sizes that
+/// cannot fit. Never panics, whatever the input.
+pub fn parse_patch_header(trailer: &[u8], file_size: u64) -> Result<PatchHeader> {
+    if trailer.len() < PATCH_TRAILER_LEN || file_size < PATCH_TRAILER_LEN as u64 {
         return Err(anyhow!("Invalid patch file: too small"));
     }
-    
-    // Read file end
-    let mut end_marker = [0u8; 9];
-    file.seek(std::io::SeekFrom::End(-9)).context("Failed to seek to end marker")?;
-    file.read_exact(&mut end_marker).context("Failed to read end marker")?;
-    
-    if &end_marker != b"PATCH_END" {
+
+    let trailer = &trailer[trailer.len() - PATCH_TRAILER_LEN..];
+    let (sizes, end_marker) = trailer.split_at(16);
+    if end_marker != PATCH_END_MARKER {
         return Err(anyhow!("Invalid patch file: missing end marker"));
     }
-    
-    // Read patch data and content size
-    let mut size_data = [0u8; 16];
-    file.seek(std::io::SeekFrom::End(-25)).context("Failed to seek to size data")?;
-    file.read_exact(&mut size_data).context("Failed to read size data")?;
-    
-    let patch_data_size = u64::from_le_bytes(size_data[0..8].try_into().unwrap());
-    let zip_data_size = u64::from_le_bytes(size_data[8..16].try_into().unwrap());
-    
-    // Read patch data and content
-    let offset = file_size - 25 - patch_data_size - zip_data_size;
-    
-    file.seek(std::io::SeekFrom::Start(offset)).context("Failed to seek to patch data")?;
-    
-    let mut patch_data_bytes = vec![0u8; patch_data_size as usize];
+
+    let mut manifest_size = [0u8; 8];
+    let mut content_size = [0u8; 8];
+    manifest_size.copy_from_slice(&sizes[0..8]);
+    content_size.copy_from_slice(&sizes[8..16]);
+    let header = PatchHeader {
+        manifest_size: u64::from_le_bytes(manifest_size),
+        content_size: u64::from_le_bytes(content_size),
+    };
+
+    // Both sections must fit in front of the trailer
+    header.manifest_offset(file_size)?;
+    Ok(header)
+}
+
+/// Parse the patch manifest (the serialized `PatchData`) embedded in a patch file
+///
+/// Never panics, whatever the input.
+pub fn parse_manifest_bytes(bytes: &[u8]) -> Result<PatchData> {
+    let patch_data: PatchData = serde_json::from_slice(bytes).context("Failed to deserialize patch data")?;
+    if let Some(manifest) = &patch_data.target_manifest {
+        manifest.validate().context("Patch contains a corrupt target manifest")?;
+    }
+    Ok(patch_data)
+}
+
+/// Read the trailer of a patch file
+pub fn read_patch_header(file: &mut File) -> Result<PatchHeader> {
+    let file_size = file.metadata().context("Failed to get file metadata")?.len();
+    if file_size < PATCH_TRAILER_LEN as u64 {
+        return Err(anyhow!("Invalid patch file: too small"));
+    }
+
+    let mut trailer = [0u8; PATCH_TRAILER_LEN];
+    file.seek(SeekFrom::End(-(PATCH_TRAILER_LEN as i64))).context("Failed to seek to patch trailer")?;
+    file.read_exact(&mut trailer).context("Failed to read patch trailer")?;
+
+    parse_patch_header(&trailer, file_size)
+}
+
+/// Open a patch file and position it at the start of the patch data
+fn open_patch(path: &Path) -> Result<(File, PatchHeader)> {
+    let mut file = File::open(path).with_context(|| {
+        format!("Failed to open executable file: {}", path.display())
+    })?;
+
+    let file_size = file.metadata().context("Failed to get file metadata")?.len();
+    let header = read_patch_header(&mut file)?;
+
+    let offset = header.manifest_offset(file_size)?;
+    file.seek(SeekFrom::Start(offset)).context("Failed to seek to patch data")?;
+
+    Ok((file, header))
+}
+
+/// Size of a patch section to be read into memory; fails instead of truncating it where
+/// it does not fit the address space (on 32-bit targets)
+fn section_len(size: u64, section: &str) -> Result<usize> {
+    usize::try_from(size).map_err(|_| anyhow!("The {} of the patch ({}) is too large to load on this platform", section, utils::format_size(size)))
+}
+
+/// Read the serialized patch manifest of a patch executable, without its content
+pub fn read_manifest_section(path: &Path) -> Result<Vec<u8>> {
+    let (mut file, header) = open_patch(path)?;
+
+    let mut patch_data_bytes = vec![0u8; section_len(header.manifest_size, "manifest")?];
     file.read_exact(&mut patch_data_bytes).context("Failed to read patch data")?;
-    
-    let mut content_bytes = vec![0u8; zip_data_size as usize];
-    file.read_exact(&mut content_bytes).context("Failed to read content data")?;
-    
-    // Deserialize patch data
-    let patch_data: PatchData = serde_json::from_slice(&patch_data_bytes)
-        .context("Failed to deserialize patch data")?;
-    
-    Ok((patch_data, content_bytes))
+
+    Ok(patch_data_bytes)
+}
+
+/// Read the serialized patch manifest of a patch executable, and locate its content
+/// archive, which is left on disk
+pub fn read_patch_sections(path: &Path) -> Result<(Vec<u8>, PatchContent)> {
+    let (mut file, header) = open_patch(path)?;
+
+    let mut patch_data_bytes = vec![0u8; section_len(header.manifest_size, "manifest")?];
+    file.read_exact(&mut patch_data_bytes).context("Failed to read patch data")?;
+    let content_start = file.stream_position().context("Failed to read content data")?;
+
+    Ok((patch_data_bytes, PatchContent::section(path, content_start, header.content_size)))
+}
+
+/// Read only the patch manifest of a patch file, without its content
+///
+/// Like `extract_patch_data`, this takes patches in any container.
+pub fn read_patch_manifest(path: &Path) -> Result<PatchData> {
+    parse_manifest_bytes(&container::read_manifest(path)?)
+}
+
+/// Extract patch data and content from a patch file: a patch executable, or a patch
+/// converted to a ZIP or tar.zst container (see `container::convert`)
+pub fn extract_patch_data(path: &Path) -> Result<(PatchData, PatchContent)> {
+    // Content from a tar container only goes to a temporary file, so it is not compressed again
+    let (_, parts) = container::read(path, CompressionSettings::new(zip::CompressionMethod::Stored, None))?;
+    let patch_data = parse_manifest_bytes(&parts.manifest)?;
+
+    Ok((patch_data, parts.content))
+}
+
+/// Extract patch data from executable
+pub fn extract_patch_data_from_exe() -> Result<(PatchData, PatchContent)> {
+    let current_exe = std::env::current_exe().context("Failed to get current executable path")?;
+    extract_patch_data(&current_exe)
+}
+
+/// Run a hook script if the applier opted in, otherwise tell the user it was skipped
+fn run_hook_if_allowed(stage: &str, hook: &HookCommand, dir: &Path, options: &ApplyOptions) -> Result<()> {
+    if options.allow_hooks {
+        hook.run(stage, dir)
+    } else {
+        println!("{}", tr!("apply.hook_skipped", stage = stage, script = hook.script.display()));
+        Ok(())
+    }
+}
+
+/// Create a file for writing
+///
+/// With `detach` the existing file is unlinked first (keeping its permissions), so other
+/// hard links to it keep the old content.
+/// Most installed executables `check_target` reads the header of
+const MAX_SNIFFED_EXECUTABLES: usize = 32;
+
+/// Refuse a patch built for another platform or architecture than the system it is
+/// applied on (`--platform`/`--arch` stand in for it) or than the installed executables
+/// it replaces, unless `force` is set
+fn check_target(patch_data: &PatchData, env: &ApplyEnvironment, current_dir: &Path, resolved: &ResolvedPaths, force: bool) -> Result<()> {
+    let (platform, arch) = (patch_data.target_platform.as_deref(), patch_data.target_arch.as_deref());
+    if platform.is_none() && arch.is_none() {
+        return Ok(());
+    }
+    let mut mismatches = Vec::new();
+    if let Some(platform) = platform
+        && platform != env.platform
+    {
+        mismatches.push(tr!("apply.target_platform_mismatch", patch = platform, system = env.platform));
+    }
+    if let Some(arch) = arch
+        && arch != env.arch
+    {
+        mismatches.push(tr!("apply.target_arch_mismatch", patch = arch, system = env.arch));
+    }
+
+    // Headers of the installed files tell what the directory was built for, which can
+    // differ from the system (a shared drive, a Wine prefix, an emulated install)
+    let replaced = patch_data.modified_files.iter()
+        .chain(patch_data.delta_files.iter().map(|delta| &delta.file))
+        .filter(|info| info.content != Some(ContentKind::Text));
+    let installed = replaced
+        .filter_map(|info| {
+            let path = resolved.get(&info.relative_path);
+            binary::executable_target(&current_dir.join(path)).ok().flatten().map(|target| (path, target))
+        })
+        .take(MAX_SNIFFED_EXECUTABLES);
+    for (path, target) in installed {
+        let other_platform = platform.is_some_and(|platform| platform != target.platform);
+        let other_arch = arch.is_some_and(|arch| target.arch.is_some_and(|found| found != arch));
+        if other_platform || other_arch {
+            let found = format!("{} {}", target.platform, target.arch.unwrap_or("?"));
+            mismatches.push(tr!("apply.target_installed_mismatch", path = path.display(), found = found));
+            break;
+        }
+    }
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    let any = || tr!("create.target_any");
+    println!(
+        "{}",
+        tr!(
+            "apply.target_mismatch",
+            platform = platform.map(str::to_string).unwrap_or_else(any),
+            arch = arch.map(str::to_string).unwrap_or_else(any),
+        )
+    );
+    for mismatch in &mismatches {
+        println!("  - {}", mismatch);
+    }
+    if !force {
+        return Err(anyhow!(tr!("apply.target_refused")));
+    }
+    println!("{}", tr!("apply.target_forced"));
+    Ok(())
+}
+
+/// Stop unless the files the patch changes are the versions it was made from, or
+/// already patched (see `PatchData::checksums`); `force` only reports them
+fn check_base(patch_data: &PatchData, current_dir: &Path, resolved: &ResolvedPaths, threads: usize, force: bool) -> Result<()> {
+    let replaced: Vec<(&FileChecksum, &str)> = patch_data.checksums.iter()
+        .filter_map(|checksum| Some((checksum, checksum.original_hash.as_deref()?)))
+        .collect();
+    if replaced.is_empty() {
+        return Ok(());
+    }
+    println!("{}", tr!("apply.checking_base", count = format_number(replaced.len() as u64)));
+    let mut other_base: Vec<&Path> = utils::io_thread_pool(threads).install(|| {
+        replaced
+            .par_iter()
+            .filter(|(checksum, original_hash)| {
+                let installed = current_dir.join(resolved.get(&checksum.path));
+                match diff::calculate_file_hash_like(&installed, original_hash) {
+                    Ok(hash) => !digest::same_digest(&hash, original_hash) && !digest::same_digest(&hash, &checksum.hash),
+                    Err(_) => true,
+                }
+            })
+            .map(|(checksum, _)| resolved.get(&checksum.path))
+            .collect()
+    });
+    if other_base.is_empty() {
+        return Ok(());
+    }
+    other_base.sort();
+    println!("{}", tr!("apply.base_mismatch", count = format_number(other_base.len() as u64)));
+    for path in other_base.iter().take(MAX_LISTED_PROBLEMS) {
+        println!("{}", tr!("apply.verify_mismatched", path = path.display()));
+    }
+    if other_base.len() > MAX_LISTED_PROBLEMS {
+        println!("{}", tr!("apply.verify_more", count = format_number((other_base.len() - MAX_LISTED_PROBLEMS) as u64)));
+    }
+    if !force {
+        return Err(anyhow!(tr!("apply.base_refused")));
+    }
+    println!("{}", tr!("apply.target_forced"));
+    Ok(())
+}
+
+fn create_output_file(path: &Path, detach: bool) -> std::io::Result<File> {
+    let permissions = if detach { fsops::detach_file(path)? } else { None };
+    let file = File::create(path)?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions)?;
+    }
+    Ok(file)
+}
+
+/// Sizes of everything an apply writes, to check the free space before starting
+///
+/// Also returns the archive entry holding the content of each written file. Files in
+/// `skipped` are not written, entries in `skipped_entries` are not extracted. Paths are
+/// those of the patch; `resolved` tells where they are on disk.
+fn plan_disk_space(
+    patch_data: &PatchData,
+    archive: &mut zip::ZipArchive<SectionReader>,
+    current_dir: &Path,
+    strategy: &ApplyStrategy,
+    skipped: &HashSet<PathBuf>,
+    skipped_entries: &HashSet<PathBuf>,
+    resolved: &ResolvedPaths,
+) -> Result<(SpacePlan, HashMap<PathBuf, usize>)> {
+    let mut entries: HashMap<PathBuf, (usize, u64)> = HashMap::new();
+    let mut extracted = 0;
+    let decoded_sizes = patch_data.decoded_sizes();
+    for index in 0..archive.len() {
+        let file = archive.by_index(index).context("Failed to access zip file entry")?;
+        if file.is_dir() {
+            continue;
+        }
+        if let Some(path) = file.enclosed_name()
+            && !skipped_entries.contains(&path)
+        {
+            let size = decoded_sizes.get(path.as_path()).copied().unwrap_or(file.size());
+            extracted += size;
+            entries.insert(path, (index, size));
+        }
+    }
+    for duplicate in &patch_data.duplicate_files {
+        if let Some(&entry) = entries.get(&duplicate.content) {
+            entries.insert(duplicate.path.clone(), entry);
+        }
+    }
+    entries.retain(|path, _| !skipped.contains(path));
+    // The archive holds deltas, the files rebuilt from them are written
+    for delta in &patch_data.delta_files {
+        if let Some((_, size)) = entries.get_mut(&delta.file.relative_path) {
+            extracted += delta.file.size.saturating_sub(*size);
+            *size = delta.file.size;
+        }
+    }
+
+    // Staged and extracted copies start out without the files the patch replaces
+    let in_place = *strategy == ApplyStrategy::InPlace;
+    let existing_size = |path: &Path| {
+        if in_place { fs::metadata(current_dir.join(resolved.get(path))).map_or(0, |m| m.len()) } else { 0 }
+    };
+    let writes = entries.iter()
+        .map(|(path, &(_, new_size))| PlannedWrite { path: path.clone(), new_size, old_size: existing_size(path) })
+        .collect();
+    let freed = patch_data.removed_files.iter().map(|path| existing_size(path)).sum();
+    let indices = entries.into_iter().map(|(path, (index, _))| (path, index)).collect();
+    Ok((SpacePlan::new(writes, extracted, freed), indices))
+}
+
+/// Fail if the apply cannot fit on the disk; returns whether it has to go file by file
+///
+/// Extracting everything first needs the most space. An in-place apply that does not
+/// fit that way removes files first and writes one file at a time instead.
+fn check_disk_space(plan: &SpacePlan, current_dir: &Path, strategy: &ApplyStrategy) -> Result<bool> {
+    let volume = match strategy {
+        ApplyStrategy::Extract(dest) => dest.as_path(),
+        _ => current_dir,
+    };
+    let Some(available) = space::available_space(volume) else {
+        return Ok(false);
+    };
+    let budget = available.saturating_sub(SPACE_RESERVE);
+    if plan.extract_first <= budget {
+        return Ok(false);
+    }
+    let in_place = *strategy == ApplyStrategy::InPlace;
+    if in_place && plan.file_by_file <= budget {
+        println!(
+            "{}",
+            tr!("apply.low_space", needed = utils::format_size(plan.extract_first), available = utils::format_size(available))
+        );
+        return Ok(true);
+    }
+    let needed = if in_place { plan.file_by_file } else { plan.extract_first };
+    Err(anyhow!(tr!(
+        "apply.not_enough_space",
+        needed = utils::format_size(needed.saturating_add(SPACE_RESERVE)),
+        available = utils::format_size(available),
+        path = volume.display(),
+    )))
+}
+
+/// Work out what applying the patch to `current_dir` would do, without changing anything
+///
+/// Only a temporary test file is written, to measure how fast the disk writes.
+fn simulate_apply(
+    patch_data: &PatchData,
+    content: &PatchContent,
+    current_dir: &Path,
+    options: &ApplyOptions,
+    skipped: &HashSet<PathBuf>,
+    resolved: &ResolvedPaths,
+    check_files: &[String],
+) -> Result<ApplySimulation> {
+    let mut skipped_entries = skipped.clone();
+    for duplicate in &patch_data.duplicate_files {
+        skipped_entries.remove(&duplicate.content);
+    }
+    let mut archive = zip::ZipArchive::new(content.open()?).context("Failed to read zip archive")?;
+
+    let mut planned = vec![ApplyStrategy::InPlace, ApplyStrategy::Staged];
+    if matches!(options.strategy, ApplyStrategy::Extract(_)) {
+        planned.push(options.strategy.clone());
+    }
+    let mut strategies = Vec::new();
+    let (mut write_bytes, mut files_written, mut available_bytes) = (0, 0, None);
+    for strategy in planned {
+        let (plan, _) = plan_disk_space(patch_data, &mut archive, current_dir, &strategy, skipped, &skipped_entries, resolved)?;
+        let (name, volume) = match &strategy {
+            ApplyStrategy::InPlace => ("in-place", current_dir),
+            ApplyStrategy::Staged => ("staged", current_dir),
+            ApplyStrategy::Extract(dest) => ("extract", dest.as_path()),
+        };
+        let available = space::available_space(volume);
+        let budget = available.map(|bytes| bytes.saturating_sub(SPACE_RESERVE));
+        let file_by_file = strategy == ApplyStrategy::InPlace && budget.is_some_and(|budget| plan.extract_first > budget);
+        let peak_bytes = if file_by_file { plan.file_by_file } else { plan.extract_first };
+        let selected = strategy == options.strategy;
+        if selected {
+            write_bytes = plan.order.iter().map(|write| write.new_size).sum();
+            files_written = plan.order.len();
+            available_bytes = available;
+        }
+        strategies.push(StrategyForecast {
+            strategy: name.to_string(),
+            selected,
+            peak_bytes,
+            file_by_file,
+            fits: budget.map(|budget| peak_bytes <= budget),
+        });
+    }
+
+    // Decompress a sample of the content to measure how fast extraction goes
+    let (mut extract_bytes, mut sampled, mut sample_time) = (0u64, 0u64, Duration::ZERO);
+    let decoded_sizes = patch_data.decoded_sizes();
+    for index in 0..archive.len() {
+        let file = archive.by_index(index).context("Failed to access zip file entry")?;
+        let Some(path) = file.enclosed_name().filter(|path| !file.is_dir() && !skipped_entries.contains(path)) else {
+            continue;
+        };
+        extract_bytes += decoded_sizes.get(path.as_path()).copied().unwrap_or(file.size());
+        if sample_time < SIMULATION_SAMPLE_TIME {
+            let started = Instant::now();
+            let mut content = patch_data.entry_reader(file)?;
+            sampled += std::io::copy(&mut content, &mut std::io::sink()).context("Failed to decompress zip file entry")?;
+            sample_time += started.elapsed();
+        }
+    }
+    let extract_throughput = (sampled > 0).then(|| sampled as f64 / sample_time.as_secs_f64().max(1e-6));
+    let write_volume = match &options.strategy {
+        ApplyStrategy::Extract(dest) if dest.is_dir() => dest.as_path(),
+        _ => current_dir,
+    };
+    let write_throughput = measure_write_throughput(write_volume, write_bytes);
+    let estimated_seconds = extract_throughput.zip(write_throughput).map(|(extract, write)| {
+        extract_bytes as f64 / extract + write_bytes as f64 / write
+    });
+
+    let delete_bytes = patch_data.removed_files.iter()
+        .filter_map(|path| fs::metadata(current_dir.join(resolved.get(path))).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    Ok(ApplySimulation {
+        directory: current_dir.to_path_buf(),
+        missing_check_files: check_files.iter().filter(|file| !current_dir.join(file).exists()).cloned().collect(),
+        files_written,
+        files_renamed: patch_data.renamed_files.len(),
+        files_removed: patch_data.removed_files.len(),
+        files_skipped: skipped.len(),
+        download_bytes: content.len(),
+        extract_bytes,
+        write_bytes,
+        delete_bytes,
+        available_bytes,
+        strategies,
+        conflicts: find_conflicts(patch_data, current_dir, resolved, !options.force),
+        extract_throughput,
+        write_throughput,
+        estimated_seconds,
+    })
+}
+
+/// Files that are not in the state the patch expects, and what the apply does with them
+///
+/// With `check_base`, changed files that are not the version the patch was made from
+/// stop the apply (see `check_base`).
+fn find_conflicts(patch_data: &PatchData, current_dir: &Path, resolved: &ResolvedPaths, check_base: bool) -> Vec<ApplyConflict> {
+    // Hash of the installed file made like `reference`, `None` if it does not exist or
+    // cannot be read
+    let installed_hash = |path: &Path, reference: &str| {
+        let installed = current_dir.join(resolved.get(path));
+        installed.is_file().then(|| diff::calculate_file_hash_like(&installed, reference).ok()).flatten()
+    };
+    let mut conflicts = Vec::new();
+    let mut push = |path: &Path, kind, outcome| conflicts.push(ApplyConflict { path: path.to_path_buf(), kind, outcome });
+    let original_hashes: HashMap<&Path, &str> = patch_data.checksums.iter()
+        .filter_map(|checksum| Some((checksum.path.as_path(), checksum.original_hash.as_deref()?)))
+        .collect();
+    let check_base = check_base && !original_hashes.is_empty();
+    let other_base = |outcome| if check_base { ConflictOutcome::Fails } else { outcome };
+
+    for info in &patch_data.added_files {
+        match installed_hash(&info.relative_path, &info.hash) {
+            Some(hash) if digest::same_digest(&hash, &info.hash) => push(&info.relative_path, ConflictKind::AlreadyUpToDate, ConflictOutcome::Overwritten),
+            Some(_) => push(&info.relative_path, ConflictKind::ExistsWithOtherContent, ConflictOutcome::Overwritten),
+            None => {}
+        }
+    }
+    // Full files of patches without checksums carry no source hash, so then only a
+    // missing or already updated file shows
+    for info in &patch_data.modified_files {
+        let original_hash = original_hashes.get(info.relative_path.as_path());
+        let outcome = if original_hash.is_some() { other_base(ConflictOutcome::Overwritten) } else { ConflictOutcome::Overwritten };
+        match installed_hash(&info.relative_path, &info.hash) {
+            Some(hash) if digest::same_digest(&hash, &info.hash) => push(&info.relative_path, ConflictKind::AlreadyUpToDate, ConflictOutcome::Overwritten),
+            Some(hash) => {
+                if original_hash.is_some_and(|original_hash| !digest::same_digest(&hash, original_hash)) {
+                    push(&info.relative_path, ConflictKind::LocallyModified, outcome);
+                }
+            }
+            None => push(&info.relative_path, ConflictKind::Missing, outcome),
+        }
+    }
+    // Text diffs that do not apply are skipped, binary deltas that do not apply stop the apply
+    let changed_from_known_version = patch_data.modified_diffs.iter()
+        .map(|diff| (&diff.relative_path, &diff.original_hash, &diff.hash, ConflictOutcome::Skipped))
+        .chain(patch_data.delta_files.iter().map(|delta| (&delta.file.relative_path, &delta.original_hash, &delta.file.hash, ConflictOutcome::Fails)));
+    for (path, original_hash, hash, outcome) in changed_from_known_version {
+        match installed_hash(path, hash) {
+            Some(installed) if digest::same_digest(&installed, original_hash) => {}
+            Some(installed) if digest::same_digest(&installed, hash) => push(path, ConflictKind::AlreadyUpToDate, outcome),
+            Some(_) => push(path, ConflictKind::LocallyModified, other_base(outcome)),
+            None => push(path, ConflictKind::Missing, other_base(outcome)),
+        }
+    }
+    for rename in &patch_data.renamed_files {
+        if !current_dir.join(resolved.get(&rename.from)).is_file() {
+            push(&rename.from, ConflictKind::Missing, ConflictOutcome::Skipped);
+        }
+    }
+    conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+    conflicts
+}
+
+/// Bytes per second written to a temporary file in `dir`, with up to `bytes` (capped at
+/// `SIMULATION_WRITE_SAMPLE`) written and synced; `None` if nothing could be written
+fn measure_write_throughput(dir: &Path, bytes: u64) -> Option<f64> {
+    let staging = StagingDir::new_in(dir, "simulate").ok()?;
+    let mut file = File::create(staging.path().join("sample")).ok()?;
+    let block = vec![0x5au8; 1024 * 1024];
+    let limit = bytes.clamp(block.len() as u64, SIMULATION_WRITE_SAMPLE as u64);
+    let started = Instant::now();
+    let mut written = 0u64;
+    while written < limit && started.elapsed() < SIMULATION_SAMPLE_TIME {
+        let len = block.len().min((limit - written) as usize);
+        file.write_all(&block[..len]).ok()?;
+        written += len as u64;
+    }
+    file.sync_all().ok()?;
+    Some(written as f64 / started.elapsed().as_secs_f64().max(1e-6))
+}
+
+/// Print the main figures of a simulation
+fn print_simulation(simulation: &ApplySimulation) {
+    println!(
+        "{}",
+        tr!(
+            "apply.simulation_summary",
+            written = format_number(simulation.files_written as u64),
+            write_size = utils::format_size(simulation.write_bytes),
+            renamed = format_number(simulation.files_renamed as u64),
+            removed = format_number(simulation.files_removed as u64),
+            delete_size = utils::format_size(simulation.delete_bytes),
+        )
+    );
+    for strategy in &simulation.strategies {
+        let key = if strategy.fits == Some(false) { "apply.simulation_space_short" } else { "apply.simulation_space" };
+        println!("{}", tr!(key, strategy = strategy.strategy, size = utils::format_size(strategy.peak_bytes)));
+    }
+    if !simulation.missing_check_files.is_empty() {
+        println!("{}", tr!("apply.simulation_check_files", count = format_number(simulation.missing_check_files.len() as u64)));
+    }
+    if !simulation.conflicts.is_empty() {
+        let failing = simulation.conflicts.iter().filter(|c| c.outcome == ConflictOutcome::Fails).count();
+        println!(
+            "{}",
+            tr!(
+                "apply.simulation_conflicts",
+                count = format_number(simulation.conflicts.len() as u64),
+                failing = format_number(failing as u64),
+            )
+        );
+    }
+    if let Some(seconds) = simulation.estimated_seconds {
+        println!("{}", tr!("apply.simulation_time", duration = utils::format_duration(seconds)));
+    }
+}
+
+/// Print one line for every file applying `patch_data` writes, patches, renames or
+/// deletes, sorted by path; `resolved` gives the destinations of placeholder paths
+pub fn print_planned_changes(patch_data: &PatchData, resolved: Option<&ResolvedPaths>) {
+    let dest = |path: &Path| resolved.map_or(path, |resolved| resolved.get(path)).display().to_string();
+    let mut lines: Vec<(&Path, String)> = Vec::new();
+    for info in &patch_data.added_files {
+        lines.push((&info.relative_path, tr!("dry_run.add", path = dest(&info.relative_path), size = utils::format_size(info.size))));
+    }
+    for info in &patch_data.modified_files {
+        lines.push((&info.relative_path, tr!("dry_run.replace", path = dest(&info.relative_path), size = utils::format_size(info.size))));
+    }
+    for diff in &patch_data.modified_diffs {
+        lines.push((&diff.relative_path, tr!("dry_run.patch_text", path = dest(&diff.relative_path))));
+    }
+    for delta in &patch_data.delta_files {
+        let path = &delta.file.relative_path;
+        lines.push((path, tr!("dry_run.patch_delta", path = dest(path), size = utils::format_size(delta.file.size))));
+    }
+    for link in &patch_data.symlinks {
+        let target = link.link_target.as_deref().unwrap_or(Path::new("")).display();
+        lines.push((&link.relative_path, tr!("dry_run.link", path = dest(&link.relative_path), target = target)));
+    }
+    for rename in &patch_data.renamed_files {
+        lines.push((&rename.to.relative_path, tr!("dry_run.rename", from = dest(&rename.from), to = dest(&rename.to.relative_path))));
+    }
+    for info in &patch_data.metadata_files {
+        let mode = info.mode.map(|mode| mode.to_string()).unwrap_or_default();
+        lines.push((&info.relative_path, tr!("dry_run.permissions", path = dest(&info.relative_path), mode = mode)));
+    }
+    for path in &patch_data.removed_files {
+        lines.push((path, tr!("dry_run.delete", path = dest(path))));
+    }
+    lines.sort_by(|a, b| a.0.cmp(b.0));
+    for (_, line) in lines {
+        println!("{}", line);
+    }
+}
+
+/// Write `content` to `dest` through a temporary file next to it
+///
+/// The file it replaces keeps existing until the new one is complete, and its
+/// permissions carry over unless `mode` is given.
+fn write_entry_in_place(content: &mut dyn Read, dest: &Path, mode: Option<u32>) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    let permissions = if mode.is_some() { None } else { fs::metadata(dest).ok().map(|m| m.permissions()) };
+    let staged = StagingFile::new(dest)?;
+    let mut out = BufWriter::with_capacity(65536, File::create(staged.path())
+        .with_context(|| format!("Failed to create file: {}", staged.path().display()))?);
+    std::io::copy(content, &mut out)
+        .and_then(|_| out.flush())
+        .with_context(|| format!("Failed to write file: {}", dest.display()))?;
+    drop(out);
+    if let Some(permissions) = permissions {
+        fs::set_permissions(staged.path(), permissions)
+            .with_context(|| format!("Failed to set permissions: {}", dest.display()))?;
+    }
+    if let Some(mode) = mode {
+        fsops::set_mode(staged.path(), mode)
+            .with_context(|| format!("Failed to set permissions: {}", dest.display()))?;
+    }
+    staged.persist()
 }
 
 /// Apply patch to current directory
-pub fn apply_patch(current_dir: &Path) -> Result<()> {
-    println!("Applying patch to directory: {}", current_dir.display());
+#[allow(clippy::collapsible_if)]
+pub fn apply_patch(current_dir: &Path, options: &ApplyOptions) -> Result<()> {
+    println!("{}", tr!("apply.start", path = current_dir.display()));
+    if let Some(threads) = options.threads {
+        utils::validate_thread_count(threads)?;
+    }
+    let threads = utils::io_thread_count(options.threads);
+    staging::remove_stale(current_dir);
     
     // Extract patch data and content
-    let (patch_data, content_bytes) = extract_patch_data_from_exe()?;
+    let (mut patch_data, content) = match &options.package {
+        Some(path) => extract_patch_data(path)?,
+        None => extract_patch_data_from_exe()?,
+    };
+    println!("{}", tr!("apply.run_id", run = runid::current()));
+    if let Some(run) = &patch_data.run_id {
+        println!("{}", tr!("apply.patch_run_id", run = run));
+    }
+    patch_data.check_codecs()?;
+    // The result is checked against the state the patch was made to produce, if it knows it
+    let result_manifest = patch_data.result_manifest();
+    let verify = !options.skip_verification && result_manifest.is_some();
+    if !options.skip_verification && !verify {
+        println!("{}", tr!("apply.verify_no_manifest"));
+    }
+    let patch_id = format!("{:x}", Sha256::digest(patch_data.to_json()?));
+    let fingerprint = if verify { patch_id.clone() } else { String::new() };
+    let signing_key = options.signing_key.as_deref().map(verify::load_key).transpose()?;
+
+    if !options.file_modes.is_empty() && !cfg!(unix) {
+        println!("{}", tr!("apply.modes_ignored"));
+    }
+
+    // Leave out the files of other platforms and of components that are not installed
+    let env = options.environment()?;
+    let skipped = patch_data.select_entries(&env);
+    if patch_data.has_conditions() {
+        println!("{}", tr!("apply.platform", platform = env.platform, arch = env.arch, count = format_number(skipped.len() as u64)));
+    }
+    // Carry on after an interrupted apply of the same patch: what it changed stays as it is
+    let journal_path = current_dir.join(JOURNAL_FILE_NAME);
+    let resumed = match ApplyJournal::load(&journal_path)? {
+        Some(journal) if journal.patch == patch_id => {
+            let resumed = patch_data.skip_completed(&journal.completed);
+            println!("{}", tr!("apply.resuming", done = format_number(resumed.len() as u64), path = journal_path.display()));
+            Some(resumed)
+        }
+        Some(_) => {
+            println!("{}", tr!("apply.journal_other_patch", path = journal_path.display()));
+            None
+        }
+        None => None,
+    };
+    // Files left alone: skipped ones and those the interrupted apply wrote. The latter are
+    // still checked against the result.
+    let mut not_written = skipped.clone();
+    not_written.extend(resumed.iter().flatten().cloned());
+    // Stored content of a skipped file may still be needed by a duplicate that is kept
+    let mut skipped_entries = not_written.clone();
+    for duplicate in &patch_data.duplicate_files {
+        skipped_entries.remove(&duplicate.content);
+    }
+
+    // Resolve the `${NAME}` placeholders before anything is changed, so a missing value
+    // stops the apply early
+    let vars = options.variables()?;
+    let resolved = ResolvedPaths::new(&vars, patch_data.destination_paths())?;
+    let check_files: Vec<String> = patch_data.check_files.iter()
+        .map(|file| vars.resolve_path(Path::new(file)).map(|path| path.to_string_lossy().into_owned()))
+        .collect::<Result<_>>()?;
+    let pre_apply_hook = patch_data.pre_apply_hook.as_ref().map(|hook| hook.resolve(&vars)).transpose()?;
+    let post_apply_hook = patch_data.post_apply_hook.as_ref().map(|hook| hook.resolve(&vars)).transpose()?;
+    if !resolved.is_empty() {
+        println!("{}", tr!("apply.variables", count = format_number(resolved.len() as u64)));
+    }
+    
+    check_target(&patch_data, &env, current_dir, &resolved, options.force)?;
     
+    if let Some(report_path) = &options.simulate {
+        println!("{}", tr!("apply.simulating"));
+        let simulation = simulate_apply(&patch_data, &content, current_dir, options, &not_written, &resolved, &check_files)?;
+        print_simulation(&simulation);
+        simulation.save(report_path)?;
+        println!("{}", tr!("apply.simulation_saved", path = report_path.display()));
+        return Ok(());
+    }
+
+    if options.dry_run {
+        println!("{}", tr!("dry_run.apply"));
+        print_planned_changes(&patch_data, Some(&resolved));
+        for (hook, key) in [(&pre_apply_hook, "dry_run.pre_apply_hook"), (&post_apply_hook, "dry_run.post_apply_hook")] {
+            if let Some(hook) = hook {
+                println!("{}", tr!(key, script = hook.script.display()));
+            }
+        }
+        let missing = check_files.iter().filter(|file| !current_dir.join(file).exists()).count();
+        if missing > 0 {
+            println!("{}", tr!("apply.simulation_check_files", count = format_number(missing as u64)));
+        }
+        println!("{}", tr!("dry_run.done"));
+        return Ok(());
+    }
+
     // Verify if patch should be applied to this directory
-    if !patch_data.check_files.is_empty() {
-        println!("Verifying directory...");
-        if !verify_directory(&patch_data.check_files, current_dir)? {
-            return Err(anyhow!("Directory verification failed. This patch cannot be applied here."));
+    if !check_files.is_empty() {
+        println!("{}", tr!("apply.verifying"));
+        if !verify_directory(&check_files, current_dir)? {
+            return Err(anyhow!(tr!("apply.verification_failed")));
         }
-        println!("Directory verification successful.");
+        println!("{}", tr!("apply.verified"));
     } else {
-        println!("Warning: No verification files specified. Applying patch without verification.");
+        println!("{}", tr!("apply.no_check_files"));
         if !dialoguer::Confirm::new()
-            .with_prompt("Continue with patch application?")
+            .with_prompt(tr!("apply.confirm"))
             .default(false)
             .interact()
             .context("Failed to get user confirmation")?
@@ -394,21 +2356,74 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
             return Ok(());
         }
     }
+    check_base(&patch_data, current_dir, &resolved, threads, options.force)?;
     
-    // Create temporary directory to extract content
-    let temp_dir = tempdir().context("Failed to create temporary directory")?;
-    let zip_path = temp_dir.path().join("content.zip");
-    
-    // Write content to temporary file
-    fs::write(&zip_path, &content_bytes).context("Failed to write content to temp file")?;
-    
-    // Unzip content
-    let file = File::open(&zip_path).context("Failed to open zip file")?;
-    let mut archive = zip::ZipArchive::new(file).context("Failed to read zip archive")?;
+    // Check that the changes fit on the disk before touching anything
+    let mut archive = zip::ZipArchive::new(content.open()?).context("Failed to read zip archive")?;
+    let (space_plan, entry_indices) =
+        plan_disk_space(&patch_data, &mut archive, current_dir, &options.strategy, &not_written, &skipped_entries, &resolved)?;
+    let file_by_file = check_disk_space(&space_plan, current_dir, &options.strategy)?;
+    // Written file by file, deltas are rebuilt after other files changed; make sure they can be
+    if file_by_file {
+        for delta in &patch_data.delta_files {
+            let installed = current_dir.join(resolved.get(&delta.file.relative_path));
+            let source = fs::read(&installed).with_context(|| format!("Failed to read file: {}", installed.display()))?;
+            if !delta_source_matches(delta, &source)? {
+                return Err(anyhow!(tr!("apply.delta_mismatch", path = installed.display())));
+            }
+        }
+    }
+
+    // Record the paths the patch changes before touching them, and keep copies of them
+    // to roll back to; staged and extracted copies are simply discarded instead
+    let touched: BTreeSet<&Path> = patch_data.destination_paths().map(|path| resolved.get(path)).collect();
+    let before: Vec<PriorState> = if verify {
+        touched.iter().map(|path| PriorState::capture(path, &current_dir.join(path))).collect::<Result<_>>()?
+    } else {
+        Vec::new()
+    };
+    let backup = if verify && options.rollback_on_failure && options.strategy == ApplyStrategy::InPlace {
+        println!("{}", tr!("apply.backing_up", count = format_number(touched.len() as u64)));
+        Some(RollbackBackup::create(current_dir, touched.iter().copied())?)
+    } else {
+        None
+    };
+
+    // Hooks act on the live installation, which extraction leaves alone
+    let run_hooks = !matches!(options.strategy, ApplyStrategy::Extract(_));
+    if let Some(hook) = pre_apply_hook.as_ref().filter(|_| run_hooks) {
+        run_hook_if_allowed("pre-apply", hook, current_dir, options)?;
+    }
+
+    // Staged and extracted copies leave out the files the patch replaces or removes, so
+    // they only cost the changed bytes
+    let replaced: HashSet<PathBuf> = patch_data.added_files.iter()
+        .chain(patch_data.modified_files.iter())
+        .map(|info| info.relative_path.as_path())
+        .chain(patch_data.removed_files.iter().map(PathBuf::as_path))
+        .chain(patch_data.symlinks.iter().map(|link| link.relative_path.as_path()))
+        .map(|path| resolved.get(path).to_path_buf())
+        .collect();
+
+    // A staged apply updates a copy of the directory, so the application never sees a
+    // half-updated tree
+    let staged_tree = match &options.strategy {
+        ApplyStrategy::InPlace => None,
+        ApplyStrategy::Staged => Some(StagedTree::create(current_dir, &replaced)?),
+        ApplyStrategy::Extract(dest) => Some(StagedTree::extract(current_dir, dest, &replaced)?),
+    };
+    let dest_dir = staged_tree.as_ref().map_or(current_dir, |tree| tree.path());
+    // Files in a staged copy are hard links to the live ones and must not be rewritten in place
+    let detach = staged_tree.is_some();
+
+    // Extract next to the destination, so files can be cloned into place
+    let temp_dir = StagingDir::new_in(dest_dir, "extract")?;
     
     // Process files
-    println!("Processing {} files...", archive.len());
-    let pb = ProgressBar::new(archive.len() as u64);
+    println!("{}", tr!("apply.processing", count = format_number(archive.len() as u64)));
+    // Written file by file straight from the archive, so nothing is extracted up front
+    let extract_count = if file_by_file { 0 } else { archive.len() };
+    let pb = ProgressBar::new(extract_count as u64);
     pb.set_style(
         ProgressStyle::default_bar()
             .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
@@ -420,12 +2435,26 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
     let extract_dir = temp_dir.path().join("extracted");
     fs::create_dir_all(&extract_dir).context("Failed to create extraction directory")?;
     
+    // Contents known to the object cache are cloned from there instead of decompressed
+    let cache = options.object_cache.as_deref().map(ObjectCache::open).transpose()?;
+    let stored_files: HashMap<&Path, &FileInfo> = patch_data.added_files.iter()
+        .chain(patch_data.modified_files.iter())
+        .map(|info| (info.relative_path.as_path(), info))
+        .collect();
+    let (mut cache_reused, mut cache_stored) = (0u64, 0u64);
+    let mut cache_objects: Vec<PathBuf> = Vec::new();
+
     // Extract files to the temporary directory first
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i).context("Failed to access zip file entry")?;
-        let outpath = match file.enclosed_name() {
-            Some(path) => extract_dir.join(path),
-            None => {
+    for i in 0..extract_count {
+        // Nothing has been written to the destination yet, so just stop
+        options.check_stop()?;
+        let file = archive.by_index(i).context("Failed to access zip file entry")?;
+        let (outpath, cached_info) = match file.enclosed_name() {
+            Some(path) if !skipped_entries.contains(&path) => {
+                let info = stored_files.get(path.as_path()).filter(|_| cache.is_some()).copied();
+                (extract_dir.join(path), info)
+            }
+            _ => {
                 pb.inc(1);
                 continue;
             }
@@ -436,26 +2465,88 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
             fs::create_dir_all(&outpath).with_context(|| format!("Failed to create directory: {}", outpath.display()))?;
         } else {
             // Create parent directory if needed
-            if let Some(parent) = outpath.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+            if let Some(parent) = outpath.parent()
+                && !parent.exists()
+            {
+                fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+            }
+            match (&cache, cached_info) {
+                (Some(cache), Some(info)) if let Some(blob) = cache.find_blob(&info.hash, info.size) => {
+                    fsops::clone_or_copy(&blob, &outpath)
+                        .with_context(|| format!("Failed to write file: {}", outpath.display()))?;
+                    cache_objects.push(blob);
+                    cache_reused += 1;
+                }
+                _ => {
+                    // Extract file with buffered IO
+                    let mut outfile = BufWriter::with_capacity(65536, 
+                        File::create(&outpath).with_context(|| format!("Failed to create file: {}", outpath.display()))?
+                    );
+                    let mut content = patch_data.entry_reader(file)?;
+                    std::io::copy(&mut content, &mut outfile)
+                        .and_then(|_| outfile.flush())
+                        .with_context(|| format!("Failed to write file: {}", outpath.display()))?;
+                    drop(outfile);
+                    if let (Some(cache), Some(info)) = (&cache, cached_info) {
+                        if cache.store_blob(&info.hash, &outpath)? {
+                            cache_stored += 1;
+                        }
+                        cache_objects.extend(cache.blob_path(&info.hash));
+                    }
                 }
             }
-            // Extract file with buffered IO
-            let mut outfile = BufWriter::with_capacity(65536, 
-                File::create(&outpath).with_context(|| format!("Failed to create file: {}", outpath.display()))?
-            );
-            std::io::copy(&mut file, &mut outfile).with_context(|| format!("Failed to write file: {}", outpath.display()))?;
         }
         
         pb.inc(1);
     }
     
-    pb.finish_with_message("Files extracted successfully");
+    pb.finish_with_message(tr!("apply.extract_done"));
+    // Replace the extracted deltas with the files rebuilt from them
+    if extract_count > 0 {
+        for delta in &patch_data.delta_files {
+            options.check_stop()?;
+            let extracted = extract_dir.join(&delta.file.relative_path);
+            let stored = fs::read(&extracted).with_context(|| format!("Failed to read file: {}", extracted.display()))?;
+            let content = rebuild_delta_file(delta, &current_dir.join(resolved.get(&delta.file.relative_path)), &stored)?;
+            fs::write(&extracted, content).with_context(|| format!("Failed to write file: {}", extracted.display()))?;
+        }
+    }
+    if let Some(cache) = &cache {
+        println!("{}", tr!("apply.object_cache", reused = format_number(cache_reused), stored = format_number(cache_stored)));
+        let counts = HitCounts { hits: cache_reused, misses: cache_objects.len() as u64 - cache_reused };
+        let patch_path = match &options.package {
+            Some(path) => path.clone(),
+            None => std::env::current_exe().unwrap_or_default(),
+        };
+        let name = patch_path.file_name().unwrap_or_default().to_string_lossy();
+        cache.record_release(CacheUser::Apply, &name, &cache_objects, counts)?;
+    }
+
+    // Paths changed so far, written to a journal if the apply is interrupted
+    let completed: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+    // Move renamed files first; their content is not part of the patch
+    if !patch_data.renamed_files.is_empty() {
+        println!("{}", tr!("apply.renaming", count = format_number(patch_data.renamed_files.len() as u64)));
+        for rename in patch_data.renamed_files.iter() {
+            if options.should_stop() {
+                break;
+            }
+            let from = dest_dir.join(resolved.get(&rename.from));
+            let to = dest_dir.join(resolved.get(&rename.to.relative_path));
+            if let Some(parent) = to.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            match fs::rename(&from, &to) {
+                Ok(()) => completed.lock().unwrap().push(rename.to.relative_path.clone()),
+                Err(e) => println!("{}", tr!("apply.rename_failed", from = from.display(), to = to.display(), error = e)),
+            }
+        }
+    }
     
     // Process diff patch files
     if !patch_data.modified_diffs.is_empty() {
-        println!("Applying {} file diffs...", patch_data.modified_diffs.len());
+        println!("{}", tr!("apply.applying_diffs", count = format_number(patch_data.modified_diffs.len() as u64)));
         let diff_pb = ProgressBar::new(patch_data.modified_diffs.len() as u64);
         diff_pb.set_style(
             ProgressStyle::default_bar()
@@ -466,7 +2557,10 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
         
         // Apply diff patches one by one (no need for parallelization as each file patch operation is already fast)
         for file_diff in patch_data.modified_diffs.iter() {
-            let file_path = current_dir.join(&file_diff.relative_path);
+            if options.should_stop() {
+                break;
+            }
+            let file_path = dest_dir.join(resolved.get(&file_diff.relative_path));
             
             // Check if file exists
             if !file_path.exists() {
@@ -487,173 +2581,579 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
                 continue;
             }
             
-            // Split file content into lines
-            let mut lines: Vec<String> = content.lines().map(|s| s.to_owned()).collect();
-            
-            // Apply changes
-            // Apply changes from back to front, so line numbers aren't affected by insertions/deletions
-            let mut sorted_changes = file_diff.changes.clone();
-            sorted_changes.sort_by(|a, b| {
-                let a_line = a.old_range.map(|(start, _)| start).unwrap_or(usize::MAX);
-                let b_line = b.old_range.map(|(start, _)| start).unwrap_or(usize::MAX);
-                b_line.cmp(&a_line)
-            });
-            
-            for change in sorted_changes {
-                match change.tag {
-                    DiffChangeTag::Delete => {
-                        if let Some((start, len)) = change.old_range {
-                            // Ensure within range
-                            if start < lines.len() {
-                                let end = std::cmp::min(start + len, lines.len());
-                                lines.drain(start..end);
-                            }
-                        }
-                    },
-                    DiffChangeTag::Insert => {
-                        if let Some((start, _)) = change.new_range {
-                            // Ensure within range
-                            if start <= lines.len() {
-                                let new_lines: Vec<String> = change.content.lines().map(|s| s.to_owned()).collect();
-                                for (i, line) in new_lines.into_iter().enumerate() {
-                                    lines.insert(start + i, line);
-                                }
-                            }
-                        }
-                    },
-                    DiffChangeTag::Equal => {
-                        // No changes needed for equal parts
-                    },
-                    DiffChangeTag::Replace => {
-                        // Replace operation: delete first, then insert
-                        if let Some((start, len)) = change.old_range {
-                            if start < lines.len() {
-                                let end = std::cmp::min(start + len, lines.len());
-                                lines.drain(start..end);
-                            }
-                        }
-                        if let Some((start, _)) = change.new_range {
-                            if start <= lines.len() {
-                                let new_lines: Vec<String> = change.content.lines().map(|s| s.to_owned()).collect();
-                                for (i, line) in new_lines.into_iter().enumerate() {
-                                    if start + i <= lines.len() {
-                                        lines.insert(start + i, line);
-                                    }
-                                }
-                            }
-                        }
-                    },
-                }
-            }
-            
-            // Recombine file content
-            let new_content = lines.join("\n");
-            
-            // Write back to file
-            if let Ok(mut file) = File::create(&file_path) {
-                if file.write_all(new_content.as_bytes()).is_err() {
-                    // Skip on write error
+            // The result is checked against the target hash, so a changed file is left alone
+            let new_content = match apply::apply_text_diff(&content, file_diff) {
+                Ok(new_content) => new_content,
+                Err(e) => {
+                    diff_pb.println(tr!("apply.diff_failed", path = file_path.display(), error = e));
                     diff_pb.inc(1);
                     continue;
                 }
+            };
+            
+            // Write back to file (skip on write error)
+            if let Ok(mut file) = create_output_file(&file_path, detach)
+                && file.write_all(new_content.as_bytes()).is_ok()
+            {
+                completed.lock().unwrap().push(file_diff.relative_path.clone());
             }
             
             diff_pb.inc(1);
         }
         
-        diff_pb.finish_with_message("File diffs applied successfully");
+        diff_pb.finish_with_message(tr!("apply.diffs_done"));
     }
     
-    // Now copy files in parallel from the temporary directory to the target directory
-    let extracted_files: Vec<_> = walkdir::WalkDir::new(&extract_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-        .collect();
-    
-    println!("Copying {} files to target directory...", extracted_files.len());
-    let copy_pb = ProgressBar::new(extracted_files.len() as u64);
-    copy_pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
+    // Create a thread pool with limited threads to avoid I/O contention
+    let pool = utils::io_thread_pool(threads);
+
+    // Remove files to be deleted in parallel
+    let remove_files = || {
+        if patch_data.removed_files.is_empty() {
+            return;
+        }
+        println!("{}", tr!("apply.removing", count = format_number(patch_data.removed_files.len() as u64)));
+        pool.install(|| {
+            patch_data.removed_files.par_iter().for_each(|path| {
+                if options.should_stop() {
+                    return;
+                }
+                let full_path = dest_dir.join(resolved.get(path));
+                // Also removes links whose target is gone
+                if fs::symlink_metadata(&full_path).is_ok() && fs::remove_file(&full_path).is_ok() {
+                    completed.lock().unwrap().push(path.clone());
+                }
+            });
+        });
+        println!("{}", tr!("apply.remove_done"));
+    };
+
+    if file_by_file {
+        // Free space first, then write the files that shrink the tree before those that grow it
+        remove_files();
+        println!("{}", tr!("apply.writing", count = format_number(space_plan.order.len() as u64)));
+        let deltas: HashMap<&PathBuf, &DeltaFile> = patch_data.delta_files.iter().map(|delta| (&delta.file.relative_path, delta)).collect();
+        let write_pb = ProgressBar::new(space_plan.order.len() as u64);
+        write_pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+        for write in &space_plan.order {
+            if options.should_stop() {
+                break;
+            }
+            let index = entry_indices[&write.path];
+            let dest_path = resolved.get(&write.path);
+            let mode = templated_mode(&options.file_modes, dest_path);
+            let entry = archive.by_index(index).context("Failed to access zip file entry")?;
+            let mut entry = patch_data.entry_reader(entry)?;
+            match deltas.get(&write.path) {
+                Some(delta) => {
+                    let mut stored = Vec::new();
+                    entry.read_to_end(&mut stored).with_context(|| format!("Failed to read {} from the patch", write.path.display()))?;
+                    let content = rebuild_delta_file(delta, &dest_dir.join(dest_path), &stored)?;
+                    write_entry_in_place(&mut content.as_slice(), &dest_dir.join(dest_path), mode)?;
+                }
+                None => write_entry_in_place(&mut entry, &dest_dir.join(dest_path), mode)?,
+            }
+            completed.lock().unwrap().push(write.path.clone());
+            write_pb.inc(1);
+        }
+        write_pb.finish_with_message(tr!("apply.copy_done"));
+    } else {
+        // Now copy files in parallel from the temporary directory to the target directory,
+        // as (extracted file, destination) pairs
+        let mut extracted_files: Vec<(PathBuf, PathBuf)> = walkdir::WalkDir::new(&extract_dir)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file())
+            .map(|e| {
+                let rel_path = e.path().strip_prefix(&extract_dir).unwrap_or(e.path()).to_path_buf();
+                (e.into_path(), rel_path)
+            })
+            .filter(|(_, rel_path)| !not_written.contains(rel_path))
+            .collect();
+        // Files with duplicated content get a copy of the one stored file
+        extracted_files.extend(patch_data.duplicate_files.iter().map(|d| (extract_dir.join(&d.content), d.path.clone())));
     
-    // Use atomic counter for progress
-    let copy_counter = Arc::new(Mutex::new(0));
+        println!("{}", tr!("apply.copying", count = format_number(extracted_files.len() as u64)));
+        let copy_pb = ProgressBar::new(extracted_files.len() as u64);
+        copy_pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
+                .unwrap()
+                .progress_chars("#>-"),
+        );
     
-    // Create a thread pool with limited threads to avoid I/O contention
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(get_io_thread_count())
-        .build()
-        .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap());
+        // Use atomic counter for progress
+        let copy_counter = Arc::new(Mutex::new(0));
     
-    // Parallel copy to target directory
-    pool.install(|| {
-        extracted_files.par_iter().for_each(|entry| {
-            let src_path = entry.path();
-            let rel_path = src_path.strip_prefix(&extract_dir).unwrap_or(src_path);
-            let dest_path = current_dir.join(rel_path);
+        // Parallel copy to target directory
+        pool.install(|| {
+            extracted_files.par_iter().for_each(|(src_path, rel_path)| {
+                if options.should_stop() {
+                    return;
+                }
+                let dest_path = dest_dir.join(resolved.get(rel_path));
             
-            // Ensure parent directory exists
-            if let Some(parent) = dest_path.parent() {
-                if !parent.exists() {
-                    if let Err(_) = fs::create_dir_all(parent) {
-                        return; // Skip on error
+                // Ensure parent directory exists
+                if let Some(parent) = dest_path.parent() {
+                    if !parent.exists() {
+                        if fs::create_dir_all(parent).is_err() {
+                            return; // Skip on error
+                        }
                     }
                 }
-            }
             
-            // Optimized copy with buffered IO
-            let result = (|| {
-                let src_file = File::open(src_path)?;
-                let mut reader = BufReader::with_capacity(65536, src_file);
-                
-                let dst_file = File::create(&dest_path)?;
-                let mut writer = BufWriter::with_capacity(65536, dst_file);
-                
-                std::io::copy(&mut reader, &mut writer)?;
-                writer.flush()?;
-                Ok::<_, std::io::Error>(())
-            })();
+                // Reflink or copy, keeping the permissions of the file being replaced
+                // unless a mode rule sets them
+                let result = (|| {
+                    let permissions = if detach {
+                        fsops::detach_file(&dest_path)?
+                    } else {
+                        fs::metadata(&dest_path).ok().map(|m| m.permissions())
+                    };
+                    fsops::clone_or_copy(src_path, &dest_path)?;
+                    if let Some(mode) = templated_mode(&options.file_modes, resolved.get(rel_path)) {
+                        fsops::set_mode(&dest_path, mode)?;
+                    } else if let Some(permissions) = permissions {
+                        fs::set_permissions(&dest_path, permissions)?;
+                    }
+                    Ok::<_, std::io::Error>(())
+                })();
             
-            if result.is_err() {
-                return; // Skip on error
-            }
+                if result.is_err() {
+                    return; // Skip on error
+                }
+                completed.lock().unwrap().push(rel_path.clone());
             
-            // Update progress
-            let mut counter = copy_counter.lock().unwrap();
-            *counter += 1;
-            copy_pb.set_position(*counter);
+                // Update progress
+                let mut counter = copy_counter.lock().unwrap();
+                *counter += 1;
+                copy_pb.set_position(*counter);
+            });
         });
-    });
     
-    copy_pb.finish_with_message("Files copied successfully");
+        copy_pb.finish_with_message(tr!("apply.copy_done"));
+    }
     
-    // Remove files to be deleted in parallel
-    if !patch_data.removed_files.is_empty() {
-        println!("Removing {} files...", patch_data.removed_files.len());
-        
-        // Use same thread pool for deletion
-        pool.install(|| {
-            patch_data.removed_files.par_iter().for_each(|path| {
-                let full_path = current_dir.join(path);
-                if full_path.exists() {
-                    let _ = fs::remove_file(&full_path);
+    if !file_by_file {
+        remove_files();
+    }
+
+    // Links last, so one replacing a removed directory finds it gone
+    if !patch_data.symlinks.is_empty() {
+        println!("{}", tr!("apply.symlinks", count = format_number(patch_data.symlinks.len() as u64)));
+        for link in &patch_data.symlinks {
+            if options.should_stop() {
+                break;
+            }
+            let Some(target) = &link.link_target else {
+                continue;
+            };
+            let link_path = dest_dir.join(resolved.get(&link.relative_path));
+            if let Some(parent) = link_path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            match fsops::create_symlink(target, &link_path) {
+                Ok(()) => completed.lock().unwrap().push(link.relative_path.clone()),
+                Err(e) => println!("{}", tr!("apply.symlink_failed", path = link_path.display(), target = target.display(), error = e)),
+            }
+        }
+    }
+
+    // Recorded permissions last, once every file is in place; mode rules take precedence
+    let modes: Vec<(&PathBuf, FileMode)> = patch_data.added_files.iter()
+        .chain(patch_data.modified_files.iter())
+        .chain(patch_data.delta_files.iter().map(|delta| &delta.file))
+        .chain(patch_data.renamed_files.iter().map(|rename| &rename.to))
+        .chain(patch_data.metadata_files.iter())
+        .map(|info| (&info.relative_path, info.mode))
+        .chain(patch_data.modified_diffs.iter().map(|diff| (&diff.relative_path, diff.mode)))
+        .filter_map(|(path, mode)| Some((path, mode?)))
+        .filter(|(path, _)| templated_mode(&options.file_modes, resolved.get(path)).is_none())
+        .collect();
+    if !modes.is_empty() && !options.should_stop() {
+        let metadata_only: HashSet<&PathBuf> = patch_data.metadata_files.iter().map(|info| &info.relative_path).collect();
+        let (mut set, mut other_platform) = (0u64, 0u64);
+        for (path, mode) in modes {
+            if options.should_stop() {
+                break;
+            }
+            let dest_path = dest_dir.join(resolved.get(path));
+            // Files of a staged copy that were not rewritten (unchanged or renamed ones) are
+            // hard links to the live ones
+            if detach && let Err(e) = fsops::unshare_file(&dest_path) {
+                println!("{}", tr!("apply.mode_failed", path = dest_path.display(), error = e));
+                continue;
+            }
+            match mode.apply(&dest_path) {
+                Ok(true) => {
+                    set += 1;
+                    if metadata_only.contains(path) {
+                        completed.lock().unwrap().push(path.clone());
+                    }
                 }
-            });
+                Ok(false) => other_platform += 1,
+                Err(e) => println!("{}", tr!("apply.mode_failed", path = dest_path.display(), error = e)),
+            }
+        }
+        println!("{}", tr!("apply.permissions", count = format_number(set)));
+        if other_platform > 0 {
+            println!("{}", tr!("apply.permissions_other_platform", count = format_number(other_platform)));
+        }
+    }
+
+    if options.should_stop() {
+        if staged_tree.is_some() {
+            // Dropping the staged copy discards it, the original directory is untouched
+            println!("{}", tr!("apply.interrupted_discarded", path = current_dir.display()));
+        } else {
+            let mut done = completed.into_inner().unwrap();
+            done.extend(resumed.into_iter().flatten());
+            let journal = ApplyJournal::new(&patch_data, patch_id, done);
+            journal.save(&journal_path)?;
+            println!(
+                "{}",
+                tr!(
+                    "apply.interrupted_journal",
+                    done = format_number(journal.completed.len() as u64),
+                    total = format_number((journal.completed.len() + journal.pending.len()) as u64),
+                    path = journal_path.display(),
+                )
+            );
+        }
+        return Err(interrupt::stop_error(options.cancel.as_ref()));
+    }
+
+    // The extracted content lives inside the destination tree, remove it before swapping
+    drop(temp_dir);
+    let mut verification_failed = false;
+    if let Some(manifest) = result_manifest.as_ref().filter(|_| verify) {
+        println!("{}", tr!("apply.verifying_result", count = format_number(manifest.files.len() as u64)));
+        let after = manifest.check_directory(threads, |path| {
+            if skipped.contains(path) {
+                return None;
+            }
+            vars.resolve_path(path).ok().map(|path| dest_dir.join(path))
         });
-        
-        println!("Files removed successfully");
+        let not_removed: Vec<PathBuf> = patch_data.removed_files.iter()
+            .filter(|path| fs::symlink_metadata(dest_dir.join(resolved.get(path))).is_ok())
+            .cloned()
+            .collect();
+        let mut record = VerificationRecord::new(fingerprint, current_dir.to_path_buf(), before, after, not_removed);
+        record.patch_run_id = patch_data.run_id.clone();
+        if record.passed {
+            println!("{}", tr!("apply.verify_passed", count = format_number(record.after.checked as u64)));
+        } else {
+            print_verification_problems(&record);
+            verification_failed = true;
+            if options.rollback_on_failure {
+                if let Some(backup) = backup {
+                    println!("{}", tr!("apply.rolling_back"));
+                    let restored = backup.restore()?;
+                    println!("{}", tr!("apply.rolled_back", count = format_number(restored as u64)));
+                }
+                record.rolled_back = true;
+            }
+        }
+        if let Some(path) = &options.verification_record {
+            record.sign(signing_key.as_deref())?;
+            record.save(path)?;
+            println!("{}", tr!("apply.verification_saved", path = path.display()));
+        }
+    }
+    if let Some(tree) = staged_tree {
+        if verification_failed && options.rollback_on_failure {
+            // Dropping the staged copy discards it
+            drop(tree);
+            println!("{}", tr!("apply.staged_discarded", path = current_dir.display()));
+            return Err(anyhow!(tr!("apply.verification_error")));
+        }
+        if options.strategy == ApplyStrategy::Staged {
+            println!("{}", tr!("apply.swapping"));
+        }
+        tree.commit()?;
+    }
+    if verification_failed {
+        return Err(anyhow!(tr!("apply.verification_error")));
+    }
+    // The journal of the resumed apply is done with; an extracted copy took it along
+    if resumed.is_some() {
+        let journal_dir = match &options.strategy {
+            ApplyStrategy::Extract(dest) => dest.as_path(),
+            _ => current_dir,
+        };
+        let path = journal_dir.join(JOURNAL_FILE_NAME);
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove apply journal: {}", path.display()))?;
+        }
+    }
+
+    if let Some(hook) = post_apply_hook.as_ref().filter(|_| run_hooks) {
+        run_hook_if_allowed("post-apply", hook, current_dir, options)?;
     }
     
-    println!("Patch applied successfully!");
-    println!("Summary:");
-    println!("  Added files: {}", patch_data.added_files.len());
-    println!("  Modified files (full): {}", patch_data.modified_files.len());
-    println!("  Modified files (diff): {}", patch_data.modified_diffs.len());
-    println!("  Removed files: {}", patch_data.removed_files.len());
+    println!("{}", tr!("apply.done"));
+    println!("{}", tr!("apply.summary"));
+    println!("{}", tr!("apply.count_added", count = format_number(patch_data.added_files.len() as u64)));
+    println!("{}", tr!("apply.count_modified", count = format_number(patch_data.modified_files.len() as u64)));
+    println!("{}", tr!("apply.count_modified_diff", count = format_number(patch_data.modified_diffs.len() as u64)));
+    if !patch_data.delta_files.is_empty() {
+        println!("{}", tr!("apply.count_modified_delta", count = format_number(patch_data.delta_files.len() as u64)));
+    }
+    println!("{}", tr!("apply.count_removed", count = format_number(patch_data.removed_files.len() as u64)));
+    if !patch_data.symlinks.is_empty() {
+        println!("{}", tr!("apply.count_symlinks", count = format_number(patch_data.symlinks.len() as u64)));
+    }
+    if !patch_data.metadata_files.is_empty() {
+        println!("{}", tr!("apply.count_permissions", count = format_number(patch_data.metadata_files.len() as u64)));
+    }
     
     Ok(())
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// Pack `dir/src` with zstd level 1 and return each entry with its size, checked
+    /// against the bytes read, and its last byte
+    fn packed_entries(dir: &TempDir) -> Vec<(String, u64, Option<u8>)> {
+        let zip_path = dir.path().join("content.zip");
+        let compression = CompressionSettings::new(zip::CompressionMethod::Zstd, Some(1));
+        create_zip_archive(&dir.path().join("src"), &zip_path, Vec::new(), compression, None, false, 1).unwrap();
+        let mut archive = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        (0..archive.len()).map(|index| {
+            let mut entry = archive.by_index(index).unwrap();
+            let (mut read, mut last, mut buffer) = (0u64, None, vec![0u8; 1 << 20]);
+            loop {
+                let n = entry.read(&mut buffer).unwrap();
+                if n == 0 {
+                    break;
+                }
+                (read, last) = (read + n as u64, Some(buffer[n - 1]));
+            }
+            assert_eq!(read, entry.size());
+            (entry.name().to_string(), entry.size(), last)
+        }).collect()
+    }
+
+    /// Trailer recording sections of `manifest_size` and `content_size` bytes
+    fn trailer(manifest_size: u64, content_size: u64) -> Vec<u8> {
+        [&manifest_size.to_le_bytes()[..], &content_size.to_le_bytes(), PATCH_END_MARKER].concat()
+    }
+
+    #[test]
+    fn patch_header_sections_must_fit_the_file() {
+        let fits = PATCH_TRAILER_LEN as u64 + 30;
+        let header = parse_patch_header(&trailer(10, 20), fits).unwrap();
+        assert_eq!((header.manifest_size, header.content_size), (10, 20));
+        assert_eq!(header.manifest_offset(fits).unwrap(), 0);
+        assert!(parse_patch_header(&trailer(10, 20), fits - 1).is_err());
+        assert!(parse_patch_header(&trailer(u64::MAX, 1), u64::MAX).is_err());
+        assert!(parse_patch_header(&trailer(1, u64::MAX), u64::MAX).is_err());
+    }
+
+    #[test]
+    fn patch_header_reads_the_last_bytes_only() {
+        let mut data = b"program".to_vec();
+        data.extend(trailer(0, 0));
+        assert!(parse_patch_header(&data, data.len() as u64).is_ok());
+        assert!(parse_patch_header(&data[1..data.len() - 1], data.len() as u64).is_err());
+        assert!(parse_patch_header(&trailer(0, 0)[1..], 100).is_err());
+        assert!(parse_patch_header(&trailer(0, 0), PATCH_TRAILER_LEN as u64 - 1).is_err());
+        assert!(parse_patch_header(&[], 0).is_err());
+    }
+
+    #[test]
+    fn manifest_bytes_are_checked() {
+        let patch_data = PatchData::from_diffs(Vec::new(), vec!["check.txt".to_string()]);
+        let bytes = serde_json::to_vec(&patch_data).unwrap();
+        assert_eq!(parse_manifest_bytes(&bytes).unwrap().check_files, ["check.txt"]);
+        for len in [0, 1, bytes.len() / 2, bytes.len() - 1] {
+            assert!(parse_manifest_bytes(&bytes[..len]).is_err());
+        }
+        assert!(parse_manifest_bytes(b"[]").is_err());
+        assert!(parse_manifest_bytes(&[0xff; 64]).is_err());
+
+        // A target manifest that would write outside the patched directory
+        let mut patch_data = patch_data;
+        let outside = PathBuf::from("../outside.txt");
+        let files = HashMap::from([(outside.clone(), FileInfo::new(outside, "00".repeat(32), 1))]);
+        patch_data.target_manifest = Some(Manifest::from_files(&files));
+        assert!(parse_manifest_bytes(&serde_json::to_vec(&patch_data).unwrap()).is_err());
+    }
+
+    /// Patch from `live` to `target` that renames `r.sh` to `moved.sh` and makes it executable
+    #[cfg(unix)]
+    fn rename_and_chmod_patch(dir: &TempDir) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = b"#!/bin/sh\necho moved\n";
+        let live = dir.write("live/r.sh", script);
+        dir.write("live/keep.txt", b"kept");
+        let moved = dir.write("target/moved.sh", script);
+        dir.write("target/keep.txt", b"kept");
+        fs::set_permissions(&live, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(&moved, fs::Permissions::from_mode(0o755)).unwrap();
+        let options = diff::DiffOptions::new().detect_renames(true).rename_min_size(1);
+        let (live_dir, target_dir) = (dir.path().join("live"), dir.path().join("target"));
+        let report = diff::compare_directories(&live_dir, &target_dir, &options).unwrap();
+        assert_eq!(report.diffs.iter().filter(|diff| diff.kind() == "renamed").count(), 1);
+        // A check file keeps the apply from asking for confirmation
+        let options = PatchOptions { check_files: vec!["keep.txt".to_string()], ..Default::default() };
+        create_patch(&live_dir, &target_dir, Path::new("p.exe"), report, options).unwrap();
+        // Patches are written into the target directory
+        let package = dir.path().join("p.exe");
+        fs::rename(target_dir.join("p.exe"), &package).unwrap();
+        package
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modes_in_a_detached_tree_leave_the_live_files_alone() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TempDir::new();
+        let package = rename_and_chmod_patch(&dir);
+        let out = dir.path().join("out");
+        let options = ApplyOptions { package: Some(package.clone()), strategy: ApplyStrategy::Extract(out.clone()), ..Default::default() };
+        apply_patch(&dir.path().join("live"), &options).unwrap();
+        let live = fs::metadata(dir.path().join("live/r.sh")).unwrap();
+        assert_eq!((live.mode() & 0o7777, live.nlink()), (0o644, 1));
+        let moved = fs::metadata(out.join("moved.sh")).unwrap();
+        assert_eq!((moved.mode() & 0o7777, moved.nlink()), (0o755, 1));
+        // Unchanged files are still shared with the live tree
+        assert_eq!(fs::metadata(out.join("keep.txt")).unwrap().nlink(), 2);
+
+        let options = ApplyOptions { package: Some(package), strategy: ApplyStrategy::Staged, ..Default::default() };
+        apply_patch(&dir.path().join("live"), &options).unwrap();
+        let moved = fs::metadata(dir.path().join("live/moved.sh")).unwrap();
+        assert_eq!((moved.mode() & 0o7777, moved.nlink()), (0o755, 1));
+        assert_eq!(fs::metadata(out.join("moved.sh")).unwrap().nlink(), 1);
+    }
+
+    /// Patch adding, replacing and removing files, with `copy.txt` stored as a duplicate
+    /// of `new.txt`
+    fn journal_patch_data() -> PatchData {
+        let mut patch_data = PatchData::from_diffs(vec![
+            DiffType::Added(FileInfo::new("new.txt".into(), "11".repeat(32), 3)),
+            DiffType::Modified(FileInfo { mode: Some(FileMode::Unix(0o755)), ..FileInfo::new("run.sh".into(), "22".repeat(32), 4) }),
+            DiffType::Removed("old.txt".into()),
+        ], Vec::new());
+        patch_data.duplicate_files.push(DuplicateFile { path: "copy.txt".into(), content: "new.txt".into() });
+        patch_data
+    }
+
+    #[test]
+    fn journal_lists_duplicates_as_pending() {
+        let patch_data = journal_patch_data();
+        let journal = ApplyJournal::new(&patch_data, "id".to_string(), vec!["run.sh".into(), "new.txt".into(), "run.sh".into()]);
+        assert_eq!(journal.completed, [PathBuf::from("new.txt"), PathBuf::from("run.sh")]);
+        assert_eq!(journal.pending, [PathBuf::from("copy.txt"), PathBuf::from("old.txt")]);
+
+        let dir = TempDir::new();
+        let path = dir.path().join(JOURNAL_FILE_NAME);
+        assert!(ApplyJournal::load(&path).unwrap().is_none());
+        journal.save(&path).unwrap();
+        let loaded = ApplyJournal::load(&path).unwrap().unwrap();
+        assert_eq!((loaded.patch, loaded.completed, loaded.pending), (journal.patch, journal.completed, journal.pending));
+        fs::write(&path, b"{").unwrap();
+        assert!(ApplyJournal::load(&path).is_err());
+    }
+
+    #[test]
+    fn completed_entries_keep_only_their_modes() {
+        let mut patch_data = journal_patch_data();
+        let done = patch_data.skip_completed(&["run.sh".into(), "copy.txt".into(), "unknown.txt".into()]);
+        assert_eq!(done, HashSet::from([PathBuf::from("run.sh"), PathBuf::from("copy.txt")]));
+        assert!(patch_data.modified_files.is_empty() && patch_data.duplicate_files.is_empty());
+        assert_eq!(patch_data.added_files.len(), 1);
+        assert_eq!(patch_data.removed_files, [PathBuf::from("old.txt")]);
+        // Permissions are set after every file is written, so they may still be missing
+        let modes: Vec<_> = patch_data.metadata_files.iter().map(|info| (info.relative_path.clone(), info.mode)).collect();
+        assert_eq!(modes, [(PathBuf::from("run.sh"), Some(FileMode::Unix(0o755)))]);
+    }
+
+    #[test]
+    fn apply_resumes_from_the_journal_and_deletes_it() {
+        let dir = TempDir::new();
+        dir.write("live/keep.txt", b"kept");
+        dir.write("live/a.txt", b"old a");
+        dir.write("live/b.txt", b"old b");
+        dir.write("target/keep.txt", b"kept");
+        dir.write("target/a.txt", b"new a");
+        dir.write("target/b.txt", b"new b");
+        let (live, target) = (dir.path().join("live"), dir.path().join("target"));
+        let report = diff::compare_directories(&live, &target, &diff::DiffOptions::new()).unwrap();
+        let options = PatchOptions { check_files: vec!["keep.txt".to_string()], ..Default::default() };
+        create_patch(&live, &target, Path::new("p.exe"), report, options).unwrap();
+        let package = dir.path().join("p.exe");
+        fs::rename(target.join("p.exe"), &package).unwrap();
+        let (patch_data, _) = extract_patch_data(&package).unwrap();
+        let patch_id = format!("{:x}", Sha256::digest(patch_data.to_json().unwrap()));
+
+        // A journal of another patch is left alone
+        let journal_path = live.join(JOURNAL_FILE_NAME);
+        ApplyJournal { patch: "other".to_string(), ..Default::default() }.save(&journal_path).unwrap();
+        let options = ApplyOptions { package: Some(package), ..Default::default() };
+        apply_patch(&live, &options).unwrap();
+        assert_eq!(fs::read(live.join("a.txt")).unwrap(), b"new a");
+        assert!(journal_path.exists());
+
+        // An interrupted apply changed a.txt to something only the journal vouches for,
+        // so skipping it shows the journal was read
+        fs::write(live.join("a.txt"), b"old a").unwrap();
+        fs::write(live.join("b.txt"), b"old b").unwrap();
+        ApplyJournal::new(&patch_data, patch_id, vec!["a.txt".into()]).save(&journal_path).unwrap();
+        let options = ApplyOptions { skip_verification: true, ..options };
+        apply_patch(&live, &options).unwrap();
+        assert_eq!(fs::read(live.join("a.txt")).unwrap(), b"old a");
+        assert_eq!(fs::read(live.join("b.txt")).unwrap(), b"new b");
+        assert!(!journal_path.exists());
+    }
+
+    #[test]
+    fn patch_executables_only_take_built_in_codecs() {
+        let mut patch_data = journal_patch_data();
+        patch_data.record_entry_codecs("lz4");
+        assert!(patch_data.check_builtin_codecs().is_ok());
+        patch_data.record_entry_codecs("brotli");
+        assert!(patch_data.check_builtin_codecs().is_err());
+
+        let dir = TempDir::new();
+        dir.write("target/a.txt", b"a");
+        fs::create_dir(dir.path().join("source")).unwrap();
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        let report = diff::compare_directories(&source, &target, &diff::DiffOptions::new()).unwrap();
+        let codec = Some(CodecSettings { id: "brotli".to_string(), level: None });
+        let options = PatchOptions { check_files: vec!["a.txt".to_string()], codec, ..Default::default() };
+        assert!(create_patch(&source, &target, Path::new("p.exe"), report, options).is_err());
+        assert!(!target.join("p.exe").exists());
+    }
+
+    #[test]
+    fn archive_holds_every_file() {
+        let dir = TempDir::new();
+        dir.write("src/a.txt", b"alpha");
+        dir.write("src/sub/b.txt", b"beta!");
+        let expected = [("a.txt".to_string(), 5, Some(b'a')), ("sub/b.txt".to_string(), 5, Some(b'!'))];
+        assert_eq!(packed_entries(&dir), expected);
+    }
+
+    #[test]
+    #[ignore = "writes more than 4 GiB"]
+    fn archive_holds_zip64_files() {
+        let dir = TempDir::new();
+        let path = dir.write("src/large.bin", b"");
+        let size = u32::MAX as u64 + 4096;
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(size - 1).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b"z").unwrap();
+        assert_eq!(packed_entries(&dir), [("large.bin".to_string(), size, Some(b'z'))]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::cmp::Ordering;
+use std::path::{Component, Path, PathBuf};
+
+/// Separator of portable paths, as written in manifests, reports and patch archives
+pub const SEPARATOR: char = '/';
+
+/// Relative path as a `/`-separated string, the form stored in manifests and archives
+///
+/// `from_portable` turns the result back into an equal `Path` for any relative path of
+/// UTF-8 names; `.` components are dropped on the way.
+pub fn to_portable(path: &Path) -> String {
+    let parts: Vec<String> = path
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    parts.join("/")
+}
+
+/// Path from a string written by `to_portable`
+///
+/// Only `/` separates: on Unix a `\` is a valid character of a file name.
+pub fn from_portable(text: &str) -> PathBuf {
+    text.split(SEPARATOR).filter(|part| !part.is_empty() && *part != ".").collect()
+}
+
+/// Rewrite a relative path with `/` separators regardless of platform
+pub fn normalize_separators(path: &Path) -> PathBuf {
+    PathBuf::from(to_portable(path))
+}
+
+/// Names of a relative path given by a user, such as an `--exclude-dirs` entry
+///
+/// Both `/` and `\` separate, so the same value works on every platform; empty and `.`
+/// parts are skipped.
+pub fn split_components(text: &str) -> impl Iterator<Item = &str> {
+    text.split(['/', '\\']).filter(|part| !part.is_empty() && *part != ".")
+}
+
+/// Whether `names` appear as consecutive whole components of `path`
+///
+/// `a/node_modules/b` contains `node_modules`, `a/my_node_modules/b` does not.
+pub fn contains_components(path: &Path, names: &[&str]) -> bool {
+    if names.is_empty() {
+        return false;
+    }
+    let components: Vec<&std::ffi::OsStr> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    components.windows(names.len()).any(|window| window.iter().zip(names).all(|(component, name)| *component == *name))
+}
+
+/// How paths are ordered in reports, the same on every platform and in every locale
+///
+/// Paths are compared name by name, so a directory's entries stay together. Names that
+/// are equal under `CaseInsensitive` or `Natural` fall back to `Bytewise`, which keeps
+/// the order total.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum PathOrder {
+    /// By the bytes of each name, as in manifests: `B` < `a` < `file10` < `file2`
+    #[default]
+    Bytewise,
+    /// By the lowercase form of each name: `a` < `B`
+    CaseInsensitive,
+    /// Case-insensitive, with runs of digits compared as numbers: `file2` < `file10`
+    Natural,
+}
+
+impl PathOrder {
+    pub fn compare(self, a: &Path, b: &Path) -> Ordering {
+        let order = match self {
+            PathOrder::Bytewise => Ordering::Equal,
+            PathOrder::CaseInsensitive => compare_names(a, b, |a, b| a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase))),
+            PathOrder::Natural => compare_names(a, b, compare_natural),
+        };
+        order.then_with(|| a.cmp(b))
+    }
+}
+
+/// Compare two paths name by name with `compare`; a path that is a prefix of the other comes first
+fn compare_names(a: &Path, b: &Path, compare: impl Fn(&str, &str) -> Ordering) -> Ordering {
+    let mut a = a.components().map(|c| c.as_os_str().to_string_lossy());
+    let mut b = b.components().map(|c| c.as_os_str().to_string_lossy());
+    loop {
+        match (a.next(), b.next()) {
+            (Some(a), Some(b)) => match compare(&a, &b) {
+                Ordering::Equal => {}
+                order => return order,
+            },
+            (a, b) => return a.is_some().cmp(&b.is_some()),
+        }
+    }
+}
+
+/// Compare names case-insensitively, with digit runs as numbers (`x02` = `x2` < `x10`)
+fn compare_natural(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.is_empty().cmp(&b.is_empty()).reverse();
+        };
+        let order = if x.is_ascii_digit() && y.is_ascii_digit() {
+            let (x_digits, x_rest) = split_digits(a);
+            let (y_digits, y_rest) = split_digits(b);
+            (a, b) = (x_rest, y_rest);
+            // Without leading zeros, a longer number is larger
+            let (x_digits, y_digits) = (x_digits.trim_start_matches('0'), y_digits.trim_start_matches('0'));
+            x_digits.len().cmp(&y_digits.len()).then_with(|| x_digits.cmp(y_digits))
+        } else {
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+            x.to_lowercase().cmp(y.to_lowercase())
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+}
+
+/// The leading ASCII digits of `text` and the rest
+fn split_digits(text: &str) -> (&str, &str) {
+    text.split_at(text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(order: PathOrder, paths: &[&str]) -> Vec<String> {
+        let mut paths: Vec<&Path> = paths.iter().map(Path::new).collect();
+        paths.sort_by(|a, b| order.compare(a, b));
+        paths.iter().map(|path| path.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn natural_order_compares_numbers() {
+        assert_eq!(sorted(PathOrder::Natural, &["file10", "file2", "File1", "file02b"]), ["File1", "file2", "file02b", "file10"]);
+        assert_eq!(compare_natural("file2", "file10"), Ordering::Less);
+        assert_eq!(compare_natural("x02", "x2"), Ordering::Equal);
+        assert_eq!(compare_natural("v1.9", "v1.10"), Ordering::Less);
+        assert_eq!(compare_natural("a", "a1"), Ordering::Less);
+        assert_eq!(compare_natural("99999999999999999999999", "100000000000000000000000"), Ordering::Less);
+    }
+
+    #[test]
+    fn names_equal_under_an_order_fall_back_to_bytes() {
+        assert_eq!(sorted(PathOrder::Natural, &["x2", "x02"]), ["x02", "x2"]);
+        assert_eq!(sorted(PathOrder::CaseInsensitive, &["b", "B", "a"]), ["a", "B", "b"]);
+        assert_eq!(sorted(PathOrder::Bytewise, &["file2", "B", "a", "file10"]), ["B", "a", "file10", "file2"]);
+    }
+
+    #[test]
+    fn entries_of_a_directory_stay_together() {
+        assert_eq!(
+            sorted(PathOrder::CaseInsensitive, &["a-b/x", "a/b", "A/c", "a"]),
+            ["a", "a/b", "A/c", "a-b/x"]
+        );
+        assert_eq!(sorted(PathOrder::Natural, &["dir10/a", "dir9/b", "dir9"]), ["dir9", "dir9/b", "dir10/a"]);
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// Classic Windows MAX_PATH limit (including the drive and install directory)
+pub const WINDOWS_MAX_PATH: usize = 260;
+
+/// Device names reserved by Windows, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters that cannot appear in Windows file names
+const INVALID_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+/// A reason a path cannot be created on Windows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowsPathIssue {
+    ReservedName(String),       // Component is a reserved device name
+    TrailingDotOrSpace(String), // Component ends with '.' or ' ', which Windows strips
+    InvalidCharacter(char),     // Component contains a forbidden character
+    TooLong(usize),             // Path exceeds the length limit
+}
+
+impl fmt::Display for WindowsPathIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowsPathIssue::ReservedName(name) => write!(f, "reserved name '{}'", name),
+            WindowsPathIssue::TrailingDotOrSpace(name) => write!(f, "trailing dot or space in '{}'", name),
+            WindowsPathIssue::InvalidCharacter(c) => write!(f, "invalid character {:?}", c),
+            WindowsPathIssue::TooLong(len) => write!(f, "path too long ({} characters)", len),
+        }
+    }
+}
+
+/// A path together with the issues found in it
+#[derive(Debug, Clone)]
+pub struct WindowsPathProblem {
+    pub path: PathBuf,
+    pub issues: Vec<WindowsPathIssue>,
+}
+
+/// Check a relative path for names Windows cannot create
+///
+/// `max_len` limits the relative path length; lower it below `WINDOWS_MAX_PATH` to
+/// leave room for the directory the patch will be installed into.
+pub fn check_windows_path(path: &Path, max_len: usize) -> Vec<WindowsPathIssue> {
+    let mut issues = Vec::new();
+
+    for component in path.components() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        let name = name.to_string_lossy();
+
+        let stem = name.split('.').next().unwrap_or("").trim_end();
+        if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+            issues.push(WindowsPathIssue::ReservedName(name.to_string()));
+        }
+
+        if name.ends_with('.') || name.ends_with(' ') {
+            issues.push(WindowsPathIssue::TrailingDotOrSpace(name.to_string()));
+        }
+
+        if let Some(c) = name.chars().find(|c| INVALID_CHARS.contains(c) || (*c as u32) < 32) {
+            issues.push(WindowsPathIssue::InvalidCharacter(c));
+        }
+    }
+
+    let len = path.to_string_lossy().encode_utf16().count();
+    if len > max_len {
+        issues.push(WindowsPathIssue::TooLong(len));
+    }
+
+    issues
+}
+
+/// Check several relative paths, returning only the ones with problems
+pub fn check_windows_paths<'a>(
+    paths: impl IntoIterator<Item = &'a Path>,
+    max_len: usize,
+) -> Vec<WindowsPathProblem> {
+    let mut problems: Vec<WindowsPathProblem> = paths
+        .into_iter()
+        .filter_map(|path| {
+            let issues = check_windows_path(path, max_len);
+            if issues.is_empty() {
+                None
+            } else {
+                Some(WindowsPathProblem { path: path.to_path_buf(), issues })
+            }
+        })
+        .collect();
+    problems.sort_by(|a, b| a.path.cmp(&b.path));
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_names_with_and_without_extensions() {
+        assert_eq!(check_windows_path(Path::new("docs/CON"), WINDOWS_MAX_PATH), vec![WindowsPathIssue::ReservedName("CON".into())]);
+        assert_eq!(check_windows_path(Path::new("nul.txt"), WINDOWS_MAX_PATH), vec![WindowsPathIssue::ReservedName("nul.txt".into())]);
+        assert_eq!(check_windows_path(Path::new("aux.tar.gz"), WINDOWS_MAX_PATH), vec![WindowsPathIssue::ReservedName("aux.tar.gz".into())]);
+        // Names only starting with a device name are fine
+        assert!(check_windows_path(Path::new("console/com10.txt"), WINDOWS_MAX_PATH).is_empty());
+        assert!(check_windows_path(Path::new("lpt/prn_notes.txt"), WINDOWS_MAX_PATH).is_empty());
+    }
+
+    #[test]
+    fn trailing_dots_spaces_and_invalid_characters() {
+        assert_eq!(check_windows_path(Path::new("dir./a"), WINDOWS_MAX_PATH), vec![WindowsPathIssue::TrailingDotOrSpace("dir.".into())]);
+        assert_eq!(check_windows_path(Path::new("a /b"), WINDOWS_MAX_PATH), vec![WindowsPathIssue::TrailingDotOrSpace("a ".into())]);
+        assert_eq!(check_windows_path(Path::new("what?.txt"), WINDOWS_MAX_PATH), vec![WindowsPathIssue::InvalidCharacter('?')]);
+        assert_eq!(check_windows_path(Path::new("tab\there"), WINDOWS_MAX_PATH), vec![WindowsPathIssue::InvalidCharacter('\t')]);
+    }
+
+    #[test]
+    fn max_path_overflow_counts_utf16_units() {
+        let path = format!("dir/{}", "a".repeat(WINDOWS_MAX_PATH - 4));
+        assert!(check_windows_path(Path::new(&path), WINDOWS_MAX_PATH).is_empty());
+        let longer = format!("{}b", path);
+        assert_eq!(check_windows_path(Path::new(&longer), WINDOWS_MAX_PATH), vec![WindowsPathIssue::TooLong(WINDOWS_MAX_PATH + 1)]);
+        // A lower limit leaves room for the install directory
+        assert_eq!(check_windows_path(Path::new("abcdef"), 5), vec![WindowsPathIssue::TooLong(6)]);
+        // Characters outside the BMP take two UTF-16 units
+        assert_eq!(check_windows_path(Path::new("😀😀😀"), 5), vec![WindowsPathIssue::TooLong(6)]);
+    }
+
+    #[test]
+    fn only_problem_paths_are_reported_in_order() {
+        let paths = [Path::new("z/COM1"), Path::new("ok.txt"), Path::new("a/b|c")];
+        let problems = check_windows_paths(paths, WINDOWS_MAX_PATH);
+        let found: Vec<&Path> = problems.iter().map(|p| p.path.as_path()).collect();
+        assert_eq!(found, [Path::new("a/b|c"), Path::new("z/COM1")]);
+        assert_eq!(problems[0].issues, vec![WindowsPathIssue::InvalidCharacter('|')]);
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// How far the scan of one directory got, passed to a `ProgressHook`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanProgress<'a> {
+    /// Directory (or archive, disk image, ...) being scanned
+    pub dir: &'a Path,
+    /// Files the scan will hash, and their total size
+    pub files_discovered: usize,
+    pub bytes_discovered: u64,
+    /// Files hashed so far, including those taken unchanged from a snapshot or skipped
+    /// for errors, and their total size
+    pub files_hashed: usize,
+    pub bytes_hashed: u64,
+}
+
+impl ScanProgress<'_> {
+    /// Whether every discovered file was hashed
+    pub fn is_done(&self) -> bool {
+        self.files_hashed >= self.files_discovered
+    }
+}
+
+/// Hook called as scans discover and hash files, e.g. to drive a progress bar
+///
+/// Called once when the files of a directory are listed (with nothing hashed yet), then
+/// after every file. Files are hashed on several threads at once, so the hook is called
+/// from all of them and should return quickly.
+#[derive(Clone)]
+pub struct ProgressHook(Arc<ProgressFn>);
+
+type ProgressFn = dyn Fn(&ScanProgress) + Send + Sync;
+
+impl ProgressHook {
+    pub fn new(report: impl Fn(&ScanProgress) + Send + Sync + 'static) -> Self {
+        ProgressHook(Arc::new(report))
+    }
+
+    /// Pass `progress` to the hook, for operations that count on their own
+    pub fn report(&self, progress: &ScanProgress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+/// Counts the files of one scan and passes the counts to a `ProgressHook`, if any
+pub struct ScanCounter<'a> {
+    hook: Option<&'a ProgressHook>,
+    dir: &'a Path,
+    files_discovered: usize,
+    bytes_discovered: u64,
+    files_hashed: AtomicUsize,
+    bytes_hashed: AtomicU64,
+}
+
+impl<'a> ScanCounter<'a> {
+    /// Report the sizes of the files a scan of `dir` will hash; they are only looked at
+    /// if there is a hook
+    pub fn start(hook: Option<&'a ProgressHook>, dir: &'a Path, sizes: impl Iterator<Item = u64>) -> Self {
+        let (files_discovered, bytes_discovered) = match hook {
+            Some(_) => sizes.fold((0, 0), |(files, bytes), size| (files + 1, bytes + size)),
+            None => (0, 0),
+        };
+        let counter = ScanCounter {
+            hook,
+            dir,
+            files_discovered,
+            bytes_discovered,
+            files_hashed: AtomicUsize::new(0),
+            bytes_hashed: AtomicU64::new(0),
+        };
+        counter.report(0, 0);
+        counter
+    }
+
+    /// Count one more file of `bytes` bytes as hashed
+    pub fn hashed(&self, bytes: u64) {
+        if self.hook.is_none() {
+            return;
+        }
+        let files = self.files_hashed.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = self.bytes_hashed.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.report(files, bytes);
+    }
+
+    fn report(&self, files_hashed: usize, bytes_hashed: u64) {
+        if let Some(ProgressHook(report)) = self.hook {
+            report(&ScanProgress {
+                dir: self.dir,
+                files_discovered: self.files_discovered,
+                bytes_discovered: self.bytes_discovered,
+                files_hashed,
+                bytes_hashed,
+            });
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::manifest::Manifest;
+use crate::staging::StagingDir;
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Program used for downloads and uploads, unless `DIFFPATCH_CURL` names another
+pub const CURL_VAR: &str = "DIFFPATCH_CURL";
+
+/// Endpoint of an S3-compatible object store such as MinIO (e.g. `http://localhost:9000`);
+/// AWS S3 if not set
+pub const S3_ENDPOINT_VAR: &str = "DIFFPATCH_S3_ENDPOINT";
+
+/// Largest download accepted, so a wrong URL cannot fill the memory
+const MAX_DOWNLOAD_SIZE: u64 = 1 << 30;
+
+/// Seconds to wait for a connection before giving up
+const CONNECT_TIMEOUT_SECS: u32 = 30;
+
+/// Region of S3 requests when neither `AWS_REGION` nor `AWS_DEFAULT_REGION` is set
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Whether `source` is an HTTP, HTTPS or S3 URL rather than a local path
+pub fn is_url(source: &Path) -> bool {
+    source.to_str().is_some_and(|source| ["http://", "https://", "s3://"].iter().any(|scheme| has_scheme(source, scheme)))
+}
+
+fn has_scheme(url: &str, scheme: &str) -> bool {
+    url.get(..scheme.len()).is_some_and(|start| start.eq_ignore_ascii_case(scheme))
+}
+
+/// A file in remote storage, read and written with curl
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteFile {
+    /// An `http://` or `https://` URL; uploads are HTTP PUT requests, e.g. to a
+    /// presigned URL
+    Http(String),
+    /// An object of an S3-compatible store, `s3://BUCKET/KEY`
+    ///
+    /// Requests are signed (AWS signature version 4) with `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and, for temporary credentials, `AWS_SESSION_TOKEN`, in
+    /// the region `AWS_REGION` or `AWS_DEFAULT_REGION` (default `us-east-1`); without
+    /// credentials they are anonymous, which public buckets allow for reading.
+    S3 { bucket: String, key: String },
+}
+
+impl RemoteFile {
+    /// Parse an HTTP, HTTPS or S3 URL; `None` if `url` is none of them (a local path)
+    pub fn parse(url: &str) -> Result<Option<Self>> {
+        if has_scheme(url, "http://") || has_scheme(url, "https://") {
+            return Ok(Some(RemoteFile::Http(url.to_string())));
+        }
+        if !has_scheme(url, "s3://") {
+            return Ok(None);
+        }
+        let (bucket, key) = url["s3://".len()..].split_once('/').unwrap_or((&url["s3://".len()..], ""));
+        let valid_bucket = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '.');
+        if bucket.len() < 3 || bucket.len() > 63 || !bucket.chars().all(valid_bucket) {
+            return Err(anyhow!("Invalid S3 bucket name in {}: use 3 to 63 lowercase letters, digits, '-' or '.'", url));
+        }
+        Ok(Some(RemoteFile::S3 { bucket: bucket.to_string(), key: key.to_string() }))
+    }
+
+    /// The file named `name` in this folder if it ends with `/`, else the file itself
+    pub fn join_if_folder(self, name: &str) -> Self {
+        match self {
+            RemoteFile::Http(url) if url.ends_with('/') => RemoteFile::Http(format!("{}{}", url, encode_path(name))),
+            RemoteFile::S3 { bucket, key } if key.is_empty() || key.ends_with('/') => {
+                RemoteFile::S3 { bucket, key: format!("{}{}", key, name) }
+            }
+            file => file,
+        }
+    }
+
+    /// Last part of the path, e.g. `update.zip`
+    pub fn file_name(&self) -> &str {
+        let path = match self {
+            RemoteFile::Http(url) => url.split(['?', '#']).next().unwrap_or(url),
+            RemoteFile::S3 { key, .. } => key,
+        };
+        path.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// URL curl requests, and the curl options for it (passed as a configuration on the
+    /// standard input, so credentials do not show in the process list)
+    fn request(&self) -> Result<(String, Vec<String>)> {
+        let (bucket, key) = match self {
+            RemoteFile::Http(url) => return Ok((url.clone(), Vec::new())),
+            RemoteFile::S3 { bucket, key } => (bucket, key),
+        };
+        if key.is_empty() || key.ends_with('/') {
+            return Err(anyhow!("{} names a folder, not an object", self));
+        }
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let region = env("AWS_REGION").or_else(|| env("AWS_DEFAULT_REGION")).unwrap_or_else(|| DEFAULT_S3_REGION.to_string());
+        // Path-style requests for custom endpoints and for bucket names with dots, which
+        // do not match the certificate of virtual-hosted ones
+        let url = match env(S3_ENDPOINT_VAR) {
+            Some(endpoint) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, encode_path(key)),
+            None if bucket.contains('.') => format!("https://s3.{}.amazonaws.com/{}/{}", region, bucket, encode_path(key)),
+            None => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, encode_path(key)),
+        };
+        let mut config = Vec::new();
+        if let (Some(access_key), Some(secret_key)) = (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+            config.push(config_line("aws-sigv4", &format!("aws:amz:{}:s3", region)));
+            config.push(config_line("user", &format!("{}:{}", access_key, secret_key)));
+            config.push(config_line("header", "x-amz-content-sha256: UNSIGNED-PAYLOAD"));
+            if let Some(token) = env("AWS_SESSION_TOKEN") {
+                config.push(config_line("header", &format!("x-amz-security-token: {}", token)));
+            }
+        }
+        Ok((url, config))
+    }
+
+    /// Download the file into memory, following redirects
+    ///
+    /// Only HTTP and HTTPS are allowed, also for redirects. Responses with an error status
+    /// fail, as do downloads larger than 1 GiB.
+    pub fn download(&self) -> Result<Vec<u8>> {
+        let max_size = MAX_DOWNLOAD_SIZE.to_string();
+        let data = self.curl(&["--location", "--compressed", "--max-filesize", &max_size], None)
+            .with_context(|| format!("Failed to download {}", self))?;
+        Ok(data)
+    }
+
+    /// Upload the local file at `file`, replacing what is stored there
+    pub fn upload(&self, file: &Path) -> Result<()> {
+        self.curl(&[], Some(file)).with_context(|| format!("Failed to upload {} to {}", file.display(), self))?;
+        Ok(())
+    }
+
+    /// Run curl for the file; uploads `upload` if given, else downloads
+    fn curl(&self, args: &[&str], upload: Option<&Path>) -> Result<Vec<u8>> {
+        let (url, config) = self.request()?;
+        let curl = std::env::var_os(CURL_VAR).filter(|program| !program.is_empty()).unwrap_or_else(|| "curl".into());
+        let mut command = Command::new(&curl);
+        command
+            .args(["--config", "-", "--fail", "--silent", "--show-error"])
+            .args(["--proto", "=http,https", "--proto-redir", "=http,https"])
+            .args(["--connect-timeout", &CONNECT_TIMEOUT_SECS.to_string()])
+            .args(args);
+        if let Some(file) = upload {
+            command.arg("--upload-file").arg(file);
+        }
+        command.arg("--").arg(&url).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(anyhow!("Transferring {} needs curl, which was not found (set {} to its path)", self, CURL_VAR));
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to run {}", curl.to_string_lossy())),
+        };
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let written = config.iter().try_for_each(|line| writeln!(stdin, "{}", line));
+        drop(stdin);
+        let output = child.wait_with_output().with_context(|| format!("Failed to run {}", curl.to_string_lossy()))?;
+        written.with_context(|| format!("Failed to pass options to {}", curl.to_string_lossy()))?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stderr);
+            let message = message.trim().trim_start_matches("curl: ");
+            return Err(anyhow!("{}", if message.is_empty() { "curl failed" } else { message }));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl fmt::Display for RemoteFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteFile::Http(url) => write!(f, "{}", url),
+            RemoteFile::S3 { bucket, key } => write!(f, "s3://{}/{}", bucket, key),
+        }
+    }
+}
+
+/// One line of a curl configuration, with the value quoted
+fn config_line(option: &str, value: &str) -> String {
+    format!("{} = \"{}\"", option, value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Percent-encode all but unreserved characters and `/`, as S3 signs object paths
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The remote file `url` names; fails for local paths
+pub fn remote_file(url: &str) -> Result<RemoteFile> {
+    RemoteFile::parse(url)?.ok_or_else(|| anyhow!("Not an http(s) or s3 URL: {}", url))
+}
+
+/// Download `url` (HTTP, HTTPS or S3) into memory; see `RemoteFile::download`
+pub fn download(url: &str) -> Result<Vec<u8>> {
+    remote_file(url)?.download()
+}
+
+/// Upload the local file at `file` to `url`; a URL ending with `/` gets the file name
+/// appended. Returns where the file was stored.
+pub fn upload(file: &Path, url: &str) -> Result<RemoteFile> {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let remote = remote_file(url)?.join_if_folder(&name);
+    remote.upload(file)?;
+    Ok(remote)
+}
+
+/// Download a manifest published at `url`, as saved with `Manifest::save`
+pub fn load_manifest(url: &str) -> Result<Manifest> {
+    let data = download(url)?;
+    let manifest: Manifest = serde_json::from_slice(&data).with_context(|| format!("Failed to parse manifest: {}", url))?;
+    manifest.validate().with_context(|| format!("Invalid manifest file: {}", url))?;
+    Ok(manifest)
+}
+
+/// Where to write an output that may be given as a URL: the local path itself, or a
+/// temporary file that `finish` uploads
+pub struct OutputFile {
+    local: PathBuf,
+    remote: Option<RemoteFile>,
+    _staging: Option<StagingDir>,
+}
+
+impl OutputFile {
+    pub fn new(output: &Path) -> Result<Self> {
+        let Some(remote) = output.to_str().map(RemoteFile::parse).transpose()?.flatten() else {
+            return Ok(OutputFile { local: output.to_path_buf(), remote: None, _staging: None });
+        };
+        if remote.file_name().is_empty() {
+            return Err(anyhow!("The output {} names a folder, not a file", remote));
+        }
+        let staging = StagingDir::new_in(&std::env::temp_dir(), "upload")?;
+        // Same name as the upload, so the format can still be told from the extension
+        let local = staging.path().join(remote.file_name());
+        Ok(OutputFile { local, remote: Some(remote), _staging: Some(staging) })
+    }
+
+    /// Local file to write the output to
+    pub fn path(&self) -> &Path {
+        &self.local
+    }
+
+    /// Where the output ends up, for messages
+    pub fn display(&self) -> String {
+        match &self.remote {
+            Some(remote) => remote.to_string(),
+            None => self.local.display().to_string(),
+        }
+    }
+
+    /// Upload the written file if the output is a URL
+    pub fn finish(self) -> Result<()> {
+        if let Some(remote) = &self.remote {
+            remote.upload(&self.local)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use crate::diff::{DiffReport, DiffType};
+use crate::digest::HashAbbreviations;
+use crate::sink::DiffSink;
+use crate::utils;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Writes a standalone HTML page describing a comparison when finished, for reviewing
+/// what a patch contains
+///
+/// The page has summary statistics and one table each for added, modified, removed,
+/// renamed and permission-only files. Tables sort by any column when its header is
+/// clicked. Everything (styles and script) is inline, so the file can be mailed or
+/// attached to a release as it is.
+#[derive(Debug)]
+pub struct HtmlReport {
+    path: PathBuf,
+    title: String,
+}
+
+impl HtmlReport {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        HtmlReport { path: path.into(), title: "Diff report".to_string() }
+    }
+
+    /// Heading and window title of the page, e.g. `Update 1.2 to 1.3`
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl DiffSink for HtmlReport {
+    fn on_finish(&mut self, report: &DiffReport) -> Result<()> {
+        fs::write(&self.path, render_html(report, &self.title))
+            .with_context(|| format!("Failed to write HTML report: {}", self.path.display()))
+    }
+}
+
+/// Render `report` as a standalone HTML page (see `HtmlReport`)
+///
+/// Size changes are shown where `DiffReport::source_files` has the old size, which
+/// reports loaded from JSON do not.
+pub fn render_html(report: &DiffReport, title: &str) -> String {
+    let mut diffs: Vec<&DiffType> = report.diffs.iter().collect();
+    diffs.sort_by(|a, b| a.path().cmp(b.path()));
+    // Diff patches only hold the changes; the manifest has the full size
+    let manifest_sizes: HashMap<&Path, u64> = report.target_manifest.iter()
+        .flat_map(|manifest| &manifest.files)
+        .map(|info| (info.relative_path.as_path(), info.size))
+        .collect();
+    let new_size = |diff: &DiffType| match diff {
+        DiffType::Added(info) | DiffType::Modified(info) | DiffType::MetadataChanged(info) => Some(info.size),
+        DiffType::Renamed(rename) => Some(rename.to.size),
+        DiffType::ModifiedDiff(file_diff) => manifest_sizes.get(file_diff.relative_path.as_path()).copied(),
+        DiffType::Removed(_) => None,
+    };
+    let old_size = |diff: &DiffType| match diff {
+        DiffType::Added(_) => None,
+        DiffType::Renamed(rename) => report.source_files.get(&rename.from).map(|info| info.size),
+        _ => report.source_files.get(diff.path()).map(|info| info.size),
+    };
+
+    let of_kind = |keep: fn(&DiffType) -> bool| diffs.iter().copied().filter(|diff| keep(diff)).collect::<Vec<_>>();
+    let added = of_kind(|diff| matches!(diff, DiffType::Added(_)));
+    let modified = of_kind(|diff| matches!(diff, DiffType::Modified(_) | DiffType::ModifiedDiff(_)));
+    let removed = of_kind(|diff| matches!(diff, DiffType::Removed(_)));
+    let renamed = of_kind(|diff| matches!(diff, DiffType::Renamed(_)));
+    let permissions = of_kind(|diff| matches!(diff, DiffType::MetadataChanged(_)));
+
+    let added_bytes: u64 = added.iter().filter_map(|diff| new_size(diff)).sum();
+    let removed_bytes: u64 = removed.iter().filter_map(|diff| old_size(diff)).sum();
+    let net: i128 = diffs.iter()
+        .map(|diff| new_size(diff).unwrap_or(0) as i128 - old_size(diff).unwrap_or(0) as i128)
+        .sum();
+
+    // Hashes are shortened for reading; the full hash shows when hovering over one
+    let abbreviations = report.hash_abbreviations();
+    let mut html = String::new();
+    let title = escape(title);
+    let _ = write!(html, "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n", title, STYLE, title);
+
+    html.push_str("<h2>Summary</h2>\n<table class=\"summary\">\n");
+    let mut summary_row = |label: &str, value: String| {
+        let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", label, escape(&value));
+    };
+    summary_row("Changed files", format!("{} of {} ({:.1}%)", diffs.len(), report.stats.total_files, report.stats.changed_percent()));
+    summary_row("Added", format!("{} files, {}", added.len(), utils::format_size(added_bytes)));
+    summary_row("Modified", format!("{} files", modified.len()));
+    summary_row("Removed", format!("{} files, {}", removed.len(), utils::format_size(removed_bytes)));
+    summary_row("Renamed", format!("{} files", renamed.len()));
+    summary_row("Permissions changed", format!("{} files", permissions.len()));
+    summary_row("Size of added and modified files", utils::format_size(report.stats.changed_bytes));
+    summary_row("Net size change", format_delta(net));
+    if let Some(run_id) = &report.run_id {
+        summary_row("Run ID", run_id.clone());
+    }
+    html.push_str("</table>\n");
+
+    if !report.warnings.is_empty() {
+        let _ = writeln!(html, "<h2>Warnings ({})</h2>\n<ul>", report.warnings.len());
+        for warning in &report.warnings {
+            let _ = writeln!(html, "<li>{}</li>", escape(&warning.to_string()));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    let mut table = Table::new(&mut html, "Added files", &added, &["Path", "Size", "Hash"]);
+    for diff in &added {
+        table.row(&[Cell::text(path_text(diff.path())), Cell::size(new_size(diff)), Cell::hash(diff.hash(), &abbreviations)]);
+    }
+    table.finish();
+
+    let mut table = Table::new(&mut html, "Modified files", &modified, &["Path", "Old size", "New size", "Change", "Stored as", "Hash"]);
+    for diff in &modified {
+        let (old, new) = (old_size(diff), new_size(diff));
+        let change = old.zip(new).map(|(old, new)| new as i128 - old as i128);
+        let stored = if matches!(diff, DiffType::ModifiedDiff(_)) { "diff patch" } else { "full file" };
+        table.row(&[
+            Cell::text(path_text(diff.path())),
+            Cell::size(old),
+            Cell::size(new),
+            Cell::delta(change),
+            Cell::text(stored),
+            Cell::hash(diff.hash(), &abbreviations),
+        ]);
+    }
+    table.finish();
+
+    let mut table = Table::new(&mut html, "Removed files", &removed, &["Path", "Size"]);
+    for diff in &removed {
+        table.row(&[Cell::text(path_text(diff.path())), Cell::size(old_size(diff))]);
+    }
+    table.finish();
+
+    let mut table = Table::new(&mut html, "Renamed files", &renamed, &["From", "To", "Size"]);
+    for diff in &renamed {
+        if let DiffType::Renamed(rename) = diff {
+            table.row(&[Cell::text(path_text(&rename.from)), Cell::text(path_text(diff.path())), Cell::size(new_size(diff))]);
+        }
+    }
+    table.finish();
+
+    let mut table = Table::new(&mut html, "Permission changes", &permissions, &["Path", "Mode"]);
+    for diff in &permissions {
+        if let DiffType::MetadataChanged(info) = diff {
+            let mode = info.mode.map(|mode| mode.to_string()).unwrap_or_default();
+            table.row(&[Cell::text(path_text(diff.path())), Cell::text(mode)]);
+        }
+    }
+    table.finish();
+
+    let _ = write!(html, "<script>{}</script>\n</body>\n</html>\n", SCRIPT);
+    html
+}
+
+/// One table section of the page; left out entirely when it has no rows
+struct Table<'a> {
+    html: &'a mut String,
+    empty: bool,
+}
+
+impl<'a> Table<'a> {
+    fn new(html: &'a mut String, heading: &str, rows: &[&DiffType], columns: &[&str]) -> Self {
+        let empty = rows.is_empty();
+        if !empty {
+            let _ = writeln!(html, "<h2>{} ({})</h2>\n<table class=\"sortable\">\n<thead><tr>", escape(heading), rows.len());
+            for column in columns {
+                let _ = write!(html, "<th>{}</th>", escape(column));
+            }
+            html.push_str("</tr></thead>\n<tbody>\n");
+        }
+        Table { html, empty }
+    }
+
+    fn row(&mut self, cells: &[Cell]) {
+        self.html.push_str("<tr>");
+        for cell in cells {
+            match (&cell.sort_key, &cell.title) {
+                (Some(key), _) => {
+                    let _ = write!(self.html, "<td class=\"num\" data-sort=\"{}\">{}</td>", key, escape(&cell.text));
+                }
+                (None, Some(title)) => {
+                    let _ = write!(self.html, "<td title=\"{}\">{}</td>", escape(title), escape(&cell.text));
+                }
+                (None, None) => {
+                    let _ = write!(self.html, "<td>{}</td>", escape(&cell.text));
+                }
+            }
+        }
+        self.html.push_str("</tr>\n");
+    }
+
+    fn finish(self) {
+        if !self.empty {
+            self.html.push_str("</tbody>\n</table>\n");
+        }
+    }
+}
+
+/// Table cell; numeric cells sort by `sort_key` instead of their text
+struct Cell {
+    text: String,
+    sort_key: Option<i128>,
+    /// Tooltip, e.g. the full form of an abbreviated hash
+    title: Option<String>,
+}
+
+impl Cell {
+    fn text(text: impl Into<String>) -> Self {
+        Cell { text: text.into(), sort_key: None, title: None }
+    }
+
+    fn hash(hash: Option<&str>, abbreviations: &HashAbbreviations) -> Self {
+        let hash = hash.unwrap_or("");
+        Cell { text: abbreviations.abbreviate(hash).to_string(), sort_key: None, title: Some(hash.to_string()) }
+    }
+
+    fn size(size: Option<u64>) -> Self {
+        match size {
+            Some(size) => Cell { text: utils::format_size(size), sort_key: Some(size as i128), title: None },
+            None => Cell { text: String::new(), sort_key: Some(-1), title: None },
+        }
+    }
+
+    fn delta(change: Option<i128>) -> Self {
+        match change {
+            Some(change) => Cell { text: format_delta(change), sort_key: Some(change), title: None },
+            None => Cell { text: String::new(), sort_key: Some(0), title: None },
+        }
+    }
+}
+
+/// Size change with its sign, e.g. `+1.5 KiB` or `-300 B`
+fn format_delta(change: i128) -> String {
+    let size = utils::format_size(change.unsigned_abs().min(u64::MAX as u128) as u64);
+    match change {
+        0 => size,
+        _ if change > 0 => format!("+{}", size),
+        _ => format!("-{}", size),
+    }
+}
+
+fn path_text(path: &Path) -> String {
+    crate::paths::normalize_separators(path).to_string_lossy().into_owned()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const STYLE: &str = "\
+body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{border:1px solid #ccc;padding:.3em .6em;text-align:left}\
+td{font-family:ui-monospace,monospace;font-size:.9em;word-break:break-all}\
+td.num{text-align:right;white-space:nowrap}\
+.sortable th{background:#f0f0f0;cursor:pointer;user-select:none}\
+.sortable th:after{content:' \\2195';color:#999}\
+.summary th{background:#f7f7f7}";
+
+const SCRIPT: &str = "\
+document.querySelectorAll('table.sortable').forEach(function(table){\
+var headers=table.querySelectorAll('th');\
+headers.forEach(function(th,column){\
+var ascending=true;\
+th.addEventListener('click',function(){\
+var body=table.tBodies[0];\
+var rows=Array.prototype.slice.call(body.rows);\
+rows.sort(function(a,b){\
+var x=a.cells[column],y=b.cells[column];\
+var result=x.dataset.sort!==undefined?Number(x.dataset.sort)-Number(y.dataset.sort):x.textContent.localeCompare(y.textContent);\
+return ascending?result:-result;\
+});\
+ascending=!ascending;\
+rows.forEach(function(row){body.appendChild(row);});\
+});\
+});\
+});";
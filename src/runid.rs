@@ -0,0 +1,59 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Run ID used when none is set explicitly, e.g. one assigned by a build pipeline
+pub const RUN_ID_VAR: &str = "DIFFPATCH_RUN_ID";
+
+/// Longest run ID accepted from `set` or `DIFFPATCH_RUN_ID`
+const MAX_RUN_ID_LEN: usize = 128;
+
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+/// Identifier of this run, written into the reports, manifests, patches and records it
+/// produces so they can be traced back to it
+///
+/// `DIFFPATCH_RUN_ID` if set and valid, otherwise a random UUID (version 4); fixed by
+/// the first call, or by `set` before it.
+pub fn current() -> &'static str {
+    RUN_ID.get_or_init(|| {
+        std::env::var(RUN_ID_VAR).ok().filter(|id| validate(id).is_ok()).unwrap_or_else(generate)
+    })
+}
+
+/// Use `id` as the run ID; fails if it is not a valid ID or the run ID was already used
+pub fn set(id: &str) -> Result<()> {
+    validate(id)?;
+    RUN_ID.set(id.to_string()).map_err(|_| anyhow!("The run ID was already chosen: {}", current()))
+}
+
+/// Check that `id` can be embedded in file names and log lines: 1 to 128 ASCII letters,
+/// digits, `-`, `_`, `.` or `:`
+pub fn validate(id: &str) -> Result<()> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':');
+    if id.is_empty() || id.len() > MAX_RUN_ID_LEN || !id.chars().all(valid_char) {
+        return Err(anyhow!("Invalid run ID '{}': use 1 to {} letters, digits, '-', '_', '.' or ':'", id, MAX_RUN_ID_LEN));
+    }
+    Ok(())
+}
+
+/// A new random UUID (version 4), e.g. `3f2b8c1e-5d4a-4f6b-9c2d-7e8f9a0b1c2d`
+///
+/// The randomly keyed hashers of the standard library are mixed with the time and the
+/// process ID, so no random number crate is needed.
+pub fn generate() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+    let mut hasher = Sha256::new();
+    hasher.update(RandomState::new().hash_one(nanos).to_le_bytes());
+    hasher.update(RandomState::new().hash_one(std::process::id()).to_le_bytes());
+    hasher.update(nanos.to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    let mut bytes: [u8; 16] = hasher.finalize()[..16].try_into().expect("SHA-256 is longer than 16 bytes");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40; // Version 4
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
@@ -0,0 +1,461 @@
+use crate::content::ContentKind;
+use crate::diff::{self, DiffOptions, DiffReport, DiffType, FileInfo, FileRename};
+use crate::digest::HashAbbreviations;
+use crate::fsops::FileMode;
+use crate::paths::normalize_separators;
+use crate::utils;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "serde")]
+use crate::livediff::LiveChange;
+#[cfg(feature = "serde")]
+use crate::writebehind::WriteBehind;
+
+/// Consumer of the differences found by a comparison (report writers, packagers, notifiers, ...)
+///
+/// Every callback defaults to doing nothing, so a sink only implements what it needs.
+/// Differences are delivered in path order, followed by one `on_finish` call.
+pub trait DiffSink {
+    fn on_added(&mut self, _info: &FileInfo) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for both `DiffType::Modified` and `DiffType::ModifiedDiff`
+    fn on_modified(&mut self, _diff: &DiffType) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_removed(&mut self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Defaults to removing the old path and adding the new one
+    fn on_renamed(&mut self, rename: &FileRename) -> Result<()> {
+        self.on_removed(&rename.from)?;
+        self.on_added(&rename.to)
+    }
+
+    /// Called for files whose permissions changed but whose content did not
+    fn on_metadata_changed(&mut self, _info: &FileInfo) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once after all differences, with the complete report
+    fn on_finish(&mut self, _report: &DiffReport) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Feed every difference of `report` into each of the `sinks`, then finish them
+///
+/// Stops at the first error of any sink.
+pub fn run_sinks(report: &DiffReport, sinks: &mut [&mut dyn DiffSink]) -> Result<()> {
+    let mut diffs: Vec<&DiffType> = report.diffs.iter().collect();
+    diffs.sort_by(|a, b| a.path().cmp(b.path()));
+    for diff in diffs {
+        for sink in sinks.iter_mut() {
+            match diff {
+                DiffType::Added(info) => sink.on_added(info)?,
+                DiffType::Modified(_) | DiffType::ModifiedDiff(_) => sink.on_modified(diff)?,
+                DiffType::Removed(path) => sink.on_removed(path)?,
+                DiffType::Renamed(rename) => sink.on_renamed(rename)?,
+                DiffType::MetadataChanged(info) => sink.on_metadata_changed(info)?,
+            }
+        }
+    }
+    for sink in sinks.iter_mut() {
+        sink.on_finish(report)?;
+    }
+    Ok(())
+}
+
+/// Compare two directories (see `diff::compare_directories`) and run the result through `sinks`
+pub fn compare_into(
+    source_dir: &Path,
+    target_dir: &Path,
+    options: &DiffOptions,
+    sinks: &mut [&mut dyn DiffSink],
+) -> Result<DiffReport> {
+    let report = diff::compare_directories(source_dir, target_dir, options)?;
+    run_sinks(&report, sinks)?;
+    Ok(report)
+}
+
+/// Number of differences of each kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffCounter {
+    pub added: usize,
+    pub modified: usize,      // Stored as full files
+    pub modified_diff: usize, // Stored as diff patches
+    pub removed: usize,
+    pub renamed: usize,
+    pub metadata_changed: usize,
+    /// Added and modified files with text content; files whose content was not
+    /// classified count in neither
+    pub text: usize,
+    /// Added and modified files with binary content
+    pub binary: usize,
+}
+
+impl DiffCounter {
+    pub fn total(&self) -> usize {
+        self.added + self.modified + self.modified_diff + self.removed + self.renamed + self.metadata_changed
+    }
+
+    fn count_content(&mut self, content: Option<ContentKind>) {
+        match content {
+            Some(ContentKind::Text) => self.text += 1,
+            Some(ContentKind::Binary) => self.binary += 1,
+            None => {}
+        }
+    }
+}
+
+impl DiffSink for DiffCounter {
+    fn on_added(&mut self, info: &FileInfo) -> Result<()> {
+        self.added += 1;
+        self.count_content(info.content);
+        Ok(())
+    }
+
+    fn on_modified(&mut self, diff: &DiffType) -> Result<()> {
+        match diff {
+            DiffType::ModifiedDiff(_) => {
+                self.modified_diff += 1;
+                // Only text becomes a diff patch
+                self.count_content(Some(ContentKind::Text));
+            }
+            DiffType::Modified(info) => {
+                self.modified += 1;
+                self.count_content(info.content);
+            }
+            _ => self.modified += 1,
+        }
+        Ok(())
+    }
+
+    fn on_removed(&mut self, _path: &Path) -> Result<()> {
+        self.removed += 1;
+        Ok(())
+    }
+
+    fn on_renamed(&mut self, _rename: &FileRename) -> Result<()> {
+        self.renamed += 1;
+        Ok(())
+    }
+
+    fn on_metadata_changed(&mut self, _info: &FileInfo) -> Result<()> {
+        self.metadata_changed += 1;
+        Ok(())
+    }
+}
+
+/// Saves the report as JSON when finished (see `DiffReport::save`)
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct ReportWriter {
+    path: PathBuf,
+    background: bool,
+    pending: Option<WriteBehind<()>>,
+}
+
+#[cfg(feature = "serde")]
+impl ReportWriter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        ReportWriter { path: path.into(), background: false, pending: None }
+    }
+
+    /// Serialize and write the report on a background thread, so the caller can go on
+    /// (e.g. with creating the patch) while a large report is written; see `wait`
+    pub fn in_background(mut self) -> Self {
+        self.background = true;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Wait until a report written in the background is saved
+    pub fn wait(&mut self) -> Result<()> {
+        match self.pending.take() {
+            Some(pending) => pending.wait(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl DiffSink for ReportWriter {
+    fn on_finish(&mut self, report: &DiffReport) -> Result<()> {
+        if !self.background {
+            return report.save(&self.path);
+        }
+        self.wait()?;
+        let (report, path) = (report.clone(), self.path.clone());
+        self.pending = Some(WriteBehind::spawn("report", move || report.save(&path))?);
+        Ok(())
+    }
+}
+
+/// How `ChangeListWriter` writes the differences
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChangeListFormat {
+    /// One line per difference, as `LineStyle` says
+    #[default]
+    Text,
+    /// A JSON array of the `DiffType` entries, one per line
+    #[cfg(feature = "serde")]
+    Json,
+    /// A `change,path,from,size,hash` header and one row per difference
+    Csv,
+}
+
+/// How much each line of `ChangeListFormat::Text` shows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineStyle {
+    /// Only the path, for shell scripts (the new path of a renamed file)
+    Paths,
+    /// Kind, path and size, e.g. `added: docs/guide.txt (4.2 KiB)`
+    #[default]
+    Short,
+    /// Kind and path with old and new size, hashes and permissions where known
+    Full,
+}
+
+/// Writes the differences as a change list for scripts and build pipelines
+///
+/// Paths use `/` separators on every platform. Each difference is written as it is
+/// delivered; `on_finish` closes the list and flushes the writer.
+#[derive(Debug)]
+pub struct ChangeListWriter<W: Write> {
+    out: W,
+    format: ChangeListFormat,
+    style: LineStyle,
+    sources: HashMap<PathBuf, FileInfo>,
+    abbreviations: Option<HashAbbreviations>,
+    written: usize,
+}
+
+impl<W: Write> ChangeListWriter<W> {
+    pub fn new(out: W, format: ChangeListFormat) -> Self {
+        ChangeListWriter { out, format, style: LineStyle::default(), sources: HashMap::new(), abbreviations: None, written: 0 }
+    }
+
+    pub fn style(mut self, style: LineStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Source tree entries of the changed files (see `DiffReport::source_files`), for the
+    /// old sizes, hashes and permissions of `LineStyle::Full`
+    pub fn sources(mut self, files: HashMap<PathBuf, FileInfo>) -> Self {
+        self.sources = files;
+        self
+    }
+
+    /// Shorten the hashes of text lines, e.g. with `DiffReport::hash_abbreviations`;
+    /// JSON and CSV always have the full hashes
+    pub fn abbreviate_hashes(mut self, abbreviations: HashAbbreviations) -> Self {
+        self.abbreviations = Some(abbreviations);
+        self
+    }
+
+    /// Number of differences written so far
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn text_line<'a>(&'a self, diff: &'a DiffType, path: &Path) -> String {
+        if self.style == LineStyle::Paths {
+            return path.display().to_string();
+        }
+        let (name, source) = match diff {
+            DiffType::Renamed(rename) => (
+                format!("{} -> {}", normalize_separators(&rename.from).display(), path.display()),
+                self.sources.get(&rename.from),
+            ),
+            DiffType::Added(_) => (path.display().to_string(), None),
+            _ => (path.display().to_string(), self.sources.get(diff.path())),
+        };
+        let new_size = match diff {
+            DiffType::Added(info) | DiffType::Modified(info) | DiffType::MetadataChanged(info) => Some(info.size),
+            DiffType::Renamed(rename) => Some(rename.to.size),
+            DiffType::ModifiedDiff(_) | DiffType::Removed(_) => None,
+        };
+        let old_size = source.map(|info| info.size);
+
+        let mut details = Vec::new();
+        match self.style {
+            LineStyle::Paths => {}
+            LineStyle::Short => details.extend(new_size.or(old_size).map(utils::format_size)),
+            LineStyle::Full => {
+                let short = |hash: &'a str| self.abbreviations.as_ref().map_or(hash, |abbreviations| abbreviations.abbreviate(hash));
+                let old_hash = match diff {
+                    DiffType::ModifiedDiff(file_diff) => Some(file_diff.original_hash.as_str()),
+                    _ => source.map(|info| info.hash.as_str()),
+                }.map(short);
+                let new_hash = diff.hash().map(short);
+                match diff {
+                    DiffType::Modified(_) | DiffType::ModifiedDiff(_) => {
+                        let size = |size: Option<u64>| size.map_or_else(|| "?".to_string(), utils::format_size);
+                        details.push(format!("{} -> {}", size(old_size), size(new_size)));
+                        details.push(format!("{} -> {}", old_hash.unwrap_or("?"), new_hash.unwrap_or("?")));
+                    }
+                    DiffType::Removed(_) => {
+                        details.extend(old_size.map(utils::format_size));
+                        details.extend(old_hash.map(str::to_string));
+                    }
+                    DiffType::MetadataChanged(info) => {
+                        let mode = |mode: Option<FileMode>| mode.map_or_else(|| "?".to_string(), |mode| mode.to_string());
+                        details.push(format!("{} -> {}", mode(source.and_then(|info| info.mode)), mode(info.mode)));
+                        details.extend(new_size.map(utils::format_size));
+                        details.extend(new_hash.map(str::to_string));
+                    }
+                    DiffType::Added(_) | DiffType::Renamed(_) => {
+                        details.extend(new_size.map(utils::format_size));
+                        details.extend(new_hash.map(str::to_string));
+                    }
+                }
+            }
+        }
+        match details.is_empty() {
+            true => format!("{}: {}", diff.kind(), name),
+            false => format!("{}: {} ({})", diff.kind(), name, details.join(", ")),
+        }
+    }
+
+    fn write(&mut self, diff: &DiffType) -> Result<()> {
+        let path = normalize_separators(diff.path());
+        match self.format {
+            ChangeListFormat::Text => {
+                let line = self.text_line(diff, &path);
+                writeln!(self.out, "{}", line)?;
+            }
+            #[cfg(feature = "serde")]
+            ChangeListFormat::Json => {
+                self.out.write_all(if self.written == 0 { b"[\n" } else { b",\n" })?;
+                let mut diff = diff.clone();
+                diff.normalize_path();
+                serde_json::to_writer(&mut self.out, &diff)?;
+            }
+            ChangeListFormat::Csv => {
+                if self.written == 0 {
+                    writeln!(self.out, "change,path,from,size,hash")?;
+                }
+                let (change, size) = match diff {
+                    DiffType::Added(info) => ("added", Some(info.size)),
+                    DiffType::Modified(info) => ("modified", Some(info.size)),
+                    DiffType::ModifiedDiff(_) => ("modified", None),
+                    DiffType::Removed(_) => ("removed", None),
+                    DiffType::Renamed(rename) => ("renamed", Some(rename.to.size)),
+                    DiffType::MetadataChanged(info) => ("permissions", Some(info.size)),
+                };
+                let from = match diff {
+                    DiffType::Renamed(rename) => normalize_separators(&rename.from).to_string_lossy().into_owned(),
+                    _ => String::new(),
+                };
+                writeln!(
+                    self.out,
+                    "{},{},{},{},{}",
+                    change,
+                    csv_field(&path.to_string_lossy()),
+                    csv_field(&from),
+                    size.map(|size| size.to_string()).unwrap_or_default(),
+                    diff.hash().unwrap_or("")
+                )?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<W: Write> ChangeListWriter<W> {
+    /// Write one change of a watched comparison (see `livediff::watch_diff`) and flush
+    ///
+    /// JSON is written as one `LiveChange` object per line rather than an array, as the
+    /// list never ends. Paths that match the source again are written as `unchanged`.
+    pub fn write_change(&mut self, change: &LiveChange) -> Result<()> {
+        match (self.format, change) {
+            (ChangeListFormat::Json, _) => {
+                let mut change = change.clone();
+                match &mut change {
+                    LiveChange::Changed(diff) => diff.normalize_path(),
+                    LiveChange::Reverted(path) => *path = normalize_separators(path),
+                }
+                serde_json::to_writer(&mut self.out, &change)?;
+                writeln!(self.out)?;
+                self.written += 1;
+            }
+            (_, LiveChange::Changed(diff)) => self.write(diff)?,
+            (ChangeListFormat::Text, LiveChange::Reverted(path)) => {
+                let path = normalize_separators(path);
+                match self.style {
+                    LineStyle::Paths => writeln!(self.out, "{}", path.display())?,
+                    _ => writeln!(self.out, "unchanged: {}", path.display())?,
+                }
+                self.written += 1;
+            }
+            (ChangeListFormat::Csv, LiveChange::Reverted(path)) => {
+                if self.written == 0 {
+                    writeln!(self.out, "change,path,from,size,hash")?;
+                }
+                writeln!(self.out, "unchanged,{},,,", csv_field(&normalize_separators(path).to_string_lossy()))?;
+                self.written += 1;
+            }
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> DiffSink for ChangeListWriter<W> {
+    fn on_added(&mut self, info: &FileInfo) -> Result<()> {
+        self.write(&DiffType::Added(info.clone()))
+    }
+
+    fn on_modified(&mut self, diff: &DiffType) -> Result<()> {
+        self.write(diff)
+    }
+
+    fn on_removed(&mut self, path: &Path) -> Result<()> {
+        self.write(&DiffType::Removed(path.to_path_buf()))
+    }
+
+    fn on_renamed(&mut self, rename: &FileRename) -> Result<()> {
+        self.write(&DiffType::Renamed(rename.clone()))
+    }
+
+    fn on_metadata_changed(&mut self, info: &FileInfo) -> Result<()> {
+        self.write(&DiffType::MetadataChanged(info.clone()))
+    }
+
+    fn on_finish(&mut self, _report: &DiffReport) -> Result<()> {
+        match self.format {
+            #[cfg(feature = "serde")]
+            ChangeListFormat::Json => self.out.write_all(if self.written == 0 { b"[]\n" } else { b"\n]\n" })?,
+            // An empty list still gets its header
+            ChangeListFormat::Csv if self.written == 0 => writeln!(self.out, "change,path,from,size,hash")?,
+            _ => {}
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Quote a CSV field if it holds a separator, quote or line break (RFC 4180)
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
@@ -0,0 +1,631 @@
+use crate::diff::{self, DiffOptions, FileInfo, ScanReport};
+use crate::manifest::Manifest;
+use crate::tr;
+use crate::watch::Watcher;
+use crate::{staging, utils};
+use crate::par::*;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long the tree must be quiet before a batch of changes is hashed and saved
+pub const WATCH_SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// Position in a volume's change journal (the NTFS/ReFS USN journal)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalCursor {
+    pub journal_id: u64, // Changes when the journal is deleted and recreated
+    pub next_usn: i64,   // First record written after the scan started
+}
+
+/// Result of an earlier scan of one directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanSnapshot {
+    /// Canonical path of the scanned directory
+    pub root: PathBuf,
+    /// Journal position taken before the scan, if the volume has a change journal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<JournalCursor>,
+    /// Process of a `diffpatch watch` that keeps this snapshot up to date; trusted only
+    /// while the watcher lock of the snapshot is held (see `is_watched`), as the id may
+    /// have been reused by another process since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watcher_pid: Option<u32>,
+    /// Options that decided which files the manifest lists; `None` in snapshots saved
+    /// before they were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<ScanScope>,
+    pub manifest: Manifest,
+}
+
+/// The scan options that decide which files a snapshot lists
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanScope {
+    pub include_hidden: bool,
+    pub follow_symlinks: bool,
+    /// See `DiffOptions::filter_fingerprint`
+    pub filters: String,
+}
+
+impl ScanScope {
+    pub fn of(options: &DiffOptions) -> Self {
+        ScanScope {
+            include_hidden: options.hidden_included(),
+            follow_symlinks: options.symlinks_followed(),
+            filters: options.filter_fingerprint(),
+        }
+    }
+
+    /// Whether a snapshot of this scope lists every file a scan with `wanted` keeps, so
+    /// filtering its entries gives the scan's result
+    ///
+    /// Followed links are hashed as files, so that option must match. Hidden files and
+    /// filters can only be taken away, so the snapshot must have the hidden files if they
+    /// are wanted, and no filters or the same ones.
+    pub fn covers(&self, wanted: &ScanScope) -> bool {
+        self.follow_symlinks == wanted.follow_symlinks
+            && (self.include_hidden || !wanted.include_hidden)
+            && (self.filters.is_empty() || self.filters == wanted.filters)
+    }
+}
+
+impl ScanSnapshot {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self).context("Failed to serialize scan snapshot")?;
+        staging::write_atomic(path, &json).with_context(|| format!("Failed to write scan snapshot: {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read scan snapshot: {}", path.display()))?;
+        let snapshot: ScanSnapshot = serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse scan snapshot: {}", path.display()))?;
+        snapshot.manifest.validate()
+            .with_context(|| format!("Scan snapshot is corrupt: {}", path.display()))?;
+        Ok(snapshot)
+    }
+}
+
+/// File in `snapshot_dir` holding the snapshot of `root` (a canonical path)
+pub fn snapshot_path(snapshot_dir: &Path, root: &Path) -> PathBuf {
+    let digest = Sha256::digest(root.to_string_lossy().as_bytes());
+    let name: String = digest.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+    snapshot_dir.join(format!("{}.json", name))
+}
+
+/// Lock file held by the watcher of the snapshot at `snapshot`
+fn watcher_lock_path(snapshot: &Path) -> PathBuf {
+    snapshot.with_extension("lock")
+}
+
+/// Exclusive lock on the watcher lock file of a snapshot, released on drop or when the
+/// process ends in any way
+#[derive(Debug)]
+pub struct WatcherLock {
+    _file: fs::File,
+}
+
+impl WatcherLock {
+    /// Take the watcher lock of the snapshot at `snapshot`, failing if another watcher has it
+    pub fn acquire(snapshot: &Path) -> Result<Self> {
+        let path = watcher_lock_path(snapshot);
+        let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)
+            .with_context(|| format!("Failed to create lock file: {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(WatcherLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(anyhow!("Another diffpatch watch keeps this snapshot up to date: {}", snapshot.display())),
+            Err(TryLockError::Error(e)) => Err(e).with_context(|| format!("Failed to lock file: {}", path.display())),
+        }
+    }
+}
+
+/// Whether a running watcher holds the lock of the snapshot at `snapshot`
+///
+/// The lock goes with the process, so unlike its process id it cannot outlive a killed
+/// watcher. Where locks cannot be checked the snapshot counts as unwatched and the
+/// directory is scanned.
+pub fn is_watched(snapshot: &Path) -> bool {
+    let Ok(file) = fs::File::open(watcher_lock_path(snapshot)) else {
+        return false;
+    };
+    matches!(file.try_lock_shared(), Err(TryLockError::WouldBlock))
+}
+
+/// Scan `dir`, re-hashing only what changed since the snapshot in `snapshot_dir`
+///
+/// The change journal names the files written since the last scan; files with any of
+/// those names, new paths and files whose size changed are hashed, the rest keep their
+/// previous hash. Without a usable journal (other file systems, missing permissions, or
+/// the journal was reset or wrapped around) every file is hashed. Either way the snapshot
+/// is refreshed for the next run.
+///
+/// While `watch_directory` maintains the snapshot, its manifest is used as is and the
+/// directory is not read at all.
+pub fn scan_with_snapshot(dir: &Path, options: &DiffOptions, snapshot_dir: &Path) -> Result<ScanReport> {
+    let root = dir.canonicalize().with_context(|| format!("Failed to resolve directory: {}", dir.display()))?;
+    let path = snapshot_path(snapshot_dir, &root);
+    // Taken before walking, so changes made during the scan are seen by the next one
+    let cursor = platform::current_cursor(&root);
+
+    let previous = match path.exists().then(|| ScanSnapshot::load(&path)) {
+        Some(Ok(snapshot)) if snapshot.root == root && snapshot.manifest.check_algorithm(options.algorithm()).is_err() => {
+            println!("{}", tr!("snapshot.other_algorithm", old = snapshot.manifest.hash_algorithm, new = options.algorithm()));
+            None
+        }
+        Some(Ok(mut snapshot)) if snapshot.root == root => {
+            // Hashes written by a run with another --digest-encoding
+            snapshot.manifest.reencode(options.encoding())?;
+            Some(snapshot)
+        }
+        Some(Err(e)) => {
+            println!("{}", tr!("snapshot.ignoring", error = format!("{:#}", e)));
+            None
+        }
+        _ => None,
+    };
+    let scope = ScanScope::of(options);
+    if let Some(snapshot) = previous.as_ref().filter(|s| s.watcher_pid.is_some() && is_watched(&path)) {
+        if snapshot.scope.as_ref().is_some_and(|watched| watched.covers(&scope)) {
+            println!("{}", tr!("snapshot.using_watched", path = dir.display()));
+            let mut files = snapshot.manifest.clone().into_file_map();
+            files.retain(|path, info| !options.is_excluded(path) && !options.exceeds_max_file_size(info.size));
+            return Ok(ScanReport { files, errors: Vec::new() });
+        }
+        println!("{}", tr!("snapshot.watched_other_options", path = dir.display()));
+    }
+
+    let changes = previous.and_then(|snapshot| {
+        let names = platform::changed_names(&root, snapshot.cursor?)?;
+        Some((snapshot.manifest.into_file_map(), names))
+    });
+
+    let report = match changes {
+        Some((previous, names)) => {
+            println!("{}", tr!("snapshot.journal", count = names.len(), path = dir.display()));
+            diff::scan_directory_reusing(dir, options, &previous, |path| {
+                path.file_name().is_none_or(|name| names.contains(&name.to_string_lossy().to_lowercase()))
+            })?
+        }
+        None => diff::scan_directory_reusing(dir, options, &HashMap::new(), |_| true)?,
+    };
+
+    fs::create_dir_all(snapshot_dir)
+        .with_context(|| format!("Failed to create snapshot directory: {}", snapshot_dir.display()))?;
+    ScanSnapshot { root, cursor, watcher_pid: None, scope: Some(scope), manifest: Manifest::from_files(&report.files) }.save(&path)?;
+    Ok(report)
+}
+
+/// Keep the snapshot of `dir` in `snapshot_dir` current until Ctrl-C is pressed or the
+/// cancel token of `options` is cancelled
+///
+/// After one full scan, only the files the file system reports changed are hashed again
+/// and the snapshot is saved after every batch of changes. If events were lost the whole
+/// directory is rescanned. Scans with the same snapshot directory use the snapshot
+/// without walking the directory for as long as this runs.
+pub fn watch_directory(dir: &Path, options: &DiffOptions, snapshot_dir: &Path) -> Result<()> {
+    let root = dir.canonicalize().with_context(|| format!("Failed to resolve directory: {}", dir.display()))?;
+    let path = snapshot_path(snapshot_dir, &root);
+    fs::create_dir_all(snapshot_dir)
+        .with_context(|| format!("Failed to create snapshot directory: {}", snapshot_dir.display()))?;
+    // Held until the watcher returns; scans trust the snapshot only while it is
+    let _lock = WatcherLock::acquire(&path)?;
+    let save = |files: &HashMap<PathBuf, FileInfo>, watcher_pid| {
+        // Nothing collects the warnings of a watcher, so print them as they come
+        for warning in options.warnings().take() {
+            println!("{}", warning);
+        }
+        let manifest = Manifest::from_files(files);
+        ScanSnapshot { root: root.clone(), cursor: None, watcher_pid, scope: Some(ScanScope::of(options)), manifest }.save(&path)
+    };
+
+    // Started before the scan, so changes made while scanning are not missed
+    let mut watcher = Watcher::new(&root)?;
+    println!("{}", tr!("snapshot.scanning", path = dir.display()));
+    let mut files = diff::scan_directory_reusing(&root, options, &HashMap::new(), |_| true)?.files;
+    save(&files, Some(std::process::id()))?;
+    println!("{}", tr!("snapshot.watching", path = dir.display(), count = files.len()));
+
+    let mut changed = HashSet::new();
+    let mut rescan = false;
+    while !options.should_stop() {
+        let events = watcher.wait(WATCH_SETTLE_TIME)?;
+        if !events.is_empty() {
+            rescan |= events.overflow;
+            changed.extend(events.paths);
+            continue;
+        }
+        if rescan {
+            println!("{}", tr!("watch.rescanning", path = dir.display()));
+            files = diff::scan_directory_reusing(&root, options, &HashMap::new(), |_| true)?.files;
+            rescan = false;
+            changed.clear();
+        } else if !changed.is_empty() {
+            let update = update_changed(&root, options, &mut files, changed.drain().collect());
+            println!("{}", tr!("snapshot.updated", count = update.count, files = files.len()));
+            // Rescanned once the tree is quiet; files that stay unreadable are then reported
+            rescan = !update.unreadable.is_empty();
+        } else {
+            continue;
+        }
+        // Scans read the directory themselves while entries are out of date
+        save(&files, (!rescan).then(std::process::id))?;
+    }
+
+    // Later scans go back to reading the directory
+    save(&files, None)?;
+    println!("{}", tr!("watch.stopped", path = dir.display()));
+    Ok(())
+}
+
+/// What `update_changed` did with a batch of changed paths
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangedFiles {
+    /// Changed paths under the root
+    pub count: usize,
+    /// Files that could not be hashed, e.g. still locked by the program writing them;
+    /// their previous entries are kept, so they are out of date until a rescan
+    pub unreadable: Vec<PathBuf>,
+}
+
+/// Re-hash or drop the entries of changed absolute `paths`
+pub fn update_changed(root: &Path, options: &DiffOptions, files: &mut HashMap<PathBuf, FileInfo>, paths: Vec<PathBuf>) -> ChangedFiles {
+    let relative: Vec<PathBuf> = paths
+        .iter()
+        .filter_map(|path| path.strip_prefix(root).ok())
+        .filter(|path| !path.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .collect();
+
+    let pool = utils::io_thread_pool(options.thread_count());
+    let updates: Vec<(PathBuf, Option<Result<FileInfo>>)> = pool.install(|| {
+        relative.par_iter().map(|relative_path| {
+            let full_path = root.join(relative_path);
+            let info = match fs::symlink_metadata(&full_path) {
+                Ok(metadata) if metadata.is_file()
+                    && !options.is_excluded(relative_path)
+                    && !options.exceeds_max_file_size(metadata.len()) =>
+                {
+                    Some(options.hash_file(&full_path)
+                        .map(|(hash, content)| FileInfo::new(relative_path.clone(), hash, metadata.len()).with_mode(&metadata).with_content(content)))
+                }
+                _ => None,
+            };
+            (relative_path.clone(), info)
+        }).collect()
+    });
+
+    let mut unreadable = Vec::new();
+    for (relative_path, info) in updates {
+        match info {
+            Some(Ok(info)) => {
+                files.insert(relative_path, info);
+            }
+            Some(Err(_)) => unreadable.push(relative_path),
+            None => {
+                // Deleted, excluded, or a directory that was removed or renamed with its content
+                let is_dir = root.join(&relative_path).is_dir();
+                files.remove(&relative_path);
+                if !is_dir {
+                    files.retain(|path, _| !path.starts_with(&relative_path));
+                }
+            }
+        }
+    }
+    ChangedFiles { count: relative.len(), unreadable }
+}
+
+/// Lowercased file names, since they are compared case-insensitively like on NTFS
+type NameSet = HashSet<String>;
+
+#[cfg(windows)]
+mod platform {
+    use super::{JournalCursor, NameSet};
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    const GENERIC_READ: u32 = 0x8000_0000;
+    const FILE_SHARE_READ: u32 = 0x1;
+    const FILE_SHARE_WRITE: u32 = 0x2;
+    const OPEN_EXISTING: u32 = 3;
+    const INVALID_HANDLE_VALUE: isize = -1;
+    const FSCTL_QUERY_USN_JOURNAL: u32 = 0x0009_00f4;
+    const FSCTL_READ_USN_JOURNAL: u32 = 0x0009_00bb;
+
+    unsafe extern "system" {
+        fn GetVolumePathNameW(file_name: *const u16, volume_path: *mut u16, length: u32) -> i32;
+        fn CreateFileW(
+            name: *const u16,
+            access: u32,
+            share_mode: u32,
+            security: *mut c_void,
+            disposition: u32,
+            flags: u32,
+            template: isize,
+        ) -> isize;
+        fn DeviceIoControl(
+            device: isize,
+            code: u32,
+            in_buffer: *const c_void,
+            in_size: u32,
+            out_buffer: *mut c_void,
+            out_size: u32,
+            returned: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+        fn CloseHandle(handle: isize) -> i32;
+    }
+
+    /// USN_JOURNAL_DATA_V0
+    #[repr(C)]
+    #[derive(Default)]
+    struct JournalData {
+        journal_id: u64,
+        first_usn: i64,
+        next_usn: i64,
+        _lowest_valid_usn: i64,
+        _max_usn: i64,
+        _maximum_size: u64,
+        _allocation_delta: u64,
+    }
+
+    /// READ_USN_JOURNAL_DATA_V0, only read by the kernel
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct ReadJournalData {
+        start_usn: i64,
+        reason_mask: u32,
+        return_only_on_close: u32,
+        timeout: u64,
+        bytes_to_wait_for: u64,
+        journal_id: u64,
+    }
+
+    struct Volume(isize);
+
+    impl Drop for Volume {
+        fn drop(&mut self) {
+            // SAFETY: the handle was opened by `open_volume` and is closed once
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+
+    /// Open the volume holding `root` (usually needs administrator rights)
+    fn open_volume(root: &Path) -> Option<Volume> {
+        let wide: Vec<u16> = root.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut volume = [0u16; 261];
+        // SAFETY: both buffers are valid for the lengths passed
+        if unsafe { GetVolumePathNameW(wide.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) } == 0 {
+            return None;
+        }
+        let len = volume.iter().position(|&c| c == 0)?;
+        // `C:\` or `\\?\Volume{...}\` becomes the device path `\\.\C:` or `\\.\Volume{...}`
+        let name = String::from_utf16(&volume[..len]).ok()?;
+        let name = name.trim_end_matches('\\');
+        let name = name.strip_prefix(r"\\?\").unwrap_or(name);
+        let device: Vec<u16> = format!(r"\\.\{}", name).encode_utf16().chain(Some(0)).collect();
+        // SAFETY: `device` is NUL-terminated; the handle is owned by the returned Volume
+        let handle = unsafe {
+            CreateFileW(device.as_ptr(), GENERIC_READ, FILE_SHARE_READ | FILE_SHARE_WRITE, ptr::null_mut(), OPEN_EXISTING, 0, 0)
+        };
+        (handle != INVALID_HANDLE_VALUE).then_some(Volume(handle))
+    }
+
+    fn query_journal(volume: &Volume) -> Option<JournalData> {
+        let mut data = JournalData::default();
+        let mut returned = 0u32;
+        // SAFETY: the output buffer is a JournalData of the size passed
+        let ok = unsafe {
+            DeviceIoControl(
+                volume.0,
+                FSCTL_QUERY_USN_JOURNAL,
+                ptr::null(),
+                0,
+                (&mut data as *mut JournalData).cast(),
+                size_of::<JournalData>() as u32,
+                &mut returned,
+                ptr::null_mut(),
+            )
+        };
+        (ok != 0).then_some(data)
+    }
+
+    pub fn current_cursor(root: &Path) -> Option<JournalCursor> {
+        let data = query_journal(&open_volume(root)?)?;
+        Some(JournalCursor { journal_id: data.journal_id, next_usn: data.next_usn })
+    }
+
+    /// Names of all files changed on the volume since `cursor`
+    ///
+    /// `None` if the journal was recreated or has already discarded records after `cursor`.
+    pub fn changed_names(root: &Path, cursor: JournalCursor) -> Option<NameSet> {
+        let volume = open_volume(root)?;
+        let data = query_journal(&volume)?;
+        if data.journal_id != cursor.journal_id || cursor.next_usn < data.first_usn {
+            return None;
+        }
+
+        let mut names = NameSet::new();
+        let mut request = ReadJournalData {
+            start_usn: cursor.next_usn,
+            reason_mask: u32::MAX,
+            return_only_on_close: 0,
+            timeout: 0,
+            bytes_to_wait_for: 0,
+            journal_id: data.journal_id,
+        };
+        // u64 elements keep the records 8-byte aligned
+        let mut buffer = vec![0u64; 8192];
+        while request.start_usn < data.next_usn {
+            let mut returned = 0u32;
+            // SAFETY: input and output buffers are valid for the sizes passed
+            let ok = unsafe {
+                DeviceIoControl(
+                    volume.0,
+                    FSCTL_READ_USN_JOURNAL,
+                    (&request as *const ReadJournalData).cast(),
+                    size_of::<ReadJournalData>() as u32,
+                    buffer.as_mut_ptr().cast(),
+                    (buffer.len() * 8) as u32,
+                    &mut returned,
+                    ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return None;
+            }
+            // SAFETY: the call wrote `returned` bytes into the buffer
+            let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), returned as usize) };
+            let next_usn = i64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+            read_record_names(&bytes[8..], &mut names)?;
+            if next_usn <= request.start_usn {
+                break;
+            }
+            request.start_usn = next_usn;
+        }
+        Some(names)
+    }
+
+    /// Add the file names of a run of USN_RECORD_V2/V3 records
+    fn read_record_names(mut records: &[u8], names: &mut NameSet) -> Option<()> {
+        let u16_at = |bytes: &[u8], at: usize| Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?));
+        while records.len() >= 8 {
+            let length = u32::from_le_bytes(records[..4].try_into().ok()?) as usize;
+            if length < 8 || length > records.len() {
+                break;
+            }
+            let record = &records[..length];
+            // FileNameLength/FileNameOffset follow the 64-bit (V2) or 128-bit (V3) file ids
+            let name_field = match u16_at(record, 4)? {
+                2 => Some(56),
+                3 => Some(72),
+                _ => None,
+            };
+            if let Some(at) = name_field {
+                let name_len = u16_at(record, at)? as usize;
+                let name_offset = u16_at(record, at + 2)? as usize;
+                let name = record.get(name_offset..name_offset + name_len)?;
+                let wide: Vec<u16> = name.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+                names.insert(String::from_utf16_lossy(&wide).to_lowercase());
+            }
+            records = &records[length..];
+        }
+        Some(())
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use super::{JournalCursor, NameSet};
+    use std::path::Path;
+
+    pub fn current_cursor(_root: &Path) -> Option<JournalCursor> {
+        None
+    }
+
+    pub fn changed_names(_root: &Path, _cursor: JournalCursor) -> Option<NameSet> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// Save an empty snapshot of `dir` in `snapshot_dir` as a watcher with a running process
+    /// id and default options
+    fn save_watched(dir: &Path, snapshot_dir: &Path) -> PathBuf {
+        save_watched_with(dir, snapshot_dir, Some(ScanScope::of(&DiffOptions::new())))
+    }
+
+    fn save_watched_with(dir: &Path, snapshot_dir: &Path, scope: Option<ScanScope>) -> PathBuf {
+        let root = dir.canonicalize().unwrap();
+        let path = snapshot_path(snapshot_dir, &root);
+        let manifest = Manifest::from_files(&HashMap::new());
+        let snapshot = ScanSnapshot { root, cursor: None, watcher_pid: Some(std::process::id()), scope, manifest };
+        snapshot.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn watched_snapshot_is_only_trusted_under_its_lock() {
+        let (tree, snapshots) = (TempDir::new(), TempDir::new());
+        tree.write("a.txt", b"a");
+        let options = DiffOptions::new();
+
+        // The recorded process is running, but holds no lock (e.g. a reused id)
+        save_watched(tree.path(), snapshots.path());
+        let report = scan_with_snapshot(tree.path(), &options, snapshots.path()).unwrap();
+        assert_eq!(report.files.len(), 1);
+
+        let path = save_watched(tree.path(), snapshots.path());
+        let lock = WatcherLock::acquire(&path).unwrap();
+        assert!(is_watched(&path));
+        let report = scan_with_snapshot(tree.path(), &options, snapshots.path()).unwrap();
+        assert!(report.files.is_empty());
+
+        drop(lock);
+        assert!(!is_watched(&path));
+    }
+
+    #[test]
+    fn watched_snapshot_is_only_used_with_options_it_covers() {
+        let (tree, snapshots) = (TempDir::new(), TempDir::new());
+        tree.write("a.txt", b"a");
+        tree.write(".hidden", b"h");
+        let path = save_watched(tree.path(), snapshots.path());
+        let _lock = WatcherLock::acquire(&path).unwrap();
+        let scan = |options: &DiffOptions| scan_with_snapshot(tree.path(), options, snapshots.path()).unwrap().files.len();
+
+        // Filters only take files away from the unfiltered snapshot
+        assert_eq!(scan(&DiffOptions::new().exclude_extensions(vec![".log".to_string()])), 0);
+        // Hidden files and followed links are not in it, so the directory is read
+        assert_eq!(scan(&DiffOptions::new().include_hidden(true)), 2);
+        // Reading the directory saved an unwatched snapshot
+        save_watched(tree.path(), snapshots.path());
+        assert_eq!(scan(&DiffOptions::new().follow_symlinks(true)), 1);
+        // Snapshots that do not say how they were made are not trusted either
+        save_watched_with(tree.path(), snapshots.path(), None);
+        assert_eq!(scan(&DiffOptions::new()), 1);
+        assert!(is_watched(&path));
+    }
+
+    #[test]
+    fn scopes_cover_narrower_scans() {
+        let unfiltered = ScanScope::of(&DiffOptions::new());
+        let filtered = ScanScope::of(&DiffOptions::new().max_file_size(10));
+        let hidden = ScanScope::of(&DiffOptions::new().include_hidden(true));
+        assert!(unfiltered.covers(&filtered) && !filtered.covers(&unfiltered));
+        assert!(filtered.covers(&filtered));
+        assert!(!filtered.covers(&ScanScope::of(&DiffOptions::new().max_file_size(20))));
+        assert!(hidden.covers(&unfiltered) && !unfiltered.covers(&hidden));
+        assert!(!unfiltered.covers(&ScanScope::of(&DiffOptions::new().follow_symlinks(true))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn files_that_fail_to_hash_keep_their_entries() {
+        // A regular file whose reads fail (EIO at offset 0), even for root
+        let root = Path::new("/proc/self");
+        let old = FileInfo::new("mem".into(), "00".repeat(32), 0);
+        let mut files = HashMap::from([(PathBuf::from("mem"), old.clone())]);
+        let update = update_changed(root, &DiffOptions::new(), &mut files, vec![root.join("mem")]);
+        assert_eq!(update, ChangedFiles { count: 1, unreadable: vec![PathBuf::from("mem")] });
+        assert_eq!(files[Path::new("mem")], old);
+    }
+
+    #[test]
+    fn one_watcher_per_snapshot() {
+        let snapshots = TempDir::new();
+        let path = snapshots.path().join("snapshot.json");
+        let _lock = WatcherLock::acquire(&path).unwrap();
+        assert!(WatcherLock::acquire(&path).is_err());
+    }
+}
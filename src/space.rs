@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+/// Space left free on top of the computed peak, for directories, the journal and file
+/// system overhead
+pub const SPACE_RESERVE: u64 = 16 * 1024 * 1024;
+
+/// One file an apply writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedWrite {
+    pub path: PathBuf,
+    pub new_size: u64,
+    pub old_size: u64, // Size of the file it replaces, 0 if there is none
+}
+
+impl PlannedWrite {
+    /// How much the destination grows (negative: shrinks) once the file is in place
+    fn growth(&self) -> i128 {
+        self.new_size as i128 - self.old_size as i128
+    }
+}
+
+/// Peak extra disk usage of the two ways to apply a patch
+#[derive(Debug, Clone)]
+pub struct SpacePlan {
+    /// Extract the whole archive to a temporary directory, copy the files into place,
+    /// then remove files
+    pub extract_first: u64,
+    /// Remove files first, then write one file at a time next to the file it replaces
+    /// (in-place applies only)
+    pub file_by_file: u64,
+    /// Order of the writes that gives `file_by_file`: files that shrink the tree first
+    pub order: Vec<PlannedWrite>,
+}
+
+impl SpacePlan {
+    /// Plan `writes`, where the archive extracts to `extracted` bytes and removed files
+    /// free `freed` bytes
+    pub fn new(writes: Vec<PlannedWrite>, extracted: u64, freed: u64) -> Self {
+        // Copies may end up as full copies; removals only happen at the end
+        let growth: u64 = writes.iter().map(|w| w.new_size.saturating_sub(w.old_size)).sum();
+        let extract_first = extracted.saturating_add(growth);
+
+        let mut order = writes;
+        order.sort_by(|a, b| a.growth().cmp(&b.growth()).then(a.new_size.cmp(&b.new_size)).then(a.path.cmp(&b.path)));
+        // While a file is written, the old one still exists next to it
+        let mut used = -(freed as i128);
+        let mut peak = 0i128;
+        for write in &order {
+            peak = peak.max(used + write.new_size as i128);
+            used += write.growth();
+        }
+
+        SpacePlan { extract_first, file_by_file: peak.max(0) as u64, order }
+    }
+}
+
+/// Free space available to this user on the volume holding `path`
+///
+/// A missing `path` is looked up through its nearest existing parent. `None` if the
+/// platform cannot tell.
+pub fn available_space(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|p| p.exists() && !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    platform::available_space(existing)
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub fn available_space(path: &Path) -> Option<u64> {
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        // SAFETY: `path` is NUL-terminated and statvfs only writes to the struct passed in
+        let stat = unsafe {
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+                return None;
+            }
+            stat
+        };
+        #[allow(clippy::unnecessary_cast)] // The field types differ between platforms
+        Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    unsafe extern "system" {
+        fn GetDiskFreeSpaceExW(directory: *const u16, available: *mut u64, total: *mut u64, free: *mut u64) -> i32;
+    }
+
+    pub fn available_space(path: &Path) -> Option<u64> {
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut available = 0u64;
+        // SAFETY: `wide` is NUL-terminated; unused outputs may be null
+        let ok = unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, ptr::null_mut(), ptr::null_mut()) };
+        (ok != 0).then_some(available)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use std::path::Path;
+
+    pub fn available_space(_path: &Path) -> Option<u64> {
+        None
+    }
+}
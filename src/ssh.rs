@@ -0,0 +1,289 @@
+use crate::diff::HashAlgorithm;
+use crate::digest;
+use crate::fsops::FileMode;
+use crate::vfs::{Vfs, VfsMetadata};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Output, Stdio};
+use std::time::{Duration, SystemTime};
+
+/// Program used for SSH connections, unless `DIFFPATCH_SSH` names another
+pub const SSH_VAR: &str = "DIFFPATCH_SSH";
+
+/// Seconds to wait for a connection before giving up
+const CONNECT_TIMEOUT_SECS: u32 = 30;
+
+/// Lists the regular files below the directory `$1`: size, modification time, octal
+/// permissions and path relative to it, separated by NUL (needs the `find` of GNU findutils)
+const LIST_SCRIPT: &str = r#"cd -- "$1" && find . -type f -printf '%s %T@ %m %P\0'"#;
+
+/// Prints one line per NUL-separated path on stdin (relative to `$1`): the digest `$2`
+/// prints for its content, or `-` if it cannot be read. Exits with 127 if there is no `$2`.
+const HASH_SCRIPT: &str = r#"cd -- "$1" || exit 1
+command -v "$2" >/dev/null 2>&1 || exit 127
+xargs -0 sh -c 'for f; do h=$("$0" < "$f" 2>/dev/null) || h=-; echo "${h%% *}"; done' "$2""#;
+
+/// Writes the content of the file `$2` below the directory `$1`
+const READ_SCRIPT: &str = r#"cd -- "$1" && exec cat -- "$2""#;
+
+/// Whether `source` is an `sftp://` or `ssh://` URL of a remote directory
+pub fn is_ssh_url(source: &Path) -> bool {
+    source.to_str().is_some_and(|source| ["sftp://", "ssh://"].iter().any(|scheme| has_scheme(source, scheme)))
+}
+
+fn has_scheme(url: &str, scheme: &str) -> bool {
+    url.get(..scheme.len()).is_some_and(|start| start.eq_ignore_ascii_case(scheme))
+}
+
+/// A directory on another machine, `sftp://[USER@]HOST[:PORT]/PATH` (or `ssh://`)
+///
+/// The path is absolute; `/~/PATH` is relative to the home directory of the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshLocation {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    pub dir: String,
+}
+
+impl SshLocation {
+    /// Parse an SFTP or SSH URL; `None` if `url` is neither (a local path)
+    pub fn parse(url: &str) -> Result<Option<Self>> {
+        let Some(rest) = ["sftp://", "ssh://"].iter().find(|scheme| has_scheme(url, scheme)).map(|scheme| &url[scheme.len()..]) else {
+            return Ok(None);
+        };
+        let (authority, dir) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        let (host, port) = match host.rsplit_once(':').filter(|(host, _)| !host.ends_with(']')) {
+            Some((host, port)) => {
+                let port = port.parse().map_err(|_| anyhow!("Invalid port in {}: {}", url, port))?;
+                (host, Some(port))
+            }
+            None => (host, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.starts_with('-') || user.as_deref().is_some_and(|user| user.is_empty() || user.starts_with('-')) {
+            return Err(anyhow!("Invalid host in {}: use sftp://[USER@]HOST[:PORT]/PATH", url));
+        }
+        let dir = match dir.strip_prefix("/~") {
+            Some("") => ".".to_string(),
+            Some(home) if home.starts_with('/') => home[1..].to_string(),
+            _ if dir.is_empty() => "/".to_string(),
+            _ => dir.to_string(),
+        };
+        Ok(Some(SshLocation { user, host: host.to_string(), port, dir }))
+    }
+
+    /// Run `script` with `sh` on the remote machine, with the directory as `$1` followed
+    /// by `args`
+    fn command(&self, script: &str, args: &[&str]) -> Command {
+        let ssh = std::env::var_os(SSH_VAR).filter(|program| !program.is_empty()).unwrap_or_else(|| "ssh".into());
+        let mut command = Command::new(ssh);
+        // Never ask for a password or host key confirmation, which nobody would see
+        command.args(["-o", "BatchMode=yes", "-o"]).arg(format!("ConnectTimeout={}", CONNECT_TIMEOUT_SECS));
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(user) = &self.user {
+            command.arg("-l").arg(user);
+        }
+        // ssh hands the command to the login shell of the user as a single line
+        let remote = ["sh", "-c", script, "sh", &self.dir].iter().chain(args).map(|arg| quote(arg)).collect::<Vec<_>>().join(" ");
+        command.arg("--").arg(&self.host).arg(remote);
+        command
+    }
+
+    /// Run `script` to completion, feeding it `input`, and return its output; fails if
+    /// ssh cannot connect or the script fails
+    fn run(&self, script: &str, args: &[&str], input: Vec<u8>) -> Result<Output> {
+        let mut child = spawn(self.command(script, args).stdin(Stdio::piped()))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // Written from another thread, as the output may fill its pipe before all of
+        // the input is read
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output().context("Failed to run ssh")?;
+        let written = writer.join().unwrap_or(Ok(()));
+        if output.status.code() == Some(255) || (!output.status.success() && output.status.code() != Some(127)) {
+            return Err(anyhow!("{}", error_message(&output.stderr)));
+        }
+        written.context("Failed to pass input to ssh")?;
+        Ok(output)
+    }
+}
+
+impl fmt::Display for SshLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sftp://")?;
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        match self.host.contains(':') {
+            true => write!(f, "[{}]", self.host)?,
+            false => write!(f, "{}", self.host)?,
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        match self.dir.strip_prefix('/') {
+            Some(_) => write!(f, "{}", self.dir),
+            None if self.dir == "." => write!(f, "/~"),
+            None => write!(f, "/~/{}", self.dir),
+        }
+    }
+}
+
+/// A directory tree on another machine, reached with the `ssh` program
+///
+/// The files are listed once, when the tree is opened. Where the machine has a tool for
+/// the hash algorithm (`sha256sum`, or `b3sum` for BLAKE3), files are hashed there and
+/// only their digests are transferred; otherwise each file is read over its own
+/// connection. Authentication is left to ssh (keys, agent, `~/.ssh/config`), as
+/// connections cannot ask for a password. Symbolic links are not listed.
+#[derive(Debug)]
+pub struct SshFs {
+    location: SshLocation,
+    files: HashMap<PathBuf, VfsMetadata>,
+}
+
+impl SshFs {
+    /// Connect to the directory at `url` and list its files
+    pub fn open(url: &str) -> Result<Self> {
+        let location = SshLocation::parse(url)?.ok_or_else(|| anyhow!("Not an sftp:// or ssh:// URL: {}", url))?;
+        let output = location.run(LIST_SCRIPT, &[], Vec::new()).with_context(|| format!("Failed to list {}", location))?;
+        if !output.status.success() {
+            return Err(anyhow!("Failed to list {}: {}", location, error_message(&output.stderr)));
+        }
+        let mut files = HashMap::new();
+        for record in output.stdout.split(|&byte| byte == 0).filter(|record| !record.is_empty()) {
+            let record = String::from_utf8_lossy(record);
+            let mut fields = record.splitn(4, ' ');
+            let (Some(size), Some(modified), Some(mode), Some(path)) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+                return Err(anyhow!("Unexpected file list from {}: {}", location, record));
+            };
+            let size = size.parse().map_err(|_| anyhow!("Unexpected file list from {}: {}", location, record))?;
+            let mode = u32::from_str_radix(mode, 8).ok().map(|mode| FileMode::Unix(mode & 0o7777));
+            files.insert(PathBuf::from(path), VfsMetadata { size, modified: parse_time(modified), mode });
+        }
+        Ok(SshFs { location, files })
+    }
+
+    pub fn location(&self) -> &SshLocation {
+        &self.location
+    }
+
+    fn check_listed(&self, path: &Path) -> Result<&VfsMetadata> {
+        self.files.get(path).ok_or_else(|| anyhow!("No such file in {}: {}", self.location, path.display()))
+    }
+}
+
+impl Vfs for SshFs {
+    fn name(&self) -> String {
+        self.location.to_string()
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.files.keys().cloned().collect())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata> {
+        self.check_listed(path).copied()
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + '_>> {
+        self.check_listed(path)?;
+        let file = path.to_string_lossy();
+        let mut child = spawn(self.location.command(READ_SCRIPT, &[&file]).stdin(Stdio::null()))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Box::new(ChildReader { child, stdout }))
+    }
+
+    fn digests(&self, paths: &[PathBuf], algorithm: HashAlgorithm) -> Result<Option<Vec<Option<Vec<u8>>>>> {
+        let tool = match algorithm {
+            HashAlgorithm::Sha256 => "sha256sum",
+            HashAlgorithm::Blake3 => "b3sum",
+            HashAlgorithm::Xxh3 => return Ok(None),
+        };
+        let input = paths.iter().flat_map(|path| [path.to_string_lossy().as_bytes(), b"\0"].concat()).collect();
+        let output = self.location.run(HASH_SCRIPT, &[tool], input).with_context(|| format!("Failed to hash the files of {}", self.location))?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let unexpected = || anyhow!("Unexpected output of {} on {}", tool, self.location.host);
+        let lines = String::from_utf8(output.stdout).map_err(|_| unexpected())?;
+        let digests = lines.lines().map(|line| match line {
+            "-" => Ok(None),
+            hex => digest::decode_hex(hex).filter(|digest| digest.len() == algorithm.digest_len()).map(Some).ok_or_else(unexpected),
+        }).collect::<Result<Vec<_>>>()?;
+        if digests.len() != paths.len() {
+            return Err(unexpected());
+        }
+        Ok(Some(digests))
+    }
+}
+
+/// Content of a remote file as the ssh process writes it; fails at the end if the file
+/// could not be read
+struct ChildReader {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let mut stderr = Vec::new();
+            if let Some(mut pipe) = self.child.stderr.take() {
+                pipe.read_to_end(&mut stderr)?;
+            }
+            if !self.child.wait()?.success() {
+                return Err(io::Error::other(error_message(&stderr)));
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        // A reader dropped early leaves a transfer nobody reads any more
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn spawn(command: &mut Command) -> Result<Child> {
+    match command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(child) => Ok(child),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Err(anyhow!("Reading remote directories needs ssh, which was not found (set {} to its path)", SSH_VAR))
+        }
+        Err(e) => Err(e).context("Failed to run ssh"),
+    }
+}
+
+fn error_message(stderr: &[u8]) -> String {
+    let message = String::from_utf8_lossy(stderr);
+    match message.trim() {
+        "" => "ssh failed".to_string(),
+        message => message.to_string(),
+    }
+}
+
+/// Quote `arg` for a POSIX shell
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Parse a time printed by `find -printf %T@`, e.g. `1700000000.1234567890`
+fn parse_time(text: &str) -> Option<SystemTime> {
+    let (secs, fraction) = text.split_once('.').unwrap_or((text, ""));
+    let nanos = format!("{:0<9}", fraction.get(..9).unwrap_or(fraction)).parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos))
+}
@@ -0,0 +1,233 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name prefix of every temporary path created here, so leftovers are easy to spot
+pub const STAGING_PREFIX: &str = ".diffpatch-";
+
+/// Unique hidden name like `.diffpatch-extract-4711-1a2b3c`
+///
+/// The process id lets `remove_stale` tell leftovers of crashed runs from paths of
+/// runs still in progress.
+fn unique_name(purpose: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{}{}-{}-{:x}{:x}", STAGING_PREFIX, purpose, std::process::id(), count, nanos)
+}
+
+/// Try unique names in `parent` until `create` succeeds on one that did not exist yet
+fn create_unique(parent: &Path, purpose: &str, create: impl Fn(&Path) -> io::Result<()>) -> Result<PathBuf> {
+    for _ in 0..16 {
+        let path = parent.join(unique_name(purpose));
+        match create(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create temporary path in {}", parent.display()));
+            }
+        }
+    }
+    Err(anyhow!("Failed to find an unused temporary name in {}", parent.display()))
+}
+
+/// A temporary directory, removed with its content when dropped
+///
+/// Created inside the directory it is used for, so its files can be renamed, hard-linked
+/// or cloned into place (all of which need the same file system). Dropping also runs
+/// while a panic unwinds, so a failed run leaves nothing behind.
+pub struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    /// Create a new directory inside `parent`; `purpose` becomes part of its name
+    pub fn new_in(parent: &Path, purpose: &str) -> Result<Self> {
+        let path = create_unique(parent, purpose, |path| fs::create_dir(path))?;
+        Ok(StagingDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A temporary file next to `dest` that replaces it on `persist`
+///
+/// Readers of `dest` see either the old or the complete new file, never a partly
+/// written one. The temporary file is removed if dropped without `persist`.
+pub struct StagingFile {
+    path: PathBuf,
+    dest: PathBuf,
+    persisted: bool,
+}
+
+impl StagingFile {
+    pub fn new(dest: &Path) -> Result<Self> {
+        let parent = match dest.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let path = create_unique(parent, "file", |path| File::create_new(path).map(drop))?;
+        Ok(StagingFile { path, dest: dest.to_path_buf(), persisted: false })
+    }
+
+    /// Path to write the new content to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush the file to disk and move it to its destination
+    pub fn persist(mut self) -> Result<()> {
+        File::open(&self.path)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("Failed to flush temporary file: {}", self.path.display()))?;
+        fs::rename(&self.path, &self.dest)
+            .with_context(|| format!("Failed to move temporary file to {}", self.dest.display()))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagingFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A file in the system temporary directory, removed when dropped
+///
+/// For data too large to keep in memory that has no place next to an output, such as
+/// the content archive rebuilt from a converted patch.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create a new empty file; `purpose` becomes part of its name
+    pub fn new(purpose: &str) -> Result<Self> {
+        let path = create_unique(&std::env::temp_dir(), purpose, |path| File::create_new(path).map(drop))?;
+        Ok(TempFile { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Replace `dest` with `data` atomically
+pub fn write_atomic(dest: &Path, data: &[u8]) -> Result<()> {
+    let staged = StagingFile::new(dest)?;
+    fs::write(staged.path(), data)
+        .with_context(|| format!("Failed to write temporary file: {}", staged.path().display()))?;
+    staged.persist()
+}
+
+/// Remove temporary paths in `dir` left behind by runs that were killed or crashed
+///
+/// Only paths whose creating process is no longer running are touched. Returns the
+/// number of paths removed.
+pub fn remove_stale(dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        let Some(pid) = staging_pid(&name.to_string_lossy()) else {
+            continue;
+        };
+        if pid == std::process::id() || is_process_running(pid) {
+            continue;
+        }
+        let path = entry.path();
+        let result = match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => fs::remove_dir_all(&path),
+            _ => fs::remove_file(&path),
+        };
+        if result.is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Whether a process with this id is running (assumed so where that cannot be checked)
+pub fn is_process_running(pid: u32) -> bool {
+    platform::is_running(pid)
+}
+
+/// Process id in a name created by `unique_name`
+fn staging_pid(name: &str) -> Option<u32> {
+    let mut parts = name.strip_prefix(STAGING_PREFIX)?.split('-');
+    let _purpose = parts.next()?;
+    let pid = parts.next()?.parse().ok()?;
+    parts.next()?;
+    parts.next().is_none().then_some(pid)
+}
+
+#[cfg(unix)]
+mod platform {
+    pub fn is_running(pid: u32) -> bool {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // SAFETY: signal 0 only checks whether the process exists
+        let result = unsafe { libc::kill(pid, 0) };
+        // EPERM means it exists but belongs to another user
+        result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const STILL_ACTIVE: u32 = 259;
+
+    unsafe extern "system" {
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> isize;
+        fn GetExitCodeProcess(process: isize, code: *mut u32) -> i32;
+        fn CloseHandle(handle: isize) -> i32;
+    }
+
+    pub fn is_running(pid: u32) -> bool {
+        // SAFETY: the handle is checked and closed again
+        unsafe {
+            let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+            if handle == 0 {
+                return std::io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED);
+            }
+            let mut code = 0;
+            let ok = GetExitCodeProcess(handle, &mut code);
+            CloseHandle(handle);
+            ok != 0 && code == STILL_ACTIVE
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    // Without a way to check, assume the owner is alive and leave the path alone
+    pub fn is_running(_pid: u32) -> bool {
+        true
+    }
+}
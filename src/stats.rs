@@ -0,0 +1,179 @@
+use crate::diff::FileInfo;
+#[cfg(feature = "serde")]
+use anyhow::{Context, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use std::fs::{self, OpenOptions};
+#[cfg(feature = "serde")]
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Statistics file used when none is configured
+pub const STATS_VAR: &str = "DIFFPATCH_STATS";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TREES: Mutex<Vec<TreeSize>> = Mutex::new(Vec::new());
+
+/// The statistics file to keep: an explicit one, else `DIFFPATCH_STATS` if set; `None`
+/// keeps no statistics
+pub fn stats_file(explicit: Option<&Path>) -> Option<PathBuf> {
+    explicit
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(STATS_VAR).filter(|file| !file.is_empty()).map(PathBuf::from))
+}
+
+/// Start collecting the sizes of scanned trees (off by default, so scans cost nothing)
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Number of files and bytes of one scanned tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TreeSize {
+    pub path: PathBuf,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Note the size of a tree a scan of `dir` found, if collecting is enabled
+pub fn record_tree(dir: &Path, files: &HashMap<PathBuf, FileInfo>) {
+    if !is_enabled() {
+        return;
+    }
+    let bytes = files.values().map(|info| info.size).sum();
+    TREES.lock().unwrap().push(TreeSize { path: dir.to_path_buf(), files: files.len(), bytes });
+}
+
+/// Trees recorded so far, in the order their scans finished; clears the list
+pub fn take_trees() -> Vec<TreeSize> {
+    std::mem::take(&mut *TREES.lock().unwrap())
+}
+
+/// One run of a command, as kept in the statistics file
+///
+/// Only counts and durations are kept, on this machine; nothing is sent anywhere.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RunStats {
+    /// Subcommand, e.g. `create`
+    pub command: String,
+    /// See `runid::current`
+    pub run_id: String,
+    /// Seconds since the Unix epoch when the run started
+    pub started_at: u64,
+    pub duration_ms: u64,
+    /// Whether the command succeeded
+    pub success: bool,
+    /// Trees scanned by the run
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub trees: Vec<TreeSize>,
+}
+
+impl RunStats {
+    /// Statistics of a run of `command` started at `started`, with the trees recorded since
+    pub fn finish(command: &str, started: SystemTime, success: bool) -> Self {
+        RunStats {
+            command: command.to_string(),
+            run_id: crate::runid::current().to_string(),
+            started_at: started.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            duration_ms: u64::try_from(started.elapsed().unwrap_or_default().as_millis()).unwrap_or(u64::MAX),
+            success,
+            trees: take_trees(),
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// Files in all scanned trees
+    pub fn files(&self) -> usize {
+        self.trees.iter().map(|tree| tree.files).sum()
+    }
+
+    /// Bytes in all scanned trees
+    pub fn bytes(&self) -> u64 {
+        self.trees.iter().map(|tree| tree.bytes).sum()
+    }
+
+    /// Add the run as one JSON line to the statistics file at `path`
+    #[cfg(feature = "serde")]
+    pub fn append(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        }
+        let mut line = serde_json::to_string(self).context("Failed to serialize run statistics")?;
+        line.push('\n');
+        // A single write of the whole line, so concurrent runs do not interleave
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write statistics: {}", path.display()))
+    }
+}
+
+/// Runs in the statistics file at `path`, oldest first; lines that cannot be read, e.g.
+/// cut off by a crash, are left out
+#[cfg(feature = "serde")]
+pub fn load(path: &Path) -> Result<Vec<RunStats>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read statistics: {}", path.display()))?;
+    Ok(text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+}
+
+/// How the runs of one command changed from the older to the newer half of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trend {
+    pub command: String,
+    pub runs: usize,
+    pub failed: usize,
+    /// Mean duration and tree size of the successful runs in the older half
+    pub earlier: (Duration, u64),
+    /// Mean duration and tree size of the successful runs in the newer half
+    pub later: (Duration, u64),
+}
+
+impl Trend {
+    /// Per command, in order of first use; only successful runs count for the means, and
+    /// a command needs two of them to compare
+    pub fn of_runs(runs: &[RunStats]) -> Vec<Trend> {
+        let mut commands: Vec<&str> = Vec::new();
+        for run in runs {
+            if !commands.contains(&run.command.as_str()) {
+                commands.push(&run.command);
+            }
+        }
+        let mean = |runs: &[&RunStats]| {
+            let count = runs.len().max(1) as u32;
+            let duration = runs.iter().map(|run| run.duration()).sum::<Duration>() / count;
+            let bytes = runs.iter().map(|run| run.bytes()).sum::<u64>() / u64::from(count);
+            (duration, bytes)
+        };
+        commands.into_iter().filter_map(|command| {
+            let all = runs.iter().filter(|run| run.command == command);
+            let succeeded: Vec<&RunStats> = all.clone().filter(|run| run.success).collect();
+            if succeeded.len() < 2 {
+                return None;
+            }
+            let (earlier, later) = succeeded.split_at(succeeded.len() / 2);
+            Some(Trend {
+                command: command.to_string(),
+                runs: all.clone().count(),
+                failed: all.filter(|run| !run.success).count(),
+                earlier: mean(earlier),
+                later: mean(later),
+            })
+        }).collect()
+    }
+}
@@ -0,0 +1,144 @@
+use crate::diff::{DiffReport, DiffType, FileInfo};
+use crate::filter::{FilterRule, RuleKind};
+use crate::sink::{DiffCounter, DiffSink};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// Tags for groups of files, such as `core`, `assets` or `localization`, given by glob rules
+///
+/// A rules file has one rule per line: a `.gitignore`-style pattern, `=`, and the tags
+/// of the files it matches, comma-separated. A file gets the tags of every matching
+/// rule. Blank lines and lines starting with `#` are skipped.
+///
+/// ```text
+/// bin/**         = core
+/// *.dll          = core, native
+/// assets/        = assets
+/// **/locale/*.po = localization
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TagRules {
+    rules: Vec<(FilterRule, Vec<String>)>,
+}
+
+impl TagRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Give the files matching `pattern` (`.gitignore` syntax, without `!`) these tags
+    pub fn add(&mut self, pattern: &str, tags: &[&str]) -> Result<()> {
+        let rule = FilterRule::gitignore(pattern)
+            .filter(|rule| rule.kind == RuleKind::Exclude)
+            .ok_or_else(|| anyhow!("Invalid tag pattern: {}", pattern))?;
+        let tags = tags.iter().map(|tag| validate_tag(tag.trim()).map(str::to_string)).collect::<Result<Vec<_>>>()?;
+        if tags.is_empty() {
+            return Err(anyhow!("No tags given for pattern: {}", pattern));
+        }
+        self.rules.push((rule, tags));
+        Ok(())
+    }
+
+    /// Read `PATTERN = TAG[, TAG...]` rules from a file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read tag rules: {}", path.display()))?;
+        let mut rules = TagRules::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (pattern, tags) = line
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow!("Invalid line {} in {}: expected PATTERN = TAGS", number + 1, path.display()))?;
+            let tags: Vec<&str> = tags.split(',').filter(|tag| !tag.trim().is_empty()).collect();
+            rules.add(pattern.trim(), &tags).with_context(|| format!("Invalid line {} in {}", number + 1, path.display()))?;
+        }
+        Ok(rules)
+    }
+
+    /// Tags of a file (relative path), sorted and without duplicates
+    pub fn tags_for(&self, relative_path: &Path) -> Vec<String> {
+        let tags: BTreeSet<&String> = self
+            .rules
+            .iter()
+            .filter(|(rule, _)| rule.matches_path(relative_path))
+            .flat_map(|(_, tags)| tags)
+            .collect();
+        tags.into_iter().cloned().collect()
+    }
+
+    /// Set the tags of a scanned file
+    pub fn tag(&self, info: &mut FileInfo) {
+        info.tags = self.tags_for(&info.relative_path);
+    }
+
+    /// Whether a difference concerns a file with one of `tags`; a rename counts with the
+    /// tags of both its old and new path
+    pub fn has_any_tag(&self, diff: &DiffType, tags: &[String]) -> bool {
+        let matches = |path: &Path| self.tags_for(path).iter().any(|tag| tags.contains(tag));
+        match diff {
+            DiffType::Renamed(rename) => matches(&rename.from) || matches(&rename.to.relative_path),
+            diff => matches(diff.path()),
+        }
+    }
+
+    /// Number of differences of each kind per tag, and for the files without a tag
+    ///
+    /// A difference is counted once for each of its tags.
+    pub fn summarize(&self, report: &DiffReport) -> TagSummary {
+        let mut summary = TagSummary::default();
+        for diff in &report.diffs {
+            let tags = self.tags_for(diff.path());
+            if tags.is_empty() {
+                count(&mut summary.untagged, diff);
+            }
+            for tag in tags {
+                count(summary.by_tag.entry(tag).or_default(), diff);
+            }
+        }
+        summary
+    }
+
+    /// Keep only the differences with one of `tags` (see `has_any_tag`)
+    ///
+    /// The target manifest of the report no longer describes the result of applying it,
+    /// so it is dropped.
+    pub fn retain_tagged(&self, report: &mut DiffReport, tags: &[String]) {
+        report.diffs.retain(|diff| self.has_any_tag(diff, tags));
+        report.archives.retain(|archive| self.tags_for(&archive.path).iter().any(|tag| tags.contains(tag)));
+        report.target_manifest = None;
+    }
+}
+
+/// Differences of a report counted per tag, see `TagRules::summarize`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagSummary {
+    pub by_tag: BTreeMap<String, DiffCounter>,
+    pub untagged: DiffCounter,
+}
+
+fn count(counter: &mut DiffCounter, diff: &DiffType) {
+    // Counting cannot fail
+    let _ = match diff {
+        DiffType::Added(info) => counter.on_added(info),
+        DiffType::Modified(_) | DiffType::ModifiedDiff(_) => counter.on_modified(diff),
+        DiffType::Removed(path) => counter.on_removed(path),
+        DiffType::Renamed(rename) => counter.on_renamed(rename),
+        DiffType::MetadataChanged(info) => counter.on_metadata_changed(info),
+    };
+}
+
+/// Check that a tag is a plain name: letters, digits, `-`, `_` and `.`
+pub fn validate_tag(tag: &str) -> Result<&str> {
+    if tag.is_empty() || !tag.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(anyhow!("Invalid tag: `{}` (use letters, digits, `-`, `_` and `.`)", tag));
+    }
+    Ok(tag)
+}
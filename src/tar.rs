@@ -0,0 +1,333 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::{self, Read, Write};
+
+/// Minimal writer of POSIX (ustar) tar archives, with GNU long names for paths over 100
+/// bytes
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+/// Content of an entry to append
+pub enum TarEntry<'a> {
+    /// Regular file with its size
+    File(&'a mut dyn Read, u64),
+    /// Symbolic link to the given target
+    Symlink(&'a str),
+}
+
+const TAR_BLOCK: usize = 512;
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> Self {
+        TarWriter { out }
+    }
+
+    pub fn append(&mut self, name: &str, entry: TarEntry, mode: u32, mtime: u64) -> Result<()> {
+        let link = match &entry {
+            TarEntry::Symlink(target) => *target,
+            TarEntry::File(..) => "",
+        };
+        if name.len() > 100 {
+            self.long_name(b'L', name)?;
+        }
+        if link.len() > 100 {
+            self.long_name(b'K', link)?;
+        }
+        match entry {
+            TarEntry::File(reader, size) => {
+                self.header(name, b'0', "", size, mode, mtime)?;
+                let copied = io::copy(&mut reader.take(size), &mut self.out)?;
+                if copied != size {
+                    return Err(anyhow!("File changed while it was added to the archive: {}", name));
+                }
+                self.pad(size)?;
+            }
+            TarEntry::Symlink(target) => self.header(name, b'2', target, 0, mode, mtime)?,
+        }
+        Ok(())
+    }
+
+    /// GNU `././@LongLink` entry carrying a name or link target too long for the header
+    fn long_name(&mut self, kind: u8, name: &str) -> Result<()> {
+        let data = [name.as_bytes(), b"\0"].concat();
+        self.header("././@LongLink", kind, "", data.len() as u64, 0o644, 0)?;
+        self.out.write_all(&data)?;
+        self.pad(data.len() as u64)
+    }
+
+    fn header(&mut self, name: &str, kind: u8, link: &str, size: u64, mode: u32, mtime: u64) -> Result<()> {
+        let mut header = [0u8; TAR_BLOCK];
+        let field = |header: &mut [u8; TAR_BLOCK], offset: usize, len: usize, value: &[u8]| {
+            let n = value.len().min(len);
+            header[offset..offset + n].copy_from_slice(&value[..n]);
+        };
+        let octal = |header: &mut [u8; TAR_BLOCK], offset: usize, len: usize, value: u64| {
+            let text = format!("{:0width$o}", value, width = len - 1);
+            field(header, offset, len - 1, text.as_bytes());
+        };
+        field(&mut header, 0, 100, name.as_bytes());
+        octal(&mut header, 100, 8, u64::from(mode & 0o7777));
+        octal(&mut header, 108, 8, 0);
+        octal(&mut header, 116, 8, 0);
+        if size >= 1 << 33 {
+            // Base-256 size for files of 8 GiB and more (GNU extension)
+            header[124] = 0x80;
+            header[128..136].copy_from_slice(&size.to_be_bytes());
+        } else {
+            octal(&mut header, 124, 12, size);
+        }
+        octal(&mut header, 136, 12, mtime);
+        header[156] = kind;
+        field(&mut header, 157, 100, link.as_bytes());
+        field(&mut header, 257, 8, b"ustar\x0000");
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        field(&mut header, 148, 8, format!("{:06o}\0 ", checksum).as_bytes());
+        self.out.write_all(&header)?;
+        Ok(())
+    }
+
+    fn pad(&mut self, size: u64) -> Result<()> {
+        let rest = (size % TAR_BLOCK as u64) as usize;
+        if rest != 0 {
+            self.out.write_all(&[0u8; TAR_BLOCK][rest..])?;
+        }
+        Ok(())
+    }
+
+    /// Write the two empty blocks that end an archive
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(&[0u8; TAR_BLOCK * 2])?;
+        Ok(self.out)
+    }
+}
+
+/// Kind of a tar entry, from its type flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarKind {
+    File,
+    Directory,
+    Symlink,
+    /// Hard links, devices, FIFOs and unknown types
+    Other,
+}
+
+/// Header of an entry read by `TarReader`
+#[derive(Debug, Clone)]
+pub struct TarHeader {
+    pub name: String,
+    pub kind: TarKind,
+    pub size: u64,
+    pub mode: u32,
+    /// Target of a symbolic link, else empty
+    pub link: String,
+}
+
+/// Minimal reader of tar archives: ustar and GNU ones, with GNU long names and the `path`
+/// and `linkpath` records of POSIX extended headers
+pub struct TarReader<R: Read> {
+    input: R,
+    /// Bytes of the current entry not read yet, and the padding after them
+    remaining: u64,
+    padding: u64,
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(input: R) -> Self {
+        TarReader { input, remaining: 0, padding: 0 }
+    }
+
+    /// Header of the next entry; `None` at the end of the archive
+    pub fn next_entry(&mut self) -> Result<Option<TarHeader>> {
+        io::copy(&mut (&mut self.input).take(self.remaining + self.padding), &mut io::sink()).context("Failed to read tar archive")?;
+        self.remaining = 0;
+        self.padding = 0;
+        let (mut long_name, mut long_link) = (None, None);
+        loop {
+            let mut header = [0u8; TAR_BLOCK];
+            match self.input.read_exact(&mut header) {
+                Ok(()) => {}
+                // Some writers leave out the end blocks
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e).context("Failed to read tar archive"),
+            }
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            // The checksum is taken with its own field as spaces
+            let checksum = header.iter().enumerate()
+                .map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+                .sum::<u64>();
+            if parse_octal(&header[148..156])? != checksum {
+                return Err(anyhow!("Invalid tar archive: header checksum mismatch"));
+            }
+            let size = parse_size(&header[124..136])?;
+            self.remaining = size;
+            self.padding = (TAR_BLOCK as u64 - size % TAR_BLOCK as u64) % TAR_BLOCK as u64;
+            match header[156] {
+                b'L' => long_name = Some(self.read_text()?),
+                b'K' => long_link = Some(self.read_text()?),
+                b'x' => {
+                    for (key, value) in parse_pax(&self.read_data()?)? {
+                        match key.as_str() {
+                            "path" => long_name = Some(value),
+                            "linkpath" => long_link = Some(value),
+                            _ => {}
+                        }
+                    }
+                }
+                b'g' => {
+                    self.read_data()?;
+                }
+                kind => {
+                    let mut name = long_name.unwrap_or_else(|| {
+                        let name = text_field(&header[0..100]);
+                        // ustar keeps the start of long paths in the prefix field
+                        let prefix = text_field(&header[345..500]);
+                        if &header[257..262] == b"ustar" && !prefix.is_empty() { format!("{}/{}", prefix, name) } else { name }
+                    });
+                    let kind = match kind {
+                        b'0' | 0 | b'7' => TarKind::File,
+                        b'5' => TarKind::Directory,
+                        b'2' => TarKind::Symlink,
+                        _ => TarKind::Other,
+                    };
+                    if kind == TarKind::Directory && !name.ends_with('/') {
+                        name.push('/');
+                    }
+                    let link = long_link.unwrap_or_else(|| text_field(&header[157..257]));
+                    let mode = parse_octal(&header[100..108])? as u32;
+                    return Ok(Some(TarHeader { name, kind, size, mode, link }));
+                }
+            }
+        }
+    }
+
+    /// Content of the current entry
+    pub fn read_data(&mut self) -> Result<Vec<u8>> {
+        if usize::try_from(self.remaining).is_err() {
+            return Err(anyhow!("Tar entry of {} bytes is too large to load on this platform", self.remaining));
+        }
+        let mut data = Vec::new();
+        self.copy_data(&mut data)?;
+        Ok(data)
+    }
+
+    /// Write the content of the current entry to `out`, without holding it in memory;
+    /// returns its size
+    pub fn copy_data<W: Write>(&mut self, out: &mut W) -> Result<u64> {
+        let copied = io::copy(&mut (&mut self.input).take(self.remaining), out).context("Failed to read tar archive")?;
+        if copied != self.remaining {
+            return Err(anyhow!("Invalid tar archive: entry cut short"));
+        }
+        // Long name and extended header records are followed by the next header at once
+        io::copy(&mut (&mut self.input).take(self.padding), &mut io::sink()).context("Failed to read tar archive")?;
+        self.remaining = 0;
+        self.padding = 0;
+        Ok(copied)
+    }
+
+    fn read_text(&mut self) -> Result<String> {
+        let data = self.read_data()?;
+        Ok(text_field(&data))
+    }
+}
+
+/// Text of a NUL-terminated header field
+fn text_field(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let text = text_field(field);
+    let text = text.trim_matches(|c: char| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| anyhow!("Invalid tar archive: bad number in header"))
+}
+
+/// Size field, octal or base-256 (GNU extension for 8 GiB and more)
+fn parse_size(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[4..].iter().fold(0u64, |size, &b| size << 8 | u64::from(b)));
+    }
+    parse_octal(field)
+}
+
+/// Records of a POSIX extended header: `LENGTH KEY=VALUE\n` each
+fn parse_pax(data: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let invalid = || anyhow!("Invalid tar archive: bad extended header");
+        let space = rest.iter().position(|&b| b == b' ').ok_or_else(invalid)?;
+        let length: usize = std::str::from_utf8(&rest[..space]).ok().and_then(|n| n.parse().ok()).ok_or_else(invalid)?;
+        if length <= space + 1 || length > rest.len() {
+            return Err(invalid());
+        }
+        let record = String::from_utf8_lossy(&rest[space + 1..length - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            records.push((key.to_string(), value.to_string()));
+        }
+        rest = &rest[length..];
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_round_trip() {
+        let long_name = format!("{}/file.txt", "directory".repeat(15));
+        let long_target = "../".repeat(40);
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append("a.txt", TarEntry::File(&mut &b"alpha"[..], 5), 0o644, 0).unwrap();
+        tar.append(&long_name, TarEntry::File(&mut &[7u8; 600][..], 600), 0o755, 0).unwrap();
+        tar.append("link", TarEntry::Symlink(&long_target), 0o777, 0).unwrap();
+        tar.append("empty", TarEntry::File(&mut &b""[..], 0), 0o600, 0).unwrap();
+        let archive = tar.finish().unwrap();
+
+        let mut reader = TarReader::new(archive.as_slice());
+        let mut entries = Vec::new();
+        while let Some(header) = reader.next_entry().unwrap() {
+            let data = if header.kind == TarKind::File { reader.read_data().unwrap() } else { Vec::new() };
+            entries.push((header.name, header.kind, header.mode, header.link, data));
+        }
+        assert_eq!(entries, [
+            ("a.txt".to_string(), TarKind::File, 0o644, String::new(), b"alpha".to_vec()),
+            (long_name, TarKind::File, 0o755, String::new(), vec![7u8; 600]),
+            ("link".to_string(), TarKind::Symlink, 0o777, long_target, Vec::new()),
+            ("empty".to_string(), TarKind::File, 0o600, String::new(), Vec::new()),
+        ]);
+    }
+
+    #[test]
+    fn unread_entries_are_skipped() {
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append("first", TarEntry::File(&mut &[1u8; 1000][..], 1000), 0o644, 0).unwrap();
+        tar.append("second", TarEntry::File(&mut &b"2"[..], 1), 0o644, 0).unwrap();
+        let archive = tar.finish().unwrap();
+        let mut reader = TarReader::new(archive.as_slice());
+        assert_eq!(reader.next_entry().unwrap().unwrap().name, "first");
+        assert_eq!(reader.next_entry().unwrap().unwrap().name, "second");
+        assert_eq!(reader.read_data().unwrap(), b"2");
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn short_file_or_archive_is_an_error() {
+        let mut tar = TarWriter::new(Vec::new());
+        assert!(tar.append("short", TarEntry::File(&mut &b"abc"[..], 10), 0o644, 0).is_err());
+
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append("cut", TarEntry::File(&mut &[0u8; 1000][..], 1000), 0o644, 0).unwrap();
+        let archive = tar.finish().unwrap();
+        let mut reader = TarReader::new(&archive[..700]);
+        reader.next_entry().unwrap();
+        assert!(reader.read_data().is_err());
+    }
+}
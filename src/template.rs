@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Variables available in output file name templates
+pub const VARIABLES: &[&str] = &["from", "to", "date", "time", "timestamp"];
+
+/// Values for the variables of an output template, see `expand`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateVars {
+    /// Version (or name) of the source tree
+    pub from: String,
+    /// Version (or name) of the target tree
+    pub to: String,
+    /// Time the artifact is created, written in UTC
+    pub time: SystemTime,
+}
+
+impl TemplateVars {
+    /// Versions taken from the given values, or else from the names of the trees
+    pub fn new(source: &Path, target: &Path, from: Option<&str>, to: Option<&str>) -> Self {
+        TemplateVars {
+            from: from.map_or_else(|| tree_name(source), str::to_string),
+            to: to.map_or_else(|| tree_name(target), str::to_string),
+            time: SystemTime::now(),
+        }
+    }
+
+    fn value(&self, name: &str) -> Option<String> {
+        let secs = self.time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (days, secs_of_day) = (secs / 86400, secs % 86400);
+        Some(match name {
+            "from" => file_name_safe(&self.from),
+            "to" => file_name_safe(&self.to),
+            "date" => {
+                let (year, month, day) = civil_from_days(days as i64);
+                format!("{:04}-{:02}-{:02}", year, month, day)
+            }
+            "time" => format!("{:02}{:02}{:02}", secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60),
+            "timestamp" => secs.to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// Name of a tree: the directory name, or the file name without extension for patches and images
+fn tree_name(path: &Path) -> String {
+    let name = if path.is_file() { path.file_stem() } else { path.file_name() };
+    // `.` and `..` have no name of their own
+    let name = name.map(|name| name.to_os_string()).or_else(|| {
+        path.canonicalize().ok().and_then(|path| path.file_name().map(|name| name.to_os_string()))
+    });
+    name.map_or_else(|| "unknown".to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+/// Replace characters that cannot appear in a file name on Windows or Unix
+fn file_name_safe(value: &str) -> String {
+    value.chars().map(|c| if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() { '_' } else { c }).collect()
+}
+
+/// Year, month and day of a day count since 1970-01-01 (proleptic Gregorian calendar)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Replace `{name}` placeholders in `template`; `{{` and `}}` stand for literal braces
+pub fn expand(template: &str, vars: &TemplateVars) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(index) = rest.find(['{', '}']) {
+        output.push_str(&rest[..index]);
+        let brace = &rest[index..];
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            output.push_str(&brace[..1]);
+            rest = &brace[2..];
+            continue;
+        }
+        if brace.starts_with('}') {
+            return Err(anyhow!("Unmatched `}}` in output template: {}", template));
+        }
+        let end = brace.find('}').ok_or_else(|| anyhow!("Unclosed `{{` in output template: {}", template))?;
+        let name = &brace[1..end];
+        let value = vars.value(name.trim()).ok_or_else(|| {
+            anyhow!("Unknown variable `{{{}}}` in output template (expected one of {})", name, VARIABLES.join(", "))
+        })?;
+        output.push_str(&value);
+        rest = &brace[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Expand the variables in the file name of an output path
+///
+/// Only the file name is expanded: values never add directories to the path.
+pub fn expand_path(path: &Path, vars: &TemplateVars) -> Result<PathBuf> {
+    let Some(name) = path.file_name() else {
+        return Ok(path.to_path_buf());
+    };
+    let name = name.to_string_lossy();
+    if !name.contains(['{', '}']) {
+        return Ok(path.to_path_buf());
+    }
+    Ok(path.with_file_name(expand(&name, vars)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn vars() -> TemplateVars {
+        TemplateVars {
+            from: "1.0".to_string(),
+            to: "release/2.0".to_string(),
+            // 2024-02-29 13:05:09 UTC
+            time: UNIX_EPOCH + Duration::from_secs(1_709_211_909),
+        }
+    }
+
+    #[test]
+    fn variables_are_expanded() {
+        assert_eq!(expand("app-{from}-to-{to}.exe", &vars()).unwrap(), "app-1.0-to-release_2.0.exe");
+        assert_eq!(expand("{date}_{time}_{ timestamp }", &vars()).unwrap(), "2024-02-29_130509_1709211909");
+        assert_eq!(expand("{{from}} {{}}", &vars()).unwrap(), "{from} {}");
+    }
+
+    #[test]
+    fn unknown_variables_and_unbalanced_braces_are_errors() {
+        let error = expand("{version}", &vars()).unwrap_err().to_string();
+        assert!(error.contains("Unknown variable `{version}`"), "{}", error);
+        assert!(expand("{from", &vars()).is_err());
+        assert!(expand("from}", &vars()).is_err());
+    }
+
+    #[test]
+    fn only_the_file_name_is_expanded() {
+        let expanded = expand_path(Path::new("out/{to}/p-{to}.exe"), &vars()).unwrap();
+        assert_eq!(expanded, Path::new("out/{to}/p-release_2.0.exe"));
+        assert_eq!(expand_path(Path::new("out/plain.exe"), &vars()).unwrap(), Path::new("out/plain.exe"));
+    }
+
+    #[test]
+    fn tree_names_come_from_directories_and_file_stems() {
+        let dir = crate::testutil::TempDir::new();
+        let patch = dir.write("v2.patch.exe", b"");
+        let vars = TemplateVars::new(dir.path(), &patch, None, Some("3.0"));
+        assert_eq!(vars.from, dir.path().file_name().unwrap().to_string_lossy());
+        assert_eq!(vars.to, "3.0");
+        assert_eq!(TemplateVars::new(&patch, dir.path(), None, None).from, "v2.patch");
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RECORDED: Mutex<Vec<PhaseTiming>> = Mutex::new(Vec::new());
+
+/// Wall-clock and CPU time of one finished phase
+#[derive(Debug, Clone)]
+pub struct PhaseTiming {
+    pub name: String,
+    pub threads: usize,
+    pub wall: Duration,
+    pub cpu: Option<Duration>, // Process CPU time, if the platform reports it
+}
+
+impl PhaseTiming {
+    /// Average CPU use per thread, from 0 to 1
+    ///
+    /// Close to 1 means the phase was CPU bound; well below 1 means the threads mostly
+    /// waited for I/O.
+    pub fn utilization(&self) -> Option<f64> {
+        let available = self.wall.as_secs_f64() * self.threads.max(1) as f64;
+        self.cpu
+            .filter(|_| available > 0.0)
+            .map(|cpu| (cpu.as_secs_f64() / available).min(1.0))
+    }
+}
+
+/// Start recording phase timings (off by default, so phases cost nothing)
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Phases recorded so far, in the order they finished; clears the list
+pub fn take() -> Vec<PhaseTiming> {
+    std::mem::take(&mut *RECORDED.lock().unwrap())
+}
+
+/// A running phase, recorded when finished (or dropped, e.g. on an early return)
+pub struct Phase {
+    name: String,
+    threads: usize,
+    start: Instant,
+    cpu_start: Option<Duration>,
+    active: bool,
+}
+
+impl Phase {
+    /// Start timing a phase that runs on `threads` threads
+    pub fn start(name: impl Into<String>, threads: usize) -> Self {
+        let active = is_enabled();
+        Phase {
+            name: if active { name.into() } else { String::new() },
+            threads,
+            start: Instant::now(),
+            cpu_start: if active { platform::process_cpu_time() } else { None },
+            active,
+        }
+    }
+
+    pub fn finish(self) {}
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        let wall = self.start.elapsed();
+        let cpu = self.cpu_start.zip(platform::process_cpu_time()).map(|(start, end)| end.saturating_sub(start));
+        RECORDED.lock().unwrap().push(PhaseTiming {
+            name: std::mem::take(&mut self.name),
+            threads: self.threads,
+            wall,
+            cpu,
+        });
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::time::Duration;
+
+    /// User plus system CPU time of all threads of the process
+    pub fn process_cpu_time() -> Option<Duration> {
+        // SAFETY: getrusage only writes to the struct passed in
+        let usage = unsafe {
+            let mut usage: libc::rusage = std::mem::zeroed();
+            if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+                return None;
+            }
+            usage
+        };
+        let to_duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+        Some(to_duration(usage.ru_utime) + to_duration(usage.ru_stime))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::time::Duration;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+
+    unsafe extern "system" {
+        fn GetCurrentProcess() -> isize;
+        fn GetProcessTimes(
+            process: isize,
+            creation: *mut FileTime,
+            exit: *mut FileTime,
+            kernel: *mut FileTime,
+            user: *mut FileTime,
+        ) -> i32;
+    }
+
+    /// User plus kernel CPU time of all threads of the process
+    pub fn process_cpu_time() -> Option<Duration> {
+        let (mut creation, mut exit) = (FileTime::default(), FileTime::default());
+        let (mut kernel, mut user) = (FileTime::default(), FileTime::default());
+        // SAFETY: all pointers refer to live FileTime values
+        let ok = unsafe { GetProcessTimes(GetCurrentProcess(), &mut creation, &mut exit, &mut kernel, &mut user) };
+        if ok == 0 {
+            return None;
+        }
+        // FILETIME counts 100 ns intervals
+        let ticks = |t: FileTime| ((t.high as u64) << 32) | t.low as u64;
+        Some(Duration::from_nanos((ticks(kernel) + ticks(user)) * 100))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use std::time::Duration;
+
+    pub fn process_cpu_time() -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,260 @@
+use crate::utils;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A byte count written like `500MB`, `1.5GiB`, `64K` or `4096`
+///
+/// `KB`, `MB`, `GB` and `TB` are decimal (powers of 1000); `KiB`, `MiB`, `GiB`, `TiB`
+/// and the single letters `K`, `M`, `G`, `T` are binary (powers of 1024), as in rsync.
+/// Units are case-insensitive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (number, unit) = split_number(value.trim());
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "kb" => 1000,
+            "mb" => 1000u64.pow(2),
+            "gb" => 1000u64.pow(3),
+            "tb" => 1000u64.pow(4),
+            "k" | "kib" => 1 << 10,
+            "m" | "mib" => 1 << 20,
+            "g" | "gib" => 1 << 30,
+            "t" | "tib" => 1 << 40,
+            _ => return Err(anyhow!("Invalid size: {} (expected e.g. 4096, 500MB or 1.5GiB)", value)),
+        };
+        // Whole numbers are exact; only fractions go through floating point
+        if !number.contains('.') {
+            let number: u64 = number.parse().map_err(|_| anyhow!("Invalid size: {}", value))?;
+            return number.checked_mul(multiplier).map(ByteSize).ok_or_else(|| anyhow!("Size out of range: {}", value));
+        }
+        let number: f64 = number.parse().map_err(|_| anyhow!("Invalid size: {}", value))?;
+        let bytes = number * multiplier as f64;
+        if !bytes.is_finite() || bytes < 0.0 || bytes > u64::MAX as f64 {
+            return Err(anyhow!("Size out of range: {}", value));
+        }
+        Ok(ByteSize(bytes.round() as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", utils::format_size(self.0))
+    }
+}
+
+/// A transfer rate written like `50MiB/s`, `10MB/s` or `1G` (per second), with the
+/// units of `ByteSize`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rate(pub u64);
+
+impl Rate {
+    pub fn bytes_per_second(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let trimmed = value.trim();
+        let size = trimmed.strip_suffix("/s").or_else(|| trimmed.strip_suffix("/S")).unwrap_or(trimmed);
+        let bytes = size.parse::<ByteSize>().map_err(|_| anyhow!("Invalid rate: {} (expected e.g. 50MiB/s or 10MB/s)", value))?;
+        if bytes.0 == 0 {
+            return Err(anyhow!("Rate must be above zero: {}", value));
+        }
+        Ok(Rate(bytes.0))
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", utils::format_size(self.0))
+    }
+}
+
+/// A percentage written like `40`, `12.5` or `40%`, from 0 to 100
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Percent(pub f64);
+
+impl Percent {
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+impl FromStr for Percent {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let number = value.trim().strip_suffix('%').unwrap_or(value.trim()).trim_end();
+        let percent: f64 = number.parse().map_err(|_| anyhow!("Invalid percentage: {} (expected e.g. 40 or 12.5%)", value))?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(anyhow!("Percentage out of range: {} (expected 0 to 100)", value));
+        }
+        Ok(Percent(percent))
+    }
+}
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+/// A duration written like `7d`, `1h30m`, `90s`, `250ms` or `2` (seconds)
+///
+/// Units are `ms`, `s`, `m`, `h`, `d` and `w`; several can be combined, largest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Span(pub Duration);
+
+impl Span {
+    pub fn duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for Span {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid duration: {} (expected e.g. 90s, 1h30m or 7d)", value);
+        let mut rest = value.trim();
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+        let mut total = Duration::ZERO;
+        while !rest.is_empty() {
+            let (number, tail) = split_number(rest);
+            let unit_len = tail.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(tail.len());
+            let (unit, tail) = tail.split_at(unit_len);
+            let seconds: f64 = match unit.trim() {
+                "ms" => 0.001,
+                "" | "s" => 1.0,
+                "m" => 60.0,
+                "h" => 3600.0,
+                "d" => 86400.0,
+                "w" => 7.0 * 86400.0,
+                _ => return Err(invalid()),
+            };
+            let number: f64 = number.parse().map_err(|_| invalid())?;
+            let part = Duration::try_from_secs_f64(number * seconds).map_err(|_| invalid())?;
+            total = total.checked_add(part).ok_or_else(invalid)?;
+            rest = tail.trim_start();
+        }
+        Ok(Span(total))
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 < Duration::from_secs(1) {
+            write!(f, "{}ms", self.0.as_millis())
+        } else {
+            write!(f, "{}", utils::format_duration(self.0.as_secs_f64()))
+        }
+    }
+}
+
+/// Split a leading decimal number from the rest of `value`
+fn split_number(value: &str) -> (&str, &str) {
+    let end = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    value.split_at(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(value: &str) -> u64 {
+        value.parse::<ByteSize>().unwrap().bytes()
+    }
+
+    #[test]
+    fn sizes_in_decimal_and_binary_units() {
+        assert_eq!(size("4096"), 4096);
+        assert_eq!(size("12 b"), 12);
+        assert_eq!(size("500MB"), 500_000_000);
+        assert_eq!(size("64K"), 64 << 10);
+        assert_eq!(size("1.5GiB"), 3 << 29);
+        assert_eq!(size("2tb"), 2_000_000_000_000);
+        assert_eq!(size(" 1 mib "), 1 << 20);
+    }
+
+    #[test]
+    fn invalid_and_negative_sizes_are_rejected() {
+        for value in ["-1MB", "", "MB", "1.2.3K", "10 parsecs", "1e3"] {
+            assert!(value.parse::<ByteSize>().is_err(), "{}", value);
+        }
+        assert!("99999999999TB".parse::<ByteSize>().is_err());
+        assert!("18446744073709551616".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn whole_sizes_are_exact() {
+        // Above 2^53, where f64 can no longer hold every integer
+        assert_eq!(size("9007199254740993"), 9_007_199_254_740_993);
+        assert_eq!(size("18446744073709551615"), u64::MAX);
+        assert_eq!(size("16777215T"), 16_777_215 << 40);
+        assert_eq!(size("0.5K"), 512);
+    }
+
+    #[test]
+    fn rates_per_second() {
+        let rate = |value: &str| value.parse::<Rate>().unwrap().bytes_per_second();
+        assert_eq!(rate("50MiB/s"), 50 << 20);
+        assert_eq!(rate("10 MB/S"), 10_000_000);
+        assert_eq!(rate("1G"), 1 << 30);
+        for value in ["0/s", "fast", "5MB/h", "/s", ""] {
+            assert!(value.parse::<Rate>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!("40".parse::<Percent>().unwrap().value(), 40.0);
+        assert_eq!("12.5%".parse::<Percent>().unwrap().value(), 12.5);
+        assert!("101%".parse::<Percent>().is_err());
+        assert!("-1".parse::<Percent>().is_err());
+        assert!("half".parse::<Percent>().is_err());
+    }
+
+    #[test]
+    fn durations_combine_units_largest_first() {
+        let span = |value: &str| value.parse::<Span>().unwrap().duration();
+        assert_eq!(span("1h30m"), Duration::from_secs(5400));
+        assert_eq!(span("7d"), Duration::from_secs(7 * 86400));
+        assert_eq!(span("1w 2d"), Duration::from_secs(9 * 86400));
+        assert_eq!(span("250ms"), Duration::from_millis(250));
+        assert_eq!(span("2"), Duration::from_secs(2));
+        assert_eq!(span("1.5s"), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        for value in ["", "-5s", "5y", "h", "1h30x"] {
+            assert!(value.parse::<Span>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Span(Duration::from_millis(250)).to_string(), "250ms");
+        assert_eq!(Percent(12.5).to_string(), "12.5%");
+        assert_eq!(Rate(1 << 20).to_string(), format!("{}/s", utils::format_size(1 << 20)));
+        assert_eq!(ByteSize(1 << 20).to_string(), utils::format_size(1 << 20));
+    }
+}
@@ -1,6 +1,14 @@
-use anyhow::{Context, Result};
-use dialoguer::Confirm;
+#[cfg(feature = "package")]
+use anyhow::Context;
+use anyhow::{anyhow, Result};
+use crate::par::ThreadPool;
+use std::io::Read;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Largest accepted I/O thread count
+pub const MAX_IO_THREADS: usize = 256;
 
 /// Check if path exists, return error if it doesn't
 pub fn check_path_exists(path: &Path, path_type: &str) -> Result<()> {
@@ -21,10 +29,152 @@ pub fn check_is_directory(path: &Path) -> Result<()> {
 }
 
 /// Interactive confirmation
+#[cfg(feature = "package")]
 pub fn confirm_action(message: &str) -> Result<bool> {
-    Confirm::new()
+    dialoguer::Confirm::new()
         .with_prompt(message)
         .default(false)
         .interact()
         .context("Failed to get user confirmation")
-}
\ No newline at end of file
+}
+
+/// Resolve the I/O thread count
+///
+/// An explicit value wins, then the `DIFFPATCH_IO_THREADS` environment variable, then
+/// the CPU count capped at 4 (for I/O bound work more threads tend to hurt). Always 1
+/// without the `parallel` feature.
+#[cfg(feature = "parallel")]
+pub fn io_thread_count(threads: Option<usize>) -> usize {
+    threads
+        .or_else(|| std::env::var("DIFFPATCH_IO_THREADS").ok()?.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| std::cmp::min(num_cpus::get(), 4))
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn io_thread_count(_threads: Option<usize>) -> usize {
+    1
+}
+
+/// Check that an explicitly requested thread count is usable
+pub fn validate_thread_count(threads: usize) -> Result<()> {
+    if threads == 0 || threads > MAX_IO_THREADS {
+        Err(anyhow!("Thread count must be between 1 and {}, got {}", MAX_IO_THREADS, threads))
+    } else {
+        Ok(())
+    }
+}
+
+/// Thread pool for file I/O with the given number of threads
+#[cfg(feature = "parallel")]
+pub fn io_thread_pool(threads: usize) -> ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap())
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn io_thread_pool(_threads: usize) -> ThreadPool {
+    ThreadPool
+}
+
+/// Format a byte count with binary units, e.g. `1.5 GiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Format a duration in seconds as e.g. `2h 05m`, `3m 20s` or `12s`
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// Limit on how fast several threads read together, e.g. to leave disk bandwidth to
+/// other programs while hashing
+///
+/// Clones share the limit. Time spent idle builds up at most a second of reading at
+/// full speed.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    next: Arc<Mutex<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Throttle { bytes_per_second: bytes_per_second.max(1), next: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Count `bytes` as read, sleeping until reading them keeps within the limit
+    pub fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let credit = now.checked_sub(Duration::from_secs(1)).unwrap_or(now);
+            *next = (*next).max(credit) + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+            next.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// `reader`, reading no faster than this limit allows
+    pub fn reader<R: Read>(&self, reader: R) -> ThrottledReader<'_, R> {
+        ThrottledReader { inner: reader, throttle: self }
+    }
+}
+
+/// Reader returned by `Throttle::reader`
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    throttle: &'a Throttle,
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.throttle.consume(read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_spreads_reads_over_time() {
+        let throttle = Throttle::new(10_000);
+        let started = Instant::now();
+        let mut data = Vec::new();
+        throttle.clone().reader(&[7u8; 2_000][..]).read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 2_000);
+        assert!(started.elapsed() >= Duration::from_millis(190));
+        // Idle time only builds up a second of credit
+        std::thread::sleep(Duration::from_millis(1_200));
+        let started = Instant::now();
+        throttle.consume(20_000);
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+}
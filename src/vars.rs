@@ -0,0 +1,242 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Values for the `${NAME}` placeholders in patch paths and hook arguments
+///
+/// Placeholders are resolved when a patch is applied, so one patch can serve installs
+/// whose layout differs slightly per environment. `$$` stands for a literal `$`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyVars {
+    values: BTreeMap<String, String>,
+}
+
+impl ApplyVars {
+    pub fn new() -> Self {
+        ApplyVars::default()
+    }
+
+    /// Read `NAME=VALUE` lines; blank lines and lines starting with `#` are skipped
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read vars file: {}", path.display()))?;
+        let mut vars = ApplyVars::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid line {} in {}: expected NAME=VALUE", number + 1, path.display()))?;
+            let name = name.trim();
+            validate_name(name).with_context(|| format!("Invalid line {} in {}", number + 1, path.display()))?;
+            vars.set(name, value.trim());
+        }
+        Ok(vars)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Take the allowed variables from the process environment, unless already set
+    ///
+    /// Only names on the allowlist are read, so a patch cannot pick up arbitrary
+    /// environment variables (credentials, paths of other tools) into its paths.
+    pub fn add_env(&mut self, allowed: &[String]) -> Result<()> {
+        for name in allowed {
+            validate_name(name)?;
+            if !self.values.contains_key(name)
+                && let Ok(value) = std::env::var(name)
+            {
+                self.set(name, &value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the placeholders in `text`; fails on variables without a value
+    pub fn substitute(&self, text: &str) -> Result<String> {
+        let mut output = String::new();
+        expand(text, |name| {
+            self.get(name).ok_or_else(|| anyhow!("Variable ${{{}}} is not set (in `{}`)", name, text))
+        }, &mut output)?;
+        Ok(output)
+    }
+
+    /// Substitute a relative path, which must stay inside the directory it is relative to
+    pub fn resolve_path(&self, path: &Path) -> Result<PathBuf> {
+        let text = path.to_string_lossy();
+        if !has_placeholders(&text) {
+            return Ok(path.to_path_buf());
+        }
+        let resolved = PathBuf::from(self.substitute(&text)?);
+        let is_relative = resolved.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !is_relative || resolved.as_os_str().is_empty() {
+            return Err(anyhow!("Path {} resolves to {}, which is outside the patched directory", path.display(), resolved.display()));
+        }
+        Ok(resolved)
+    }
+}
+
+/// Whether `text` contains a `${NAME}` placeholder
+pub fn has_placeholders(text: &str) -> bool {
+    !placeholders(text).is_empty()
+}
+
+/// Names of the variables `text` refers to
+pub fn placeholders(text: &str) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    let _ = expand(text, |name| {
+        names.insert(name.to_string());
+        Ok("")
+    }, &mut String::new());
+    names
+}
+
+/// Variable names are letters, digits and `_`, not starting with a digit
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(anyhow!("Invalid variable name: `{}` (letters, digits and _ only)", name));
+    }
+    Ok(())
+}
+
+/// Write `text` to `output` with every placeholder replaced by `value(name)`
+fn expand<'a>(text: &str, mut value: impl FnMut(&str) -> Result<&'a str>, output: &mut String) -> Result<()> {
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        output.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+        } else if let Some(body) = after.strip_prefix('{')
+            && let Some(end) = body.find('}')
+            && validate_name(&body[..end]).is_ok()
+        {
+            output.push_str(value(&body[..end])?);
+            rest = &body[end + 1..];
+        } else {
+            output.push('$');
+            rest = after;
+        }
+    }
+    output.push_str(rest);
+    Ok(())
+}
+
+/// Relative paths of a patch with their placeholders resolved
+///
+/// Paths without placeholders are not stored and map to themselves.
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedPaths {
+    paths: HashMap<PathBuf, PathBuf>,
+}
+
+impl ResolvedPaths {
+    /// Resolve all `paths` up front, so a missing variable stops the apply before any change
+    pub fn new<'a>(vars: &ApplyVars, paths: impl IntoIterator<Item = &'a Path>) -> Result<Self> {
+        let mut resolved = HashMap::new();
+        for path in paths {
+            if has_placeholders(&path.to_string_lossy()) && !resolved.contains_key(path) {
+                resolved.insert(path.to_path_buf(), vars.resolve_path(path)?);
+            }
+        }
+        Ok(ResolvedPaths { paths: resolved })
+    }
+
+    /// Where `path` (as written in the patch) goes
+    pub fn get<'a>(&'a self, path: &'a Path) -> &'a Path {
+        self.paths.get(path).map_or(path, PathBuf::as_path)
+    }
+
+    /// Number of paths that contained placeholders
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn vars(values: &[(&str, &str)]) -> ApplyVars {
+        let mut vars = ApplyVars::new();
+        for (name, value) in values {
+            vars.set(name, value);
+        }
+        vars
+    }
+
+    #[test]
+    fn placeholders_and_escapes() {
+        let vars = vars(&[("GAME_DIR", "game"), ("LANG", "en")]);
+        assert_eq!(vars.substitute("${GAME_DIR}/lang/${LANG}.pak").unwrap(), "game/lang/en.pak");
+        assert_eq!(vars.substitute("cost $$5 and $${LANG}").unwrap(), "cost $5 and ${LANG}");
+        // Anything that is not a placeholder is kept as written
+        assert_eq!(vars.substitute("$HOME ${not valid} ${1X} $").unwrap(), "$HOME ${not valid} ${1X} $");
+        assert_eq!(placeholders("${A}/${B}/${A}/$${C}"), BTreeSet::from(["A".to_string(), "B".to_string()]));
+    }
+
+    #[test]
+    fn unknown_variables_are_errors() {
+        let error = vars(&[]).substitute("${MISSING}/file").unwrap_err().to_string();
+        assert!(error.contains("Variable ${MISSING} is not set"), "{}", error);
+    }
+
+    #[test]
+    fn resolved_paths_must_stay_inside_the_tree() {
+        let vars = vars(&[("UP", ".."), ("ABS", "/etc"), ("EMPTY", ""), ("SUB", "data/x")]);
+        assert_eq!(vars.resolve_path(Path::new("${SUB}/a.bin")).unwrap(), Path::new("data/x/a.bin"));
+        assert_eq!(vars.resolve_path(Path::new("../plain")).unwrap(), Path::new("../plain"));
+        for path in ["${UP}/escape", "a/${UP}/${UP}/b", "${ABS}/passwd", "${EMPTY}"] {
+            assert!(vars.resolve_path(Path::new(path)).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn resolved_paths_fail_before_any_change() {
+        let paths = [Path::new("plain.txt"), Path::new("${DIR}/a"), Path::new("${DIR}/a")];
+        let resolved = ResolvedPaths::new(&vars(&[("DIR", "d")]), paths).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved.get(Path::new("${DIR}/a")), Path::new("d/a"));
+        assert_eq!(resolved.get(Path::new("plain.txt")), Path::new("plain.txt"));
+        assert!(ResolvedPaths::new(&ApplyVars::new(), paths).is_err());
+    }
+
+    #[test]
+    fn vars_files() {
+        let dir = TempDir::new();
+        let path = dir.write("vars.env", b"# install layout\n\nGAME_DIR = games/x\nEMPTY=\n");
+        let vars = ApplyVars::load(&path).unwrap();
+        assert_eq!(vars.get("GAME_DIR"), Some("games/x"));
+        assert_eq!(vars.get("EMPTY"), Some(""));
+
+        let bad = dir.write("bad.env", b"OK=1\nno equals sign\n");
+        assert!(ApplyVars::load(&bad).unwrap_err().to_string().contains("line 2"));
+        let bad = dir.write("bad_name.env", b"9LIVES=1\n");
+        assert!(ApplyVars::load(&bad).is_err());
+    }
+
+    #[test]
+    fn only_allowed_environment_variables_are_read() {
+        let mut vars = vars(&[("PATH", "kept")]);
+        vars.add_env(&["PATH".to_string(), "DIFFPATCH_TEST_UNSET_VAR".to_string()]).unwrap();
+        assert_eq!(vars.get("PATH"), Some("kept"));
+        assert_eq!(vars.get("DIFFPATCH_TEST_UNSET_VAR"), None);
+        assert!(vars.add_env(&["BAD-NAME".to_string()]).is_err());
+    }
+}
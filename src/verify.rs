@@ -0,0 +1,268 @@
+use crate::diff;
+use crate::digest::{self, DigestEncoding};
+use crate::fsops;
+use crate::manifest::ManifestCheck;
+use crate::runid;
+use crate::staging::{self, StagingDir};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Signature algorithm of records signed with a key
+pub const HMAC_SHA256: &str = "hmac-sha256";
+/// Signature algorithm of records written without a key: a plain checksum, which shows
+/// accidental damage but not tampering
+pub const SHA256: &str = "sha256";
+
+/// Outcome of checking a patched directory against the target manifest of the patch
+///
+/// Records the state before (the files the patch changes) and after (the manifest check),
+/// so it can serve as evidence that the directory was brought to the released state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerificationRecord {
+    /// SHA-256 of the patch manifest, identifying the applied patch
+    pub patch: String,
+    /// Run that created the applied patch, if the patch names it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch_run_id: Option<String>,
+    /// Run that applied and checked the patch (see `runid::current`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    pub directory: PathBuf,
+    /// Seconds since the Unix epoch when the check finished
+    pub verified_at: u64,
+    /// The paths the patch changes, as they were before it was applied
+    pub before: Vec<PriorState>,
+    pub after: ManifestCheck,
+    /// Files the patch removes that are still there
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_removed: Vec<PathBuf>,
+    pub passed: bool,
+    /// Whether the changes were undone because the check failed
+    pub rolled_back: bool,
+    /// `HMAC_SHA256` or `SHA256`
+    pub signature_algorithm: String,
+    /// Hex signature over the record with an empty `signature` (see `sign`)
+    pub signature: String,
+}
+
+/// A path the patch changes, before it was applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriorState {
+    pub path: PathBuf,
+    /// Hash of the file, or `None` if there was no file
+    pub hash: Option<String>,
+    /// Target of a symbolic link that was at the path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+}
+
+impl PriorState {
+    /// Record what is at `full_path` now; `path` is how the record names it
+    pub fn capture(path: &Path, full_path: &Path) -> Result<Self> {
+        let mut state = PriorState { path: path.to_path_buf(), hash: None, link_target: None };
+        match fs::symlink_metadata(full_path) {
+            Ok(metadata) if metadata.is_symlink() => {
+                state.link_target = Some(fs::read_link(full_path).with_context(|| format!("Failed to read link: {}", full_path.display()))?);
+            }
+            Ok(metadata) if metadata.is_file() => {
+                state.hash = Some(diff::calculate_file_hash_as(full_path, DigestEncoding::Hex)?);
+            }
+            _ => {}
+        }
+        Ok(state)
+    }
+}
+
+impl VerificationRecord {
+    pub fn new(patch: String, directory: PathBuf, before: Vec<PriorState>, after: ManifestCheck, not_removed: Vec<PathBuf>) -> Self {
+        let verified_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let passed = after.passed() && not_removed.is_empty();
+        VerificationRecord {
+            patch,
+            patch_run_id: None,
+            run_id: Some(runid::current().to_string()),
+            directory,
+            verified_at,
+            before,
+            after,
+            not_removed,
+            passed,
+            rolled_back: false,
+            signature_algorithm: SHA256.to_string(),
+            signature: String::new(),
+        }
+    }
+
+    /// Sign the record with HMAC-SHA256 under `key`, or without a key with a plain SHA-256
+    pub fn sign(&mut self, key: Option<&[u8]>) -> Result<()> {
+        self.signature_algorithm = if key.is_some() { HMAC_SHA256 } else { SHA256 }.to_string();
+        self.signature = self.compute_signature(key)?;
+        Ok(())
+    }
+
+    /// Check the signature; a keyed record needs the key it was signed with
+    ///
+    /// Given a key, only a record signed with it passes: anyone can recompute a plain
+    /// SHA-256, so an unkeyed record proves nothing then.
+    pub fn verify_signature(&self, key: Option<&[u8]>) -> Result<()> {
+        let key = match (self.signature_algorithm.as_str(), key) {
+            (HMAC_SHA256, None) => return Err(anyhow!("The record is signed with a key; give the key to check it")),
+            (HMAC_SHA256, key) => key,
+            (SHA256, Some(_)) => return Err(anyhow!("The record is not signed with a key, so it cannot be trusted under one")),
+            (SHA256, None) => None,
+            (other, _) => return Err(anyhow!("Unknown signature algorithm: {}", other)),
+        };
+        if !digest::same_digest(&self.compute_signature(key)?, &self.signature) {
+            return Err(anyhow!("Signature does not match the record"));
+        }
+        Ok(())
+    }
+
+    fn compute_signature(&self, key: Option<&[u8]>) -> Result<String> {
+        let unsigned = VerificationRecord { signature: String::new(), ..self.clone() };
+        let message = serde_json::to_vec(&unsigned).context("Failed to serialize verification record")?;
+        let digest = match key {
+            Some(key) => digest::hmac_sha256(key, &message),
+            None => Sha256::digest(&message).into(),
+        };
+        Ok(DigestEncoding::Hex.encode(&digest))
+    }
+
+    /// Write the record as pretty JSON
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_vec_pretty(self).context("Failed to serialize verification record")?;
+        json.push(b'\n');
+        staging::write_atomic(path, &json).with_context(|| format!("Failed to write verification record: {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read verification record: {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse verification record: {}", path.display()))
+    }
+}
+
+/// Read a signing key file; surrounding whitespace (such as a final line break) is not
+/// part of the key
+pub fn load_key(path: &Path) -> Result<Vec<u8>> {
+    let key = fs::read(path).with_context(|| format!("Failed to read signing key: {}", path.display()))?;
+    let key = key.trim_ascii();
+    if key.is_empty() {
+        return Err(anyhow!("Signing key is empty: {}", path.display()));
+    }
+    Ok(key.to_vec())
+}
+
+/// Copies of the paths an in-place apply is about to change, to put them back if the
+/// result fails verification
+///
+/// The copies live in a temporary directory inside the patched one (so they can be
+/// cloned), which is removed when the backup is dropped.
+pub struct RollbackBackup {
+    dir: StagingDir,
+    root: PathBuf,
+    /// Relative path, and what was there: a copied file, a link target or nothing
+    entries: Vec<(PathBuf, Saved)>,
+}
+
+enum Saved {
+    File(PathBuf),
+    Link(PathBuf),
+    Nothing,
+}
+
+impl RollbackBackup {
+    /// Save the current state of `paths` (relative to `root`)
+    pub fn create<'a>(root: &Path, paths: impl IntoIterator<Item = &'a Path>) -> Result<Self> {
+        let dir = StagingDir::new_in(root, "rollback")?;
+        let mut entries = Vec::new();
+        for (index, path) in paths.into_iter().enumerate() {
+            let full_path = root.join(path);
+            let saved = match fs::symlink_metadata(&full_path) {
+                Ok(metadata) if metadata.is_symlink() => {
+                    Saved::Link(fs::read_link(&full_path).with_context(|| format!("Failed to read link: {}", full_path.display()))?)
+                }
+                Ok(metadata) if metadata.is_file() => {
+                    let copy = dir.path().join(index.to_string());
+                    fsops::clone_or_copy(&full_path, &copy).with_context(|| format!("Failed to back up {}", full_path.display()))?;
+                    Saved::File(copy)
+                }
+                _ => Saved::Nothing,
+            };
+            entries.push((path.to_path_buf(), saved));
+        }
+        Ok(RollbackBackup { dir, root: root.to_path_buf(), entries })
+    }
+
+    /// Put every saved path back as it was; returns the number of paths restored
+    ///
+    /// Goes on after a failure, so as much as possible is restored, and reports the
+    /// failures at the end.
+    pub fn restore(self) -> Result<usize> {
+        let mut failed = Vec::new();
+        for (path, saved) in &self.entries {
+            let full_path = self.root.join(path);
+            let result = match saved {
+                Saved::File(copy) => {
+                    if let Some(parent) = full_path.parent() {
+                        let _ = fs::create_dir_all(parent);
+                    }
+                    fsops::clone_or_copy(copy, &full_path).map(|_| ())
+                }
+                Saved::Link(target) => fsops::create_symlink(target, &full_path),
+                Saved::Nothing => match fs::symlink_metadata(&full_path) {
+                    Ok(_) => fs::remove_file(&full_path),
+                    Err(_) => Ok(()),
+                },
+            };
+            if let Err(e) = result {
+                failed.push(format!("{} ({})", path.display(), e));
+            }
+        }
+        drop(self.dir);
+        if !failed.is_empty() {
+            return Err(anyhow!("Failed to restore {} paths: {}", failed.len(), failed.join(", ")));
+        }
+        Ok(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> VerificationRecord {
+        VerificationRecord::new("patch.exe".to_string(), PathBuf::from("app"), Vec::new(), ManifestCheck::default(), Vec::new())
+    }
+
+    #[test]
+    fn keyed_record_needs_its_key() {
+        let mut record = record();
+        record.sign(Some(b"secret")).unwrap();
+        assert!(record.verify_signature(Some(b"secret")).is_ok());
+        assert!(record.verify_signature(Some(b"other")).is_err());
+        assert!(record.verify_signature(None).is_err());
+    }
+
+    #[test]
+    fn unkeyed_record_is_rejected_under_a_key() {
+        let mut record = record();
+        record.sign(Some(b"secret")).unwrap();
+        // Edited, then re-signed without a key as an attacker could
+        record.passed = true;
+        record.sign(None).unwrap();
+        assert!(record.verify_signature(None).is_ok());
+        assert!(record.verify_signature(Some(b"secret")).is_err());
+    }
+
+    #[test]
+    fn edited_record_fails() {
+        let mut record = record();
+        record.sign(None).unwrap();
+        record.rolled_back = true;
+        assert!(record.verify_signature(None).is_err());
+    }
+}
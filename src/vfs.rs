@@ -0,0 +1,412 @@
+use crate::diff::HashAlgorithm;
+use crate::fsops::FileMode;
+use crate::image::{self, DiskImage};
+use crate::paths;
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use walkdir::WalkDir;
+#[cfg(feature = "package")]
+use std::collections::HashMap;
+#[cfg(feature = "package")]
+use std::io::Cursor;
+#[cfg(feature = "package")]
+use std::sync::Mutex;
+
+/// What a scan needs to know about a file besides its content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VfsMetadata {
+    pub size: u64,
+    /// Last modification time, if the source records one
+    pub modified: Option<SystemTime>,
+    /// Permissions, if the source records them
+    pub mode: Option<FileMode>,
+}
+
+/// A tree of files that can be scanned and compared: a directory, an archive, a disk image
+///
+/// Paths are relative to the root of the tree. Implementations must allow `open` from
+/// several threads at once, as scans hash files in parallel.
+pub trait Vfs: Sync {
+    /// Shown in progress output, e.g. the path of the directory or archive
+    fn name(&self) -> String;
+
+    /// Relative paths of all regular files, in any order
+    fn list(&self) -> Result<Vec<PathBuf>>;
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata>;
+
+    /// Read the content of a file
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + '_>>;
+
+    /// Digests by `algorithm` of the files at `paths`, in the same order, computed where
+    /// the files are stored so that their content need not be read (`None` for a file
+    /// that cannot be read there)
+    ///
+    /// Returns `None` if the tree cannot do this, and scans open and hash every file.
+    /// Otherwise scans still read the first bytes of each file to tell text from binary.
+    fn digests(&self, _paths: &[PathBuf], _algorithm: HashAlgorithm) -> Result<Option<Vec<Option<Vec<u8>>>>> {
+        Ok(None)
+    }
+}
+
+/// A directory on the local file system
+///
+/// `diff::scan_directory` remains the faster way to scan a directory, as it can reuse
+/// snapshots; this is for code written against any `Vfs`.
+#[derive(Debug, Clone)]
+pub struct LocalFs {
+    root: PathBuf,
+}
+
+impl LocalFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalFs { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Vfs for LocalFs {
+    fn name(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in WalkDir::new(&self.root) {
+            let entry = entry.with_context(|| format!("Failed to list directory: {}", self.root.display()))?;
+            if entry.file_type().is_file()
+                && let Ok(path) = entry.path().strip_prefix(&self.root)
+            {
+                files.push(path.to_path_buf());
+            }
+        }
+        Ok(files)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata> {
+        let full_path = self.root.join(path);
+        let metadata = fs::metadata(&full_path).with_context(|| format!("Failed to read metadata: {}", full_path.display()))?;
+        Ok(VfsMetadata { size: metadata.len(), modified: metadata.modified().ok(), mode: FileMode::of(&metadata) })
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + '_>> {
+        let full_path = self.root.join(path);
+        let file = File::open(&full_path).with_context(|| format!("Failed to open file: {}", full_path.display()))?;
+        Ok(Box::new(file))
+    }
+}
+
+/// A file tree held in memory, for examples and for tests that should not touch the disk
+///
+/// Built like `DiffOptions`: `MemoryFs::new().file("a.txt", "hello").file("dir/b.bin", bytes)`.
+/// Paths use `/` separators on every platform; `.`, `..` and root components are dropped.
+#[derive(Debug, Clone)]
+pub struct MemoryFs {
+    name: String,
+    files: BTreeMap<PathBuf, MemoryFile>,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryFile {
+    content: Arc<[u8]>,
+    modified: Option<SystemTime>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        MemoryFs { name: "<memory>".to_string(), files: BTreeMap::new() }
+    }
+
+    /// Name shown in progress output (default `<memory>`)
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Add a file, replacing any file at the same path
+    pub fn file(mut self, path: impl AsRef<Path>, content: impl AsRef<[u8]>) -> Self {
+        self.insert(path, content);
+        self
+    }
+
+    /// Add a file with a modification time
+    pub fn file_modified(mut self, path: impl AsRef<Path>, content: impl AsRef<[u8]>, modified: SystemTime) -> Self {
+        let path = memory_path(path.as_ref());
+        self.files.insert(path, MemoryFile { content: Arc::from(content.as_ref()), modified: Some(modified) });
+        self
+    }
+
+    /// Leave out the file at `path`, e.g. after cloning another tree
+    pub fn without(mut self, path: impl AsRef<Path>) -> Self {
+        self.remove(path);
+        self
+    }
+
+    pub fn insert(&mut self, path: impl AsRef<Path>, content: impl AsRef<[u8]>) {
+        let path = memory_path(path.as_ref());
+        self.files.insert(path, MemoryFile { content: Arc::from(content.as_ref()), modified: None });
+    }
+
+    /// Remove a file; returns whether it existed
+    pub fn remove(&mut self, path: impl AsRef<Path>) -> bool {
+        self.files.remove(&memory_path(path.as_ref())).is_some()
+    }
+
+    /// Content of the file at `path`
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&[u8]> {
+        self.files.get(&memory_path(path.as_ref())).map(|file| &*file.content)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn find(&self, path: &Path) -> Result<&MemoryFile> {
+        self.files.get(path).ok_or_else(|| anyhow!("No such file in {}: {}", self.name, path.display()))
+    }
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        MemoryFs::new()
+    }
+}
+
+/// Relative path made of the names in `path`, as scans of other trees return it
+fn memory_path(path: &Path) -> PathBuf {
+    paths::split_components(&path.to_string_lossy()).filter(|name| *name != "..").collect()
+}
+
+impl Vfs for MemoryFs {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.files.keys().cloned().collect())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata> {
+        let file = self.find(path)?;
+        Ok(VfsMetadata { size: file.content.len() as u64, modified: file.modified, mode: None })
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(&*self.find(path)?.content))
+    }
+}
+
+/// A plain ZIP archive (not a patch file, whose archive follows the executable)
+///
+/// Entries are decompressed into memory one at a time when opened.
+#[cfg(feature = "package")]
+#[derive(Debug)]
+pub struct ZipFs {
+    path: PathBuf,
+    archive: Mutex<zip::ZipArchive<File>>,
+    /// Index, size and permissions of every file entry, by path
+    entries: HashMap<PathBuf, (usize, u64, Option<FileMode>)>,
+}
+
+#[cfg(feature = "package")]
+impl ZipFs {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()))?;
+        let mut archive = zip::ZipArchive::new(file).with_context(|| format!("Failed to read archive: {}", path.display()))?;
+        let mut entries = HashMap::new();
+        for index in 0..archive.len() {
+            let entry = archive.by_index_raw(index).with_context(|| format!("Failed to read archive: {}", path.display()))?;
+            // Entries whose names leave the archive root are left out, as on extraction
+            if entry.is_file()
+                && let Some(name) = entry.enclosed_name()
+            {
+                entries.insert(name, (index, entry.size(), entry.unix_mode().map(|mode| FileMode::Unix(mode & 0o7777))));
+            }
+        }
+        Ok(ZipFs { path: path.to_path_buf(), archive: Mutex::new(archive), entries })
+    }
+}
+
+#[cfg(feature = "package")]
+impl Vfs for ZipFs {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.entries.keys().cloned().collect())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata> {
+        let (_, size, mode) = self.entries.get(path).ok_or_else(|| anyhow!("No such file in archive: {}", path.display()))?;
+        Ok(VfsMetadata { size: *size, modified: None, mode: *mode })
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + '_>> {
+        let (index, size, _) = self.entries.get(path).ok_or_else(|| anyhow!("No such file in archive: {}", path.display()))?;
+        let mut archive = self.archive.lock().unwrap();
+        let mut entry = archive.by_index(*index).with_context(|| format!("Failed to read {} from archive", path.display()))?;
+        let capacity = usize::try_from(*size).map_err(|_| anyhow!("{} is too large to read from the archive on this platform", path.display()))?;
+        let mut content = Vec::with_capacity(capacity);
+        entry.read_to_end(&mut content).with_context(|| format!("Failed to read {} from archive", path.display()))?;
+        Ok(Box::new(Cursor::new(content)))
+    }
+}
+
+/// Whether `path` is a plain ZIP archive, judging by its first bytes
+#[cfg(feature = "package")]
+pub fn is_zip_archive(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    path.is_file() && File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && (magic == *b"PK\x03\x04" || magic == *b"PK\x05\x06")
+}
+
+#[cfg(not(feature = "package"))]
+pub fn is_zip_archive(_path: &Path) -> bool {
+    false
+}
+
+/// Open a file that holds a tree of files (disk image or ZIP archive) as a `Vfs`
+///
+/// Returns `None` for other files, such as patch files.
+pub fn open_archive(path: &Path) -> Result<Option<Box<dyn Vfs>>> {
+    if image::is_disk_image(path) {
+        return Ok(Some(Box::new(DiskImage::open(path)?)));
+    }
+    #[cfg(feature = "package")]
+    if is_zip_archive(path) {
+        return Ok(Some(Box::new(ZipFs::open(path)?)));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::ContentKind;
+    use crate::diff::{self, DiffOptions};
+    use crate::testutil::TempDir;
+
+    fn changes(source: &dyn Vfs, target: &dyn Vfs, options: &DiffOptions) -> Vec<(&'static str, PathBuf)> {
+        let mut changes: Vec<_> = diff::compare_vfs(source, target, options).unwrap().diffs.iter()
+            .map(|diff| (diff.kind(), diff.path().to_path_buf()))
+            .collect();
+        changes.sort_by(|a, b| a.1.cmp(&b.1));
+        changes
+    }
+
+    #[test]
+    fn memory_trees_compare_like_directories() {
+        let source = MemoryFs::new()
+            .file("README.txt", "Version 1\n")
+            .file("bin/app", [0x7f, b'E', b'L', b'F', 1])
+            .file("data/old.dat", "obsolete")
+            .file(".hidden", "left out");
+        let target = source.clone()
+            .file("bin/app", [0x7f, b'E', b'L', b'F', 2])
+            .file("data/new.dat", "fresh")
+            .file(".hidden", "changed, still left out")
+            .without("data/old.dat");
+        let options = DiffOptions::new();
+        assert_eq!(changes(&source, &target, &options), [
+            ("modified", PathBuf::from("bin/app")),
+            ("added", PathBuf::from("data/new.dat")),
+            ("removed", PathBuf::from("data/old.dat")),
+        ]);
+        assert!(changes(&source, &source.clone(), &options).is_empty());
+    }
+
+    #[test]
+    fn memory_tree_hashes_like_the_disk() {
+        let dir = TempDir::new();
+        dir.write("a.txt", b"alpha");
+        dir.write("sub/b.bin", &[0, 1, 2, 3]);
+        let memory = MemoryFs::new().file("a.txt", "alpha").file("sub/b.bin", [0, 1, 2, 3]);
+        assert!(changes(&memory, &LocalFs::new(dir.path()), &DiffOptions::new()).is_empty());
+    }
+
+    /// Memory tree that hands out SHA-256 digests, as `SshFs` does with `sha256sum`
+    struct Digested(MemoryFs);
+
+    impl Vfs for Digested {
+        fn name(&self) -> String {
+            self.0.name()
+        }
+
+        fn list(&self) -> Result<Vec<PathBuf>> {
+            self.0.list()
+        }
+
+        fn metadata(&self, path: &Path) -> Result<VfsMetadata> {
+            self.0.metadata(path)
+        }
+
+        fn open(&self, path: &Path) -> Result<Box<dyn Read + '_>> {
+            self.0.open(path)
+        }
+
+        fn digests(&self, paths: &[PathBuf], _algorithm: HashAlgorithm) -> Result<Option<Vec<Option<Vec<u8>>>>> {
+            use sha2::{Digest, Sha256};
+            Ok(Some(paths.iter().map(|path| self.0.get(path).map(|content| Sha256::digest(content).to_vec())).collect()))
+        }
+    }
+
+    #[test]
+    fn vfs_scans_record_mode_and_content_like_directory_scans() {
+        let dir = TempDir::new();
+        dir.write("a.txt", b"alpha");
+        dir.write("sub/b.bin", &[0, 1, 2, 3]);
+        let options = DiffOptions::new();
+        let scanned = diff::scan_vfs_report(&LocalFs::new(dir.path()), &options).unwrap().files;
+        let expected = diff::scan_directory_report(dir.path(), &options).unwrap().files;
+        assert_eq!(scanned.len(), 2);
+        for (path, info) in &scanned {
+            assert_eq!((info.mode, info.content), (expected[path].mode, expected[path].content));
+        }
+        assert_eq!(scanned[Path::new("sub/b.bin")].content, Some(ContentKind::Binary));
+        #[cfg(unix)]
+        assert!(scanned[Path::new("a.txt")].mode.is_some());
+    }
+
+    #[test]
+    fn digested_files_are_classified() {
+        let memory = MemoryFs::new().file("a.txt", "alpha").file("b.bin", [0, 1, 2, 3]);
+        let options = DiffOptions::new().hash_algorithm(HashAlgorithm::Sha256);
+        let digested = diff::scan_vfs_report(&Digested(memory.clone()), &options).unwrap().files;
+        let read = diff::scan_vfs_report(&memory, &options).unwrap().files;
+        assert_eq!(digested, read);
+        assert_eq!(digested[Path::new("a.txt")].content, Some(ContentKind::Text));
+        assert_eq!(digested[Path::new("b.bin")].content, Some(ContentKind::Binary));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn vfs_scans_run_the_annotator() {
+        let memory = MemoryFs::new().named("memory").file("a.txt", "alpha");
+        let annotator = diff::Annotator::new(|path, info| {
+            info.extra.insert("seen".to_string(), path.to_string_lossy().into());
+        });
+        let files = diff::scan_vfs_report(&memory, &DiffOptions::new().annotator(annotator)).unwrap().files;
+        assert_eq!(files[Path::new("a.txt")].extra["seen"], Path::new("memory").join("a.txt").to_string_lossy().as_ref());
+    }
+
+    #[test]
+    fn memory_paths_are_normalized() {
+        let tree = MemoryFs::new().file("./dir/../a.txt", "a").file("/root.txt", "r");
+        assert_eq!(tree.list().unwrap(), [PathBuf::from("dir/a.txt"), PathBuf::from("root.txt")]);
+        assert_eq!(tree.get("dir/a.txt"), Some(&b"a"[..]));
+        assert!(tree.metadata(Path::new("missing")).is_err());
+        assert_eq!(tree.clone().without("root.txt").len(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// What a warning is about, used to suppress whole categories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum WarningKind {
+    /// A file left out of a scan (unreadable, or over the size limit)
+    SkippedFile,
+    /// A directory that could not be listed, so its whole subtree is left out of a scan
+    UnreadableDir,
+    /// Paths that differ only by case and collide on Windows/macOS
+    CaseConflict,
+    /// A file that changed while it was hashed
+    UnstableFile,
+    /// A path Windows cannot create (too long, reserved or invalid name)
+    LongPath,
+    /// More changed than `create --max-changed-files` or `--max-changed-size` allow
+    ChangeLimit,
+}
+
+impl WarningKind {
+    pub const ALL: [WarningKind; 6] = [
+        WarningKind::SkippedFile,
+        WarningKind::UnreadableDir,
+        WarningKind::CaseConflict,
+        WarningKind::UnstableFile,
+        WarningKind::LongPath,
+        WarningKind::ChangeLimit,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::SkippedFile => "skipped-file",
+            WarningKind::UnreadableDir => "unreadable-dir",
+            WarningKind::CaseConflict => "case-conflict",
+            WarningKind::UnstableFile => "unstable-file",
+            WarningKind::LongPath => "long-path",
+            WarningKind::ChangeLimit => "change-limit",
+        }
+    }
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for WarningKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        WarningKind::ALL.into_iter().find(|kind| kind.name() == name.trim()).ok_or_else(|| {
+            let names: Vec<&str> = WarningKind::ALL.iter().map(|kind| kind.name()).collect();
+            anyhow!("Unknown warning category: {} (expected one of {})", name, names.join(", "))
+        })
+    }
+}
+
+/// How much a warning matters; only `Warning` fails a run with `--warnings-as-errors`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    /// Expected from the options given, e.g. a file over `--max-file-size`
+    Info,
+    /// The result may not be what was intended
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "note",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// What a scan was doing with a file when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum ErrorPhase {
+    /// Listing a directory
+    List,
+    /// Reading the size, times and permissions
+    Metadata,
+    /// Reading the target of a symbolic link
+    ReadLink,
+    /// Opening and reading the content to hash it
+    Hash,
+    /// Checking the file did not change while it was hashed
+    Stability,
+}
+
+/// What went wrong with one file, kept in reports so automated triage can tell e.g.
+/// permission problems from disk errors without running the scan again
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileError {
+    pub phase: ErrorPhase,
+    /// Error number the operating system gave (`errno`, or the Windows error code)
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub os_code: Option<i32>,
+    /// Kind of the I/O error, e.g. `PermissionDenied` or `NotFound`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub kind: Option<String>,
+    /// The operating system's message, e.g. `Permission denied (os error 13)`
+    pub message: String,
+}
+
+impl FileError {
+    pub fn new(phase: ErrorPhase, message: impl Into<String>) -> Self {
+        FileError { phase, os_code: None, kind: None, message: message.into() }
+    }
+
+    pub fn from_io(phase: ErrorPhase, error: &io::Error) -> Self {
+        FileError { phase, os_code: error.raw_os_error(), kind: Some(format!("{:?}", error.kind())), message: error.to_string() }
+    }
+
+    /// Error of `phase` from the first I/O error among the causes of `error`, or from its
+    /// whole message if there is none
+    pub fn from_error(phase: ErrorPhase, error: &anyhow::Error) -> Self {
+        match error.chain().find_map(|cause| cause.downcast_ref::<io::Error>()) {
+            Some(io_error) => FileError::from_io(phase, io_error),
+            None => FileError::new(phase, format!("{:#}", error)),
+        }
+    }
+}
+
+/// A problem found while scanning or comparing that did not stop the run
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub severity: Severity,
+    /// Relative path the warning is about, if any
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub path: Option<PathBuf>,
+    pub message: String,
+    /// The error behind a warning about a file that could not be read
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub error: Option<FileError>,
+}
+
+impl Warning {
+    pub fn new(kind: WarningKind, severity: Severity, message: impl Into<String>) -> Self {
+        Warning { kind, severity, path: None, message: message.into(), error: None }
+    }
+
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn with_error(mut self, error: FileError) -> Self {
+        self.error = Some(error);
+        self
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: ", self.severity, self.kind)?;
+        if let Some(path) = &self.path {
+            write!(f, "{}: ", path.display())?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Warnings collected from parallel scans; clones share the same list
+///
+/// Warnings of suppressed categories are dropped when pushed.
+#[derive(Debug, Clone, Default)]
+pub struct WarningLog {
+    warnings: Arc<Mutex<Vec<Warning>>>,
+    suppressed: Arc<HashSet<WarningKind>>,
+}
+
+impl WarningLog {
+    pub fn new(suppressed: HashSet<WarningKind>) -> Self {
+        WarningLog { warnings: Arc::default(), suppressed: Arc::new(suppressed) }
+    }
+
+    pub fn is_suppressed(&self, kind: WarningKind) -> bool {
+        self.suppressed.contains(&kind)
+    }
+
+    pub fn push(&self, warning: Warning) {
+        if !self.is_suppressed(warning.kind) {
+            self.warnings.lock().unwrap().push(warning);
+        }
+    }
+
+    /// Remove and return the warnings collected so far, sorted by category and path
+    pub fn take(&self) -> Vec<Warning> {
+        let mut warnings = std::mem::take(&mut *self.warnings.lock().unwrap());
+        warnings.sort_by(|a, b| a.kind.cmp(&b.kind).then_with(|| a.path.cmp(&b.path)));
+        warnings
+    }
+}
+
+/// Number of warnings that fail a run with `--warnings-as-errors`
+pub fn count_failing(warnings: &[Warning]) -> usize {
+    warnings.iter().filter(|w| w.severity >= Severity::Warning).count()
+}
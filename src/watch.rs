@@ -0,0 +1,397 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Changes reported by a `Watcher`
+#[derive(Debug, Default)]
+pub struct WatchEvents {
+    /// Absolute paths of files or directories that were created, written, removed or renamed
+    pub paths: Vec<PathBuf>,
+    /// Events were dropped (e.g. the kernel queue overflowed), so the tree must be rescanned
+    pub overflow: bool,
+}
+
+impl WatchEvents {
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && !self.overflow
+    }
+}
+
+/// Recursive file system watcher (inotify on Linux, FSEvents on macOS)
+pub struct Watcher {
+    inner: platform::Watcher,
+}
+
+impl Watcher {
+    /// Start watching `root` and everything below it
+    pub fn new(root: &Path) -> Result<Self> {
+        Ok(Watcher { inner: platform::Watcher::new(root)? })
+    }
+
+    /// Wait up to `timeout` for changes; returns no events if nothing changed
+    pub fn wait(&mut self, timeout: Duration) -> Result<WatchEvents> {
+        self.inner.wait(timeout)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::WatchEvents;
+    use anyhow::{anyhow, Result};
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use walkdir::WalkDir;
+
+    const WATCH_MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_ATTRIB
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ONLYDIR;
+
+    /// Size of `struct inotify_event` without the name
+    const EVENT_HEADER_LEN: usize = 16;
+
+    pub struct Watcher {
+        fd: libc::c_int,
+        dirs: HashMap<libc::c_int, PathBuf>, // Watch descriptor -> directory
+        buffer: Vec<u8>,
+    }
+
+    impl Watcher {
+        pub fn new(root: &Path) -> Result<Self> {
+            // SAFETY: plain syscall, the descriptor is owned by the Watcher
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(anyhow!("Failed to start inotify: {}", io::Error::last_os_error()));
+            }
+            let mut watcher = Watcher { fd, dirs: HashMap::new(), buffer: vec![0; 64 * 1024] };
+            watcher.add_tree(root, &mut Vec::new())?;
+            Ok(watcher)
+        }
+
+        /// Watch `dir` and its subdirectories, adding the files found to `files`
+        fn add_tree(&mut self, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+            for entry in WalkDir::new(dir).into_iter().filter_map(Result::ok) {
+                if entry.file_type().is_dir() {
+                    self.add_watch(entry.path())?;
+                } else {
+                    files.push(entry.into_path());
+                }
+            }
+            Ok(())
+        }
+
+        fn add_watch(&mut self, dir: &Path) -> Result<()> {
+            let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+                return Ok(());
+            };
+            // SAFETY: `path` is NUL-terminated
+            let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                let error = io::Error::last_os_error();
+                return match error.raw_os_error() {
+                    Some(libc::ENOSPC) => Err(anyhow!(
+                        "Too many directories to watch, raise fs.inotify.max_user_watches (at {})",
+                        dir.display()
+                    )),
+                    // Removed again before it could be watched
+                    Some(libc::ENOENT) | Some(libc::ENOTDIR) => Ok(()),
+                    _ => Err(anyhow!("Failed to watch {}: {}", dir.display(), error)),
+                };
+            }
+            // Re-adding a moved directory returns its old descriptor, which now maps to the new path
+            self.dirs.insert(wd, dir.to_path_buf());
+            Ok(())
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> Result<WatchEvents> {
+            let mut events = WatchEvents::default();
+            let mut poll = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            // SAFETY: polls a single valid descriptor
+            let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis().min(i32::MAX as u128) as i32) };
+            if ready <= 0 {
+                // Timeout, or interrupted by a signal such as Ctrl-C
+                return Ok(events);
+            }
+
+            loop {
+                // SAFETY: reads into a buffer of the length passed
+                let read = unsafe { libc::read(self.fd, self.buffer.as_mut_ptr().cast(), self.buffer.len()) };
+                if read <= 0 {
+                    break;
+                }
+                let mut offset = 0;
+                while offset + EVENT_HEADER_LEN <= read as usize {
+                    let field = |at: usize| {
+                        let start = offset + at;
+                        u32::from_ne_bytes(self.buffer[start..start + 4].try_into().unwrap())
+                    };
+                    let (wd, mask, name_len) = (field(0) as libc::c_int, field(4), field(12) as usize);
+                    let name_start = offset + EVENT_HEADER_LEN;
+                    let name: Vec<u8> = self.buffer[name_start..name_start + name_len]
+                        .iter()
+                        .copied()
+                        .take_while(|&b| b != 0)
+                        .collect();
+                    offset = name_start + name_len;
+
+                    if mask & libc::IN_Q_OVERFLOW != 0 {
+                        events.overflow = true;
+                        continue;
+                    }
+                    if mask & libc::IN_IGNORED != 0 {
+                        self.dirs.remove(&wd);
+                        continue;
+                    }
+                    let Some(dir) = self.dirs.get(&wd) else {
+                        continue;
+                    };
+                    let path = dir.join(std::ffi::OsStr::from_bytes(&name));
+                    if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                        // Files may have been added before the new directory was watched
+                        self.add_tree(&path, &mut events.paths)?;
+                    }
+                    events.paths.push(path);
+                }
+            }
+            Ok(events)
+        }
+    }
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            // SAFETY: the descriptor is owned by this Watcher
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::WatchEvents;
+    use anyhow::{anyhow, Result};
+    use std::ffi::{c_char, c_void, CStr, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    type FsEventStreamRef = *mut c_void;
+    type Callback = extern "C" fn(FsEventStreamRef, *mut c_void, usize, *mut c_void, *const u32, *const u64);
+
+    #[repr(C)]
+    struct FsEventStreamContext {
+        version: isize,
+        info: *mut c_void,
+        retain: *const c_void,
+        release: *const c_void,
+        copy_description: *const c_void,
+    }
+
+    const CREATE_FLAG_NO_DEFER: u32 = 0x02;
+    const CREATE_FLAG_FILE_EVENTS: u32 = 0x10;
+    const EVENT_FLAG_MUST_SCAN_SUBDIRS: u32 = 0x01;
+    const EVENT_FLAG_USER_DROPPED: u32 = 0x02;
+    const EVENT_FLAG_KERNEL_DROPPED: u32 = 0x04;
+    const EVENT_ID_SINCE_NOW: u64 = u64::MAX;
+    const CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+    /// Latency in seconds; events are delivered in batches at most this often
+    const LATENCY: f64 = 0.1;
+
+    #[link(name = "CoreServices", kind = "framework")]
+    unsafe extern "C" {
+        fn FSEventStreamCreate(
+            allocator: *const c_void,
+            callback: Callback,
+            context: *const FsEventStreamContext,
+            paths: *const c_void,
+            since_when: u64,
+            latency: f64,
+            flags: u32,
+        ) -> FsEventStreamRef;
+        fn FSEventStreamSetDispatchQueue(stream: FsEventStreamRef, queue: *mut c_void);
+        fn FSEventStreamStart(stream: FsEventStreamRef) -> u8;
+        fn FSEventStreamStop(stream: FsEventStreamRef);
+        fn FSEventStreamInvalidate(stream: FsEventStreamRef);
+        fn FSEventStreamRelease(stream: FsEventStreamRef);
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    unsafe extern "C" {
+        static kCFTypeArrayCallBacks: c_void;
+        fn CFStringCreateWithBytes(
+            allocator: *const c_void,
+            bytes: *const u8,
+            length: isize,
+            encoding: u32,
+            external: u8,
+        ) -> *const c_void;
+        fn CFArrayCreate(
+            allocator: *const c_void,
+            values: *const *const c_void,
+            count: isize,
+            callbacks: *const c_void,
+        ) -> *const c_void;
+        fn CFRelease(object: *const c_void);
+    }
+
+    unsafe extern "C" {
+        fn dispatch_queue_create(label: *const c_char, attributes: *const c_void) -> *mut c_void;
+        fn dispatch_release(object: *mut c_void);
+    }
+
+    enum Event {
+        Path(PathBuf),
+        Overflow,
+    }
+
+    type EventSender = Mutex<Sender<Event>>;
+
+    extern "C" fn on_events(
+        _stream: FsEventStreamRef,
+        info: *mut c_void,
+        count: usize,
+        paths: *mut c_void,
+        flags: *const u32,
+        _ids: *const u64,
+    ) {
+        // SAFETY: `info` is the EventSender owned by the Watcher, which outlives the stream;
+        // `paths` and `flags` hold `count` entries
+        let (sender, paths, flags) = unsafe {
+            (
+                &*(info as *const EventSender),
+                std::slice::from_raw_parts(paths as *const *const c_char, count),
+                std::slice::from_raw_parts(flags, count),
+            )
+        };
+        let Ok(sender) = sender.lock() else {
+            return;
+        };
+        for (&path, &flag) in paths.iter().zip(flags) {
+            let dropped = EVENT_FLAG_MUST_SCAN_SUBDIRS | EVENT_FLAG_USER_DROPPED | EVENT_FLAG_KERNEL_DROPPED;
+            let event = if flag & dropped != 0 {
+                Event::Overflow
+            } else {
+                // SAFETY: FSEvents passes NUL-terminated C strings
+                let path = unsafe { CStr::from_ptr(path) };
+                Event::Path(PathBuf::from(OsStr::from_bytes(path.to_bytes())))
+            };
+            let _ = sender.send(event);
+        }
+    }
+
+    pub struct Watcher {
+        stream: FsEventStreamRef,
+        queue: *mut c_void,
+        sender: *mut EventSender,
+        receiver: Receiver<Event>,
+    }
+
+    impl Watcher {
+        pub fn new(root: &Path) -> Result<Self> {
+            let (sender, receiver) = mpsc::channel();
+            let sender = Box::into_raw(Box::new(Mutex::new(sender)));
+            let context = FsEventStreamContext {
+                version: 0,
+                info: sender.cast(),
+                retain: std::ptr::null(),
+                release: std::ptr::null(),
+                copy_description: std::ptr::null(),
+            };
+            let root_bytes = root.as_os_str().as_bytes();
+            // SAFETY: CoreFoundation objects are released once the stream holds them; the
+            // stream, queue and sender are owned by the returned Watcher
+            unsafe {
+                let path = CFStringCreateWithBytes(
+                    std::ptr::null(),
+                    root_bytes.as_ptr(),
+                    root_bytes.len() as isize,
+                    CF_STRING_ENCODING_UTF8,
+                    0,
+                );
+                let paths = CFArrayCreate(std::ptr::null(), &path, 1, &raw const kCFTypeArrayCallBacks);
+                let stream = FSEventStreamCreate(
+                    std::ptr::null(),
+                    on_events,
+                    &context,
+                    paths,
+                    EVENT_ID_SINCE_NOW,
+                    LATENCY,
+                    CREATE_FLAG_NO_DEFER | CREATE_FLAG_FILE_EVENTS,
+                );
+                CFRelease(paths);
+                CFRelease(path);
+                if stream.is_null() {
+                    drop(Box::from_raw(sender));
+                    return Err(anyhow!("Failed to watch {}", root.display()));
+                }
+                let queue = dispatch_queue_create(c"diffpatch.watch".as_ptr(), std::ptr::null());
+                FSEventStreamSetDispatchQueue(stream, queue);
+                let watcher = Watcher { stream, queue, sender, receiver };
+                if FSEventStreamStart(stream) == 0 {
+                    return Err(anyhow!("Failed to start watching {}", root.display()));
+                }
+                Ok(watcher)
+            }
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> Result<WatchEvents> {
+            let first = match self.receiver.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => return Ok(WatchEvents::default()),
+                Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("File system watcher stopped")),
+            };
+            let mut events = WatchEvents::default();
+            for event in std::iter::once(first).chain(self.receiver.try_iter()) {
+                match event {
+                    Event::Path(path) => events.paths.push(path),
+                    Event::Overflow => events.overflow = true,
+                }
+            }
+            Ok(events)
+        }
+    }
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            // SAFETY: stops callbacks before the sender they use is freed
+            unsafe {
+                FSEventStreamStop(self.stream);
+                FSEventStreamInvalidate(self.stream);
+                FSEventStreamRelease(self.stream);
+                dispatch_release(self.queue);
+                drop(Box::from_raw(self.sender));
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod platform {
+    use super::WatchEvents;
+    use anyhow::{anyhow, Result};
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub struct Watcher;
+
+    impl Watcher {
+        pub fn new(_root: &Path) -> Result<Self> {
+            Err(anyhow!("Watching directories is only supported on Linux and macOS"))
+        }
+
+        pub fn wait(&mut self, _timeout: Duration) -> Result<WatchEvents> {
+            Ok(WatchEvents::default())
+        }
+    }
+}
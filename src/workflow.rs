@@ -0,0 +1,108 @@
+use crate::diff::{DiffOptions, UnchangedVerification};
+use std::fmt;
+
+/// Common uses of a comparison, each with a set of options that suits it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workflow {
+    /// Ship a new release to existing installs: leave out leftovers of the build, skip
+    /// executables that only got new build stamps, record moved files as moves, and
+    /// refuse patches with warnings
+    Deploy,
+    /// Check a backup against the original: compare every file, confirm equal hashes
+    /// byte by byte, and fail if any file could not be read
+    BackupVerify,
+    /// Merge a game or application mod into an install: look inside changed archives,
+    /// match renamed and moved files, and flag paths that collide on case-insensitive systems
+    ModMerge,
+}
+
+impl Workflow {
+    /// The options this workflow turns on
+    pub fn preset(self) -> WorkflowPreset {
+        match self {
+            Workflow::Deploy => WorkflowPreset {
+                exclude_extensions: [".tmp", ".bak", ".orig"].map(String::from).to_vec(),
+                exclude_dirs: vec!["__pycache__".to_string()],
+                check_case_conflicts: true,
+                ignore_build_stamps: true,
+                detect_renames: true,
+                verify_unchanged: UnchangedVerification::Sample(64),
+                binary_delta: true,
+                reproducible: true,
+                warnings_as_errors: true,
+                ..WorkflowPreset::default()
+            },
+            Workflow::BackupVerify => WorkflowPreset {
+                verify_unchanged: UnchangedVerification::All,
+                reproducible: true,
+                warnings_as_errors: true,
+                ..WorkflowPreset::default()
+            },
+            Workflow::ModMerge => WorkflowPreset {
+                exclude_extensions: [".tmp", ".bak"].map(String::from).to_vec(),
+                check_case_conflicts: true,
+                match_extension_renames: true,
+                detect_renames: true,
+                inspect_archives: true,
+                binary_delta: true,
+                ..WorkflowPreset::default()
+            },
+        }
+    }
+
+    /// Comparison options of this workflow, to be refined with further builder calls
+    pub fn diff_options(self) -> DiffOptions {
+        self.preset().apply(DiffOptions::new())
+    }
+}
+
+impl fmt::Display for Workflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Workflow::Deploy => "deploy",
+            Workflow::BackupVerify => "backup-verify",
+            Workflow::ModMerge => "mod-merge",
+        })
+    }
+}
+
+/// Settings of a `Workflow`
+///
+/// Options a caller sets explicitly add to these: flags that are on stay on, exclude
+/// lists are extended, and an explicit `verify_unchanged` replaces the preset one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkflowPreset {
+    pub exclude_extensions: Vec<String>,
+    pub exclude_dirs: Vec<String>,
+    pub check_case_conflicts: bool,
+    pub match_extension_renames: bool,
+    pub detect_renames: bool,
+    pub ignore_build_stamps: bool,
+    pub inspect_archives: bool,
+    pub verify_unchanged: UnchangedVerification,
+    /// Store modified files as binary deltas (`PatchOptions::binary_delta`)
+    pub binary_delta: bool,
+    /// Normalize manifests and reports (`DiffReport::make_reproducible`)
+    pub reproducible: bool,
+    /// Refuse to create a patch when the comparison produced warnings
+    pub warnings_as_errors: bool,
+}
+
+impl WorkflowPreset {
+    /// Set the comparison options of the preset on `options`
+    pub fn apply(&self, mut options: DiffOptions) -> DiffOptions {
+        if !self.exclude_extensions.is_empty() {
+            options = options.exclude_extensions(self.exclude_extensions.clone());
+        }
+        if !self.exclude_dirs.is_empty() {
+            options = options.exclude_dirs(self.exclude_dirs.clone());
+        }
+        options
+            .check_case_conflicts(self.check_case_conflicts)
+            .match_extension_renames(self.match_extension_renames)
+            .detect_renames(self.detect_renames)
+            .ignore_build_stamps(self.ignore_build_stamps)
+            .inspect_archives(self.inspect_archives)
+            .verify_unchanged(self.verify_unchanged)
+    }
+}
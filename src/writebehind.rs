@@ -0,0 +1,43 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::thread::{self, JoinHandle};
+
+/// Work running on a background thread, such as serializing and writing a report while
+/// the run goes on with hashing, copying or compressing
+///
+/// `wait` returns the result of the work. Dropping without `wait` still waits for the
+/// thread, so the process does not exit with a half-written file, but discards the result.
+pub struct WriteBehind<T: Send + 'static> {
+    handle: Option<JoinHandle<Result<T>>>,
+}
+
+impl<T: Send + 'static> WriteBehind<T> {
+    /// Start `work` on a new thread; `name` names the thread
+    pub fn spawn(name: &str, work: impl FnOnce() -> Result<T> + Send + 'static) -> Result<Self> {
+        let handle = thread::Builder::new()
+            .name(format!("diffpatch-{}", name))
+            .spawn(work)
+            .with_context(|| format!("Failed to start background thread: {}", name))?;
+        Ok(WriteBehind { handle: Some(handle) })
+    }
+
+    /// Wait for the work to finish and return its result
+    pub fn wait(mut self) -> Result<T> {
+        let handle = self.handle.take().expect("background work is only waited for once");
+        handle.join().map_err(|_| anyhow!("Background thread panicked"))?
+    }
+}
+
+impl<T: Send + 'static> Drop for WriteBehind<T> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<T: Send + 'static> fmt::Debug for WriteBehind<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteBehind").field("finished", &self.handle.as_ref().is_none_or(JoinHandle::is_finished)).finish()
+    }
+}